@@ -0,0 +1,94 @@
+//! Cancelling connects, calls and commando clients from outside, behind the `cancel` cargo
+//! feature.
+//!
+//! Applications that run lnsocket as part of a larger workflow usually cancel the workflow
+//! as a whole, with a [`CancellationToken`]. Attach the token (or a child of it) where the
+//! work starts instead of wrapping every future:
+//!
+//! - [`Dialer::with_cancellation`](crate::dial::Dialer::with_cancellation): connects made
+//!   with the dialer, DNS lookups and reconnects included, fail with `Error::Cancelled`.
+//! - [`CallOpts::cancel_on`](crate::commando::CallOpts::cancel_on): the call fails with
+//!   `Error::Cancelled`. As with a timeout, the node still runs the command.
+//! - [`CommandoConfig::cancel_on`](crate::commando::CommandoConfig::cancel_on): the pump
+//!   stops with [`PumpExit::Cancelled`](crate::PumpExit::Cancelled), calls in flight fail
+//!   with `Error::Cancelled` and later ones with `Error::PumpExited`.
+//!
+//! ```no_run
+//! use lnsocket::cancel::CancellationToken;
+//! use lnsocket::commando::CommandoConfig;
+//! use lnsocket::dial::Dialer;
+//! use lnsocket::CommandoClient;
+//! # async fn ex(key: bitcoin::secp256k1::SecretKey, pk: bitcoin::secp256k1::PublicKey) -> Result<(), lnsocket::Error> {
+//! let workflow = CancellationToken::new();
+//! let dialer = Dialer::new().with_cancellation(workflow.child_token());
+//! let sock = dialer.connect_and_init(key, pk, "node.example.com:9735").await?;
+//! let config = CommandoConfig::new().cancel_on(workflow.child_token());
+//! let client = CommandoClient::spawn_with_config(sock, "rune", config);
+//!
+//! // later, from anywhere
+//! workflow.cancel();
+//! # Ok(()) }
+//! ```
+
+pub use tokio_util::sync::CancellationToken;
+
+use crate::Error;
+
+/// Run `fut`, unless `token` is cancelled first.
+pub(crate) async fn or_cancelled<T>(
+    token: &CancellationToken,
+    fut: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    tokio::select! {
+        biased;
+        _ = token.cancelled() => Err(Error::Cancelled),
+        res = fut => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commando::{CallOpts, CommandoConfig};
+    use crate::dial::Dialer;
+    use crate::lnsocket::testing::*;
+    use crate::{CommandoClient, PumpExit};
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn tokens_cancel_connects_calls_and_the_pump() {
+        // a node that accepts but never answers the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let their_pubkey = PublicKey::from_secret_key(&Secp256k1::signing_only(), &key);
+        let token = CancellationToken::new();
+        let dialer = Dialer::new().with_cancellation(token.clone());
+        let connect =
+            tokio::spawn(async move { dialer.connect_and_init(key, their_pubkey, &addr).await });
+        let _accepted = listener.accept().await.unwrap();
+        token.cancel();
+        assert!(matches!(connect.await.unwrap(), Err(Error::Cancelled)));
+
+        let (sock, _server, _peer) = loopback_pair().await;
+        let pump_token = CancellationToken::new();
+        let config = CommandoConfig::new()
+            .no_reconnect()
+            .cancel_on(pump_token.clone());
+        let client = CommandoClient::spawn_with_config(sock, "rune", config);
+
+        let call_token = CancellationToken::new();
+        let opts = CallOpts::new().cancel_on(call_token.clone());
+        let call = client.call_with_opts("waitblockheight", serde_json::json!({}), opts);
+        let (call, ()) = tokio::join!(call, async { call_token.cancel() });
+        assert!(matches!(call, Err(Error::Cancelled)));
+        // only that call, the pump goes on
+        assert_eq!(client.exit_reason(), None);
+
+        let other = client.call("getinfo", serde_json::json!({}));
+        let (other, ()) = tokio::join!(other, async { pump_token.cancel() });
+        assert!(matches!(other, Err(Error::Cancelled)));
+        assert_eq!(client.closed().await, PumpExit::Cancelled);
+    }
+}
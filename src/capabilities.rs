@@ -0,0 +1,250 @@
+//! What peers said they support, remembered across connections.
+//!
+//! A [`CapabilityCache`] keeps, per node id, the features and networks of the last `init`
+//! seen from it. An application can then tell before connecting whether a node supports
+//! something (onion messages, say) and skip the ones that don't, and a
+//! [`PeerPool`](crate::pool::PeerPool) can send a message only to the peers that understand it.
+//!
+//! ```no_run
+//! use lnsocket::capabilities::CapabilityCache;
+//! use lnsocket::ln::features::ONION_MESSAGES_FEATURE_BIT;
+//! # #[cfg(feature = "tokio")]
+//! use lnsocket::LNSocketConfig;
+//! # #[cfg(feature = "tokio")]
+//! # async fn ex(key: bitcoin::secp256k1::SecretKey, pk: bitcoin::secp256k1::PublicKey)
+//! # -> Result<(), lnsocket::Error> {
+//! let cache = CapabilityCache::new();
+//! let config = LNSocketConfig::new().with_capability_cache(cache.clone());
+//! let sock = lnsocket::LNSocket::connect_with_config(key, pk, "node.example.com", &config).await?;
+//!
+//! // later, without a connection
+//! if cache.supports(&pk, ONION_MESSAGES_FEATURE_BIT) == Some(false) {
+//!     println!("{pk} doesn't do onion messages");
+//! }
+//! # Ok(()) }
+//! ```
+//!
+//! The cache keeps entries in memory unless it is made with
+//! [`CapabilityCache::with_store`], which hands them to a [`CapabilityStore`] of the
+//! application's, e.g. to persist them with [`PeerCapabilities::encode`]. What a peer
+//! advertised last time is a hint: it may have changed since, and only the next `init` says.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::PublicKey;
+
+use crate::ln::features;
+use crate::ln::msgs::{self, DecodeError};
+use crate::util::ser::{Readable, Writeable, Writer};
+
+const CAPABILITIES_VERSION: u8 = 1;
+
+/// What a peer's `init` said it supports.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCapabilities {
+    /// The `features` and `global_features` of the `init`, merged.
+    pub features: Vec<u8>,
+    /// The chains the peer is interested in, `None` if it didn't say.
+    pub networks: Option<Vec<ChainHash>>,
+    /// When the `init` was seen.
+    pub seen_at: SystemTime,
+}
+
+impl PeerCapabilities {
+    pub fn from_init(init: &msgs::Init) -> Self {
+        Self {
+            features: features::of_init(init),
+            networks: init.networks.clone(),
+            seen_at: SystemTime::now(),
+        }
+    }
+
+    /// Whether the peer advertised `bit`'s feature, as required or optional.
+    pub fn supports(&self, bit: usize) -> bool {
+        features::supports(&self.features, bit)
+    }
+
+    /// Whether the peer is on `chain`. A peer that listed no networks might be on any.
+    pub fn on_network(&self, chain: ChainHash) -> bool {
+        self.networks
+            .as_ref()
+            .is_none_or(|networks| networks.contains(&chain))
+    }
+
+    /// Parses capabilities serialized with [`Writeable::encode`].
+    pub fn decode(mut bytes: &[u8]) -> Result<Self, DecodeError> {
+        Readable::read(&mut bytes)
+    }
+}
+
+impl Writeable for PeerCapabilities {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        CAPABILITIES_VERSION.write(w)?;
+        let seen_at = self
+            .seen_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        seen_at.as_secs().write(w)?;
+        // an init carries both, and reads back with the same checks as one off the wire
+        Some(msgs::Init {
+            features: self.features.clone(),
+            global_features: Vec::new(),
+            networks: self.networks.clone(),
+            remote_network_address: None,
+        })
+        .write(w)
+    }
+}
+
+impl Readable for PeerCapabilities {
+    fn read<R: io::Read>(r: &mut R) -> Result<Self, DecodeError> {
+        let version: u8 = Readable::read(r)?;
+        if version != CAPABILITIES_VERSION {
+            return Err(DecodeError::UnknownVersion);
+        }
+        let seen_at: u64 = Readable::read(r)?;
+        let init: Option<msgs::Init> = Readable::read(r)?;
+        let init = init.ok_or(DecodeError::InvalidValue)?;
+        Ok(Self {
+            seen_at: SystemTime::UNIX_EPOCH + Duration::from_secs(seen_at),
+            ..Self::from_init(&init)
+        })
+    }
+}
+
+/// Where a [`CapabilityCache`] keeps its entries. Implement it to persist them; calls come
+/// from whatever task reads a peer's `init`, so they should be quick.
+pub trait CapabilityStore: Send + Sync {
+    fn load(&self, node_id: &PublicKey) -> Option<PeerCapabilities>;
+
+    fn save(&self, node_id: &PublicKey, capabilities: &PeerCapabilities);
+
+    fn remove(&self, node_id: &PublicKey);
+}
+
+/// The [`CapabilityStore`] of [`CapabilityCache::new`], gone with the process.
+#[derive(Debug, Default)]
+pub struct MemoryStore(Mutex<HashMap<PublicKey, PeerCapabilities>>);
+
+impl CapabilityStore for MemoryStore {
+    fn load(&self, node_id: &PublicKey) -> Option<PeerCapabilities> {
+        self.0.lock().unwrap().get(node_id).cloned()
+    }
+
+    fn save(&self, node_id: &PublicKey, capabilities: &PeerCapabilities) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(*node_id, capabilities.clone());
+    }
+
+    fn remove(&self, node_id: &PublicKey) {
+        self.0.lock().unwrap().remove(node_id);
+    }
+}
+
+/// The capabilities of peers by node id, see the [module docs](self). Clones share the cache.
+#[derive(Clone)]
+pub struct CapabilityCache {
+    store: Arc<dyn CapabilityStore>,
+}
+
+impl Default for CapabilityCache {
+    fn default() -> Self {
+        Self::with_store(MemoryStore::default())
+    }
+}
+
+impl std::fmt::Debug for CapabilityCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CapabilityCache").finish_non_exhaustive()
+    }
+}
+
+impl CapabilityCache {
+    /// A cache in memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_store(store: impl CapabilityStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+        }
+    }
+
+    /// Remember what `node_id` said in its `init`, replacing what it said before.
+    pub fn record(&self, node_id: &PublicKey, init: &msgs::Init) {
+        self.store.save(node_id, &PeerCapabilities::from_init(init));
+    }
+
+    pub fn get(&self, node_id: &PublicKey) -> Option<PeerCapabilities> {
+        self.store.load(node_id)
+    }
+
+    /// Whether `node_id` advertised `bit`'s feature last time, `None` if it was never seen.
+    pub fn supports(&self, node_id: &PublicKey, bit: usize) -> Option<bool> {
+        self.get(node_id).map(|caps| caps.supports(bit))
+    }
+
+    pub fn forget(&self, node_id: &PublicKey) {
+        self.store.remove(node_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::testing::key;
+
+    fn init(features: Vec<u8>, global_features: Vec<u8>) -> msgs::Init {
+        msgs::Init {
+            features,
+            global_features,
+            networks: Some(vec![ChainHash::SIGNET]),
+            remote_network_address: None,
+        }
+    }
+
+    #[test]
+    fn capabilities_merge_global_features_and_round_trip() {
+        let mut features = Vec::new();
+        features::set(&mut features, features::ONION_MESSAGES_FEATURE_BIT - 1);
+        let caps = PeerCapabilities::from_init(&init(features, vec![0x20]));
+        assert!(caps.supports(features::ONION_MESSAGES_FEATURE_BIT));
+        assert!(caps.supports(5));
+        assert!(!caps.supports(8));
+        assert!(caps.on_network(ChainHash::SIGNET));
+        assert!(!caps.on_network(ChainHash::BITCOIN));
+
+        let decoded = PeerCapabilities::decode(&caps.encode()).unwrap();
+        assert_eq!(decoded.features, caps.features);
+        assert_eq!(decoded.networks, caps.networks);
+        let secs = |t: SystemTime| t.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(secs(decoded.seen_at), secs(caps.seen_at));
+
+        let mut bytes = caps.encode();
+        bytes[0] = 2;
+        assert!(matches!(
+            PeerCapabilities::decode(&bytes),
+            Err(DecodeError::UnknownVersion)
+        ));
+    }
+
+    #[test]
+    fn cache_remembers_the_last_init() {
+        let cache = CapabilityCache::new();
+        assert_eq!(cache.supports(&key(1), 5), None);
+        cache.record(&key(1), &init(vec![0x20], vec![]));
+        assert_eq!(cache.clone().supports(&key(1), 5), Some(true));
+        cache.record(&key(1), &init(vec![], vec![]));
+        assert_eq!(cache.supports(&key(1), 5), Some(false));
+        assert_eq!(cache.supports(&key(2), 5), None);
+        cache.forget(&key(1));
+        assert!(cache.get(&key(1)).is_none());
+    }
+}
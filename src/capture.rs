@@ -0,0 +1,309 @@
+//! Capture and replay of decrypted wire traffic.
+//!
+//! When a peer misbehaves it helps to have the exact messages it exchanged with us.
+//! [`LNSocket::start_capture`](crate::LNSocket::start_capture) logs every message read or
+//! written after decryption, with a timestamp, through a [`CaptureWriter`]; a
+//! [`CaptureReader`] reads the file back so the frames can go through the decoder again,
+//! offline:
+//!
+//! ```no_run
+//! use lnsocket::capture::{CaptureReader, CaptureWriter, Direction};
+//! # #[cfg(feature = "tokio")]
+//! # async fn ex(mut sock: lnsocket::LNSocket) -> Result<(), lnsocket::Error> {
+//! // commando commands carry the rune, keep them out of the file
+//! let writer = CaptureWriter::create("peer.jsonl")?.redact(|frame| frame.type_id != 0x4c4f);
+//! sock.start_capture(writer);
+//! // ... reproduce the problem ...
+//! sock.stop_capture()?;
+//!
+//! for frame in CaptureReader::open("peer.jsonl")? {
+//!     let frame = frame?;
+//!     if frame.direction == Direction::Inbound {
+//!         println!("{:?}", frame.decode());
+//!     }
+//! }
+//! # Ok(()) }
+//! ```
+//!
+//! The file has one JSON object per line, e.g.
+//! `{"ts_us":1700000000000000,"dir":"in","type":18,"payload":"0004..."}`, where `payload` is
+//! the hex message body after the type. Writes are synchronous and happen on the read and
+//! write paths of the socket, so buffer them (as [`CaptureWriter::create`] does) and keep
+//! capturing for debugging only.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::{self, Message};
+
+/// Which way a frame went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    #[serde(rename = "in")]
+    Inbound,
+    #[serde(rename = "out")]
+    Outbound,
+}
+
+/// One decrypted message, as captured.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapturedFrame {
+    pub at: SystemTime,
+    pub direction: Direction,
+    pub type_id: u16,
+    /// The message body after the type.
+    pub payload: Vec<u8>,
+}
+
+impl CapturedFrame {
+    /// Decode the frame the way [`LNSocket::read`](crate::LNSocket::read) would.
+    pub fn decode(&self) -> Result<Message<()>, DecodeError> {
+        self.decode_custom(|_, _| Ok(None))
+    }
+
+    /// Decode the frame the way [`LNSocket::read_custom`](crate::LNSocket::read_custom)
+    /// would, handing custom types to `handler`.
+    pub fn decode_custom<T: core::fmt::Debug>(
+        &self,
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Message<T>, DecodeError> {
+        wire::read_payload(&mut Cursor::new(&self.payload[..]), self.type_id, handler)
+    }
+}
+
+/// A line of the capture file.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    ts_us: u64,
+    dir: Direction,
+    #[serde(rename = "type")]
+    type_id: u16,
+    payload: String,
+}
+
+type RedactHook = Box<dyn FnMut(&mut CapturedFrame) -> bool + Send>;
+
+/// Appends captured frames to a file or any other `Write`, see the [module docs](self).
+pub struct CaptureWriter {
+    out: Box<dyn Write + Send>,
+    redact: Option<RedactHook>,
+}
+
+impl CaptureWriter {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Box::new(out),
+            redact: None,
+        }
+    }
+
+    /// Capture to `path` through a buffer, appending if the file exists.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+
+    /// Run `hook` on every frame before it is written. It may blank or rewrite the payload
+    /// (runes, invoices, ...), and the frame is left out of the capture if it returns `false`.
+    pub fn redact(mut self, hook: impl FnMut(&mut CapturedFrame) -> bool + Send + 'static) -> Self {
+        self.redact = Some(Box::new(hook));
+        self
+    }
+
+    pub fn record(&mut self, mut frame: CapturedFrame) -> Result<(), Error> {
+        if let Some(redact) = &mut self.redact
+            && !redact(&mut frame)
+        {
+            return Ok(());
+        }
+        let ts = frame.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = Line {
+            ts_us: ts.as_micros() as u64,
+            dir: frame.direction,
+            type_id: frame.type_id,
+            payload: hex::encode(&frame.payload),
+        };
+        serde_json::to_writer(&mut self.out, &line).map_err(|_| Error::Json)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.out.flush()?)
+    }
+}
+
+/// A frame as a frame hook sees it: what went by and when, without the payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub type_id: u16,
+    /// The plaintext length, type included.
+    pub len: usize,
+    pub at: SystemTime,
+}
+
+pub(crate) type FrameHook = Box<dyn FnMut(&FrameInfo) + Send>;
+
+/// What a socket and its writer task look at every frame with.
+#[derive(Default)]
+pub(crate) struct Taps {
+    /// `None` when not capturing.
+    pub writer: Option<CaptureWriter>,
+    pub on_sent: Option<FrameHook>,
+    pub on_received: Option<FrameHook>,
+}
+
+pub(crate) type SharedCapture = Arc<Mutex<Taps>>;
+
+/// Hand a frame to the hook for its direction, and record it if capturing. A failing writer
+/// is dropped so traffic isn't held up by it.
+pub(crate) fn capture(shared: &SharedCapture, direction: Direction, type_id: u16, payload: &[u8]) {
+    let mut taps = shared.lock().unwrap();
+    let at = SystemTime::now();
+    let hook = match direction {
+        Direction::Inbound => &mut taps.on_received,
+        Direction::Outbound => &mut taps.on_sent,
+    };
+    if let Some(hook) = hook {
+        hook(&FrameInfo {
+            type_id,
+            len: payload.len() + 2,
+            at,
+        });
+    }
+
+    let Some(writer) = taps.writer.as_mut() else {
+        return;
+    };
+    let frame = CapturedFrame {
+        at,
+        direction,
+        type_id,
+        payload: payload.to_vec(),
+    };
+    if let Err(err) = writer.record(frame) {
+        tracing::warn!("stopping wire capture: {err}");
+        taps.writer = None;
+    }
+}
+
+/// Reads the frames of a capture file back, in order.
+pub struct CaptureReader<R> {
+    lines: io::Lines<R>,
+}
+
+impl<R: BufRead> CaptureReader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            lines: input.lines(),
+        }
+    }
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> Iterator for CaptureReader<R> {
+    /// `Error::Json` for a line that isn't a captured frame.
+    type Item = Result<CapturedFrame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(parse_line(&line));
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Result<CapturedFrame, Error> {
+    let line: Line = serde_json::from_str(line).map_err(|_| Error::Json)?;
+    Ok(CapturedFrame {
+        at: UNIX_EPOCH + Duration::from_micros(line.ts_us),
+        direction: line.dir,
+        type_id: line.type_id,
+        payload: hex::decode(&line.payload).map_err(|_| Error::Json)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs;
+    use crate::ln::wire::Encode;
+    use crate::util::ser::Writeable;
+
+    /// A `Write` the test can look into after handing it to the capture.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Write::write(&mut *self.0.lock().unwrap(), buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn capture_round_trips_through_the_decoder() {
+        let buf = SharedBuf::default();
+        let shared = SharedCapture::default();
+        shared.lock().unwrap().writer = Some(CaptureWriter::new(buf.clone()).redact(|frame| {
+            if frame.type_id == 0x4c4f {
+                frame.payload.clear();
+            }
+            frame.type_id != msgs::Pong::TYPE
+        }));
+
+        let ping = msgs::Ping {
+            ponglen: 4,
+            byteslen: 2,
+        };
+        capture(
+            &shared,
+            Direction::Outbound,
+            msgs::Ping::TYPE,
+            &ping.encode(),
+        );
+        capture(
+            &shared,
+            Direction::Inbound,
+            msgs::Pong::TYPE,
+            &[0, 4, 0, 0, 0, 0],
+        );
+        capture(&shared, Direction::Outbound, 0x4c4f, b"secret rune");
+
+        let contents = buf.0.lock().unwrap().clone();
+        let frames: Vec<CapturedFrame> = CaptureReader::new(&contents[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].direction, Direction::Outbound);
+        assert!(matches!(frames[0].decode(), Ok(Message::Ping(p)) if p == ping));
+        assert_eq!(frames[1].type_id, 0x4c4f);
+        assert!(frames[1].payload.is_empty());
+
+        assert!(matches!(
+            CaptureReader::new(&b"\n{\"nope\":1}\n"[..]).next(),
+            Some(Err(Error::Json))
+        ));
+    }
+}
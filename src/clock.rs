@@ -0,0 +1,262 @@
+//! Where timeouts, keepalives and sweeps get the time.
+//!
+//! The [`CommandoClient`](crate::CommandoClient)'s call deadlines, idle hook, connection
+//! rotation, reply cache and reconnect backoff, and the timeouts of a
+//! [`RequestResponse`](crate::reqresp::RequestResponse), ask a [`Clock`] for the time and
+//! for sleeps. The default, [`TokioClock`], is tokio's timer, so tests that pause tokio's
+//! time run them deterministically:
+//!
+//! ```no_run
+//! # #[cfg(feature = "tokio")]
+//! # async fn ex(client: lnsocket::CommandoClient) {
+//! // in a #[tokio::test(start_paused = true)]
+//! let call = tokio::spawn(async move { client.call("getinfo", serde_json::json!({})).await });
+//! tokio::time::advance(std::time::Duration::from_secs(31)).await;
+//! // the 30s default timeout has passed, without waiting for it
+//! # }
+//! ```
+//!
+//! A socket's own timers, its write linger and stall watch, quota windows, ping rate limit
+//! and [probes](crate::LNSocket::ping_probe), follow the clock set with
+//! [`LNSocket::set_clock`](crate::LNSocket::set_clock).
+//!
+//! Environments without tokio's timer, such as wasm, implement [`Clock`] on theirs and hand
+//! it to [`CommandoConfig::clock`](crate::commando::CommandoConfig::clock) and the socket.
+//! Times are [`Instant`]s of the clock's own making, as `std::time::Instant` panics on
+//! `wasm32-unknown-unknown`.
+
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A point in time of a [`Clock`]: how long after the clock's origin, which the clock picks.
+/// Instants of different clocks don't compare.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub const fn from_origin(elapsed: Duration) -> Self {
+        Self(elapsed)
+    }
+
+    pub const fn since_origin(self) -> Duration {
+        self.0
+    }
+
+    /// How long after `earlier` this is, zero if it isn't.
+    pub fn saturating_duration_since(self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
+/// Saturates rather than overflow, so a huge timeout is just never reached.
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(rhs))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+/// Saturates at the clock's origin.
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_sub(rhs))
+    }
+}
+
+/// Zero if `rhs` is later, like `std::time::Instant`.
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.saturating_duration_since(rhs)
+    }
+}
+
+/// What [`Clock::sleep_until`] returns.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A source of time and sleeps.
+pub trait Clock: Send + Sync + 'static {
+    /// The time, which never goes backwards.
+    fn now(&self) -> Instant;
+
+    /// Resolves once [`Clock::now`] reaches `deadline`, at once if it has.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+/// Tokio's timer, which follows `tokio::time::pause` and `advance`. Its origin is when it
+/// was made.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug)]
+pub struct TokioClock {
+    origin: tokio::time::Instant,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioClock {
+    pub fn new() -> Self {
+        Self {
+            origin: tokio::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant(tokio::time::Instant::now().saturating_duration_since(self.origin))
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        match self.origin.checked_add(deadline.0) {
+            Some(deadline) => Box::pin(tokio::time::sleep_until(deadline)),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+}
+
+/// A [`Clock`] to share between clones of a config.
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl Clock for SharedClock {
+    fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        self.0.sleep_until(deadline)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self::new(TokioClock::new())
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedClock")
+    }
+}
+
+impl SharedClock {
+    pub fn new(clock: impl Clock) -> Self {
+        Self(Arc::new(clock))
+    }
+
+    pub fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    /// How long ago `earlier` was, zero if it is yet to come.
+    pub fn since(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+
+    pub fn sleep_until(&self, deadline: Instant) -> Sleep {
+        self.0.sleep_until(deadline)
+    }
+
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+
+    /// `fut`'s output, or `None` if `deadline` came first.
+    #[cfg(feature = "tokio")]
+    pub async fn timeout_at<F: Future>(&self, deadline: Instant, fut: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            out = fut => Some(out),
+            _ = self.sleep_until(deadline) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// A clock that moves only when told to. Its sleeps that aren't over at once never end.
+    #[derive(Clone, Default)]
+    pub(crate) struct ManualClock(Arc<Mutex<Duration>>);
+
+    impl ManualClock {
+        pub(crate) fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            Instant(*self.0.lock().unwrap())
+        }
+
+        fn sleep_until(&self, deadline: Instant) -> Sleep {
+            if self.now() >= deadline {
+                Box::pin(std::future::ready(()))
+            } else {
+                Box::pin(std::future::pending())
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn tokio_clock_follows_paused_time() {
+        let clock = SharedClock::default();
+        let start = clock.now();
+        let sleep = clock.sleep(Duration::from_secs(3600));
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        sleep.await;
+        assert_eq!(clock.since(start), Duration::from_secs(3600));
+
+        let deadline = clock.now() + Duration::from_secs(5);
+        assert_eq!(clock.timeout_at(deadline, async { 1 }).await, Some(1));
+        let never = std::future::pending::<()>();
+        assert_eq!(clock.timeout_at(deadline, never).await, None);
+        assert_eq!(clock.now(), deadline);
+    }
+
+    #[test]
+    fn instants_saturate() {
+        let at = Instant::from_origin(Duration::from_secs(5));
+        assert_eq!(at - Duration::from_secs(9), Instant::default());
+        assert_eq!(at - (at + Duration::from_secs(1)), Duration::ZERO);
+        assert_eq!((at + Duration::MAX).since_origin(), Duration::MAX);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_far_deadline_never_comes() {
+        let clock = SharedClock::default();
+        let never = clock.now() + Duration::MAX;
+        let sleep = clock.timeout_at(never, clock.sleep(Duration::from_secs(1)));
+        assert_eq!(sleep.await, Some(()));
+    }
+}
@@ -10,38 +10,169 @@
 //!   - `reconnect`: Auto { max_attempts: 10, base_backoff: 200ms, max_backoff: 5s }
 //!   - `retry_policy`: Always { max_retries: 3 }  // ← adjust if you prefer Never by default
 //! - Per-call overrides via `CallOpts` (`retry()`, `timeout()`, `rune()`).
+//! - Request ids are random 64-bit numbers unless [`CommandoConfig::random_ids`] says to
+//!   count. The JSON `id` of a reply is lightningd's own and is not checked against them.
+//! - The client counts its connections, reconnects and replaced sockets included. When one
+//!   ends, the ids of the commands written on it are retired: calls resent on the next
+//!   connection get a new id, and replies to a retired id are dropped for two more
+//!   connections. The node still sends replies to commands of the old connection, which
+//!   would otherwise mix with the replies to their resends.
+//! - A timeout is a deadline the pump enforces too: when it passes, the call is dropped from
+//!   the pump, it is not resent, and the rest of its reply is thrown away as it arrives
+//!   instead of being buffered for nobody. Commando has no way to cancel a command on the
+//!   node, so the node still runs it to completion.
 //!
 //! ### Reconnect behavior
 //! - On `BrokenPipe`, pending in-flight calls are **classified** by their `RetryPolicy`:
 //!   eligible ones are queued (attempts++ and their partial buffers cleared), others
-//!   fail immediately with `Error::Io(BrokenPipe)`.
+//!   fail immediately with `Error::PumpTerminated`.
 //! - After a successful reconnect, queued calls are **resent FIFO**. On the first resend
 //!   failure, the remainder is preserved in order for the next reconnect cycle.
 //!
+//! ### Replacing the socket
+//! - If the pump gives up (reconnect exhausted or `ReconnectMode::Never`), hand it a freshly
+//!   connected socket with [`CommandoClient::replace_socket`]. Only the calls in flight on the
+//!   old socket fail; the client keeps its id counter, default rune and config.
+//!
+//! ### Compression
+//! - With the `compression` cargo feature, `init` advertises the odd custom feature bit
+//!   [`COMMANDO_COMPRESSION_FEATURE_BIT`]. When the peer advertises it too, large commands
+//!   are gzip-compressed; gzip replies are detected by their magic bytes and inflated.
+//! - Stock CLN never sets the bit, so against it everything stays plain JSON.
+//!
+//! ### Shutdown
+//! - [`CommandoClient::close`] stops accepting calls, lets the ones in flight finish and
+//!   waits for the pump to exit. [`CommandoClient::closed`] just waits for the exit.
+//! - Once the pump is gone, for whatever reason, calls fail with
+//!   `Error::PumpExited(reason)`; a panic in the pump shows up as `PumpExit::Panicked`.
+//! - Calls the connection ends for fail with `Error::PumpTerminated`, which classifies the
+//!   end (EOF, timeout, bad message) and counts the calls and partial replies lost with it.
+//!   An `error` from the peer about all channels fails them with
+//!   `Error::PeerClosedConnection` instead.
+//!
+//! ### Typed replies
+//! - [`CommandoClient::call_typed`] decodes a reply into any `Deserialize` type; the
+//!   [`crate::rpc`] module has reply types and wrappers for bookkeeper and plugin methods.
+//!
+//! ### Large replies
+//! - [`CommandoClient::call`] builds a `serde_json::Value` tree of the whole reply, which
+//!   takes several times the size of the JSON. For replies of many megabytes (`listforwards`,
+//!   `listinvoices` on a busy node) pick another mode per call:
+//!   - [`CommandoClient::call_typed`] deserializes straight from the reply bytes into your
+//!     type, with no intermediate tree.
+//!   - [`CommandoClient::call_raw`] hands back the JSON-RPC response bytes unparsed, for a
+//!     streaming deserializer of your choice.
+//!   - [`CommandoClient::call_with_sink`] passes each fragment to a callback as it arrives,
+//!     so the reply is never held in memory at all.
+//! - [`CallOpts::on_progress`] reports bytes, fragments and time so far as a reply comes in,
+//!   in any mode, and can give up on the call before its last fragment.
+//!
+//! ### Reply cache
+//! - [`CommandoConfig::cache_ttl`] caches the replies of a method for a while, so that a
+//!   dashboard polling `getinfo` every few seconds doesn't go to the node over Tor each
+//!   time. Replies are cached by method, params, filter and rune; errors never are.
+//! - [`CallOpts::cache`] skips or refreshes the cache for one call, and
+//!   [`CommandoClient::invalidate_cache`] drops the entries of a method, e.g. after a call
+//!   that changes what it returns.
+//!
+//! ### Probing
+//! - [`probe`] tries a rune on a fresh connection before handing it to a client, and says
+//!   whether the rune works, is refused or only restricted, or the node has no commando.
+//!
+//! ### Idle connections
+//! - [`CommandoConfig::on_idle`] installs a hook that runs when the connection has been quiet
+//!   for a while and decides whether to ping, hang up or do nothing.
+//!
+//! ### Transports
+//! - The pump talks to the node through a [`MessageTransport`]. [`LNSocket`] is the one you
+//!   want in production; tests can hand [`CommandoClient::spawn`] a fake that plays the node.
+//! - Over an [`LNSocket`] the pump keeps reading while a command is being written, so a
+//!   large command to a node that is itself stuck writing a large reply doesn't deadlock,
+//!   and pings keep being answered meanwhile. Other transports are written to in line.
+//!
+//! ### Connection lifetime
+//! - [`CommandoConfig::max_lifetime`] replaces connections older than a limit, e.g. ones
+//!   over Tor circuits that degrade over hours. The pump waits until no call is in flight,
+//!   reconnects, and reports it on [`CommandoClient::rotations`].
+//! - [`CommandoClient::rekey_identity`] replaces the connection the same way with one made
+//!   with a new key of ours, for services that rotate their node identity. The old
+//!   connection stays until the new one is up, and stays for good if it can't be made.
+//!
+//! ### Method runes
+//! - Least-privilege setups use several runes: one restricted to `list*` methods, another
+//!   that may only `pay`. [`CommandoClient::add_rune`] registers a rune for a method name or
+//!   prefix pattern, and calls to matching methods use it instead of the client's rune. The
+//!   most specific pattern wins, and [`CallOpts::rune`] still overrides all of them.
+//!
+//! ### Rune renewal
+//! - [`CommandoConfig::rune_provider`] installs an async callback that fetches or mints a
+//!   new rune. When a call made with the client's rune fails because the rune expired, the
+//!   client asks the provider for a new one, keeps it for later calls and retries the call
+//!   once. Calls that bring their own rune ([`CallOpts::rune`]) or use a method rune are
+//!   left alone.
+//!
+//! ### Validation
+//! - [`CommandoConfig::validate_params`] and [`CommandoConfig::validate_result`] check what
+//!   goes to and comes back from a method, so that a CLN upgrade that changes its API fails
+//!   calls with `Error::Validation` instead of going unnoticed. See [`crate::validation`].
+//!
+//! ### Notifications
+//! - Reply bodies that are JSON-RPC notifications (a `method` and no `id`) are not treated as
+//!   call results; they go to every [`NotificationStream`] from
+//!   [`CommandoClient::notifications`]. See [`crate::notifications`].
+//!
+//! ### Tracing
+//! - Every call gets a `commando_call` span created by the pump with `req_id`, `method` and
+//!   `peer_id`; `bytes`, `chunks` and `duration` are recorded as the reply arrives, so slow
+//!   RPCs can be correlated with network events.
+//!
 //! ### Error model
 //! - `Error::Io(io::ErrorKind)` (incl. `TimedOut`, `BrokenPipe`), `Error::Json`,
 //!   `Error::Decode`, `Error::Lightning`, `Error::DnsError`, etc.
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use serde::de::IgnoredAny;
 use serde_json::Value;
-use tokio::sync::{mpsc, oneshot};
-use tokio::time::timeout;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 
+use crate::CLNErrorCode;
 use crate::Error;
 use crate::LNSocket;
+use crate::MessageSender;
+use crate::PumpExit;
+use crate::PumpTermination;
 use crate::RpcError;
+use crate::TerminationCause;
+#[cfg(feature = "cancel")]
+use crate::cancel::{CancellationToken, or_cancelled};
+use crate::clock::{Clock, Instant, SharedClock};
 use crate::ln::msgs;
-use crate::ln::msgs::DecodeError;
-use crate::ln::wire::{Message, Type};
-use crate::util::ser::{LengthLimitedRead, Readable, Writeable, Writer};
+use crate::ln::wire::{self, Message, Type};
+use crate::notifications::{NOTIFICATION_BUFFER, Notification, NotificationStream};
+use crate::ping::MAX_PONGLEN;
+use crate::sender::Frame;
+use crate::stats::{StatsHandle, WireStats};
+use crate::util::ser::Writeable;
+use crate::validation::{Payload, Validator, Validators};
+use bitcoin::secp256k1::rand::{self, Rng};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
 
-pub const COMMANDO_COMMAND: u16 = 0x4c4f;
-pub const COMMANDO_REPLY_CONT: u16 = 0x594b;
-pub const COMMANDO_REPLY_TERM: u16 = 0x594d;
+#[cfg(feature = "compression")]
+use crate::commando_protocol::CompressedCommand;
+pub use crate::commando_protocol::{
+    COMMANDO_COMMAND, COMMANDO_COMMAND_CONT, COMMANDO_COMPRESSION_FEATURE_BIT, COMMANDO_REPLY_CONT,
+    COMMANDO_REPLY_TERM, CommandoCommand, CommandoCommandChunk, CommandoReplyChunk,
+    IncomingCommandoMessage, read_incoming_commando_message,
+};
+use crate::commando_protocol::{
+    CommandoEvent, CommandoProtocol, ReplyAssembler, decode_reply, maybe_decompress, split_command,
+};
 
 #[derive(Clone, Copy, Debug)]
 pub enum RetryPolicy {
@@ -49,15 +180,535 @@ pub enum RetryPolicy {
     Always { max_retries: usize },
 }
 
+/// What the pump needs from a connection: reading and writing message frames, and making a
+/// fresh connection to the same node for reconnects.
+///
+/// [`LNSocket`] is the real one. Implement it for a fake to test code built on
+/// [`CommandoClient`] without a node, or to run commando over another transport.
+pub trait MessageTransport: Send + Sized + 'static {
+    /// The next message, as its type and payload.
+    fn read_frame(&mut self) -> impl Future<Output = Result<(u16, Vec<u8>), Error>> + Send;
+
+    fn write_frame(&mut self, frame: Frame) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// A new connection to the same node, replacing this one after it broke or got old.
+    fn reconnect(&self) -> impl Future<Output = Result<Self, Error>> + Send;
+
+    /// Like [`MessageTransport::reconnect`], as `our_key` from now on, see
+    /// [`CommandoClient::rekey_identity`]. Unsupported unless implemented.
+    fn reconnect_as(
+        &self,
+        _our_key: SecretKey,
+    ) -> impl Future<Output = Result<Self, Error>> + Send {
+        async { Err(Error::Io(std::io::ErrorKind::Unsupported)) }
+    }
+
+    fn their_pubkey(&self) -> PublicKey;
+
+    fn our_node_id(&self) -> PublicKey;
+
+    /// Whether both sides negotiated feature `bit`, see [`LNSocket::peer_supports_feature`].
+    fn peer_supports_feature(&self, _bit: usize) -> bool {
+        false
+    }
+
+    /// A handle for the [`IdleContext`], if the transport has one.
+    fn sender(&self) -> Option<MessageSender> {
+        None
+    }
+
+    /// The clock of the transport's timers, tokio's unless it has its own, see
+    /// [`LNSocket::set_clock`]. [`probe`] waits on it.
+    fn clock(&self) -> SharedClock {
+        SharedClock::default()
+    }
+
+    /// The connection's wire stats, if the transport keeps any, see
+    /// [`CommandoClient::stats`].
+    fn stats_handle(&self) -> Option<StatsHandle> {
+        None
+    }
+
+    /// Answer a `ping` the pump read. By default every ping BOLT #1 allows to be answered
+    /// is, see [`LNSocket::answer_ping`] for the rate-limited version.
+    ///
+    /// The pump reads nothing until this resolves, so it should not wait for a write held up
+    /// by the peer. [`LNSocket`] just queues the pong.
+    fn answer_ping(&mut self, ping: &msgs::Ping) -> impl Future<Output = Result<(), Error>> + Send {
+        let pong = (ping.ponglen <= MAX_PONGLEN).then(|| {
+            Frame::new(&msgs::Pong {
+                byteslen: ping.ponglen,
+            })
+        });
+        async move {
+            match pong {
+                Some(pong) => self.write_frame(pong?).await,
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+impl MessageTransport for LNSocket {
+    fn read_frame(&mut self) -> impl Future<Output = Result<(u16, Vec<u8>), Error>> + Send {
+        self.read_raw()
+    }
+
+    fn write_frame(&mut self, frame: Frame) -> impl Future<Output = Result<(), Error>> + Send {
+        self.write_frames(vec![frame])
+    }
+
+    fn reconnect(&self) -> impl Future<Output = Result<Self, Error>> + Send {
+        self.reconnect_fresh()
+    }
+
+    fn reconnect_as(&self, our_key: SecretKey) -> impl Future<Output = Result<Self, Error>> + Send {
+        self.reconnect_fresh_as(our_key)
+    }
+
+    fn their_pubkey(&self) -> PublicKey {
+        LNSocket::their_pubkey(self)
+    }
+
+    fn our_node_id(&self) -> PublicKey {
+        LNSocket::our_node_id(self)
+    }
+
+    fn peer_supports_feature(&self, bit: usize) -> bool {
+        LNSocket::peer_supports_feature(self, bit)
+    }
+
+    fn answer_ping(&mut self, ping: &msgs::Ping) -> impl Future<Output = Result<(), Error>> + Send {
+        // a failed write shows up on the next read
+        tokio::spawn(LNSocket::answer_ping(self, ping));
+        async { Ok(()) }
+    }
+
+    fn sender(&self) -> Option<MessageSender> {
+        Some(LNSocket::sender(self))
+    }
+
+    fn clock(&self) -> SharedClock {
+        LNSocket::clock(self)
+    }
+
+    fn stats_handle(&self) -> Option<StatsHandle> {
+        Some(LNSocket::stats_handle(self))
+    }
+}
+
+/// Write `msg` as a single frame.
+async fn write_message<T: MessageTransport, M: Type + Writeable>(
+    sock: &mut T,
+    msg: &M,
+) -> Result<(), Error> {
+    sock.write_frame(Frame::new(msg)?).await
+}
+
+/// Read the next message, decoding commando replies.
+async fn read_message<T: MessageTransport>(
+    sock: &mut T,
+) -> Result<Message<IncomingCommandoMessage>, Error> {
+    let (type_id, payload) = sock.read_frame().await?;
+    Ok(wire::read_payload(
+        &mut std::io::Cursor::new(&payload[..]),
+        type_id,
+        read_incoming_commando_message,
+    )?)
+}
+
 // Control messages to the pump task
-enum Ctrl {
+enum Ctrl<T> {
     Start {
         cmd: CommandoCommand,
         policy: RetryPolicy,
-        done_tx: oneshot::Sender<Result<Value, Error>>,
+        mode: ReplyMode,
+        done_tx: oneshot::Sender<Result<ReplyBody, Error>>,
+        deadline: Option<Instant>,
+        progress: Option<ProgressHook>,
     },
+    ReplaceSocket(Box<T>),
+    /// Reconnect as a new identity once nothing is in flight.
+    Rekey {
+        key: SecretKey,
+        done_tx: oneshot::Sender<Result<(), Error>>,
+    },
+    /// Stop accepting calls and exit once the ones in flight are done.
+    Close,
+    /// A caller stopped waiting for its call: drop the calls nobody waits for any more.
+    #[cfg(feature = "cancel")]
+    Abandoned,
+}
+
+/// The client's handles on one pump task.
+struct PumpHandle<T> {
+    tx: mpsc::Sender<Ctrl<T>>,
+    /// Set once the task has exited.
+    exit: watch::Receiver<Option<PumpExit>>,
+}
+
+impl<T> Clone for PumpHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            exit: self.exit.clone(),
+        }
+    }
+}
+
+impl<T> PumpHandle<T> {
+    async fn wait_exit(mut self) -> PumpExit {
+        match self.exit.wait_for(Option::is_some).await {
+            Ok(exit) => exit.clone().expect("waited for Some"),
+            // the watcher task was dropped with the runtime
+            Err(_) => PumpExit::Closed,
+        }
+    }
+}
+
+/// Calls in flight and reply bytes buffered, shared by the client and its pumps, and the
+/// connection they are on.
+#[derive(Default)]
+pub(crate) struct Load {
+    in_flight: AtomicUsize,
+    buffered: AtomicUsize,
+    conn: Mutex<Option<ConnInfo>>,
+    /// The last request id, when they are counted.
+    next_id: AtomicU64,
+    /// The connections the client's pumps have had.
+    connections: AtomicU64,
+}
+
+/// What the client tells about the pump's current connection.
+#[derive(Clone)]
+pub(crate) struct ConnInfo {
+    pub(crate) their_pubkey: PublicKey,
+    pub(crate) stats: Option<StatsHandle>,
+}
+
+/// Counts a call as in flight until dropped.
+struct CallSlot<'a>(&'a Load);
+
+impl Load {
+    /// Tell the client about a new connection of the pump.
+    fn connected<T: MessageTransport>(&self, sock: &T) {
+        *self.conn.lock().unwrap() = Some(ConnInfo {
+            their_pubkey: sock.their_pubkey(),
+            stats: sock.stats_handle(),
+        });
+    }
+
+    pub(crate) fn conn(&self) -> ConnInfo {
+        self.conn
+            .lock()
+            .unwrap()
+            .clone()
+            .expect("set when the pump is spawned")
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// A new request id, see [`CommandoConfig::random_ids`].
+    fn alloc_id(&self, random: bool) -> u64 {
+        if random {
+            return rand::thread_rng().r#gen();
+        }
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The epoch of the client's next connection.
+    fn next_epoch(&self) -> Epoch {
+        Epoch(self.connections.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Admit a call, unless a limit of `cfg` is reached.
+    fn admit(&self, cfg: &CommandoConfig) -> Result<CallSlot<'_>, Error> {
+        if cfg
+            .max_buffered
+            .is_some_and(|max| self.buffered.load(Ordering::Relaxed) >= max)
+        {
+            return Err(Error::Overloaded);
+        }
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let slot = CallSlot(self);
+        if cfg.max_in_flight.is_some_and(|max| in_flight >= max) {
+            return Err(Error::Overloaded);
+        }
+        Ok(slot)
+    }
+}
+
+impl Drop for CallSlot<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Which of the client's connections the pump is on, see the [module docs](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Epoch(u64);
+
+/// The pump's connection, and the request ids retired on earlier ones.
+struct Epochs {
+    current: Epoch,
+    /// Request ids whose replies are dropped, and the connection they were retired on.
+    retired: HashMap<u64, Epoch>,
+}
+
+impl Epochs {
+    fn new(current: Epoch) -> Self {
+        Epochs {
+            current,
+            retired: HashMap::new(),
+        }
+    }
+
+    /// Drop the replies to `ids`, whose commands were written on the current connection.
+    fn retire(&mut self, ids: impl IntoIterator<Item = u64>) {
+        let current = self.current;
+        self.retired.extend(ids.into_iter().map(|id| (id, current)));
+    }
+
+    /// Move on to the client's next connection, forgetting ids retired two connections ago.
+    fn next(&mut self, load: &Load) {
+        self.current = load.next_epoch();
+        let current = self.current.0;
+        self.retired.retain(|_, epoch| epoch.0 + 2 >= current);
+    }
+
+    /// Whether a reply to `req_id` is dropped; its last chunk unretires the id.
+    fn is_retired(&mut self, req_id: u64, done: bool) -> bool {
+        if done {
+            self.retired.remove(&req_id).is_some()
+        } else {
+            self.retired.contains_key(&req_id)
+        }
+    }
+}
+
+/// How a call uses the reply cache, see [`CommandoConfig::cache_ttl`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Answer from the cache while the reply there is fresh, and cache the reply otherwise.
+    #[default]
+    Use,
+    /// Go to the node, and cache its reply in place of the one there.
+    Refresh,
+    /// Go to the node and leave the cache alone.
+    Bypass,
+}
+
+/// Most replies [`ReplyCache`] holds; once full, replies aren't cached until entries expire.
+const MAX_CACHED_REPLIES: usize = 1024;
+
+/// What a cached reply answers. Replies for another rune may be refused by the node, so
+/// the rune is part of it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    method: String,
+    params: String,
+    filter: Option<String>,
+    rune: String,
+    raw: bool,
+}
+
+#[derive(Default)]
+struct ReplyCache {
+    entries: Mutex<HashMap<CacheKey, (Instant, ReplyBody)>>,
 }
 
+impl ReplyCache {
+    fn get(&self, key: &CacheKey, now: Instant) -> Option<ReplyBody> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((expires, body)) if *expires > now => Some(body.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: CacheKey, now: Instant, ttl: Duration, body: ReplyBody) {
+        if let ReplyBody::Raw(bytes) = &body {
+            // raw replies carry their RPC errors in the bytes
+            #[derive(Deserialize)]
+            struct Probe {
+                error: Option<serde::de::IgnoredAny>,
+            }
+            if !matches!(serde_json::from_slice(bytes), Ok(Probe { error: None })) {
+                return;
+            }
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_CACHED_REPLIES {
+            entries.retain(|_, (expires, _)| *expires > now);
+        }
+        if entries.len() < MAX_CACHED_REPLIES || entries.contains_key(&key) {
+            entries.insert(key, (now + ttl, body));
+        }
+    }
+
+    fn invalidate(&self, method: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|key, _| key.method != method);
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// What the node thought of a call, see [`CommandoClient::check`].
+#[derive(Clone, Debug)]
+pub enum CheckOutcome {
+    /// The parameters are valid and the rune allows the call.
+    Ok,
+    /// The parameters are missing, unknown or malformed.
+    InvalidParams(RpcError),
+    /// The node has no such command.
+    UnknownCommand(RpcError),
+    /// The rune does not allow this call.
+    Unauthorized(RpcError),
+    /// Any other refusal.
+    Other(RpcError),
+}
+
+impl CheckOutcome {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, CheckOutcome::Ok)
+    }
+
+    fn from_rpc_error(err: RpcError) -> Self {
+        match err.kind() {
+            CLNErrorCode::InvalidParams => CheckOutcome::InvalidParams(err),
+            CLNErrorCode::MethodNotFound => CheckOutcome::UnknownCommand(err),
+            _ if err.is_rune_denied() => CheckOutcome::Unauthorized(err),
+            _ => CheckOutcome::Other(err),
+        }
+    }
+}
+
+/// What [`probe`] found out about a node and a rune.
+#[derive(Clone, Debug)]
+pub enum ProbeOutcome {
+    /// The rune works: `getinfo` came back, with the node's answer.
+    RuneValid(Value),
+    /// The node made the rune but it doesn't allow `getinfo`, e.g. it is restricted to
+    /// other methods. It may well do for the calls it was made for.
+    RuneRestricted(RpcError),
+    /// The node refused the rune: it is malformed, expired, revoked, made by another node
+    /// or for another node id of ours.
+    RuneDenied(RpcError),
+    /// The rune was accepted but `getinfo` failed anyway.
+    Failed(RpcError),
+    /// Nothing came back in time. The node doesn't run commando (CLN without the plugin,
+    /// or another implementation), as nodes ignore message types they don't know.
+    NoCommando,
+}
+
+impl ProbeOutcome {
+    fn from_rpc_error(err: RpcError) -> Self {
+        // an expired rune fails a restriction too, but won't do for any call
+        let restricted = match err.kind() {
+            CLNErrorCode::RuneNotPermitted => true,
+            CLNErrorCode::RuneNotAuthorized => false,
+            // commando's own code is used for both, only the message tells them apart
+            CLNErrorCode::RuneCheckFailed => err.message.contains("Not permitted"),
+            _ => return ProbeOutcome::Failed(err),
+        };
+        if restricted && !err.is_rune_expired() {
+            ProbeOutcome::RuneRestricted(err)
+        } else {
+            ProbeOutcome::RuneDenied(err)
+        }
+    }
+}
+
+/// Find out whether `rune` will do on a freshly connected `sock`, by calling `getinfo` with
+/// it and telling apart the ways it can go wrong, for onboarding screens that want to say
+/// more than "timed out" when a user pastes a bad rune. The socket is left for
+/// [`CommandoClient::spawn`].
+///
+/// `timeout` is how long to wait for the node before deciding it has no commando: a node
+/// answers in well under a second, over Tor in a few. Only errors of the connection are
+/// `Err`.
+pub async fn probe<T: MessageTransport>(
+    sock: &mut T,
+    rune: &str,
+    timeout: Duration,
+) -> Result<ProbeOutcome, Error> {
+    let mut commando = CommandoProtocol::new(rune);
+    let req = commando.request("getinfo", serde_json::json!({}));
+    write_command(sock, req.command()).await?;
+
+    let clock = sock.clock();
+    let deadline = clock.now() + timeout;
+    loop {
+        let Some(frame) = clock.timeout_at(deadline, sock.read_frame()).await else {
+            return Ok(ProbeOutcome::NoCommando);
+        };
+        let (type_id, payload) = frame?;
+        match commando.handle_message(type_id, &payload)? {
+            Some(CommandoEvent::Reply { result, .. }) => {
+                return Ok(match result {
+                    Ok(info) => ProbeOutcome::RuneValid(info),
+                    Err(Error::Rpc(err)) => ProbeOutcome::from_rpc_error(err),
+                    Err(err) => return Err(err),
+                });
+            }
+            Some(CommandoEvent::Notification { .. }) => {}
+            None => {
+                let msg = wire::read_payload(&mut &payload[..], type_id, |_, _| {
+                    Ok(None::<IncomingCommandoMessage>)
+                });
+                if let Ok(Message::Ping(ping)) = msg {
+                    sock.answer_ping(&ping).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Params for CLN's `check`: the original params with the method to check added in front.
+fn check_params(method: String, params: Value) -> Result<Value, Error> {
+    match params {
+        Value::Null => Ok(serde_json::json!({ "command_to_check": method })),
+        Value::Object(mut obj) => {
+            obj.insert("command_to_check".to_string(), Value::String(method));
+            Ok(Value::Object(obj))
+        }
+        Value::Array(mut arr) => {
+            arr.insert(0, Value::String(method));
+            Ok(Value::Array(arr))
+        }
+        _ => Err(Error::Json),
+    }
+}
+
+/// A scheduled reconnect, see [`CommandoConfig::max_lifetime`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RotationEvent {
+    /// The connection is `age` old and being replaced. Calls made now wait for the new one.
+    Started { age: Duration },
+    /// Calls go over the new connection.
+    Finished,
+    /// Connecting failed; the old connection stays, and the next attempt is
+    /// [`ROTATION_RETRY`] later.
+    Failed,
+}
+
+/// How long after a failed rotation the pump tries again.
+pub const ROTATION_RETRY: Duration = Duration::from_secs(60);
+
+/// How many [`RotationEvent`]s a slow subscriber may fall behind by.
+const ROTATION_BUFFER: usize = 16;
+
 #[derive(Clone, Copy, Debug)]
 pub enum ReconnectMode {
     Never,
@@ -84,6 +735,134 @@ pub struct CommandoConfig {
     timeout: Option<Duration>,
     reconnect: ReconnectMode,
     retry_policy: RetryPolicy,
+    idle: Option<IdleConfig>,
+    max_in_flight: Option<usize>,
+    max_buffered: Option<usize>,
+    max_unsolicited: Option<usize>,
+    max_lifetime: Option<Duration>,
+    rune_provider: Option<RuneProvider>,
+    #[cfg(feature = "cancel")]
+    cancel: Option<CancellationToken>,
+    random_ids: bool,
+    cache_ttls: HashMap<String, Duration>,
+    validators: Validators,
+    clock: SharedClock,
+}
+
+/// What the pump should do when the connection has gone quiet, see
+/// [`CommandoConfig::on_idle`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdleAction {
+    /// Leave the connection alone.
+    Nothing,
+    /// Send a `ping`; its `pong` counts as traffic.
+    Ping,
+    /// Drop the connection without reconnecting. Calls in flight fail with
+    /// `Error::Io(BrokenPipe)` and the pump exits with [`PumpExit::Idle`].
+    Disconnect,
+}
+
+/// Passed to the idle hook.
+#[derive(Clone)]
+pub struct IdleContext {
+    /// How long nothing was read or written.
+    pub idle_for: Duration,
+    pub their_pubkey: PublicKey,
+    /// Calls in flight, waiting for a reply.
+    pub in_flight: usize,
+    /// For sending messages of your own, e.g. gossip queries. The hook runs on the pump, so
+    /// spawn a task to send rather than blocking. `None` for transports without one.
+    pub sender: Option<MessageSender>,
+}
+
+type IdleHook = Arc<dyn Fn(&IdleContext) -> IdleAction + Send + Sync>;
+
+#[derive(Clone)]
+struct IdleConfig {
+    after: Duration,
+    hook: IdleHook,
+}
+
+impl std::fmt::Debug for IdleConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdleConfig")
+            .field("after", &self.after)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How far the reply to a call has got, see [`CallOpts::on_progress`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallProgress {
+    pub req_id: u64,
+    /// Reply bytes received so far, as sent (compressed, if the node compresses).
+    pub bytes: usize,
+    /// Reply fragments received so far.
+    pub chunks: usize,
+    /// Since the pump took the call.
+    pub elapsed: Duration,
+}
+
+/// What the pump should do with a call after a progress report, see
+/// [`CallOpts::on_progress`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressAction {
+    Continue,
+    /// Fail the call with `Error::Cancelled` and throw away the rest of its reply.
+    Abort,
+}
+
+/// A hook installed with [`CallOpts::on_progress`].
+#[derive(Clone)]
+pub struct ProgressHook(Arc<dyn Fn(&CallProgress) -> ProgressAction + Send + Sync>);
+
+impl std::fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressHook")
+    }
+}
+
+type RuneFuture = Pin<Box<dyn Future<Output = Result<String, Error>> + Send>>;
+
+#[derive(Clone)]
+struct RuneProvider(Arc<dyn Fn() -> RuneFuture + Send + Sync>);
+
+impl std::fmt::Debug for RuneProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RuneProvider")
+    }
+}
+
+/// Runes for method patterns, see [`CommandoClient::add_rune`].
+#[derive(Default)]
+struct MethodRunes(Vec<(String, String)>);
+
+impl MethodRunes {
+    fn insert(&mut self, pattern: String, rune: String) {
+        match self.0.iter_mut().find(|(p, _)| *p == pattern) {
+            Some((_, old)) => *old = rune,
+            None => self.0.push((pattern, rune)),
+        }
+    }
+
+    fn remove(&mut self, pattern: &str) -> Option<String> {
+        let at = self.0.iter().position(|(p, _)| p == pattern)?;
+        Some(self.0.remove(at).1)
+    }
+
+    /// The rune of the most specific pattern `method` matches: its own name, else the
+    /// longest matching prefix.
+    fn select(&self, method: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .filter_map(|(pattern, rune)| match pattern.strip_suffix('*') {
+                Some(prefix) if method.starts_with(prefix) => Some((prefix.len(), rune)),
+                None if pattern == method => Some((usize::MAX, rune)),
+                _ => None,
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, rune)| rune.as_str())
+    }
 }
 
 /// Per-call overrides. Leave fields as `None` to inherit from the client.
@@ -101,6 +880,11 @@ pub struct CallOpts {
     pub timeout: Option<Duration>,
     pub rune: Option<String>,
     pub filter: Option<Value>,
+    pub progress: Option<ProgressHook>,
+    pub cache: Option<CacheMode>,
+    /// Fail the call with `Error::Cancelled` once this is cancelled.
+    #[cfg(feature = "cancel")]
+    pub cancel: Option<CancellationToken>,
 }
 
 impl CallOpts {
@@ -127,6 +911,34 @@ impl CallOpts {
         self.rune = Some(rune);
         self
     }
+
+    /// Call `hook` with the running totals every time a fragment of the reply arrives, for
+    /// a progress bar on a reply that takes a while. Returning [`ProgressAction::Abort`]
+    /// fails the call with `Error::Cancelled`, e.g. once the reply is bigger than the caller
+    /// is willing to take.
+    ///
+    /// The hook runs on the pump task, so it should be quick. Totals start over if the call
+    /// is resent after a reconnect.
+    pub fn on_progress(
+        mut self,
+        hook: impl Fn(&CallProgress) -> ProgressAction + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(ProgressHook(Arc::new(hook)));
+        self
+    }
+
+    pub fn cache(mut self, mode: CacheMode) -> Self {
+        self.cache = Some(mode);
+        self
+    }
+
+    /// Fail the call with `Error::Cancelled` once `token` is cancelled, see the
+    /// [`cancel`](crate::cancel) module. The pump drops the call and the rest of its reply.
+    #[cfg(feature = "cancel")]
+    pub fn cancel_on(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
 }
 
 impl CommandoConfig {
@@ -162,132 +974,345 @@ impl CommandoConfig {
         };
         self
     }
-}
 
-impl Default for CommandoConfig {
-    fn default() -> Self {
-        Self {
-            timeout: Some(Duration::from_secs(30)),
-            reconnect: ReconnectMode::Auto {
-                max_attempts: 10,
-                base_backoff: Duration::from_millis(200),
-                max_backoff: Duration::from_secs(5),
-            },
-            retry_policy: RetryPolicy::Always { max_retries: 3 },
-        }
+    /// Fail calls with `Error::Overloaded` while `max` calls are already in flight, instead
+    /// of queueing them. Unlimited by default.
+    pub fn max_in_flight(mut self, max: Option<usize>) -> Self {
+        self.max_in_flight = max;
+        self
     }
-}
 
-impl CommandoCommand {
-    pub fn new(
-        id: u64,
-        method: String,
-        rune: String,
-        params: Value,
-        filter: Option<Value>,
-    ) -> Self {
-        Self {
-            id,
-            method,
-            rune,
-            params,
-            filter,
-        }
-    }
-    pub fn req_id(&self) -> u64 {
-        self.id
-    }
-    pub fn method(&self) -> &str {
-        &self.method
-    }
-    pub fn rune(&self) -> &str {
-        &self.rune
+    /// Cap the reply bytes buffered for unfinished calls. While the cap is reached new calls
+    /// fail with `Error::Overloaded`, and once a reply pushes past it, the call with the
+    /// largest reply so far fails with `Error::Overloaded` and the rest of its reply is
+    /// dropped. Unlimited by default.
+    pub fn max_buffered_reply_bytes(mut self, max: Option<usize>) -> Self {
+        self.max_buffered = max;
+        self
     }
-    pub fn params(&self) -> &Value {
-        &self.params
+
+    /// Cap the bytes buffered for unfinished replies nobody called for, such as
+    /// notifications. Once they pass it every such reply is dropped. 1 MiB by default.
+    pub fn max_unsolicited_bytes(mut self, max: Option<usize>) -> Self {
+        self.max_unsolicited = max;
+        self
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CommandoCommand {
-    id: u64,
-    method: String,
-    params: Value,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    filter: Option<Value>,
-    rune: String,
+    /// Call `hook` whenever nothing has been read from or written to the connection for
+    /// `after`, and again after every further `after` of silence. Lets mobile apps decide
+    /// when a keepalive ping is worth waking the radio for, and when to hang up instead.
+    pub fn on_idle(
+        mut self,
+        after: Duration,
+        hook: impl Fn(&IdleContext) -> IdleAction + Send + Sync + 'static,
+    ) -> Self {
+        self.idle = Some(IdleConfig {
+            after,
+            hook: Arc::new(hook),
+        });
+        self
+    }
+
+    /// Reconnect proactively once a connection is `lifetime` old, at the first moment no call
+    /// is in flight. Calls made while it reconnects wait for the new connection. Off by
+    /// default.
+    pub fn max_lifetime(mut self, lifetime: Option<Duration>) -> Self {
+        self.max_lifetime = lifetime;
+        self
+    }
+
+    /// Get a new rune from `provider` when the client's rune has expired, and retry the
+    /// failed call once with it. See *Rune renewal* in the [module docs](self).
+    ///
+    /// Calls failing at the same time share one renewal. An error from the provider is
+    /// what the call fails with.
+    pub fn rune_provider<F, Fut>(mut self, provider: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, Error>> + Send + 'static,
+    {
+        self.rune_provider = Some(RuneProvider(Arc::new(move || Box::pin(provider()))));
+        self
+    }
+
+    /// Stop the pump once `token` is cancelled: calls in flight fail with `Error::Cancelled`
+    /// and the pump exits with [`PumpExit::Cancelled`]. See the [`cancel`](crate::cancel)
+    /// module.
+    #[cfg(feature = "cancel")]
+    pub fn cancel_on(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Pick request ids at random (the default), or count up from 1.
+    ///
+    /// Counted ids are predictable, and two clients talking to the same node at once use the
+    /// same ones, which confuses whoever reads the node's logs. Random ones are neither.
+    pub fn random_ids(mut self, random: bool) -> Self {
+        self.random_ids = random;
+        self
+    }
+
+    /// Cache successful replies to `method` for `ttl`, see *Reply cache* in the
+    /// [module docs](self). Only worth it for read-only methods: `getinfo`, `listchannels`
+    /// and the like. Replies streamed to a sink are never cached.
+    pub fn cache_ttl(mut self, method: impl Into<String>, ttl: Duration) -> Self {
+        self.cache_ttls.insert(method.into(), ttl);
+        self
+    }
+
+    /// Check the params of every call to `method` with `validator` before sending it, failing
+    /// the call with `Error::Validation` if they don't pass. See the
+    /// [`validation`](crate::validation) module.
+    pub fn validate_params(
+        mut self,
+        method: impl Into<String>,
+        validator: impl Validator + 'static,
+    ) -> Self {
+        self.validators
+            .insert(Payload::Params, method.into(), Arc::new(validator));
+        self
+    }
+
+    /// Check the result of every call to `method` with `validator`, failing the call with
+    /// `Error::Validation` if it doesn't pass. Calls to `method` with a sink are refused, as
+    /// their reply can't be checked; see the [`validation`](crate::validation) module.
+    pub fn validate_result(
+        mut self,
+        method: impl Into<String>,
+        validator: impl Validator + 'static,
+    ) -> Self {
+        self.validators
+            .insert(Payload::Result, method.into(), Arc::new(validator));
+        self
+    }
+
+    /// Take the time for timeouts, the idle hook, rotation, the reply cache, reconnect
+    /// backoff and call durations from `clock` instead of tokio's timer, see the
+    /// [`clock`](crate::clock) module. The socket's own timers follow
+    /// [`LNSocket::set_clock`].
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Resolves once the token from [`CommandoConfig::cancel_on`] is cancelled, never
+    /// without one.
+    async fn cancelled(&self) {
+        #[cfg(feature = "cancel")]
+        if let Some(token) = &self.cancel {
+            return token.cancelled().await;
+        }
+        std::future::pending().await
+    }
+
+    /// When a connection made now is due for rotation.
+    fn rotation_due(&self) -> Option<Instant> {
+        self.max_lifetime
+            .map(|lifetime| self.clock.now() + lifetime)
+    }
+}
+
+const DEFAULT_MAX_UNSOLICITED: usize = 1024 * 1024;
+
+impl Default for CommandoConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Some(Duration::from_secs(30)),
+            reconnect: ReconnectMode::Auto {
+                max_attempts: 10,
+                base_backoff: Duration::from_millis(200),
+                max_backoff: Duration::from_secs(5),
+            },
+            retry_policy: RetryPolicy::Always { max_retries: 3 },
+            idle: None,
+            max_in_flight: None,
+            max_buffered: None,
+            max_unsolicited: Some(DEFAULT_MAX_UNSOLICITED),
+            max_lifetime: None,
+            rune_provider: None,
+            #[cfg(feature = "cancel")]
+            cancel: None,
+            random_ids: true,
+            cache_ttls: HashMap::new(),
+            validators: Validators::default(),
+            clock: SharedClock::default(),
+        }
+    }
+}
+
+/// Destination of reply fragments for [`CommandoClient::call_with_sink`].
+type ReplySink = Box<dyn FnMut(&[u8]) + Send>;
+
+/// What the pump does with a finished reply.
+enum ReplyMode {
+    /// Parse it into a `Value` and unwrap `result`.
+    Value,
+    /// Hand back the (inflated) response bytes.
+    Raw,
+    /// Forward every fragment as it arrives, buffering nothing.
+    Sink(ReplySink),
+}
+
+/// What a finished call hands back, depending on its [`ReplyMode`].
+#[derive(Clone, Debug, PartialEq)]
+enum ReplyBody {
+    Value(Value),
+    Raw(Vec<u8>),
+    /// Bytes passed to the sink.
+    Streamed(usize),
 }
 
 struct InProgress {
     cmd: CommandoCommand,
-    done_tx: oneshot::Sender<Result<Value, Error>>,
+    done_tx: oneshot::Sender<Result<ReplyBody, Error>>,
     policy: RetryPolicy,
+    mode: ReplyMode,
     attempts: usize,
-    buf: Vec<u8>,
+    /// Reply bytes received so far, whether the pump's [`ReplyAssembler`] buffers them or
+    /// they went to a sink.
+    bytes: usize,
+    chunks: usize,
+    clock: SharedClock,
+    started: Instant,
+    /// When the caller stops waiting for the reply.
+    deadline: Option<Instant>,
+    progress: Option<ProgressHook>,
+    span: tracing::Span,
 }
 
-#[derive(Debug, Clone)]
-pub struct CommandoReplyChunk {
-    pub req_id: u64,
-    pub chunk: Vec<u8>,
-}
+impl InProgress {
+    fn new(
+        cmd: CommandoCommand,
+        policy: RetryPolicy,
+        mode: ReplyMode,
+        done_tx: oneshot::Sender<Result<ReplyBody, Error>>,
+        deadline: Option<Instant>,
+        clock: SharedClock,
+        span: tracing::Span,
+    ) -> Self {
+        Self {
+            cmd,
+            done_tx,
+            policy,
+            mode,
+            attempts: 0,
+            bytes: 0,
+            chunks: 0,
+            started: clock.now(),
+            clock,
+            deadline,
+            progress: None,
+            span,
+        }
+    }
 
-#[derive(Debug, Clone)]
-pub enum IncomingCommandoMessage {
-    Chunk(CommandoReplyChunk),
-    Done(CommandoReplyChunk),
-}
+    /// Count a reply fragment, passing it on if the reply goes to a sink, record the running
+    /// totals on the call's span and report them to the progress hook, if any.
+    fn push_chunk(&mut self, chunk: &[u8]) -> ProgressAction {
+        if let ReplyMode::Sink(sink) = &mut self.mode {
+            sink(chunk);
+        }
+        self.bytes += chunk.len();
+        self.chunks += 1;
+        self.span.record("bytes", self.bytes);
+        self.span.record("chunks", self.chunks);
+        match &self.progress {
+            Some(ProgressHook(hook)) => hook(&CallProgress {
+                req_id: self.cmd.req_id(),
+                bytes: self.bytes,
+                chunks: self.chunks,
+                elapsed: self.clock.since(self.started),
+            }),
+            None => ProgressAction::Continue,
+        }
+    }
 
-pub fn read_incoming_commando_message<R: LengthLimitedRead>(
-    typ: u16,
-    buf: &mut R,
-) -> Result<Option<IncomingCommandoMessage>, DecodeError> {
-    if typ == COMMANDO_REPLY_CONT {
-        let req_id: u64 = Readable::read(buf)?;
-        let mut chunk = Vec::with_capacity(buf.remaining_bytes() as usize);
-        buf.read_to_end(&mut chunk)?;
-        Ok(Some(IncomingCommandoMessage::Chunk(CommandoReplyChunk {
-            req_id,
-            chunk,
-        })))
-    } else if typ == COMMANDO_REPLY_TERM {
-        let req_id: u64 = Readable::read(buf)?;
-        let mut chunk = Vec::with_capacity(buf.remaining_bytes() as usize);
-        buf.read_to_end(&mut chunk)?;
-        Ok(Some(IncomingCommandoMessage::Done(CommandoReplyChunk {
-            req_id,
-            chunk,
-        })))
-    } else {
-        Ok(None)
+    /// Give the command a new id before it is resent, its old one is retired.
+    fn renumber(&mut self, req_id: u64) {
+        self.cmd.set_req_id(req_id);
+        self.span.record("req_id", req_id);
     }
-}
 
-impl Writeable for CommandoCommand {
-    fn write<W: Writer>(&self, writer: &mut W) -> Result<(), std::io::Error> {
-        self.id.write(writer)?;
-        writer.write_all(
-            &serde_json::to_string(self)
-                .expect("commando command json")
-                .into_bytes(),
-        )?;
-        Ok(())
+    /// Forget the counts of a partial reply before the command is resent.
+    fn reset_reply(&mut self) {
+        self.bytes = 0;
+        self.chunks = 0;
     }
+
+    /// Complete the call, recording its total duration on the span.
+    fn finish(self, res: Result<ReplyBody, Error>) {
+        let elapsed = self.clock.since(self.started);
+        self.span.record("duration", tracing::field::debug(elapsed));
+        match &res {
+            Ok(_) => tracing::debug!(parent: &self.span, "commando call completed"),
+            Err(err) => tracing::debug!(parent: &self.span, "commando call failed: {err}"),
+        }
+        let _ = self.done_tx.send(res);
+    }
+}
+
+fn call_span(cmd: &CommandoCommand, peer_id: &PublicKey) -> tracing::Span {
+    tracing::info_span!(
+        "commando_call",
+        req_id = cmd.req_id(),
+        method = cmd.method(),
+        peer_id = %peer_id,
+        bytes = tracing::field::Empty,
+        chunks = tracing::field::Empty,
+        duration = tracing::field::Empty,
+    )
 }
 
-impl Type for CommandoCommand {
-    fn type_id(&self) -> u16 {
-        COMMANDO_COMMAND
+/// Write a command, compressing it if the peer negotiated commando compression, and split
+/// into continuations if it doesn't fit in one message.
+async fn write_command<T: MessageTransport>(
+    sock: &mut T,
+    cmd: &CommandoCommand,
+) -> Result<(), Error> {
+    for chunk in &command_chunks(sock, cmd) {
+        write_message(sock, chunk).await?;
     }
+    Ok(())
 }
 
-impl Type for IncomingCommandoMessage {
-    fn type_id(&self) -> u16 {
-        match self {
-            IncomingCommandoMessage::Chunk(_) => COMMANDO_REPLY_CONT,
-            IncomingCommandoMessage::Done(_) => COMMANDO_REPLY_TERM,
-        }
+/// The messages of `cmd` for `sock`, compressed if the peer takes it.
+fn command_chunks<T: MessageTransport>(
+    sock: &T,
+    cmd: &CommandoCommand,
+) -> Vec<CommandoCommandChunk> {
+    #[cfg(feature = "compression")]
+    if sock.peer_supports_feature(COMMANDO_COMPRESSION_FEATURE_BIT) {
+        return split_command(&CompressedCommand(cmd));
     }
+    #[cfg(not(feature = "compression"))]
+    let _ = sock;
+    split_command(cmd)
+}
+
+/// Start writing the command of call `req_id` without holding on to `sock`, when it has a
+/// [`MessageSender`] to write with, so the pump goes on reading while the peer holds the
+/// write up. The outcome comes back on `written`. Returns `false` if `sock` has no sender.
+fn spawn_write_command<T: MessageTransport>(
+    sock: &T,
+    cmd: &CommandoCommand,
+    epoch: Epoch,
+    written: &mpsc::UnboundedSender<(Epoch, u64, Result<(), Error>)>,
+) -> bool {
+    let Some(sender) = sock.sender() else {
+        return false;
+    };
+    let req_id = cmd.req_id();
+    let frames = command_chunks(sock, cmd).iter().map(Frame::new).collect();
+    let written = written.clone();
+    tokio::spawn(async move {
+        let res = match frames {
+            Ok(frames) => sender.send_frames(frames).await,
+            Err(err) => Err(err),
+        };
+        let _ = written.send((epoch, req_id, res));
+    });
+    true
 }
 
 /// Public client for Core Lightning Commando over an `LNSocket`.
@@ -317,39 +1342,312 @@ impl Type for IncomingCommandoMessage {
 /// let v2 = client.call_with_opts("getchaninfo", json!({"channel": "..." }), opts).await?;
 /// # Ok(()) }
 /// ```
-pub struct CommandoClient {
-    tx: mpsc::Sender<Ctrl>,
-    next_id: AtomicU64,
+///
+/// The pump runs over any [`MessageTransport`]; `T` is [`LNSocket`] unless you bring your
+/// own, e.g. a fake for tests.
+pub struct CommandoClient<T: MessageTransport = LNSocket> {
+    pump: Mutex<PumpHandle<T>>,
+    notify_tx: broadcast::Sender<Notification>,
+    rotation_tx: broadcast::Sender<RotationEvent>,
     config: CommandoConfig,
-    rune: String,
+    rune: Mutex<String>,
+    method_runes: Mutex<MethodRunes>,
+    /// Held while the rune provider runs, so that concurrent failures renew only once.
+    renewing: tokio::sync::Mutex<()>,
+    load: Arc<Load>,
+    /// Of the current socket, updated by `replace_socket`.
+    our_node_id: Mutex<PublicKey>,
+    cache: ReplyCache,
 }
 
-impl CommandoClient {
-    /// Spawn the background pump that owns the LNSocket.
-    pub fn spawn_with_config(
-        sock: LNSocket,
-        rune: impl Into<String>,
-        config: CommandoConfig,
-    ) -> Self {
-        let (tx, rx) = mpsc::channel::<Ctrl>(128);
-        // move everything into the task
-        tokio::spawn(pump(sock, rx, config.clone()));
+impl<T: MessageTransport> CommandoClient<T> {
+    /// Spawn the background pump that owns the socket.
+    pub fn spawn_with_config(sock: T, rune: impl Into<String>, config: CommandoConfig) -> Self {
+        let (notify_tx, _) = broadcast::channel(NOTIFICATION_BUFFER);
+        let (rotation_tx, _) = broadcast::channel(ROTATION_BUFFER);
+        let load = Arc::new(Load::default());
+        let our_node_id = Mutex::new(sock.our_node_id());
+        let pump = spawn_pump(
+            sock,
+            config.clone(),
+            notify_tx.clone(),
+            rotation_tx.clone(),
+            load.clone(),
+        );
 
         Self {
-            tx,
-            rune: rune.into(),
-            next_id: AtomicU64::new(1),
+            load,
+            our_node_id,
+            pump: Mutex::new(pump),
+            notify_tx,
+            rotation_tx,
+            rune: Mutex::new(rune.into()),
+            method_runes: Mutex::default(),
+            renewing: tokio::sync::Mutex::new(()),
             config,
+            cache: ReplyCache::default(),
         }
     }
 
-    pub fn spawn(sock: LNSocket, rune: impl Into<String>) -> Self {
+    pub fn spawn(sock: T, rune: impl Into<String>) -> Self {
         Self::spawn_with_config(sock, rune, CommandoConfig::default())
     }
 
+    /// Hand the client a freshly connected socket.
+    ///
+    /// Calls in flight on the old socket fail with `Error::Io(BrokenPipe)`; the id counter,
+    /// default rune and config carry over. If the pump has already exited (e.g. reconnect
+    /// attempts were exhausted, or after [`CommandoClient::close`]) a new one is spawned
+    /// around `sock`. The reply cache is cleared, `sock` may well be to another node.
+    pub async fn replace_socket(&self, sock: T) {
+        *self.our_node_id.lock().unwrap() = sock.our_node_id();
+        self.cache.clear();
+        let mut ctrl = Ctrl::ReplaceSocket(Box::new(sock));
+        loop {
+            let tx = self.handle().tx;
+            let Err(mpsc::error::SendError(returned)) = tx.send(ctrl).await else {
+                return;
+            };
+            let Ctrl::ReplaceSocket(sock) = returned else {
+                unreachable!("we only sent a ReplaceSocket");
+            };
+
+            let mut current = self.pump.lock().unwrap();
+            if current.tx.same_channel(&tx) {
+                // the pump is gone, start a new one around the fresh socket
+                *current = spawn_pump(
+                    *sock,
+                    self.config.clone(),
+                    self.notify_tx.clone(),
+                    self.rotation_tx.clone(),
+                    self.load.clone(),
+                );
+                return;
+            }
+            // someone else already restarted the pump, hand the socket to that one
+            ctrl = Ctrl::ReplaceSocket(sock);
+        }
+    }
+
+    /// Subscribe to notifications pushed by the node, see [`crate::notifications`].
+    ///
+    /// Only notifications received after this call are delivered. Streams survive socket
+    /// replacement and reconnects.
+    pub fn notifications(&self) -> NotificationStream {
+        NotificationStream::new(self.notify_tx.subscribe())
+    }
+
+    /// Subscribe to scheduled reconnects, see [`CommandoConfig::max_lifetime`]. Only
+    /// rotations after this call are reported.
+    pub fn rotations(&self) -> broadcast::Receiver<RotationEvent> {
+        self.rotation_tx.subscribe()
+    }
+
+    /// Stop the pump gracefully: calls already made are completed (or fail as usual), later
+    /// ones fail with `Error::PumpExited(PumpExit::Closed)`. Resolves once the pump has exited,
+    /// with the reason it stopped, which is not `Closed` if it had already died.
+    ///
+    /// Calls whose reply never arrives keep the pump alive until the connection breaks; wrap
+    /// this in a timeout if the node might not answer.
+    pub async fn close(&self) -> PumpExit {
+        let pump = self.handle();
+        let _ = pump.tx.send(Ctrl::Close).await;
+        pump.wait_exit().await
+    }
+
+    /// Wait until the pump exits, for whatever reason, without asking it to.
+    pub async fn closed(&self) -> PumpExit {
+        self.handle().wait_exit().await
+    }
+
+    /// The node id we connect to the node as, see [`LNSocket::our_node_id`]. Handy for
+    /// telling the node operator which id to mint a rune for.
+    pub fn our_node_id(&self) -> PublicKey {
+        *self.our_node_id.lock().unwrap()
+    }
+
+    /// The node id of the node the client talks to, as of its current connection.
+    pub fn their_pubkey(&self) -> PublicKey {
+        self.load.conn().their_pubkey
+    }
+
+    /// A snapshot of the wire stats of the current connection, see [`LNSocket::stats`].
+    /// They start over with every reconnect. `None` for transports without stats, and once
+    /// the pump has exited.
+    pub fn stats(&self) -> Option<WireStats> {
+        self.load.conn().stats?.snapshot()
+    }
+
+    pub(crate) fn load(&self) -> &Arc<Load> {
+        &self.load
+    }
+
+    /// Calls made and not finished yet, including those waiting for a reconnect.
+    pub fn in_flight(&self) -> usize {
+        self.load.in_flight()
+    }
+
+    /// Connect to the node again as `new_key`, and make it our identity for this client's
+    /// connections from now on, reconnects included. Resolves once the connection made with
+    /// `new_key` has replaced the old one.
+    ///
+    /// Like a rotation (see [`CommandoConfig::max_lifetime`]), this waits for a moment
+    /// without calls in flight and is reported on [`CommandoClient::rotations`]. Calls made
+    /// meanwhile wait for the new connection. If it fails, the old connection and key stay
+    /// and the error is returned. Runes tied to our node id (see
+    /// [`Restriction::id`](crate::rpc::Restriction::id)) won't work for the new one.
+    pub async fn rekey_identity(&self, new_key: SecretKey) -> Result<(), Error> {
+        let (done_tx, done_rx) = oneshot::channel();
+        let pump = self.handle();
+        let rekey = Ctrl::Rekey {
+            key: new_key,
+            done_tx,
+        };
+        if pump.tx.send(rekey).await.is_err() {
+            return Err(Error::PumpExited(pump.wait_exit().await));
+        }
+        match done_rx.await {
+            Ok(Ok(())) => {
+                *self.our_node_id.lock().unwrap() = PublicKey::from_secret_key(
+                    &bitcoin::secp256k1::Secp256k1::signing_only(),
+                    &new_key,
+                );
+                Ok(())
+            }
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(Error::PumpExited(pump.wait_exit().await)),
+        }
+    }
+
+    /// The rune calls use unless a method rune or [`CallOpts::rune`] overrides it.
+    pub(crate) fn default_rune(&self) -> String {
+        self.rune.lock().unwrap().clone()
+    }
+
+    /// Use `rune` for the methods `pattern` matches instead of the client's rune, see *Method
+    /// runes* in the [module docs](self). `pattern` is a method name, or a prefix followed by
+    /// `*`: `list*` covers `listfunds` and `listpeers`, and `*` alone every method. Adding a
+    /// pattern again replaces its rune.
+    pub fn add_rune(&self, pattern: impl Into<String>, rune: impl Into<String>) {
+        self.method_runes
+            .lock()
+            .unwrap()
+            .insert(pattern.into(), rune.into());
+    }
+
+    /// Stop using the rune added for `pattern`, returning it.
+    pub fn remove_rune(&self, pattern: &str) -> Option<String> {
+        self.method_runes.lock().unwrap().remove(pattern)
+    }
+
+    /// The rune a call to `method` uses without a [`CallOpts::rune`]: the one added for the
+    /// most specific pattern matching it, else the client's rune.
+    pub fn rune_for(&self, method: &str) -> String {
+        match self.method_runes.lock().unwrap().select(method) {
+            Some(rune) => rune.to_string(),
+            None => self.default_rune(),
+        }
+    }
+
+    /// The rune of a call to `method` made with `opts`.
+    fn call_rune(&self, method: &str, opts: &CallOpts) -> String {
+        opts.rune.clone().unwrap_or_else(|| self.rune_for(method))
+    }
+
+    /// Run `call` with `opts`, and if it fails because the client's rune expired, renew the
+    /// rune and run it once more. See [`CommandoConfig::rune_provider`].
+    pub(crate) async fn with_rune_renewal<R, Fut>(
+        &self,
+        method: &str,
+        opts: CallOpts,
+        call: impl Fn(CallOpts) -> Fut,
+    ) -> Result<R, Error>
+    where
+        Fut: Future<Output = Result<R, Error>>,
+    {
+        let own_rune =
+            opts.rune.is_some() || self.method_runes.lock().unwrap().select(method).is_some();
+        let provider = match &self.config.rune_provider {
+            Some(provider) if !own_rune => provider,
+            _ => return call(opts).await,
+        };
+        // pin the rune, to know whether it is still the one that expired
+        let rune = self.default_rune();
+        let first = call(CallOpts {
+            rune: Some(rune.clone()),
+            ..opts.clone()
+        });
+        match first.await {
+            Err(Error::Rpc(err)) if err.is_rune_expired() => {
+                self.renew_rune(&rune, provider).await?;
+                call(opts).await
+            }
+            res => res,
+        }
+    }
+
+    async fn renew_rune(&self, expired: &str, provider: &RuneProvider) -> Result<(), Error> {
+        let _renewing = self.renewing.lock().await;
+        if self.default_rune() != expired {
+            // another call renewed it while we waited
+            return Ok(());
+        }
+        tracing::debug!("rune expired, asking the provider for a new one");
+        let fresh = (provider.0)().await?;
+        *self.rune.lock().unwrap() = fresh;
+        Ok(())
+    }
+
+    /// Drop the cached replies to `method`, see [`CommandoConfig::cache_ttl`].
+    pub fn invalidate_cache(&self, method: &str) {
+        self.cache.invalidate(method);
+    }
+
+    /// Drop every cached reply.
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// Where a reply to this call is cached, and for how long, if it is.
+    fn cache_key(
+        &self,
+        method: &str,
+        params: &Value,
+        opts: &CallOpts,
+        mode: &ReplyMode,
+    ) -> Option<(CacheKey, Duration)> {
+        let ttl = *self.config.cache_ttls.get(method)?;
+        let raw = match mode {
+            ReplyMode::Value => false,
+            ReplyMode::Raw => true,
+            ReplyMode::Sink(_) => return None,
+        };
+        if opts.cache == Some(CacheMode::Bypass) {
+            return None;
+        }
+        let key = CacheKey {
+            method: method.to_string(),
+            params: params.to_string(),
+            filter: opts.filter.as_ref().map(Value::to_string),
+            rune: self.call_rune(method, opts),
+            raw,
+        };
+        Some((key, ttl))
+    }
+
+    /// Why the pump stopped, or `None` while it is still running.
+    pub fn exit_reason(&self) -> Option<PumpExit> {
+        self.pump.lock().unwrap().exit.borrow().clone()
+    }
+
+    #[inline]
+    fn handle(&self) -> PumpHandle<T> {
+        self.pump.lock().unwrap().clone()
+    }
+
     #[inline]
     fn alloc_id(&self) -> u64 {
-        self.next_id.fetch_add(1, Ordering::Relaxed)
+        self.load.alloc_id(self.config.random_ids)
     }
 
     pub async fn call(&self, method: impl Into<String>, params: Value) -> Result<Value, Error> {
@@ -357,268 +1655,1843 @@ impl CommandoClient {
             .await
     }
 
+    /// Ask the node whether `method` would accept `params` with our rune, without running it,
+    /// using CLN's `check` command. Handy to validate a form before a call with side effects.
+    ///
+    /// Refusals come back as a [`CheckOutcome`]; only transport failures and malformed
+    /// replies are errors. `params` must be an object, an array or `null`.
+    pub async fn check(
+        &self,
+        method: impl Into<String>,
+        params: Value,
+    ) -> Result<CheckOutcome, Error> {
+        self.check_with_opts(method, params, CallOpts::default())
+            .await
+    }
+
+    /// [`CommandoClient::check`] with per-call overrides, e.g. to check another rune.
+    pub async fn check_with_opts(
+        &self,
+        method: impl Into<String>,
+        params: Value,
+        opts: CallOpts,
+    ) -> Result<CheckOutcome, Error> {
+        let method = method.into();
+        let params = check_params(method.clone(), params)?;
+        // check with the rune the call itself would use, renewed as it would be
+        let res = self
+            .with_rune_renewal(&method, opts, |opts| async {
+                let opts = CallOpts {
+                    rune: Some(self.call_rune(&method, &opts)),
+                    ..opts
+                };
+                match self
+                    .start_call("check", params.clone(), opts, ReplyMode::Value)
+                    .await?
+                {
+                    ReplyBody::Value(value) => Ok(value),
+                    _ => unreachable!("the pump answers in the mode it was asked for"),
+                }
+            })
+            .await;
+        match res.and_then(|value| {
+            self.config
+                .validators
+                .check(Payload::Result, "check", &value)
+        }) {
+            Ok(_) => Ok(CheckOutcome::Ok),
+            Err(Error::Rpc(err)) => Ok(CheckOutcome::from_rpc_error(err)),
+            Err(err) => Err(err),
+        }
+    }
+
     pub async fn call_with_opts(
         &self,
         method: impl Into<String>,
         params: Value,
         opts: CallOpts,
     ) -> Result<Value, Error> {
+        let method = method.into();
+        let result = self
+            .with_rune_renewal(&method, opts, |opts| async {
+                match self
+                    .start_call(method.clone(), params.clone(), opts, ReplyMode::Value)
+                    .await?
+                {
+                    ReplyBody::Value(value) => Ok(value),
+                    _ => unreachable!("the pump answers in the mode it was asked for"),
+                }
+            })
+            .await?;
+        self.config
+            .validators
+            .check(Payload::Result, &method, &result)?;
+        Ok(result)
+    }
+
+    /// Like [`CommandoClient::call_with_opts`], but hand back the JSON-RPC response
+    /// (`{"jsonrpc":"2.0","id":..,"result":..}`, or `"error"`) as bytes, unparsed. Nothing
+    /// is checked beyond the reply not being a notification, and the result validator of
+    /// `method` if it has one: an RPC error is part of the bytes, not an `Err`.
+    ///
+    /// The reply is still buffered in full, but skips the `Value` tree, which takes several
+    /// times the size of the JSON. Decode it with a streaming deserializer, e.g.
+    /// `serde_json::Deserializer::from_slice`.
+    pub async fn call_raw(
+        &self,
+        method: impl Into<String>,
+        params: Value,
+        opts: CallOpts,
+    ) -> Result<Vec<u8>, Error> {
+        let method = method.into();
+        match self
+            .start_call(method.clone(), params, opts, ReplyMode::Raw)
+            .await?
+        {
+            ReplyBody::Raw(bytes) => {
+                self.config.validators.check_raw_result(&method, &bytes)?;
+                Ok(bytes)
+            }
+            _ => unreachable!("the pump answers in the mode it was asked for"),
+        }
+    }
+
+    /// Pass the reply to `sink` fragment by fragment as it arrives, without buffering it.
+    /// Resolves with the number of bytes passed once the last fragment is in.
+    ///
+    /// The fragments concatenate to the same bytes [`CommandoClient::call_raw`] returns,
+    /// except that:
+    /// - `sink` runs on the pump task, so it should be quick (push into a channel, feed a
+    ///   file or an incremental parser) or it delays every other call,
+    /// - gzip replies (see *Compression*) arrive compressed,
+    /// - notifications the node sends on this call's id are passed to `sink` too, since
+    ///   they can't be told apart before they are complete.
+    ///
+    /// The call is never resent after a reconnect, whatever the retry policy: part of the
+    /// reply may already have been passed on.
+    pub async fn call_with_sink(
+        &self,
+        method: impl Into<String>,
+        params: Value,
+        opts: CallOpts,
+        sink: impl FnMut(&[u8]) + Send + 'static,
+    ) -> Result<usize, Error> {
+        let method = method.into();
+        self.config.validators.check_unbuffered(&method)?;
+        let opts = CallOpts {
+            retry_policy: Some(RetryPolicy::Never),
+            ..opts
+        };
+        let mode = ReplyMode::Sink(Box::new(sink));
+        match self.start_call(method, params, opts, mode).await? {
+            ReplyBody::Streamed(bytes) => Ok(bytes),
+            _ => unreachable!("the pump answers in the mode it was asked for"),
+        }
+    }
+
+    async fn start_call(
+        &self,
+        method: impl Into<String>,
+        params: Value,
+        opts: CallOpts,
+        mode: ReplyMode,
+    ) -> Result<ReplyBody, Error> {
+        #[cfg(feature = "cancel")]
+        let mut opts = opts;
+        #[cfg(feature = "cancel")]
+        if let Some(token) = opts.cancel.take() {
+            let call = Box::pin(self.start_call(method, params, opts, mode));
+            let res = or_cancelled(&token, call).await;
+            if token.is_cancelled() {
+                // otherwise the pump keeps buffering the reply until the call expires; if the
+                // channel is full the next call to give up takes this one along
+                let _ = self.handle().tx.try_send(Ctrl::Abandoned);
+            }
+            return res;
+        }
+
+        let method = method.into();
+        self.config
+            .validators
+            .check(Payload::Params, &method, &params)?;
+        let cached = self.cache_key(&method, &params, &opts, &mode);
+        let hit = cached
+            .as_ref()
+            .filter(|_| opts.cache.unwrap_or_default() == CacheMode::Use)
+            .and_then(|(key, _)| self.cache.get(key, self.config.clock.now()));
+        if let Some(body) = hit {
+            tracing::trace!(%method, "commando reply from cache");
+            return Ok(body);
+        }
+
+        let _slot = self.load.admit(&self.config)?;
         let (done_tx, done_rx) = oneshot::channel();
-        let cmd = CommandoCommand::new(
-            self.alloc_id(),
-            method.into(),
-            opts.rune.clone().unwrap_or_else(|| self.rune.clone()),
-            params,
-            opts.filter.clone(),
-        );
+        let rune = self.call_rune(&method, &opts);
+        let cmd = CommandoCommand::new(self.alloc_id(), method, rune, params, opts.filter.clone());
 
-        self.tx
-            .send(Ctrl::Start {
-                policy: opts.retry_policy.unwrap_or(self.config.retry_policy),
-                cmd,
-                done_tx,
-            })
-            .await
-            .map_err(|_| Error::Io(std::io::ErrorKind::BrokenPipe))?;
+        let pump = self.handle();
+        let deadline = opts
+            .timeout
+            .or(self.config.timeout)
+            .map(|d| self.config.clock.now() + d);
+        let start = Ctrl::Start {
+            policy: opts.retry_policy.unwrap_or(self.config.retry_policy),
+            cmd,
+            mode,
+            done_tx,
+            deadline,
+            progress: opts.progress,
+        };
+        if pump.tx.send(start).await.is_err() {
+            return Err(Error::PumpExited(pump.wait_exit().await));
+        }
 
-        match self.config.timeout {
-            Some(d) => timeout(d, done_rx)
+        // the pump fails the call at the deadline too, unless it is busy reconnecting
+        let reply = match deadline {
+            Some(deadline) => self
+                .config
+                .clock
+                .timeout_at(deadline, done_rx)
                 .await
-                .map_err(|_| Error::Io(std::io::ErrorKind::TimedOut))?
-                .map_err(|_| Error::Io(std::io::ErrorKind::BrokenPipe))?,
-            None => done_rx
-                .await
-                .map_err(|_| Error::Io(std::io::ErrorKind::BrokenPipe))?,
+                .ok_or(Error::Io(std::io::ErrorKind::TimedOut))?,
+            None => done_rx.await,
+        };
+        let res = match reply {
+            Ok(res) => res,
+            // the pump died without answering; a cancel can beat it to our Start, which is
+            // still a call cancelled in flight
+            Err(_) => match pump.wait_exit().await {
+                PumpExit::Cancelled => Err(Error::Cancelled),
+                exit => Err(Error::PumpExited(exit)),
+            },
+        };
+        if let (Ok(body), Some((key, ttl))) = (&res, cached) {
+            self.cache
+                .insert(key, self.config.clock.now(), ttl, body.clone());
         }
+        res
+    }
+}
+
+fn spawn_pump<T: MessageTransport>(
+    sock: T,
+    config: CommandoConfig,
+    notify_tx: broadcast::Sender<Notification>,
+    rotation_tx: broadcast::Sender<RotationEvent>,
+    load: Arc<Load>,
+) -> PumpHandle<T> {
+    let (tx, rx) = mpsc::channel::<Ctrl<T>>(128);
+    let (exit_tx, exit) = watch::channel(None);
+    load.connected(&sock);
+    let epoch = load.next_epoch();
+    // move everything into the task
+    let task = tokio::spawn(pump(sock, rx, config, notify_tx, rotation_tx, load, epoch));
+    tokio::spawn(async move {
+        let exit = match task.await {
+            Ok(exit) => exit,
+            Err(err) if err.is_panic() => PumpExit::Panicked(panic_message(err.into_panic())),
+            Err(_) => PumpExit::Closed,
+        };
+        tracing::debug!("pump exited: {exit}");
+        let _ = exit_tx.send(Some(exit));
+    });
+    PumpHandle { tx, exit }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic payload".to_string()
     }
 }
 
 // Background task: single reader + demux per internal req_id.
-async fn pump(mut sock: LNSocket, mut rx: mpsc::Receiver<Ctrl>, cfg: CommandoConfig) {
+async fn pump<T: MessageTransport>(
+    mut sock: T,
+    mut rx: mpsc::Receiver<Ctrl<T>>,
+    cfg: CommandoConfig,
+    notify_tx: broadcast::Sender<Notification>,
+    rotation_tx: broadcast::Sender<RotationEvent>,
+    load: Arc<Load>,
+    epoch: Epoch,
+) -> PumpExit {
+    let clock = cfg.clock.clone();
     let mut pending: HashMap<u64, InProgress> = HashMap::new();
     let mut queue: Vec<InProgress> = Vec::new();
+    // reply fragments of calls and of notifications alike, except those going to a sink
+    let mut replies = ReplyAssembler::new();
+    // false once closed, or once every client handle is gone
+    let mut rx_open = true;
+    let mut last_traffic = clock.now();
+    // calls shed for overload whose remaining reply chunks are dropped
+    let mut discarding: HashSet<u64> = HashSet::new();
+    let mut connected_at = clock.now();
+    let mut rotate_at = cfg.rotation_due();
+    let mut epochs = Epochs::new(epoch);
+    // the connection the client was last told about
+    let mut published = epochs.current;
+    // a new identity to reconnect as, and who is waiting for it
+    let mut rekey: Option<(SecretKey, oneshot::Sender<Result<(), Error>>)> = None;
+    // how the command writes handed to the socket's writer went
+    let (written_tx, mut written_rx) = mpsc::unbounded_channel();
 
     loop {
-        tokio::select! {
-            maybe_ctrl = rx.recv() => {
-                let Some(Ctrl::Start { cmd, policy, done_tx }) = maybe_ctrl else {
-                    // channel closed; if nothing is pending, we can end. Otherwise, keep reading until we fail.
-                    if pending.is_empty() { break; }
-                    continue;
-                };
+        if !rx_open {
+            // nobody is waiting for calls whose caller gave up (e.g. timed out)
+            drop_abandoned(&mut pending, &mut queue, &mut replies, &mut discarding);
+            if pending.is_empty() && queue.is_empty() {
+                return PumpExit::Closed;
+            }
+        }
 
-                let req_id = cmd.req_id();
-                let ip = InProgress { cmd, policy, attempts: 0, done_tx, buf: Vec::new() };
-                pending.insert(req_id, ip);
+        if published != epochs.current {
+            load.connected(&sock);
+            published = epochs.current;
+            // partial replies don't survive the connection
+            replies.clear();
+        }
+        load.buffered.store(replies.buffered(), Ordering::Relaxed);
 
-                if let Err(_e) = sock.write(&pending[&req_id].cmd).await {
-                    if handle_broken_pipe(&cfg, &mut sock, &mut pending, &mut queue).await.is_err() {
-                        break;
-                    }
-                }
+        let idle_at = cfg.idle.as_ref().map(|idle| last_traffic + idle.after);
+        let expire_at = pending
+            .values()
+            .chain(&queue)
+            .filter_map(|p| p.deadline)
+            .min();
+        // rotations, and rekeys which are due right away, wait for a moment with nothing in
+        // flight
+        let rotate_now = match rekey {
+            Some(_) => Some(clock.now()),
+            None => rotate_at,
+        }
+        .filter(|_| pending.is_empty() && queue.is_empty());
+
+        tokio::select! {
+            _ = cfg.cancelled() => {
+                tracing::debug!("pump: cancelled, {} calls in flight", pending.len() + queue.len());
+                fail_all(&mut pending, &mut queue, Error::Cancelled);
+                return PumpExit::Cancelled;
             }
 
-            res = sock.read_custom(|typ, buf| read_incoming_commando_message(typ, buf)) => {
-                match res {
-                    Err(_e) => {
-                        if handle_broken_pipe(&cfg, &mut sock, &mut pending, &mut queue).await.is_err() {
-                            break;
-                        }
+            _ = clock.sleep_until(rotate_now.unwrap_or_else(|| clock.now())), if rotate_now.is_some() => {
+                let age = clock.since(connected_at);
+                let _ = rotation_tx.send(RotationEvent::Started { age });
+                let (res, rekeyed_tx) = match rekey.take() {
+                    Some((key, done_tx)) => {
+                        tracing::info!("pump: reconnecting with a new identity");
+                        (sock.reconnect_as(key).await, Some(done_tx))
                     }
-                    Ok(Message::Ping(ping)) => {
-                        tracing::trace!("pump: pingpong {}", ping.ponglen);
-                        let _ = sock.write(&msgs::Pong { byteslen: ping.ponglen }).await;
+                    None => {
+                        tracing::info!("pump: connection is {age:?} old, rotating");
+                        (sock.reconnect().await, None)
                     }
-                    Ok(Message::Custom(IncomingCommandoMessage::Chunk(chunk))) => {
-                        tracing::trace!("pump: [{}] chunk_partial {}", chunk.req_id, chunk.chunk.len());
-                        if let Some(p) = pending.get_mut(&chunk.req_id) {
-                            p.buf.extend_from_slice(&chunk.chunk);
+                };
+                match res {
+                    Ok(new_sock) => {
+                        sock = new_sock;
+                        epochs.retire(discarding.drain());
+                        epochs.next(&load);
+                        connected_at = clock.now();
+                        rotate_at = cfg.rotation_due();
+                        last_traffic = clock.now();
+                        let _ = rotation_tx.send(RotationEvent::Finished);
+                        if let Some(done_tx) = rekeyed_tx {
+                            let _ = done_tx.send(Ok(()));
                         }
                     }
-                    Ok(Message::Custom(IncomingCommandoMessage::Done(chunk))) => {
-                        tracing::trace!("pump: [{}] chunk_done {}", chunk.req_id, chunk.chunk.len());
-                        if let Some(mut p) = pending.remove(&chunk.req_id) {
-                            p.buf.extend_from_slice(&chunk.chunk);
-                            let parsed = parse_commando_response(&p.buf);
-                            let _ = p.done_tx.send(parsed);
+                    Err(err) => {
+                        tracing::warn!("pump: rotation failed, keeping the old connection: {err}");
+                        let _ = rotation_tx.send(RotationEvent::Failed);
+                        match rekeyed_tx {
+                            // the caller decides whether to try again
+                            Some(done_tx) => {
+                                let _ = done_tx.send(Err(err));
+                            }
+                            None => rotate_at = Some(clock.now() + ROTATION_RETRY),
                         }
                     }
-                    Ok(other) => {
-                        tracing::trace!("pump: other_msg {}", other.type_id());
+                }
+            }
+
+            _ = clock.sleep_until(expire_at.unwrap_or_else(|| clock.now())), if expire_at.is_some() => {
+                expire_calls(&mut pending, &mut queue, &mut replies, &mut discarding, clock.now());
+            }
+
+            _ = clock.sleep_until(idle_at.unwrap_or_else(|| clock.now())), if idle_at.is_some() => {
+                let idle = cfg.idle.as_ref().expect("idle_at is only set with an idle config");
+                let ctx = IdleContext {
+                    idle_for: clock.since(last_traffic),
+                    their_pubkey: sock.their_pubkey(),
+                    in_flight: pending.len() + queue.len(),
+                    sender: sock.sender(),
+                };
+                // the next call comes after another full idle period
+                last_traffic = clock.now();
+                match (idle.hook)(&ctx) {
+                    IdleAction::Nothing => {}
+                    IdleAction::Ping => {
+                        tracing::trace!("pump: idle for {:?}, pinging", ctx.idle_for);
+                        let _ = write_message(&mut sock, &msgs::Ping { ponglen: 0, byteslen: 0 }).await;
+                    }
+                    IdleAction::Disconnect => {
+                        tracing::debug!("pump: idle for {:?}, disconnecting", ctx.idle_for);
+                        fail_all(&mut pending, &mut queue, Error::Io(std::io::ErrorKind::BrokenPipe));
+                        return PumpExit::Idle;
                     }
                 }
             }
+
+            maybe_ctrl = rx.recv(), if rx_open => {
+                let (cmd, policy, mode, done_tx, deadline, progress) = match maybe_ctrl {
+                    Some(Ctrl::Start { cmd, policy, mode, done_tx, deadline, progress }) => (cmd, policy, mode, done_tx, deadline, progress),
+                    Some(Ctrl::ReplaceSocket(new_sock)) => {
+                        let in_flight = pending.len() + queue.len();
+                        tracing::info!("pump: replacing socket, failing {in_flight} in-flight calls");
+                        epochs.retire(pending.keys().copied().chain(discarding.drain()));
+                        fail_all(&mut pending, &mut queue, Error::Io(std::io::ErrorKind::BrokenPipe));
+                        sock = *new_sock;
+                        epochs.next(&load);
+                        connected_at = clock.now();
+                        rotate_at = cfg.rotation_due();
+                        continue;
+                    }
+                    Some(Ctrl::Rekey { key, done_tx }) => {
+                        if let Some((_, superseded)) = rekey.replace((key, done_tx)) {
+                            let _ = superseded.send(Err(Error::Cancelled));
+                        }
+                        continue;
+                    }
+                    #[cfg(feature = "cancel")]
+                    Some(Ctrl::Abandoned) => {
+                        drop_abandoned(&mut pending, &mut queue, &mut replies, &mut discarding);
+                        continue;
+                    }
+                    Some(Ctrl::Close) => {
+                        tracing::debug!("pump: closing, {} calls in flight", pending.len() + queue.len());
+                        // calls already queued still get through before recv() returns None
+                        rx.close();
+                        continue;
+                    }
+                    None => {
+                        // no more calls can arrive; finish the ones in flight, then exit
+                        rx_open = false;
+                        continue;
+                    }
+                };
+
+                let req_id = cmd.req_id();
+                let span = call_span(&cmd, &sock.their_pubkey());
+                let mut ip = InProgress::new(cmd, policy, mode, done_tx, deadline, clock.clone(), span);
+                ip.progress = progress;
+                pending.insert(req_id, ip);
+
+                last_traffic = clock.now();
+                let cmd = &pending[&req_id].cmd;
+                if spawn_write_command(&sock, cmd, epochs.current, &written_tx) {
+                    continue;
+                }
+                if let Err(err) = write_command(&mut sock, cmd).await {
+                    epochs.retire(discarding.drain());
+                    if handle_broken_pipe(&cfg, &mut sock, &load, &mut epochs, &mut pending, &mut queue, &err).await.is_err() {
+                        return PumpExit::Disconnected;
+                    }
+                    connected_at = clock.now();
+                    rotate_at = cfg.rotation_due();
+                }
+            }
+
+            Some((written_epoch, req_id, res)) = written_rx.recv() => {
+                let Err(err) = res else { continue };
+                // the connection the write was for may already be gone
+                if written_epoch != epochs.current {
+                    continue;
+                }
+                tracing::debug!("pump: [{req_id}] writing the command failed: {err}");
+                epochs.retire(discarding.drain());
+                if handle_broken_pipe(&cfg, &mut sock, &load, &mut epochs, &mut pending, &mut queue, &err).await.is_err() {
+                    return PumpExit::Disconnected;
+                }
+                connected_at = clock.now();
+                rotate_at = cfg.rotation_due();
+            }
+
+            res = read_message(&mut sock) => {
+                last_traffic = clock.now();
+                match res {
+                    Err(Error::PeerClosedConnection { message }) => {
+                        // BOLT 1: an error about all channels ends the connection
+                        tracing::info!("pump: peer closed the connection: {message}");
+                        let err = Error::PeerClosedConnection { message: message.clone() };
+                        fail_all(&mut pending, &mut queue, err);
+                        return PumpExit::PeerClosed(message);
+                    }
+                    Err(err) => {
+                        // partial replies don't survive the connection
+                        epochs.retire(discarding.drain());
+                        if handle_broken_pipe(&cfg, &mut sock, &load, &mut epochs, &mut pending, &mut queue, &err).await.is_err() {
+                            return PumpExit::Disconnected;
+                        }
+                        connected_at = clock.now();
+                        rotate_at = cfg.rotation_due();
+                    }
+                    Ok(Message::Ping(ping)) => {
+                        tracing::trace!("pump: pingpong {}", ping.ponglen);
+                        let _ = sock.answer_ping(&ping).await;
+                    }
+                    Ok(Message::Custom(msg)) => {
+                        let (IncomingCommandoMessage::Chunk(chunk) | IncomingCommandoMessage::Done(chunk)) = &msg;
+                        if epochs.is_retired(chunk.req_id, matches!(msg, IncomingCommandoMessage::Done(_))) {
+                            tracing::trace!("pump: dropping reply {} of an earlier connection", chunk.req_id);
+                            continue;
+                        }
+                        if discarding.contains(&chunk.req_id) {
+                            if matches!(msg, IncomingCommandoMessage::Done(_)) {
+                                discarding.remove(&chunk.req_id);
+                            }
+                            continue;
+                        }
+                        handle_reply(&mut pending, &mut replies, &mut discarding, &notify_tx, msg);
+                        if cfg.max_unsolicited.is_some_and(|max| unsolicited_bytes(&pending, &replies) > max) {
+                            tracing::debug!("pump: too many unsolicited reply bytes, dropping them");
+                            discarding.extend(drop_unsolicited(&pending, &mut replies));
+                        }
+                        if let Some(max) = cfg.max_buffered {
+                            shed_load(&mut pending, &mut replies, &mut discarding, max);
+                        }
+                    }
+                    Ok(other) => {
+                        tracing::trace!("pump: other_msg {}", other.type_id());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Fail the calls whose deadline is up. What came of their replies is dropped, and replies
+/// still on their way for calls that were sent go in `discarding`.
+fn expire_calls(
+    pending: &mut HashMap<u64, InProgress>,
+    queue: &mut Vec<InProgress>,
+    replies: &mut ReplyAssembler,
+    discarding: &mut HashSet<u64>,
+    now: Instant,
+) {
+    let expired = |p: &InProgress| p.deadline.is_some_and(|deadline| deadline <= now);
+    let ids: Vec<u64> = pending
+        .iter()
+        .filter(|(_, p)| expired(p))
+        .map(|(id, _)| *id)
+        .collect();
+    for req_id in ids {
+        let p = pending.remove(&req_id).expect("collected above");
+        tracing::debug!("pump: [{req_id}] timed out, dropping it");
+        replies.discard(req_id);
+        discarding.insert(req_id);
+        p.finish(Err(Error::Io(std::io::ErrorKind::TimedOut)));
+    }
+    // queued calls wait for a resend, their old replies died with the connection
+    let (timed_out, waiting) = std::mem::take(queue).into_iter().partition(expired);
+    *queue = waiting;
+    for p in timed_out {
+        p.finish(Err(Error::Io(std::io::ErrorKind::TimedOut)));
+    }
+}
+
+/// Drop the calls whose caller stopped waiting, throwing away the rest of their replies as
+/// [`expire_calls`] does.
+fn drop_abandoned(
+    pending: &mut HashMap<u64, InProgress>,
+    queue: &mut Vec<InProgress>,
+    replies: &mut ReplyAssembler,
+    discarding: &mut HashSet<u64>,
+) {
+    pending.retain(|req_id, p| {
+        let waited_for = !p.done_tx.is_closed();
+        if !waited_for {
+            tracing::debug!("pump: [{req_id}] abandoned, dropping it");
+            replies.discard(*req_id);
+            discarding.insert(*req_id);
         }
+        waited_for
+    });
+    queue.retain(|p| !p.done_tx.is_closed());
+}
+
+/// Bytes buffered for replies nobody called for, such as notifications.
+fn unsolicited_bytes(pending: &HashMap<u64, InProgress>, replies: &ReplyAssembler) -> usize {
+    let solicited: usize = pending.keys().map(|id| replies.buffered_for(*id)).sum();
+    replies.buffered() - solicited
+}
+
+/// Drop the unfinished replies nobody called for, returning their request ids.
+fn drop_unsolicited(pending: &HashMap<u64, InProgress>, replies: &mut ReplyAssembler) -> Vec<u64> {
+    let ids: Vec<u64> = replies
+        .unfinished()
+        .filter(|id| !pending.contains_key(id))
+        .collect();
+    for id in &ids {
+        replies.discard(*id);
+    }
+    ids
+}
+
+/// Fail the calls with the largest replies, and drop unsolicited fragments, until at most
+/// `max` reply bytes are buffered. Later fragments of their replies go in `discarding`.
+fn shed_load(
+    pending: &mut HashMap<u64, InProgress>,
+    replies: &mut ReplyAssembler,
+    discarding: &mut HashSet<u64>,
+    max: usize,
+) {
+    if replies.buffered() <= max {
+        return;
+    }
+    discarding.extend(drop_unsolicited(pending, replies));
+    while replies.buffered() > max {
+        let Some(req_id) = pending
+            .keys()
+            .copied()
+            .max_by_key(|id| replies.buffered_for(*id))
+        else {
+            break;
+        };
+        let p = pending.remove(&req_id).expect("found above");
+        tracing::warn!(
+            "pump: [{req_id}] reply of {} bytes exceeds the buffer cap, failing the call",
+            replies.buffered_for(req_id)
+        );
+        replies.discard(req_id);
+        discarding.insert(req_id);
+        p.finish(Err(Error::Overloaded));
+    }
+}
+
+/// Route an incoming reply fragment. Fragments are joined per request id in `replies`,
+/// except those of calls that stream to a sink; a finished body either completes its call
+/// or, if it is a notification, goes to the notification streams (leaving any call with
+/// that id waiting for its real reply).
+///
+/// Fragments follow the rules of [`ReplyAssembler`] whether or not there is a call for
+/// them: replies may interleave, empty fragments add nothing, and a terminal fragment
+/// with nothing before it is a reply of its own, so a duplicated one finishes nothing that
+/// came before it. A reply that comes out empty is ignored.
+fn handle_reply(
+    pending: &mut HashMap<u64, InProgress>,
+    replies: &mut ReplyAssembler,
+    discarding: &mut HashSet<u64>,
+    notify_tx: &broadcast::Sender<Notification>,
+    msg: IncomingCommandoMessage,
+) {
+    let done = matches!(msg, IncomingCommandoMessage::Done(_));
+    let (IncomingCommandoMessage::Chunk(chunk) | IncomingCommandoMessage::Done(chunk)) = &msg;
+    let req_id = chunk.req_id;
+    tracing::trace!("pump: [{req_id}] chunk {} done={done}", chunk.chunk.len());
+
+    if let Some(p) = pending.get_mut(&req_id) {
+        if done && chunk.chunk.is_empty() && p.bytes == 0 {
+            tracing::debug!("pump: [{req_id}] empty reply, ignoring it");
+            return;
+        }
+        if p.push_chunk(&chunk.chunk) == ProgressAction::Abort {
+            tracing::debug!("pump: [{req_id}] call aborted by its progress hook");
+            let p = pending.remove(&req_id).expect("found above");
+            replies.discard(req_id);
+            if !done {
+                discarding.insert(req_id);
+            }
+            p.finish(Err(Error::Cancelled));
+            return;
+        }
+        if let ReplyMode::Sink(_) = p.mode {
+            if done {
+                let p = pending.remove(&req_id).expect("found above");
+                let bytes = p.bytes;
+                p.finish(Ok(ReplyBody::Streamed(bytes)));
+            }
+            return;
+        }
+    }
+
+    let Some((req_id, body)) = replies.push(msg) else {
+        return;
+    };
+    let reply = match pending.get(&req_id).map(|p| &p.mode) {
+        Some(ReplyMode::Raw) => raw_commando_reply(req_id, &body),
+        _ => parse_commando_reply(req_id, &body),
+    };
+    match reply {
+        Reply::Notification(n) => {
+            if let Some(p) = pending.get_mut(&req_id) {
+                p.reset_reply();
+            }
+            let _ = notify_tx.send(n);
+        }
+        Reply::Response(res) => match pending.remove(&req_id) {
+            Some(p) => p.finish(res),
+            None => tracing::debug!("pump: [{req_id}] reply for unknown request"),
+        },
+    }
+}
+
+/// A complete commando reply body.
+enum Reply {
+    Response(Result<ReplyBody, Error>),
+    Notification(Notification),
+}
+
+fn parse_commando_reply(req_id: u64, buf: &[u8]) -> Reply {
+    match decode_reply(req_id, buf) {
+        CommandoEvent::Reply { result, .. } => Reply::Response(result.map(ReplyBody::Value)),
+        CommandoEvent::Notification { notification, .. } => Reply::Notification(notification),
+    }
+}
+
+/// Like [`parse_commando_reply`], but keep a response as bytes. The notification check
+/// deserializes just the two fields it needs, skipping over everything else.
+fn raw_commando_reply(req_id: u64, buf: &[u8]) -> Reply {
+    #[derive(Deserialize)]
+    struct Probe {
+        method: Option<String>,
+        id: Option<IgnoredAny>,
+    }
+
+    let buf = match maybe_decompress(buf) {
+        Ok(buf) => buf,
+        Err(err) => return Reply::Response(Err(err)),
+    };
+    match serde_json::from_slice::<Probe>(&buf) {
+        Ok(Probe {
+            method: Some(_),
+            id: None,
+        }) => parse_commando_reply(req_id, &buf),
+        Ok(_) => Reply::Response(Ok(ReplyBody::Raw(buf.into_owned()))),
+        Err(_) => Reply::Response(Err(Error::Json)),
+    }
+}
+
+async fn reconnect<T: MessageTransport>(
+    cfg: &CommandoConfig,
+    sock: &mut T,
+    load: &Load,
+    epochs: &mut Epochs,
+    pending: &mut HashMap<u64, InProgress>,
+    queued_while_down: &mut Vec<InProgress>,
+    err: &Error,
+) -> Result<(), ()> {
+    let ReconnectMode::Auto {
+        max_attempts,
+        base_backoff,
+        max_backoff,
+    } = cfg.reconnect
+    else {
+        return Err(());
+    };
+
+    // replies to the commands written so far may still arrive on the next connection
+    epochs.retire(pending.keys().copied());
+
+    // Decide what to retry (respect per-request policy)
+    let mut to_retry = Vec::new();
+    let mut aborted = Vec::new();
+    for (_id, mut p) in pending.drain() {
+        match p.policy {
+            RetryPolicy::Always { max_retries } if p.attempts < max_retries => {
+                p.attempts += 1;
+                p.reset_reply();
+                to_retry.push(p);
+            }
+            _ => aborted.push(p),
+        }
+    }
+    if !aborted.is_empty() {
+        terminate(aborted, TerminationCause::classify(err));
+    }
+    queued_while_down.extend(to_retry);
+
+    // Exponential backoff (no RNG jitter here to keep deps minimal)
+    let mut attempt = 0usize;
+    let mut delay = base_backoff.min(max_backoff);
+
+    loop {
+        match sock.reconnect().await {
+            Ok(new_sock) => {
+                tracing::info!("reconnected!");
+                *sock = new_sock;
+                epochs.next(load);
+                break;
+            }
+            Err(dial_err) => {
+                attempt += 1;
+                // the policy won't change its mind on a retry
+                if attempt >= max_attempts || matches!(dial_err, Error::DialDenied(_)) {
+                    tracing::error!("reconnect exhausted after {attempt} attempts: {dial_err}");
+                    // Fail any still-queued items
+                    terminate(queued_while_down.drain(..), TerminationCause::classify(err));
+                    return Err(());
+                }
+                tracing::warn!("reconnect failed: {dial_err}; retrying in {:?}", delay);
+                cfg.clock.sleep(delay).await;
+                delay = (delay * 2).min(max_backoff);
+            }
+        }
+    }
+
+    // Resend queued (preserve remainder on early failure)
+    if !queued_while_down.is_empty() {
+        tracing::info!("attempting to retry {} commands", queued_while_down.len());
+    }
+
+    // Take ownership of queued items so we can iterate by value.
+    // If we fail partway, we’ll put the current and remaining items back.
+    let mut rest = std::mem::take(queued_while_down).into_iter();
+
+    while let Some(mut p) = rest.next() {
+        p.renumber(load.alloc_id(cfg.random_ids));
+        if write_command(sock, &p.cmd).await.is_ok() {
+            pending.insert(p.cmd.req_id(), p);
+        } else {
+            // Put back the current item and all remaining ones, preserving order.
+            queued_while_down.push(p);
+            queued_while_down.extend(rest); // moves the remaining items
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Fail every pending and queued call with `err`.
+fn fail_all(pending: &mut HashMap<u64, InProgress>, queue: &mut Vec<InProgress>, err: Error) {
+    for (_id, p) in pending.drain() {
+        p.finish(Err(err.clone()));
+    }
+    for p in queue.drain(..) {
+        p.finish(Err(err.clone()));
+    }
+}
+
+/// Fail `calls`, oldest first, with an `Error::PumpTerminated` that says how the connection
+/// ended and what was lost with it.
+fn terminate(calls: impl IntoIterator<Item = InProgress>, cause: TerminationCause) {
+    let mut calls: Vec<InProgress> = calls.into_iter().collect();
+    calls.sort_by_key(|p| p.cmd.req_id());
+    let partial_replies = calls
+        .iter()
+        .filter(|p| p.bytes > 0)
+        .map(|p| (p.cmd.req_id(), p.bytes))
+        .collect();
+    let err = Error::PumpTerminated(Box::new(PumpTermination {
+        cause,
+        aborted_calls: calls.len(),
+        partial_replies,
+    }));
+    tracing::debug!("pump: {err}");
+    for p in calls {
+        p.finish(Err(err.clone()));
+    }
+}
+
+async fn handle_broken_pipe<T: MessageTransport>(
+    cfg: &CommandoConfig,
+    sock: &mut T,
+    load: &Load,
+    epochs: &mut Epochs,
+    pending: &mut HashMap<u64, InProgress>,
+    queue: &mut Vec<InProgress>,
+    err: &Error,
+) -> Result<(), ()> {
+    match cfg.reconnect {
+        ReconnectMode::Never => {
+            let calls = pending.drain().map(|(_, p)| p).chain(queue.drain(..));
+            terminate(calls, TerminationCause::classify(err));
+            Err(())
+        }
+        ReconnectMode::Auto { .. } => reconnect(cfg, sock, load, epochs, pending, queue, err).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lnsocket::testing::*;
+    use crate::util::ser::{Writeable, Writer};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    fn mk_cmd(id: u64) -> CommandoCommand {
+        CommandoCommand::new(
+            id,
+            format!("m{id}"),
+            "rune".to_string(),
+            serde_json::Value::Null,
+            None,
+        )
+    }
+
+    /// Build an InProgress with a fresh oneshot so callers can assert how it completes.
+    fn mk_ip(
+        id: u64,
+        policy: RetryPolicy,
+        attempts: usize,
+    ) -> (InProgress, oneshot::Receiver<Result<ReplyBody, Error>>) {
+        let (tx, rx) = oneshot::channel();
+        let mut ip = InProgress::new(
+            mk_cmd(id),
+            policy,
+            ReplyMode::Value,
+            tx,
+            None,
+            SharedClock::default(),
+            tracing::Span::none(),
+        );
+        ip.attempts = attempts;
+        (ip, rx)
+    }
+
+    #[test]
+    fn check_params_prepend_method() {
+        assert_eq!(
+            check_params("invoice".into(), serde_json::json!({"label": "x"})).unwrap(),
+            serde_json::json!({"command_to_check": "invoice", "label": "x"})
+        );
+        assert_eq!(
+            check_params("invoice".into(), serde_json::json!([1000, "x"])).unwrap(),
+            serde_json::json!(["invoice", 1000, "x"])
+        );
+        assert_eq!(
+            check_params("getinfo".into(), Value::Null).unwrap(),
+            serde_json::json!({"command_to_check": "getinfo"})
+        );
+        assert!(check_params("getinfo".into(), serde_json::json!(1)).is_err());
+    }
+
+    #[test]
+    fn check_outcome_from_rpc_error() {
+        let err = |code| RpcError {
+            code,
+            message: String::new(),
+            data: None,
+        };
+        assert!(matches!(
+            CheckOutcome::from_rpc_error(err(-32602)),
+            CheckOutcome::InvalidParams(_)
+        ));
+        assert!(matches!(
+            CheckOutcome::from_rpc_error(err(19537)),
+            CheckOutcome::Unauthorized(_)
+        ));
+        assert!(matches!(
+            CheckOutcome::from_rpc_error(err(-1)),
+            CheckOutcome::Other(_)
+        ));
+    }
+
+    impl Writeable for IncomingCommandoMessage {
+        fn write<W: Writer>(&self, w: &mut W) -> Result<(), std::io::Error> {
+            let (IncomingCommandoMessage::Chunk(c) | IncomingCommandoMessage::Done(c)) = self;
+            c.req_id.write(w)?;
+            w.write_all(&c.chunk)
+        }
+    }
+
+    fn test_config() -> CommandoConfig {
+        CommandoConfig::new().no_reconnect().random_ids(false)
+    }
+
+    #[tokio::test]
+    async fn close_finishes_in_flight_calls() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let client = Arc::new(CommandoClient::spawn_with_config(
+            sock,
+            "rune",
+            test_config(),
+        ));
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call("getinfo", serde_json::json!({})).await }
+        });
+        // the command is on the wire before we ask the pump to close
+        peer_recv(&mut server, &mut peer).await;
+
+        let close = tokio::spawn({
+            let client = client.clone();
+            async move { client.close().await }
+        });
+        while !client.handle().tx.is_closed() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(client.exit_reason(), None);
+
+        peer_send(
+            &mut server,
+            &mut peer,
+            &reply(1, br#"{"id":1,"result":{"ok":true}}"#, true),
+        )
+        .await;
+
+        assert_eq!(call.await.unwrap().unwrap()["ok"], true);
+        assert_eq!(close.await.unwrap(), PumpExit::Closed);
+        assert!(matches!(
+            client.call("getinfo", serde_json::json!({})).await,
+            Err(Error::PumpExited(PumpExit::Closed))
+        ));
+    }
+
+    #[tokio::test]
+    async fn calls_report_why_the_pump_exited() {
+        let (sock, server, _peer) = loopback_pair().await;
+        let client = CommandoClient::spawn_with_config(sock, "rune", test_config());
+
+        drop(server);
+        assert_eq!(client.closed().await, PumpExit::Disconnected);
+        assert!(matches!(
+            client.call("getinfo", serde_json::json!({})).await,
+            Err(Error::PumpExited(PumpExit::Disconnected))
+        ));
+    }
+
+    #[tokio::test]
+    async fn calls_aborted_by_eof_are_told_what_was_lost() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let client = Arc::new(CommandoClient::spawn_with_config(
+            sock,
+            "rune",
+            test_config(),
+        ));
+        let mut calls = Vec::new();
+        for _ in 0..2 {
+            let client = client.clone();
+            calls.push(tokio::spawn(async move {
+                client.call("getinfo", serde_json::json!({})).await
+            }));
+            peer_recv(&mut server, &mut peer).await;
+        }
+        peer_send(&mut server, &mut peer, &reply(2, br#"{"result":"#, false)).await;
+        // make sure the chunk is in before hanging up
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 0,
+        };
+        peer_send(&mut server, &mut peer, &ping).await;
+        peer_recv(&mut server, &mut peer).await;
+        drop(server);
+
+        for call in calls {
+            let Err(Error::PumpTerminated(term)) = call.await.unwrap() else {
+                panic!("the call should see the connection end");
+            };
+            assert!(matches!(term.cause, TerminationCause::Eof));
+            assert_eq!(term.aborted_calls, 2);
+            assert_eq!(term.partial_replies, [(2, 10)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_hook_pings_then_disconnects() {
+        use std::sync::atomic::AtomicUsize;
+
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let config = test_config().on_idle(Duration::from_millis(50), {
+            let calls = calls.clone();
+            move |ctx| {
+                assert!(ctx.idle_for >= Duration::from_millis(50));
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => IdleAction::Ping,
+                    _ => IdleAction::Disconnect,
+                }
+            }
+        });
+        let client = CommandoClient::spawn_with_config(sock, "rune", config);
+
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Ping(msgs::Ping { ponglen: 0, .. })
+        ));
+        // no pong, so the connection stays quiet
+        assert_eq!(client.closed().await, PumpExit::Idle);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_rotation_keeps_the_connection() {
+        // the listener behind the socket is gone, so reconnecting fails
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let config = test_config().max_lifetime(Some(Duration::from_millis(50)));
+        let client = CommandoClient::spawn_with_config(sock, "rune", config);
+        let mut rotations = client.rotations();
+
+        assert!(matches!(
+            rotations.recv().await.unwrap(),
+            RotationEvent::Started { age } if age >= Duration::from_millis(50)
+        ));
+        assert_eq!(rotations.recv().await.unwrap(), RotationEvent::Failed);
+
+        let call = tokio::spawn(async move { client.call("getinfo", serde_json::json!({})).await });
+        peer_recv(&mut server, &mut peer).await;
+        peer_send(&mut server, &mut peer, &reply(1, br#"{"result":{}}"#, true)).await;
+        assert_eq!(call.await.unwrap().unwrap(), serde_json::json!({}));
+    }
+
+    /// A [`FakeTransport`] that reconnects to the next of `next`.
+    struct Reconnecting {
+        conn: FakeTransport,
+        next: Arc<std::sync::Mutex<Vec<FakeTransport>>>,
+    }
+
+    impl MessageTransport for Reconnecting {
+        async fn read_frame(&mut self) -> Result<(u16, Vec<u8>), Error> {
+            self.conn.read_frame().await
+        }
+
+        async fn write_frame(&mut self, frame: Frame) -> Result<(), Error> {
+            self.conn.write_frame(frame).await
+        }
+
+        async fn reconnect(&self) -> Result<Self, Error> {
+            let conn = self.next.lock().unwrap().pop().ok_or(Error::NotConnected)?;
+            Ok(Reconnecting {
+                conn,
+                next: self.next.clone(),
+            })
+        }
+
+        fn their_pubkey(&self) -> PublicKey {
+            key(2)
+        }
+
+        fn our_node_id(&self) -> PublicKey {
+            key(1)
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_from_an_earlier_connection_are_dropped() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        let (fake_after, to_client_after, mut from_client_after) = FakeTransport::new();
+        let sock = Reconnecting {
+            conn: fake,
+            next: Arc::new(std::sync::Mutex::new(vec![fake_after])),
+        };
+        let config = test_config().reconnect(1, Duration::ZERO, Duration::ZERO);
+        let client = CommandoClient::spawn_with_config(sock, "rune", config);
+
+        let call = tokio::spawn(async move { client.call("getinfo", serde_json::json!({})).await });
+        let frame = from_client.recv().await.unwrap();
+        assert_eq!(&frame.1[..8], &1u64.to_be_bytes());
+        drop(to_client);
+
+        // resent on the new connection, with a new id
+        let resent_id: u64 = 2;
+        let frame = from_client_after.recv().await.unwrap();
+        assert_eq!(&frame.1[..8], &resent_id.to_be_bytes());
+
+        // the node answers the first command late, on the new connection
+        let mut stale = 1u64.to_be_bytes().to_vec();
+        stale.extend_from_slice(br#"{"result":{"alias":"#);
+        to_client_after.send((COMMANDO_REPLY_CONT, stale)).unwrap();
+        let mut payload = resent_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(br#"{"result":{"alias":"node"}}"#);
+        to_client_after
+            .send((COMMANDO_REPLY_TERM, payload))
+            .unwrap();
+        assert_eq!(
+            call.await.unwrap().unwrap(),
+            serde_json::json!({"alias": "node"})
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_write_retires_replies_being_discarded() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        let (fake_after, to_client_after, mut from_client_after) = FakeTransport::new();
+        let sock = Reconnecting {
+            conn: fake,
+            next: Arc::new(std::sync::Mutex::new(vec![fake_after])),
+        };
+        let config = test_config()
+            .reconnect(1, Duration::ZERO, Duration::ZERO)
+            .max_buffered_reply_bytes(Some(16));
+        let client = Arc::new(CommandoClient::spawn_with_config(sock, "rune", config));
+
+        // the first call's reply outgrows the buffer, the rest of it is discarded
+        let big = tokio::spawn({
+            let client = client.clone();
+            async move { client.call("listpeers", serde_json::json!({})).await }
+        });
+        from_client.recv().await.unwrap();
+        let mut head = 1u64.to_be_bytes().to_vec();
+        head.extend_from_slice(&[b' '; 32]);
+        to_client.send((COMMANDO_REPLY_CONT, head)).unwrap();
+        assert!(matches!(big.await.unwrap(), Err(Error::Overloaded)));
+
+        // writing the next call fails, which moves the pump to the next connection
+        drop(from_client);
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call("getinfo", serde_json::json!({})).await }
+        });
+        let frame = from_client_after.recv().await.unwrap();
+        let resent_id = u64::from_be_bytes(frame.1[..8].try_into().unwrap());
+        assert_eq!(resent_id, 3);
+
+        // the tail of the discarded reply arrives late, on the new connection
+        let mut tail = 1u64.to_be_bytes().to_vec();
+        tail.extend_from_slice(b"{}");
+        to_client_after.send((COMMANDO_REPLY_TERM, tail)).unwrap();
+        let mut payload = resent_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(br#"{"result":{"alias":"node"}}"#);
+        to_client_after
+            .send((COMMANDO_REPLY_TERM, payload))
+            .unwrap();
+        assert_eq!(
+            call.await.unwrap().unwrap(),
+            serde_json::json!({"alias": "node"})
+        );
+        drop(to_client);
+    }
+
+    #[tokio::test]
+    async fn connections_are_counted_across_pumps() {
+        let (fake, to_client, _from_client) = FakeTransport::new();
+        let client = CommandoClient::spawn_with_config(fake, "rune", test_config());
+        drop(to_client);
+        client.closed().await;
+
+        let (fake, _to_client, _from_client) = FakeTransport::new();
+        client.replace_socket(fake).await;
+        assert_eq!(client.load().next_epoch(), Epoch(3));
+    }
+
+    #[tokio::test]
+    async fn calls_lost_to_a_failed_reconnect_are_told_why() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        // FakeTransport never reconnects
+        let config = test_config().reconnect(1, Duration::ZERO, Duration::ZERO);
+        let client = Arc::new(CommandoClient::spawn_with_config(fake, "rune", config));
+
+        let no_retry = CallOpts {
+            retry_policy: Some(RetryPolicy::Never),
+            ..CallOpts::new()
+        };
+        let mut calls = Vec::new();
+        for opts in [no_retry, CallOpts::new()] {
+            let client = client.clone();
+            calls.push(tokio::spawn(async move {
+                client
+                    .call_with_opts("getinfo", serde_json::json!({}), opts)
+                    .await
+            }));
+            from_client.recv().await.unwrap();
+        }
+        drop(to_client);
+
+        // the call that won't be resent fails right away, the other once reconnecting gives up
+        for call in calls {
+            let Err(Error::PumpTerminated(term)) = call.await.unwrap() else {
+                panic!("the call should see the connection end");
+            };
+            assert!(matches!(term.cause, TerminationCause::Eof));
+            assert_eq!(term.aborted_calls, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn pump_runs_over_any_transport() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        let client = CommandoClient::spawn_with_config(fake, "rune", test_config());
+        assert_eq!(client.our_node_id(), key(1));
+
+        let call = tokio::spawn(async move { client.call("getinfo", serde_json::json!({})).await });
+        let frame = from_client.recv().await.unwrap();
+        assert_eq!(frame.0, COMMANDO_COMMAND);
+        assert_eq!(&frame.1[..8], &1u64.to_be_bytes());
+
+        let mut payload = 1u64.to_be_bytes().to_vec();
+        payload.extend_from_slice(br#"{"result":{"id":"02ab"}}"#);
+        to_client.send((COMMANDO_REPLY_TERM, payload)).unwrap();
+        assert_eq!(
+            call.await.unwrap().unwrap(),
+            serde_json::json!({"id": "02ab"})
+        );
+    }
+
+    #[tokio::test]
+    async fn odd_fragment_sequences_finish_the_right_calls() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        let client = Arc::new(CommandoClient::spawn_with_config(
+            fake,
+            "rune",
+            test_config(),
+        ));
+
+        let call = |method: &'static str| {
+            let client = client.clone();
+            tokio::spawn(async move { client.call(method, serde_json::json!({})).await })
+        };
+        let first = call("getinfo");
+        let first_id = from_client.recv().await.unwrap().1[..8].to_vec();
+        let second = call("listpeers");
+        let second_id = from_client.recv().await.unwrap().1[..8].to_vec();
+
+        let send = |typ: u16, id: &[u8], body: &[u8]| {
+            let mut payload = id.to_vec();
+            payload.extend_from_slice(body);
+            to_client.send((typ, payload)).unwrap();
+        };
+        // interleaved, with empty fragments, and a lone empty terminal that is no reply
+        send(COMMANDO_REPLY_TERM, &first_id, b"");
+        send(COMMANDO_REPLY_CONT, &first_id, br#"{"result":"#);
+        send(COMMANDO_REPLY_CONT, &second_id, b"");
+        send(COMMANDO_REPLY_CONT, &second_id, br#"{"result":"#);
+        send(COMMANDO_REPLY_CONT, &first_id, b"");
+        send(COMMANDO_REPLY_TERM, &first_id, br#""one"}"#);
+        // the terminal again, after its call is done, doesn't end up in the other reply
+        send(COMMANDO_REPLY_TERM, &first_id, br#""one"}"#);
+        send(COMMANDO_REPLY_TERM, &second_id, br#""two"}"#);
+
+        assert_eq!(first.await.unwrap().unwrap(), "one");
+        assert_eq!(second.await.unwrap().unwrap(), "two");
+
+        let third = call("getinfo");
+        let third_id = from_client.recv().await.unwrap().1[..8].to_vec();
+        send(COMMANDO_REPLY_TERM, &third_id, br#"{"result":"three"}"#);
+        assert_eq!(third.await.unwrap().unwrap(), "three");
+    }
+
+    #[tokio::test]
+    async fn expired_runes_are_renewed_once() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        let renewals = Arc::new(AtomicUsize::new(0));
+        let config = test_config().rune_provider({
+            let renewals = renewals.clone();
+            move || {
+                let n = renewals.fetch_add(1, Ordering::Relaxed);
+                async move { Ok(format!("fresh{n}")) }
+            }
+        });
+        let client = CommandoClient::spawn_with_config(fake, "old", config);
+
+        let call = tokio::spawn(async move {
+            let res = client.call("getinfo", serde_json::json!({})).await;
+            (client, res)
+        });
+        let mut answer = async |body: &[u8]| {
+            let frame = from_client.recv().await.unwrap();
+            let cmd: Value = serde_json::from_slice(&frame.1[8..]).unwrap();
+            let mut payload = frame.1[..8].to_vec();
+            payload.extend_from_slice(body);
+            to_client.send((COMMANDO_REPLY_TERM, payload)).unwrap();
+            cmd["rune"].as_str().unwrap().to_string()
+        };
+        let expired =
+            br#"{"error":{"code":19537,"message":"Not permitted: time is greater or equal to 1"}}"#;
+        assert_eq!(answer(expired).await, "old");
+        assert_eq!(answer(br#"{"result":{}}"#).await, "fresh0");
+        let (client, res) = call.await.unwrap();
+        assert_eq!(res.unwrap(), serde_json::json!({}));
+
+        // the new rune sticks, and a call is retried only once
+        let call = tokio::spawn(async move { client.call("getinfo", serde_json::json!({})).await });
+        assert_eq!(answer(expired).await, "fresh0");
+        assert_eq!(answer(expired).await, "fresh1");
+        assert!(matches!(call.await.unwrap(), Err(Error::Rpc(err)) if err.is_rune_expired()));
+        assert_eq!(renewals.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn checks_renew_the_client_rune() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        let config = test_config().rune_provider(|| async { Ok("fresh".to_string()) });
+        let client = CommandoClient::spawn_with_config(fake, "old", config);
+
+        let check = tokio::spawn(async move { client.check("getinfo", Value::Null).await });
+        let mut answer = async |body: &[u8]| {
+            let frame = from_client.recv().await.unwrap();
+            let cmd: Value = serde_json::from_slice(&frame.1[8..]).unwrap();
+            let mut payload = frame.1[..8].to_vec();
+            payload.extend_from_slice(body);
+            to_client.send((COMMANDO_REPLY_TERM, payload)).unwrap();
+            cmd["rune"].as_str().unwrap().to_string()
+        };
+        let expired =
+            br#"{"error":{"code":19537,"message":"Not permitted: time is greater or equal to 1"}}"#;
+        assert_eq!(answer(expired).await, "old");
+        assert_eq!(answer(br#"{"result":{}}"#).await, "fresh");
+        assert!(matches!(check.await.unwrap(), Ok(CheckOutcome::Ok)));
+    }
+
+    #[tokio::test]
+    async fn calls_pick_the_rune_of_their_method() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        let client = Arc::new(CommandoClient::spawn_with_config(
+            fake,
+            "default",
+            test_config(),
+        ));
+        client.add_rune("list*", "read");
+        client.add_rune("listpays", "payments");
+        client.add_rune("pay", "pay");
+
+        for (method, opts, rune) in [
+            ("listfunds", CallOpts::new(), "read"),
+            ("listpays", CallOpts::new(), "payments"),
+            ("pay", CallOpts::new(), "pay"),
+            ("payer", CallOpts::new(), "default"),
+            ("listfunds", CallOpts::new().rune("given".into()), "given"),
+        ] {
+            let call = tokio::spawn({
+                let client = client.clone();
+                async move { client.call_with_opts(method, Value::Null, opts).await }
+            });
+            let frame = from_client.recv().await.unwrap();
+            let cmd: Value = serde_json::from_slice(&frame.1[8..]).unwrap();
+            assert_eq!(cmd["method"], method);
+            assert_eq!(cmd["rune"], rune, "{method}");
+            let mut payload = frame.1[..8].to_vec();
+            payload.extend_from_slice(br#"{"result":{}}"#);
+            to_client.send((COMMANDO_REPLY_TERM, payload)).unwrap();
+            call.await.unwrap().unwrap();
+        }
+
+        assert_eq!(client.remove_rune("list*").as_deref(), Some("read"));
+        assert_eq!(client.rune_for("listfunds"), "default");
+        client.add_rune("*", "any");
+        client.add_rune("*", "every");
+        assert_eq!(client.rune_for("listfunds"), "every");
+        assert_eq!(client.rune_for("pay"), "pay");
     }
-}
 
-fn parse_commando_response(buf: &[u8]) -> Result<Value, Error> {
-    let value = serde_json::from_slice::<Value>(buf).map_err(|_| Error::Json)?;
-    let obj = value.as_object().ok_or(Error::Json)?;
+    #[tokio::test]
+    async fn rekeying_swaps_the_connection_only_once_it_is_up() {
+        /// Never says anything; reconnects as any key but `key(9)`.
+        struct Quiet(PublicKey);
 
-    if let Some(error) = obj.get("error") {
-        let rpc_err: RpcError =
-            serde_json::from_value(error.clone()).unwrap_or_else(|_| RpcError {
-                code: -1,
-                message: serde_json::to_string(error).unwrap(),
-            });
-        return Err(Error::Rpc(rpc_err));
-    }
+        impl MessageTransport for Quiet {
+            async fn read_frame(&mut self) -> Result<(u16, Vec<u8>), Error> {
+                std::future::pending().await
+            }
 
-    match obj.get("result") {
-        None => Err(Error::Json),
-        Some(res) => Ok(res.clone()),
-    }
-}
+            async fn write_frame(&mut self, _frame: Frame) -> Result<(), Error> {
+                Ok(())
+            }
 
-async fn reconnect(
-    sock: &mut LNSocket,
-    max_attempts: usize,
-    base_backoff: Duration,
-    max_backoff: Duration,
-    pending: &mut HashMap<u64, InProgress>,
-    queued_while_down: &mut Vec<InProgress>,
-) -> Result<(), ()> {
-    // Decide what to retry (respect per-request policy)
-    let mut to_retry = Vec::new();
-    for (_id, mut p) in pending.drain() {
-        match p.policy {
-            RetryPolicy::Always { max_retries } if p.attempts < max_retries => {
-                p.attempts += 1;
-                p.buf.clear();
-                to_retry.push(p);
+            async fn reconnect(&self) -> Result<Self, Error> {
+                Ok(Quiet(self.0))
+            }
+
+            async fn reconnect_as(&self, our_key: SecretKey) -> Result<Self, Error> {
+                let id = PublicKey::from_secret_key(
+                    &bitcoin::secp256k1::Secp256k1::signing_only(),
+                    &our_key,
+                );
+                if id == key(9) {
+                    return Err(Error::DialDenied("not this one".to_string()));
+                }
+                Ok(Quiet(id))
+            }
+
+            fn their_pubkey(&self) -> PublicKey {
+                key(2)
             }
-            _ => {
-                let _ = p
-                    .done_tx
-                    .send(Err(Error::Io(std::io::ErrorKind::BrokenPipe)));
+
+            fn our_node_id(&self) -> PublicKey {
+                self.0
             }
         }
+
+        let client = CommandoClient::spawn_with_config(Quiet(key(1)), "rune", test_config());
+        let mut rotations = client.rotations();
+        let secret = |byte| SecretKey::from_slice(&[byte; 32]).unwrap();
+
+        client.rekey_identity(secret(7)).await.unwrap();
+        assert_eq!(client.our_node_id(), key(7));
+        assert!(matches!(
+            rotations.recv().await.unwrap(),
+            RotationEvent::Started { .. }
+        ));
+        assert_eq!(rotations.recv().await.unwrap(), RotationEvent::Finished);
+
+        assert!(matches!(
+            client.rekey_identity(secret(9)).await,
+            Err(Error::DialDenied(_))
+        ));
+        assert_eq!(client.our_node_id(), key(7));
+        rotations.recv().await.unwrap();
+        assert_eq!(rotations.recv().await.unwrap(), RotationEvent::Failed);
+        assert_eq!(client.exit_reason(), None);
     }
-    queued_while_down.extend(to_retry);
 
-    // Exponential backoff (no RNG jitter here to keep deps minimal)
-    let mut attempt = 0usize;
-    let mut delay = base_backoff.min(max_backoff);
+    #[tokio::test]
+    async fn oversized_commands_go_out_in_chunks() {
+        let (fake, _to_client, mut from_client) = FakeTransport::new();
+        let client = CommandoClient::spawn_with_config(fake, "rune", test_config());
 
-    loop {
-        match sock.reconnect_fresh().await {
-            Ok(new_sock) => {
-                tracing::info!("reconnected!");
-                *sock = new_sock;
+        let description = "x".repeat(100_000);
+        tokio::spawn(async move {
+            let _ = client
+                .call("invoice", serde_json::json!({ "description": description }))
+                .await;
+        });
+        let mut types = Vec::new();
+        let mut body = Vec::new();
+        loop {
+            let frame = from_client.recv().await.unwrap();
+            assert_eq!(&frame.1[..8], &1u64.to_be_bytes());
+            body.extend_from_slice(&frame.1[8..]);
+            types.push(frame.0);
+            if frame.0 == COMMANDO_COMMAND {
                 break;
             }
-            Err(err) => {
-                attempt += 1;
-                if attempt >= max_attempts {
-                    tracing::error!("reconnect exhausted after {attempt} attempts: {err}");
-                    // Fail any still-queued items
-                    for p in queued_while_down.drain(..) {
-                        let _ = p
-                            .done_tx
-                            .send(Err(Error::Io(std::io::ErrorKind::BrokenPipe)));
-                    }
-                    return Err(());
-                }
-                tracing::warn!("reconnect failed: {err}; retrying in {:?}", delay);
-                tokio::time::sleep(delay).await;
-                delay = (delay * 2).min(max_backoff);
-            }
         }
+        assert_eq!(types, [COMMANDO_COMMAND_CONT, COMMANDO_COMMAND]);
+        let cmd: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            cmd["params"]["description"].as_str().unwrap().len(),
+            100_000
+        );
     }
 
-    // Resend queued (preserve remainder on early failure)
-    if !queued_while_down.is_empty() {
-        tracing::info!("attempting to retry {} commands", queued_while_down.len());
+    #[tokio::test(start_paused = true)]
+    async fn call_timeouts_follow_the_clock() {
+        let (fake, _to_client, mut from_client) = FakeTransport::new();
+        let client = Arc::new(CommandoClient::spawn_with_config(
+            fake,
+            "rune",
+            test_config().timeout(Some(Duration::from_secs(3600))),
+        ));
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call("slow", serde_json::json!({})).await }
+        });
+        from_client.recv().await.unwrap();
+        tokio::time::advance(Duration::from_secs(3599)).await;
+        assert!(!call.is_finished());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(matches!(
+            call.await.unwrap(),
+            Err(Error::Io(std::io::ErrorKind::TimedOut))
+        ));
     }
 
-    // Take ownership of queued items so we can iterate by value.
-    // If we fail partway, we’ll put the current and remaining items back.
-    let mut rest = std::mem::take(queued_while_down).into_iter();
+    #[tokio::test]
+    async fn timed_out_calls_leave_the_pump() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        let client = Arc::new(CommandoClient::spawn_with_config(
+            fake,
+            "rune",
+            test_config(),
+        ));
 
-    while let Some(p) = rest.next() {
-        if sock.write(&p.cmd).await.is_ok() {
-            pending.insert(p.cmd.req_id(), p);
-        } else {
-            // Put back the current item and all remaining ones, preserving order.
-            queued_while_down.push(p);
-            queued_while_down.extend(rest); // moves the remaining items
-            return Err(());
-        }
+        let opts = CallOpts::new().timeout(Duration::from_millis(20));
+        assert!(matches!(
+            client
+                .call_with_opts("slow", serde_json::json!({}), opts)
+                .await,
+            Err(Error::Io(std::io::ErrorKind::TimedOut))
+        ));
+        from_client.recv().await.unwrap();
+
+        // the late reply is thrown away rather than buffered
+        let chunk = |id: u64, body: &[u8]| {
+            let mut payload = id.to_be_bytes().to_vec();
+            payload.extend_from_slice(body);
+            payload
+        };
+        to_client
+            .send((COMMANDO_REPLY_CONT, chunk(1, &[b' '; 1000])))
+            .unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call("getinfo", serde_json::json!({})).await }
+        });
+        from_client.recv().await.unwrap();
+        to_client
+            .send((COMMANDO_REPLY_TERM, chunk(2, br#"{"result":{}}"#)))
+            .unwrap();
+        call.await.unwrap().unwrap();
+        assert_eq!(client.load.buffered.load(Ordering::Relaxed), 0);
     }
 
-    Ok(())
-}
+    #[cfg(feature = "cancel")]
+    #[tokio::test]
+    async fn cancelled_calls_leave_the_pump() {
+        let (fake, to_client, mut from_client) = FakeTransport::new();
+        let client = Arc::new(CommandoClient::spawn_with_config(
+            fake,
+            "rune",
+            test_config(),
+        ));
 
-async fn handle_broken_pipe(
-    cfg: &CommandoConfig,
-    sock: &mut LNSocket,
-    pending: &mut HashMap<u64, InProgress>,
-    queue: &mut Vec<InProgress>,
-) -> Result<(), ()> {
-    match cfg.reconnect {
-        ReconnectMode::Never => {
-            for (_id, p) in pending.drain() {
-                let _ = p
-                    .done_tx
-                    .send(Err(Error::Io(std::io::ErrorKind::BrokenPipe)));
-            }
-            for p in queue.drain(..) {
-                let _ = p
-                    .done_tx
-                    .send(Err(Error::Io(std::io::ErrorKind::BrokenPipe)));
+        let token = CancellationToken::new();
+        let call = tokio::spawn({
+            let client = client.clone();
+            let opts = CallOpts::new().cancel_on(token.clone());
+            async move {
+                client
+                    .call_with_opts("slow", serde_json::json!({}), opts)
+                    .await
             }
-            Err(())
-        }
-        ReconnectMode::Auto {
-            max_attempts,
-            base_backoff,
-            max_backoff,
-        } => {
-            reconnect(
-                sock,
-                max_attempts,
-                base_backoff,
-                max_backoff,
-                pending,
-                queue,
-            )
+        });
+        from_client.recv().await.unwrap();
+        token.cancel();
+        assert!(matches!(call.await.unwrap(), Err(Error::Cancelled)));
+
+        // the late reply is thrown away rather than buffered
+        let chunk = |id: u64, body: &[u8]| {
+            let mut payload = id.to_be_bytes().to_vec();
+            payload.extend_from_slice(body);
+            payload
+        };
+        to_client
+            .send((COMMANDO_REPLY_CONT, chunk(1, &[b' '; 1000])))
+            .unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call("getinfo", serde_json::json!({})).await }
+        });
+        from_client.recv().await.unwrap();
+        to_client
+            .send((COMMANDO_REPLY_TERM, chunk(2, br#"{"result":{}}"#)))
+            .unwrap();
+        call.await.unwrap().unwrap();
+        assert_eq!(client.load.buffered.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn peer_error_fails_calls_and_stops_the_pump() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        // would reconnect on a plain disconnect
+        let client = Arc::new(CommandoClient::spawn(sock, "rune"));
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call("getinfo", serde_json::json!({})).await }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        let error = msgs::ErrorMessage::new(None, "internal error");
+        peer_send(&mut server, &mut peer, &error).await;
+
+        assert!(matches!(
+            call.await.unwrap(),
+            Err(Error::PeerClosedConnection { message }) if message == "internal error"
+        ));
+        assert_eq!(
+            client.closed().await,
+            PumpExit::PeerClosed("internal error".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn calls_over_the_limits_are_refused() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let config = test_config()
+            .max_in_flight(Some(1))
+            .max_buffered_reply_bytes(Some(16));
+        let client = Arc::new(CommandoClient::spawn_with_config(sock, "rune", config));
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call("getinfo", serde_json::json!({})).await }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        assert!(matches!(
+            client.call("getinfo", serde_json::json!({})).await,
+            Err(Error::Overloaded)
+        ));
+
+        // a reply too big to buffer fails its call, and the rest of it is ignored
+        peer_send(&mut server, &mut peer, &reply(1, &[b' '; 32], false)).await;
+        assert!(matches!(call.await.unwrap(), Err(Error::Overloaded)));
+        peer_send(&mut server, &mut peer, &reply(1, br#"{}"#, true)).await;
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call("getinfo", serde_json::json!({})).await }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        peer_send(
+            &mut server,
+            &mut peer,
+            &reply(2, br#"{"id":2,"result":{}}"#, true),
+        )
+        .await;
+        assert!(call.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn unsolicited_fragments_are_capped() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let config = test_config().max_unsolicited_bytes(Some(16));
+        let client = CommandoClient::spawn_with_config(sock, "rune", config);
+        let mut notifications = client.notifications();
+
+        // a notification that grows past the cap is dropped, the rest of it too...
+        let tail = br#"{"jsonrpc":"2.0","method":"log","params":{"log":"tail"}}"#;
+        peer_send(&mut server, &mut peer, &reply(99, &[b'x'; 32], false)).await;
+        peer_send(&mut server, &mut peer, &reply(99, tail, true)).await;
+        // ...and the next one on the id is delivered whole
+        let note = br#"{"jsonrpc":"2.0","method":"log","params":{"log":"hi"}}"#;
+        peer_send(&mut server, &mut peer, &reply(99, note, true)).await;
+        let got = tokio::time::timeout(Duration::from_secs(5), notifications.next())
             .await
-        }
+            .unwrap()
+            .unwrap();
+        assert_eq!(got.params["log"], "hi");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::Duration;
-    use tokio::sync::oneshot;
+    #[tokio::test]
+    async fn large_reply_modes() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let client = Arc::new(CommandoClient::spawn_with_config(
+            sock,
+            "rune",
+            test_config(),
+        ));
+        let body = br#"{"jsonrpc":"2.0","id":1,"result":{"forwards":[]}}"#;
+        let (head, tail) = body.split_at(20);
 
-    fn mk_cmd(id: u64) -> CommandoCommand {
-        CommandoCommand::new(
-            id,
-            format!("m{id}"),
-            "rune".to_string(),
-            serde_json::Value::Null,
-            None,
+        // raw: the response bytes, with notifications on the same id still filtered out
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .call_raw("listforwards", serde_json::json!({}), CallOpts::new())
+                    .await
+            }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        let note = br#"{"jsonrpc":"2.0","method":"message","params":{}}"#;
+        peer_send(&mut server, &mut peer, &reply(1, note, true)).await;
+        peer_send(&mut server, &mut peer, &reply(1, head, false)).await;
+        peer_send(&mut server, &mut peer, &reply(1, tail, true)).await;
+        assert_eq!(call.await.unwrap().unwrap(), body);
+
+        // sink: fragments are passed on as they arrive
+        let (chunk_tx, mut chunk_rx) = mpsc::unbounded_channel();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move {
+                let sink = move |chunk: &[u8]| {
+                    let _ = chunk_tx.send(chunk.to_vec());
+                };
+                client
+                    .call_with_sink("listforwards", serde_json::json!({}), CallOpts::new(), sink)
+                    .await
+            }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        peer_send(&mut server, &mut peer, &reply(2, head, false)).await;
+        assert_eq!(chunk_rx.recv().await.unwrap(), head);
+        peer_send(&mut server, &mut peer, &reply(2, tail, true)).await;
+        assert_eq!(chunk_rx.recv().await.unwrap(), tail);
+        assert_eq!(call.await.unwrap().unwrap(), body.len());
+
+        // typed: straight into the caller's type, errors still surface as Error::Rpc
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .call_typed::<HashMap<String, Vec<Value>>>(
+                        "listforwards",
+                        serde_json::json!({}),
+                    )
+                    .await
+            }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        peer_send(&mut server, &mut peer, &reply(3, body, true)).await;
+        assert!(call.await.unwrap().unwrap()["forwards"].is_empty());
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .call_typed::<Value>("nope", serde_json::json!({}))
+                    .await
+            }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        let err = br#"{"id":4,"error":{"code":-32601,"message":"Unknown command"}}"#;
+        peer_send(&mut server, &mut peer, &reply(4, err, true)).await;
+        assert!(matches!(
+            call.await.unwrap(),
+            Err(Error::Rpc(RpcError { code: -32601, .. }))
+        ));
+
+        // a null result is fine for a type that takes null
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call_typed::<()>("stop", serde_json::json!({})).await }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        peer_send(
+            &mut server,
+            &mut peer,
+            &reply(5, br#"{"id":5,"result":null}"#, true),
+        )
+        .await;
+        call.await.unwrap().unwrap();
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .call_typed::<u32>("stop", serde_json::json!({}))
+                    .await
+            }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        peer_send(
+            &mut server,
+            &mut peer,
+            &reply(6, br#"{"id":6,"result":null}"#, true),
         )
+        .await;
+        assert!(matches!(call.await.unwrap(), Err(Error::Json)));
     }
 
-    /// Build an InProgress with a fresh oneshot so callers can assert how it completes.
-    fn mk_ip(
-        id: u64,
-        policy: RetryPolicy,
-        attempts: usize,
-    ) -> (InProgress, oneshot::Receiver<Result<Value, Error>>) {
-        let (tx, rx) = oneshot::channel();
-        let ip = InProgress {
-            cmd: mk_cmd(id),
-            done_tx: tx,
-            policy,
-            attempts,
-            buf: Vec::new(),
+    #[test]
+    fn request_ids_are_random_by_default() {
+        let (a, b) = (test_config(), CommandoConfig::new());
+        assert!(!a.random_ids && b.random_ids);
+    }
+
+    #[tokio::test]
+    async fn random_ids_are_not_counted() {
+        let (sock, _server, _peer) = loopback_pair().await;
+        let client = CommandoClient::spawn_with_config(sock, "rune", CommandoConfig::new());
+        let ids: Vec<u64> = (0..4).map(|_| client.alloc_id()).collect();
+        assert!(ids.windows(2).any(|w| w[1] != w[0] + 1));
+    }
+
+    fn parse_commando_response(buf: &[u8]) -> Result<Value, Error> {
+        match parse_commando_reply(1, buf) {
+            Reply::Response(res) => res.map(|body| match body {
+                ReplyBody::Value(value) => value,
+                body => panic!("unexpected body {body:?}"),
+            }),
+            Reply::Notification(n) => panic!("unexpected notification {n:?}"),
+        }
+    }
+
+    fn reply(req_id: u64, body: &[u8], done: bool) -> IncomingCommandoMessage {
+        let chunk = CommandoReplyChunk {
+            req_id,
+            chunk: body.to_vec(),
         };
-        (ip, rx)
+        if done {
+            IncomingCommandoMessage::Done(chunk)
+        } else {
+            IncomingCommandoMessage::Chunk(chunk)
+        }
     }
 
     /// This mirrors the resend loop in `reconnect`, but takes a closure for "socket write".
@@ -653,7 +3526,7 @@ mod tests {
             match p.policy {
                 RetryPolicy::Always { max_retries } if p.attempts < max_retries => {
                     p.attempts += 1;
-                    p.buf.clear();
+                    p.reset_reply();
                     to_retry.push(p);
                 }
                 _ => {
@@ -756,36 +3629,6 @@ mod tests {
 
     // --- API surface & wiring -------------------------------------------------
 
-    #[test]
-    fn commando_command_getters_and_type_id() {
-        let c = CommandoCommand::new(
-            42,
-            "listpeers".to_string(),
-            "rune-abc".to_string(),
-            serde_json::json!({"id": "0299..."}),
-            None,
-        );
-
-        assert_eq!(c.req_id(), 42);
-        assert_eq!(c.method(), "listpeers");
-        assert_eq!(c.rune(), "rune-abc");
-        assert_eq!(c.params(), &serde_json::json!({"id": "0299..."}));
-        assert_eq!(c.type_id(), COMMANDO_COMMAND);
-    }
-
-    #[test]
-    fn incoming_message_type_ids_match_constants() {
-        let chunk = CommandoReplyChunk {
-            req_id: 7,
-            chunk: vec![1, 2, 3],
-        };
-        let cont = IncomingCommandoMessage::Chunk(chunk.clone());
-        let done = IncomingCommandoMessage::Done(chunk);
-
-        assert_eq!(cont.type_id(), COMMANDO_REPLY_CONT);
-        assert_eq!(done.type_id(), COMMANDO_REPLY_TERM);
-    }
-
     #[test]
     fn callopts_builders_override_values() {
         let opts = CallOpts::new()
@@ -846,7 +3689,7 @@ mod tests {
 
         // eligible for retry and has stale bytes to clear
         let (mut ip, _rx) = mk_ip(100, RetryPolicy::Always { max_retries: 2 }, 0);
-        ip.buf.extend_from_slice(b"stale-partial-json");
+        ip.push_chunk(b"stale-partial-json");
         pending.insert(100, ip);
 
         classify_for_retry(&mut pending, &mut queue);
@@ -855,7 +3698,357 @@ mod tests {
         assert_eq!(queue.len(), 1);
         assert_eq!(queue[0].cmd.req_id(), 100);
         assert_eq!(queue[0].attempts, 1);
-        assert!(queue[0].buf.is_empty(), "buf must be cleared before retry");
+        assert_eq!(
+            queue[0].bytes, 0,
+            "partial reply must be forgotten before retry"
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_all_fails_pending_and_queued() {
+        let mut pending: HashMap<u64, InProgress> = HashMap::new();
+        let mut queue: Vec<InProgress> = Vec::new();
+
+        let (ip1, rx1) = mk_ip(50, RetryPolicy::Always { max_retries: 3 }, 0);
+        let (ip2, rx2) = mk_ip(51, RetryPolicy::Never, 0);
+        pending.insert(50, ip1);
+        queue.push(ip2);
+
+        fail_all(
+            &mut pending,
+            &mut queue,
+            Error::Io(std::io::ErrorKind::BrokenPipe),
+        );
+
+        assert!(pending.is_empty());
+        assert!(queue.is_empty());
+        for rx in [rx1, rx2] {
+            match rx.await.expect("call must be completed") {
+                Err(Error::Io(kind)) => assert_eq!(kind, std::io::ErrorKind::BrokenPipe),
+                other => panic!("expected BrokenPipe error, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_are_broadcast_without_finishing_calls() {
+        let mut pending: HashMap<u64, InProgress> = HashMap::new();
+        let mut replies = ReplyAssembler::new();
+        let (notify_tx, notify_rx) = broadcast::channel(8);
+        let mut stream = NotificationStream::new(notify_rx);
+
+        let (ip, mut rx) = mk_ip(1, RetryPolicy::Never, 0);
+        pending.insert(1, ip);
+
+        // a notification on the id of a pending call leaves the call waiting
+        let note = br#"{"jsonrpc":"2.0","method":"log","params":{"log":"hi"}}"#;
+        handle_reply(
+            &mut pending,
+            &mut replies,
+            &mut HashSet::new(),
+            &notify_tx,
+            reply(1, note, true),
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(pending[&1].bytes, 0);
+        assert_eq!(stream.next().await.unwrap().method, "log");
+
+        // a fragmented notification on an unknown id is reassembled
+        let (head, tail) = note.split_at(10);
+        handle_reply(
+            &mut pending,
+            &mut replies,
+            &mut HashSet::new(),
+            &notify_tx,
+            reply(99, head, false),
+        );
+        handle_reply(
+            &mut pending,
+            &mut replies,
+            &mut HashSet::new(),
+            &notify_tx,
+            reply(99, tail, true),
+        );
+        assert_eq!(replies.buffered(), 0);
+        assert_eq!(stream.next().await.unwrap().params["log"], "hi");
+
+        // the real reply still completes the call
+        handle_reply(
+            &mut pending,
+            &mut replies,
+            &mut HashSet::new(),
+            &notify_tx,
+            reply(1, br#"{"id":1,"result":{}}"#, true),
+        );
+        assert!(pending.is_empty());
+        assert_eq!(
+            rx.await.unwrap().unwrap(),
+            ReplyBody::Value(serde_json::json!({}))
+        );
+    }
+
+    #[tokio::test]
+    async fn progress_hook_sees_every_chunk_and_can_abort() {
+        let mut pending: HashMap<u64, InProgress> = HashMap::new();
+        let mut replies = ReplyAssembler::new();
+        let mut discarding: HashSet<u64> = HashSet::new();
+        let (notify_tx, _notify_rx) = broadcast::channel(8);
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let opts = {
+            let seen = seen.clone();
+            CallOpts::default().on_progress(move |p: &CallProgress| {
+                seen.lock().unwrap().push((p.req_id, p.bytes, p.chunks));
+                if p.bytes > 8 {
+                    ProgressAction::Abort
+                } else {
+                    ProgressAction::Continue
+                }
+            })
+        };
+        let (mut ip, mut rx) = mk_ip(3, RetryPolicy::Never, 0);
+        ip.progress = opts.progress;
+        pending.insert(3, ip);
+
+        for chunk in [&b"12345"[..], b"6789"] {
+            handle_reply(
+                &mut pending,
+                &mut replies,
+                &mut discarding,
+                &notify_tx,
+                reply(3, chunk, false),
+            );
+        }
+        assert_eq!(*seen.lock().unwrap(), [(3, 5, 1), (3, 9, 2)]);
+        assert!(matches!(rx.try_recv(), Ok(Err(Error::Cancelled))));
+        assert!(pending.is_empty());
+        assert!(discarding.contains(&3));
+    }
+
+    #[tokio::test]
+    async fn probe_tells_rune_problems_apart() {
+        let answers = [
+            (r#"{"id":0,"result":{"alias":"node"}}"#, "valid"),
+            (
+                r#"{"id":0,"error":{"code":19537,"message":"Not authorized: Not derived from master"}}"#,
+                "denied",
+            ),
+            (
+                r#"{"id":0,"error":{"code":19537,"message":"Not authorized: Not permitted: method is not equal to listpeers"}}"#,
+                "restricted",
+            ),
+            (
+                r#"{"id":0,"error":{"code":19537,"message":"Not authorized: Not permitted: time is greater or equal to 1700000000"}}"#,
+                "denied",
+            ),
+            (
+                r#"{"id":0,"error":{"code":-32601,"message":"Unknown command"}}"#,
+                "failed",
+            ),
+            // checkrune's codes say which it is whatever the wording
+            (
+                r#"{"id":0,"error":{"code":1502,"message":"method is not equal to listpeers"}}"#,
+                "restricted",
+            ),
+            (
+                r#"{"id":0,"error":{"code":1501,"message":"Not permitted: unknown rune"}}"#,
+                "denied",
+            ),
+            (
+                r#"{"id":0,"error":{"code":1502,"message":"Not permitted: time is greater or equal to 1700000000"}}"#,
+                "denied",
+            ),
+        ];
+        for (body, expected) in answers {
+            let (mut sock, mut server, mut peer) = loopback_pair().await;
+            let node = tokio::spawn(async move {
+                peer_recv(&mut server, &mut peer).await;
+                let ping = msgs::Ping {
+                    ponglen: 1,
+                    byteslen: 0,
+                };
+                peer_send(&mut server, &mut peer, &ping).await;
+                peer_send(&mut server, &mut peer, &reply(0, body.as_bytes(), true)).await;
+                peer_recv(&mut server, &mut peer).await
+            });
+            let outcome = probe(&mut sock, "rune", Duration::from_secs(5))
+                .await
+                .unwrap();
+            let got = match outcome {
+                ProbeOutcome::RuneValid(info) => {
+                    assert_eq!(info["alias"], "node");
+                    "valid"
+                }
+                ProbeOutcome::RuneDenied(_) => "denied",
+                ProbeOutcome::RuneRestricted(_) => "restricted",
+                ProbeOutcome::Failed(_) => "failed",
+                ProbeOutcome::NoCommando => "none",
+            };
+            assert_eq!(got, expected, "{body}");
+            // the ping was answered along the way
+            assert!(matches!(node.await.unwrap(), Message::Pong(_)));
+        }
+
+        // a node without commando says nothing
+        let (mut sock, _server, _peer) = loopback_pair().await;
+        assert!(matches!(
+            probe(&mut sock, "rune", Duration::from_millis(50)).await,
+            Ok(ProbeOutcome::NoCommando)
+        ));
+    }
+
+    #[tokio::test]
+    async fn cached_replies_skip_the_node() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let config = test_config().cache_ttl("getinfo", Duration::from_secs(60));
+        let client = Arc::new(CommandoClient::spawn_with_config(sock, "rune", config));
+        let getinfo = |opts: CallOpts| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                client
+                    .call_with_opts("getinfo", serde_json::json!({}), opts)
+                    .await
+            })
+        };
+        // answers the next command on the wire, which must have id `req_id`
+        macro_rules! node_answers {
+            ($req_id:expr, $alias:expr) => {
+                match peer_recv(&mut server, &mut peer).await {
+                    Message::Unknown(_) => {}
+                    msg => panic!("expected a commando command, got {msg:?}"),
+                }
+                let body = format!(r#"{{"id":{},"result":{{"alias":"{}"}}}}"#, $req_id, $alias);
+                peer_send(
+                    &mut server,
+                    &mut peer,
+                    &reply($req_id, body.as_bytes(), true),
+                )
+                .await;
+            };
+        }
+
+        let first = getinfo(CallOpts::new());
+        node_answers!(1, "a");
+        assert_eq!(first.await.unwrap().unwrap()["alias"], "a");
+
+        // from the cache, nothing goes out
+        let cached = getinfo(CallOpts::new()).await.unwrap().unwrap();
+        assert_eq!(cached["alias"], "a");
+
+        // bypassing goes to the node without touching the cache
+        let bypass = getinfo(CallOpts::new().cache(CacheMode::Bypass));
+        node_answers!(2, "b");
+        assert_eq!(bypass.await.unwrap().unwrap()["alias"], "b");
+        assert_eq!(
+            getinfo(CallOpts::new()).await.unwrap().unwrap()["alias"],
+            "a"
+        );
+
+        let refresh = getinfo(CallOpts::new().cache(CacheMode::Refresh));
+        node_answers!(3, "c");
+        assert_eq!(refresh.await.unwrap().unwrap()["alias"], "c");
+        assert_eq!(
+            getinfo(CallOpts::new()).await.unwrap().unwrap()["alias"],
+            "c"
+        );
+
+        client.invalidate_cache("getinfo");
+        let fresh = getinfo(CallOpts::new());
+        node_answers!(4, "d");
+        assert_eq!(fresh.await.unwrap().unwrap()["alias"], "d");
+    }
+
+    #[tokio::test]
+    async fn validators_fail_calls_on_bad_params_and_results() {
+        use crate::validation::ValidationError;
+
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let positive = |value: &Value| match value["amount_msat"].as_u64() {
+            Some(amount) if amount > 0 => Ok(()),
+            _ => Err("amount_msat must be positive".to_string()),
+        };
+        let has_alias = |value: &Value| match value.get("alias") {
+            Some(_) => Ok(()),
+            None => Err("no alias".to_string()),
+        };
+        let config = test_config()
+            .validate_params("invoice", positive)
+            .validate_result("getinfo", has_alias);
+        let client = CommandoClient::spawn_with_config(sock, "rune", config);
+
+        // refused before anything goes out
+        let err = client
+            .call("invoice", serde_json::json!({"amount_msat": 0}))
+            .await
+            .unwrap_err();
+        let Error::Validation(err) = err else {
+            panic!("expected a validation error, got {err:?}");
+        };
+        assert_eq!(
+            *err,
+            ValidationError {
+                method: "invoice".to_string(),
+                payload: Payload::Params,
+                problems: vec!["amount_msat must be positive".to_string()],
+            }
+        );
+
+        let node = tokio::spawn(async move {
+            for (req_id, body) in [
+                (1, r#"{"id":1,"result":{"id":"02ab"}}"#),
+                (2, r#"{"id":2,"result":{"alias":"a"}}"#),
+                (3, r#"{"id":3,"result":{"id":"02ab"}}"#),
+                (4, r#"{"id":4,"result":{"id":"02ab"}}"#),
+            ] {
+                peer_recv(&mut server, &mut peer).await;
+                peer_send(
+                    &mut server,
+                    &mut peer,
+                    &reply(req_id, body.as_bytes(), true),
+                )
+                .await;
+            }
+        });
+        assert!(matches!(
+            client.call("getinfo", serde_json::json!({})).await,
+            Err(Error::Validation(err)) if err.payload == Payload::Result
+        ));
+        let info = client.call("getinfo", serde_json::json!({})).await.unwrap();
+        assert_eq!(info["alias"], "a");
+
+        // the other modes check the result too, or refuse the call if they can't
+        let raw = client
+            .call_raw("getinfo", serde_json::json!({}), CallOpts::new())
+            .await;
+        assert!(matches!(raw, Err(Error::Validation(_))));
+        let typed = client
+            .call_typed::<Value>("getinfo", serde_json::json!({}))
+            .await;
+        assert!(matches!(typed, Err(Error::Validation(_))));
+        let streamed = client
+            .call_with_sink("getinfo", serde_json::json!({}), CallOpts::new(), |_| {})
+            .await;
+        assert!(matches!(streamed, Err(Error::Validation(_))));
+        node.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_progress_tracks_reply_bytes_and_chunks() {
+        let (mut ip, rx) = mk_ip(7, RetryPolicy::Never, 0);
+
+        ip.push_chunk(br#"{"result":"#);
+        ip.push_chunk(br#"{"ok":true}}"#);
+        assert_eq!(ip.chunks, 2);
+        assert_eq!(ip.bytes, 22);
+
+        let parsed = parse_commando_response(br#"{"result":{"ok":true}}"#);
+        ip.finish(parsed.map(ReplyBody::Value));
+
+        let res = rx.await.expect("finish must complete the call");
+        assert_eq!(
+            res.unwrap(),
+            ReplyBody::Value(serde_json::json!({"ok": true}))
+        );
     }
 
     #[tokio::test]
@@ -0,0 +1,835 @@
+//! Sans-IO commando framing.
+//!
+//! [`CommandoClient`](crate::CommandoClient) runs commando on a tokio task next to an
+//! [`LNSocket`](crate::LNSocket). [`CommandoProtocol`] is the part of it that does no I/O:
+//! it numbers and encodes requests and reassembles the reply fragments into results, so
+//! commando can run on top of [`crate::transport`] over any runtime, or none:
+//!
+//! 1. [`CommandoProtocol::request`] gives a message to encrypt with
+//!    [`Transport::encrypt_message`](crate::transport::Transport::encrypt_message) and send.
+//!    A command too large for one message, such as a big batch, has to be sent as the
+//!    messages of [`OutgoingCommand::chunks`] instead.
+//! 2. Hand every message [`Transport::decrypt_message`](crate::transport::Transport::decrypt_message)
+//!    yields to [`CommandoProtocol::handle_message`]. Fragments are buffered; the last one of
+//!    a reply comes back as a [`CommandoEvent`].
+//!
+//! ```
+//! use lnsocket::commando_protocol::{COMMANDO_REPLY_TERM, CommandoEvent, CommandoProtocol};
+//! use serde_json::json;
+//!
+//! let mut commando = CommandoProtocol::new("my-rune");
+//! let cmd = commando.request("getinfo", json!({}));
+//! // send(transport.encrypt_message(&cmd)) ...
+//!
+//! // ... and a reply comes back
+//! let mut payload = cmd.req_id().to_be_bytes().to_vec();
+//! payload.extend_from_slice(br#"{"id":0,"result":{"alias":"node"}}"#);
+//! match commando.handle_message(COMMANDO_REPLY_TERM, &payload).unwrap() {
+//!     Some(CommandoEvent::Reply { result, .. }) => assert_eq!(result.unwrap()["alias"], "node"),
+//!     other => panic!("{other:?}"),
+//! }
+//! ```
+//!
+//! Retries, timeouts and reconnects are left to the caller: [`CommandoProtocol::cancel`]
+//! forgets a request, and after a reconnect [`CommandoProtocol::reset_replies`] drops the
+//! fragments that will never be finished.
+//!
+//! [`ReplyAssembler`] is the fragment reassembly on its own, with the rules for interleaved,
+//! empty and repeated fragments that both this and the client follow.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Error;
+use crate::RpcError;
+use crate::ln::msgs::DecodeError;
+use crate::ln::peer_channel_encryptor::LN_MAX_MSG_LEN;
+use crate::ln::wire::Type;
+use crate::notifications::Notification;
+use crate::util::ser::{LengthLimitedRead, Readable, Writeable, Writer};
+
+pub const COMMANDO_COMMAND: u16 = 0x4c4f;
+/// A part of a command that continues in the next message, ending with a
+/// [`COMMANDO_COMMAND`].
+pub const COMMANDO_COMMAND_CONT: u16 = 0x4c4d;
+pub const COMMANDO_REPLY_CONT: u16 = 0x594b;
+pub const COMMANDO_REPLY_TERM: u16 = 0x594d;
+
+/// Odd (optional) custom feature bit advertising gzip support for commando bodies.
+pub const COMMANDO_COMPRESSION_FEATURE_BIT: usize = 259;
+
+/// Commands with JSON bodies smaller than this are sent uncompressed.
+#[cfg(feature = "compression")]
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+#[cfg(feature = "compression")]
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The most a gzip reply body may inflate to. A few bytes of gzip can stand for gigabytes.
+#[cfg(feature = "compression")]
+const MAX_INFLATED_REPLY: usize = 64 * 1024 * 1024;
+
+/// The most command body that fits in one message, after the type and the request id.
+const MAX_COMMAND_CHUNK: usize = LN_MAX_MSG_LEN - 2 - 8;
+
+impl CommandoCommand {
+    pub fn new(
+        id: u64,
+        method: String,
+        rune: String,
+        params: Value,
+        filter: Option<Value>,
+    ) -> Self {
+        Self {
+            id,
+            method,
+            rune,
+            params,
+            filter,
+        }
+    }
+    pub fn req_id(&self) -> u64 {
+        self.id
+    }
+    pub(crate) fn set_req_id(&mut self, id: u64) {
+        self.id = id;
+    }
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+    pub fn rune(&self) -> &str {
+        &self.rune
+    }
+    pub fn params(&self) -> &Value {
+        &self.params
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandoCommand {
+    id: u64,
+    method: String,
+    params: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<Value>,
+    rune: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandoReplyChunk {
+    pub req_id: u64,
+    pub chunk: Vec<u8>,
+}
+
+/// One message of a command, see [`OutgoingCommand::chunks`]. The node joins the chunks of a
+/// request back together, in order, when the `last` one arrives.
+#[derive(Debug, Clone)]
+pub struct CommandoCommandChunk {
+    pub req_id: u64,
+    pub chunk: Vec<u8>,
+    pub last: bool,
+}
+
+impl Writeable for CommandoCommandChunk {
+    fn write<W: Writer>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        self.req_id.write(writer)?;
+        writer.write_all(&self.chunk)
+    }
+}
+
+impl Type for CommandoCommandChunk {
+    fn type_id(&self) -> u16 {
+        if self.last {
+            COMMANDO_COMMAND
+        } else {
+            COMMANDO_COMMAND_CONT
+        }
+    }
+}
+
+/// Encode a command (request id, then body) as messages that each fit in a frame: as many
+/// [`COMMANDO_COMMAND_CONT`] as it takes, then the [`COMMANDO_COMMAND`].
+pub(crate) fn split_command<M: Writeable>(cmd: &M) -> Vec<CommandoCommandChunk> {
+    let encoded = cmd.encode();
+    let (id, body) = encoded.split_at(8);
+    let req_id = u64::from_be_bytes(id.try_into().expect("command starts with its id"));
+    let mut chunks: Vec<_> = body
+        .chunks(MAX_COMMAND_CHUNK)
+        .map(|chunk| CommandoCommandChunk {
+            req_id,
+            chunk: chunk.to_vec(),
+            last: false,
+        })
+        .collect();
+    if chunks.is_empty() {
+        chunks.push(CommandoCommandChunk {
+            req_id,
+            chunk: Vec::new(),
+            last: false,
+        });
+    }
+    chunks.last_mut().unwrap().last = true;
+    chunks
+}
+
+#[derive(Debug, Clone)]
+pub enum IncomingCommandoMessage {
+    Chunk(CommandoReplyChunk),
+    Done(CommandoReplyChunk),
+}
+
+pub fn read_incoming_commando_message<R: LengthLimitedRead>(
+    typ: u16,
+    buf: &mut R,
+) -> Result<Option<IncomingCommandoMessage>, DecodeError> {
+    if typ == COMMANDO_REPLY_CONT {
+        let req_id: u64 = Readable::read(buf)?;
+        let mut chunk = Vec::with_capacity(buf.remaining_bytes() as usize);
+        buf.read_to_end(&mut chunk)?;
+        Ok(Some(IncomingCommandoMessage::Chunk(CommandoReplyChunk {
+            req_id,
+            chunk,
+        })))
+    } else if typ == COMMANDO_REPLY_TERM {
+        let req_id: u64 = Readable::read(buf)?;
+        let mut chunk = Vec::with_capacity(buf.remaining_bytes() as usize);
+        buf.read_to_end(&mut chunk)?;
+        Ok(Some(IncomingCommandoMessage::Done(CommandoReplyChunk {
+            req_id,
+            chunk,
+        })))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Joins reply fragments back into whole reply bodies, per request id.
+///
+/// It decides only what a fragment belongs to, not what the body means:
+/// - Fragments of different request ids may interleave freely; each reply is joined on its
+///   own, in the order its fragments arrive.
+/// - Empty fragments are fragments like any other: an empty [`IncomingCommandoMessage::Chunk`]
+///   adds nothing, an empty [`IncomingCommandoMessage::Done`] finishes what is buffered.
+/// - A `Done` with nothing buffered before it is a whole reply by itself. So a terminal
+///   fragment sent twice comes out as a second, separate reply, never mixed into the next
+///   reply with that id.
+/// - A reply that comes out empty, e.g. a lone empty `Done`, carries nothing to decode and
+///   is ignored.
+/// - Fragments are kept until their `Done`, [`ReplyAssembler::discard`] or
+///   [`ReplyAssembler::clear`]; it is up to the caller to bound what a peer can make it
+///   buffer, e.g. by [`ReplyAssembler::buffered`].
+#[derive(Debug, Default)]
+pub struct ReplyAssembler {
+    partial: HashMap<u64, Vec<u8>>,
+}
+
+impl ReplyAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a fragment. When it is the last one of a reply, returns the request id and the
+    /// whole body.
+    pub fn push(&mut self, msg: IncomingCommandoMessage) -> Option<(u64, Vec<u8>)> {
+        match msg {
+            IncomingCommandoMessage::Chunk(chunk) if chunk.chunk.is_empty() => None,
+            IncomingCommandoMessage::Chunk(chunk) => {
+                self.partial
+                    .entry(chunk.req_id)
+                    .or_default()
+                    .extend_from_slice(&chunk.chunk);
+                None
+            }
+            IncomingCommandoMessage::Done(chunk) => {
+                let body = match self.partial.remove(&chunk.req_id) {
+                    Some(mut buf) => {
+                        buf.extend_from_slice(&chunk.chunk);
+                        buf
+                    }
+                    None => chunk.chunk,
+                };
+                if body.is_empty() {
+                    tracing::debug!("commando: [{}] empty reply, ignoring it", chunk.req_id);
+                    return None;
+                }
+                Some((chunk.req_id, body))
+            }
+        }
+    }
+
+    /// Drop the fragments of `req_id` so far, returning whether there were any.
+    pub fn discard(&mut self, req_id: u64) -> bool {
+        self.partial.remove(&req_id).is_some()
+    }
+
+    /// Drop every unfinished reply, e.g. when the connection they came on is gone.
+    pub fn clear(&mut self) {
+        self.partial.clear();
+    }
+
+    /// Request ids with an unfinished reply.
+    pub fn unfinished(&self) -> impl Iterator<Item = u64> + '_ {
+        self.partial.keys().copied()
+    }
+
+    /// Bytes buffered for unfinished replies.
+    pub fn buffered(&self) -> usize {
+        self.partial.values().map(Vec::len).sum()
+    }
+
+    /// Bytes buffered for the unfinished reply to `req_id`.
+    pub fn buffered_for(&self, req_id: u64) -> usize {
+        self.partial.get(&req_id).map_or(0, Vec::len)
+    }
+}
+
+impl Writeable for CommandoCommand {
+    fn write<W: Writer>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        self.id.write(writer)?;
+        writer.write_all(
+            &serde_json::to_string(self)
+                .expect("commando command json")
+                .into_bytes(),
+        )?;
+        Ok(())
+    }
+}
+
+impl Type for CommandoCommand {
+    fn type_id(&self) -> u16 {
+        COMMANDO_COMMAND
+    }
+}
+
+impl Type for IncomingCommandoMessage {
+    fn type_id(&self) -> u16 {
+        match self {
+            IncomingCommandoMessage::Chunk(_) => COMMANDO_REPLY_CONT,
+            IncomingCommandoMessage::Done(_) => COMMANDO_REPLY_TERM,
+        }
+    }
+}
+
+/// A [`CommandoCommand`] whose JSON body is gzipped when it is large enough to be worth it.
+#[cfg(feature = "compression")]
+pub(crate) struct CompressedCommand<'a>(pub &'a CommandoCommand);
+
+#[cfg(feature = "compression")]
+impl Writeable for CompressedCommand<'_> {
+    fn write<W: Writer>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        self.0.id.write(writer)?;
+        let json = serde_json::to_vec(self.0).expect("commando command json");
+        if json.len() < COMPRESSION_THRESHOLD {
+            writer.write_all(&json)
+        } else {
+            writer.write_all(&gzip(&json))
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Type for CompressedCommand<'_> {
+    fn type_id(&self) -> u16 {
+        COMMANDO_COMMAND
+    }
+}
+
+#[cfg(feature = "compression")]
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    Writer::write_all(&mut encoder, data).expect("in-memory gzip");
+    encoder.finish().expect("in-memory gzip")
+}
+
+/// Inflate a gzip reply body; anything else is passed through untouched. A body inflating
+/// past [`MAX_INFLATED_REPLY`] fails with `Io(InvalidData)`.
+#[cfg(feature = "compression")]
+pub(crate) fn maybe_decompress(buf: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    inflate(buf, MAX_INFLATED_REPLY)
+}
+
+#[cfg(feature = "compression")]
+fn inflate(buf: &[u8], limit: usize) -> Result<Cow<'_, [u8]>, Error> {
+    use std::io::Read;
+    if !buf.starts_with(&GZIP_MAGIC) {
+        return Ok(Cow::Borrowed(buf));
+    }
+    let mut out = Vec::new();
+    flate2::read::GzDecoder::new(buf)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)?;
+    if out.len() > limit {
+        tracing::warn!("commando: gzip reply inflates past {limit} bytes, dropping it");
+        return Err(Error::Io(std::io::ErrorKind::InvalidData));
+    }
+    Ok(Cow::Owned(out))
+}
+
+#[cfg(not(feature = "compression"))]
+pub(crate) fn maybe_decompress(buf: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    Ok(Cow::Borrowed(buf))
+}
+
+/// A command from [`CommandoProtocol::request`], ready for
+/// [`Transport::encrypt_message`](crate::transport::Transport::encrypt_message).
+#[derive(Debug, Clone)]
+pub struct OutgoingCommand {
+    cmd: CommandoCommand,
+    #[cfg(feature = "compression")]
+    compress: bool,
+}
+
+impl OutgoingCommand {
+    pub fn req_id(&self) -> u64 {
+        self.cmd.req_id()
+    }
+
+    pub fn command(&self) -> &CommandoCommand {
+        &self.cmd
+    }
+
+    /// The command as messages of at most 65535 bytes, to encrypt and send in order. A single
+    /// [`COMMANDO_COMMAND`] unless the command is larger than that.
+    pub fn chunks(&self) -> Vec<CommandoCommandChunk> {
+        split_command(self)
+    }
+}
+
+impl Writeable for OutgoingCommand {
+    fn write<W: Writer>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        #[cfg(feature = "compression")]
+        if self.compress {
+            return CompressedCommand(&self.cmd).write(writer);
+        }
+        self.cmd.write(writer)
+    }
+}
+
+impl Type for OutgoingCommand {
+    fn type_id(&self) -> u16 {
+        COMMANDO_COMMAND
+    }
+}
+
+/// A finished reply body.
+#[derive(Debug)]
+pub enum CommandoEvent {
+    /// The answer to request `req_id`: its `result`, or the error the node answered with.
+    Reply {
+        req_id: u64,
+        result: Result<Value, Error>,
+    },
+    /// A notification the node pushed under `req_id`. A request with that id, if any, is still
+    /// waiting for its reply.
+    Notification {
+        req_id: u64,
+        notification: Notification,
+    },
+}
+
+/// Commando requests and replies without the I/O, see the [module docs](self).
+#[derive(Debug)]
+pub struct CommandoProtocol {
+    rune: String,
+    next_id: u64,
+    #[cfg(feature = "compression")]
+    compress: bool,
+    in_flight: HashSet<u64>,
+    replies: ReplyAssembler,
+}
+
+impl CommandoProtocol {
+    /// Requests are authorized with `rune` unless [`CommandoProtocol::request_with`] says
+    /// otherwise.
+    pub fn new(rune: impl Into<String>) -> Self {
+        Self {
+            rune: rune.into(),
+            next_id: 0,
+            #[cfg(feature = "compression")]
+            compress: false,
+            in_flight: HashSet::new(),
+            replies: ReplyAssembler::new(),
+        }
+    }
+
+    /// Gzip large commands. Only turn this on once the peer's `init` advertised
+    /// [`COMMANDO_COMPRESSION_FEATURE_BIT`].
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Start a request for `method` with the default rune.
+    pub fn request(&mut self, method: impl Into<String>, params: Value) -> OutgoingCommand {
+        let rune = self.rune.clone();
+        self.request_with(method, params, rune, None)
+    }
+
+    /// Start a request with its own rune and an optional `filter`.
+    pub fn request_with(
+        &mut self,
+        method: impl Into<String>,
+        params: Value,
+        rune: impl Into<String>,
+        filter: Option<Value>,
+    ) -> OutgoingCommand {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.in_flight.insert(id);
+        OutgoingCommand {
+            cmd: CommandoCommand::new(id, method.into(), rune.into(), params, filter),
+            #[cfg(feature = "compression")]
+            compress: self.compress,
+        }
+    }
+
+    /// Feed a decrypted message. Returns an event when it finishes a reply; fragments, replies
+    /// to requests we don't know (any more) and other message types give `None`.
+    pub fn handle_message(
+        &mut self,
+        type_id: u16,
+        payload: &[u8],
+    ) -> Result<Option<CommandoEvent>, Error> {
+        let msg = match read_incoming_commando_message(type_id, &mut Cursor::new(payload))? {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+        let Some((req_id, body)) = self.replies.push(msg) else {
+            return Ok(None);
+        };
+        let event = decode_reply(req_id, &body);
+        if let CommandoEvent::Reply { req_id, .. } = &event
+            && !self.in_flight.remove(req_id)
+        {
+            tracing::debug!("commando: [{req_id}] reply for unknown request");
+            return Ok(None);
+        }
+        Ok(Some(event))
+    }
+
+    /// Forget request `req_id`, e.g. after it timed out. A late reply is then ignored.
+    pub fn cancel(&mut self, req_id: u64) {
+        self.in_flight.remove(&req_id);
+        self.replies.discard(req_id);
+    }
+
+    /// Drop the partial replies, keeping the requests in flight: the stream they came on
+    /// broke, and the requests will be sent again on the next one.
+    pub fn reset_replies(&mut self) {
+        self.replies.clear();
+    }
+
+    /// How many requests are waiting for their reply.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Reply bytes buffered for replies that haven't finished yet.
+    pub fn buffered(&self) -> usize {
+        self.replies.buffered()
+    }
+}
+
+/// Decode a complete reply body to request `req_id`.
+pub(crate) fn decode_reply(req_id: u64, buf: &[u8]) -> CommandoEvent {
+    let value = match maybe_decompress(buf)
+        .and_then(|buf| serde_json::from_slice::<Value>(&buf).map_err(|_| Error::Json))
+    {
+        Ok(value) => value,
+        Err(err) => {
+            return CommandoEvent::Reply {
+                req_id,
+                result: Err(err),
+            };
+        }
+    };
+    match Notification::from_json(value) {
+        Ok(notification) => CommandoEvent::Notification {
+            req_id,
+            notification,
+        },
+        Err(value) => CommandoEvent::Reply {
+            req_id,
+            result: response_result(&value),
+        },
+    }
+}
+
+fn response_result(value: &Value) -> Result<Value, Error> {
+    let obj = value.as_object().ok_or(Error::Json)?;
+
+    if let Some(error) = obj.get("error") {
+        return Err(Error::Rpc(rpc_error(error)));
+    }
+
+    match obj.get("result") {
+        None => Err(Error::Json),
+        Some(res) => Ok(res.clone()),
+    }
+}
+
+/// The `error` member of a response, keeping its JSON as the message if it isn't the usual
+/// `{code, message}` object.
+pub(crate) fn rpc_error(error: &Value) -> RpcError {
+    serde_json::from_value(error.clone()).unwrap_or_else(|_| RpcError {
+        code: -1,
+        message: serde_json::to_string(error).unwrap(),
+        data: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    fn commando_msg(typ: u16, req_id: u64, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut payload = req_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(body);
+        (typ, payload)
+    }
+
+    #[test]
+    fn commando_command_getters_and_type_id() {
+        let c = CommandoCommand::new(
+            42,
+            "listpeers".to_string(),
+            "rune-abc".to_string(),
+            json!({"id": "0299..."}),
+            None,
+        );
+
+        assert_eq!(c.req_id(), 42);
+        assert_eq!(c.method(), "listpeers");
+        assert_eq!(c.rune(), "rune-abc");
+        assert_eq!(c.params(), &json!({"id": "0299..."}));
+        assert_eq!(c.type_id(), COMMANDO_COMMAND);
+    }
+
+    #[test]
+    fn incoming_message_type_ids_match_constants() {
+        let chunk = CommandoReplyChunk {
+            req_id: 7,
+            chunk: vec![1, 2, 3],
+        };
+        let cont = IncomingCommandoMessage::Chunk(chunk.clone());
+        let done = IncomingCommandoMessage::Done(chunk);
+
+        assert_eq!(cont.type_id(), COMMANDO_REPLY_CONT);
+        assert_eq!(done.type_id(), COMMANDO_REPLY_TERM);
+    }
+
+    #[test]
+    fn protocol_reassembles_replies() {
+        let mut commando = CommandoProtocol::new("rune");
+        let first = commando.request("getinfo", json!({}));
+        let second = commando.request_with("listpeers", json!([]), "other", None);
+        assert_eq!((first.req_id(), second.req_id()), (0, 1));
+        assert_eq!(second.command().rune(), "other");
+        let encoded = first.encode();
+        assert_eq!(&encoded[..8], &0u64.to_be_bytes());
+        assert_eq!(commando.in_flight(), 2);
+
+        // fragments of both replies interleave
+        let cont = |id, body: &[u8]| commando_msg(COMMANDO_REPLY_CONT, id, body);
+        let term = |id, body: &[u8]| commando_msg(COMMANDO_REPLY_TERM, id, body);
+        for (typ, payload) in [
+            cont(1, br#"{"jsonrpc":"2.0","#),
+            cont(0, br#"{"id":0,"res"#),
+        ] {
+            assert!(commando.handle_message(typ, &payload).unwrap().is_none());
+        }
+        assert_eq!(commando.buffered(), 29);
+
+        let (typ, payload) = term(0, br#"ult":{"alias":"node"}}"#);
+        match commando.handle_message(typ, &payload).unwrap() {
+            Some(CommandoEvent::Reply { req_id: 0, result }) => {
+                assert_eq!(result.unwrap(), json!({"alias": "node"}))
+            }
+            other => panic!("{other:?}"),
+        }
+
+        // a notification doesn't finish the request it arrived under
+        let (typ, payload) = term(1, br#""method":"log","params":{}}"#);
+        assert!(matches!(
+            commando.handle_message(typ, &payload).unwrap(),
+            Some(CommandoEvent::Notification { req_id: 1, notification }) if notification.method == "log"
+        ));
+        assert_eq!(commando.in_flight(), 1);
+
+        let (typ, payload) = term(1, br#"{"id":1,"error":{"code":-32601,"message":"no"}}"#);
+        assert!(matches!(
+            commando.handle_message(typ, &payload).unwrap(),
+            Some(CommandoEvent::Reply {
+                req_id: 1,
+                result: Err(Error::Rpc(RpcError { code: -32601, .. }))
+            })
+        ));
+
+        // cancelled requests and other messages are ignored
+        let third = commando.request("stop", Value::Null);
+        commando.cancel(third.req_id());
+        let (typ, payload) = term(2, br#"{"id":2,"result":{}}"#);
+        assert!(commando.handle_message(typ, &payload).unwrap().is_none());
+        assert!(commando.handle_message(18, &[0, 0]).unwrap().is_none());
+        assert_eq!((commando.in_flight(), commando.buffered()), (0, 0));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn gzip_replies_are_inflated() {
+        let body = br#"{"result":{"alias":"node"}}"#;
+        match decode_reply(3, &gzip(body)) {
+            CommandoEvent::Reply { req_id: 3, result } => {
+                assert_eq!(result.unwrap(), json!({"alias": "node"}))
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn gzip_bombs_stop_at_the_limit() {
+        let bomb = gzip(&[b' '; 100_000]);
+        assert!(bomb.len() < 1000);
+        assert_eq!(inflate(&bomb, 100_000).unwrap().len(), 100_000);
+        assert!(matches!(
+            inflate(&bomb, 99_999),
+            Err(Error::Io(std::io::ErrorKind::InvalidData))
+        ));
+    }
+
+    #[test]
+    fn large_commands_are_split_into_continuations() {
+        let mut commando = CommandoProtocol::new("rune");
+        let small = commando.request("getinfo", json!({}));
+        let chunks = small.chunks();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].type_id(), COMMANDO_COMMAND);
+        assert_eq!(chunks[0].encode(), small.encode());
+
+        let labels: Vec<_> = (0..4000).map(|i| format!("invoice-label-{i:05}")).collect();
+        let big = commando.request("batch", json!({ "labels": labels }));
+        let chunks = big.chunks();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].type_id(), COMMANDO_COMMAND_CONT);
+        assert_eq!(chunks[1].type_id(), COMMANDO_COMMAND);
+        assert_eq!(chunks[0].encode().len(), LN_MAX_MSG_LEN - 2);
+        assert!(chunks.iter().all(|c| c.req_id == big.req_id()));
+
+        let joined: Vec<u8> = chunks.iter().flat_map(|c| c.chunk.clone()).collect();
+        assert_eq!(joined, big.encode()[8..]);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_command_only_compresses_large_bodies() {
+        let small = CommandoCommand::new(1, "getinfo".into(), "rune".into(), json!({}), None);
+        let encoded = CompressedCommand(&small).encode();
+        assert_eq!(encoded, small.encode());
+
+        let big = CommandoCommand::new(
+            2,
+            "invoice".to_string(),
+            "rune".to_string(),
+            json!({ "description": "x".repeat(4096) }),
+            None,
+        );
+        let encoded = CompressedCommand(&big).encode();
+        assert_eq!(&encoded[..8], &2u64.to_be_bytes());
+        assert!(encoded[8..].starts_with(&GZIP_MAGIC));
+        assert!(encoded.len() < big.encode().len());
+
+        let mut commando = CommandoProtocol::new("rune");
+        commando.set_compression(true);
+        let cmd = commando.request("invoice", json!({ "description": "x".repeat(4096) }));
+        assert!(cmd.encode()[8..].starts_with(&GZIP_MAGIC));
+    }
+
+    fn fragment(req_id: u64, body: &[u8], done: bool) -> IncomingCommandoMessage {
+        let chunk = CommandoReplyChunk {
+            req_id,
+            chunk: body.to_vec(),
+        };
+        if done {
+            IncomingCommandoMessage::Done(chunk)
+        } else {
+            IncomingCommandoMessage::Chunk(chunk)
+        }
+    }
+
+    proptest! {
+        /// Replies cut into fragments (some empty), interleaved in any order, some with their
+        /// terminal fragment sent twice, come out whole and each once.
+        #[test]
+        fn interleaved_fragments_reassemble(
+            replies in prop::collection::vec(
+                prop::collection::vec(prop::collection::vec(any::<u8>(), 0..8), 1..5),
+                1..5,
+            ),
+            picks in prop::collection::vec(any::<usize>(), 0..64),
+            duplicate in any::<[bool; 5]>(),
+        ) {
+            let mut assembler = ReplyAssembler::new();
+            let mut next = vec![0; replies.len()];
+            let (mut got, mut want) = (Vec::new(), Vec::new());
+            let mut picks = picks.into_iter();
+            loop {
+                let open: Vec<usize> = (0..replies.len())
+                    .filter(|&i| next[i] < replies[i].len())
+                    .collect();
+                if open.is_empty() {
+                    break;
+                }
+                let i = open[picks.next().unwrap_or(0) % open.len()];
+                let req_id = i as u64;
+                let fragments = &replies[i];
+                let done = next[i] + 1 == fragments.len();
+                got.extend(assembler.push(fragment(req_id, &fragments[next[i]], done)));
+                next[i] += 1;
+                if !done {
+                    continue;
+                }
+                let body = fragments.concat();
+                if !body.is_empty() {
+                    want.push((req_id, body));
+                }
+                let last = fragments.last().unwrap();
+                if duplicate[i] {
+                    got.extend(assembler.push(fragment(req_id, last, true)));
+                    if !last.is_empty() {
+                        want.push((req_id, last.clone()));
+                    }
+                }
+            }
+            prop_assert_eq!(got, want);
+            prop_assert_eq!(assembler.buffered(), 0);
+            prop_assert_eq!(assembler.unfinished().count(), 0);
+        }
+    }
+
+    #[test]
+    fn assembler_drops_what_it_is_told_to() {
+        let mut assembler = ReplyAssembler::new();
+        assert!(assembler.push(fragment(1, b"ab", false)).is_none());
+        assert!(assembler.push(fragment(2, b"c", false)).is_none());
+        assert_eq!(assembler.buffered(), 3);
+        assert_eq!(assembler.buffered_for(1), 2);
+        assert_eq!(assembler.buffered_for(3), 0);
+        assert!(assembler.push(fragment(3, b"", false)).is_none());
+        assert_eq!(assembler.unfinished().count(), 2);
+        assert!(assembler.discard(1));
+        assert!(!assembler.discard(1));
+        assert_eq!(
+            assembler.push(fragment(1, b"d", true)),
+            Some((1, b"d".to_vec()))
+        );
+        assembler.clear();
+        assert!(assembler.push(fragment(2, b"", true)).is_none());
+    }
+}
@@ -0,0 +1,383 @@
+//! All the knobs of a connection in one place.
+//!
+//! Options for outbound connections live on a [`Dialer`], options of a live socket are set
+//! with `LNSocket::set_*` once it is connected, and commando has its own [`CommandoConfig`].
+//! [`LNSocketConfig`] gathers all of them behind one builder with the defaults of the pieces,
+//! and [`LNSocket::connect_with_config`] connects with it, so an application can keep one
+//! value around instead of remembering which setter goes where:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use bitcoin::Network;
+//! use lnsocket::config::LNSocketConfig;
+//! use lnsocket::dial::NoPrivateAddrs;
+//! use lnsocket::{CommandoClient, LNSocket};
+//! # async fn ex(key: bitcoin::secp256k1::SecretKey, pk: bitcoin::secp256k1::PublicKey) -> Result<(), lnsocket::Error> {
+//! let config = LNSocketConfig::new()
+//!     .with_timeout(Some(Duration::from_secs(20)))
+//!     .with_network(Network::Bitcoin)
+//!     .with_policy(NoPrivateAddrs)
+//!     .with_write_linger(Some(Duration::from_millis(5)));
+//!
+//! let sock = LNSocket::connect_with_config(key, pk, "node.example.com:9735", &config).await?;
+//! let commando = CommandoClient::spawn_with_config(sock, "rune", config.commando().clone());
+//! # Ok(()) }
+//! ```
+//!
+//! Sockets remember the config they were made with: [`LNSocket::reconnect_fresh`], and with
+//! it every [`CommandoClient`](crate::CommandoClient) reconnect, applies it again.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bitcoin::Network;
+
+#[cfg(feature = "cancel")]
+use crate::cancel::CancellationToken;
+use crate::capabilities::CapabilityCache;
+use crate::capture::Direction;
+use crate::clock::{Clock, SharedClock};
+use crate::commando::CommandoConfig;
+use crate::congestion::CongestionThresholds;
+use crate::dial::{DialPolicy, Dialer};
+use crate::ln::peer_channel_encryptor::LN_MAX_MSG_LEN;
+use crate::lnsocket::{DEFAULT_PRE_INIT_LIMIT, InitOrder};
+use crate::privacy::PrivacyOptions;
+use crate::sender::DEFAULT_WRITE_STALL_THRESHOLD;
+use crate::stats::{Quota, QuotaEvent, QuotaHook};
+use crate::{Error, LNSocket};
+
+/// Everything [`LNSocket::connect_with_config`] needs to know, see the [module docs](self).
+#[derive(Clone)]
+pub struct LNSocketConfig {
+    dialer: Dialer,
+    timeout: Option<Duration>,
+    init: bool,
+    pre_init_limit: usize,
+    max_message_len: usize,
+    close_on_peer_error: bool,
+    answer_gossip_queries: bool,
+    capabilities: Option<CapabilityCache>,
+    strict_features: bool,
+    stats_log_interval: Option<Duration>,
+    write_linger: Option<Duration>,
+    encrypt_offload: Option<usize>,
+    write_stall_threshold: Option<Duration>,
+    congestion_thresholds: CongestionThresholds,
+    inbound_quota: Option<Quota>,
+    outbound_quota: Option<Quota>,
+    quota_hook: Option<QuotaHook>,
+    clock: Option<SharedClock>,
+    commando: CommandoConfig,
+}
+
+impl Default for LNSocketConfig {
+    fn default() -> Self {
+        Self {
+            dialer: Dialer::new(),
+            timeout: None,
+            init: true,
+            pre_init_limit: DEFAULT_PRE_INIT_LIMIT,
+            max_message_len: LN_MAX_MSG_LEN,
+            close_on_peer_error: true,
+            answer_gossip_queries: false,
+            capabilities: None,
+            strict_features: false,
+            stats_log_interval: None,
+            write_linger: None,
+            encrypt_offload: None,
+            write_stall_threshold: Some(DEFAULT_WRITE_STALL_THRESHOLD),
+            congestion_thresholds: CongestionThresholds::default(),
+            inbound_quota: None,
+            outbound_quota: None,
+            quota_hook: None,
+            clock: None,
+            commando: CommandoConfig::default(),
+        }
+    }
+}
+
+impl std::fmt::Debug for LNSocketConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LNSocketConfig")
+            .field("networks", &self.dialer.networks())
+            .field("init_order", &self.dialer.init_order())
+            .field("timeout", &self.timeout)
+            .field("init", &self.init)
+            .field("pre_init_limit", &self.pre_init_limit)
+            .field("max_message_len", &self.max_message_len)
+            .field("close_on_peer_error", &self.close_on_peer_error)
+            .field("answer_gossip_queries", &self.answer_gossip_queries)
+            .field("capabilities", &self.capabilities)
+            .field("strict_features", &self.strict_features)
+            .field("write_linger", &self.write_linger)
+            .field("encrypt_offload", &self.encrypt_offload)
+            .field("inbound_quota", &self.inbound_quota)
+            .field("outbound_quota", &self.outbound_quota)
+            .field("commando", &self.commando)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LNSocketConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect through `dialer`, replacing the policy, networks, init order, privacy options,
+    /// Tor proxy and cancellation token set so far.
+    pub fn with_dialer(mut self, dialer: Dialer) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    pub fn dialer(&self) -> &Dialer {
+        &self.dialer
+    }
+
+    /// See [`Dialer::with_policy`].
+    pub fn with_policy(mut self, policy: impl DialPolicy + 'static) -> Self {
+        self.dialer = self.dialer.with_policy(policy);
+        self
+    }
+
+    /// See [`Dialer::with_network`].
+    pub fn with_network(self, network: Network) -> Self {
+        self.with_networks([network])
+    }
+
+    /// See [`Dialer::with_networks`].
+    pub fn with_networks(mut self, networks: impl IntoIterator<Item = Network>) -> Self {
+        self.dialer = self.dialer.with_networks(networks);
+        self
+    }
+
+    /// See [`Dialer::with_init_order`].
+    pub fn with_init_order(mut self, order: InitOrder) -> Self {
+        self.dialer = self.dialer.with_init_order(order);
+        self
+    }
+
+    /// See [`Dialer::with_privacy`].
+    pub fn with_privacy(mut self, privacy: PrivacyOptions) -> Self {
+        self.dialer = self.dialer.with_privacy(privacy);
+        self
+    }
+
+    /// See [`Dialer::with_tor_proxy`].
+    pub fn with_tor_proxy(mut self, proxy: SocketAddr) -> Self {
+        self.dialer = self.dialer.with_tor_proxy(proxy);
+        self
+    }
+
+    /// See [`Dialer::with_dns_rebinding_protection`].
+    pub fn with_dns_rebinding_protection(mut self, enabled: bool) -> Self {
+        self.dialer = self.dialer.with_dns_rebinding_protection(enabled);
+        self
+    }
+
+    /// See [`Dialer::with_cancellation`].
+    #[cfg(feature = "cancel")]
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.dialer = self.dialer.with_cancellation(token);
+        self
+    }
+
+    /// Give up a connect that takes longer than `timeout` altogether, resolving, handshake and
+    /// `init` included, with `Error::Io(TimedOut)`. `None` (the default) waits as long as the
+    /// OS does.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether to exchange `init` after the handshake (the default), as
+    /// [`LNSocket::connect_and_init`] does. Reconnects always do.
+    pub fn with_init(mut self, init: bool) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// See [`LNSocket::set_pre_init_limit`].
+    pub fn with_pre_init_limit(mut self, limit: usize) -> Self {
+        self.pre_init_limit = limit;
+        self
+    }
+
+    /// See [`LNSocket::set_max_message_len`].
+    pub fn with_max_message_len(mut self, len: usize) -> Self {
+        self.max_message_len = len;
+        self
+    }
+
+    /// See [`LNSocket::set_close_on_peer_error`].
+    pub fn with_close_on_peer_error(mut self, close: bool) -> Self {
+        self.close_on_peer_error = close;
+        self
+    }
+
+    /// See [`LNSocket::set_answer_gossip_queries`].
+    pub fn with_answer_gossip_queries(mut self, answer: bool) -> Self {
+        self.answer_gossip_queries = answer;
+        self
+    }
+
+    /// See [`LNSocket::set_capability_cache`].
+    pub fn with_capability_cache(mut self, cache: CapabilityCache) -> Self {
+        self.capabilities = Some(cache);
+        self
+    }
+
+    /// See [`LNSocket::set_strict_features`].
+    pub fn with_strict_features(mut self, strict: bool) -> Self {
+        self.strict_features = strict;
+        self
+    }
+
+    /// See [`LNSocket::set_stats_log_interval`].
+    pub fn with_stats_log_interval(mut self, interval: Option<Duration>) -> Self {
+        self.stats_log_interval = interval;
+        self
+    }
+
+    /// See [`LNSocket::set_clock`]. The [`CommandoConfig`] has a clock of its own.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Some(SharedClock::new(clock));
+        self
+    }
+
+    /// See [`LNSocket::set_write_linger`].
+    pub fn with_write_linger(mut self, linger: Option<Duration>) -> Self {
+        self.write_linger = linger;
+        self
+    }
+
+    /// See [`LNSocket::set_encrypt_offload`].
+    pub fn with_encrypt_offload(mut self, min_bytes: Option<usize>) -> Self {
+        self.encrypt_offload = min_bytes;
+        self
+    }
+
+    /// See [`LNSocket::set_write_stall_threshold`].
+    pub fn with_write_stall_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.write_stall_threshold = threshold;
+        self
+    }
+
+    /// See [`LNSocket::set_congestion_thresholds`].
+    pub fn with_congestion_thresholds(mut self, thresholds: CongestionThresholds) -> Self {
+        self.congestion_thresholds = thresholds;
+        self
+    }
+
+    /// See [`LNSocket::set_quota`]. Every connection made with the config gets a quota of
+    /// its own.
+    pub fn with_quota(mut self, direction: Direction, quota: Option<Quota>) -> Self {
+        match direction {
+            Direction::Inbound => self.inbound_quota = quota,
+            Direction::Outbound => self.outbound_quota = quota,
+        }
+        self
+    }
+
+    /// See [`LNSocket::on_quota`].
+    pub fn with_quota_hook(mut self, hook: impl Fn(QuotaEvent) + Send + Sync + 'static) -> Self {
+        self.quota_hook = Some(std::sync::Arc::new(hook));
+        self
+    }
+
+    /// The config for a [`CommandoClient`](crate::CommandoClient) on sockets made with this
+    /// one. Not applied by [`LNSocket::connect_with_config`]: pass [`LNSocketConfig::commando`] to
+    /// `CommandoClient::spawn_with_config`.
+    pub fn with_commando(mut self, config: CommandoConfig) -> Self {
+        self.commando = config;
+        self
+    }
+
+    pub fn commando(&self) -> &CommandoConfig {
+        &self.commando
+    }
+
+    pub(crate) fn connect_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub(crate) fn exchanges_init(&self) -> bool {
+        self.init
+    }
+
+    /// Set the options of a socket fresh from the handshake, before its `init` exchange.
+    pub(crate) async fn apply(&self, sock: &mut LNSocket) -> Result<(), Error> {
+        if let Some(clock) = &self.clock {
+            sock.set_clock(clock.clone()).await?;
+        }
+        sock.set_pre_init_limit(self.pre_init_limit);
+        sock.set_max_message_len(self.max_message_len);
+        sock.set_close_on_peer_error(self.close_on_peer_error);
+        sock.set_answer_gossip_queries(self.answer_gossip_queries);
+        sock.set_capability_cache(self.capabilities.clone());
+        sock.set_strict_features(self.strict_features);
+        sock.set_stats_log_interval(self.stats_log_interval);
+        sock.set_congestion_thresholds(self.congestion_thresholds);
+        sock.set_quota(Direction::Inbound, self.inbound_quota);
+        sock.set_quota(Direction::Outbound, self.outbound_quota);
+        if let Some(hook) = &self.quota_hook {
+            let hook = hook.clone();
+            sock.on_quota(move |event| hook(event));
+        }
+        sock.set_write_linger(self.write_linger).await?;
+        sock.set_encrypt_offload(self.encrypt_offload).await?;
+        sock.set_write_stall_threshold(self.write_stall_threshold)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dial::NoPrivateAddrs;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+    fn keys() -> (SecretKey, PublicKey) {
+        let their_key = SecretKey::from_slice(&[2; 32]).unwrap();
+        let their_pubkey = PublicKey::from_secret_key(&Secp256k1::signing_only(), &their_key);
+        (SecretKey::from_slice(&[1; 32]).unwrap(), their_pubkey)
+    }
+
+    #[tokio::test]
+    async fn dialer_options_are_forwarded() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (our_key, their_pubkey) = keys();
+
+        let config = LNSocketConfig::new()
+            .with_network(Network::Regtest)
+            .with_policy(NoPrivateAddrs);
+        assert_eq!(config.dialer().networks(), [Network::Regtest]);
+        let err = LNSocket::connect_with_config(our_key, their_pubkey, &addr, &config)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::DialDenied(_)));
+    }
+
+    #[tokio::test]
+    async fn timeout_covers_the_whole_connect() {
+        // accepts the connection but never answers act one
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let _peer = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+            drop(stream);
+        });
+        let (our_key, their_pubkey) = keys();
+
+        let config = LNSocketConfig::new().with_timeout(Some(Duration::from_millis(50)));
+        let err = LNSocket::connect_with_config(our_key, their_pubkey, &addr, &config)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Io(std::io::ErrorKind::TimedOut)));
+    }
+}
@@ -0,0 +1,245 @@
+//! The [BOLT #8] test vectors, for checking another implementation against this one, or this
+//! one against the spec, in CI.
+//!
+//! Only the initiator side of the handshake is implemented here, so these are the initiator
+//! transcript and the message encryption vectors. [`verify_initiator`] runs all of them;
+//! [`act_one`], [`act_three`] and [`spec_transport`] give the exact bytes this crate produces
+//! for fixed keys, for a responder under test to be fed and compared against:
+//!
+//! ```
+//! use lnsocket::conformance::{self, ACT_ONE, ACT_TWO, ACT_THREE};
+//!
+//! conformance::verify_initiator().unwrap();
+//!
+//! let act_one = conformance::spec_act_one();
+//! assert_eq!(act_one[..], conformance::hex(ACT_ONE)[..]);
+//! // a responder under test answers `act_one`; with the spec's keys it must answer ACT_TWO
+//! let act_two = conformance::hex(ACT_TWO).try_into().unwrap();
+//! let act_three = conformance::spec_act_three(&act_two).unwrap();
+//! assert_eq!(act_three[..], conformance::hex(ACT_THREE)[..]);
+//! ```
+//!
+//! Needs the `test-utils` feature: these handshakes reuse a fixed ephemeral key.
+//!
+//! [BOLT #8]: https://github.com/lightning/bolts/blob/master/08-transport.md#appendix-a-transport-test-vectors
+
+use std::fmt;
+use std::io;
+
+use bitcoin::hex::FromHex;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+
+use crate::Error;
+use crate::ln::wire::Type;
+use crate::transport::{ACT_TWO_SIZE, Handshake, Transport};
+use crate::util::ser::{Writeable, Writer};
+
+/// The initiator's static private key, `ls.priv`.
+pub const INITIATOR_STATIC_KEY: &str =
+    "1111111111111111111111111111111111111111111111111111111111111111";
+/// The initiator's ephemeral private key, `e.priv`.
+pub const INITIATOR_EPHEMERAL_KEY: &str =
+    "1212121212121212121212121212121212121212121212121212121212121212";
+/// The responder's static public key, `rs.pub`.
+pub const RESPONDER_STATIC_PUBKEY: &str =
+    "028d7500dd4c12685d1f568b4c2b5048e8534b873319f3a8daa612b469132ec7f7";
+/// The responder's static private key, `ls.priv` in the responder tests.
+pub const RESPONDER_STATIC_KEY: &str =
+    "2121212121212121212121212121212121212121212121212121212121212121";
+/// The responder's ephemeral private key, `e.priv` in the responder tests.
+pub const RESPONDER_EPHEMERAL_KEY: &str =
+    "2222222222222222222222222222222222222222222222222222222222222222";
+
+pub const ACT_ONE: &str = "00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a";
+pub const ACT_TWO: &str = "0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae";
+pub const ACT_THREE: &str = "00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc28fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba";
+
+/// Act twos an initiator must reject after sending [`ACT_ONE`]: a bad version, a bad key
+/// serialization and a bad MAC.
+pub const BAD_ACT_TWOS: [&str; 3] = [
+    "0102466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae",
+    "0004466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae",
+    "0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730af",
+];
+
+/// The message the spec encrypts over and over after the handshake.
+pub const MESSAGE: &[u8] = b"hello";
+
+/// How the initiator encrypts [`MESSAGE`] the n-th time, for the n the spec lists. The keys
+/// rotate after 1000 messages.
+pub const MESSAGE_VECTORS: [(usize, &str); 6] = [
+    (
+        0,
+        "cf2b30ddf0cf3f80e7c35a6e6730b59fe802473180f396d88a8fb0db8cbcf25d2f214cf9ea1d95",
+    ),
+    (
+        1,
+        "72887022101f0b6753e0c7de21657d35a4cb2a1f5cde2650528bbc8f837d0f0d7ad833b1a256a1",
+    ),
+    (
+        500,
+        "178cb9d7387190fa34db9c2d50027d21793c9bc2d40b1e14dcf30ebeeeb220f48364f7a4c68bf8",
+    ),
+    (
+        501,
+        "1b186c57d44eb6de4c057c49940d79bb838a145cb528d6e8fd26dbe50a60ca2c104b56b60e45bd",
+    ),
+    (
+        1000,
+        "4a2f3cc3b5e78ddb83dcb426d9863d9d9a723b0337c89dd0b005d89f8d3c05c52b76b29b740f09",
+    ),
+    (
+        1001,
+        "2ecd8c8a5629d0d02ab457a0fdd0f7b90a192cd46be5ecb6ca570bfc5e268338b1a16cf4ef2d36",
+    ),
+];
+
+/// Decode one of the hex strings above. Panics on anything that isn't hex.
+pub fn hex(s: &str) -> Vec<u8> {
+    <Vec<u8>>::from_hex(s).expect("test vectors are hex")
+}
+
+fn secret_key(s: &str) -> SecretKey {
+    SecretKey::from_slice(&hex(s)).expect("test vector keys are valid")
+}
+
+fn pubkey(s: &str) -> PublicKey {
+    PublicKey::from_slice(&hex(s)).expect("test vector keys are valid")
+}
+
+/// The act one this crate sends as `our_key` to `their_pubkey` with the ephemeral key
+/// `ephemeral`.
+pub fn act_one(our_key: SecretKey, their_pubkey: PublicKey, ephemeral: SecretKey) -> [u8; 50] {
+    Handshake::with_ephemeral_key(our_key, their_pubkey, ephemeral).1
+}
+
+/// The act three this crate answers `act_two` with, after the [`act_one`] of the same keys,
+/// and the transport for the rest of the session.
+pub fn act_three(
+    our_key: SecretKey,
+    their_pubkey: PublicKey,
+    ephemeral: SecretKey,
+    act_two: &[u8; ACT_TWO_SIZE],
+) -> Result<(Transport, [u8; 66]), Error> {
+    let (handshake, _) = Handshake::with_ephemeral_key(our_key, their_pubkey, ephemeral);
+    handshake.process_act_two(act_two)
+}
+
+/// [`act_one`] with the spec's initiator keys.
+pub fn spec_act_one() -> [u8; 50] {
+    act_one(
+        secret_key(INITIATOR_STATIC_KEY),
+        pubkey(RESPONDER_STATIC_PUBKEY),
+        secret_key(INITIATOR_EPHEMERAL_KEY),
+    )
+}
+
+/// [`act_three`] with the spec's initiator keys.
+pub fn spec_act_three(act_two: &[u8; ACT_TWO_SIZE]) -> Result<[u8; 66], Error> {
+    spec_handshake(act_two).map(|(_, act_three)| act_three)
+}
+
+/// The initiator's transport after the spec handshake, ready to encrypt [`MESSAGE`] as in
+/// [`MESSAGE_VECTORS`], or anything else a responder under test should decrypt.
+pub fn spec_transport() -> Transport {
+    let act_two = hex(ACT_TWO).try_into().expect("act two is 50 bytes");
+    spec_handshake(&act_two)
+        .expect("the spec act two is valid")
+        .0
+}
+
+fn spec_handshake(act_two: &[u8; ACT_TWO_SIZE]) -> Result<(Transport, [u8; 66]), Error> {
+    act_three(
+        secret_key(INITIATOR_STATIC_KEY),
+        pubkey(RESPONDER_STATIC_PUBKEY),
+        secret_key(INITIATOR_EPHEMERAL_KEY),
+        act_two,
+    )
+}
+
+/// Encrypt [`MESSAGE`] with `transport`, as the spec does.
+pub fn encrypt_spec_message(transport: &mut Transport) -> Vec<u8> {
+    transport.encrypt_message(&Hello)
+}
+
+/// [`MESSAGE`] as a message of type 0x6865 ("he") with the payload "llo".
+#[derive(Debug)]
+struct Hello;
+
+impl Type for Hello {
+    fn type_id(&self) -> u16 {
+        u16::from_be_bytes([MESSAGE[0], MESSAGE[1]])
+    }
+}
+
+impl Writeable for Hello {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        w.write_all(&MESSAGE[2..])
+    }
+}
+
+/// A test vector this crate doesn't reproduce, from [`verify_initiator`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VectorMismatch {
+    /// Which vector, e.g. `"act three"` or `"message 500"`.
+    pub vector: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for VectorMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: expected {}, got {}",
+            self.vector, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for VectorMismatch {}
+
+fn check(vector: impl Into<String>, expected: &str, actual: &[u8]) -> Result<(), VectorMismatch> {
+    let actual = ::hex::encode(actual);
+    if actual == expected {
+        return Ok(());
+    }
+    Err(VectorMismatch {
+        vector: vector.into(),
+        expected: expected.to_string(),
+        actual,
+    })
+}
+
+/// Run every initiator vector: the acts, the act twos to reject, and the encryption of
+/// [`MESSAGE`] across the first key rotation.
+pub fn verify_initiator() -> Result<(), VectorMismatch> {
+    check("act one", ACT_ONE, &spec_act_one())?;
+    let act_two = hex(ACT_TWO).try_into().expect("act two is 50 bytes");
+    let (mut transport, act_three) = spec_handshake(&act_two).map_err(|err| VectorMismatch {
+        vector: "act two".to_string(),
+        expected: "accepted".to_string(),
+        actual: err.to_string(),
+    })?;
+    check("act three", ACT_THREE, &act_three)?;
+
+    for bad in BAD_ACT_TWOS {
+        let act_two = hex(bad).try_into().expect("act two is 50 bytes");
+        if spec_handshake(&act_two).is_ok() {
+            return Err(VectorMismatch {
+                vector: format!("act two {bad}"),
+                expected: "rejected".to_string(),
+                actual: "accepted".to_string(),
+            });
+        }
+    }
+
+    let mut vectors = MESSAGE_VECTORS.iter().peekable();
+    for n in 0..=MESSAGE_VECTORS[MESSAGE_VECTORS.len() - 1].0 {
+        let encrypted = encrypt_spec_message(&mut transport);
+        if let Some((_, expected)) = vectors.next_if(|(i, _)| *i == n) {
+            check(format!("message {n}"), expected, &encrypted)?;
+        }
+    }
+    Ok(())
+}
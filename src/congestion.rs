@@ -0,0 +1,235 @@
+//! A congestion signal for the sending side of a connection.
+//!
+//! Every write the [`sender`](crate::sender) task makes updates exponential moving averages
+//! of how long the write took and how many bytes were still waiting to go out. Together they
+//! give a [`Congestion::score`]: `0.0` for an idle connection, `1.0` once either average
+//! reaches its budget, and more when the peer falls further behind. Layers that produce more
+//! than they must send, like gossip rebroadcasting, can shed load on it before the outbox
+//! and the kernel buffers balloon:
+//!
+//! ```no_run
+//! # use lnsocket::LNSocket;
+//! use lnsocket::congestion::CongestionLevel;
+//! # async fn ex(sock: LNSocket) {
+//! let mut level = sock.watch_congestion();
+//! while level.changed().await.is_ok() {
+//!     if *level.borrow() == CongestionLevel::Congested {
+//!         // stop queueing optional messages for now
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! Snapshots are available from [`LNSocket::congestion`](crate::LNSocket::congestion) and
+//! [`MessageSender::congestion`](crate::MessageSender::congestion).
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// The weight of a new sample in the moving averages.
+const EMA_WEIGHT: f64 = 0.2;
+
+/// How long a write may take on average before the latency half of the score reaches `1.0`,
+/// by default.
+pub const DEFAULT_LATENCY_BUDGET: Duration = Duration::from_millis(250);
+
+/// How many bytes may be waiting on average before the backlog half of the score reaches
+/// `1.0`, by default.
+pub const DEFAULT_PENDING_BUDGET: usize = 256 * 1024;
+
+/// Where the score stands relative to the [`CongestionThresholds`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CongestionLevel {
+    #[default]
+    Clear,
+    Elevated,
+    Congested,
+}
+
+/// The scores at which the level changes, and what the score is measured against.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CongestionThresholds {
+    /// Scores from here on are [`CongestionLevel::Elevated`].
+    pub elevated: f64,
+    /// Scores from here on are [`CongestionLevel::Congested`].
+    pub congested: f64,
+    pub latency_budget: Duration,
+    pub pending_budget: usize,
+}
+
+impl Default for CongestionThresholds {
+    fn default() -> Self {
+        Self {
+            elevated: 0.5,
+            congested: 1.0,
+            latency_budget: DEFAULT_LATENCY_BUDGET,
+            pending_budget: DEFAULT_PENDING_BUDGET,
+        }
+    }
+}
+
+impl CongestionThresholds {
+    fn level(&self, score: f64) -> CongestionLevel {
+        if score >= self.congested {
+            CongestionLevel::Congested
+        } else if score >= self.elevated {
+            CongestionLevel::Elevated
+        } else {
+            CongestionLevel::Clear
+        }
+    }
+}
+
+/// A snapshot of the congestion signal.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Congestion {
+    /// Moving average of how long a write took.
+    pub write_latency: Duration,
+    /// Moving average of the bytes waiting when a write started, the write included.
+    pub pending_bytes: f64,
+    /// Bytes queued for writing right now.
+    pub pending_now: usize,
+    /// The larger of the two averages relative to its budget.
+    pub score: f64,
+    pub level: CongestionLevel,
+}
+
+struct State {
+    congestion: Congestion,
+    /// Whether the averages have their first sample yet.
+    sampled: bool,
+    thresholds: CongestionThresholds,
+}
+
+/// Shared between the senders, which count bytes in, and the writer task, which counts them
+/// out and samples.
+pub(crate) struct CongestionTracker {
+    pending: AtomicUsize,
+    state: Mutex<State>,
+    level: watch::Sender<CongestionLevel>,
+}
+
+impl CongestionTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            state: Mutex::new(State {
+                congestion: Congestion::default(),
+                sampled: false,
+                thresholds: CongestionThresholds::default(),
+            }),
+            level: watch::channel(CongestionLevel::Clear).0,
+        }
+    }
+
+    /// `bytes` were handed to the writer task.
+    pub(crate) fn queued(&self, bytes: usize) {
+        self.pending.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// `bytes` won't be written after all.
+    pub(crate) fn dropped(&self, bytes: usize) {
+        self.pending.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// A write of `bytes` took `took`. Updates the averages, and the level watchers when
+    /// the level changes.
+    pub(crate) fn written(&self, bytes: usize, took: Duration) {
+        let backlog = self.pending.fetch_sub(bytes, Ordering::Relaxed);
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let c = &mut state.congestion;
+        if state.sampled {
+            c.write_latency = c.write_latency.mul_f64(1.0 - EMA_WEIGHT) + took.mul_f64(EMA_WEIGHT);
+            c.pending_bytes = c.pending_bytes * (1.0 - EMA_WEIGHT) + backlog as f64 * EMA_WEIGHT;
+        } else {
+            c.write_latency = took;
+            c.pending_bytes = backlog as f64;
+            state.sampled = true;
+        }
+        self.rescore(state);
+    }
+
+    pub(crate) fn set_thresholds(&self, thresholds: CongestionThresholds) {
+        let mut state = self.state.lock().unwrap();
+        state.thresholds = thresholds;
+        self.rescore(&mut state);
+    }
+
+    pub(crate) fn snapshot(&self) -> Congestion {
+        let mut congestion = self.state.lock().unwrap().congestion;
+        congestion.pending_now = self.pending.load(Ordering::Relaxed);
+        congestion
+    }
+
+    pub(crate) fn watch(&self) -> watch::Receiver<CongestionLevel> {
+        self.level.subscribe()
+    }
+
+    fn rescore(&self, state: &mut State) {
+        let t = state.thresholds;
+        let c = &mut state.congestion;
+        let latency = c.write_latency.as_secs_f64() / t.latency_budget.as_secs_f64();
+        let backlog = c.pending_bytes / t.pending_budget as f64;
+        c.score = latency.max(backlog);
+        c.level = t.level(c.score);
+
+        let level = c.level;
+        self.level.send_if_modified(|current| {
+            if *current == level {
+                return false;
+            }
+            tracing::debug!("congestion {current:?} -> {level:?} (score {:.2})", c.score);
+            *current = level;
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score_follows_latency_and_backlog() {
+        let tracker = CongestionTracker::new();
+        let mut level = tracker.watch();
+        tracker.set_thresholds(CongestionThresholds {
+            latency_budget: Duration::from_millis(100),
+            pending_budget: 1000,
+            ..Default::default()
+        });
+
+        tracker.queued(100);
+        tracker.written(100, Duration::from_millis(10));
+        let c = tracker.snapshot();
+        assert_eq!(c.write_latency, Duration::from_millis(10));
+        assert_eq!(c.level, CongestionLevel::Clear);
+        assert!(!level.has_changed().unwrap());
+
+        // a slow peer pushes the average over the budget within a few writes
+        for _ in 0..10 {
+            tracker.queued(100);
+            tracker.written(100, Duration::from_millis(500));
+        }
+        let c = tracker.snapshot();
+        assert!(c.score > 1.0, "{c:?}");
+        assert_eq!(*level.borrow_and_update(), CongestionLevel::Congested);
+
+        // and a big backlog alone is enough too
+        let tracker = CongestionTracker::new();
+        tracker.set_thresholds(CongestionThresholds {
+            pending_budget: 1000,
+            ..Default::default()
+        });
+        tracker.queued(10_000);
+        tracker.written(100, Duration::ZERO);
+        let c = tracker.snapshot();
+        assert_eq!(c.pending_now, 9_900);
+        assert_eq!(c.pending_bytes, 10_000.0);
+        assert_eq!(c.level, CongestionLevel::Congested);
+    }
+}
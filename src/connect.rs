@@ -0,0 +1,196 @@
+//! Connecting step by step, with progress.
+//!
+//! A connect over Tor can take half a minute, and an app showing a spinner over it can't
+//! tell the user whether the proxy is slow or the node isn't answering. [`ConnectBuilder`]
+//! connects like [`LNSocket::connect_with_config`] and calls back as it gets through each
+//! [`ConnectProgress`] step:
+//!
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::connect::ConnectProgress;
+//! # async fn ex(key: bitcoin::secp256k1::SecretKey, pk: bitcoin::secp256k1::PublicKey) -> Result<(), lnsocket::Error> {
+//! let sock = LNSocket::builder()
+//!     .key(key)
+//!     .peer(pk)
+//!     .addr("abcdef.onion:9735")
+//!     .on_progress(|progress| match progress {
+//!         ConnectProgress::Connecting => println!("building circuit..."),
+//!         ConnectProgress::Act2Received => println!("node answered"),
+//!         _ => {}
+//!     })
+//!     .await?;
+//! # Ok(()) }
+//! ```
+//!
+//! The builder is a future itself, so it can be awaited directly as above; `connect` borrows
+//! it instead, to connect again with the same options.
+
+use std::fmt;
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bitcoin::secp256k1::rand;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+
+use crate::config::LNSocketConfig;
+use crate::dial::ConnectTrace;
+use crate::{Error, LNSocket};
+
+/// How far a connect got, in the order the steps happen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ConnectProgress {
+    /// Resolving the address and checking the dial policy.
+    Resolving,
+    /// Opening the TCP connection, through the proxy if there is one.
+    Connecting,
+    /// Act one of the Noise handshake is out; waiting for the node to answer.
+    Act1Sent,
+    /// The node answered with act two: it is the node we meant to reach.
+    Act2Received,
+    /// `init` was exchanged and the socket is ready. Not reported when the config skips
+    /// `init`, see [`LNSocketConfig::with_init`].
+    InitExchanged,
+}
+
+pub(crate) type ProgressCallback = Arc<dyn Fn(ConnectProgress) + Send + Sync>;
+
+/// Builds a connect, see the [module docs](self).
+#[derive(Clone, Default)]
+pub struct ConnectBuilder {
+    key: Option<SecretKey>,
+    peer: Option<PublicKey>,
+    addr: Option<String>,
+    config: LNSocketConfig,
+    progress: Option<ProgressCallback>,
+}
+
+impl fmt::Debug for ConnectBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectBuilder")
+            .field("peer", &self.peer)
+            .field("addr", &self.addr)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ConnectBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Our node key. Without one, a fresh key is made for every connect.
+    pub fn key(mut self, key: SecretKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// The node id of the peer. Required.
+    pub fn peer(mut self, their_pubkey: PublicKey) -> Self {
+        self.peer = Some(their_pubkey);
+        self
+    }
+
+    /// Where to reach the peer, as for [`LNSocket::connect`]. Required.
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = Some(addr.into());
+        self
+    }
+
+    /// Connect with `config`; [`LNSocketConfig::default`] otherwise.
+    pub fn config(mut self, config: LNSocketConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Call `callback` as the connect gets through each step. It runs on the connecting
+    /// task, so it should return quickly.
+    pub fn on_progress(
+        mut self,
+        callback: impl Fn(ConnectProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Connect, failing with `Error::MissingConnectOption` if the peer or address is missing.
+    pub async fn connect(&self) -> Result<LNSocket, Error> {
+        let their_pubkey = self.peer.ok_or(Error::MissingConnectOption("peer"))?;
+        let addr = self
+            .addr
+            .as_deref()
+            .ok_or(Error::MissingConnectOption("addr"))?;
+        let our_key = self
+            .key
+            .unwrap_or_else(|| SecretKey::new(&mut rand::thread_rng()));
+        let trace = ConnectTrace::untraced_within(self.config.connect_timeout())
+            .with_progress(self.progress.clone());
+        LNSocket::connect_with_config_traced(our_key, their_pubkey, addr, &self.config, trace).await
+    }
+}
+
+impl IntoFuture for ConnectBuilder {
+    type Output = Result<LNSocket, Error>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move { self.connect().await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ACT_TWO_SIZE;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn pubkey() -> PublicKey {
+        PublicKey::from_secret_key(
+            &bitcoin::secp256k1::Secp256k1::signing_only(),
+            &SecretKey::from_slice(&[2; 32]).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn progress_is_reported_up_to_the_failing_step() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut act_one = [0u8; 50];
+            stream.read_exact(&mut act_one).await.unwrap();
+            // not a valid act two
+            stream.write_all(&[1u8; ACT_TWO_SIZE]).await.unwrap();
+        });
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let res = LNSocket::builder()
+            .peer(pubkey())
+            .addr(addr.to_string())
+            .on_progress({
+                let seen = seen.clone();
+                move |progress| seen.lock().unwrap().push(progress)
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                ConnectProgress::Resolving,
+                ConnectProgress::Connecting,
+                ConnectProgress::Act1Sent
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_and_addr_are_required() {
+        let err = LNSocket::builder().peer(pubkey()).connect().await;
+        assert!(matches!(err, Err(Error::MissingConnectOption("addr"))));
+        let err = LNSocket::builder().addr("127.0.0.1:9735").await;
+        assert!(matches!(err, Err(Error::MissingConnectOption("peer"))));
+    }
+}
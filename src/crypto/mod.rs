@@ -3,5 +3,6 @@ use bitcoin::hashes::cmp::fixed_time_eq;
 pub(crate) mod chacha20;
 pub(crate) mod chacha20poly1305rfc;
 pub(crate) mod poly1305;
+#[cfg(feature = "std")]
 pub(crate) mod streams;
 pub(crate) mod utils;
@@ -267,6 +267,7 @@ mod test {
     use core::iter::repeat;
 
     use super::Poly1305;
+    use crate::prelude::*;
 
     fn poly1305(key: &[u8], msg: &[u8], mac: &mut [u8]) {
         let mut poly = Poly1305::new(key);
@@ -0,0 +1,1065 @@
+//! Policy hooks for outbound connections.
+//!
+//! Wallets and hosted services often need a say in where their sockets go: only known peers,
+//! no clearnet in Tor-only mode, no private IP ranges from a multi-tenant server. Instead of
+//! wrapping every connect call, install a [`DialPolicy`] on a [`Dialer`] and connect through
+//! it. The policy sees the peer and every resolved address before any TCP connection is made,
+//! and sockets remember their dialer, so [`LNSocket::reconnect_fresh`] (and therefore
+//! [`CommandoClient`] reconnects) are checked too.
+//!
+//! ```no_run
+//! use lnsocket::dial::{Dialer, NoPrivateAddrs};
+//! # async fn ex(key: bitcoin::secp256k1::SecretKey, pk: bitcoin::secp256k1::PublicKey) -> Result<(), lnsocket::Error> {
+//! let dialer = Dialer::new().with_policy(NoPrivateAddrs);
+//! let sock = dialer.connect_and_init(key, pk, "node.example.com:9735").await?;
+//! # Ok(()) }
+//! ```
+//!
+//! A dialer also decides how to get there: [`Dialer::with_tor_proxy`] reaches onion
+//! addresses through Tor, and [`Dialer::connect_racing`] tries several addresses of a node at
+//! once, say its clearnet and onion ones, keeping whichever connects first. With the `tls`
+//! feature, `Dialer::with_tls` reaches nodes behind a TLS-terminating proxy, see the `tls`
+//! module, and with the `ssh` feature `Dialer::with_ssh_jump` reaches nodes behind an SSH
+//! bastion, see the `ssh` module.
+//!
+//! [`LNSocket::reconnect_fresh`]: crate::LNSocket::reconnect_fresh
+//! [`CommandoClient`]: crate::CommandoClient
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bitcoin::Network;
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use std::net::ToSocketAddrs;
+use tokio::net::{TcpSocket, lookup_host};
+use tokio::task::JoinSet;
+
+#[cfg(feature = "cancel")]
+use crate::cancel::{CancellationToken, or_cancelled};
+use crate::connect::{ConnectProgress, ProgressCallback};
+use crate::error::{ConnectStage, ConnectTimings};
+use crate::ln::msgs::DecodeError;
+use crate::lnsocket::InitOrder;
+use crate::network;
+use crate::privacy::{PaddingPolicy, PrivacyOptions, random_ephemeral_port};
+use crate::socket_addr::{DEFAULT_PORT, SocketAddress};
+use crate::socks;
+#[cfg(all(feature = "ssh", unix))]
+use crate::ssh::SshJump;
+use crate::stream::Stream;
+#[cfg(feature = "tls")]
+use crate::tls::TlsTunnel;
+use crate::util::ser::{Readable, Writeable, Writer};
+use crate::{Error, LNSocket};
+
+/// What a [`DialPolicy`] gets to look at before a connection is made.
+#[derive(Debug)]
+pub struct DialRequest<'a> {
+    /// The node we are about to connect to.
+    pub their_pubkey: &'a PublicKey,
+    /// The address exactly as given to the dialer, e.g. `"node.example.com:9735"`.
+    pub addr: &'a str,
+    /// Everything `addr` resolved to. We connect to the first one.
+    pub resolved: &'a [SocketAddr],
+}
+
+/// Decides whether an outbound connection may be made.
+///
+/// Returning `Err(reason)` aborts the connect with [`Error::DialDenied`]. Closures of the form
+/// `Fn(&DialRequest) -> Result<(), String>` implement this trait, and so do tuples of two
+/// policies, which must both allow the dial.
+pub trait DialPolicy: Send + Sync {
+    fn check(&self, req: &DialRequest<'_>) -> Result<(), String>;
+
+    /// Whether the policy looks at [`DialRequest::resolved`]. Through an SSH jump host
+    /// hostnames aren't resolved here, so such a policy refuses to dial them.
+    fn checks_addresses(&self) -> bool {
+        false
+    }
+}
+
+impl<F> DialPolicy for F
+where
+    F: Fn(&DialRequest<'_>) -> Result<(), String> + Send + Sync,
+{
+    fn check(&self, req: &DialRequest<'_>) -> Result<(), String> {
+        self(req)
+    }
+}
+
+impl<A: DialPolicy, B: DialPolicy> DialPolicy for (A, B) {
+    fn check(&self, req: &DialRequest<'_>) -> Result<(), String> {
+        self.0.check(req)?;
+        self.1.check(req)
+    }
+
+    fn checks_addresses(&self) -> bool {
+        self.0.checks_addresses() || self.1.checks_addresses()
+    }
+}
+
+/// Only allow connections to the given node ids.
+#[derive(Clone, Debug, Default)]
+pub struct PubkeyAllowlist(pub HashSet<PublicKey>);
+
+impl DialPolicy for PubkeyAllowlist {
+    fn check(&self, req: &DialRequest<'_>) -> Result<(), String> {
+        if self.0.contains(req.their_pubkey) {
+            Ok(())
+        } else {
+            Err(format!("{} is not in the allowlist", req.their_pubkey))
+        }
+    }
+}
+
+/// Never connect to the given node ids.
+#[derive(Clone, Debug, Default)]
+pub struct PubkeyDenylist(pub HashSet<PublicKey>);
+
+impl DialPolicy for PubkeyDenylist {
+    fn check(&self, req: &DialRequest<'_>) -> Result<(), String> {
+        if self.0.contains(req.their_pubkey) {
+            Err(format!("{} is denylisted", req.their_pubkey))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Refuse to connect if any resolved address is loopback, private, link-local, unspecified
+/// or otherwise not publicly routable. Checking every address keeps a hostname that resolves
+/// to both a public and a private address from slipping through.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoPrivateAddrs;
+
+impl DialPolicy for NoPrivateAddrs {
+    fn check(&self, req: &DialRequest<'_>) -> Result<(), String> {
+        match req.resolved.iter().find(|addr| !is_public(addr.ip())) {
+            Some(addr) => Err(format!(
+                "{} resolves to non-public address {addr}",
+                req.addr
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn checks_addresses(&self) -> bool {
+        true
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // shared address space (carrier-grade NAT), 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let seg = ip.segments();
+            let [.., a, b, c, d] = ip.octets();
+            let low_v4 = IpAddr::V4(Ipv4Addr::new(a, b, c, d));
+            // NAT64 64:ff9b::/96 and IPv4-compatible ::a.b.c.d reach the IPv4 address inside
+            if seg[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_public(low_v4);
+            }
+            if seg[..6] == [0; 6] && !ip.is_loopback() && !ip.is_unspecified() {
+                return is_public(low_v4);
+            }
+            // 6to4 2002::/16 carries it in the next 32 bits
+            if seg[0] == 0x2002 {
+                let [_, _, a, b, c, d, ..] = ip.octets();
+                return is_public(IpAddr::V4(Ipv4Addr::new(a, b, c, d)));
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local fc00::/7 and link-local fe80::/10
+                || (seg[0] & 0xfe00) == 0xfc00
+                || (seg[0] & 0xffc0) == 0xfe80
+                // documentation 2001:db8::/32
+                || seg[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
+/// Makes outbound [`LNSocket`] connections, consulting an optional [`DialPolicy`] first.
+///
+/// Cheap to clone; [`LNSocket::connect`] is the same as `Dialer::new().connect(..)`.
+#[derive(Clone, Default)]
+pub struct Dialer {
+    policy: Option<Arc<dyn DialPolicy>>,
+    networks: Vec<Network>,
+    init_order: InitOrder,
+    privacy: PrivacyOptions,
+    tor_proxy: Option<SocketAddr>,
+    dns_rebinding_protection: bool,
+    #[cfg(feature = "cancel")]
+    cancel: Option<CancellationToken>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsTunnel>,
+    #[cfg(all(feature = "ssh", unix))]
+    ssh: Option<SshJump>,
+}
+
+/// Where a dial goes once resolved.
+pub(crate) enum Target {
+    Direct(SocketAddr),
+    /// An onion service, through the Tor proxy.
+    Onion {
+        proxy: SocketAddr,
+        host: String,
+        port: u16,
+    },
+    /// Anything, through the SSH jump host, which resolves it.
+    #[cfg(all(feature = "ssh", unix))]
+    Jump {
+        host: String,
+        port: u16,
+    },
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Direct(addr) => write!(f, "{addr}"),
+            Target::Onion { host, port, .. } => write!(f, "{host}:{port}"),
+            #[cfg(all(feature = "ssh", unix))]
+            Target::Jump { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+impl Dialer {
+    /// A dialer that allows every connection.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consult `policy` before every connection made with this dialer.
+    pub fn with_policy(mut self, policy: impl DialPolicy + 'static) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Expect peers to be on `network`: our `init` advertises it, and a peer whose `init`
+    /// lists only other chains fails the connect with [`Error::NetworkMismatch`]. Without
+    /// this any network goes, as before.
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.networks = vec![network];
+        self
+    }
+
+    /// Like [`Dialer::with_network`] for a node on several chains at once: our `init`
+    /// advertises all of `networks`, in order, and a peer has to be on one of them. An empty
+    /// list lets any network go again.
+    pub fn with_networks(mut self, networks: impl IntoIterator<Item = Network>) -> Self {
+        self.networks = networks.into_iter().collect();
+        self
+    }
+
+    /// The first of the networks we expect, the one messages that name a single chain use.
+    pub fn network(&self) -> Option<Network> {
+        self.networks.first().copied()
+    }
+
+    pub fn networks(&self) -> &[Network] {
+        &self.networks
+    }
+
+    /// Who sends `init` first on connections made with this dialer. [`InitOrder::PeerFirst`]
+    /// by default.
+    pub fn with_init_order(mut self, order: InitOrder) -> Self {
+        self.init_order = order;
+        self
+    }
+
+    pub fn init_order(&self) -> InitOrder {
+        self.init_order
+    }
+
+    /// Take the measures against fingerprinting in `privacy` on connections made with this
+    /// dialer, see the [`privacy`](crate::privacy) module.
+    pub fn with_privacy(mut self, privacy: PrivacyOptions) -> Self {
+        self.privacy = privacy;
+        self
+    }
+
+    pub fn privacy(&self) -> &PrivacyOptions {
+        &self.privacy
+    }
+
+    /// Reach onion addresses through the SOCKS5 proxy of a Tor daemon at `proxy`, usually
+    /// `127.0.0.1:9050`. Other addresses are still connected to directly.
+    pub fn with_tor_proxy(mut self, proxy: SocketAddr) -> Self {
+        self.tor_proxy = Some(proxy);
+        self
+    }
+
+    /// Open TLS to whatever is connected to, a fronting proxy, and run the Noise handshake
+    /// inside it. See the [`tls`](crate::tls) module.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tunnel: TlsTunnel) -> Self {
+        self.tls = Some(tunnel);
+        self
+    }
+
+    /// Log in to an SSH bastion and reach every address from there, through a
+    /// `direct-tcpip` channel. See the [`ssh`](crate::ssh) module.
+    ///
+    /// The bastion resolves hostnames, so with DNS rebinding protection or a policy that
+    /// [checks addresses](DialPolicy::checks_addresses) only IPs can be dialed; hostnames
+    /// fail with [`Error::Ssh`].
+    #[cfg(all(feature = "ssh", unix))]
+    pub fn with_ssh_jump(mut self, jump: SshJump) -> Self {
+        self.ssh = Some(jump);
+        self
+    }
+
+    /// Refuse to connect when a hostname resolves to a loopback, private, link-local or
+    /// otherwise non-public address, failing with [`Error::ForbiddenAddress`]. Off by default.
+    ///
+    /// For services that connect to node URIs their users supply: without it, a hostname
+    /// under the user's control can point the service at its own internal network. Addresses
+    /// given as IPs are still connected to, and the address that was checked is the one
+    /// connected to, so the name can't be re-resolved in between. For a ban on private
+    /// addresses however they're given, use the [`NoPrivateAddrs`] policy.
+    pub fn with_dns_rebinding_protection(mut self, enabled: bool) -> Self {
+        self.dns_rebinding_protection = enabled;
+        self
+    }
+
+    /// Give up connects made with this dialer with `Error::Cancelled` once `token` is
+    /// cancelled, at whatever stage they are. Sockets remember their dialer, so this covers
+    /// their reconnects too. See the [`cancel`](crate::cancel) module.
+    #[cfg(feature = "cancel")]
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Run `fut` unless the dialer's cancellation token is cancelled first.
+    pub(crate) async fn cancellable<T>(
+        &self,
+        fut: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        #[cfg(feature = "cancel")]
+        if let Some(token) = &self.cancel {
+            return or_cancelled(token, fut).await;
+        }
+        fut.await
+    }
+
+    /// A socket to connect to `addr` from, bound to a random local port if the privacy
+    /// options ask for one.
+    pub(crate) fn tcp_socket(&self, addr: &SocketAddr) -> io::Result<TcpSocket> {
+        let new = || {
+            if addr.is_ipv4() {
+                TcpSocket::new_v4()
+            } else {
+                TcpSocket::new_v6()
+            }
+        };
+        if !self.privacy.random_local_port {
+            return new();
+        }
+        // another connection may hold the port we picked; after a few tries, let the OS pick
+        for _ in 0..8 {
+            let socket = new()?;
+            socket.set_reuseaddr(true)?;
+            let local = SocketAddr::new(unspecified(addr), random_ephemeral_port());
+            if socket.bind(local).is_ok() {
+                return Ok(socket);
+            }
+        }
+        new()
+    }
+
+    /// Resolve `addr` and run the policy, returning where to connect to.
+    ///
+    /// `addr` is parsed as a [`SocketAddress`]: the port defaults to the
+    /// [conventional one](crate::network::default_port) of our network, 9735 on mainnet or
+    /// without one. Onion addresses
+    /// fail right away with [`Error::OnionRequiresProxy`] rather than as a DNS error unless
+    /// there is a [Tor proxy](Dialer::with_tor_proxy); the policy then sees no resolved
+    /// addresses for them. Hostnames are checked for
+    /// [DNS rebinding](Dialer::with_dns_rebinding_protection) before the policy runs.
+    /// Through an SSH jump host hostnames are resolved by the bastion, so they fail with
+    /// [`Error::Ssh`] when DNS rebinding protection or an address-checking policy is on.
+    pub(crate) async fn resolve(
+        &self,
+        their_pubkey: &PublicKey,
+        addr: &str,
+    ) -> Result<Target, Error> {
+        let default_port = self.network().map_or(DEFAULT_PORT, network::default_port);
+        let target = SocketAddress::parse_with_default_port(addr, default_port)?;
+        #[cfg(all(feature = "ssh", unix))]
+        if self.ssh.is_some() {
+            let resolved: Vec<SocketAddr> = match &target {
+                SocketAddress::TcpIpV4 { .. } | SocketAddress::TcpIpV6 { .. } => {
+                    target.to_socket_addrs()?.collect()
+                }
+                SocketAddress::Hostname { .. }
+                    if self.dns_rebinding_protection
+                        || self.policy.as_ref().is_some_and(|p| p.checks_addresses()) =>
+                {
+                    return Err(Error::Ssh(format!(
+                        "{addr} would be resolved by the jump host, out of reach of the address checks"
+                    )));
+                }
+                _ => Vec::new(),
+            };
+            self.check_policy(their_pubkey, addr, &resolved)?;
+            let display = target.to_string();
+            let (host, port) = display.rsplit_once(':').expect("addresses have a port");
+            return Ok(Target::Jump {
+                host: host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                port: port.parse().expect("addresses have a numeric port"),
+            });
+        }
+        let resolved: Vec<SocketAddr> = match &target {
+            SocketAddress::Hostname { hostname, port } => {
+                let resolved: Vec<SocketAddr> =
+                    lookup_host((hostname.as_str(), *port)).await?.collect();
+                let forbidden = resolved.iter().find(|addr| !is_public(addr.ip()));
+                if let (true, Some(forbidden)) = (self.dns_rebinding_protection, forbidden) {
+                    return Err(Error::ForbiddenAddress {
+                        addr: addr.to_string(),
+                        ip: forbidden.ip(),
+                    });
+                }
+                resolved
+            }
+            SocketAddress::OnionV2(_) | SocketAddress::OnionV3 { .. } => Vec::new(),
+            ip => ip.to_socket_addrs()?.collect(),
+        };
+        let target = match (&target, self.tor_proxy) {
+            (SocketAddress::OnionV3 { port, .. }, Some(proxy)) => {
+                let display = target.to_string();
+                let (host, _) = display
+                    .rsplit_once(':')
+                    .expect("onion addresses have a port");
+                Target::Onion {
+                    proxy,
+                    host: host.to_string(),
+                    port: *port,
+                }
+            }
+            (SocketAddress::OnionV2(_) | SocketAddress::OnionV3 { .. }, _) => {
+                // Tor dropped v2 onion services, there is no proxying those
+                return Err(Error::OnionRequiresProxy(addr.to_string()));
+            }
+            _ => Target::Direct(*resolved.first().ok_or(Error::DnsError)?),
+        };
+
+        self.check_policy(their_pubkey, addr, &resolved)?;
+        Ok(target)
+    }
+
+    fn check_policy(
+        &self,
+        their_pubkey: &PublicKey,
+        addr: &str,
+        resolved: &[SocketAddr],
+    ) -> Result<(), Error> {
+        match &self.policy {
+            Some(policy) => policy
+                .check(&DialRequest {
+                    their_pubkey,
+                    addr,
+                    resolved,
+                })
+                .map_err(Error::DialDenied),
+            None => Ok(()),
+        }
+    }
+
+    /// Open the TCP connection to `target`, and the TLS tunnel in it if there is one, or the
+    /// channel to it through the SSH jump host, whose login gives up at `deadline`.
+    #[cfg_attr(not(all(feature = "ssh", unix)), allow(unused_variables))]
+    pub(crate) async fn open(
+        &self,
+        target: &Target,
+        deadline: Option<Instant>,
+    ) -> Result<Stream, Error> {
+        let stream = match target {
+            Target::Direct(addr) => self.tcp_socket(addr)?.connect(*addr).await?,
+            Target::Onion { proxy, host, port } => {
+                let mut stream = self.tcp_socket(proxy)?.connect(*proxy).await?;
+                socks::connect(&mut stream, host, *port).await?;
+                stream
+            }
+            #[cfg(all(feature = "ssh", unix))]
+            Target::Jump { host, port } => return self.open_jump(host, *port, deadline).await,
+        };
+        #[cfg(feature = "tls")]
+        if let Some(tunnel) = &self.tls {
+            return Ok(Stream::Tls(Box::new(tunnel.wrap(stream).await?)));
+        }
+        Ok(stream.into())
+    }
+
+    #[cfg(all(feature = "ssh", unix))]
+    async fn open_jump(
+        &self,
+        host: &str,
+        port: u16,
+        deadline: Option<Instant>,
+    ) -> Result<Stream, Error> {
+        let jump = self
+            .ssh
+            .as_ref()
+            .expect("jump targets come from a dialer with a jump host");
+        let bastion = lookup_host((jump.host(), jump.port()))
+            .await?
+            .next()
+            .ok_or(Error::DnsError)?;
+        let stream = self.tcp_socket(&bastion)?.connect(bastion).await?;
+        Ok(Stream::Ssh(jump.open(stream, host, port, deadline).await?))
+    }
+
+    /// Like [`LNSocket::connect`], subject to this dialer's policy.
+    pub async fn connect(
+        &self,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<LNSocket, Error> {
+        self.cancellable(LNSocket::dial(self.clone(), our_key, their_pubkey, addr))
+            .await
+    }
+
+    /// Like [`LNSocket::connect_and_init`], subject to this dialer's policy.
+    pub async fn connect_and_init(
+        &self,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<LNSocket, Error> {
+        self.cancellable(async {
+            let mut lnsocket = LNSocket::dial(self.clone(), our_key, their_pubkey, addr).await?;
+            lnsocket.perform_init().await?;
+            Ok(lnsocket)
+        })
+        .await
+    }
+
+    /// Connect to all of `addrs` at once, e.g. a node's clearnet and onion addresses, and
+    /// keep the socket whose handshake completes first. The other attempts are cancelled.
+    /// Fails only if every attempt does, with the error of the last one to fail.
+    ///
+    /// Like [`Dialer::connect`], no `init` is exchanged. Reconnects of the socket go to the
+    /// address that won.
+    pub async fn connect_racing(
+        &self,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addrs: &[&str],
+    ) -> Result<LNSocket, Error> {
+        self.cancellable(race(addrs.iter().map(|addr| {
+            let (dialer, addr) = (self.clone(), addr.to_string());
+            async move { LNSocket::dial(dialer, our_key, their_pubkey, &addr).await }
+        })))
+        .await
+    }
+}
+
+/// The first of `attempts` to succeed, or the last error if none does.
+async fn race<T, F>(attempts: impl IntoIterator<Item = F>) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: Future<Output = Result<T, Error>> + Send + 'static,
+{
+    let mut attempts: JoinSet<_> = attempts.into_iter().collect();
+    let mut last_err = Error::DnsError;
+    while let Some(res) = attempts.join_next().await {
+        match res {
+            // dropping the set aborts the others
+            Ok(Ok(winner)) => return Ok(winner),
+            Ok(Err(err)) => last_err = err,
+            Err(_) => last_err = Error::Io(io::ErrorKind::Interrupted),
+        }
+    }
+    Err(last_err)
+}
+
+impl Dialer {
+    /// Writes the settings that can cross a process boundary, see
+    /// [`ExportedSession`](crate::session::ExportedSession). The policy, TLS tunnel, SSH jump
+    /// host and cancellation token stay behind.
+    pub(crate) fn write_settings<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        (self.networks.len() as u16).write(w)?;
+        for network in &self.networks {
+            ChainHash::using_genesis_block(*network).write(w)?;
+        }
+        (self.init_order as u8).write(w)?;
+        let privacy = &self.privacy;
+        privacy
+            .connect_jitter
+            .map(|jitter| jitter.as_millis() as u64)
+            .write(w)?;
+        privacy.random_local_port.write(w)?;
+        let (padding, size) = match privacy.padding {
+            PaddingPolicy::None => (0u8, 0),
+            PaddingPolicy::RandomUpTo(size) => (1, size),
+            PaddingPolicy::Bucketed(size) => (2, size),
+        };
+        padding.write(w)?;
+        (size as u64).write(w)?;
+        privacy.minimal_init.write(w)?;
+        self.tor_proxy.map(|proxy| proxy.to_string()).write(w)?;
+        self.dns_rebinding_protection.write(w)
+    }
+
+    pub(crate) fn read_settings<R: io::Read>(r: &mut R) -> Result<Dialer, DecodeError> {
+        let count: u16 = Readable::read(r)?;
+        let mut networks = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let chain: ChainHash = Readable::read(r)?;
+            networks.push(Network::from_chain_hash(chain).ok_or(DecodeError::InvalidValue)?);
+        }
+        let init_order = match u8::read(r)? {
+            0 => InitOrder::PeerFirst,
+            1 => InitOrder::OursFirst,
+            _ => return Err(DecodeError::InvalidValue),
+        };
+        let connect_jitter: Option<u64> = Readable::read(r)?;
+        let random_local_port = Readable::read(r)?;
+        let padding: u8 = Readable::read(r)?;
+        let size = u64::read(r)? as usize;
+        let padding = match padding {
+            0 => PaddingPolicy::None,
+            1 => PaddingPolicy::RandomUpTo(size),
+            2 => PaddingPolicy::Bucketed(size),
+            _ => return Err(DecodeError::InvalidValue),
+        };
+        let privacy = PrivacyOptions {
+            connect_jitter: connect_jitter.map(Duration::from_millis),
+            random_local_port,
+            padding,
+            minimal_init: Readable::read(r)?,
+        };
+        let tor_proxy = Option::<String>::read(r)?
+            .map(|proxy| proxy.parse().map_err(|_| DecodeError::InvalidValue))
+            .transpose()?;
+        Ok(Dialer {
+            networks,
+            init_order,
+            privacy,
+            tor_proxy,
+            dns_rebinding_protection: Readable::read(r)?,
+            ..Dialer::default()
+        })
+    }
+}
+
+fn unspecified(addr: &SocketAddr) -> IpAddr {
+    if addr.is_ipv4() {
+        IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED)
+    }
+}
+
+/// Times the stages of a connect and enforces its deadline.
+pub(crate) struct ConnectTrace {
+    deadline: Option<Instant>,
+    timings: ConnectTimings,
+    /// Whether to wrap errors in `Error::Connect`. Plain connects keep their plain errors.
+    traced: bool,
+    progress: Option<ProgressCallback>,
+}
+
+impl ConnectTrace {
+    pub(crate) fn untraced() -> Self {
+        Self {
+            deadline: None,
+            timings: ConnectTimings::default(),
+            traced: false,
+            progress: None,
+        }
+    }
+
+    pub(crate) fn with_progress(mut self, progress: Option<ProgressCallback>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Tell the [`ConnectBuilder::on_progress`](crate::connect::ConnectBuilder::on_progress)
+    /// callback, if any, how far the connect got.
+    pub(crate) fn report(&self, progress: ConnectProgress) {
+        if let Some(callback) = &self.progress {
+            callback(progress);
+        }
+    }
+
+    /// Keeps plain errors like [`ConnectTrace::untraced`], with a deadline.
+    pub(crate) fn untraced_within(timeout: Option<Duration>) -> Self {
+        Self {
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            ..Self::untraced()
+        }
+    }
+
+    fn traced(timeout: Option<Duration>) -> Self {
+        Self {
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            timings: ConnectTimings::default(),
+            traced: true,
+            progress: None,
+        }
+    }
+
+    /// When the whole connect must be done by, if ever.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Run one stage, failing with `TimedOut` if the deadline passes first.
+    pub(crate) async fn stage<T>(
+        &mut self,
+        stage: ConnectStage,
+        fut: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let start = Instant::now();
+        let res = match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), fut)
+                .await
+                .unwrap_or(Err(Error::Io(io::ErrorKind::TimedOut))),
+            None => fut.await,
+        };
+        if !self.traced {
+            return res;
+        }
+        self.timings.0.push((stage, start.elapsed()));
+        res.map_err(|error| Error::Connect {
+            stage,
+            timings: self.timings.clone(),
+            error: Box::new(error),
+        })
+    }
+}
+
+impl Dialer {
+    /// Like [`Dialer::connect_and_init`], but a failure says which [`ConnectStage`] it
+    /// happened in and how long every stage up to it took, as `Error::Connect`. On success
+    /// the timings are returned along with the socket.
+    ///
+    /// `timeout` bounds the whole connect; running out fails the current stage with
+    /// `Error::Io(TimedOut)`. Useful to tell a slow Tor circuit (`Tcp`) from a peer that
+    /// never answers the handshake (`ActTwo`) or never sends its `init` (`InitRead`).
+    pub async fn connect_and_init_traced(
+        &self,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        timeout: Option<Duration>,
+    ) -> Result<(LNSocket, ConnectTimings), Error> {
+        let mut trace = ConnectTrace::traced(timeout);
+        self.cancellable(async {
+            let mut lnsocket =
+                LNSocket::dial_traced(self.clone(), our_key, their_pubkey, addr, &mut trace)
+                    .await?;
+            lnsocket.perform_init_traced(&mut trace).await?;
+            Ok((lnsocket, trace.timings))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::Secp256k1;
+
+    fn pubkey(b: u8) -> PublicKey {
+        PublicKey::from_secret_key(
+            &Secp256k1::signing_only(),
+            &SecretKey::from_slice(&[b; 32]).unwrap(),
+        )
+    }
+
+    fn check(policy: &dyn DialPolicy, pk: &PublicKey, addrs: &[&str]) -> Result<(), String> {
+        let resolved: Vec<SocketAddr> = addrs.iter().map(|a| a.parse().unwrap()).collect();
+        policy.check(&DialRequest {
+            their_pubkey: pk,
+            addr: "node.example.com:9735",
+            resolved: &resolved,
+        })
+    }
+
+    #[test]
+    fn private_addresses_are_refused() {
+        for addr in [
+            "127.0.0.1:9735",
+            "10.1.2.3:9735",
+            "192.168.1.1:9735",
+            "169.254.0.1:9735",
+            "100.100.0.1:9735",
+            "[::1]:9735",
+            "[fd00::1]:9735",
+            "[fe80::1]:9735",
+            "[::ffff:10.0.0.1]:9735",
+        ] {
+            assert!(
+                check(&NoPrivateAddrs, &pubkey(1), &[addr]).is_err(),
+                "{addr}"
+            );
+        }
+        assert!(
+            check(
+                &NoPrivateAddrs,
+                &pubkey(1),
+                &["1.1.1.1:9735", "[2606:4700::1]:9735"]
+            )
+            .is_ok()
+        );
+        // one bad address taints the whole lookup
+        assert!(
+            check(
+                &NoPrivateAddrs,
+                &pubkey(1),
+                &["1.1.1.1:9735", "10.0.0.1:9735"]
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn nat64_addresses_are_judged_by_their_ipv4() {
+        assert!(!is_public("64:ff9b::10.0.0.1".parse().unwrap()));
+        assert!(!is_public("64:ff9b::127.0.0.1".parse().unwrap()));
+        assert!(is_public("64:ff9b::1.1.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn six_to_four_addresses_are_judged_by_their_ipv4() {
+        // 2002:c0a8:0101:: is 192.168.1.1
+        assert!(!is_public("2002:c0a8:101::1".parse().unwrap()));
+        assert!(!is_public("2002:7f00:1::".parse().unwrap()));
+        assert!(is_public("2002:101:101::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv4_compatible_addresses_are_judged_by_their_ipv4() {
+        assert!(!is_public("::10.0.0.1".parse().unwrap()));
+        assert!(!is_public("::192.168.1.1".parse().unwrap()));
+        assert!(is_public("::1.1.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_multicast_is_refused() {
+        assert!(!is_public("ff02::1".parse().unwrap()));
+        assert!(!is_public("ff0e::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_documentation_addresses_are_refused() {
+        assert!(!is_public("2001:db8::1".parse().unwrap()));
+        assert!(!is_public("2001:db8:ffff::1".parse().unwrap()));
+        assert!(is_public("2001:db9::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn pubkey_lists_and_combinators() {
+        let allow = PubkeyAllowlist([pubkey(1)].into_iter().collect());
+        assert!(check(&allow, &pubkey(1), &["1.1.1.1:9735"]).is_ok());
+        assert!(check(&allow, &pubkey(2), &["1.1.1.1:9735"]).is_err());
+
+        let deny = PubkeyDenylist([pubkey(2)].into_iter().collect());
+        assert!(check(&deny, &pubkey(2), &["1.1.1.1:9735"]).is_err());
+
+        let tor_only = |req: &DialRequest<'_>| {
+            if req.addr.contains(".onion:") {
+                Ok(())
+            } else {
+                Err("clearnet disabled".to_string())
+            }
+        };
+        assert!(check(&(allow, tor_only), &pubkey(1), &["1.1.1.1:9735"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn denied_dial_never_connects() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let err = Dialer::new()
+            .with_policy(NoPrivateAddrs)
+            .connect(SecretKey::from_slice(&[3; 32]).unwrap(), pubkey(1), &addr)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::DialDenied(_)));
+    }
+
+    #[tokio::test]
+    async fn traced_connect_reports_stalled_stage() {
+        // accepts the connection but never answers act one
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let _peer = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+            drop(stream);
+        });
+
+        let err = Dialer::new()
+            .connect_and_init_traced(
+                SecretKey::from_slice(&[3; 32]).unwrap(),
+                pubkey(1),
+                &addr,
+                Some(Duration::from_millis(100)),
+            )
+            .await
+            .err()
+            .unwrap();
+        let Error::Connect {
+            stage,
+            timings,
+            error,
+        } = err
+        else {
+            panic!("expected a connect error, got {err}");
+        };
+        assert_eq!(stage, ConnectStage::ActTwo);
+        assert!(matches!(*error, Error::Io(io::ErrorKind::TimedOut)));
+        let stages: Vec<_> = timings.0.iter().map(|(stage, _)| *stage).collect();
+        assert_eq!(
+            stages,
+            [
+                ConnectStage::Resolve,
+                ConnectStage::Tcp,
+                ConnectStage::ActOne,
+                ConnectStage::ActTwo
+            ]
+        );
+        assert!(timings.get(ConnectStage::ActTwo).unwrap() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn random_local_port_is_ephemeral() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dialer = Dialer::new().with_privacy(PrivacyOptions {
+            random_local_port: true,
+            ..Default::default()
+        });
+        let socket = dialer.tcp_socket(&addr).unwrap();
+        // when every port tried is taken the OS picks one on connect, from its own range
+        let picked = socket.local_addr().unwrap().port();
+        let stream = socket.connect(addr).await.unwrap();
+        if picked != 0 {
+            assert!(picked >= 49152);
+            assert_eq!(stream.local_addr().unwrap().port(), picked);
+        }
+    }
+
+    #[tokio::test]
+    async fn hostnames_resolving_to_private_addresses_are_refused() {
+        let dialer = Dialer::new().with_dns_rebinding_protection(true);
+        let err = dialer
+            .resolve(&pubkey(1), "localhost:9735")
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, Error::ForbiddenAddress { addr, ip } if addr == "localhost:9735" && ip.is_loopback())
+        );
+
+        // an IP is taken as given, and the check is off by default
+        assert!(dialer.resolve(&pubkey(1), "127.0.0.1:9735").await.is_ok());
+        assert!(
+            Dialer::new()
+                .resolve(&pubkey(1), "localhost:9735")
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn ports_default_to_the_network_convention() {
+        let resolve = |dialer: Dialer, addr: &'static str| async move {
+            dialer.resolve(&pubkey(1), addr).await.unwrap().to_string()
+        };
+        assert_eq!(resolve(Dialer::new(), "127.0.0.1").await, "127.0.0.1:9735");
+        let regtest = Dialer::new().with_network(Network::Regtest);
+        assert_eq!(
+            resolve(regtest.clone(), "127.0.0.1").await,
+            "127.0.0.1:19846"
+        );
+        assert_eq!(resolve(regtest, "127.0.0.1:1234").await, "127.0.0.1:1234");
+        let signet = Dialer::new().with_network(Network::Signet);
+        assert_eq!(resolve(signet, "127.0.0.1").await, "127.0.0.1:39735");
+    }
+
+    #[tokio::test]
+    async fn onion_addresses_fail_fast() {
+        let onion = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:9735";
+        let err = Dialer::new()
+            .connect(SecretKey::from_slice(&[3; 32]).unwrap(), pubkey(1), onion)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::OnionRequiresProxy(addr) if addr == onion));
+
+        let err = Dialer::new()
+            .connect(
+                SecretKey::from_slice(&[3; 32]).unwrap(),
+                pubkey(1),
+                "bad host",
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::InvalidAddress(_)));
+
+        let proxy: SocketAddr = "127.0.0.1:9050".parse().unwrap();
+        let dialer = Dialer::new().with_tor_proxy(proxy);
+        let target = dialer.resolve(&pubkey(1), onion).await.unwrap();
+        assert!(matches!(&target, Target::Onion { proxy: p, port: 9735, .. } if *p == proxy));
+        assert_eq!(target.to_string(), onion);
+    }
+
+    #[tokio::test]
+    async fn first_attempt_to_succeed_wins() {
+        let attempt = |delay: u64, res: Result<u8, Error>| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            res
+        };
+        let winner = race([
+            attempt(500, Ok(1)),
+            attempt(20, Ok(2)),
+            attempt(0, Err(Error::DnsError)),
+        ])
+        .await;
+        assert!(matches!(winner, Ok(2)));
+
+        let failed = race([
+            attempt(0, Err(Error::DnsError)),
+            attempt(20, Err(Error::Io(io::ErrorKind::ConnectionRefused))),
+        ])
+        .await;
+        assert!(matches!(
+            failed,
+            Err(Error::Io(io::ErrorKind::ConnectionRefused))
+        ));
+    }
+}
@@ -0,0 +1,279 @@
+//! Finding nodes through DNS, behind the `srv` cargo feature.
+//!
+//! Hosted services that run several CLN nodes can publish them as SRV records, e.g.
+//! `_lightning._tcp.example.com`, with each record's target carrying the node id in a TXT
+//! record (`pubkey=02ab...`, or just the hex). [`SrvDiscovery`] resolves them and connects to
+//! one, so clients only need to know a name:
+//!
+//! ```no_run
+//! use lnsocket::discovery::{Selection, SrvDiscovery};
+//! # async fn ex(key: bitcoin::secp256k1::SecretKey) -> Result<(), lnsocket::Error> {
+//! let nodes = SrvDiscovery::new("_lightning._tcp.example.com")?.selection(Selection::Random);
+//! let sock = nodes.connect_and_init(&lnsocket::dial::Dialer::new(), key).await?;
+//! # Ok(()) }
+//! ```
+//!
+//! Records are tried by ascending priority as in RFC 2782. Among records of the same
+//! priority, [`Selection`] decides who goes first. Targets without a usable node id are
+//! skipped.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bitcoin::secp256k1::rand::{self, Rng};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use hickory_resolver::TokioResolver;
+
+use crate::dial::Dialer;
+use crate::{Error, LNSocket};
+
+/// A node published in DNS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeRecord {
+    pub node_id: PublicKey,
+    pub host: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+impl NodeRecord {
+    /// `host:port`, ready for [`LNSocket::connect`].
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// How to order records of the same priority.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Selection {
+    /// Each lookup starts one record further along, spreading clients of one
+    /// [`SrvDiscovery`] evenly.
+    #[default]
+    RoundRobin,
+    /// Weighted by the SRV weight, spreading independent clients by the operator's weights.
+    Random,
+}
+
+/// Resolves the nodes behind an SRV name, see the [module docs](self).
+pub struct SrvDiscovery {
+    name: String,
+    selection: Selection,
+    resolver: TokioResolver,
+    turn: AtomicUsize,
+}
+
+impl SrvDiscovery {
+    /// Use the system's resolver configuration. Fails with `Error::DnsError` if it can't be
+    /// read.
+    pub fn new(name: impl Into<String>) -> Result<Self, Error> {
+        let resolver = TokioResolver::builder_tokio()
+            .map_err(|err| {
+                tracing::debug!("no system resolver config: {err}");
+                Error::DnsError
+            })?
+            .build();
+        Ok(Self {
+            name: name.into(),
+            selection: Selection::default(),
+            resolver,
+            turn: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Every published node with a usable node id, in the order to try them. Fails with
+    /// `Error::DnsError` if there is none.
+    pub async fn lookup(&self) -> Result<Vec<NodeRecord>, Error> {
+        let srv = self
+            .resolver
+            .srv_lookup(self.name.as_str())
+            .await
+            .map_err(|err| {
+                tracing::debug!("SRV lookup of {} failed: {err}", self.name);
+                Error::DnsError
+            })?;
+
+        let mut records = Vec::new();
+        for srv in srv.iter() {
+            let host = srv.target().to_utf8();
+            let host = host.trim_end_matches('.');
+            let Some(node_id) = self.node_id(host).await else {
+                tracing::debug!("{host} has no node id TXT record, skipping it");
+                continue;
+            };
+            records.push(NodeRecord {
+                node_id,
+                host: host.to_string(),
+                port: srv.port(),
+                priority: srv.priority(),
+                weight: srv.weight(),
+            });
+        }
+        if records.is_empty() {
+            return Err(Error::DnsError);
+        }
+
+        let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+        Ok(order(
+            records,
+            self.selection,
+            turn,
+            &mut rand::thread_rng(),
+        ))
+    }
+
+    /// Look the nodes up and connect to the first one that completes the handshake and
+    /// `init`, returning the last error if none does.
+    pub async fn connect_and_init(
+        &self,
+        dialer: &Dialer,
+        our_key: SecretKey,
+    ) -> Result<LNSocket, Error> {
+        let mut last_err = Error::DnsError;
+        for record in self.lookup().await? {
+            match dialer
+                .connect_and_init(our_key, record.node_id, &record.addr())
+                .await
+            {
+                Ok(sock) => return Ok(sock),
+                Err(err) => {
+                    tracing::debug!("{} ({}) failed: {err}", record.addr(), record.node_id);
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn node_id(&self, host: &str) -> Option<PublicKey> {
+        let txt = self.resolver.txt_lookup(host).await.ok()?;
+        txt.iter()
+            .flat_map(|txt| txt.txt_data().iter())
+            .find_map(|data| parse_node_id(data))
+    }
+}
+
+/// A node id TXT string: `pubkey=<hex>` or the bare hex.
+fn parse_node_id(data: &[u8]) -> Option<PublicKey> {
+    let s = std::str::from_utf8(data).ok()?.trim();
+    let hex = s.strip_prefix("pubkey=").unwrap_or(s);
+    hex.parse().ok()
+}
+
+/// Sort by priority, then order each priority group by `selection`.
+fn order(
+    mut records: Vec<NodeRecord>,
+    selection: Selection,
+    turn: usize,
+    rng: &mut impl Rng,
+) -> Vec<NodeRecord> {
+    records.sort_by_key(|r| r.priority);
+    let mut ordered = Vec::with_capacity(records.len());
+    for group in records.chunk_by(|a, b| a.priority == b.priority) {
+        let mut group = group.to_vec();
+        match selection {
+            Selection::RoundRobin => {
+                let len = group.len();
+                group.rotate_left(turn % len);
+                ordered.extend(group);
+            }
+            Selection::Random => {
+                // RFC 2782 weighted picking; the +1 leaves weight 0 records a small chance
+                while !group.is_empty() {
+                    let total: u32 = group.iter().map(|r| r.weight as u32 + 1).sum();
+                    let mut pick = rng.gen_range(0..total);
+                    let i = group
+                        .iter()
+                        .position(|r| {
+                            let w = r.weight as u32 + 1;
+                            if pick < w {
+                                return true;
+                            }
+                            pick -= w;
+                            false
+                        })
+                        .expect("pick is below the total weight");
+                    ordered.push(group.remove(i));
+                }
+            }
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::Secp256k1;
+
+    fn record(key: u8, priority: u16, weight: u16) -> NodeRecord {
+        NodeRecord {
+            node_id: PublicKey::from_secret_key(
+                &Secp256k1::signing_only(),
+                &SecretKey::from_slice(&[key; 32]).unwrap(),
+            ),
+            host: format!("node{key}.example.com"),
+            port: 9735,
+            priority,
+            weight,
+        }
+    }
+
+    fn hosts(records: &[NodeRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.host.as_str()).collect()
+    }
+
+    #[test]
+    fn node_id_txt_formats() {
+        let node_id = record(1, 0, 0).node_id;
+        assert_eq!(
+            parse_node_id(format!("pubkey={node_id}").as_bytes()),
+            Some(node_id)
+        );
+        assert_eq!(parse_node_id(node_id.to_string().as_bytes()), Some(node_id));
+        assert_eq!(parse_node_id(b"v=spf1 -all"), None);
+    }
+
+    #[test]
+    fn ordering_respects_priority_then_selection() {
+        let records = vec![record(3, 20, 0), record(1, 10, 0), record(2, 10, 0)];
+        let mut rng = rand::thread_rng();
+
+        let first = order(records.clone(), Selection::RoundRobin, 0, &mut rng);
+        assert_eq!(
+            hosts(&first),
+            [
+                "node1.example.com",
+                "node2.example.com",
+                "node3.example.com"
+            ]
+        );
+        let second = order(records.clone(), Selection::RoundRobin, 1, &mut rng);
+        assert_eq!(
+            hosts(&second),
+            [
+                "node2.example.com",
+                "node1.example.com",
+                "node3.example.com"
+            ]
+        );
+
+        // the backup stays last however the dice fall
+        for _ in 0..20 {
+            let random = order(records.clone(), Selection::Random, 0, &mut rng);
+            assert_eq!(random.len(), 3);
+            assert_eq!(random[2].host, "node3.example.com");
+        }
+
+        // a heavy weight wins nearly always
+        let weighted = vec![record(1, 0, 0), record(2, 0, 60000)];
+        let wins = (0..100)
+            .filter(|_| order(weighted.clone(), Selection::Random, 0, &mut rng)[0].weight > 0)
+            .count();
+        assert!(wins > 90, "{wins}");
+    }
+}
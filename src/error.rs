@@ -1,8 +1,17 @@
+use crate::capture::Direction;
 use crate::ln::msgs::{DecodeError, LightningError};
+use crate::ln::onion_failure::{self, OnionFailure};
+use crate::ln::peer_channel_encryptor::NoiseError;
+use crate::network::ChainName;
+use crate::socket_addr::SocketAddressParseError;
+use crate::validation::ValidationError;
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::PublicKey;
 use serde::Deserialize;
 use std::fmt;
 use std::io;
-use std::net::AddrParseError;
+use std::net::{AddrParseError, IpAddr};
+use std::time::Duration;
 
 /// Errors surfaced by this crate.
 ///
@@ -22,12 +31,385 @@ pub enum Error {
     Decode(DecodeError),
     AddrParse(std::net::AddrParseError),
     Rpc(RpcError),
+    /// Refused to send an even message type the peer did not advertise support for, see
+    /// [`LNSocket::set_strict_features`](crate::LNSocket::set_strict_features).
+    FeatureNotNegotiated(u16),
+    /// A [`DialPolicy`](crate::dial::DialPolicy) refused the connection, with its reason.
+    DialDenied(String),
+    /// The address given to connect to could not be parsed.
+    InvalidAddress(SocketAddressParseError),
+    /// Tried to connect to a Tor onion address, which needs a proxy that isn't configured.
+    OnionRequiresProxy(String),
+    /// The [`CommandoClient`](crate::CommandoClient)'s background task has stopped.
+    PumpExited(PumpExit),
+    /// The [`CommandoClient`](crate::CommandoClient)'s connection failed for good while the
+    /// call was in flight, see [`PumpTermination`].
+    PumpTerminated(Box<PumpTermination>),
+    /// A call was refused because the client is at one of its limits, see
+    /// [`CommandoConfig::max_in_flight`](crate::commando::CommandoConfig::max_in_flight).
+    Overloaded,
+    /// A BOLT 11 invoice failed to decode or verify, see
+    /// [`Invoice::parse`](crate::invoice::Invoice::parse).
+    InvalidInvoice(String),
+    /// A traced connect failed during `stage`, see
+    /// [`Dialer::connect_and_init_traced`](crate::dial::Dialer::connect_and_init_traced).
+    /// `timings` includes the failed stage.
+    Connect {
+        stage: ConnectStage,
+        timings: ConnectTimings,
+        error: Box<Error>,
+    },
+    /// The peer's `init` only lists chains other than the ones we expect, see
+    /// [`Dialer::with_network`](crate::dial::Dialer::with_network).
+    NetworkMismatch {
+        ours: Vec<ChainHash>,
+        theirs: Vec<ChainHash>,
+    },
+    /// The peer sent an `error` about all channels, after which BOLT 1 considers the
+    /// connection closed, see
+    /// [`LNSocket::set_close_on_peer_error`](crate::LNSocket::set_close_on_peer_error).
+    PeerClosedConnection {
+        message: String,
+    },
+    /// The SOCKS5 proxy failed to connect us, see
+    /// [`Dialer::with_tor_proxy`](crate::dial::Dialer::with_tor_proxy).
+    Proxy(String),
+    /// The TLS handshake with a fronting proxy failed, e.g. on its certificate, see
+    /// [`Dialer::with_tls`](crate::dial::Dialer::with_tls).
+    Tls(String),
+    /// Getting through the SSH jump host failed: connecting, authenticating, checking its host
+    /// key, or opening the channel to the node, see
+    /// [`Dialer::with_ssh_jump`](crate::dial::Dialer::with_ssh_jump).
+    Ssh(String),
+    /// The handshake failed the way it does when the node's key isn't `expected`: act two
+    /// was well formed but didn't decrypt. The node id is most likely wrong or out of date.
+    /// A peer hanging up during the handshake is an `Io` error, since rate limits, other
+    /// services on the port and Tor circuits dropping look the same.
+    PeerKeyMismatch {
+        expected: PublicKey,
+    },
+    /// A payment didn't go through, for CLN's `reason`; the call itself was fine. See
+    /// [`WalletClient::pay_invoice`](crate::wallet::WalletClient::pay_invoice).
+    PaymentFailed {
+        reason: CLNErrorCode,
+        message: String,
+    },
+    /// `addr` resolved to `ip`, which isn't publicly routable, with
+    /// [`Dialer::with_dns_rebinding_protection`](crate::dial::Dialer::with_dns_rebinding_protection)
+    /// on.
+    ForbiddenAddress {
+        addr: String,
+        ip: IpAddr,
+    },
+    /// A signed message's signature isn't a valid zbase32 recoverable signature, see
+    /// [`sign::recover_signer`](crate::sign::recover_signer).
+    InvalidSignature,
+    /// An enforced [`Quota`](crate::stats::Quota) for `direction` is used up; traffic that
+    /// way resumes in `resets_in`.
+    QuotaExceeded {
+        direction: Direction,
+        resets_in: Duration,
+    },
+    /// A length header or message from the peer failed authentication: it was tampered with,
+    /// or the two sides' ciphers are out of step. The connection can't be read any further.
+    BadMac,
+    /// The peer declared a message of `len` bytes (type and payload), more than the `max` this
+    /// connection accepts, see
+    /// [`LNSocket::set_max_message_len`](crate::LNSocket::set_max_message_len). Nothing of the
+    /// message was read.
+    MessageTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// A [`ConnectBuilder`](crate::connect::ConnectBuilder) was told to connect without this
+    /// option set.
+    MissingConnectOption(&'static str),
+    /// A [`RequestResponse`](crate::reqresp::RequestResponse) call used the id of another
+    /// call in flight, shown with `Debug`.
+    RequestIdInUse(String),
+    /// A cancellation token attached to the connect, call or client was cancelled, see the
+    /// `cancel` module. Also what a
+    /// [`CommandoClient::rekey_identity`](crate::CommandoClient::rekey_identity) superseded
+    /// by a later one before it ran returns, and what a call aborted by its
+    /// [`CallOpts::on_progress`](crate::commando::CallOpts::on_progress) hook fails with.
+    Cancelled,
+    /// A commando call's params or result failed the validator registered for its method,
+    /// see the [`validation`](crate::validation) module.
+    Validation(Box<ValidationError>),
+    /// A [`FanoutTransport`](crate::fanout::FanoutTransport) fell behind and missed this many
+    /// messages, so what it reads next may not follow on from what it read before.
+    Lagged(u64),
+}
+
+/// The steps of connecting to a peer, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectStage {
+    /// Parsing and resolving the address, and the dial policy.
+    Resolve,
+    /// The TCP connection.
+    Tcp,
+    /// Sending act one of the Noise handshake.
+    ActOne,
+    /// Waiting for and processing act two.
+    ActTwo,
+    /// Sending act three.
+    ActThree,
+    /// Waiting for the peer's `init`.
+    InitRead,
+    /// Sending our `init`.
+    InitWrite,
+}
+
+impl fmt::Display for ConnectStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectStage::Resolve => "resolve",
+            ConnectStage::Tcp => "tcp",
+            ConnectStage::ActOne => "act one",
+            ConnectStage::ActTwo => "act two",
+            ConnectStage::ActThree => "act three",
+            ConnectStage::InitRead => "init read",
+            ConnectStage::InitWrite => "init write",
+        })
+    }
+}
+
+/// How long each stage of a connect took, in the order they ran.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectTimings(pub Vec<(ConnectStage, Duration)>);
+
+impl ConnectTimings {
+    /// Time spent in `stage`, if it was reached.
+    pub fn get(&self, stage: ConnectStage) -> Option<Duration> {
+        self.0.iter().find(|(s, _)| *s == stage).map(|(_, d)| *d)
+    }
+
+    /// Time spent in all stages.
+    pub fn total(&self) -> Duration {
+        self.0.iter().map(|(_, d)| *d).sum()
+    }
+}
+
+impl fmt::Display for ConnectTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (stage, elapsed)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{stage} {elapsed:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a [`CommandoClient`](crate::CommandoClient)'s background task stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PumpExit {
+    /// [`CommandoClient::close`](crate::CommandoClient::close) was called, or every handle to
+    /// the client was dropped.
+    Closed,
+    /// The connection broke and was not re-established.
+    Disconnected,
+    /// The idle hook asked to disconnect, see
+    /// [`CommandoConfig::on_idle`](crate::commando::CommandoConfig::on_idle).
+    Idle,
+    /// The task panicked, with the panic message.
+    Panicked(String),
+    /// The peer sent an `error` about all channels, with its message. Calls in flight fail
+    /// with `Error::PeerClosedConnection`.
+    PeerClosed(String),
+    /// The token from [`CommandoConfig::cancel_on`](crate::commando::CommandoConfig::cancel_on)
+    /// was cancelled. Calls in flight fail with `Error::Cancelled`.
+    Cancelled,
+}
+
+impl fmt::Display for PumpExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PumpExit::Closed => write!(f, "closed"),
+            PumpExit::Disconnected => write!(f, "disconnected"),
+            PumpExit::Idle => write!(f, "disconnected while idle"),
+            PumpExit::Panicked(msg) => write!(f, "panicked: {msg}"),
+            PumpExit::PeerClosed(msg) => write!(f, "peer closed the connection: {msg:?}"),
+            PumpExit::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// How a [`CommandoClient`](crate::CommandoClient)'s connection ended, as every call it
+/// aborted is told with `Error::PumpTerminated`.
+///
+/// Calls the pump won't resend end this way: all of them with reconnects off or once
+/// reconnecting gives up, and those whose retry policy is spent when it reconnects. After an
+/// `error` from the peer calls fail with `Error::PeerClosedConnection` instead.
+#[derive(Debug, Clone)]
+pub struct PumpTermination {
+    pub cause: TerminationCause,
+    /// Calls aborted, the one told included. They are failed oldest first.
+    pub aborted_calls: usize,
+    /// Request id and reply bytes received, for each aborted call that had started to get
+    /// its reply.
+    pub partial_replies: Vec<(u64, usize)>,
+}
+
+/// What ended the connection, see [`PumpTermination`].
+#[derive(Debug, Clone)]
+pub enum TerminationCause {
+    /// The peer sent an `error` about all channels, with its message.
+    PeerError(String),
+    /// The peer closed the connection.
+    Eof,
+    TimedOut,
+    /// A message didn't decrypt or decode.
+    Decode(Box<Error>),
+    /// Any other I/O error.
+    Io(io::ErrorKind),
+}
+
+impl TerminationCause {
+    /// Sort the error a read or write failed with.
+    pub fn classify(err: &Error) -> Self {
+        match err {
+            Error::PeerClosedConnection { message } => Self::PeerError(message.clone()),
+            Error::Io(io::ErrorKind::UnexpectedEof) => Self::Eof,
+            Error::Io(io::ErrorKind::TimedOut) => Self::TimedOut,
+            Error::Io(kind) => Self::Io(*kind),
+            err => Self::Decode(Box::new(err.clone())),
+        }
+    }
+}
+
+impl fmt::Display for TerminationCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerminationCause::PeerError(message) => write!(f, "peer error {message:?}"),
+            TerminationCause::Eof => write!(f, "closed by the peer"),
+            TerminationCause::TimedOut => write!(f, "timed out"),
+            TerminationCause::Decode(err) => write!(f, "bad message: {err}"),
+            TerminationCause::Io(kind) => write!(f, "I/O error: {kind}"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RpcError {
     pub code: i64,
     pub message: String,
+    /// Details some errors come with, e.g. the failed payment of a `waitsendpay` error.
+    #[serde(default)]
+    pub data: Option<serde_json::Value>,
+}
+
+impl RpcError {
+    /// The code as one of the errors CLN documents.
+    pub fn kind(&self) -> CLNErrorCode {
+        CLNErrorCode::from_code(self.code)
+    }
+
+    /// The node has no such command, or the plugin providing it isn't running.
+    pub fn is_method_not_found(&self) -> bool {
+        self.kind() == CLNErrorCode::MethodNotFound
+    }
+
+    /// The rune doesn't allow this call.
+    pub fn is_rune_denied(&self) -> bool {
+        matches!(
+            self.kind(),
+            CLNErrorCode::RuneNotAuthorized
+                | CLNErrorCode::RuneNotPermitted
+                | CLNErrorCode::RuneCheckFailed
+        )
+    }
+
+    /// The rune was refused because its time restriction has passed: a new one is needed,
+    /// see [`CommandoConfig::rune_provider`](crate::commando::CommandoConfig::rune_provider).
+    pub fn is_rune_expired(&self) -> bool {
+        // e.g. "Not permitted: time is greater or equal to 1700000000"
+        self.is_rune_denied() && self.message.contains("time is greater")
+    }
+
+    /// A payment failed, as opposed to the call being malformed or refused: one of CLN's
+    /// `pay`/`sendpay` errors 201 to 214. 200, a payment still in progress, is no failure.
+    pub fn is_payment_failure(&self) -> bool {
+        (201..=214).contains(&self.code)
+    }
+
+    /// The onion failure a payment error reports, decoded from the `raw_message` in its
+    /// data. `None` if there is none or it doesn't decode, see
+    /// [`onion_failure`](crate::ln::onion_failure).
+    pub fn onion_failure(&self) -> Option<OnionFailure> {
+        let raw = self.data.as_ref()?.get("raw_message")?.as_str()?;
+        onion_failure::decode_failure_message(&hex::decode(raw).ok()?).ok()
+    }
+}
+
+macro_rules! cln_error_codes {
+    ($($(#[$doc:meta])* $name:ident = $code:literal,)*) => {
+        /// Error codes Core Lightning answers with, see `common/jsonrpc_errors.h` in its
+        /// source. Codes not listed here are kept as [`CLNErrorCode::Other`].
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum CLNErrorCode {
+            $($(#[$doc])* $name,)*
+            Other(i64),
+        }
+
+        impl CLNErrorCode {
+            pub fn from_code(code: i64) -> Self {
+                match code {
+                    $($code => Self::$name,)*
+                    other => Self::Other(other),
+                }
+            }
+
+            pub fn code(&self) -> i64 {
+                match self {
+                    $(Self::$name => $code,)*
+                    Self::Other(code) => *code,
+                }
+            }
+        }
+    };
+}
+
+cln_error_codes! {
+    InvalidRequest = -32600,
+    MethodNotFound = -32601,
+    InvalidParams = -32602,
+    /// A plugin failed to handle the call.
+    PluginError = -3,
+    /// The plugin handling the call died.
+    PluginTerminated = -4,
+    PayInProgress = 200,
+    PayRhashAlreadyUsed = 201,
+    PayUnparseableOnion = 202,
+    PayDestinationPermFail = 203,
+    PayTryOtherRoute = 204,
+    PayRouteNotFound = 205,
+    PayRouteTooExpensive = 206,
+    PayInvoiceExpired = 207,
+    PayNoSuchPayment = 208,
+    PayUnspecifiedError = 209,
+    PayStoppedRetrying = 210,
+    PayStatusUnexpected = 211,
+    PayInvoiceRequestInvalid = 212,
+    PayInvoicePreimageInvalid = 213,
+    FundMaxExceeded = 300,
+    FundCannotAfford = 301,
+    FundOutputIsDust = 302,
+    ConnectNoKnownAddress = 400,
+    ConnectAllAddressesFailed = 401,
+    InvoiceLabelAlreadyExists = 900,
+    InvoicePreimageAlreadyExists = 901,
+    InvoiceHintsGaveNoRoutes = 902,
+    InvoiceExpiredDuringWait = 903,
+    InvoiceWaitTimedOut = 904,
+    /// The rune isn't one of the node's: a bad signature, an unknown or blacklisted id.
+    RuneNotAuthorized = 1501,
+    /// The node's rune, but its restrictions don't allow the call.
+    RuneNotPermitted = 1502,
+    /// Commando's "rune check failed", for either of the above.
+    RuneCheckFailed = 19537,
 }
 
 impl fmt::Display for Error {
@@ -42,6 +424,85 @@ impl fmt::Display for Error {
             Error::Json => write!(f, "json error"),
             Error::AddrParse(err) => write!(f, "Address parse error: {err}"),
             Error::Rpc(err) => write!(f, "commando rpc error: {err:?}"),
+            Error::FeatureNotNegotiated(type_id) => write!(
+                f,
+                "peer did not negotiate support for even message type {type_id}"
+            ),
+            Error::DialDenied(reason) => write!(f, "dial denied by policy: {reason}"),
+            Error::InvalidAddress(err) => write!(f, "invalid address: {err}"),
+            Error::OnionRequiresProxy(addr) => write!(
+                f,
+                "{addr} is a Tor onion address, connecting to it requires a Tor proxy"
+            ),
+            Error::PumpExited(exit) => write!(f, "commando client stopped: {exit}"),
+            Error::PumpTerminated(term) => write!(
+                f,
+                "commando connection lost ({}), {} calls aborted",
+                term.cause, term.aborted_calls
+            ),
+            Error::Overloaded => write!(f, "commando client overloaded"),
+            Error::InvalidInvoice(err) => write!(f, "invalid invoice: {err}"),
+            Error::Connect {
+                stage,
+                timings,
+                error,
+            } => write!(f, "connect failed during {stage} ({timings}): {error}"),
+            Error::NetworkMismatch { ours, theirs } => {
+                let chains = |f: &mut fmt::Formatter<'_>, chains: &[ChainHash]| {
+                    for (i, chain) in chains.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}", ChainName(chain))?;
+                    }
+                    Ok(())
+                };
+                write!(f, "peer is on ")?;
+                chains(f, theirs)?;
+                write!(f, ", we are on ")?;
+                chains(f, ours)
+            }
+            // the peer's text is escaped, it may contain anything
+            Error::PeerClosedConnection { message } => {
+                write!(f, "peer closed the connection: {message:?}")
+            }
+            Error::Proxy(err) => write!(f, "{err}"),
+            Error::Tls(err) => write!(f, "TLS tunnel failed: {err}"),
+            Error::Ssh(err) => write!(f, "SSH jump host failed: {err}"),
+            Error::PeerKeyMismatch { expected } => write!(
+                f,
+                "handshake failed: the node doesn't seem to be {expected}, check that the \
+                 node id is current"
+            ),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::Validation(err) => write!(f, "validation failed: {err}"),
+            Error::Lagged(skipped) => write!(f, "fell behind, missed {skipped} messages"),
+            Error::ForbiddenAddress { addr, ip } => {
+                write!(f, "{addr} resolves to {ip}, which is not a public address")
+            }
+            Error::RequestIdInUse(id) => write!(f, "request id {id} is already in flight"),
+            Error::InvalidSignature => write!(f, "invalid message signature"),
+            Error::QuotaExceeded {
+                direction,
+                resets_in,
+            } => {
+                let way = match direction {
+                    Direction::Inbound => "inbound",
+                    Direction::Outbound => "outbound",
+                };
+                write!(f, "{way} quota used up, resets in {resets_in:?}")
+            }
+            Error::BadMac => write!(f, "message from the peer failed authentication"),
+            Error::MessageTooLarge { len, max } => {
+                write!(
+                    f,
+                    "peer declared a {len} byte message, over the limit of {max}"
+                )
+            }
+            Error::MissingConnectOption(option) => write!(f, "no {option} to connect with"),
+            Error::PaymentFailed { reason, message } => {
+                write!(f, "payment failed ({}): {message}", reason.code())
+            }
         }
     }
 }
@@ -70,8 +531,76 @@ impl From<LightningError> for Error {
     }
 }
 
+impl From<NoiseError> for Error {
+    fn from(err: NoiseError) -> Self {
+        Self::Lightning(err.into())
+    }
+}
+
+impl From<SocketAddressParseError> for Error {
+    fn from(err: SocketAddressParseError) -> Self {
+        Self::InvalidAddress(err)
+    }
+}
+
 impl From<AddrParseError> for Error {
     fn from(err: AddrParseError) -> Self {
         Self::AddrParse(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(code: i64) -> RpcError {
+        RpcError {
+            code,
+            message: String::new(),
+            data: None,
+        }
+    }
+
+    #[test]
+    fn cln_error_codes_round_trip() {
+        assert_eq!(rpc(-32601).kind(), CLNErrorCode::MethodNotFound);
+        assert_eq!(CLNErrorCode::PayRouteNotFound.code(), 205);
+        assert_eq!(CLNErrorCode::from_code(12345), CLNErrorCode::Other(12345));
+        assert_eq!(CLNErrorCode::Other(12345).code(), 12345);
+
+        assert!(rpc(19537).is_rune_denied());
+        let expired = RpcError {
+            code: 19537,
+            message: "Not authorized: Not permitted: time is greater or equal to 1700000000"
+                .to_string(),
+            data: None,
+        };
+        assert!(expired.is_rune_expired());
+        assert!(!rpc(19537).is_rune_expired());
+        assert!(rpc(1501).is_rune_denied() && rpc(1502).is_rune_denied());
+        assert!(rpc(-32601).is_method_not_found());
+        assert!(rpc(205).is_payment_failure());
+        assert!(rpc(211).is_payment_failure());
+        assert!(rpc(214).is_payment_failure());
+        assert!(!rpc(215).is_payment_failure());
+        assert!(!rpc(200).is_payment_failure());
+        assert!(!rpc(-32602).is_payment_failure());
+    }
+
+    #[test]
+    fn payment_errors_carry_their_onion_failure() {
+        let err: RpcError = serde_json::from_value(serde_json::json!({
+            "code": 204,
+            "message": "failed: WIRE_TEMPORARY_CHANNEL_FAILURE",
+            "data": {"failcode": 4103, "raw_message": "10070000"}
+        }))
+        .unwrap();
+        assert_eq!(
+            err.onion_failure(),
+            Some(OnionFailure::TemporaryChannelFailure {
+                channel_update: None
+            })
+        );
+        assert_eq!(rpc(204).onion_failure(), None);
+    }
+}
@@ -0,0 +1,117 @@
+//! Out-of-band events about a connection.
+//!
+//! Some things a peer does are worth knowing about without every read loop having to look
+//! for them. [`LNSocket::events`](crate::LNSocket::events) returns an [`EventStream`] that
+//! sees them as they are read, independently of what the reader does with the messages.
+//!
+//! Failed reads and writes are reported here too, with a [`FailureCause`] telling a device
+//! that lost its network apart from a node that went away, so a mobile app can show "offline"
+//! and wait for connectivity instead of hammering the node with reconnects. A write that has
+//! been stuck for a while shows up as [`SocketEvent::WriteStalled`] before it fails, see
+//! [`LNSocket::set_write_stall_threshold`](crate::LNSocket::set_write_stall_threshold).
+//! Reads have no such event: a quiet peer is normal, ping it to find out whether it is there.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::Error;
+use crate::ln::msgs;
+
+/// How many events are buffered per stream before slow readers start dropping them.
+pub(crate) const EVENT_BUFFER: usize = 64;
+
+/// Something that happened on a connection.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum SocketEvent {
+    /// The peer sent a BOLT 1 `warning`. Its `data` is peer-controlled: sanitize it before
+    /// displaying or logging it.
+    Warning(msgs::WarningMessage),
+    /// Reading from the connection failed.
+    ReadFailed(FailureCause),
+    /// Writing to the connection failed; the messages in that write are lost.
+    WriteFailed(FailureCause),
+    /// A write has been waiting for `waited` without the peer taking the bytes. It may still
+    /// go through; a `WriteFailed` follows if it doesn't.
+    WriteStalled { waited: Duration },
+}
+
+/// The likely reason a connection failed, from what the OS reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FailureCause {
+    /// We have no route to the peer: the network is down or the interface went away. On a
+    /// phone this is usually the device losing Wi-Fi or cellular rather than the node.
+    NetworkUnreachable,
+    /// The peer, or a NAT or proxy on the way, reset or closed the connection. The node went
+    /// down or restarted, or the path to it changed.
+    Reset,
+    /// The network stopped answering in time.
+    Timeout,
+    /// Anything else.
+    Other,
+}
+
+impl FailureCause {
+    pub fn of(kind: io::ErrorKind) -> Self {
+        use io::ErrorKind::*;
+        match kind {
+            NetworkUnreachable | HostUnreachable | NetworkDown | AddrNotAvailable => {
+                Self::NetworkUnreachable
+            }
+            ConnectionReset | ConnectionAborted | ConnectionRefused | BrokenPipe
+            | UnexpectedEof | WriteZero => Self::Reset,
+            TimedOut => Self::Timeout,
+            _ => Self::Other,
+        }
+    }
+
+    /// Classify an error returned by the socket, e.g. from a connect. `None` for errors that
+    /// aren't about the connection, like a peer sending garbage.
+    pub fn of_error(err: &Error) -> Option<Self> {
+        match err {
+            Error::Io(kind) => Some(Self::of(*kind)),
+            _ => None,
+        }
+    }
+}
+
+/// Events of one connection, see the [module docs](self).
+///
+/// Every stream sees every event emitted after it was created; a stream that falls far
+/// behind skips the oldest ones.
+pub struct EventStream {
+    rx: broadcast::Receiver<SocketEvent>,
+}
+
+impl EventStream {
+    pub(crate) fn new(rx: broadcast::Receiver<SocketEvent>) -> Self {
+        Self { rx }
+    }
+
+    /// Wait for the next event. Returns `None` once the socket is dropped.
+    pub async fn next(&mut self) -> Option<SocketEvent> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("event stream lagged, skipped {skipped} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The next event if one is ready, without waiting.
+    pub fn try_next(&mut self) -> Option<SocketEvent> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Many readers for one connection.
+//!
+//! An [`LNSocket`] has a single reader: whoever calls `read` gets the message and nobody else
+//! sees it. [`LNSocket::into_fanout`] hands reading over to a task that decodes every message
+//! and broadcasts it, so that a commando client, a gossip recorder and a ping responder can
+//! each watch all the traffic. Writing goes through the [`MessageSender`] as before.
+//!
+//! Messages this crate doesn't decode arrive as [`Message::Custom`] with a [`RawMessage`]
+//! holding their type and payload. A subscriber that falls behind by more than the channel's
+//! capacity loses the oldest messages; a [`FanoutTransport`] reading for it fails with
+//! `Error::Lagged` rather than hand its client a reply with chunks missing. The channel closes
+//! when the connection does, and the task stops reading once every receiver is gone.
+//!
+//! [`FanoutTransport`] runs a [`CommandoClient`](crate::CommandoClient) on one subscription:
+//!
+//! ```no_run
+//! use lnsocket::fanout::FanoutTransport;
+//! use lnsocket::{CommandoClient, LNSocket};
+//!
+//! # fn example(sock: LNSocket) {
+//! let (their_pubkey, our_node_id) = (sock.their_pubkey(), sock.our_node_id());
+//! let (sender, messages) = sock.into_fanout(1024);
+//!
+//! let gossip = messages.resubscribe();
+//! let transport = FanoutTransport::new(sender, messages, their_pubkey, our_node_id);
+//! let commando = CommandoClient::spawn(transport, "rune");
+//! # }
+//! ```
+//!
+//! A client on a subscription can't reconnect: the connection belongs to the read task.
+
+use std::io::{self, Read};
+
+use bitcoin::secp256k1::PublicKey;
+use tokio::sync::broadcast;
+
+use crate::Error;
+use crate::LNSocket;
+use crate::commando::MessageTransport;
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::{self, Message, Type};
+use crate::sender::{Frame, MessageSender};
+use crate::util::ser::{Writeable, Writer};
+
+/// A message of a type this crate doesn't decode, as read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawMessage {
+    pub type_id: u16,
+    pub payload: Vec<u8>,
+}
+
+impl RawMessage {
+    fn read(type_id: u16, r: &mut io::Cursor<&[u8]>) -> Result<Self, DecodeError> {
+        let mut payload = Vec::new();
+        r.read_to_end(&mut payload)?;
+        Ok(Self { type_id, payload })
+    }
+}
+
+impl Type for RawMessage {
+    fn type_id(&self) -> u16 {
+        self.type_id
+    }
+}
+
+impl Writeable for RawMessage {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        w.write_all(&self.payload)
+    }
+}
+
+/// What the read task broadcasts.
+pub type FanoutMessage = Message<RawMessage>;
+
+/// Read `sock` until it fails or nobody is listening, broadcasting every message on `tx`.
+pub(crate) async fn read_loop(mut sock: LNSocket, tx: broadcast::Sender<FanoutMessage>) {
+    loop {
+        let (type_id, payload) = match sock.read_raw().await {
+            Ok(msg) => msg,
+            Err(err) => {
+                tracing::debug!("fanout: read failed, closing: {err}");
+                return;
+            }
+        };
+        let mut cursor = io::Cursor::new(&payload[..]);
+        let msg = match wire::read_payload(&mut cursor, type_id, |type_id, r| {
+            RawMessage::read(type_id, r).map(Some)
+        }) {
+            Ok(msg) => msg,
+            Err(err) => {
+                tracing::warn!("fanout: dropping undecodable type {type_id}: {err:?}");
+                continue;
+            }
+        };
+        if tx.send(msg).is_err() {
+            tracing::debug!("fanout: no receivers left, closing");
+            return;
+        }
+    }
+}
+
+/// A [`MessageTransport`] over a subscription to [`LNSocket::into_fanout`], see the
+/// [module docs](self).
+pub struct FanoutTransport {
+    sender: MessageSender,
+    messages: broadcast::Receiver<FanoutMessage>,
+    their_pubkey: PublicKey,
+    our_node_id: PublicKey,
+}
+
+impl FanoutTransport {
+    /// Read from `messages` and write with `sender`, for the connection between `our_node_id`
+    /// and `their_pubkey`.
+    pub fn new(
+        sender: MessageSender,
+        messages: broadcast::Receiver<FanoutMessage>,
+        their_pubkey: PublicKey,
+        our_node_id: PublicKey,
+    ) -> Self {
+        Self {
+            sender,
+            messages,
+            their_pubkey,
+            our_node_id,
+        }
+    }
+}
+
+impl MessageTransport for FanoutTransport {
+    async fn read_frame(&mut self) -> Result<(u16, Vec<u8>), Error> {
+        match self.messages.recv().await {
+            Ok(Message::Custom(raw)) => Ok((raw.type_id, raw.payload)),
+            Ok(msg) => {
+                let frame = Frame::new(&msg)?;
+                Ok((frame.type_id(), frame.payload().to_vec()))
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                tracing::warn!("fanout transport lagged, skipped {skipped} messages");
+                Err(Error::Lagged(skipped))
+            }
+            Err(broadcast::error::RecvError::Closed) => {
+                Err(Error::Io(io::ErrorKind::UnexpectedEof))
+            }
+        }
+    }
+
+    async fn write_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        self.sender.send_frames(vec![frame]).await
+    }
+
+    async fn reconnect(&self) -> Result<Self, Error> {
+        Err(Error::NotConnected)
+    }
+
+    fn their_pubkey(&self) -> PublicKey {
+        self.their_pubkey
+    }
+
+    fn our_node_id(&self) -> PublicKey {
+        self.our_node_id
+    }
+
+    fn sender(&self) -> Option<MessageSender> {
+        Some(self.sender.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commando::{CommandoClient, CommandoConfig};
+    use crate::commando_protocol::{CommandoReplyChunk, IncomingCommandoMessage};
+    use crate::ln::msgs;
+    use crate::lnsocket::testing::*;
+
+    #[tokio::test]
+    async fn every_subscriber_sees_every_message() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let (their_pubkey, our_node_id) = (sock.their_pubkey(), sock.our_node_id());
+        let (sender, messages) = sock.into_fanout(16);
+        let mut watcher = messages.resubscribe();
+
+        let transport = FanoutTransport::new(sender, messages, their_pubkey, our_node_id);
+        let config = CommandoConfig::new().no_reconnect().random_ids(false);
+        let client = CommandoClient::spawn_with_config(transport, "rune", config);
+        let call = tokio::spawn(async move { client.call("getinfo", serde_json::json!({})).await });
+
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Unknown(crate::commando_protocol::COMMANDO_COMMAND)
+        ));
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 4,
+        };
+        peer_send(&mut server, &mut peer, &ping).await;
+        let reply = IncomingCommandoMessage::Done(CommandoReplyChunk {
+            req_id: 1,
+            chunk: br#"{"result":{"alias":"node"}}"#.to_vec(),
+        });
+        peer_send(&mut server, &mut peer, &reply).await;
+        assert_eq!(call.await.unwrap().unwrap()["alias"], "node");
+
+        assert!(matches!(
+            watcher.recv().await.unwrap(),
+            Message::Ping(msgs::Ping { byteslen: 4, .. })
+        ));
+        match watcher.recv().await.unwrap() {
+            Message::Custom(raw) => {
+                assert_eq!(raw.type_id, reply.type_id());
+                assert_eq!(&raw.payload[..8], &1u64.to_be_bytes());
+            }
+            other => panic!("{other:?}"),
+        }
+
+        drop((server, peer));
+        assert!(matches!(
+            watcher.recv().await,
+            Err(broadcast::error::RecvError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_are_told() {
+        let (sock, _server, _peer) = loopback_pair().await;
+        let (tx, messages) = broadcast::channel(1);
+        let mut transport = FanoutTransport::new(
+            sock.sender(),
+            messages,
+            sock.their_pubkey(),
+            sock.our_node_id(),
+        );
+        for byteslen in 0..3 {
+            let ping = msgs::Ping {
+                ponglen: 0,
+                byteslen,
+            };
+            tx.send(Message::Ping(ping)).unwrap();
+        }
+
+        assert!(matches!(
+            transport.read_frame().await,
+            Err(Error::Lagged(2))
+        ));
+        // what comes after is read as usual
+        let (type_id, _) = transport.read_frame().await.unwrap();
+        assert_eq!(type_id, 18, "a ping");
+    }
+}
@@ -0,0 +1,111 @@
+//! Formatting helpers for logs: bytes and node ids as hex, without allocating a `String`.
+//!
+//! ```
+//! use bitcoin::secp256k1::PublicKey;
+//! use lnsocket::fmt::{Hex, ShortHex, ShortPubKey};
+//!
+//! let node_id: PublicKey =
+//!     "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".parse().unwrap();
+//!
+//! assert_eq!(Hex(&[0xde, 0xad]).to_string(), "dead");
+//! assert_eq!(ShortHex(&[0xab; 32]).to_string(), "abababab…abababab");
+//! assert_eq!(ShortPubKey(&node_id).to_string(), "0279be66…16f81798");
+//! ```
+//!
+//! For hex-encoded fields in serde types see [`rpc::hex`](crate::rpc::hex).
+
+use core::fmt;
+
+use bitcoin::secp256k1::PublicKey;
+
+/// Bytes shown at each end by [`ShortHex`] and [`ShortPubKey`].
+const SHORT_BYTES: usize = 4;
+
+/// `bytes` as lowercase hex, the whole of it.
+#[derive(Clone, Copy)]
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        let mut buf = [0u8; 128];
+        for chunk in self.0.chunks(buf.len() / 2) {
+            for (i, byte) in chunk.iter().enumerate() {
+                buf[2 * i] = DIGITS[(byte >> 4) as usize];
+                buf[2 * i + 1] = DIGITS[(byte & 0xf) as usize];
+            }
+            let digits = core::str::from_utf8(&buf[..2 * chunk.len()]).expect("hex digits");
+            f.write_str(digits)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// `bytes` as hex, with the middle elided when there are more than 8 bytes:
+/// `abababab…abababab`. For hashes and ids, where the ends tell them apart in a log.
+#[derive(Clone, Copy)]
+pub struct ShortHex<'a>(pub &'a [u8]);
+
+impl fmt::Display for ShortHex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0;
+        if bytes.len() <= 2 * SHORT_BYTES {
+            return Hex(bytes).fmt(f);
+        }
+        Hex(&bytes[..SHORT_BYTES]).fmt(f)?;
+        f.write_str("…")?;
+        Hex(&bytes[bytes.len() - SHORT_BYTES..]).fmt(f)
+    }
+}
+
+impl fmt::Debug for ShortHex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A node id as [`ShortHex`] of its compressed form, e.g. `0279be66…16f81798`.
+#[derive(Clone, Copy)]
+pub struct ShortPubKey<'a>(pub &'a PublicKey);
+
+impl fmt::Display for ShortPubKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        ShortHex(&self.0.serialize()).fmt(f)
+    }
+}
+
+impl fmt::Debug for ShortPubKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_matches_the_hex_crate() {
+        let bytes: Vec<u8> = (0..=255).chain(0..=255).collect();
+        for len in [0, 1, 63, 64, 65, bytes.len()] {
+            assert_eq!(Hex(&bytes[..len]).to_string(), hex::encode(&bytes[..len]));
+        }
+        assert_eq!(format!("{:?}", Hex(&[1, 2])), "0102");
+    }
+
+    #[test]
+    fn short_hex_elides_only_long_input() {
+        assert_eq!(ShortHex(&[]).to_string(), "");
+        assert_eq!(ShortHex(&[0xab; 8]).to_string(), "abababababababab");
+        let mut bytes = [0u8; 9];
+        bytes[0] = 0x10;
+        bytes[8] = 0x99;
+        assert_eq!(ShortHex(&bytes).to_string(), "10000000…00000099");
+    }
+}
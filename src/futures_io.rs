@@ -0,0 +1,161 @@
+//! BOLT 8 connections over any `futures::io` stream.
+//!
+//! [`LNStream`] is the runtime-agnostic counterpart of [`LNSocket`](crate::LNSocket) for
+//! async-std, smol, or anything else implementing `futures::io::{AsyncRead, AsyncWrite}`.
+//! You connect the stream yourself; [`LNStream::connect`] does the handshake on top.
+//!
+//! It is deliberately plain: no writer task, no stats, and no commando client. Reads and
+//! writes borrow the stream mutably, so split it yourself if you need both at once.
+
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io::Cursor;
+
+use crate::Error;
+use crate::ln::msgs::{self, DecodeError};
+use crate::ln::wire::{self, CustomMessageReader, Message};
+use crate::privacy::PaddingPolicy;
+use crate::transport::{ACT_TWO_SIZE, Handshake, LENGTH_HEADER_SIZE, Transport};
+use crate::util::ser::Writeable;
+
+/// An encrypted Lightning connection over a `futures::io` stream.
+pub struct LNStream<S> {
+    stream: S,
+    transport: Transport,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> LNStream<S> {
+    /// Perform the BOLT 8 handshake as initiator over an already connected stream.
+    ///
+    /// Does **not** exchange `init`, see [`LNStream::perform_init`].
+    pub async fn connect(
+        mut stream: S,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+    ) -> Result<Self, Error> {
+        let (handshake, act_one) = Handshake::new(our_key, their_pubkey);
+        stream.write_all(&act_one).await?;
+
+        let mut act_two = [0u8; ACT_TWO_SIZE];
+        stream.read_exact(&mut act_two).await?;
+        let (transport, act_three) = handshake.process_act_two(&act_two)?;
+
+        stream.write_all(&act_three).await?;
+        stream.flush().await?;
+
+        Ok(Self { stream, transport })
+    }
+
+    /// Wait for the peer's `init` and answer it, like
+    /// [`LNSocket::perform_init`](crate::LNSocket::perform_init). Returns the peer's `init`.
+    pub async fn perform_init(&mut self) -> Result<msgs::Init, Error> {
+        let Message::Init(their_init) = self.read().await? else {
+            return Err(Error::FirstMessageNotInit);
+        };
+        self.write(&crate::transport::init_reply(&their_init))
+            .await?;
+        Ok(their_init)
+    }
+
+    /// Pad every write from now on as `policy` says, see [`Transport::set_padding`]. Call it
+    /// after [`LNStream::perform_init`].
+    pub fn set_padding(&mut self, policy: PaddingPolicy) {
+        self.transport.set_padding(policy);
+    }
+
+    /// Refuse messages longer than `len` bytes, see [`Transport::set_max_message_len`].
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.transport.set_max_message_len(len);
+    }
+
+    /// Encrypt and write a message.
+    pub async fn write<M: wire::Type + Writeable>(&mut self, msg: &M) -> Result<(), Error> {
+        let frame = self.transport.encrypt_message(msg);
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    pub async fn read(&mut self) -> Result<Message<()>, Error> {
+        self.read_custom(|_type, _buf| Ok(None)).await
+    }
+
+    /// Read the next message, handing types this crate doesn't decode to `handler` with the
+    /// message body. The body is a [`LengthLimitedRead`](crate::ser::LengthLimitedRead) whose
+    /// [`remaining_bytes`](crate::ser::LengthLimitedRead::remaining_bytes) is what's left of it.
+    pub async fn read_custom<T>(
+        &mut self,
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Message<T>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        let (type_id, payload) = self.read_raw().await?;
+        let mut cursor = Cursor::new(&payload[..]);
+        Ok(wire::read_payload(&mut cursor, type_id, handler)?)
+    }
+
+    /// Read the next message, decoding custom types with `reader`.
+    pub async fn read_with<C: CustomMessageReader>(
+        &mut self,
+        reader: &C,
+    ) -> Result<Message<C::CustomMessage>, Error> {
+        self.read_custom(|type_id, r| reader.read(type_id, r)).await
+    }
+
+    /// Read and decrypt the next message, returning its type id and payload.
+    pub async fn read_raw(&mut self) -> Result<(u16, Vec<u8>), Error> {
+        let mut hdr = [0u8; LENGTH_HEADER_SIZE];
+        self.stream.read_exact(&mut hdr).await?;
+        let len = self.transport.decrypt_length_header(&hdr)?;
+
+        let mut body = vec![0u8; len];
+        self.stream.read_exact(&mut body).await?;
+        self.transport.decrypt_message(body)
+    }
+
+    /// Give back the underlying stream. The session cannot be resumed afterwards.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::testing::transport;
+    use futures_util::FutureExt;
+    use futures_util::io::Cursor;
+
+    #[test]
+    fn messages_roundtrip_over_futures_io() {
+        let mut writer = LNStream {
+            stream: Cursor::new(Vec::new()),
+            transport: transport(1, 2),
+        };
+        let ping = msgs::Ping {
+            ponglen: 4,
+            byteslen: 8,
+        };
+        // the in-memory cursor never blocks, so the futures complete immediately
+        writer.write(&ping).now_or_never().unwrap().unwrap();
+        writer
+            .write(&msgs::Pong { byteslen: 3 })
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        let mut reader = LNStream {
+            stream: Cursor::new(writer.into_inner().into_inner()),
+            transport: transport(2, 1),
+        };
+        match reader.read().now_or_never().unwrap().unwrap() {
+            Message::Ping(got) => assert_eq!(got, ping),
+            other => panic!("unexpected message {other:?}"),
+        }
+        assert!(matches!(
+            reader.read().now_or_never().unwrap().unwrap(),
+            Message::Pong(msgs::Pong { byteslen: 3 })
+        ));
+    }
+}
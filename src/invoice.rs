@@ -0,0 +1,170 @@
+//! BOLT 11 invoice decoding, behind the `invoice` cargo feature.
+//!
+//! Commando replies are full of invoices (`invoice`, `listinvoices`, `listpays`, ...).
+//! [`Invoice`] decodes and verifies them with the [`lightning_invoice`] version this crate is
+//! built against, so its `bitcoin` and `secp256k1` types line up with ours:
+//!
+//! ```no_run
+//! use lnsocket::invoice::Invoice;
+//! # async fn ex(client: lnsocket::CommandoClient) -> Result<(), lnsocket::Error> {
+//! let reply = client
+//!     .call("invoice", serde_json::json!({"amount_msat": 1000, "label": "x", "description": "coffee"}))
+//!     .await?;
+//! let invoice = Invoice::parse(reply["bolt11"].as_str().unwrap_or_default())?;
+//! println!("{:?} msat, expires in {:?}", invoice.amount_msat(), invoice.expiry());
+//! # Ok(()) }
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bitcoin::Network;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+pub use lightning_invoice;
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescriptionRef, ParseOrSemanticError};
+
+use crate::Error;
+
+/// A decoded BOLT 11 invoice whose signature has been checked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invoice(Bolt11Invoice);
+
+impl Invoice {
+    /// Decode `s` and verify its signature. Surrounding whitespace and a `lightning:` prefix
+    /// are accepted.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let s = s.trim();
+        let s = s
+            .strip_prefix("lightning:")
+            .or_else(|| s.strip_prefix("LIGHTNING:"))
+            .unwrap_or(s);
+        Ok(Self(Bolt11Invoice::from_str(s)?))
+    }
+
+    /// The requested amount, or `None` for "any amount" invoices.
+    pub fn amount_msat(&self) -> Option<u64> {
+        self.0.amount_milli_satoshis()
+    }
+
+    pub fn payment_hash(&self) -> [u8; 32] {
+        self.0.payment_hash().to_byte_array()
+    }
+
+    pub fn payment_secret(&self) -> [u8; 32] {
+        self.0.payment_secret().0
+    }
+
+    /// The node to pay, from the explicit payee field or recovered from the signature.
+    pub fn payee(&self) -> PublicKey {
+        self.0.get_payee_pub_key()
+    }
+
+    pub fn network(&self) -> Network {
+        self.0.network()
+    }
+
+    /// The description, or `None` if the invoice only commits to a description hash.
+    /// Peer-controlled text: sanitize before display.
+    pub fn description(&self) -> Option<String> {
+        match self.0.description() {
+            Bolt11InvoiceDescriptionRef::Direct(description) => Some(description.to_string()),
+            Bolt11InvoiceDescriptionRef::Hash(_) => None,
+        }
+    }
+
+    /// When the invoice was created, as time since the unix epoch.
+    pub fn created_at(&self) -> Duration {
+        self.0.duration_since_epoch()
+    }
+
+    /// How long after creation the invoice stays payable (one hour unless it says otherwise).
+    pub fn expiry(&self) -> Duration {
+        self.0.expiry_time()
+    }
+
+    /// When the invoice expires, as time since the unix epoch.
+    pub fn expires_at(&self) -> Duration {
+        self.created_at().saturating_add(self.expiry())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.0.is_expired()
+    }
+
+    pub fn min_final_cltv_expiry_delta(&self) -> u64 {
+        self.0.min_final_cltv_expiry_delta()
+    }
+
+    /// The full [`lightning_invoice`] type, for everything not covered here (route hints,
+    /// fallback addresses, features, ...).
+    pub fn inner(&self) -> &Bolt11Invoice {
+        &self.0
+    }
+}
+
+impl FromStr for Invoice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for Invoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<ParseOrSemanticError> for Error {
+    fn from(err: ParseOrSemanticError) -> Self {
+        Self::InvalidInvoice(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The "any amount" donation invoice from the BOLT 11 test vectors.
+    const DONATION: &str = "lnbc1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6twvus8g6rfwvs8qun0dfjkxaq9qrsgq357wnc5r2ueh7ck6q93dj32dlqnls087fxdwk8qakdyafkq3yap9us6v52vjjsrvywa6rt52cm9r9zqt8r2t7mlcwspyetp5h2tztugp9lfyql";
+
+    #[test]
+    fn decodes_spec_vector() {
+        let invoice = Invoice::parse(&format!(" lightning:{DONATION}\n")).unwrap();
+
+        assert_eq!(invoice.amount_msat(), None);
+        assert_eq!(invoice.network(), Network::Bitcoin);
+        assert_eq!(
+            hex::encode(invoice.payment_hash()),
+            "0001020304050607080900010203040506070809000102030405060708090102"
+        );
+        assert_eq!(
+            invoice.payee().to_string(),
+            "03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad"
+        );
+        assert_eq!(
+            invoice.description().as_deref(),
+            Some("Please consider supporting this project")
+        );
+        assert_eq!(invoice.created_at(), Duration::from_secs(1496314658));
+        assert_eq!(invoice.expiry(), Duration::from_secs(3600));
+        assert_eq!(invoice.expires_at(), Duration::from_secs(1496314658 + 3600));
+        assert!(invoice.is_expired());
+        assert_eq!(invoice.to_string(), DONATION);
+    }
+
+    #[test]
+    fn rejects_tampered_invoices() {
+        // flip a character in the data part: the checksum no longer matches
+        let mut tampered = DONATION.to_string();
+        tampered.replace_range(20..21, "q");
+        assert!(matches!(
+            Invoice::parse(&tampered),
+            Err(Error::InvalidInvoice(_))
+        ));
+        assert!(Invoice::parse("not an invoice").is_err());
+    }
+}
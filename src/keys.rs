@@ -0,0 +1,139 @@
+//! A stable node key per app.
+//!
+//! CLN runes can be restricted to the node id they were handed to (`id=...`), and peers
+//! remember who they talked to, so most apps want to connect with the same key every time
+//! instead of a fresh random one. [`load_or_generate`] keeps that key in a file:
+//!
+//! ```no_run
+//! # fn ex() -> Result<(), lnsocket::Error> {
+//! let key = lnsocket::keys::load_or_generate("lnsocket.key")?;
+//! # Ok(()) }
+//! ```
+//!
+//! The file holds the secret key as 64 hex characters. On unix it is created readable by
+//! its owner only.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use bitcoin::secp256k1::{SecretKey, rand};
+
+use crate::Error;
+
+/// Read the secret key stored at `path`, or generate one and store it there if the file
+/// doesn't exist yet. Fails with `Error::Io(InvalidData)` if the file exists but doesn't hold
+/// a key, rather than replacing it.
+///
+/// Safe against concurrent first runs: the key is written to a temporary file next to `path`
+/// and hard-linked into place once on disk, so `path` never holds a partial key, and whoever
+/// loses the race reads the winner's.
+pub fn load_or_generate(path: impl AsRef<Path>) -> Result<SecretKey, Error> {
+    let path = path.as_ref();
+    match load(path) {
+        Err(Error::Io(io::ErrorKind::NotFound)) => {}
+        res => return res,
+    }
+
+    let key = SecretKey::new(&mut rand::thread_rng());
+    let name = path
+        .file_name()
+        .ok_or(Error::Io(io::ErrorKind::InvalidInput))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+    let tmp = path.with_file_name(tmp_name);
+
+    let linked = write_new(&tmp, &key).and_then(|()| fs::hard_link(&tmp, path));
+    let _ = fs::remove_file(&tmp);
+    match linked {
+        Ok(()) => Ok(key),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => load(path),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Create `path`, which mustn't exist, holding `key`, and flush it to disk.
+fn write_new(path: &Path, key: &SecretKey) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(hex::encode(key.secret_bytes()).as_bytes())?;
+    file.sync_all()
+}
+
+fn load(path: &Path) -> Result<SecretKey, Error> {
+    let contents = fs::read_to_string(path)?;
+    let bytes = hex::decode(contents.trim()).map_err(|_| Error::Io(io::ErrorKind::InvalidData))?;
+    let key = SecretKey::from_slice(&bytes).map_err(|_| Error::Io(io::ErrorKind::InvalidData))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            tracing::warn!(
+                "{} is accessible by other users (mode {:o})",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_once_then_loads() {
+        let dir = std::env::temp_dir().join(format!("lnsocket-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.key");
+        let _ = fs::remove_file(&path);
+
+        let key = load_or_generate(&path).unwrap();
+        assert_eq!(load_or_generate(&path).unwrap(), key);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // garbage is an error, not a reason to overwrite
+        fs::write(&path, "not a key").unwrap();
+        assert!(matches!(
+            load_or_generate(&path),
+            Err(Error::Io(io::ErrorKind::InvalidData))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a key");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_first_runs_agree() {
+        let dir = std::env::temp_dir().join(format!("lnsocket-keys-race-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.key");
+        let _ = fs::remove_file(&path);
+
+        let runs: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || load_or_generate(&path).unwrap())
+            })
+            .collect();
+        let keys: Vec<SecretKey> = runs.into_iter().map(|run| run.join().unwrap()).collect();
+        assert!(keys.iter().all(|key| *key == keys[0]));
+        // only the key is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,12 +2,52 @@
 //!
 //! `lnsocket` is a minimal, async Lightning Network socket library built on `tokio`.
 //! It implements the BOLT 8 Noise handshake and typed Lightning wire framing, and
-//! it stays out of your way: no global state, no TLS, no heavy deps.
+//! it stays out of your way: no global state, no heavy deps.
 //!
 //! ## What this crate gives you
 //! - **`LNSocket`** – connect over TCP, perform Noise (act1/2/3), and read/write typed BOLT#1 messages.
 //! - **`CommandoClient`** – a small client for Core Lightning **Commando** over a live `LNSocket`,
 //!   with a background pump, **auto-reconnect**, and **retry/resend** semantics.
+//! - **`lsps::LspsClient`** – JSON-RPC to Lightning Service Providers (LSPS0), with typed
+//!   LSPS1 and LSPS2 calls.
+//!
+//! ## Feature flags
+//! - **`tokio`** (default) – `LNSocket`, `CommandoClient` and the rest of the tokio-based API.
+//! - **`futures-io`** – `futures_io::LNStream`, the same handshake and framing over any
+//!   `futures::io` stream, for async-std, smol and friends.
+//! - **`compression`** – gzip commando payloads when the peer supports it (implies `tokio`).
+//! - **`invoice`** – `invoice::Invoice`, BOLT 11 decoding via `lightning-invoice`.
+//! - **`srv`** – `discovery::SrvDiscovery`, finding nodes and their ids through DNS SRV and
+//!   TXT records (implies `tokio`).
+//! - **`cancel`** – attach a `tokio_util` `CancellationToken` to connects, calls and
+//!   commando clients, see the `cancel` module (implies `tokio`).
+//! - **`wallet`** – `wallet::WalletClient`, balance, payments, invoices and history over
+//!   commando for wallet apps (implies `tokio`).
+//! - **`tls`** – `tls::TlsTunnel`, running connections inside TLS to a fronting proxy that
+//!   routes on SNI, via `rustls` (implies `tokio`).
+//! - **`jsonschema`** – `validation::JsonSchema`, checking commando params and results
+//!   against JSON schemas.
+//! - **`ssh`** – `ssh::SshJump`, dialing through an SSH bastion's `direct-tcpip` channels,
+//!   via `libssh2` (implies `tokio`, unix only).
+//!
+//! - **`std`** (default) – everything but the Noise handshake and BOLT 8 framing; implied by
+//!   all of the above.
+//! - **`test-utils`** – constructors meant for tests only, e.g. a handshake with a fixed
+//!   ephemeral key, and the BOLT 8 test vectors in `conformance`.
+//!
+//! With `default-features = false, features = ["std"]` only the runtime-agnostic core remains:
+//! the wire types in [`ln`], [`ser`], the sans-IO handshake in [`transport`] and commando
+//! framing in [`commando_protocol`].
+//!
+//! Without `std` the crate is `no_std` and needs only `alloc`, for embedded devices that speak
+//! Lightning over a serial line or a transport of their own. What's left is
+//! [`ln::peer_channel_encryptor::PeerChannelEncryptor`]: the initiator side of the handshake,
+//! with an ephemeral key the caller supplies, and the encryption of messages the caller encodes
+//! (type, then payload) with `encrypt_buffer`.
+//!
+//! `no_std` support is partial: there is no responder side of the handshake and no BOLT 1
+//! framing, so `init`, `ping` and every other message have to be encoded and decoded by the
+//! caller. Errors are [`ln::peer_channel_encryptor::NoiseError`], the same type as with `std`.
 //!
 //! ## Design philosophy
 //! - Keep the transport tight and explicit. You own key management, policies, and backpressure.
@@ -18,7 +58,9 @@
 //! ### Low-level: just a Lightning socket
 //! ```no_run
 //! use bitcoin::secp256k1::{SecretKey, PublicKey, rand};
+//! # #[cfg(feature = "tokio")]
 //! use lnsocket::{LNSocket, ln::msgs};
+//! # #[cfg(feature = "tokio")]
 //! # async fn demo(their_pubkey: PublicKey) -> Result<(), lnsocket::Error> {
 //! let our_key = SecretKey::new(&mut rand::thread_rng());
 //! let mut sock = LNSocket::connect_and_init(our_key, their_pubkey, "node.example.com:9735").await?;
@@ -30,8 +72,10 @@
 //! ### Higher-level: Commando over LNSocket
 //! ```no_run
 //! use bitcoin::secp256k1::{SecretKey, PublicKey, rand};
+//! # #[cfg(feature = "tokio")]
 //! use lnsocket::{LNSocket, CommandoClient};
 //! use serde_json::json;
+//! # #[cfg(feature = "tokio")]
 //! # async fn demo(their_pubkey: PublicKey, rune: &str) -> Result<(), lnsocket::Error> {
 //! let key = SecretKey::new(&mut rand::thread_rng());
 //! let sock = LNSocket::connect_and_init(key, their_pubkey, "ln.example.com:9735").await?;
@@ -46,39 +90,144 @@
 //! ```
 //!
 //! ## Footguns & non-goals
-//! - No built-in keepalives/backpressure – handle in your app.
+//! - No built-in keepalives/backpressure – handle in your app; [`congestion`] gives you a signal
+//!   to shed load on.
 //! - Reconnection logic lives in `CommandoClient`, **not** `LNSocket`.
 //! - `LNSocket::perform_init` performs a minimal `init` exchange by design.
 
+// the crate-internal helpers for the tokio socket (stats, send gating, ...) go unused without it
+#![cfg_attr(not(feature = "tokio"), allow(dead_code))]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "cancel")]
+pub mod cancel;
+#[cfg(feature = "std")]
+pub mod capabilities;
+#[cfg(feature = "std")]
+pub mod capture;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "tokio")]
 pub mod commando;
+#[cfg(feature = "std")]
+pub mod commando_protocol;
+#[cfg(feature = "tokio")]
+pub mod config;
+#[cfg(all(feature = "std", any(test, feature = "test-utils")))]
+pub mod conformance;
+#[cfg(feature = "tokio")]
+pub mod congestion;
+#[cfg(feature = "tokio")]
+pub mod connect;
 mod crypto;
+#[cfg(feature = "tokio")]
+pub mod dial;
+#[cfg(feature = "srv")]
+pub mod discovery;
+#[cfg(feature = "std")]
 pub mod error;
+#[cfg(feature = "tokio")]
+pub mod events;
+#[cfg(feature = "tokio")]
+pub mod fanout;
+#[cfg(feature = "std")]
+pub mod fmt;
+#[cfg(feature = "futures-io")]
+pub mod futures_io;
+#[cfg(feature = "invoice")]
+pub mod invoice;
+#[cfg(feature = "std")]
+pub mod keys;
 pub mod ln;
+#[cfg(feature = "tokio")]
 pub mod lnsocket;
-mod sign;
-mod socket_addr;
+#[cfg(feature = "tokio")]
+pub mod lsps;
+#[cfg(feature = "tokio")]
+pub mod mux;
+#[cfg(feature = "std")]
+pub mod network;
+#[cfg(feature = "std")]
+pub mod node_info;
+#[cfg(feature = "std")]
+pub mod notifications;
+#[cfg(feature = "std")]
+pub mod ping;
+#[cfg(feature = "tokio")]
+pub mod pool;
+#[cfg(feature = "std")]
+pub mod privacy;
+#[cfg(feature = "tokio")]
+pub mod registry;
+#[cfg(feature = "tokio")]
+pub mod reqresp;
+#[cfg(feature = "std")]
+pub mod rpc;
+#[cfg(feature = "tokio")]
+pub mod sender;
+#[cfg(feature = "std")]
+pub mod ser;
+#[cfg(feature = "tokio")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod sign;
+#[cfg(feature = "std")]
+pub mod socket_addr;
+#[cfg(feature = "tokio")]
+mod socks;
+#[cfg(all(feature = "ssh", unix))]
+pub mod ssh;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "tokio")]
+mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
 mod util;
+#[cfg(feature = "std")]
+pub mod validation;
+#[cfg(feature = "wallet")]
+pub mod wallet;
 
 pub use bitcoin;
+#[cfg(feature = "tokio")]
 pub use commando::{CallOpts, CommandoClient};
-pub use error::{Error, RpcError};
+#[cfg(feature = "tokio")]
+pub use config::LNSocketConfig;
+#[cfg(feature = "std")]
+pub use error::{
+    CLNErrorCode, ConnectStage, ConnectTimings, Error, PumpExit, PumpTermination, RpcError,
+    TerminationCause,
+};
+#[cfg(feature = "tokio")]
 pub use lnsocket::LNSocket;
+#[cfg(feature = "tokio")]
+pub use sender::MessageSender;
+#[cfg(feature = "std")]
+pub use stats::WireStats;
 
 mod prelude {
     #![allow(unused_imports)]
 
-    pub use std::{boxed::Box, collections::VecDeque, string::String, vec, vec::Vec};
+    pub use alloc::{boxed::Box, collections::VecDeque, string::String, vec, vec::Vec};
 
-    pub use std::borrow::ToOwned;
-    pub use std::string::ToString;
+    pub use alloc::borrow::ToOwned;
+    pub use alloc::string::ToString;
 
     pub use core::convert::{AsMut, AsRef, TryFrom, TryInto};
     pub use core::default::Default;
     pub use core::marker::Sized;
 
+    #[cfg(feature = "std")]
     pub(crate) use crate::util::hash_tables::*;
 }
 
+#[cfg(feature = "std")]
 #[doc(hidden)]
 /// IO utilities public only for use by in-crate macros. These should not be used externally
 ///
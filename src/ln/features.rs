@@ -0,0 +1,157 @@
+//! Helpers for [BOLT #9] feature bitfields as carried in `init`.
+//!
+//! Feature fields on the wire are big-endian byte vectors: bit 0 is the least significant bit
+//! of the *last* byte. Even bits mean "required", odd bits mean "optional".
+//!
+//! [BOLT #9]: https://github.com/lightning/bolts/blob/master/09-features.md
+
+use crate::Error;
+use crate::ln::msgs;
+use std::collections::HashMap;
+
+/// `option_onion_messages`, the optional bit of the pair.
+pub const ONION_MESSAGES_FEATURE_BIT: usize = 39;
+
+/// Returns whether `bit` is set in the big-endian feature vector.
+pub fn is_set(features: &[u8], bit: usize) -> bool {
+    let byte = bit / 8;
+    if byte >= features.len() {
+        return false;
+    }
+    features[features.len() - 1 - byte] & (1 << (bit % 8)) != 0
+}
+
+/// Sets `bit` in the big-endian feature vector, growing it on the left if needed.
+pub fn set(features: &mut Vec<u8>, bit: usize) {
+    let byte = bit / 8;
+    if byte >= features.len() {
+        let mut grown = vec![0; byte + 1 - features.len()];
+        grown.extend_from_slice(features);
+        *features = grown;
+    }
+    let idx = features.len() - 1 - byte;
+    features[idx] |= 1 << (bit % 8);
+}
+
+/// Returns whether the feature pair containing `bit` is advertised, either as required (even)
+/// or optional (odd).
+pub fn supports(features: &[u8], bit: usize) -> bool {
+    is_set(features, bit & !1) || is_set(features, bit | 1)
+}
+
+/// The `features` and `global_features` of `init` merged into one vector, as peers are meant to
+/// be read.
+pub fn of_init(init: &msgs::Init) -> Vec<u8> {
+    let mut features = init.features.clone();
+    for bit in 0..init.global_features.len() * 8 {
+        if is_set(&init.global_features, bit) {
+            set(&mut features, bit);
+        }
+    }
+    features
+}
+
+/// Decides which messages may be sent to a peer when strict feature checking is enabled.
+///
+/// Odd message types are always allowed ("it's ok to be odd"), as are the BOLT #1 messages.
+/// Any other even type is only sent once the peer's `init` advertised the feature it was
+/// registered with via [`SendGate::register`].
+#[derive(Debug, Default)]
+pub(crate) struct SendGate {
+    strict: bool,
+    /// `features | global_features` from the peer's `init`, once received.
+    peer_features: Option<Vec<u8>>,
+    message_features: HashMap<u16, usize>,
+}
+
+impl SendGate {
+    pub(crate) fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub(crate) fn set_peer_init(&mut self, init: &msgs::Init) {
+        self.peer_features = Some(of_init(init));
+    }
+
+    /// Whether the peer's `init` has been received, and with it ours sent.
+    pub(crate) fn init_done(&self) -> bool {
+        self.peer_features.is_some()
+    }
+
+    pub(crate) fn register(&mut self, type_id: u16, bit: usize) {
+        self.message_features.insert(type_id, bit);
+    }
+
+    pub(crate) fn check(&self, type_id: u16) -> Result<(), Error> {
+        if !self.strict || type_id & 1 == 1 || BOLT1_TYPES.contains(&type_id) {
+            return Ok(());
+        }
+        match (&self.peer_features, self.message_features.get(&type_id)) {
+            (Some(features), Some(bit)) if supports(features, *bit) => Ok(()),
+            _ => Err(Error::FeatureNotNegotiated(type_id)),
+        }
+    }
+}
+
+/// warning, init, error, ping, pong
+const BOLT1_TYPES: [u16; 5] = [1, 16, 17, 18, 19];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_check_bits() {
+        let mut features = vec![0; 2];
+        set(&mut features, 0);
+        set(&mut features, 9);
+        assert_eq!(features, vec![0b10, 0b1]);
+        assert!(is_set(&features, 0));
+        assert!(is_set(&features, 9));
+        assert!(!is_set(&features, 8));
+        assert!(!is_set(&features, 200));
+    }
+
+    #[test]
+    fn set_grows_vector() {
+        let mut features = vec![0xff];
+        set(&mut features, 17);
+        assert_eq!(features, vec![0b10, 0, 0xff]);
+    }
+
+    #[test]
+    fn supports_checks_both_bits_of_pair() {
+        let mut features = Vec::new();
+        set(&mut features, 259);
+        assert!(supports(&features, 258));
+        assert!(supports(&features, 259));
+        assert!(!supports(&features, 260));
+    }
+
+    #[test]
+    fn send_gate_only_checks_even_types_in_strict_mode() {
+        let mut gate = SendGate::default();
+        gate.register(0x8000, 200);
+        assert!(gate.check(0x8000).is_ok());
+
+        gate.set_strict(true);
+        assert!(gate.check(18).is_ok());
+        assert!(gate.check(0x8001).is_ok());
+        assert!(matches!(
+            gate.check(0x8000),
+            Err(Error::FeatureNotNegotiated(0x8000))
+        ));
+        assert!(gate.check(0x8002).is_err());
+
+        let mut features = Vec::new();
+        set(&mut features, 201);
+        gate.set_peer_init(&msgs::Init {
+            features,
+            global_features: vec![],
+            networks: None,
+            remote_network_address: None,
+        });
+        assert!(gate.check(0x8000).is_ok());
+        assert!(gate.check(0x8002).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Deduplication of [BOLT #7] gossip across peer connections.
+//!
+//! A gossip ingester connected to many peers receives the same `channel_update` once per
+//! peer, over and over. Feed every message through one shared [`GossipDedup`] and only pass
+//! on those it reports as new:
+//!
+//! ```
+//! use lnsocket::ln::gossip::GossipDedup;
+//! # fn handle(_: u16, _: &[u8]) {}
+//! # let messages: Vec<(u16, Vec<u8>)> = vec![];
+//! let mut dedup = GossipDedup::new(GossipDedup::DEFAULT_CAPACITY);
+//! for (type_id, payload) in messages {
+//!     if dedup.first_seen(type_id, &payload) {
+//!         handle(type_id, &payload);
+//!     }
+//! }
+//! ```
+//!
+//! Gossip messages are keyed by their signature, which commits to the whole message, so a
+//! newer `channel_update` for the same channel is never mistaken for a duplicate. Only the
+//! most recently seen [`capacity`](GossipDedup::new) keys are remembered.
+//!
+//! [BOLT #7]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md
+
+use std::collections::{HashMap, VecDeque};
+
+pub const CHANNEL_ANNOUNCEMENT: u16 = 256;
+pub const NODE_ANNOUNCEMENT: u16 = 257;
+pub const CHANNEL_UPDATE: u16 = 258;
+
+/// Every gossip message above starts with a 64-byte signature.
+const SIGNATURE_LEN: usize = 64;
+
+/// Key of a gossip message: its type and leading signature.
+type Key = (u16, [u8; SIGNATURE_LEN]);
+
+/// Remembers recently seen gossip, evicting the least recently seen once full.
+pub struct GossipDedup {
+    capacity: usize,
+    /// Each key with the tick it was last seen at.
+    seen: HashMap<Key, u64>,
+    /// Keys in the order they were seen; entries whose tick is older than in `seen` are stale.
+    order: VecDeque<(Key, u64)>,
+    tick: u64,
+}
+
+impl GossipDedup {
+    /// Enough to hold a full copy of today's public graph.
+    pub const DEFAULT_CAPACITY: usize = 200_000;
+
+    /// Remember up to `capacity` messages. A capacity of zero remembers nothing.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns whether this message should be passed on: `false` if it is gossip seen
+    /// recently, `true` otherwise. Non-gossip and malformed messages always pass.
+    pub fn first_seen(&mut self, type_id: u16, payload: &[u8]) -> bool {
+        let Some(key) = key(type_id, payload) else {
+            return true;
+        };
+        if self.capacity == 0 {
+            return true;
+        }
+
+        self.tick += 1;
+        let new = self.seen.insert(key, self.tick).is_none();
+        self.order.push_back((key, self.tick));
+        if new && self.seen.len() > self.capacity {
+            self.evict_one();
+        }
+        // repeated hits leave stale entries behind, don't let them pile up
+        if self.order.len() > 2 * self.capacity {
+            self.order
+                .retain(|(key, tick)| self.seen.get(key) == Some(tick));
+        }
+        new
+    }
+
+    /// How many messages are currently remembered.
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Forget everything.
+    pub fn clear(&mut self) {
+        self.seen.clear();
+        self.order.clear();
+    }
+
+    fn evict_one(&mut self) {
+        while let Some((key, tick)) = self.order.pop_front() {
+            if self.seen.get(&key) == Some(&tick) {
+                self.seen.remove(&key);
+                return;
+            }
+        }
+    }
+}
+
+fn key(type_id: u16, payload: &[u8]) -> Option<Key> {
+    match type_id {
+        CHANNEL_ANNOUNCEMENT | NODE_ANNOUNCEMENT | CHANNEL_UPDATE => {
+            Some((type_id, payload.get(..SIGNATURE_LEN)?.try_into().ok()?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(sig: u8) -> Vec<u8> {
+        let mut payload = vec![sig; SIGNATURE_LEN];
+        payload.extend_from_slice(&[0; 64]);
+        payload
+    }
+
+    #[test]
+    fn drops_repeats_but_not_new_updates() {
+        let mut dedup = GossipDedup::new(10);
+        assert!(dedup.first_seen(CHANNEL_UPDATE, &update(1)));
+        assert!(!dedup.first_seen(CHANNEL_UPDATE, &update(1)));
+        assert!(dedup.first_seen(CHANNEL_UPDATE, &update(2)));
+        // same signature bytes under another type is a different message
+        assert!(dedup.first_seen(NODE_ANNOUNCEMENT, &update(1)));
+
+        // non-gossip and truncated gossip always pass
+        assert!(dedup.first_seen(18, &update(1)));
+        assert!(dedup.first_seen(18, &update(1)));
+        assert!(dedup.first_seen(CHANNEL_UPDATE, &[1; 10]));
+        assert!(dedup.first_seen(CHANNEL_UPDATE, &[1; 10]));
+        assert_eq!(dedup.len(), 3);
+    }
+
+    #[test]
+    fn evicts_least_recently_seen() {
+        let mut dedup = GossipDedup::new(2);
+        assert!(dedup.first_seen(CHANNEL_UPDATE, &update(1)));
+        assert!(dedup.first_seen(CHANNEL_UPDATE, &update(2)));
+        // touch 1 so that 2 is the oldest
+        assert!(!dedup.first_seen(CHANNEL_UPDATE, &update(1)));
+        assert!(dedup.first_seen(CHANNEL_UPDATE, &update(3)));
+
+        assert_eq!(dedup.len(), 2);
+        assert!(!dedup.first_seen(CHANNEL_UPDATE, &update(1)));
+        assert!(dedup.first_seen(CHANNEL_UPDATE, &update(2)));
+
+        for _ in 0..100 {
+            dedup.first_seen(CHANNEL_UPDATE, &update(2));
+        }
+        assert!(dedup.order.len() <= 4);
+    }
+}
@@ -0,0 +1,496 @@
+//! The [BOLT #7] gossip queries: `query_short_channel_ids`, `reply_short_channel_ids_end`,
+//! `query_channel_range`, `reply_channel_range` and `gossip_timestamp_filter`.
+//!
+//! Like the [interactive tx](crate::ln::interactive_tx) messages these are not part of
+//! [`Message`](crate::ln::wire::Message). Send queries with
+//! [`LNSocket::query_channel_range`](crate::LNSocket::query_channel_range) and
+//! [`LNSocket::gossip_timestamp_filter`](crate::LNSocket::gossip_timestamp_filter), decode
+//! the replies with [`GossipQueryMessage::read`], and let a [`ChannelRangeCollector`] put a
+//! reply that spans several messages back together:
+//!
+//! ```no_run
+//! use lnsocket::ln::gossip_queries::{ChannelRangeCollector, GossipQueryMessage};
+//! use lnsocket::ln::wire::Message;
+//! # #[cfg(feature = "tokio")]
+//! # async fn ex(mut sock: lnsocket::LNSocket) -> Result<(), lnsocket::Error> {
+//! let query = sock.query_channel_range(800_000, 10_000).await?;
+//! let mut replies = ChannelRangeCollector::new(&query);
+//! while !replies.is_complete() {
+//!     let msg = sock.read_custom(|t, r| GossipQueryMessage::read(t, r)).await?;
+//!     if let Message::Custom(GossipQueryMessage::ReplyChannelRange(reply)) = msg {
+//!         if let Err(err) = replies.add(reply) {
+//!             eprintln!("{err}");
+//!             break;
+//!         }
+//!     }
+//! }
+//! println!("{} channels", replies.short_channel_ids().len());
+//! # Ok(()) }
+//! ```
+//!
+//! Only the uncompressed encoding of short channel ids is supported; zlib was removed from
+//! the spec. Optional TLVs after the fixed fields are skipped.
+//!
+//! Clients that only do RPC have no gossip to give, but some peers hang up on queries left
+//! unanswered. [`LNSocket::set_answer_gossip_queries`](crate::LNSocket::set_answer_gossip_queries)
+//! answers them with the [empty replies](QueryChannelRange::empty_reply) of a node that keeps
+//! no channel information.
+//!
+//! [BOLT #7]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md
+
+use std::fmt;
+use std::io::{self, Read};
+
+use bitcoin::constants::ChainHash;
+
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::Encode;
+use crate::util::ser::{Readable, Writeable, Writer};
+
+pub const QUERY_SHORT_CHANNEL_IDS: u16 = 261;
+pub const REPLY_SHORT_CHANNEL_IDS_END: u16 = 262;
+pub const QUERY_CHANNEL_RANGE: u16 = 263;
+pub const REPLY_CHANNEL_RANGE: u16 = 264;
+pub const GOSSIP_TIMESTAMP_FILTER: u16 = 265;
+
+/// The only `encoded_short_ids` encoding left in the spec: plain 8-byte ids.
+const ENCODING_UNCOMPRESSED: u8 = 0;
+
+/// Ask for the announcements and updates of some channels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryShortChannelIds {
+    pub chain_hash: ChainHash,
+    pub short_channel_ids: Vec<u64>,
+}
+
+impl QueryShortChannelIds {
+    /// The answer of a node without channel information: nothing, then the end of it.
+    pub fn empty_reply(&self) -> ReplyShortChannelIdsEnd {
+        ReplyShortChannelIdsEnd {
+            chain_hash: self.chain_hash,
+            full_information: false,
+        }
+    }
+}
+
+/// Ends the gossip sent for a [`QueryShortChannelIds`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplyShortChannelIdsEnd {
+    pub chain_hash: ChainHash,
+    /// `false` if the peer doesn't keep up-to-date channel information for this chain.
+    pub full_information: bool,
+}
+
+/// Ask for the short channel ids of the channels opened in a range of blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryChannelRange {
+    pub chain_hash: ChainHash,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
+}
+
+impl QueryChannelRange {
+    /// The block after the last one queried, saturating at `u32::MAX`.
+    pub fn end_blocknum(&self) -> u32 {
+        self.first_blocknum.saturating_add(self.number_of_blocks)
+    }
+
+    /// The answer of a node without channel information: no channels in the whole range, and
+    /// `sync_complete` unset to say it doesn't know better.
+    pub fn empty_reply(&self) -> ReplyChannelRange {
+        ReplyChannelRange {
+            chain_hash: self.chain_hash,
+            first_blocknum: self.first_blocknum,
+            number_of_blocks: self.number_of_blocks,
+            sync_complete: false,
+            short_channel_ids: Vec::new(),
+        }
+    }
+}
+
+/// One part of the answer to a [`QueryChannelRange`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplyChannelRange {
+    pub chain_hash: ChainHash,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
+    /// `false` if the peer doesn't keep up-to-date channel information for this chain.
+    pub sync_complete: bool,
+    pub short_channel_ids: Vec<u64>,
+}
+
+/// Ask the peer to send us the gossip with timestamps in
+/// `first_timestamp..first_timestamp + timestamp_range`, from now on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GossipTimestampFilter {
+    pub chain_hash: ChainHash,
+    pub first_timestamp: u32,
+    pub timestamp_range: u32,
+}
+
+impl Encode for QueryShortChannelIds {
+    const TYPE: u16 = QUERY_SHORT_CHANNEL_IDS;
+}
+
+impl Encode for ReplyShortChannelIdsEnd {
+    const TYPE: u16 = REPLY_SHORT_CHANNEL_IDS_END;
+}
+
+impl Encode for QueryChannelRange {
+    const TYPE: u16 = QUERY_CHANNEL_RANGE;
+}
+
+impl Encode for ReplyChannelRange {
+    const TYPE: u16 = REPLY_CHANNEL_RANGE;
+}
+
+impl Encode for GossipTimestampFilter {
+    const TYPE: u16 = GOSSIP_TIMESTAMP_FILTER;
+}
+
+impl Writeable for QueryShortChannelIds {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        write_encoded_short_ids(w, &self.short_channel_ids)
+    }
+}
+
+impl Writeable for ReplyShortChannelIdsEnd {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        (self.full_information as u8).write(w)
+    }
+}
+
+impl Writeable for QueryChannelRange {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        self.first_blocknum.write(w)?;
+        self.number_of_blocks.write(w)
+    }
+}
+
+impl Writeable for ReplyChannelRange {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        self.first_blocknum.write(w)?;
+        self.number_of_blocks.write(w)?;
+        (self.sync_complete as u8).write(w)?;
+        write_encoded_short_ids(w, &self.short_channel_ids)
+    }
+}
+
+impl Writeable for GossipTimestampFilter {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        self.first_timestamp.write(w)?;
+        self.timestamp_range.write(w)
+    }
+}
+
+/// Any of the messages of this module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum GossipQueryMessage {
+    QueryShortChannelIds(QueryShortChannelIds),
+    ReplyShortChannelIdsEnd(ReplyShortChannelIdsEnd),
+    QueryChannelRange(QueryChannelRange),
+    ReplyChannelRange(ReplyChannelRange),
+    GossipTimestampFilter(GossipTimestampFilter),
+}
+
+impl GossipQueryMessage {
+    /// Decode the payload of a message of type `type_id`, or return `Ok(None)` if the type
+    /// isn't one of this module's. Meant to be called from a [`LNSocket::read_custom`] handler.
+    ///
+    /// [`LNSocket::read_custom`]: crate::LNSocket::read_custom
+    pub fn read<R: Read>(type_id: u16, r: &mut R) -> Result<Option<Self>, DecodeError> {
+        let msg = match type_id {
+            QUERY_SHORT_CHANNEL_IDS => Self::QueryShortChannelIds(QueryShortChannelIds {
+                chain_hash: Readable::read(r)?,
+                short_channel_ids: read_encoded_short_ids(r)?,
+            }),
+            REPLY_SHORT_CHANNEL_IDS_END => {
+                let chain_hash = Readable::read(r)?;
+                Self::ReplyShortChannelIdsEnd(ReplyShortChannelIdsEnd {
+                    chain_hash,
+                    full_information: read_bool(r)?,
+                })
+            }
+            QUERY_CHANNEL_RANGE => Self::QueryChannelRange(QueryChannelRange {
+                chain_hash: Readable::read(r)?,
+                first_blocknum: Readable::read(r)?,
+                number_of_blocks: Readable::read(r)?,
+            }),
+            REPLY_CHANNEL_RANGE => {
+                let chain_hash = Readable::read(r)?;
+                let first_blocknum = Readable::read(r)?;
+                let number_of_blocks = Readable::read(r)?;
+                let sync_complete = read_bool(r)?;
+                Self::ReplyChannelRange(ReplyChannelRange {
+                    chain_hash,
+                    first_blocknum,
+                    number_of_blocks,
+                    sync_complete,
+                    short_channel_ids: read_encoded_short_ids(r)?,
+                })
+            }
+            GOSSIP_TIMESTAMP_FILTER => Self::GossipTimestampFilter(GossipTimestampFilter {
+                chain_hash: Readable::read(r)?,
+                first_timestamp: Readable::read(r)?,
+                timestamp_range: Readable::read(r)?,
+            }),
+            _ => return Ok(None),
+        };
+        // the TLVs (query options, timestamps, checksums) aren't decoded
+        io::copy(r, &mut io::sink())?;
+        Ok(Some(msg))
+    }
+}
+
+fn read_bool<R: Read>(r: &mut R) -> Result<bool, DecodeError> {
+    match u8::read(r)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(DecodeError::InvalidValue),
+    }
+}
+
+/// `ids` as a length-prefixed, uncompressed `encoded_short_ids`.
+fn write_encoded_short_ids<W: Writer>(w: &mut W, ids: &[u64]) -> Result<(), io::Error> {
+    let len = 1 + 8 * ids.len();
+    let len = u16::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    len.write(w)?;
+    ENCODING_UNCOMPRESSED.write(w)?;
+    for scid in ids {
+        scid.write(w)?;
+    }
+    Ok(())
+}
+
+/// A length-prefixed `encoded_short_ids`. Fails with `UnknownVersion` for zlib.
+fn read_encoded_short_ids<R: Read>(r: &mut R) -> Result<Vec<u64>, DecodeError> {
+    let len: u16 = Readable::read(r)?;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    if u8::read(r)? != ENCODING_UNCOMPRESSED {
+        return Err(DecodeError::UnknownVersion);
+    }
+    let ids = len as usize - 1;
+    if !ids.is_multiple_of(8) {
+        return Err(DecodeError::BadLengthDescriptor);
+    }
+    (0..ids / 8).map(|_| u64::read(r)).collect()
+}
+
+/// Why a [`ChannelRangeCollector`] rejected a reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelRangeError {
+    /// The reply is about another chain than the query.
+    WrongChain,
+    /// The reply starts before the previous one or after where the replies so far end, or
+    /// lies outside the query.
+    OutOfOrder,
+}
+
+impl fmt::Display for ChannelRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongChain => write!(f, "reply_channel_range for another chain"),
+            Self::OutOfOrder => write!(f, "reply_channel_range out of order"),
+        }
+    }
+}
+
+impl std::error::Error for ChannelRangeError {}
+
+/// Reassembles the `reply_channel_range` messages answering one [`QueryChannelRange`].
+///
+/// Per BOLT #7 the replies come in block order, the first one starting at or before the
+/// queried range and each following one no earlier than the one before, until they cover the
+/// whole range. Replies may overlap, as LND's do, but leave no gap.
+#[derive(Clone, Debug)]
+pub struct ChannelRangeCollector {
+    query: QueryChannelRange,
+    /// Where the last reply started and where the replies so far end, once one has arrived.
+    covered: Option<(u32, u32)>,
+    synced: bool,
+    short_channel_ids: Vec<u64>,
+}
+
+impl ChannelRangeCollector {
+    pub fn new(query: &QueryChannelRange) -> Self {
+        Self {
+            query: query.clone(),
+            covered: None,
+            synced: true,
+            short_channel_ids: Vec::new(),
+        }
+    }
+
+    /// Take the next reply. Returns whether the range is now complete.
+    pub fn add(&mut self, reply: ReplyChannelRange) -> Result<bool, ChannelRangeError> {
+        if reply.chain_hash != self.query.chain_hash {
+            return Err(ChannelRangeError::WrongChain);
+        }
+        let first = reply.first_blocknum;
+        let in_order = match self.covered {
+            None => first <= self.query.first_blocknum,
+            Some((prev_first, end)) => first >= prev_first && first <= end,
+        };
+        let end = first.saturating_add(reply.number_of_blocks);
+        if !in_order || end <= self.query.first_blocknum {
+            return Err(ChannelRangeError::OutOfOrder);
+        }
+
+        let covered_to = self.covered.map_or(end, |(_, to)| to.max(end));
+        self.covered = Some((first, covered_to));
+        self.synced &= reply.sync_complete;
+        self.short_channel_ids.extend(reply.short_channel_ids);
+        Ok(self.is_complete())
+    }
+
+    /// Whether the replies so far cover the whole queried range.
+    pub fn is_complete(&self) -> bool {
+        self.covered
+            .is_some_and(|(_, to)| to >= self.query.end_blocknum())
+    }
+
+    /// `false` if any reply said the peer isn't keeping up with this chain.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// The ids received so far, in the order the peer sent them.
+    pub fn short_channel_ids(&self) -> &[u64] {
+        &self.short_channel_ids
+    }
+
+    pub fn into_short_channel_ids(self) -> Vec<u64> {
+        self.short_channel_ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(first_blocknum: u32, number_of_blocks: u32, ids: &[u64]) -> ReplyChannelRange {
+        ReplyChannelRange {
+            chain_hash: ChainHash::BITCOIN,
+            first_blocknum,
+            number_of_blocks,
+            sync_complete: true,
+            short_channel_ids: ids.to_vec(),
+        }
+    }
+
+    #[test]
+    fn reply_round_trips_and_skips_tlvs() {
+        let msg = reply(100, 50, &[1 << 40, 2 << 40]);
+        let mut payload = msg.encode();
+        // a timestamps TLV we don't decode
+        payload.extend_from_slice(&[1, 2, 0xaa, 0xbb]);
+        assert_eq!(
+            GossipQueryMessage::read(REPLY_CHANNEL_RANGE, &mut &payload[..]).unwrap(),
+            Some(GossipQueryMessage::ReplyChannelRange(msg))
+        );
+
+        let query = QueryChannelRange {
+            chain_hash: ChainHash::TESTNET3,
+            first_blocknum: 7,
+            number_of_blocks: 9,
+        };
+        let payload = query.encode();
+        assert_eq!(payload.len(), 32 + 4 + 4);
+        assert_eq!(
+            GossipQueryMessage::read(QUERY_CHANNEL_RANGE, &mut &payload[..]).unwrap(),
+            Some(GossipQueryMessage::QueryChannelRange(query))
+        );
+        assert_eq!(GossipQueryMessage::read(18, &mut &[][..]).unwrap(), None);
+
+        // zlib
+        let mut zlib = reply(0, 1, &[]).encode();
+        let at = 32 + 4 + 4 + 1 + 2;
+        zlib[at] = 1;
+        assert_eq!(
+            GossipQueryMessage::read(REPLY_CHANNEL_RANGE, &mut &zlib[..]),
+            Err(DecodeError::UnknownVersion)
+        );
+    }
+
+    #[test]
+    fn empty_replies_are_well_formed() {
+        let query = QueryShortChannelIds {
+            chain_hash: ChainHash::SIGNET,
+            short_channel_ids: vec![1, 2 << 40],
+        };
+        let payload = query.encode();
+        assert_eq!(payload.len(), 32 + 2 + 1 + 16);
+        assert_eq!(
+            GossipQueryMessage::read(QUERY_SHORT_CHANNEL_IDS, &mut &payload[..]).unwrap(),
+            Some(GossipQueryMessage::QueryShortChannelIds(query.clone()))
+        );
+
+        let end = query.empty_reply();
+        assert!(!end.full_information);
+        let payload = end.encode();
+        assert_eq!(payload.len(), 32 + 1);
+        assert_eq!(
+            GossipQueryMessage::read(REPLY_SHORT_CHANNEL_IDS_END, &mut &payload[..]).unwrap(),
+            Some(GossipQueryMessage::ReplyShortChannelIdsEnd(end))
+        );
+
+        let range = QueryChannelRange {
+            chain_hash: ChainHash::SIGNET,
+            first_blocknum: 5,
+            number_of_blocks: 10,
+        }
+        .empty_reply();
+        assert_eq!((range.first_blocknum, range.number_of_blocks), (5, 10));
+        assert!(!range.sync_complete);
+        // no ids still says how they are encoded
+        assert_eq!(&range.encode()[32 + 4 + 4 + 1..], &[0, 1, 0]);
+    }
+
+    #[test]
+    fn collector_reassembles_split_replies() {
+        let query = QueryChannelRange {
+            chain_hash: ChainHash::BITCOIN,
+            first_blocknum: 100,
+            number_of_blocks: 100,
+        };
+        let mut replies = ChannelRangeCollector::new(&query);
+        assert!(!replies.add(reply(90, 60, &[1, 2])).unwrap());
+        assert_eq!(
+            replies.add(reply(160, 40, &[3])),
+            Err(ChannelRangeError::OutOfOrder)
+        );
+        let mut last = reply(150, 50, &[3]);
+        last.sync_complete = false;
+        assert!(replies.add(last).unwrap());
+        assert!(!replies.is_synced());
+        assert_eq!(replies.into_short_channel_ids(), [1, 2, 3]);
+
+        // overlapping replies, as LND sends them
+        let mut replies = ChannelRangeCollector::new(&query);
+        assert!(!replies.add(reply(100, 60, &[1])).unwrap());
+        assert!(!replies.add(reply(140, 20, &[2])).unwrap());
+        assert_eq!(
+            replies.add(reply(130, 70, &[3])),
+            Err(ChannelRangeError::OutOfOrder)
+        );
+        assert!(replies.add(reply(140, 60, &[3])).unwrap());
+        assert_eq!(replies.short_channel_ids(), [1, 2, 3]);
+
+        let mut replies = ChannelRangeCollector::new(&query);
+        let mut other = reply(100, 100, &[]);
+        other.chain_hash = ChainHash::SIGNET;
+        assert_eq!(replies.add(other), Err(ChannelRangeError::WrongChain));
+        assert_eq!(
+            replies.add(reply(101, 99, &[])),
+            Err(ChannelRangeError::OutOfOrder)
+        );
+    }
+}
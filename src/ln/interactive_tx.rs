@@ -0,0 +1,387 @@
+//! Read-only decoding of the [BOLT #2] interactive transaction construction and splicing
+//! messages.
+//!
+//! These are not part of [`Message`](crate::ln::wire::Message); decode them with
+//! [`InteractiveTxMessage::read`] from [`LNSocket::read_custom`] to observe a dual-funding or
+//! splice negotiation:
+//!
+//! ```no_run
+//! use lnsocket::ln::interactive_tx::InteractiveTxMessage;
+//! use lnsocket::ln::wire::Message;
+//! # #[cfg(feature = "tokio")]
+//! # async fn ex(mut sock: lnsocket::LNSocket) -> Result<(), lnsocket::Error> {
+//! loop {
+//!     let msg = sock.read_custom(|t, r| InteractiveTxMessage::read(t, r)).await?;
+//!     if let Message::Custom(msg) = msg {
+//!         println!("{msg:?}");
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! Only the fixed fields are decoded. Whatever follows them, usually a TLV stream, is kept
+//! undecoded in `tlvs`, so messages from newer spec revisions still decode.
+//!
+//! [BOLT #2]: https://github.com/lightning/bolts/blob/master/02-peer-protocol.md
+//! [`LNSocket::read_custom`]: crate::LNSocket::read_custom
+
+use std::io::{self, Read};
+
+use bitcoin::secp256k1::PublicKey;
+
+use crate::ln::msgs::DecodeError;
+use crate::ln::types::ChannelId;
+use crate::util::ser::Readable;
+
+pub const STFU: u16 = 2;
+pub const TX_ADD_INPUT: u16 = 66;
+pub const TX_ADD_OUTPUT: u16 = 67;
+pub const TX_REMOVE_INPUT: u16 = 68;
+pub const TX_REMOVE_OUTPUT: u16 = 69;
+pub const TX_COMPLETE: u16 = 70;
+pub const TX_SIGNATURES: u16 = 71;
+pub const TX_INIT_RBF: u16 = 72;
+pub const TX_ACK_RBF: u16 = 73;
+pub const TX_ABORT: u16 = 74;
+pub const SPLICE_LOCKED: u16 = 77;
+pub const SPLICE_INIT: u16 = 80;
+pub const SPLICE_ACK: u16 = 81;
+
+/// `stfu`: quiesce the channel before a splice.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stfu {
+    pub channel_id: ChannelId,
+    pub initiator: bool,
+    pub tlvs: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxAddInput {
+    pub channel_id: ChannelId,
+    pub serial_id: u64,
+    /// The whole transaction the input spends, consensus-encoded.
+    pub prevtx: Vec<u8>,
+    pub prevtx_vout: u32,
+    pub sequence: u32,
+    pub tlvs: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxAddOutput {
+    pub channel_id: ChannelId,
+    pub serial_id: u64,
+    pub sats: u64,
+    pub script: Vec<u8>,
+    pub tlvs: Vec<u8>,
+}
+
+/// `tx_remove_input` and `tx_remove_output`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxRemove {
+    pub channel_id: ChannelId,
+    pub serial_id: u64,
+    pub tlvs: Vec<u8>,
+}
+
+/// `tx_complete` and `tx_ack_rbf`, which carry nothing but the channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxChannelOnly {
+    pub channel_id: ChannelId,
+    pub tlvs: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxSignatures {
+    pub channel_id: ChannelId,
+    /// In wire (internal) byte order.
+    pub txid: [u8; 32],
+    /// One consensus-encoded witness stack per input we contributed, in serial id order.
+    pub witnesses: Vec<Vec<u8>>,
+    pub tlvs: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxInitRbf {
+    pub channel_id: ChannelId,
+    pub locktime: u32,
+    pub feerate_per_kw: u32,
+    pub tlvs: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxAbort {
+    pub channel_id: ChannelId,
+    /// Peer-controlled, usually but not necessarily text.
+    pub data: Vec<u8>,
+    pub tlvs: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpliceInit {
+    pub channel_id: ChannelId,
+    /// Negative when the initiator splices funds out.
+    pub funding_contribution_sats: i64,
+    pub funding_feerate_per_kw: u32,
+    pub locktime: u32,
+    pub funding_pubkey: PublicKey,
+    pub tlvs: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpliceAck {
+    pub channel_id: ChannelId,
+    pub funding_contribution_sats: i64,
+    pub funding_pubkey: PublicKey,
+    pub tlvs: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpliceLocked {
+    pub channel_id: ChannelId,
+    /// In wire (internal) byte order.
+    pub splice_txid: [u8; 32],
+    pub tlvs: Vec<u8>,
+}
+
+/// Any of the messages of this module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InteractiveTxMessage {
+    Stfu(Stfu),
+    TxAddInput(TxAddInput),
+    TxAddOutput(TxAddOutput),
+    TxRemoveInput(TxRemove),
+    TxRemoveOutput(TxRemove),
+    TxComplete(TxChannelOnly),
+    TxSignatures(TxSignatures),
+    TxInitRbf(TxInitRbf),
+    TxAckRbf(TxChannelOnly),
+    TxAbort(TxAbort),
+    SpliceInit(SpliceInit),
+    SpliceAck(SpliceAck),
+    SpliceLocked(SpliceLocked),
+}
+
+impl InteractiveTxMessage {
+    /// Decode the payload of a message of type `type_id`, or return `Ok(None)` if the type
+    /// isn't one of this module's. Meant to be called from a [`LNSocket::read_custom`] handler.
+    ///
+    /// [`LNSocket::read_custom`]: crate::LNSocket::read_custom
+    pub fn read<R: Read>(type_id: u16, r: &mut R) -> Result<Option<Self>, DecodeError> {
+        let channel_id = match type_id {
+            STFU | TX_ADD_INPUT..=TX_ABORT | SPLICE_LOCKED | SPLICE_INIT | SPLICE_ACK => {
+                ChannelId::read(r)?
+            }
+            _ => return Ok(None),
+        };
+
+        let msg = match type_id {
+            STFU => {
+                let initiator = u8::read(r)? != 0;
+                Self::Stfu(Stfu {
+                    channel_id,
+                    initiator,
+                    tlvs: rest(r)?,
+                })
+            }
+            TX_ADD_INPUT => Self::TxAddInput(TxAddInput {
+                channel_id,
+                serial_id: Readable::read(r)?,
+                prevtx: Readable::read(r)?,
+                prevtx_vout: Readable::read(r)?,
+                sequence: Readable::read(r)?,
+                tlvs: rest(r)?,
+            }),
+            TX_ADD_OUTPUT => Self::TxAddOutput(TxAddOutput {
+                channel_id,
+                serial_id: Readable::read(r)?,
+                sats: Readable::read(r)?,
+                script: Readable::read(r)?,
+                tlvs: rest(r)?,
+            }),
+            TX_REMOVE_INPUT | TX_REMOVE_OUTPUT => {
+                let remove = TxRemove {
+                    channel_id,
+                    serial_id: Readable::read(r)?,
+                    tlvs: rest(r)?,
+                };
+                if type_id == TX_REMOVE_INPUT {
+                    Self::TxRemoveInput(remove)
+                } else {
+                    Self::TxRemoveOutput(remove)
+                }
+            }
+            TX_COMPLETE | TX_ACK_RBF => {
+                let msg = TxChannelOnly {
+                    channel_id,
+                    tlvs: rest(r)?,
+                };
+                if type_id == TX_COMPLETE {
+                    Self::TxComplete(msg)
+                } else {
+                    Self::TxAckRbf(msg)
+                }
+            }
+            TX_SIGNATURES => {
+                let txid = Readable::read(r)?;
+                let count: u16 = Readable::read(r)?;
+                let witnesses = (0..count)
+                    .map(|_| Readable::read(r))
+                    .collect::<Result<_, _>>()?;
+                Self::TxSignatures(TxSignatures {
+                    channel_id,
+                    txid,
+                    witnesses,
+                    tlvs: rest(r)?,
+                })
+            }
+            TX_INIT_RBF => Self::TxInitRbf(TxInitRbf {
+                channel_id,
+                locktime: Readable::read(r)?,
+                feerate_per_kw: Readable::read(r)?,
+                tlvs: rest(r)?,
+            }),
+            TX_ABORT => Self::TxAbort(TxAbort {
+                channel_id,
+                data: Readable::read(r)?,
+                tlvs: rest(r)?,
+            }),
+            SPLICE_INIT => Self::SpliceInit(SpliceInit {
+                channel_id,
+                funding_contribution_sats: Readable::read(r)?,
+                funding_feerate_per_kw: Readable::read(r)?,
+                locktime: Readable::read(r)?,
+                funding_pubkey: read_pubkey(r)?,
+                tlvs: rest(r)?,
+            }),
+            SPLICE_ACK => Self::SpliceAck(SpliceAck {
+                channel_id,
+                funding_contribution_sats: Readable::read(r)?,
+                funding_pubkey: read_pubkey(r)?,
+                tlvs: rest(r)?,
+            }),
+            SPLICE_LOCKED => Self::SpliceLocked(SpliceLocked {
+                channel_id,
+                splice_txid: Readable::read(r)?,
+                tlvs: rest(r)?,
+            }),
+            _ => unreachable!("filtered above"),
+        };
+        Ok(Some(msg))
+    }
+
+    /// The wire type of this message.
+    pub fn type_id(&self) -> u16 {
+        match self {
+            Self::Stfu(_) => STFU,
+            Self::TxAddInput(_) => TX_ADD_INPUT,
+            Self::TxAddOutput(_) => TX_ADD_OUTPUT,
+            Self::TxRemoveInput(_) => TX_REMOVE_INPUT,
+            Self::TxRemoveOutput(_) => TX_REMOVE_OUTPUT,
+            Self::TxComplete(_) => TX_COMPLETE,
+            Self::TxSignatures(_) => TX_SIGNATURES,
+            Self::TxInitRbf(_) => TX_INIT_RBF,
+            Self::TxAckRbf(_) => TX_ACK_RBF,
+            Self::TxAbort(_) => TX_ABORT,
+            Self::SpliceInit(_) => SPLICE_INIT,
+            Self::SpliceAck(_) => SPLICE_ACK,
+            Self::SpliceLocked(_) => SPLICE_LOCKED,
+        }
+    }
+
+    /// The channel the message is about.
+    pub fn channel_id(&self) -> ChannelId {
+        match self {
+            Self::Stfu(m) => m.channel_id,
+            Self::TxAddInput(m) => m.channel_id,
+            Self::TxAddOutput(m) => m.channel_id,
+            Self::TxRemoveInput(m) | Self::TxRemoveOutput(m) => m.channel_id,
+            Self::TxComplete(m) | Self::TxAckRbf(m) => m.channel_id,
+            Self::TxSignatures(m) => m.channel_id,
+            Self::TxInitRbf(m) => m.channel_id,
+            Self::TxAbort(m) => m.channel_id,
+            Self::SpliceInit(m) => m.channel_id,
+            Self::SpliceAck(m) => m.channel_id,
+            Self::SpliceLocked(m) => m.channel_id,
+        }
+    }
+}
+
+fn read_pubkey<R: Read>(r: &mut R) -> Result<PublicKey, DecodeError> {
+    let mut buf = [0u8; 33];
+    r.read_exact(&mut buf)?;
+    PublicKey::from_slice(&buf).map_err(|_| DecodeError::InvalidValue)
+}
+
+fn rest<R: Read>(r: &mut R) -> Result<Vec<u8>, io::Error> {
+    let mut buf = Vec::new();
+    r.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(type_id: u16, payload: &[u8]) -> Result<Option<InteractiveTxMessage>, DecodeError> {
+        InteractiveTxMessage::read(type_id, &mut io::Cursor::new(payload))
+    }
+
+    #[test]
+    fn decodes_add_input_and_keeps_tlvs() {
+        let mut payload = vec![7; 32];
+        payload.extend_from_slice(&2u64.to_be_bytes());
+        payload.extend_from_slice(&3u16.to_be_bytes());
+        payload.extend_from_slice(&[0xaa, 0xbb, 0xcc]);
+        payload.extend_from_slice(&1u32.to_be_bytes());
+        payload.extend_from_slice(&0xfffffffdu32.to_be_bytes());
+        payload.extend_from_slice(&[0x01, 0x00]);
+
+        let msg = decode(TX_ADD_INPUT, &payload).unwrap().unwrap();
+        assert_eq!(msg.type_id(), TX_ADD_INPUT);
+        assert_eq!(msg.channel_id(), ChannelId([7; 32]));
+        assert_eq!(
+            msg,
+            InteractiveTxMessage::TxAddInput(TxAddInput {
+                channel_id: ChannelId([7; 32]),
+                serial_id: 2,
+                prevtx: vec![0xaa, 0xbb, 0xcc],
+                prevtx_vout: 1,
+                sequence: 0xfffffffd,
+                tlvs: vec![0x01, 0x00],
+            })
+        );
+
+        // truncated fixed fields are an error, not trailing data
+        assert!(decode(TX_ADD_INPUT, &payload[..40]).is_err());
+    }
+
+    #[test]
+    fn decodes_splice_init() {
+        let pubkey = PublicKey::from_secret_key(
+            &bitcoin::secp256k1::Secp256k1::signing_only(),
+            &bitcoin::secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
+        );
+        let mut payload = vec![1; 32];
+        payload.extend_from_slice(&(-50_000i64).to_be_bytes());
+        payload.extend_from_slice(&253u32.to_be_bytes());
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        payload.extend_from_slice(&pubkey.serialize());
+
+        match decode(SPLICE_INIT, &payload).unwrap().unwrap() {
+            InteractiveTxMessage::SpliceInit(init) => {
+                assert_eq!(init.funding_contribution_sats, -50_000);
+                assert_eq!(init.funding_feerate_per_kw, 253);
+                assert_eq!(init.funding_pubkey, pubkey);
+                assert!(init.tlvs.is_empty());
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn other_types_are_left_alone() {
+        assert_eq!(decode(18, &[0; 4]).unwrap(), None);
+        assert_eq!(decode(0x8001, &[]).unwrap(), None);
+    }
+}
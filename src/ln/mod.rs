@@ -7,7 +7,24 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
+// only the handshake and framing build without `std`
+#[cfg(feature = "std")]
+pub mod features;
+#[cfg(feature = "std")]
+pub mod gossip;
+#[cfg(feature = "std")]
+pub mod gossip_queries;
+#[cfg(feature = "std")]
+pub mod interactive_tx;
+#[cfg(feature = "std")]
 pub mod msgs;
+#[cfg(feature = "std")]
+pub mod onion_failure;
 pub mod peer_channel_encryptor;
+#[cfg(feature = "std")]
 pub mod types;
+#[cfg(feature = "std")]
 pub mod wire;
+
+#[cfg(all(test, feature = "std"))]
+mod test_vectors;
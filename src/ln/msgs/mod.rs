@@ -1,6 +1,9 @@
 use crate::util::{
     logger,
-    ser::{LengthLimitedRead, LengthReadable, Readable, WithoutLength, Writeable, Writer},
+    ser::{
+        BigSize, FixedLengthReader, LengthLimitedRead, LengthReadable, Readable, WithoutLength,
+        Writeable, Writer,
+    },
 };
 use crate::{encode_tlv_stream, ln::types::ChannelId, socket_addr::SocketAddress};
 use bitcoin::blockdata::constants::ChainHash;
@@ -101,6 +104,45 @@ pub struct WarningMessage {
     pub data: String,
 }
 
+/// The longest `data` an error or warning can carry: a message is at most 65535 bytes, of
+/// which the type, channel id and length take 36.
+pub const MAX_ERROR_DATA_LEN: usize = 65535 - 2 - 32 - 2;
+
+/// Cut `data` down to [`MAX_ERROR_DATA_LEN`] bytes on a character boundary.
+fn truncate_error_data(mut data: String) -> String {
+    if data.len() > MAX_ERROR_DATA_LEN {
+        let mut end = MAX_ERROR_DATA_LEN;
+        while !data.is_char_boundary(end) {
+            end -= 1;
+        }
+        data.truncate(end);
+    }
+    data
+}
+
+impl ErrorMessage {
+    /// An error about `channel_id`, or about the whole connection if `None` (the all-zero
+    /// channel id, after which the peer closes all our channels). `data` is truncated to
+    /// [`MAX_ERROR_DATA_LEN`] bytes.
+    pub fn new(channel_id: Option<ChannelId>, data: impl Into<String>) -> Self {
+        Self {
+            channel_id: channel_id.unwrap_or_else(ChannelId::new_zero),
+            data: truncate_error_data(data.into()),
+        }
+    }
+}
+
+impl WarningMessage {
+    /// A warning about `channel_id`, or about the connection in general if `None`. `data` is
+    /// truncated to [`MAX_ERROR_DATA_LEN`] bytes.
+    pub fn new(channel_id: Option<ChannelId>, data: impl Into<String>) -> Self {
+        Self {
+            channel_id: channel_id.unwrap_or_else(ChannelId::new_zero),
+            data: truncate_error_data(data.into()),
+        }
+    }
+}
+
 /// A [`ping`] message to be sent to or received from a peer.
 ///
 /// [`ping`]: https://github.com/lightning/bolts/blob/master/01-messaging.md#the-ping-and-pong-messages
@@ -326,24 +368,49 @@ impl LengthReadable for Init {
         //println!("reading global features {:?}", global_features);
         let features: Vec<u8> = Readable::read(r)?;
         //println!("reading remote features {:?}", features);
-        //let mut remote_network_address: Option<SocketAddress> = None;
-        //let mut networks: Option<WithoutLength<Vec<ChainHash>>> = None;
+        let mut remote_network_address: Option<SocketAddress> = None;
+        let mut networks: Option<Vec<ChainHash>> = None;
 
-        let mut buf = Vec::with_capacity(r.remaining_bytes() as usize);
-        r.read_to_end(&mut buf)?;
+        // the TLV stream runs to the end of the message. decode_tlv_stream! relies on EOF
+        // surfacing as ShortRead, which our DecodeError doesn't do, so walk it by hand
+        let mut last_type = None;
+        while r.remaining_bytes() > 0 {
+            let typ: BigSize = Readable::read(r)?;
+            if last_type.is_some_and(|last| typ.0 <= last) {
+                return Err(DecodeError::InvalidValue);
+            }
+            last_type = Some(typ.0);
+
+            let len: BigSize = Readable::read(r)?;
+            let mut value = FixedLengthReader::new(r, len.0);
+            match typ.0 {
+                1 => {
+                    if !len.0.is_multiple_of(32) {
+                        return Err(DecodeError::InvalidValue);
+                    }
+                    let mut chains = Vec::new();
+                    while value.remaining_bytes() > 0 {
+                        chains.push(Readable::read(&mut value)?);
+                    }
+                    networks = Some(chains);
+                }
+                // optional, so an address type we don't know is no reason to hang up
+                3 => remote_network_address = Readable::read(&mut value).ok(),
+                t if t.is_multiple_of(2) => return Err(DecodeError::UnknownRequiredFeature),
+                _ => {}
+            }
+            // skip unknown odd types, and make sure the value was all there
+            io::copy(&mut value, &mut io::sink())?;
+            if value.remaining_bytes() > 0 {
+                return Err(DecodeError::ShortRead);
+            }
+        }
 
-        // TODO: fixme
-        /*
-        decode_tlv_stream!(r, {
-            (1, networks, option),
-            (3, remote_network_address, option)
-        });
-        */
         Ok(Init {
             global_features,
             features,
-            networks: None,
-            remote_network_address: None,
+            networks,
+            remote_network_address,
         })
     }
 }
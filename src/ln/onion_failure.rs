@@ -0,0 +1,437 @@
+//! The [BOLT #4] failure messages an erring node returns for a payment.
+//!
+//! Core Lightning hands the decrypted failure to its callers: the errors of `waitsendpay` and
+//! `sendpay` carry it as `raw_message` in their `data`, next to a `failcode`.
+//! [`decode_failure`] turns a code and its data into an [`OnionFailure`], with the fields
+//! the spec gives it and a description to show users:
+//!
+//! ```no_run
+//! use lnsocket::ln::onion_failure::OnionFailure;
+//! # #[cfg(feature = "tokio")]
+//! # async fn ex(client: lnsocket::CommandoClient, hash: &str) {
+//! let params = serde_json::json!({"payment_hash": hash});
+//! if let Err(lnsocket::Error::Rpc(err)) = client.call("waitsendpay", params).await {
+//!     match err.onion_failure() {
+//!         Some(OnionFailure::IncorrectOrUnknownPaymentDetails { .. }) => {
+//!             println!("the recipient doesn't know this invoice")
+//!         }
+//!         Some(failure) if failure.is_permanent() => println!("giving up: {failure}"),
+//!         Some(failure) => println!("retrying elsewhere: {failure}"),
+//!         None => println!("{}", err.message),
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! The `channel_update`s some failures carry are kept as the erring node sent them, usually
+//! with their message type in front. Data after the fields is ignored, as the spec asks;
+//! codes it doesn't define come back as [`OnionFailure::Unknown`].
+//!
+//! [BOLT #4]: https://github.com/lightning/bolts/blob/master/04-onion-routing.md#failure-messages
+
+use std::fmt;
+use std::io;
+
+use crate::ln::msgs::DecodeError;
+use crate::util::ser::{BigSize, Readable};
+
+/// The failure code bit of failures caused by an unparsable onion.
+pub const BADONION: u16 = 0x8000;
+/// The failure code bit of failures that retrying won't fix.
+pub const PERM: u16 = 0x4000;
+/// The failure code bit of failures of the erring node itself, not of a channel.
+pub const NODE: u16 = 0x2000;
+/// The failure code bit of failures that carry a `channel_update`.
+pub const UPDATE: u16 = 0x1000;
+
+/// A decoded failure message, see the [module docs](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OnionFailure {
+    InvalidRealm,
+    TemporaryNodeFailure,
+    PermanentNodeFailure,
+    RequiredNodeFeatureMissing,
+    InvalidOnionVersion {
+        sha256_of_onion: [u8; 32],
+    },
+    InvalidOnionHmac {
+        sha256_of_onion: [u8; 32],
+    },
+    InvalidOnionKey {
+        sha256_of_onion: [u8; 32],
+    },
+    TemporaryChannelFailure {
+        channel_update: Option<Vec<u8>>,
+    },
+    PermanentChannelFailure,
+    RequiredChannelFeatureMissing,
+    UnknownNextPeer,
+    AmountBelowMinimum {
+        htlc_msat: u64,
+        channel_update: Option<Vec<u8>>,
+    },
+    FeeInsufficient {
+        htlc_msat: u64,
+        channel_update: Option<Vec<u8>>,
+    },
+    IncorrectCltvExpiry {
+        cltv_expiry: u32,
+        channel_update: Option<Vec<u8>>,
+    },
+    ExpiryTooSoon {
+        channel_update: Option<Vec<u8>>,
+    },
+    IncorrectOrUnknownPaymentDetails {
+        htlc_msat: u64,
+        height: u32,
+    },
+    FinalIncorrectCltvExpiry {
+        cltv_expiry: u32,
+    },
+    FinalIncorrectHtlcAmount {
+        incoming_htlc_amt: u64,
+    },
+    ChannelDisabled {
+        disabled_flags: u16,
+        channel_update: Option<Vec<u8>>,
+    },
+    ExpiryTooFar,
+    InvalidOnionPayload {
+        /// The TLV type that was wrong, with the byte `offset` it starts at.
+        tlv_type: u64,
+        offset: u16,
+    },
+    MppTimeout,
+    InvalidOnionBlinding {
+        sha256_of_onion: [u8; 32],
+    },
+    /// A code the spec doesn't define, with its undecoded data.
+    Unknown {
+        code: u16,
+        data: Vec<u8>,
+    },
+}
+
+/// Decode the failure with `code` and the `data` after it.
+///
+/// Fails with `ShortRead` if `data` is too short for the fields of `code`.
+pub fn decode_failure(code: u16, data: &[u8]) -> Result<OnionFailure, DecodeError> {
+    decode_fields(code, data).map_err(short_read)
+}
+
+fn decode_fields(code: u16, data: &[u8]) -> Result<OnionFailure, DecodeError> {
+    use OnionFailure::*;
+
+    let r = &mut &data[..];
+    Ok(match code {
+        c if c == PERM | 1 => InvalidRealm,
+        c if c == NODE | 2 => TemporaryNodeFailure,
+        c if c == PERM | NODE | 2 => PermanentNodeFailure,
+        c if c == PERM | NODE | 3 => RequiredNodeFeatureMissing,
+        c if c == BADONION | PERM | 4 => InvalidOnionVersion {
+            sha256_of_onion: Readable::read(r)?,
+        },
+        c if c == BADONION | PERM | 5 => InvalidOnionHmac {
+            sha256_of_onion: Readable::read(r)?,
+        },
+        c if c == BADONION | PERM | 6 => InvalidOnionKey {
+            sha256_of_onion: Readable::read(r)?,
+        },
+        c if c == UPDATE | 7 => TemporaryChannelFailure {
+            channel_update: read_channel_update(r)?,
+        },
+        c if c == PERM | 8 => PermanentChannelFailure,
+        c if c == PERM | 9 => RequiredChannelFeatureMissing,
+        c if c == PERM | 10 => UnknownNextPeer,
+        c if c == UPDATE | 11 => AmountBelowMinimum {
+            htlc_msat: Readable::read(r)?,
+            channel_update: read_channel_update(r)?,
+        },
+        c if c == UPDATE | 12 => FeeInsufficient {
+            htlc_msat: Readable::read(r)?,
+            channel_update: read_channel_update(r)?,
+        },
+        c if c == UPDATE | 13 => IncorrectCltvExpiry {
+            cltv_expiry: Readable::read(r)?,
+            channel_update: read_channel_update(r)?,
+        },
+        c if c == UPDATE | 14 => ExpiryTooSoon {
+            channel_update: read_channel_update(r)?,
+        },
+        c if c == PERM | 15 => IncorrectOrUnknownPaymentDetails {
+            htlc_msat: Readable::read(r)?,
+            height: Readable::read(r)?,
+        },
+        18 => FinalIncorrectCltvExpiry {
+            cltv_expiry: Readable::read(r)?,
+        },
+        19 => FinalIncorrectHtlcAmount {
+            incoming_htlc_amt: Readable::read(r)?,
+        },
+        c if c == UPDATE | 20 => ChannelDisabled {
+            disabled_flags: Readable::read(r)?,
+            channel_update: read_channel_update(r)?,
+        },
+        21 => ExpiryTooFar,
+        c if c == PERM | 22 => InvalidOnionPayload {
+            tlv_type: BigSize::read(r)?.0,
+            offset: Readable::read(r)?,
+        },
+        23 => MppTimeout,
+        c if c == BADONION | PERM | 24 => InvalidOnionBlinding {
+            sha256_of_onion: Readable::read(r)?,
+        },
+        code => Unknown {
+            code,
+            data: data.to_vec(),
+        },
+    })
+}
+
+/// Decode a whole failure message: the 2-byte code, then its data. This is what CLN calls
+/// `raw_message`.
+pub fn decode_failure_message(msg: &[u8]) -> Result<OnionFailure, DecodeError> {
+    let code: u16 = Readable::read(&mut &msg[..]).map_err(short_read)?;
+    decode_failure(code, &msg[2..])
+}
+
+/// Readers of slices run out with an I/O error, report it as the short read it is.
+fn short_read(err: DecodeError) -> DecodeError {
+    match err {
+        DecodeError::Io(io::ErrorKind::UnexpectedEof) => DecodeError::ShortRead,
+        err => err,
+    }
+}
+
+/// A length-prefixed `channel_update`. Left out, or of length zero, it is `None`: the spec
+/// lets nodes leave it out.
+fn read_channel_update(r: &mut &[u8]) -> Result<Option<Vec<u8>>, DecodeError> {
+    if r.is_empty() {
+        return Ok(None);
+    }
+    let len: u16 = Readable::read(r)?;
+    if r.len() < len as usize {
+        return Err(DecodeError::ShortRead);
+    }
+    let (update, rest) = r.split_at(len as usize);
+    *r = rest;
+    Ok((len > 0).then(|| update.to_vec()))
+}
+
+impl OnionFailure {
+    pub fn code(&self) -> u16 {
+        use OnionFailure::*;
+
+        match self {
+            InvalidRealm => PERM | 1,
+            TemporaryNodeFailure => NODE | 2,
+            PermanentNodeFailure => PERM | NODE | 2,
+            RequiredNodeFeatureMissing => PERM | NODE | 3,
+            InvalidOnionVersion { .. } => BADONION | PERM | 4,
+            InvalidOnionHmac { .. } => BADONION | PERM | 5,
+            InvalidOnionKey { .. } => BADONION | PERM | 6,
+            TemporaryChannelFailure { .. } => UPDATE | 7,
+            PermanentChannelFailure => PERM | 8,
+            RequiredChannelFeatureMissing => PERM | 9,
+            UnknownNextPeer => PERM | 10,
+            AmountBelowMinimum { .. } => UPDATE | 11,
+            FeeInsufficient { .. } => UPDATE | 12,
+            IncorrectCltvExpiry { .. } => UPDATE | 13,
+            ExpiryTooSoon { .. } => UPDATE | 14,
+            IncorrectOrUnknownPaymentDetails { .. } => PERM | 15,
+            FinalIncorrectCltvExpiry { .. } => 18,
+            FinalIncorrectHtlcAmount { .. } => 19,
+            ChannelDisabled { .. } => UPDATE | 20,
+            ExpiryTooFar => 21,
+            InvalidOnionPayload { .. } => PERM | 22,
+            MppTimeout => 23,
+            InvalidOnionBlinding { .. } => BADONION | PERM | 24,
+            Unknown { code, .. } => *code,
+        }
+    }
+
+    /// The name the spec gives the failure, e.g. `"temporary_channel_failure"`.
+    pub fn name(&self) -> &'static str {
+        use OnionFailure::*;
+
+        match self {
+            InvalidRealm => "invalid_realm",
+            TemporaryNodeFailure => "temporary_node_failure",
+            PermanentNodeFailure => "permanent_node_failure",
+            RequiredNodeFeatureMissing => "required_node_feature_missing",
+            InvalidOnionVersion { .. } => "invalid_onion_version",
+            InvalidOnionHmac { .. } => "invalid_onion_hmac",
+            InvalidOnionKey { .. } => "invalid_onion_key",
+            TemporaryChannelFailure { .. } => "temporary_channel_failure",
+            PermanentChannelFailure => "permanent_channel_failure",
+            RequiredChannelFeatureMissing => "required_channel_feature_missing",
+            UnknownNextPeer => "unknown_next_peer",
+            AmountBelowMinimum { .. } => "amount_below_minimum",
+            FeeInsufficient { .. } => "fee_insufficient",
+            IncorrectCltvExpiry { .. } => "incorrect_cltv_expiry",
+            ExpiryTooSoon { .. } => "expiry_too_soon",
+            IncorrectOrUnknownPaymentDetails { .. } => "incorrect_or_unknown_payment_details",
+            FinalIncorrectCltvExpiry { .. } => "final_incorrect_cltv_expiry",
+            FinalIncorrectHtlcAmount { .. } => "final_incorrect_htlc_amount",
+            ChannelDisabled { .. } => "channel_disabled",
+            ExpiryTooFar => "expiry_too_far",
+            InvalidOnionPayload { .. } => "invalid_onion_payload",
+            MppTimeout => "mpp_timeout",
+            InvalidOnionBlinding { .. } => "invalid_onion_blinding",
+            Unknown { .. } => "unknown",
+        }
+    }
+
+    /// What went wrong, in a sentence for users.
+    pub fn description(&self) -> &'static str {
+        use OnionFailure::*;
+
+        match self {
+            InvalidRealm => "a node on the route didn't understand its part of the onion",
+            TemporaryNodeFailure => "a node on the route is temporarily unable to forward",
+            PermanentNodeFailure => "a node on the route can no longer forward",
+            RequiredNodeFeatureMissing => "a node on the route requires a feature we lack",
+            InvalidOnionVersion { .. } => "a node on the route didn't know the onion's version",
+            InvalidOnionHmac { .. } => "a node on the route found the onion tampered with",
+            InvalidOnionKey { .. } => "a node on the route couldn't use the onion's key",
+            TemporaryChannelFailure { .. } => {
+                "a channel on the route can't carry the payment right now, e.g. lacking liquidity"
+            }
+            PermanentChannelFailure => "a channel on the route is closed or closing",
+            RequiredChannelFeatureMissing => "a channel on the route requires a feature we lack",
+            UnknownNextPeer => "a node on the route doesn't have the next channel",
+            AmountBelowMinimum { .. } => "the amount is below a channel's minimum",
+            FeeInsufficient { .. } => "the fee is too low for a channel on the route",
+            IncorrectCltvExpiry { .. } => "the expiry doesn't match a channel's delta",
+            ExpiryTooSoon { .. } => "the payment would expire too soon",
+            IncorrectOrUnknownPaymentDetails { .. } => {
+                "the recipient doesn't know the payment hash, or the amount or expiry is wrong"
+            }
+            FinalIncorrectCltvExpiry { .. } => "the expiry the recipient got is wrong",
+            FinalIncorrectHtlcAmount { .. } => "the amount the recipient got is wrong",
+            ChannelDisabled { .. } => "a channel on the route is disabled",
+            ExpiryTooFar => "the payment's expiry is too far in the future",
+            InvalidOnionPayload { .. } => "a node on the route found its onion payload invalid",
+            MppTimeout => "the recipient didn't receive every part of the payment in time",
+            InvalidOnionBlinding { .. } => "a node in a blinded path couldn't forward",
+            Unknown { .. } => "a failure the spec doesn't define",
+        }
+    }
+
+    /// Retrying the same route won't help: the `PERM` bit is set.
+    pub fn is_permanent(&self) -> bool {
+        self.code() & PERM != 0
+    }
+
+    /// The erring node failed as a whole rather than one of its channels.
+    pub fn is_node(&self) -> bool {
+        self.code() & NODE != 0
+    }
+
+    /// A node couldn't parse the onion. The failure then comes from the node before it.
+    pub fn is_bad_onion(&self) -> bool {
+        self.code() & BADONION != 0
+    }
+
+    /// The `channel_update` of the failing channel, if the node sent one.
+    pub fn channel_update(&self) -> Option<&[u8]> {
+        use OnionFailure::*;
+
+        match self {
+            TemporaryChannelFailure { channel_update }
+            | AmountBelowMinimum { channel_update, .. }
+            | FeeInsufficient { channel_update, .. }
+            | IncorrectCltvExpiry { channel_update, .. }
+            | ExpiryTooSoon { channel_update }
+            | ChannelDisabled { channel_update, .. } => channel_update.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for OnionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:#06x}): {}",
+            self.name(),
+            self.code(),
+            self.description()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_decode_with_their_fields() {
+        let mut data = 1000u64.to_be_bytes().to_vec();
+        data.extend_from_slice(&800_000u32.to_be_bytes());
+        let failure = decode_failure(PERM | 15, &data).unwrap();
+        assert_eq!(
+            failure,
+            OnionFailure::IncorrectOrUnknownPaymentDetails {
+                htlc_msat: 1000,
+                height: 800_000
+            }
+        );
+        assert!(failure.is_permanent() && !failure.is_node());
+        assert_eq!(
+            failure.to_string(),
+            "incorrect_or_unknown_payment_details (0x400f): the recipient doesn't know the \
+             payment hash, or the amount or expiry is wrong"
+        );
+
+        // fee_insufficient: htlc_msat, then the length-prefixed update, then ignored extras
+        let mut data = 5000u64.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0, 3, 0x01, 0x02, 0x03, 0xff]);
+        let failure = decode_failure(UPDATE | 12, &data).unwrap();
+        assert_eq!(failure.channel_update(), Some(&[1, 2, 3][..]));
+        assert!(!failure.is_permanent());
+
+        let mut msg = (PERM | 22).to_be_bytes().to_vec();
+        msg.extend_from_slice(&[0xfd, 0x01, 0x00, 0x00, 0x2a]);
+        assert_eq!(
+            decode_failure_message(&msg).unwrap(),
+            OnionFailure::InvalidOnionPayload {
+                tlv_type: 256,
+                offset: 42
+            }
+        );
+    }
+
+    #[test]
+    fn updates_may_be_left_out_and_short_data_fails() {
+        let failure = decode_failure(UPDATE | 7, &[]).unwrap();
+        assert_eq!(failure.channel_update(), None);
+        assert_eq!(
+            decode_failure(UPDATE | 7, &[0, 0]).unwrap(),
+            OnionFailure::TemporaryChannelFailure {
+                channel_update: None
+            }
+        );
+        assert_eq!(
+            decode_failure(UPDATE | 7, &[0, 5, 1]),
+            Err(DecodeError::ShortRead)
+        );
+        assert_eq!(
+            decode_failure(BADONION | PERM | 5, &[0; 31]),
+            Err(DecodeError::ShortRead)
+        );
+        assert_eq!(decode_failure_message(&[0x40]), Err(DecodeError::ShortRead));
+    }
+
+    #[test]
+    fn every_code_round_trips() {
+        let data = [0u8; 64];
+        for code in (0..=0xffffu16).filter(|code| code & 0x0fff <= 30) {
+            let failure = decode_failure(code, &data).unwrap();
+            assert_eq!(failure.code(), code);
+            let known = !matches!(failure, OnionFailure::Unknown { .. });
+            assert_eq!(known, failure.name() != "unknown", "{code:#06x}");
+        }
+    }
+}
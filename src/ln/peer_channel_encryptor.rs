@@ -9,8 +9,9 @@
 
 //use crate::prelude::*;
 
-use crate::ln::msgs;
-use crate::ln::msgs::LightningError;
+#[cfg(feature = "std")]
+use crate::ln::msgs::{self, LightningError};
+#[cfg(feature = "std")]
 use crate::ln::wire;
 
 use bitcoin::hashes::sha256::Hash as Sha256;
@@ -22,7 +23,15 @@ use bitcoin::secp256k1::{self, PublicKey, Secp256k1, SecretKey, Signing, ecdh::S
 
 use crate::crypto::chacha20poly1305rfc::ChaCha20Poly1305RFC;
 use crate::crypto::utils::hkdf_extract_expand_twice;
-use crate::util::ser::{VecWriter, Writeable};
+#[cfg(feature = "std")]
+use crate::util::ser::{Readable, VecWriter, Writeable, Writer};
+
+use alloc::borrow::ToOwned;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io;
 
 /// Maximum Lightning message data length according to
 /// [BOLT-8](https://github.com/lightning/bolts/blob/v1.0/08-transport.md#lightning-message-specification)
@@ -44,6 +53,34 @@ const NOISE_H: [u8; 32] = [
     0x4b, 0xb4, 0x20, 0xd8, 0x9d, 0x2a, 0x04, 0x8a, 0x3c, 0x4f, 0x4c, 0x09, 0x2e, 0x37, 0xb6, 0x76,
 ];
 
+/// Why a handshake or message failed. The peer has to be disconnected either way; with `std`
+/// this converts into a `LightningError` saying so.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NoiseError {
+    pub err: String,
+}
+
+impl core::fmt::Display for NoiseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<NoiseError> for LightningError {
+    fn from(err: NoiseError) -> Self {
+        LightningError {
+            err: err.err,
+            action: msgs::ErrorAction::DisconnectPeer { msg: None },
+        }
+    }
+}
+
+/// An error after which the peer has to be disconnected.
+fn disconnect(err: String) -> NoiseError {
+    NoiseError { err }
+}
+
 #[derive(PartialEq)]
 enum NoiseStep {
     PreActOne,
@@ -82,6 +119,42 @@ enum NoiseState {
     },
 }
 
+/// The send/receive keys, nonces and chaining keys of a finished session.
+pub(crate) struct CipherState {
+    pub(crate) sk: [u8; 32],
+    pub(crate) sn: u64,
+    pub(crate) sck: [u8; 32],
+    pub(crate) rk: [u8; 32],
+    pub(crate) rn: u64,
+    pub(crate) rck: [u8; 32],
+}
+
+#[cfg(feature = "std")]
+impl Writeable for CipherState {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.sk.write(w)?;
+        self.sn.write(w)?;
+        self.sck.write(w)?;
+        self.rk.write(w)?;
+        self.rn.write(w)?;
+        self.rck.write(w)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Readable for CipherState {
+    fn read<R: io::Read>(r: &mut R) -> Result<Self, msgs::DecodeError> {
+        Ok(CipherState {
+            sk: Readable::read(r)?,
+            sn: Readable::read(r)?,
+            sck: Readable::read(r)?,
+            rk: Readable::read(r)?,
+            rn: Readable::read(r)?,
+            rck: Readable::read(r)?,
+        })
+    }
+}
+
 pub struct PeerChannelEncryptor {
     their_node_id: Option<PublicKey>, // filled in for outbound, or inbound after noise_state is Finished
 
@@ -143,17 +216,14 @@ impl PeerChannelEncryptor {
         n: u64,
         key: &[u8; 32],
         h: &[u8],
-    ) -> Result<(), LightningError> {
+    ) -> Result<(), NoiseError> {
         let mut nonce = [0; 12];
         nonce[4..].copy_from_slice(&n.to_le_bytes()[..]);
 
         let mut chacha = ChaCha20Poly1305RFC::new(key, &nonce, h);
         let (inout, tag) = inout.split_at_mut(inout.len() - 16);
         if chacha.check_decrypt_in_place(inout, tag).is_err() {
-            return Err(LightningError {
-                err: "Bad MAC".to_owned(),
-                action: msgs::ErrorAction::DisconnectPeer { msg: None },
-            });
+            return Err(disconnect("Bad MAC".to_owned()));
         }
         Ok(())
     }
@@ -165,7 +235,7 @@ impl PeerChannelEncryptor {
         key: &[u8; 32],
         h: &[u8],
         cyphertext: &[u8],
-    ) -> Result<(), LightningError> {
+    ) -> Result<(), NoiseError> {
         let mut nonce = [0; 12];
         nonce[4..].copy_from_slice(&n.to_le_bytes()[..]);
 
@@ -187,10 +257,7 @@ impl PeerChannelEncryptor {
             )
             .is_err()
         {
-            return Err(LightningError {
-                err: "Bad MAC".to_owned(),
-                action: msgs::ErrorAction::DisconnectPeer { msg: None },
-            });
+            return Err(disconnect("Bad MAC".to_owned()));
         }
         //println!("ok! {}", hex::encode(res));
         Ok(())
@@ -237,22 +304,22 @@ impl PeerChannelEncryptor {
         state: &mut BidirectionalNoiseState,
         act: &[u8],
         secret_key: &SecretKey,
-    ) -> Result<(PublicKey, [u8; 32]), LightningError> {
+    ) -> Result<(PublicKey, [u8; 32]), NoiseError> {
         assert_eq!(act.len(), 50);
 
         if act[0] != 0 {
-            return Err(LightningError {
-                err: format!("Unknown handshake version number {}", act[0]),
-                action: msgs::ErrorAction::DisconnectPeer { msg: None },
-            });
+            return Err(disconnect(format!(
+                "Unknown handshake version number {}",
+                act[0]
+            )));
         }
 
         let their_pub = match PublicKey::from_slice(&act[1..34]) {
             Err(_) => {
-                return Err(LightningError {
-                    err: format!("Invalid public key {}", &act[1..34].as_hex()),
-                    action: msgs::ErrorAction::DisconnectPeer { msg: None },
-                });
+                return Err(disconnect(format!(
+                    "Invalid public key {}",
+                    &act[1..34].as_hex()
+                )));
             }
             Ok(key) => key,
         };
@@ -359,7 +426,7 @@ impl PeerChannelEncryptor {
         secp_ctx: &Secp256k1<C>,
         act_two: &[u8; 50],
         node_signer: &SecretKey,
-    ) -> Result<[u8; 66], LightningError> {
+    ) -> Result<[u8; 66], NoiseError> {
         let final_hkdf;
         let ck;
         let res: [u8; 66] = match self.noise_state {
@@ -513,7 +580,7 @@ impl PeerChannelEncryptor {
     ///
     /// For effeciency, the [`Vec::capacity`] should be at least 16 bytes larger than the
     /// [`Vec::len`], to avoid reallocating for the message MAC, which will be appended to the vec.
-    fn encrypt_message_with_header_0s(&mut self, msgbuf: &mut Vec<u8>) {
+    pub(crate) fn encrypt_message_with_header_0s(&mut self, msgbuf: &mut Vec<u8>) {
         let msg_len = msgbuf.len() - 16 - 2;
         if msg_len > LN_MAX_MSG_LEN {
             panic!("Attempted to encrypt message longer than 65535 bytes!");
@@ -560,9 +627,23 @@ impl PeerChannelEncryptor {
     }
     */
 
+    /// Encrypts an encoded message (its two byte type, then the payload), returning the
+    /// encrypted length header followed by the encrypted message. This is what to use without
+    /// `std`, where messages are encoded by hand.
+    ///
+    /// Panics if `msg` is longer than 65535 bytes or if the Noise handshake has not finished.
+    pub fn encrypt_buffer(&mut self, msg: &[u8]) -> Vec<u8> {
+        let mut res = Vec::with_capacity(16 + 2 + msg.len() + 16);
+        res.resize(16 + 2, 0);
+        res.extend_from_slice(msg);
+        self.encrypt_message_with_header_0s(&mut res);
+        res
+    }
+
     /// Encrypts the given message, returning the encrypted version.
     /// panics if the length of `message`, once encoded, is greater than 65535 or if the Noise
     /// handshake has not finished.
+    #[cfg(feature = "std")]
     pub fn encrypt_message<M: wire::Type + Writeable>(&mut self, message: &M) -> Vec<u8> {
         // Allocate a buffer with 2KB, fitting most common messages. Reserve the first 16+2 bytes
         // for the 2-byte message type prefix and its MAC.
@@ -576,7 +657,7 @@ impl PeerChannelEncryptor {
 
     /// Decrypts a message length header from the remote peer.
     /// panics if noise handshake has not yet finished or msg.len() != 18
-    pub fn decrypt_length_header(&mut self, msg: &[u8; 18]) -> Result<u16, LightningError> {
+    pub fn decrypt_length_header(&mut self, msg: &[u8; 18]) -> Result<u16, NoiseError> {
         match self.noise_state {
             NoiseState::Finished {
                 sk: _,
@@ -605,10 +686,10 @@ impl PeerChannelEncryptor {
     /// Decrypts the given message up to msg.len() - 16. Bytes after msg.len() - 16 will be left
     /// undefined (as they contain the Poly1305 tag bytes).
     ///
-    /// panics if msg.len() > 65535 + 16
-    pub fn decrypt_message(&mut self, msg: &mut [u8]) -> Result<(), LightningError> {
-        if msg.len() > LN_MAX_MSG_LEN + 16 {
-            panic!("Attempted to decrypt message longer than 65535 + 16 bytes!");
+    /// Fails without decrypting if msg.len() is below 16 or above 65535 + 16.
+    pub fn decrypt_message(&mut self, msg: &mut [u8]) -> Result<(), NoiseError> {
+        if msg.len() < 16 || msg.len() > LN_MAX_MSG_LEN + 16 {
+            return Err(disconnect(format!("Invalid message length {}", msg.len())));
         }
 
         match self.noise_state {
@@ -628,6 +709,71 @@ impl PeerChannelEncryptor {
         }
     }
 
+    /// The transport keys and nonces of a finished session, or `None` mid-handshake.
+    pub(crate) fn cipher_state(&self) -> Option<CipherState> {
+        match self.noise_state {
+            NoiseState::Finished {
+                sk,
+                sn,
+                sck,
+                rk,
+                rn,
+                rck,
+            } => Some(CipherState {
+                sk,
+                sn,
+                sck,
+                rk,
+                rn,
+                rck,
+            }),
+            NoiseState::InProgress { .. } => None,
+        }
+    }
+
+    /// Resumes a finished session from a [`CipherState`] taken with
+    /// [`PeerChannelEncryptor::cipher_state`].
+    pub(crate) fn from_cipher_state(their_node_id: PublicKey, state: CipherState) -> Self {
+        PeerChannelEncryptor {
+            their_node_id: Some(their_node_id),
+            noise_state: NoiseState::Finished {
+                sk: state.sk,
+                sn: state.sn,
+                sck: state.sck,
+                rk: state.rk,
+                rn: state.rn,
+                rck: state.rck,
+            },
+        }
+    }
+
+    /// Returns an encryptor for the other end of this (finished) session: its send keys are our
+    /// receive keys and vice versa. Lets tests decrypt what we encrypt without a responder.
+    #[cfg(test)]
+    pub(crate) fn mirrored(&self) -> PeerChannelEncryptor {
+        match self.noise_state {
+            NoiseState::Finished {
+                sk,
+                sn,
+                sck,
+                rk,
+                rn,
+                rck,
+            } => PeerChannelEncryptor {
+                their_node_id: None,
+                noise_state: NoiseState::Finished {
+                    sk: rk,
+                    sn: rn,
+                    sck: rck,
+                    rk: sk,
+                    rn: sn,
+                    rck: sck,
+                },
+            },
+            _ => panic!("Tried to mirror a session prior to noise handshake completion"),
+        }
+    }
+
     /*
     //TODO: inbound
     pub fn is_ready_for_encryption(&self) -> bool {
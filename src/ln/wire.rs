@@ -14,7 +14,9 @@
 
 use crate::ln::msgs;
 use crate::util::ser::{LengthLimitedRead, LengthReadable, Readable, Writeable, Writer};
+use std::fmt;
 use std::io;
+use std::ops::RangeInclusive;
 
 // TestEq is a dummy trait which requires PartialEq when built in testing, and otherwise is
 // blanket-implemented for all types.
@@ -22,7 +24,7 @@ use std::io;
 /// A Lightning message returned by [`read`] when decoding bytes received over the wire. Each
 /// variant contains a message from [`msgs`] or otherwise the message type if unknown.
 #[allow(missing_docs)]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Message<T> {
     Init(msgs::Init),
     Error(msgs::ErrorMessage),
@@ -87,10 +89,14 @@ where
 {
     let message_type = <u16 as Readable>::read(buffer).map_err(|e| (e, None))?;
     //println!("message_type {}", message_type);
-    do_read(buffer, message_type, custom_reader).map_err(|e| (e, Some(message_type)))
+    read_payload(buffer, message_type, custom_reader).map_err(|e| (e, Some(message_type)))
 }
 
-fn do_read<T, R>(
+/// Decodes a message payload whose 2-byte type has already been consumed, e.g. one returned by
+/// [`LNSocket::read_raw`].
+///
+/// [`LNSocket::read_raw`]: crate::LNSocket::read_raw
+pub fn read_payload<T, R>(
     buffer: &mut R,
     message_type: u16,
     custom_reader: impl FnOnce(u16, &mut R) -> Result<Option<T>, msgs::DecodeError>,
@@ -126,6 +132,179 @@ where
     }
 }
 
+/// The message types [`read_payload`] decodes itself. No [`TypeRegistry`] entry may claim them.
+const BUILTIN_TYPES: [u16; 5] = [
+    msgs::WarningMessage::TYPE,
+    msgs::Init::TYPE,
+    msgs::ErrorMessage::TYPE,
+    msgs::Ping::TYPE,
+    msgs::Pong::TYPE,
+];
+
+type BoxedReader<T> =
+    Box<dyn Fn(u16, &mut dyn LengthLimitedRead) -> Result<T, msgs::DecodeError> + Send + Sync>;
+
+/// Decodes the custom messages of a protocol, for [`read`], [`read_payload`] and
+/// [`LNSocket::read_with`](crate::LNSocket::read_with).
+///
+/// `buffer` holds exactly the message body after the type, and its
+/// [`remaining_bytes`](LengthLimitedRead::remaining_bytes) says how much of it is left, so a
+/// trailing field or TLV stream can be read to the end of the message. The closures taken by
+/// [`read`] and [`LNSocket::read_custom`](crate::LNSocket::read_custom) get the same kind of
+/// reader.
+pub trait CustomMessageReader {
+    type CustomMessage: core::fmt::Debug;
+
+    /// Decode a message of type `message_type`, or return `Ok(None)` if the type isn't one of
+    /// this reader's.
+    fn read<R: LengthLimitedRead>(
+        &self,
+        message_type: u16,
+        buffer: &mut R,
+    ) -> Result<Option<Self::CustomMessage>, msgs::DecodeError>;
+}
+
+/// Routes custom message types to the reader that owns them.
+///
+/// With several protocols attached to one connection, a single custom reader has to try each
+/// of them in turn, and a sloppy one can claim another's types. Instead, register every
+/// reader with the exact range of types it owns; overlapping ranges are refused up front.
+/// Use [`TypeRegistry::read`] as the custom reader of [`read`] or
+/// [`LNSocket::read_custom`](crate::LNSocket::read_custom):
+///
+/// ```
+/// use lnsocket::ln::wire::{self, Message, TypeRegistry};
+/// use std::io::Read;
+///
+/// let mut registry = TypeRegistry::new();
+/// registry
+///     .register(0x8000..=0x80ff, |type_id, r| {
+///         let mut body = Vec::new();
+///         r.read_to_end(&mut body)?;
+///         Ok((type_id, body))
+///     })
+///     .unwrap();
+/// // types owned by someone else, or by BOLT 1 itself, are refused
+/// assert!(registry.register(0x80f0..=0x8100, |t, _| Ok((t, vec![]))).is_err());
+/// assert!(registry.register(16..=16, |t, _| Ok((t, vec![]))).is_err());
+///
+/// let msg = wire::read_payload(&mut &[1u8, 2][..], 0x8001, |t, r| registry.read(t, r)).unwrap();
+/// assert!(matches!(msg, Message::Custom((0x8001, body)) if body == [1, 2]));
+/// ```
+pub struct TypeRegistry<T> {
+    /// Sorted by range start, never overlapping.
+    entries: Vec<(RangeInclusive<u16>, BoxedReader<T>)>,
+}
+
+/// A [`TypeRegistry::register`] range overlapped types that are already owned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeConflict {
+    /// The range that was being registered.
+    pub types: RangeInclusive<u16>,
+    /// The range it overlaps with.
+    pub owned: RangeInclusive<u16>,
+}
+
+impl fmt::Display for TypeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "message types {}..={} overlap already owned types {}..={}",
+            self.types.start(),
+            self.types.end(),
+            self.owned.start(),
+            self.owned.end()
+        )
+    }
+}
+
+impl<T> Default for TypeRegistry<T> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> TypeRegistry<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hand every message whose type is in `types` to `reader`. Fails if any of the types is
+    /// one of the BOLT 1 messages decoded by [`read_payload`] or is already registered.
+    ///
+    /// `reader` gets the message body as a [`LengthLimitedRead`]; pass it on to [`Readable`]s
+    /// as `&mut r`.
+    pub fn register(
+        &mut self,
+        types: RangeInclusive<u16>,
+        reader: impl Fn(u16, &mut dyn LengthLimitedRead) -> Result<T, msgs::DecodeError>
+        + Send
+        + Sync
+        + 'static,
+    ) -> Result<(), TypeConflict> {
+        if let Some(&builtin) = BUILTIN_TYPES.iter().find(|t| types.contains(t)) {
+            return Err(TypeConflict {
+                types,
+                owned: builtin..=builtin,
+            });
+        }
+        let at = self
+            .entries
+            .partition_point(|(owned, _)| owned.start() < types.start());
+        let neighbours = self.entries[at.saturating_sub(1)..].iter().take(2);
+        for (owned, _) in neighbours {
+            if owned.start() <= types.end() && types.start() <= owned.end() {
+                return Err(TypeConflict {
+                    types,
+                    owned: owned.clone(),
+                });
+            }
+        }
+        self.entries.insert(at, (types, Box::new(reader)));
+        Ok(())
+    }
+
+    /// The range registered for `type_id`, if any.
+    pub fn owner(&self, type_id: u16) -> Option<&RangeInclusive<u16>> {
+        self.find(type_id).map(|(types, _)| types)
+    }
+
+    /// Decode a message with the reader owning `type_id`, or `Ok(None)` if there is none.
+    /// Has the signature of the custom reader of [`read`] and [`read_payload`].
+    pub fn read<R: LengthLimitedRead>(
+        &self,
+        type_id: u16,
+        r: &mut R,
+    ) -> Result<Option<T>, msgs::DecodeError> {
+        match self.find(type_id) {
+            Some((_, reader)) => reader(type_id, r).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn find(&self, type_id: u16) -> Option<&(RangeInclusive<u16>, BoxedReader<T>)> {
+        let at = self
+            .entries
+            .partition_point(|(types, _)| *types.start() <= type_id);
+        let entry = self.entries.get(at.checked_sub(1)?)?;
+        entry.0.contains(&type_id).then_some(entry)
+    }
+}
+
+impl<T: core::fmt::Debug> CustomMessageReader for TypeRegistry<T> {
+    type CustomMessage = T;
+
+    fn read<R: LengthLimitedRead>(
+        &self,
+        message_type: u16,
+        buffer: &mut R,
+    ) -> Result<Option<T>, msgs::DecodeError> {
+        TypeRegistry::read(self, message_type, buffer)
+    }
+}
+
 /// Writes a message to the data buffer encoded as a 2-byte big-endian type and a variable-length
 /// payload.
 ///
@@ -140,6 +319,12 @@ pub(crate) fn write<M: Type + Writeable, W: Writer>(
     message.write(buffer)
 }
 
+/// The length of `message` once encoded by [`write`]: its 2-byte type and payload. Measured
+/// without allocating, see [`Writeable::serialized_length`].
+pub fn encoded_len<M: Type + Writeable>(message: &M) -> usize {
+    2 + message.serialized_length()
+}
+
 mod encode {
     /// Defines a constant type identifier for reading messages from the wire.
     pub trait Encode {
@@ -189,3 +374,105 @@ impl Encode for msgs::Ping {
 impl Encode for msgs::Pong {
     const TYPE: u16 = 19;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::ser::VecWriter;
+    use std::io::Read;
+
+    #[test]
+    fn read_payload_matches_read() {
+        let ping = msgs::Ping {
+            ponglen: 4,
+            byteslen: 8,
+        };
+        let mut buf = VecWriter(Vec::new());
+        write(&ping, &mut buf).unwrap();
+
+        let full = read::<(), _>(&mut &buf.0[..], |_, _| Ok(None)).unwrap();
+        let payload =
+            read_payload::<(), _>(&mut &buf.0[2..], msgs::Ping::TYPE, |_, _| Ok(None)).unwrap();
+
+        match (full, payload) {
+            (Message::Ping(a), Message::Ping(b)) => {
+                assert_eq!(a, ping);
+                assert_eq!(b, ping);
+            }
+            other => panic!("expected pings, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn registry_routes_by_range_and_refuses_overlaps() {
+        let mut registry = TypeRegistry::new();
+        registry.register(0x8000..=0x800f, |_, _| Ok("a")).unwrap();
+        registry.register(0x8020..=0x802f, |_, _| Ok("b")).unwrap();
+        // fits exactly in the gap
+        registry.register(0x8010..=0x801f, |_, _| Ok("c")).unwrap();
+
+        for overlapping in [
+            0x7fff..=0x8000,
+            0x800f..=0x8010,
+            0x8025..=0x8025,
+            0..=u16::MAX,
+        ] {
+            assert!(registry.register(overlapping, |_, _| Ok("x")).is_err());
+        }
+        assert_eq!(
+            registry.register(0..=10, |_, _| Ok("x")),
+            Err(TypeConflict {
+                types: 0..=10,
+                owned: 1..=1
+            })
+        );
+
+        let read = |type_id| registry.read(type_id, &mut &[][..]).unwrap();
+        assert_eq!(read(0x8000), Some("a"));
+        assert_eq!(read(0x801f), Some("c"));
+        assert_eq!(read(0x802f), Some("b"));
+        assert_eq!(read(0x8030), None);
+        assert_eq!(read(0x7fff), None);
+        assert_eq!(registry.owner(0x8015), Some(&(0x8010..=0x801f)));
+    }
+
+    #[test]
+    fn custom_readers_know_the_body_length() {
+        // a u16, then opaque bytes to the end of the message
+        let mut registry = TypeRegistry::new();
+        registry
+            .register(0x8000..=0x8000, |_, mut r| {
+                let id = u16::read(&mut r)?;
+                let mut rest = vec![0; r.remaining_bytes() as usize];
+                r.read_exact(&mut rest)?;
+                Ok((id, rest))
+            })
+            .unwrap();
+
+        let payload = [0, 7, 1, 2, 3];
+        let msg = read_payload(&mut io::Cursor::new(&payload[..]), 0x8000, |t, r| {
+            CustomMessageReader::read(&registry, t, r)
+        })
+        .unwrap();
+        assert!(matches!(msg, Message::Custom((7, rest)) if rest == [1, 2, 3]));
+    }
+
+    #[test]
+    fn read_payload_hands_unknown_types_to_custom_reader() {
+        let payload = [1u8, 2, 3];
+        let msg = read_payload(&mut &payload[..], 0x8001, |typ, r| {
+            let mut rest = Vec::new();
+            r.read_to_end(&mut rest)?;
+            Ok(Some((typ, rest)))
+        })
+        .unwrap();
+
+        match msg {
+            Message::Custom((typ, rest)) => {
+                assert_eq!(typ, 0x8001);
+                assert_eq!(rest, vec![1, 2, 3]);
+            }
+            other => panic!("expected custom message, got {:?}", other),
+        }
+    }
+}
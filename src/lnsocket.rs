@@ -1,23 +1,163 @@
 use crate::{
     Error,
+    capabilities::CapabilityCache,
+    capture::{self, CaptureWriter, Direction, FrameInfo, SharedCapture},
+    clock::{Clock, SharedClock},
+    config::LNSocketConfig,
+    congestion::{Congestion, CongestionLevel, CongestionThresholds},
+    connect::{ConnectBuilder, ConnectProgress},
+    dial::{ConnectTrace, Dialer},
+    error::ConnectStage,
+    events::{EVENT_BUFFER, EventStream, FailureCause, SocketEvent},
+    fanout::{self, FanoutMessage},
     ln::{
+        features,
+        gossip_queries::{GossipQueryMessage, GossipTimestampFilter, QueryChannelRange},
         msgs::{self, DecodeError},
-        peer_channel_encryptor::PeerChannelEncryptor,
-        wire::{self, Message},
+        peer_channel_encryptor::{CipherState, LN_MAX_MSG_LEN, PeerChannelEncryptor},
+        types::ChannelId,
+        wire::{self, CustomMessageReader, Encode, Message},
     },
+    network::check_peer_networks,
+    ping::{MAX_PONGLEN, PingOutcome, PingPolicy, PingProbe, PingStats, ProbeWait},
+    privacy::{self, PaddingPolicy},
+    sender::{Frame, MessageSender, PendingWrite, Writer},
+    session::ExportedSession,
+    socket_addr::SocketAddress,
+    stats::{Quota, QuotaEvent, QuotaUsage, StatsHandle, StatsRecorder, WireStats},
+    stream::{ReadHalf, Stream},
+    transport::{self, ACT_TWO_SIZE, Handshake, LENGTH_HEADER_SIZE},
     util::ser::Writeable,
 };
-use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey, rand};
+use bitcoin::Network;
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::rand::{self, Rng};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use std::collections::VecDeque;
 use std::io::{self, Cursor};
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpSocket, TcpStream, lookup_host};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, watch};
 
-const ACT_TWO_SIZE: usize = 50;
+/// How many messages [`LNSocket::perform_init`] sets aside by default while waiting for the
+/// peer's `init`.
+pub const DEFAULT_PRE_INIT_LIMIT: usize = 16;
+
+/// How many messages [`LNSocket::ping_probe`] and [`LNSocket::write_and_confirm`] keep for
+/// later reads while they wait.
+pub const MAX_PROBE_BACKLOG: usize = 1024;
+
+/// Who sends `init` first, see [`LNSocket::set_init_order`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InitOrder {
+    /// Wait for the peer's `init` and answer it, as LDK does.
+    #[default]
+    PeerFirst,
+    /// Send our `init` as soon as the connection is up, then wait for theirs. Some
+    /// implementations, and bridges that replay stored gossip before `init`, only get to
+    /// theirs once they have seen ours.
+    OursFirst,
+}
 
 struct ReconnectData {
     our_key: SecretKey,
     their_pubkey: PublicKey,
     addr: String,
+    /// The address as given, before it was resolved.
+    host: String,
+    dialer: Dialer,
+    /// What [`LNSocket::connect_with_config`] was given, applied to reconnects too.
+    config: Option<Box<LNSocketConfig>>,
+    fallbacks: ReconnectAddrs,
+}
+
+/// Where [`LNSocket::reconnect_fresh`] goes when the address a socket was connected to no
+/// longer works, say after the node moved to a new IP. Get it with
+/// [`LNSocket::reconnect_addrs`].
+///
+/// The list is shared with the socket and every socket reconnected from it, so it can be
+/// updated while a [`CommandoClient`] owns the socket, e.g. with the addresses of a fresh
+/// `node_announcement`. Reconnects try the socket's address first, then these in order; when
+/// a fallback works, the new socket keeps it and the old address goes to the end of the list.
+///
+/// Note that the peer's `init` carries *our* address, as
+/// [`LNSocket::our_address_as_seen_by_peer`], not one of its own: it is no fallback.
+#[derive(Clone, Debug, Default)]
+pub struct ReconnectAddrs(Arc<Mutex<Vec<String>>>);
+
+impl ReconnectAddrs {
+    /// Replace the fallback addresses.
+    pub fn set(&self, addrs: impl IntoIterator<Item = impl Into<String>>) {
+        *self.0.lock().unwrap() = addrs.into_iter().map(Into::into).collect();
+    }
+
+    /// Add `addr` at the end, unless it is already there.
+    pub fn push(&self, addr: impl Into<String>) {
+        let addr = addr.into();
+        let mut addrs = self.0.lock().unwrap();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// How far [`LNSocket`] got reading the current frame.
+enum ReadState {
+    /// Reading the encrypted length header.
+    Header {
+        buf: [u8; LENGTH_HEADER_SIZE],
+        filled: usize,
+    },
+    /// The header is decrypted; reading the body and its MAC.
+    Body { buf: Vec<u8>, filled: usize },
+}
+
+impl Default for ReadState {
+    fn default() -> Self {
+        ReadState::Header {
+            buf: [0; LENGTH_HEADER_SIZE],
+            filled: 0,
+        }
+    }
+}
+
+impl ReadState {
+    /// Whether no part of a frame has been read.
+    fn is_idle(&self) -> bool {
+        matches!(self, ReadState::Header { filled: 0, .. })
+    }
+}
+
+/// Read into the rest of `buf`, past `filled`, keeping `filled` up to date after every read
+/// so the bytes aren't lost if this is dropped. Read failures are broadcast.
+async fn read_into(
+    stream: &mut ReadHalf,
+    events: &broadcast::Sender<SocketEvent>,
+    buf: &mut [u8],
+    filled: &mut usize,
+) -> Result<(), Error> {
+    while *filled < buf.len() {
+        let res = match stream.read(&mut buf[*filled..]).await {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            res => res,
+        };
+        match res {
+            Ok(n) => *filled += n,
+            Err(err) => {
+                let _ = events.send(SocketEvent::ReadFailed(FailureCause::of(err.kind())));
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(())
 }
 
 /// A Lightning Network TCP socket that performs the BOLT 8 Noise handshake and message encryption.
@@ -40,10 +180,38 @@ struct ReconnectData {
 /// ```
 ///
 /// ⚠️ This type does **not** do retries/keepalive; see [`CommandoClient`] if you want managed reconnects.
+///
+/// The `read*` methods are cancel safe: a read dropped halfway through a frame, say in a
+/// `select!`, keeps what it got in the socket and the next read carries on from there.
 pub struct LNSocket {
+    /// Only used for the receiving direction, the writer task owns the sending one.
     channel: PeerChannelEncryptor,
-    stream: TcpStream,
+    stream: ReadHalf,
+    /// The frame being read, so that a cancelled read loses nothing.
+    read_state: ReadState,
+    writer: Writer,
     reconnect: ReconnectData,
+    stats: Arc<Mutex<StatsRecorder>>,
+    capture: SharedCapture,
+    clock: SharedClock,
+    their_init: Option<msgs::Init>,
+    /// Whether [`LNSocket::send_init`] has sent our `init`.
+    sent_init: bool,
+    /// The peer's `init` when [`LNSocket::read_init`] got it ahead of ours, until
+    /// [`LNSocket::send_init`] completes the exchange.
+    pending_init: Option<msgs::Init>,
+    /// What came before that `init`, or before the one being read.
+    pending_early: Vec<(u16, Vec<u8>)>,
+    /// Messages received before the peer's `init`, handed out by the next reads.
+    inbox: VecDeque<(u16, Vec<u8>)>,
+    pre_init_limit: usize,
+    max_message_len: usize,
+    events: broadcast::Sender<SocketEvent>,
+    close_on_peer_error: bool,
+    answer_gossip_queries: bool,
+    capabilities: Option<CapabilityCache>,
+    /// The message of the peer's all-channels `error`, once it has sent one.
+    peer_closed: Option<String>,
 }
 
 impl LNSocket {
@@ -52,51 +220,199 @@ impl LNSocket {
     /// Resolves the given `addr`, establishes a TCP connection, and performs act1/act2/act3
     /// handshake using `our_key` and the peer’s public key.
     ///
+    /// `addr` is anything [`SocketAddress`](crate::socket_addr::SocketAddress) parses; the port
+    /// defaults to 9735. Onion addresses fail with `Error::OnionRequiresProxy`.
+    ///
     /// Does **not** send or expect an `init` message.  
     /// Use [`LNSocket::connect_and_init`] if you want handshake + `init` exchange.
+    ///
+    /// To restrict where connections may go, connect through a [`Dialer`] instead, or use
+    /// [`LNSocket::connect_with_config`] to set every option at once.
     pub async fn connect(
         our_key: SecretKey,
         their_pubkey: PublicKey,
         addr: &str,
     ) -> Result<LNSocket, Error> {
-        let secp_ctx = Secp256k1::signing_only();
-
-        // Look up host to resolve domain name to IP address
-        let addr = lookup_host(addr).await?.next().ok_or(Error::DnsError)?;
-
-        let socket = if addr.is_ipv4() {
-            TcpSocket::new_v4()?
-        } else {
-            TcpSocket::new_v6()?
-        };
+        Self::dial(Dialer::new(), our_key, their_pubkey, addr).await
+    }
 
-        let mut stream = socket.connect(addr).await?;
-        let ephemeral = SecretKey::new(&mut rand::thread_rng());
+    pub(crate) async fn dial(
+        dialer: Dialer,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<LNSocket, Error> {
+        Self::dial_traced(
+            dialer,
+            our_key,
+            their_pubkey,
+            addr,
+            &mut ConnectTrace::untraced(),
+        )
+        .await
+    }
 
-        let mut channel = PeerChannelEncryptor::new_outbound(their_pubkey, ephemeral);
-        let act_one = channel.get_act_one(&secp_ctx);
-        stream.write_all(&act_one).await?;
+    pub(crate) async fn dial_traced(
+        dialer: Dialer,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        trace: &mut ConnectTrace,
+    ) -> Result<LNSocket, Error> {
+        let jitter = dialer.privacy().jitter();
+        if !jitter.is_zero() {
+            tokio::time::sleep(jitter).await;
+        }
 
-        let mut act_two = [0u8; ACT_TWO_SIZE];
-        stream.read_exact(&mut act_two).await?;
-        let act_three = channel.process_act_two(&secp_ctx, &act_two, &our_key)?;
+        // Look up host to resolve domain name to IP address
+        trace.report(ConnectProgress::Resolving);
+        let target = trace
+            .stage(ConnectStage::Resolve, dialer.resolve(&their_pubkey, addr))
+            .await?;
 
-        // Finalize the handshake by sending act3
-        stream.write_all(&act_three).await?;
+        trace.report(ConnectProgress::Connecting);
+        let deadline = trace.deadline();
+        let stream = trace
+            .stage(ConnectStage::Tcp, dialer.open(&target, deadline))
+            .await?;
 
-        Ok(Self {
-            channel,
+        Self::handshake(
             stream,
-            reconnect: ReconnectData {
+            ReconnectData {
+                our_key,
+                their_pubkey,
+                addr: target.to_string(),
+                host: addr.to_string(),
+                dialer,
+                config: None,
+                fallbacks: ReconnectAddrs::default(),
+            },
+            trace,
+        )
+        .await
+    }
+
+    /// Perform only the BOLT 8 Noise handshake on a TCP stream that is already connected,
+    /// e.g. to the local end of an stunnel or ssh port forward, or a socket that had to be
+    /// set up by the application itself.
+    ///
+    /// Like [`LNSocket::connect`], no `init` is exchanged; follow up with
+    /// [`LNSocket::perform_init`]. [`LNSocket::reconnect_fresh`] dials the stream's peer
+    /// address again.
+    pub async fn from_stream(
+        stream: TcpStream,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+    ) -> Result<LNSocket, Error> {
+        let addr = stream.peer_addr()?;
+        Self::handshake(
+            stream.into(),
+            ReconnectData {
                 our_key,
                 their_pubkey,
                 addr: addr.to_string(),
+                host: addr.to_string(),
+                dialer: Dialer::new(),
+                config: None,
+                fallbacks: ReconnectAddrs::default(),
             },
-        })
+            &mut ConnectTrace::untraced(),
+        )
+        .await
+    }
+
+    /// Acts one to three over `stream` as the initiator.
+    async fn handshake(
+        mut stream: Stream,
+        reconnect: ReconnectData,
+        trace: &mut ConnectTrace,
+    ) -> Result<LNSocket, Error> {
+        let (handshake, act_one) = Handshake::new(reconnect.our_key, reconnect.their_pubkey);
+        trace
+            .stage(ConnectStage::ActOne, async {
+                Ok(stream.write_all(&act_one).await?)
+            })
+            .await?;
+        trace.report(ConnectProgress::Act1Sent);
+
+        let (transport, act_three) = trace
+            .stage(ConnectStage::ActTwo, async {
+                let mut act_two = [0u8; ACT_TWO_SIZE];
+                stream.read_exact(&mut act_two).await?;
+                handshake.process_act_two(&act_two)
+            })
+            .await?;
+        trace.report(ConnectProgress::Act2Received);
+
+        // Finalize the handshake by sending act3
+        trace
+            .stage(ConnectStage::ActThree, async {
+                Ok(stream.write_all(&act_three).await?)
+            })
+            .await?;
+
+        let padding = reconnect.dialer.privacy().padding;
+        let sock = Self::from_parts(transport.into_channel(), stream, reconnect, None);
+        if padding != PaddingPolicy::None {
+            sock.writer.set_padding(padding).await?;
+        }
+        Ok(sock)
+    }
+
+    /// Split a stream with a finished handshake into the read half we keep and the writer task.
+    fn from_parts(
+        channel: PeerChannelEncryptor,
+        stream: Stream,
+        reconnect: ReconnectData,
+        their_init: Option<msgs::Init>,
+    ) -> Self {
+        let cipher = channel
+            .cipher_state()
+            .expect("handshake must be finished before splitting the stream");
+        let send_channel = PeerChannelEncryptor::from_cipher_state(reconnect.their_pubkey, cipher);
+        let clock = SharedClock::default();
+        let stats = Arc::new(Mutex::new(StatsRecorder::new(clock.clone())));
+        let capture = SharedCapture::default();
+        let events = broadcast::channel(EVENT_BUFFER).0;
+        let (read_half, write_half) = stream.into_split();
+        let writer = Writer::spawn(
+            write_half,
+            send_channel,
+            stats.clone(),
+            capture.clone(),
+            events.clone(),
+        );
+        if let Some(init) = &their_init {
+            writer.gate().set_peer_init(init);
+        }
+
+        Self {
+            channel,
+            stream: read_half,
+            read_state: ReadState::default(),
+            writer,
+            reconnect,
+            stats,
+            capture,
+            clock,
+            their_init,
+            sent_init: false,
+            pending_init: None,
+            pending_early: Vec::new(),
+            inbox: VecDeque::new(),
+            pre_init_limit: DEFAULT_PRE_INIT_LIMIT,
+            max_message_len: LN_MAX_MSG_LEN,
+            events,
+            close_on_peer_error: true,
+            answer_gossip_queries: false,
+            capabilities: None,
+            peer_closed: None,
+        }
     }
 
     /// Connect as above and also perform a minimal `init` exchange.
-    /// Fails with `Error::FirstMessageNotInit` if the peer’s first message isn’t `Init`.
+    /// Fails with `Error::FirstMessageNotInit` if the peer sends too many other messages before
+    /// its `Init`, see [`LNSocket::perform_init`].
     pub async fn connect_and_init(
         our_key: SecretKey,
         their_pubkey: PublicKey,
@@ -107,78 +423,2272 @@ impl LNSocket {
         Ok(lnsocket)
     }
 
-    /// Build a brand-new socket using the stored reconnect inputs.
-    pub async fn reconnect_fresh(&self) -> Result<LNSocket, Error> {
-        LNSocket::connect_and_init(
-            self.reconnect.our_key,
-            self.reconnect.their_pubkey,
-            &self.reconnect.addr,
+    /// Connect with every option in `config`: through its dialer, within its timeout, with the
+    /// socket options set before the `init` exchange (if `config` asks for one). See the
+    /// [`config`](crate::config) module.
+    ///
+    /// The socket keeps `config` for [`LNSocket::reconnect_fresh`]; later `set_*` calls only
+    /// change this socket, except those that change its dialer.
+    pub async fn connect_with_config(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        config: &LNSocketConfig,
+    ) -> Result<LNSocket, Error> {
+        let trace = ConnectTrace::untraced_within(config.connect_timeout());
+        Self::connect_with_config_traced(our_key, their_pubkey, addr, config, trace).await
+    }
+
+    pub(crate) async fn connect_with_config_traced(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        config: &LNSocketConfig,
+        mut trace: ConnectTrace,
+    ) -> Result<LNSocket, Error> {
+        let dialer = config.dialer().clone();
+        dialer
+            .cancellable(async {
+                let mut lnsocket =
+                    Self::dial_traced(dialer.clone(), our_key, their_pubkey, addr, &mut trace)
+                        .await?;
+                config.apply(&mut lnsocket).await?;
+                lnsocket.reconnect.config = Some(Box::new(config.clone()));
+                if config.exchanges_init() {
+                    lnsocket.perform_init_traced(&mut trace).await?;
+                }
+                Ok(lnsocket)
+            })
+            .await
+    }
+
+    /// Connect step by step, with a callback told how far the connect got, see
+    /// [`ConnectBuilder`].
+    pub fn builder() -> ConnectBuilder {
+        ConnectBuilder::new()
+    }
+
+    /// [`LNSocket::connect_and_init`] reporting the stage a failure happened in, and how long
+    /// each stage took. See [`Dialer::connect_and_init_traced`].
+    pub async fn connect_and_init_traced(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        timeout: Option<Duration>,
+    ) -> Result<(LNSocket, crate::ConnectTimings), Error> {
+        Dialer::new()
+            .connect_and_init_traced(our_key, their_pubkey, addr, timeout)
+            .await
+    }
+
+    /// Our node id: the public key of the secret key this socket was connected with.
+    pub fn our_node_id(&self) -> PublicKey {
+        PublicKey::from_secret_key(
+            &bitcoin::secp256k1::Secp256k1::signing_only(),
+            &self.reconnect.our_key,
         )
-        .await
+    }
+
+    /// The node id of the peer this socket is connected to.
+    pub fn their_pubkey(&self) -> PublicKey {
+        self.reconnect.their_pubkey
+    }
+
+    /// The `init` message the peer sent us, once [`LNSocket::perform_init`] has completed.
+    pub fn their_init(&self) -> Option<&msgs::Init> {
+        self.their_init.as_ref()
+    }
+
+    /// Our address as the peer sees it, if it said so in its `init`. Behind NAT this is the
+    /// public address, which makes it a cheap way to find out what to advertise in our own
+    /// `node_announcement`. Peers may lie, so ask more than one before trusting it.
+    pub fn our_address_as_seen_by_peer(&self) -> Option<&SocketAddress> {
+        self.their_init
+            .as_ref()
+            .and_then(|init| init.remote_network_address.as_ref())
+    }
+
+    /// Whether the peer advertised the feature pair containing `bit` (required or optional)
+    /// in its `init`. Always `false` before the `init` exchange.
+    pub fn peer_supports_feature(&self, bit: usize) -> bool {
+        self.their_init.as_ref().is_some_and(|init| {
+            features::supports(&init.features, bit)
+                || features::supports(&init.global_features, bit)
+        })
+    }
+
+    /// Refuse to send even message types the peer did not negotiate, failing the write with
+    /// [`Error::FeatureNotNegotiated`] instead of risking a disconnect. Off by default.
+    ///
+    /// Odd types and the BOLT #1 messages are always sent. Other even types must be tied to a
+    /// feature bit with [`LNSocket::register_message_feature`]. Applies to every
+    /// [`MessageSender`] of this socket.
+    pub fn set_strict_features(&mut self, strict: bool) {
+        self.writer.gate().set_strict(strict);
+    }
+
+    /// Declare that the even message type `type_id` may only be sent to peers advertising the
+    /// feature pair containing `bit`. Only consulted in strict mode.
+    pub fn register_message_feature(&mut self, type_id: u16, bit: usize) {
+        self.writer.gate().register(type_id, bit);
+    }
+
+    /// A snapshot of the per-type message counts and size histogram for this connection.
+    pub fn stats(&self) -> WireStats {
+        self.stats.lock().unwrap().snapshot()
+    }
+
+    /// A handle to [`LNSocket::stats`] that can be kept without the socket.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle::new(&self.stats)
+    }
+
+    /// How strictly the [ping rules](crate::ping) are enforced on this connection, for pings
+    /// answered by [`LNSocket::answer_ping`] and pongs read from now on.
+    pub fn set_ping_policy(&mut self, policy: PingPolicy) {
+        self.writer.pings().set_policy(policy);
+    }
+
+    /// The pings and pongs that broke the [ping rules](crate::ping) on this connection, and
+    /// those that didn't.
+    pub fn ping_stats(&self) -> PingStats {
+        self.writer.pings().stats()
+    }
+
+    /// Subscribe to out-of-band events on this connection, such as warnings from the peer.
+    /// See [`crate::events`].
+    pub fn events(&self) -> EventStream {
+        EventStream::new(self.events.subscribe())
+    }
+
+    /// Periodically emit the wire stats as an `info` log line, checked on every read/write.
+    /// `None` (the default) disables the log line.
+    pub fn set_stats_log_interval(&mut self, interval: Option<Duration>) {
+        self.stats.lock().unwrap().set_log_interval(interval);
+    }
+
+    /// Cap the bytes `direction` carries per window, or lift the cap with `None`. Replacing a
+    /// quota starts a new window. See the [`stats`](crate::stats#quotas) module.
+    pub fn set_quota(&mut self, direction: Direction, quota: Option<Quota>) {
+        self.stats.lock().unwrap().set_quota(direction, quota);
+    }
+
+    /// Call `hook` when a quota reaches its warning threshold or its limit, replacing any hook
+    /// set before. It runs on whichever task did the read or write, keep it short.
+    pub fn on_quota(&mut self, hook: impl Fn(QuotaEvent) + Send + Sync + 'static) {
+        self.stats
+            .lock()
+            .unwrap()
+            .set_quota_hook(Some(Arc::new(hook)));
+    }
+
+    /// Where `direction` stands against its quota, if it has one.
+    pub fn quota_usage(&self, direction: Direction) -> Option<QuotaUsage> {
+        self.stats.lock().unwrap().quota_usage(direction)
+    }
+
+    /// Log every message read or written from now on, decrypted, to `writer`. See
+    /// [`crate::capture`]. Replaces any capture already running.
+    pub fn start_capture(&mut self, writer: CaptureWriter) {
+        self.capture.lock().unwrap().writer = Some(writer);
+    }
+
+    /// Stop capturing and flush what was captured. Messages queued by a [`MessageSender`]
+    /// but not yet written are not captured.
+    pub fn stop_capture(&mut self) -> Result<(), Error> {
+        match self.capture.lock().unwrap().writer.take() {
+            Some(mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Call `hook` with every message written from now on, as it is encrypted, replacing any
+    /// hook set before. Lighter than a capture for following a conversation: the hook sees the
+    /// type and size of each message but not its content. It runs on the writer task, keep it
+    /// short.
+    pub fn on_frame_sent(&mut self, hook: impl FnMut(&FrameInfo) + Send + 'static) {
+        self.capture.lock().unwrap().on_sent = Some(Box::new(hook));
+    }
+
+    /// Call `hook` with every message read from now on, as it is decrypted, replacing any hook
+    /// set before. See [`LNSocket::on_frame_sent`].
+    pub fn on_frame_received(&mut self, hook: impl FnMut(&FrameInfo) + Send + 'static) {
+        self.capture.lock().unwrap().on_received = Some(Box::new(hook));
+    }
+
+    /// Remove the hooks set with [`LNSocket::on_frame_sent`] and
+    /// [`LNSocket::on_frame_received`].
+    pub fn clear_frame_hooks(&mut self) {
+        let mut taps = self.capture.lock().unwrap();
+        taps.on_sent = None;
+        taps.on_received = None;
+    }
+
+    /// Build a brand-new socket using the stored reconnect inputs, through the same
+    /// [`Dialer`] (and so the same policy) this socket was made with. A socket from
+    /// [`LNSocket::connect_with_config`] reconnects with the same config, always with `init`.
+    ///
+    /// If this socket's address fails, the [fallback addresses](ReconnectAddrs) are tried in
+    /// order; the error is that of the last one.
+    pub async fn reconnect_fresh(&self) -> Result<LNSocket, Error> {
+        self.reconnect_fresh_as(self.reconnect.our_key).await
+    }
+
+    /// Like [`LNSocket::reconnect_fresh`], with `our_key` as our identity instead. The new
+    /// socket reconnects with `our_key` too.
+    pub async fn reconnect_fresh_as(&self, our_key: SecretKey) -> Result<LNSocket, Error> {
+        let fallbacks = self.reconnect.fallbacks.to_vec();
+        let addrs = std::iter::once(&self.reconnect.addr).chain(
+            fallbacks
+                .iter()
+                .filter(|addr| **addr != self.reconnect.addr),
+        );
+        let mut last_err = Error::NotConnected;
+        for (i, addr) in addrs.enumerate() {
+            match self.reconnect_to(our_key, addr).await {
+                Ok(mut sock) => {
+                    if i > 0 {
+                        self.reconnect.fallbacks.push(self.reconnect.addr.clone());
+                    }
+                    sock.reconnect.fallbacks = self.reconnect.fallbacks.clone();
+                    return Ok(sock);
+                }
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                Err(err) => {
+                    tracing::debug!("reconnecting to {addr} failed: {err}");
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn reconnect_to(&self, our_key: SecretKey, addr: &str) -> Result<LNSocket, Error> {
+        if let Some(config) = &self.reconnect.config {
+            let config = (**config)
+                .clone()
+                .with_dialer(self.reconnect.dialer.clone())
+                .with_init(true);
+            return Self::connect_with_config(our_key, self.reconnect.their_pubkey, addr, &config)
+                .await;
+        }
+        let mut sock = self
+            .reconnect
+            .dialer
+            .connect_and_init(our_key, self.reconnect.their_pubkey, addr)
+            .await?;
+        sock.set_capability_cache(self.capabilities.clone());
+        Ok(sock)
+    }
+
+    /// The addresses reconnects fall back to, see [`ReconnectAddrs`].
+    pub fn reconnect_addrs(&self) -> ReconnectAddrs {
+        self.reconnect.fallbacks.clone()
+    }
+
+    /// Split this socket into its serializable session state and the underlying TCP file
+    /// descriptor, so that another process can resume the connection with
+    /// [`LNSocket::import_session`].
+    ///
+    /// See the [`session`](crate::session) module docs for the security implications and
+    /// caveats; in particular the exported state contains the transport keys, though not our
+    /// node secret key.
+    ///
+    /// Messages already queued by [`MessageSender`]s are written first; senders fail with
+    /// `BrokenPipe` afterwards. A socket in a [TLS tunnel](crate::tls) fails with
+    /// `Error::Io(Unsupported)`, since it is gone by then. So does one that has read messages
+    /// it hasn't handed out yet, with `Error::Io(WouldBlock)`: read them first, and finish an
+    /// `init` exchange started with [`LNSocket::read_init`] with [`LNSocket::send_init`].
+    #[cfg(unix)]
+    pub async fn export_session(self) -> Result<(ExportedSession, OwnedFd), Error> {
+        let set_aside =
+            !self.inbox.is_empty() || self.pending_init.is_some() || !self.pending_early.is_empty();
+        if !self.read_state.is_idle() || set_aside {
+            // the start of the frame, or whole messages, are in our buffers, not in the socket
+            return Err(Error::Io(io::ErrorKind::WouldBlock));
+        }
+        let (write_half, send_channel) = self.writer.stop().await?;
+        let send = send_channel.cipher_state().ok_or(Error::NotConnected)?;
+        let recv = self.channel.cipher_state().ok_or(Error::NotConnected)?;
+        let cipher = CipherState {
+            sk: send.sk,
+            sn: send.sn,
+            sck: send.sck,
+            rk: recv.rk,
+            rn: recv.rn,
+            rck: recv.rck,
+        };
+
+        let stream = self.stream.reunite(write_half)?.into_std()?;
+        // the receiving process will set it back to non-blocking for tokio
+        stream.set_nonblocking(false)?;
+
+        let session = ExportedSession {
+            their_pubkey: self.reconnect.their_pubkey,
+            our_pubkey: PublicKey::from_secret_key(
+                &bitcoin::secp256k1::Secp256k1::signing_only(),
+                &self.reconnect.our_key,
+            ),
+            host: self.reconnect.host,
+            dialer: self.reconnect.dialer,
+            cipher,
+            their_init: self.their_init,
+        };
+        Ok((session, stream.into()))
+    }
+
+    /// Rebuild a socket from a session exported with [`LNSocket::export_session`], our node
+    /// secret key and the raw file descriptor of its TCP connection. Must be called from within
+    /// a tokio runtime. Fails with `Io(InvalidInput)` if `our_key` isn't the key of the
+    /// session's [`our_pubkey`](ExportedSession::our_pubkey).
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open TCP socket belonging to the exported connection, and ownership of
+    /// it passes to the returned [`LNSocket`]: nothing else may read, write or close it
+    /// afterwards. The process that exported the session must no longer use it.
+    #[cfg(unix)]
+    pub unsafe fn import_session(
+        session: ExportedSession,
+        our_key: SecretKey,
+        fd: RawFd,
+    ) -> Result<LNSocket, Error> {
+        if PublicKey::from_secret_key(&bitcoin::secp256k1::Secp256k1::signing_only(), &our_key)
+            != session.our_pubkey
+        {
+            return Err(Error::Io(io::ErrorKind::InvalidInput));
+        }
+        // SAFETY: the caller guarantees `fd` is an open socket that we now own.
+        let stream = std::net::TcpStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        stream.set_nonblocking(true)?;
+        let stream = TcpStream::from_std(stream)?;
+
+        Ok(Self::from_parts(
+            PeerChannelEncryptor::from_cipher_state(session.their_pubkey, session.cipher),
+            stream.into(),
+            ReconnectData {
+                our_key,
+                their_pubkey: session.their_pubkey,
+                addr: session.host.clone(),
+                host: session.host,
+                dialer: session.dialer,
+                config: None,
+                fallbacks: ReconnectAddrs::default(),
+            },
+            session.their_init,
+        ))
+    }
+
+    /// Whether an `error` from the peer with the all-zero channel id, after which BOLT 1
+    /// considers the connection dead, closes the socket (the default). Reads then fail with
+    /// [`Error::PeerClosedConnection`] carrying the peer's message, this one and every later
+    /// one, instead of waiting for a disconnect that may take a while. Turned off, such errors
+    /// are read as `Message::Error` like any other.
+    pub fn set_close_on_peer_error(&mut self, close: bool) {
+        self.close_on_peer_error = close;
+    }
+
+    /// Answer the peer's `query_channel_range` and `query_short_channel_ids` with the
+    /// [empty replies](crate::ln::gossip_queries) of a node that keeps no gossip, instead of
+    /// handing them to the reader. Off by default; for clients that only do RPC, talking to
+    /// peers that hang up when their queries go unanswered. A `gossip_timestamp_filter` needs
+    /// no answer, and gets the gossip we have: none.
+    pub fn set_answer_gossip_queries(&mut self, answer: bool) {
+        self.answer_gossip_queries = answer;
+    }
+
+    /// Record the peer's `init` in `cache` when it arrives, or right away if it already has.
+    pub fn set_capability_cache(&mut self, cache: Option<CapabilityCache>) {
+        if let (Some(cache), Some(init)) = (&cache, &self.their_init) {
+            cache.record(&self.their_pubkey(), init);
+        }
+        self.capabilities = cache;
+    }
+
+    /// How many other messages [`LNSocket::perform_init`] tolerates before the peer's `init`
+    /// (default [`DEFAULT_PRE_INIT_LIMIT`]). `0` restores the strict behaviour of failing when
+    /// the first message isn't `init`.
+    pub fn set_pre_init_limit(&mut self, limit: usize) {
+        self.pre_init_limit = limit;
+    }
+
+    /// Refuse messages longer than `len` bytes (type and payload), at most 65535, the default.
+    /// A read of a longer one fails with [`Error::MessageTooLarge`] as soon as its length header
+    /// is in, before anything is allocated for it; like any failed read that ends the
+    /// connection, since the rest of the stream can't be made sense of without reading it.
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.max_message_len = len.min(LN_MAX_MSG_LEN);
+    }
+
+    /// Expect the peer to be on `network` from the next `init` exchange on, including those
+    /// of reconnects. See [`Dialer::with_network`].
+    pub fn set_network(&mut self, network: Network) {
+        self.reconnect.dialer = self.reconnect.dialer.clone().with_network(network);
+    }
+
+    /// Like [`LNSocket::set_network`] for several networks, see [`Dialer::with_networks`].
+    pub fn set_networks(&mut self, networks: impl IntoIterator<Item = Network>) {
+        self.reconnect.dialer = self.reconnect.dialer.clone().with_networks(networks);
+    }
+
+    /// Who sends `init` first from the next `init` exchange on, including those of
+    /// reconnects. See [`Dialer::with_init_order`].
+    pub fn set_init_order(&mut self, order: InitOrder) {
+        self.reconnect.dialer = self.reconnect.dialer.clone().with_init_order(order);
     }
 
     /// Completes the initial `init` message exchange.
     ///
     /// This must be called before issuing any other Lightning messages.
-    /// Fails if the first incoming message isn’t `Init`.
+    ///
+    /// Some peers send a `ping` (or gossip) before their `init`. Up to
+    /// [`set_pre_init_limit`](LNSocket::set_pre_init_limit) such messages are set aside: pings
+    /// are answered once our own `init` has gone out, and everything else is returned by the
+    /// following reads, in order. Fails with `Error::FirstMessageNotInit` when the limit is
+    /// exceeded. Once `init` has been exchanged, any repeated `init` from the peer is dropped.
+    ///
+    /// With [`InitOrder::OursFirst`] our `init` is written before anything is read, and the
+    /// messages before theirs are set aside the same way. Raise the limit for peers that send
+    /// a whole gossip burst first.
     pub async fn perform_init(&mut self) -> Result<(), Error> {
-        // first message should be init, if not, we fail
-        if let Message::Init(init_msg) = self.read().await? {
-            // send some bs
-            self.write(&msgs::Init {
-                features: vec![0; 5],
-                global_features: vec![0; 2],
-                remote_network_address: None,
-                networks: init_msg.networks,
-            })
-            .await?;
+        self.perform_init_traced(&mut ConnectTrace::untraced())
+            .await
+    }
 
-            Ok(())
-        } else {
-            Err(Error::FirstMessageNotInit)
+    pub(crate) async fn perform_init_traced(
+        &mut self,
+        trace: &mut ConnectTrace,
+    ) -> Result<(), Error> {
+        self.exchange_init(trace).await?;
+        trace.report(ConnectProgress::InitExchanged);
+        Ok(())
+    }
+
+    async fn exchange_init(&mut self, trace: &mut ConnectTrace) -> Result<(), Error> {
+        let networks = self.reconnect.dialer.networks().to_vec();
+        match self.reconnect.dialer.init_order() {
+            InitOrder::PeerFirst => {
+                let (init_msg, early) = trace
+                    .stage(ConnectStage::InitRead, self.read_peer_init())
+                    .await?;
+                trace
+                    .stage(ConnectStage::InitWrite, async {
+                        check_peer_networks(&init_msg, &networks)?;
+                        let ours = self.our_init(transport::init_reply_on(&init_msg, &networks));
+                        self.write(&ours).await?;
+                        self.finish_init(init_msg, early).await
+                    })
+                    .await
+            }
+            InitOrder::OursFirst => {
+                trace
+                    .stage(ConnectStage::InitWrite, async {
+                        let ours = self.our_init(transport::init_first(&networks));
+                        self.write(&ours).await
+                    })
+                    .await?;
+                let (init_msg, early) = trace
+                    .stage(ConnectStage::InitRead, self.read_peer_init())
+                    .await?;
+                check_peer_networks(&init_msg, &networks)?;
+                self.finish_init(init_msg, early).await
+            }
         }
     }
 
-    pub async fn write<M: wire::Type + Writeable>(&mut self, m: &M) -> Result<(), io::Error> {
-        let msg = self.channel.encrypt_message(m);
-        self.stream.write_all(&msg).await?;
-        Ok(())
+    /// `init` as the dialer's privacy options want it encoded.
+    fn our_init(&self, mut init: msgs::Init) -> msgs::Init {
+        if self.reconnect.dialer.privacy().minimal_init {
+            privacy::minimize_init(&mut init);
+        }
+        init
     }
 
-    pub async fn read(&mut self) -> Result<Message<()>, Error> {
-        self.read_custom(|_type, _buf| Ok(None)).await
+    /// Send `init` exactly as given, for callers doing the `init` exchange themselves with
+    /// [`LNSocket::read_init`] instead of [`LNSocket::perform_init`]. Neither the network nor
+    /// the privacy options of the dialer are applied to it.
+    ///
+    /// If the peer's `init` has already been read, this completes the exchange: pings that
+    /// came before it are answered now.
+    pub async fn send_init(&mut self, init: &msgs::Init) -> Result<(), Error> {
+        self.write(init).await?;
+        self.sent_init = true;
+        match self.pending_init.take() {
+            Some(init_msg) => {
+                let early = std::mem::take(&mut self.pending_early);
+                self.finish_init(init_msg, early).await
+            }
+            None => Ok(()),
+        }
     }
 
-    pub async fn read_custom<T>(
+    /// Read until the peer's `init` and return it, the other half of [`LNSocket::send_init`].
+    /// Messages before it are set aside as [`LNSocket::perform_init`] does, and the network
+    /// set with [`LNSocket::set_network`] is checked.
+    ///
+    /// The exchange is complete, and [`LNSocket::their_init`] set, once both `init`s have
+    /// been through, in either order.
+    pub async fn read_init(&mut self) -> Result<msgs::Init, Error> {
+        let (init_msg, early) = self.read_peer_init().await?;
+        check_peer_networks(&init_msg, self.reconnect.dialer.networks())?;
+        if self.sent_init {
+            self.finish_init(init_msg.clone(), early).await?;
+        } else {
+            self.pending_init = Some(init_msg.clone());
+            self.pending_early = early;
+        }
+        Ok(init_msg)
+    }
+
+    /// Read until the peer's `init`, returning it and the messages received before it.
+    /// Those are kept in `pending_early` meanwhile, so a read cancelled halfway loses none.
+    async fn read_peer_init(&mut self) -> Result<(msgs::Init, Vec<(u16, Vec<u8>)>), Error> {
+        let init_msg = loop {
+            let (type_id, payload) = self.recv_raw().await?;
+            if type_id == msgs::Init::TYPE {
+                let mut cursor = io::Cursor::new(&payload[..]);
+                match wire::read_payload::<(), _>(&mut cursor, type_id, |_, _| Ok(None))? {
+                    Message::Init(init) => break init,
+                    _ => unreachable!("type 16 always decodes as init"),
+                }
+            }
+            if self.pending_early.len() >= self.pre_init_limit {
+                return Err(Error::FirstMessageNotInit);
+            }
+            tracing::debug!("type {type_id} received before init, setting it aside");
+            self.pending_early.push((type_id, payload));
+        };
+        Ok((init_msg, std::mem::take(&mut self.pending_early)))
+    }
+
+    /// Take the peer's `init` and deal with what arrived before it, once ours has been sent.
+    async fn finish_init(
         &mut self,
-        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
-    ) -> Result<Message<T>, Error>
-    where
-        T: core::fmt::Debug,
-    {
-        let mut hdr = [0u8; 18];
+        init_msg: msgs::Init,
+        early: Vec<(u16, Vec<u8>)>,
+    ) -> Result<(), Error> {
+        self.writer.gate().set_peer_init(&init_msg);
+        if let Some(cache) = &self.capabilities {
+            cache.record(&self.their_pubkey(), &init_msg);
+        }
+        self.their_init = Some(init_msg);
 
-        self.stream.read_exact(&mut hdr).await?;
-        let size = self.channel.decrypt_length_header(&hdr)? as usize;
-        //println!("len header {size}");
-        let mut buf = vec![0; size + 16];
-        self.stream.read_exact(&mut buf).await?;
-        //println!("got cipher bytes {}", hex::encode(&buf));
-        self.channel.decrypt_message(&mut buf)?;
-        let u8_buf: &[u8] = &buf[..buf.len() - 16];
-        let mut cursor = io::Cursor::new(u8_buf);
+        for (type_id, payload) in early {
+            if type_id == msgs::Ping::TYPE {
+                let mut cursor = io::Cursor::new(&payload[..]);
+                if let Message::Ping(ping) =
+                    wire::read_payload::<(), _>(&mut cursor, type_id, |_, _| Ok(None))?
+                {
+                    self.answer_ping(&ping).await?;
+                    continue;
+                }
+            }
+            self.inbox.push_back((type_id, payload));
+        }
+        Ok(())
+    }
 
-        Ok(wire::read(&mut cursor, handler).map_err(|(de, _)| de)?)
+    /// Encrypt and send a message, waiting until it has been written to the socket.
+    ///
+    /// This goes through the same queue as [`MessageSender::send`], so it is ordered with
+    /// respect to messages sent from other tasks. The write doesn't borrow the socket, which
+    /// can be read while it waits for the peer, see
+    /// [reading while writing](crate::sender#reading-while-writing).
+    pub fn write<M: wire::Type + Writeable>(&self, m: &M) -> PendingWrite {
+        self.writer
+            .sender()
+            .detached(Frame::new(m).map(|frame| vec![frame]), false)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::CallOpts;
+    /// Write `m` right away, cutting short any [`LNSocket::set_write_linger`], and resolve once
+    /// it (and everything queued before it) has been handed to the kernel.
+    pub fn write_and_flush<M: wire::Type + Writeable>(&self, m: &M) -> PendingWrite {
+        self.writer
+            .sender()
+            .detached(Frame::new(m).map(|frame| vec![frame]), true)
+    }
+
+    /// Answer `ping` with a `pong` if the [ping rules](crate::ping) allow it, returning whether
+    /// it was answered. Reads hand pings to the caller, who should pass them here. Whether to
+    /// answer is decided right away; only the write is left to the future.
+    pub fn answer_ping(
+        &self,
+        ping: &msgs::Ping,
+    ) -> impl Future<Output = Result<bool, Error>> + Send + use<> {
+        let pong = self
+            .writer
+            .pings()
+            .on_ping(ping, self.clock.now())
+            .map(|pong| self.write(&pong));
+        async move {
+            match pong {
+                Some(write) => write.await.map(|()| true),
+                None => Ok(false),
+            }
+        }
+    }
+
+    /// Like [`LNSocket::write_and_flush`], then ping the peer and wait for its `pong`. As the
+    /// peer answers in order, the `pong` confirms it has read `m`, and that the connection is
+    /// alive.
+    ///
+    /// Messages read while waiting are kept for the following reads, up to
+    /// [`MAX_PROBE_BACKLOG`] of them as with [`LNSocket::ping_probe`]. Doesn't time out by
+    /// itself; wrap it in a timeout if the peer might not answer.
+    pub async fn write_and_confirm<M: wire::Type + Writeable>(
+        &mut self,
+        m: &M,
+    ) -> Result<(), Error> {
+        if let Some(message) = &self.peer_closed {
+            return Err(Error::PeerClosedConnection {
+                message: message.clone(),
+            });
+        }
+        let ponglen = rand::thread_rng().gen_range(1..256);
+        self.write_and_flush(m).await?;
+        // after `m`, which may be a ping of its own
+        let mut wait = self.writer.pings().probe_sent(ponglen);
+        self.write_and_flush(&msgs::Ping {
+            ponglen,
+            byteslen: 0,
+        })
+        .await?;
+        self.wait_for_pong(&mut wait).await.map(|_| ())
+    }
+
+    /// Send `probe` and wait for its pong, returning how long it took and whether it had the
+    /// length the probe asked for. A mismatch is logged too. Large probes and pongs check that
+    /// big frames make it through in each direction, the cheapest way to notice a middlebox
+    /// that mangles them before real traffic does.
+    ///
+    /// Pongs to pings sent before the probe, keepalives included, are told apart by their
+    /// length and order. Other messages read while waiting are kept for the following reads,
+    /// up to [`MAX_PROBE_BACKLOG`] of them; a peer that sends more before answering fails the
+    /// probe with `Error::Io(OutOfMemory)`. A peer that drops large frames, or a path that
+    /// does, never answers: the probe fails with `Error::Io(TimedOut)` after its `timeout`.
+    /// A probe asking for more than [`MAX_PONGLEN`] bytes, which would never be answered
+    /// either, fails with `Error::Io(InvalidInput)` without being sent.
+    pub async fn ping_probe(&mut self, probe: PingProbe) -> Result<PingOutcome, Error> {
+        if probe.ponglen > MAX_PONGLEN {
+            return Err(Error::Io(io::ErrorKind::InvalidInput));
+        }
+        if let Some(message) = &self.peer_closed {
+            return Err(Error::PeerClosedConnection {
+                message: message.clone(),
+            });
+        }
+        let mut wait = self.writer.pings().probe_sent(probe.ponglen);
+        let clock = self.clock.clone();
+        let sent = clock.now();
+        let deadline = sent + probe.timeout;
+        self.write_and_flush(&probe).await?;
+
+        let received = clock
+            .timeout_at(deadline, self.wait_for_pong(&mut wait))
+            .await
+            .ok_or(Error::Io(io::ErrorKind::TimedOut))??;
+        let rtt = clock.since(sent);
+        if received == probe.ponglen as usize {
+            return Ok(PingOutcome::Pong { rtt });
+        }
+        tracing::warn!(
+            expected = probe.ponglen,
+            received,
+            "pong to a {} byte ping probe has the wrong length",
+            probe.byteslen
+        );
+        Ok(PingOutcome::LengthMismatch {
+            expected: probe.ponglen,
+            received,
+            rtt,
+        })
+    }
+
+    /// Read until the pong `wait` is for, returning how many bytes it carried. Everything
+    /// else read meanwhile is [set aside](LNSocket::set_aside).
+    async fn wait_for_pong(&mut self, wait: &mut ProbeWait) -> Result<usize, Error> {
+        loop {
+            // past the ping rules, which would drop a pong of the wrong length
+            let (type_id, payload) = self.recv_frame().await?;
+            if type_id == msgs::Pong::TYPE {
+                let pass_on = self.writer.pings().on_pong(&payload);
+                let received = payload.len().saturating_sub(2);
+                if wait.answers_probe(received) {
+                    return Ok(received);
+                }
+                if pass_on {
+                    self.set_aside(type_id, payload)?;
+                }
+                continue;
+            }
+            if type_id == msgs::Init::TYPE && self.their_init.is_some() {
+                tracing::debug!("dropping repeated init from peer");
+                continue;
+            }
+            self.set_aside(type_id, payload)?;
+        }
+    }
+
+    /// Keep a message read while waiting for a probe's pong for the following reads.
+    fn set_aside(&mut self, type_id: u16, payload: Vec<u8>) -> Result<(), Error> {
+        if self.inbox.len() >= MAX_PROBE_BACKLOG {
+            return Err(Error::Io(io::ErrorKind::OutOfMemory));
+        }
+        self.inbox.push_back((type_id, payload));
+        Ok(())
+    }
+
+    /// Tell the peer why we're giving up on `channel_id`, or on the whole connection if
+    /// `None`, before disconnecting. Resolves once the `error` has been written, so dropping
+    /// the socket right after doesn't lose it.
+    ///
+    /// Per BOLT 1 the peer fails every channel an error applies to; use
+    /// [`LNSocket::send_warning`] to complain without that.
+    pub async fn send_error(
+        &mut self,
+        channel_id: Option<ChannelId>,
+        message: impl Into<String>,
+    ) -> Result<(), Error> {
+        self.write(&msgs::ErrorMessage::new(channel_id, message))
+            .await
+    }
+
+    /// Send a `warning` about `channel_id`, or about the connection in general if `None`.
+    pub async fn send_warning(
+        &mut self,
+        channel_id: Option<ChannelId>,
+        message: impl Into<String>,
+    ) -> Result<(), Error> {
+        self.write(&msgs::WarningMessage::new(channel_id, message))
+            .await
+    }
+
+    /// Ask for the short channel ids of the channels opened in `number_of_blocks` blocks from
+    /// `first_blocknum`, returning the query for a
+    /// [`ChannelRangeCollector`](crate::ln::gossip_queries::ChannelRangeCollector).
+    ///
+    /// The chain is the (first) one set with [`LNSocket::set_network`], else the first one
+    /// in the peer's `init`, else bitcoin.
+    pub async fn query_channel_range(
+        &mut self,
+        first_blocknum: u32,
+        number_of_blocks: u32,
+    ) -> Result<QueryChannelRange, Error> {
+        let query = QueryChannelRange {
+            chain_hash: self.chain_hash(),
+            first_blocknum,
+            number_of_blocks,
+        };
+        self.write(&query).await?;
+        Ok(query)
+    }
+
+    /// Ask the peer to send us gossip with timestamps from `first_timestamp` on, for
+    /// `timestamp_range` seconds, replacing any filter sent before. Peers send no gossip at
+    /// all until they get one; `gossip_timestamp_filter(now, u32::MAX)` asks for everything
+    /// new. The chain is picked as for [`LNSocket::query_channel_range`].
+    pub async fn gossip_timestamp_filter(
+        &mut self,
+        first_timestamp: u32,
+        timestamp_range: u32,
+    ) -> Result<(), Error> {
+        self.write(&GossipTimestampFilter {
+            chain_hash: self.chain_hash(),
+            first_timestamp,
+            timestamp_range,
+        })
+        .await
+    }
+
+    fn chain_hash(&self) -> ChainHash {
+        if let Some(network) = self.reconnect.dialer.network() {
+            return crate::network::chain_hash(network);
+        }
+        self.their_init
+            .as_ref()
+            .and_then(|init| init.networks.as_ref()?.first().copied())
+            .unwrap_or(ChainHash::BITCOIN)
+    }
+
+    /// Hold outgoing messages back for up to `linger` so that more of them go out in one
+    /// write, like Nagle's algorithm. `None` (the default) writes as soon as the writer task
+    /// gets to a message, only coalescing what is already queued by then.
+    ///
+    /// Every `write` and [`MessageSender::send`] waits for its own message to be written, so
+    /// a linger delays them too; use [`LNSocket::flush`] for latency-critical messages.
+    pub async fn set_write_linger(&mut self, linger: Option<Duration>) -> Result<(), Error> {
+        self.writer.set_linger(linger).await
+    }
+
+    /// Time the socket's write linger and stall threshold, quota windows, ping rate limit and
+    /// [probes](LNSocket::ping_probe) with `clock` instead of tokio's timer. The quota windows
+    /// start over. See the [`clock`](crate::clock) module.
+    pub async fn set_clock(&mut self, clock: impl Clock) -> Result<(), Error> {
+        let clock = SharedClock::new(clock);
+        self.stats.lock().unwrap().set_clock(clock.clone());
+        self.clock = clock.clone();
+        self.writer.set_clock(clock).await
+    }
+
+    /// The clock the socket's timers follow, see [`LNSocket::set_clock`].
+    pub fn clock(&self) -> SharedClock {
+        self.clock.clone()
+    }
+
+    /// Encrypt batches of outgoing messages of at least `min_bytes` on tokio's blocking pool
+    /// instead of the writer task, so that big bursts don't hold up a runtime thread. `None`
+    /// (the default) always encrypts on the writer task. Nonces stay in order either way, see
+    /// the [`sender`](crate::sender) module.
+    pub async fn set_encrypt_offload(&mut self, min_bytes: Option<usize>) -> Result<(), Error> {
+        self.writer.set_encrypt_offload(min_bytes).await
+    }
+
+    /// Pad outgoing writes as `policy` says, see the [`privacy`](crate::privacy) module.
+    /// Sockets from a [`Dialer`] with [`PrivacyOptions`](crate::privacy::PrivacyOptions)
+    /// start out with their padding policy.
+    pub async fn set_padding(&mut self, policy: PaddingPolicy) -> Result<(), Error> {
+        self.writer.set_padding(policy).await
+    }
+
+    /// Emit [`SocketEvent::WriteStalled`] when a write has waited this long for the peer to
+    /// take its bytes (default [`DEFAULT_WRITE_STALL_THRESHOLD`]), so a dead network shows up
+    /// before the OS gives up on it, which can take many minutes. `None` turns the event off.
+    ///
+    /// [`DEFAULT_WRITE_STALL_THRESHOLD`]: crate::sender::DEFAULT_WRITE_STALL_THRESHOLD
+    pub async fn set_write_stall_threshold(
+        &mut self,
+        threshold: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.writer.set_stall_threshold(threshold).await
+    }
+
+    /// The congestion signal of our sending side right now, see the
+    /// [`congestion`](crate::congestion) module.
+    pub fn congestion(&self) -> Congestion {
+        self.writer.congestion().snapshot()
+    }
+
+    /// A watch on the [`CongestionLevel`], which changes whenever the score crosses one of
+    /// the [`CongestionThresholds`].
+    pub fn watch_congestion(&self) -> watch::Receiver<CongestionLevel> {
+        self.writer.congestion().watch()
+    }
+
+    pub fn set_congestion_thresholds(&mut self, thresholds: CongestionThresholds) {
+        self.writer.congestion().set_thresholds(thresholds);
+    }
+
+    /// Write everything held back by [`LNSocket::set_write_linger`] now.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.writer.sender().flush().await
+    }
+
+    /// Send a burst of pre-encoded messages in one vectored write, see
+    /// [`MessageSender::send_frames`].
+    ///
+    /// Frames are encrypted by the writer task as they are written: handing out ciphertext
+    /// from a live socket would let other senders take nonces out from under it. To build
+    /// wire-accurate ciphertext, e.g. for fixtures, use a [`transport::Transport`].
+    pub fn write_frames(&self, frames: Vec<Frame>) -> PendingWrite {
+        self.writer.sender().detached(Ok(frames), false)
+    }
+
+    /// A cloneable handle for sending messages from other tasks while this socket is used for
+    /// reading. See [`MessageSender`].
+    pub fn sender(&self) -> MessageSender {
+        self.writer.sender().clone()
+    }
+
+    /// Hand reading over to a task that broadcasts every message, so that more than one
+    /// reader can see them, see the [`fanout`](crate::fanout) module. Each receiver buffers up
+    /// to `capacity` messages; more receivers come from
+    /// [`resubscribe`](broadcast::Receiver::resubscribe).
+    pub fn into_fanout(
+        self,
+        capacity: usize,
+    ) -> (MessageSender, broadcast::Receiver<FanoutMessage>) {
+        let sender = self.sender();
+        let (tx, rx) = broadcast::channel(capacity);
+        tokio::spawn(fanout::read_loop(self, tx));
+        (sender, rx)
+    }
+
+    pub async fn read(&mut self) -> Result<Message<()>, Error> {
+        self.read_custom(|_type, _buf| Ok(None)).await
+    }
+
+    /// Read the next message, handing types this crate doesn't decode to `handler` with the
+    /// message body. The body is a [`LengthLimitedRead`](crate::ser::LengthLimitedRead) whose
+    /// [`remaining_bytes`](crate::ser::LengthLimitedRead::remaining_bytes) is what's left of it.
+    pub async fn read_custom<T>(
+        &mut self,
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Message<T>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        let (type_id, payload) = self.read_raw().await?;
+        let mut cursor = io::Cursor::new(&payload[..]);
+
+        Ok(wire::read_payload(&mut cursor, type_id, handler)?)
+    }
+
+    /// Read the next message, decoding custom types with `reader`.
+    pub async fn read_with<C: CustomMessageReader>(
+        &mut self,
+        reader: &C,
+    ) -> Result<Message<C::CustomMessage>, Error> {
+        self.read_custom(|type_id, r| reader.read(type_id, r)).await
+    }
+
+    /// Read and decrypt the next message, returning its type id and the owned payload.
+    ///
+    /// Unlike [`LNSocket::read_custom`], nothing is decoded here, so the payload can be moved
+    /// to another task (e.g. `spawn_blocking`) and decoded with [`wire::read_payload`].
+    pub async fn read_raw(&mut self) -> Result<(u16, Vec<u8>), Error> {
+        if let Some(msg) = self.inbox.pop_front() {
+            return Ok(msg);
+        }
+        loop {
+            let (type_id, payload) = self.recv_raw().await?;
+            if type_id == msgs::Init::TYPE && self.their_init.is_some() {
+                tracing::debug!("dropping repeated init from peer");
+                continue;
+            }
+            return Ok((type_id, payload));
+        }
+    }
+
+    /// Read and decrypt the next message off the wire, but for pongs the ping rules drop.
+    async fn recv_raw(&mut self) -> Result<(u16, Vec<u8>), Error> {
+        if let Some(message) = &self.peer_closed {
+            return Err(Error::PeerClosedConnection {
+                message: message.clone(),
+            });
+        }
+        loop {
+            let (type_id, payload) = self.recv_frame().await?;
+            if type_id == msgs::Pong::TYPE && !self.writer.pings().on_pong(&payload) {
+                tracing::debug!("dropping a pong that answers none of our pings");
+                continue;
+            }
+            if self.answer_gossip_queries && self.answer_gossip_query(type_id, &payload) {
+                continue;
+            }
+            return Ok((type_id, payload));
+        }
+    }
+
+    /// Queue the empty reply to a gossip query, returning whether the message was one.
+    fn answer_gossip_query(&self, type_id: u16, payload: &[u8]) -> bool {
+        let reply = match GossipQueryMessage::read(type_id, &mut &payload[..]) {
+            Ok(Some(GossipQueryMessage::QueryChannelRange(query))) => {
+                self.write(&query.empty_reply())
+            }
+            Ok(Some(GossipQueryMessage::QueryShortChannelIds(query))) => {
+                self.write(&query.empty_reply())
+            }
+            _ => return false,
+        };
+        tracing::debug!("answering gossip query {type_id} with an empty reply");
+        // not waiting for the write keeps the read going; a failed one shows up on the next read
+        tokio::spawn(reply);
+        true
+    }
+
+    /// Read and decrypt the next message off the wire.
+    ///
+    /// Every await reads into `read_state` and nothing else, and each decryption happens
+    /// right after the read that completes its part of the frame, so dropping this future
+    /// leaves the socket ready to resume.
+    async fn recv_frame(&mut self) -> Result<(u16, Vec<u8>), Error> {
+        if self.read_state.is_idle() {
+            let blocked = self.stats.lock().unwrap().quota_blocked(Direction::Inbound);
+            if let Some(resets_in) = blocked {
+                return Err(Error::QuotaExceeded {
+                    direction: Direction::Inbound,
+                    resets_in,
+                });
+            }
+        }
+        let buf = loop {
+            match &mut self.read_state {
+                ReadState::Header { buf, filled } if *filled < LENGTH_HEADER_SIZE => {
+                    read_into(&mut self.stream, &self.events, buf, filled).await?;
+                }
+                ReadState::Header { buf, .. } => {
+                    let size = transport::decrypt_length_header(
+                        &mut self.channel,
+                        buf,
+                        self.max_message_len,
+                    )?;
+                    self.read_state = ReadState::Body {
+                        buf: vec![0; size],
+                        filled: 0,
+                    };
+                }
+                ReadState::Body { buf, filled } if *filled < buf.len() => {
+                    read_into(&mut self.stream, &self.events, buf, filled).await?;
+                }
+                ReadState::Body { .. } => match std::mem::take(&mut self.read_state) {
+                    ReadState::Body { buf, .. } => break buf,
+                    ReadState::Header { .. } => unreachable!(),
+                },
+            }
+        };
+        let (type_id, payload) = transport::decrypt_message(&mut self.channel, buf)?;
+        capture::capture(&self.capture, Direction::Inbound, type_id, &payload);
+
+        let report = {
+            let mut stats = self.stats.lock().unwrap();
+            stats.record_inbound(type_id, payload.len() + 2);
+            stats.take_quota_report()
+        };
+        report.fire();
+
+        if type_id == msgs::WarningMessage::TYPE {
+            self.on_warning(&payload);
+        }
+        if type_id == msgs::ErrorMessage::TYPE && self.close_on_peer_error {
+            self.on_error(&payload)?;
+        }
+
+        Ok((type_id, payload))
+    }
+
+    /// Close the socket for reading if the peer's `error` is about all channels.
+    fn on_error(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let mut cursor = io::Cursor::new(payload);
+        let Ok(Message::Error(error)) =
+            wire::read_payload::<(), _>(&mut cursor, msgs::ErrorMessage::TYPE, |_, _| Ok(None))
+        else {
+            return Ok(());
+        };
+        if !error.channel_id.is_zero() {
+            return Ok(());
+        }
+        tracing::info!(
+            "{} closed the connection: {:?}",
+            self.reconnect.their_pubkey,
+            error.data
+        );
+        self.peer_closed = Some(error.data.clone());
+        Err(Error::PeerClosedConnection {
+            message: error.data,
+        })
+    }
+
+    /// Count, log and broadcast a warning from the peer. The message itself is still handed
+    /// to the reader as usual.
+    fn on_warning(&mut self, payload: &[u8]) {
+        let mut cursor = io::Cursor::new(payload);
+        let warning =
+            match wire::read_payload::<(), _>(&mut cursor, msgs::WarningMessage::TYPE, |_, _| {
+                Ok(None)
+            }) {
+                Ok(Message::Warning(warning)) => warning,
+                Ok(_) => return,
+                Err(err) => {
+                    tracing::debug!("undecodable warning from peer: {err:?}");
+                    return;
+                }
+            };
+        self.stats
+            .lock()
+            .unwrap()
+            .record_warning(&self.reconnect.their_pubkey, &warning);
+        // no subscribers is fine
+        let _ = self.events.send(SocketEvent::Warning(warning));
+    }
+}
+
+/// Helpers for tests that need a live socket without a network.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::commando::MessageTransport;
+    use crate::transport::MAC_SIZE;
+    pub(crate) use crate::transport::testing::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Both ends of a TCP connection over loopback.
+    pub(crate) async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// Like [`tcp_pair`], with socket buffers of about `size` bytes either way, for tests
+    /// that need writes to wait for the peer without sending megabytes first.
+    pub(crate) async fn small_tcp_pair(size: u32) -> (TcpStream, TcpStream) {
+        let listener = tokio::net::TcpSocket::new_v4().unwrap();
+        // accepted connections take the listener's buffer sizes
+        listener.set_recv_buffer_size(size).unwrap();
+        listener.set_send_buffer_size(size).unwrap();
+        listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = listener.listen(1).unwrap();
+        let client = tokio::net::TcpSocket::new_v4().unwrap();
+        client.set_recv_buffer_size(size).unwrap();
+        client.set_send_buffer_size(size).unwrap();
+        let client = client.connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// A socket whose handshake is already done, and the raw peer end with its cipher.
+    pub(crate) async fn loopback_pair() -> (LNSocket, TcpStream, PeerChannelEncryptor) {
+        loopback_pair_over(tcp_pair().await)
+    }
+
+    /// [`loopback_pair`] over the given client and server ends.
+    pub(crate) fn loopback_pair_over(
+        (client, server): (TcpStream, TcpStream),
+    ) -> (LNSocket, TcpStream, PeerChannelEncryptor) {
+        let addr = server.local_addr().unwrap();
+        let their_pubkey = key(2);
+        let (ours, peer) = encryptor_pair();
+        let sock = LNSocket::from_parts(
+            ours,
+            client.into(),
+            ReconnectData {
+                our_key: SecretKey::from_slice(&[1; 32]).unwrap(),
+                their_pubkey,
+                addr: addr.to_string(),
+                host: addr.to_string(),
+                dialer: Dialer::new(),
+                config: None,
+                fallbacks: ReconnectAddrs::default(),
+            },
+            None,
+        );
+        (sock, server, peer)
+    }
+
+    /// Two sockets connected to each other over loopback, with node ids `key(1)` and `key(2)`.
+    pub(crate) async fn socket_pair() -> (LNSocket, LNSocket) {
+        let (a, b) = tcp_pair().await;
+        let end = |stream: TcpStream, us: u8, them: u8, sk: u8, rk: u8| {
+            let addr = stream.peer_addr().unwrap().to_string();
+            LNSocket::from_parts(
+                PeerChannelEncryptor::from_cipher_state(key(them), cipher(sk, rk)),
+                stream.into(),
+                ReconnectData {
+                    our_key: SecretKey::from_slice(&[us; 32]).unwrap(),
+                    their_pubkey: key(them),
+                    addr: addr.clone(),
+                    host: addr,
+                    dialer: Dialer::new(),
+                    config: None,
+                    fallbacks: ReconnectAddrs::default(),
+                },
+                None,
+            )
+        };
+        (end(a, 1, 2, 3, 4), end(b, 2, 1, 4, 3))
+    }
+
+    /// A message's type and payload.
+    pub(crate) type RawFrame = (u16, Vec<u8>);
+
+    /// One end of an in-memory connection carrying [`RawFrame`]s, as a test
+    /// would fake a node. It can't reconnect.
+    pub(crate) struct FakeTransport {
+        pub(crate) inbound: mpsc::UnboundedReceiver<RawFrame>,
+        pub(crate) outbound: mpsc::UnboundedSender<RawFrame>,
+        pub(crate) us: PublicKey,
+        pub(crate) them: PublicKey,
+    }
+
+    impl FakeTransport {
+        /// A transport from `key(1)` to `key(2)`, and the node's ends: where to send what the
+        /// transport reads, and what it wrote.
+        pub(crate) fn new() -> (
+            Self,
+            mpsc::UnboundedSender<RawFrame>,
+            mpsc::UnboundedReceiver<RawFrame>,
+        ) {
+            let (to_us, inbound) = mpsc::unbounded_channel();
+            let (outbound, from_us) = mpsc::unbounded_channel();
+            let fake = FakeTransport {
+                inbound,
+                outbound,
+                us: key(1),
+                them: key(2),
+            };
+            (fake, to_us, from_us)
+        }
+    }
+
+    /// Two ends of an in-memory connection, the first with the lower node id.
+    pub(crate) fn fake_pair() -> (FakeTransport, FakeTransport) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        let (lower, higher) = (key(1).min(key(2)), key(1).max(key(2)));
+        (
+            FakeTransport {
+                inbound: a_rx,
+                outbound: b_tx,
+                us: lower,
+                them: higher,
+            },
+            FakeTransport {
+                inbound: b_rx,
+                outbound: a_tx,
+                us: higher,
+                them: lower,
+            },
+        )
+    }
+
+    impl MessageTransport for FakeTransport {
+        async fn read_frame(&mut self) -> Result<(u16, Vec<u8>), Error> {
+            self.inbound
+                .recv()
+                .await
+                .ok_or(Error::Io(io::ErrorKind::UnexpectedEof))
+        }
+
+        async fn write_frame(&mut self, frame: Frame) -> Result<(), Error> {
+            self.outbound
+                .send((frame.type_id(), frame.payload().to_vec()))
+                .map_err(|_| Error::Io(io::ErrorKind::BrokenPipe))
+        }
+
+        async fn reconnect(&self) -> Result<Self, Error> {
+            Err(Error::NotConnected)
+        }
+
+        fn their_pubkey(&self) -> PublicKey {
+            self.them
+        }
+
+        fn our_node_id(&self) -> PublicKey {
+            self.us
+        }
+    }
+
+    pub(crate) async fn peer_send<M: wire::Type + Writeable>(
+        stream: &mut TcpStream,
+        peer: &mut PeerChannelEncryptor,
+        msg: &M,
+    ) {
+        stream.write_all(&peer.encrypt_message(msg)).await.unwrap();
+    }
+
+    pub(crate) async fn peer_recv(
+        stream: &mut TcpStream,
+        peer: &mut PeerChannelEncryptor,
+    ) -> Message<()> {
+        let (type_id, payload) = peer_recv_raw(stream, peer).await;
+        wire::read_payload(&mut io::Cursor::new(&payload[..]), type_id, |_, _| Ok(None)).unwrap()
+    }
+
+    pub(crate) async fn peer_recv_raw(
+        stream: &mut TcpStream,
+        peer: &mut PeerChannelEncryptor,
+    ) -> (u16, Vec<u8>) {
+        let mut hdr = [0u8; LENGTH_HEADER_SIZE];
+        stream.read_exact(&mut hdr).await.unwrap();
+        let len = peer.decrypt_length_header(&hdr).unwrap() as usize;
+        let mut body = vec![0u8; len + MAC_SIZE];
+        stream.read_exact(&mut body).await.unwrap();
+        transport::decrypt_message(peer, body).unwrap()
+    }
+
+    pub(crate) fn init() -> msgs::Init {
+        msgs::Init {
+            features: vec![],
+            global_features: vec![],
+            networks: None,
+            remote_network_address: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CallOpts;
     use crate::ln::msgs;
+    use crate::lnsocket::testing::*;
+    use bitcoin::secp256k1::rand;
     use std::str::FromStr;
 
+    #[tokio::test]
+    async fn messages_before_init_are_tolerated() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+
+        let ping = msgs::Ping {
+            ponglen: 3,
+            byteslen: 0,
+        };
+        let warning = msgs::WarningMessage {
+            channel_id: crate::ln::types::ChannelId([0; 32]),
+            data: "early".to_string(),
+        };
+        peer_send(&mut server, &mut peer, &ping).await;
+        peer_send(&mut server, &mut peer, &warning).await;
+        peer_send(&mut server, &mut peer, &init()).await;
+        peer_send(&mut server, &mut peer, &init()).await;
+        peer_send(&mut server, &mut peer, &msgs::Pong { byteslen: 1 }).await;
+
+        sock.perform_init().await.unwrap();
+
+        // our init goes out before the delayed pong
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Init(_)
+        ));
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Pong(msgs::Pong { byteslen: 3 })
+        ));
+
+        // the set-aside warning comes first, the repeated init is dropped
+        assert!(matches!(sock.read().await.unwrap(), Message::Warning(w) if w.data == "early"));
+        assert!(matches!(
+            sock.read().await.unwrap(),
+            Message::Pong(msgs::Pong { byteslen: 1 })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn export_waits_for_set_aside_messages() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let warning = msgs::WarningMessage {
+            channel_id: crate::ln::types::ChannelId([0; 32]),
+            data: "early".to_string(),
+        };
+        peer_send(&mut server, &mut peer, &warning).await;
+        peer_send(&mut server, &mut peer, &init()).await;
+        sock.perform_init().await.unwrap();
+
+        let err = sock.export_session().await.err().unwrap();
+        assert!(matches!(err, Error::Io(io::ErrorKind::WouldBlock)));
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_init_read_keeps_what_came_before() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let warning = msgs::WarningMessage {
+            channel_id: crate::ln::types::ChannelId([0; 32]),
+            data: "early".to_string(),
+        };
+        peer_send(&mut server, &mut peer, &warning).await;
+        let res = tokio::time::timeout(Duration::from_millis(50), sock.read_init()).await;
+        assert!(res.is_err(), "no init was sent yet");
+
+        peer_send(&mut server, &mut peer, &init()).await;
+        sock.read_init().await.unwrap();
+        sock.send_init(&init()).await.unwrap();
+        assert!(matches!(sock.read().await.unwrap(), Message::Warning(w) if w.data == "early"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn export_waits_for_a_half_done_init_exchange() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        peer_send(&mut server, &mut peer, &init()).await;
+        sock.read_init().await.unwrap();
+
+        let err = sock.export_session().await.err().unwrap();
+        assert!(matches!(err, Error::Io(io::ErrorKind::WouldBlock)));
+    }
+
+    #[tokio::test]
+    async fn unexpected_pongs_can_be_dropped() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_ping_policy(PingPolicy {
+            drop_unexpected_pongs: true,
+            ..PingPolicy::default()
+        });
+        let warning = msgs::WarningMessage {
+            channel_id: crate::ln::types::ChannelId([0; 32]),
+            data: "after".to_string(),
+        };
+        peer_send(&mut server, &mut peer, &msgs::Pong { byteslen: 1 }).await;
+        peer_send(&mut server, &mut peer, &warning).await;
+
+        // the pong answering no ping of ours is dropped
+        assert!(matches!(sock.read().await.unwrap(), Message::Warning(w) if w.data == "after"));
+        assert_eq!(sock.ping_stats().unexpected_pongs, 1);
+    }
+
+    #[tokio::test]
+    async fn pings_past_the_policy_go_unanswered() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_ping_policy(PingPolicy {
+            max_pings: 1,
+            ..PingPolicy::default()
+        });
+        let ping = |ponglen| msgs::Ping {
+            ponglen,
+            byteslen: 0,
+        };
+
+        assert!(sock.answer_ping(&ping(2)).await.unwrap());
+        assert!(!sock.answer_ping(&ping(3)).await.unwrap());
+        assert!(!sock.answer_ping(&ping(65532)).await.unwrap());
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Pong(msgs::Pong { byteslen: 2 })
+        ));
+
+        let stats = sock.ping_stats();
+        assert_eq!(
+            (stats.answered, stats.rate_limited, stats.oversized),
+            (1, 1, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_reads_resume_mid_frame() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let ping = |byteslen| msgs::Ping {
+            ponglen: 4,
+            byteslen,
+        };
+        let first = peer.encrypt_message(&ping(10));
+        let wire = [first.clone(), peer.encrypt_message(&ping(20))].concat();
+        let mut sent = 0;
+        // inside the first header, inside the first body, the rest of the first frame and the
+        // start of the second header, and the rest
+        for (upto, expect) in [
+            (7, None),
+            (LENGTH_HEADER_SIZE + 5, None),
+            (first.len() + 3, Some(10)),
+            (first.len() + 3, None),
+            (wire.len(), Some(20)),
+        ] {
+            server.write_all(&wire[sent..upto]).await.unwrap();
+            sent = upto;
+            let read = tokio::time::timeout(Duration::from_millis(20), sock.read()).await;
+            match (read, expect) {
+                (Ok(Ok(Message::Ping(ping))), Some(byteslen)) => {
+                    assert_eq!(ping.byteslen, byteslen)
+                }
+                (Err(_), None) => {}
+                (read, _) => panic!("after {upto} bytes, read {read:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused_before_reading_them() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_max_message_len(100);
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 200,
+        };
+        // only the header is sent: the body is never waited for
+        let frame = peer.encrypt_message(&ping);
+        server
+            .write_all(&frame[..LENGTH_HEADER_SIZE])
+            .await
+            .unwrap();
+        assert!(matches!(
+            sock.read().await,
+            Err(Error::MessageTooLarge { len: 206, max: 100 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn tampered_frames_fail_authentication() {
+        // a flipped bit in the length header, then in the body
+        for at in [3, LENGTH_HEADER_SIZE + 3] {
+            let (mut sock, mut server, mut peer) = loopback_pair().await;
+            let mut frame = peer.encrypt_message(&msgs::Ping {
+                ponglen: 0,
+                byteslen: 8,
+            });
+            frame[at] ^= 1;
+            server.write_all(&frame).await.unwrap();
+            assert!(matches!(sock.read().await, Err(Error::BadMac)));
+        }
+    }
+
+    #[tokio::test]
+    async fn gossip_queries_get_empty_replies_when_asked_to() {
+        use crate::ln::gossip_queries::*;
+
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_answer_gossip_queries(true);
+        let range = QueryChannelRange {
+            chain_hash: ChainHash::BITCOIN,
+            first_blocknum: 800_000,
+            number_of_blocks: 1000,
+        };
+        let scids = QueryShortChannelIds {
+            chain_hash: ChainHash::BITCOIN,
+            short_channel_ids: vec![1 << 40],
+        };
+        peer_send(&mut server, &mut peer, &range).await;
+        peer_send(&mut server, &mut peer, &scids).await;
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 0,
+        };
+        peer_send(&mut server, &mut peer, &ping).await;
+
+        // the queries are answered, not read
+        assert!(matches!(sock.read().await, Ok(Message::Ping(_))));
+        let replies = [
+            peer_recv_raw(&mut server, &mut peer).await,
+            peer_recv_raw(&mut server, &mut peer).await,
+        ];
+        let decode = |(type_id, payload): &(u16, Vec<u8>)| {
+            GossipQueryMessage::read(*type_id, &mut &payload[..])
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(
+            replies.iter().map(decode).collect::<Vec<_>>(),
+            [
+                GossipQueryMessage::ReplyChannelRange(range.empty_reply()),
+                GossipQueryMessage::ReplyShortChannelIdsEnd(scids.empty_reply()),
+            ]
+        );
+
+        // off, they are the reader's
+        sock.set_answer_gossip_queries(false);
+        peer_send(&mut server, &mut peer, &range).await;
+        assert_eq!(
+            sock.read_raw().await.unwrap(),
+            (QUERY_CHANNEL_RANGE, range.encode())
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_go_on_while_a_write_waits_for_the_peer() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        // far more than the kernel buffers take while the peer isn't reading
+        let big = msgs::Ping {
+            ponglen: 0,
+            byteslen: 65000,
+        };
+        let frames = (0..256).map(|_| Frame::new(&big).unwrap()).collect();
+        let mut write = std::pin::pin!(sock.write_frames(frames));
+
+        peer_send(
+            &mut server,
+            &mut peer,
+            &msgs::Ping {
+                ponglen: 4,
+                byteslen: 0,
+            },
+        )
+        .await;
+        let msg = tokio::select! {
+            res = &mut write => panic!("written without the peer reading: {res:?}"),
+            msg = sock.read() => msg,
+        };
+        assert!(matches!(msg, Ok(Message::Ping(ping)) if ping.ponglen == 4));
+
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1 << 16];
+            while server.read(&mut buf).await.unwrap() > 0 {}
+        });
+        write.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn enforced_quotas_stop_traffic() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let quota = Quota {
+            enforce: true,
+            ..Quota::new(100, Duration::from_secs(60))
+        };
+        sock.set_quota(Direction::Inbound, Some(quota));
+        sock.set_quota(Direction::Outbound, Some(quota));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        sock.on_quota({
+            let seen = seen.clone();
+            move |event| seen.lock().unwrap().push(event)
+        });
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 60,
+        };
+
+        // 34 + 2 + 4 + 60 bytes: the first message reaches the limit and still goes through
+        for _ in 0..2 {
+            peer_send(&mut server, &mut peer, &ping).await;
+        }
+        assert!(matches!(sock.read().await, Ok(Message::Ping(_))));
+        assert!(matches!(
+            sock.read().await,
+            Err(Error::QuotaExceeded {
+                direction: Direction::Inbound,
+                ..
+            })
+        ));
+
+        sock.write(&ping).await.unwrap();
+        assert!(matches!(
+            sock.sender().send(&ping).await,
+            Err(Error::QuotaExceeded {
+                direction: Direction::Outbound,
+                ..
+            })
+        ));
+        let seen = seen.lock().unwrap().clone();
+        assert!(
+            matches!(
+                seen[..],
+                [
+                    QuotaEvent::Exceeded {
+                        direction: Direction::Inbound,
+                        used: 100,
+                        ..
+                    },
+                    QuotaEvent::Exceeded {
+                        direction: Direction::Outbound,
+                        used: 100,
+                        ..
+                    }
+                ]
+            ),
+            "{seen:?}"
+        );
+
+        // lifting the quota lets the waiting message through
+        sock.set_quota(Direction::Inbound, None);
+        assert!(matches!(sock.read().await, Ok(Message::Ping(_))));
+        assert_eq!(sock.quota_usage(Direction::Outbound).unwrap().used, 100);
+    }
+
+    #[tokio::test]
+    async fn quota_windows_follow_the_socket_clock() {
+        use crate::clock::testing::ManualClock;
+
+        let (mut sock, _server, _peer) = loopback_pair().await;
+        let clock = ManualClock::default();
+        sock.set_clock(clock.clone()).await.unwrap();
+        let quota = Quota {
+            enforce: true,
+            ..Quota::new(100, Duration::from_secs(60))
+        };
+        sock.set_quota(Direction::Outbound, Some(quota));
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 60,
+        };
+
+        sock.write(&ping).await.unwrap();
+        assert!(sock.write(&ping).await.is_err());
+        clock.advance(Duration::from_secs(59));
+        let usage = sock.quota_usage(Direction::Outbound).unwrap();
+        assert_eq!(usage.resets_in, Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        sock.write(&ping).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconnects_fall_back_to_other_addresses() {
+        // the listener of the pair is gone, so its address refuses connections
+        let (sock, _server, _peer) = loopback_pair().await;
+        let fallback = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = sock.reconnect_addrs();
+        addrs.set(["127.0.0.1:1", &fallback.local_addr().unwrap().to_string()]);
+        addrs.push("127.0.0.1:1");
+        assert_eq!(addrs.to_vec().len(), 2);
+
+        let (res, ()) = tokio::join!(sock.reconnect_fresh(), async {
+            let (mut stream, _) = fallback.accept().await.unwrap();
+            let mut act_one = [0u8; 50];
+            stream.read_exact(&mut act_one).await.unwrap();
+        });
+        // the fallback was reached, and hung up too
+        assert!(matches!(res, Err(Error::Io(_))));
+        assert_eq!(sock.reconnect_addrs().to_vec(), addrs.to_vec());
+    }
+
+    #[tokio::test]
+    async fn manual_init_exchange_in_either_order() {
+        let ours = msgs::Init {
+            networks: Some(vec![crate::network::chain_hash(Network::Testnet)]),
+            ..init()
+        };
+
+        // theirs first: nothing is answered until ours is out
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let ping = msgs::Ping {
+            ponglen: 2,
+            byteslen: 0,
+        };
+        peer_send(&mut server, &mut peer, &ping).await;
+        peer_send(&mut server, &mut peer, &init()).await;
+        assert_eq!(sock.read_init().await.unwrap(), init());
+        assert_eq!(sock.their_init(), None);
+        sock.send_init(&ours).await.unwrap();
+        assert_eq!(sock.their_init(), Some(&init()));
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Init(sent) if sent == ours
+        ));
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Pong(msgs::Pong { byteslen: 2 })
+        ));
+
+        // ours first
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.send_init(&ours).await.unwrap();
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Init(_)
+        ));
+        peer_send(&mut server, &mut peer, &init()).await;
+        sock.read_init().await.unwrap();
+        assert_eq!(sock.their_init(), Some(&init()));
+    }
+
+    #[tokio::test]
+    async fn capability_cache_records_the_peers_init() {
+        let cache = CapabilityCache::new();
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_capability_cache(Some(cache.clone()));
+        let node = sock.their_pubkey();
+        assert_eq!(cache.get(&node), None);
+
+        let mut theirs = init();
+        features::set(&mut theirs.features, features::ONION_MESSAGES_FEATURE_BIT);
+        peer_send(&mut server, &mut peer, &theirs).await;
+        sock.send_init(&init()).await.unwrap();
+        sock.read_init().await.unwrap();
+        assert_eq!(
+            cache.supports(&node, features::ONION_MESSAGES_FEATURE_BIT),
+            Some(true)
+        );
+
+        // a cache set after the exchange gets it right away
+        let late = CapabilityCache::new();
+        sock.set_capability_cache(Some(late.clone()));
+        assert_eq!(late.get(&node).unwrap().features, theirs.features);
+    }
+
+    #[tokio::test]
+    async fn peer_reports_our_address() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        assert_eq!(sock.our_address_as_seen_by_peer(), None);
+
+        let seen = SocketAddress::TcpIpV4 {
+            addr: [203, 0, 113, 7],
+            port: 50123,
+        };
+        let their_init = msgs::Init {
+            networks: Some(vec![bitcoin::constants::ChainHash::BITCOIN]),
+            remote_network_address: Some(seen.clone()),
+            ..init()
+        };
+        peer_send(&mut server, &mut peer, &their_init).await;
+        sock.perform_init().await.unwrap();
+
+        assert_eq!(sock.our_address_as_seen_by_peer(), Some(&seen));
+        assert_eq!(sock.their_init(), Some(&their_init));
+    }
+
+    #[test]
+    fn unknown_remote_address_types_are_ignored() {
+        // no features, then TLV 3 holding an address of type 0xff
+        let bytes = [0, 0, 0, 0, 3, 3, 0xff, 0x12, 0x34];
+        let msg = wire::read_payload(&mut io::Cursor::new(&bytes[..]), 16, |_, _| Ok(None));
+        let Ok(Message::<()>::Init(init)) = msg else {
+            panic!("init should decode, got {msg:?}");
+        };
+        assert_eq!(init.remote_network_address, None);
+    }
+
+    #[tokio::test]
+    async fn init_checks_and_advertises_the_network() {
+        use crate::network::chain_hash;
+
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_network(Network::Regtest);
+        let their_init = msgs::Init {
+            networks: Some(vec![chain_hash(Network::Bitcoin)]),
+            ..init()
+        };
+        peer_send(&mut server, &mut peer, &their_init).await;
+        assert!(matches!(
+            sock.perform_init().await,
+            Err(Error::NetworkMismatch { .. })
+        ));
+
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_network(Network::Regtest);
+        peer_send(&mut server, &mut peer, &init()).await;
+        sock.perform_init().await.unwrap();
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Init(ours) if ours.networks == Some(vec![chain_hash(Network::Regtest)])
+        ));
+
+        // several networks are all advertised, and one in common is enough
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_networks([Network::Signet, Network::Bitcoin]);
+        peer_send(&mut server, &mut peer, &their_init).await;
+        sock.perform_init().await.unwrap();
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Init(ours) if ours.networks
+                == Some(vec![chain_hash(Network::Signet), chain_hash(Network::Bitcoin)])
+        ));
+    }
+
+    #[tokio::test]
+    async fn error_and_warning_before_disconnect() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let channel_id = ChannelId::from_bytes([7; 32]);
+
+        sock.send_warning(Some(channel_id), "fee too low")
+            .await
+            .unwrap();
+        sock.send_error(None, "x".repeat(70_000)).await.unwrap();
+        drop(sock);
+
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Warning(w) if w.channel_id == channel_id && w.data == "fee too low"
+        ));
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Error(e) if e.channel_id.is_zero() && e.data.len() == msgs::MAX_ERROR_DATA_LEN
+        ));
+    }
+
+    #[tokio::test]
+    async fn our_node_id_matches_our_key() {
+        let (sock, _server, _peer) = loopback_pair().await;
+        let expected = PublicKey::from_secret_key(
+            &bitcoin::secp256k1::Secp256k1::signing_only(),
+            &SecretKey::from_slice(&[1; 32]).unwrap(),
+        );
+        assert_eq!(sock.our_node_id(), expected);
+
+        let client = crate::CommandoClient::spawn(sock, "rune");
+        assert_eq!(client.our_node_id(), expected);
+    }
+
+    #[tokio::test]
+    async fn config_options_are_applied_to_the_socket() {
+        let (mut sock, _server, _peer) = loopback_pair().await;
+        let config = LNSocketConfig::new()
+            .with_pre_init_limit(0)
+            .with_max_message_len(1000)
+            .with_close_on_peer_error(false)
+            .with_answer_gossip_queries(true)
+            .with_write_linger(Some(Duration::from_millis(5)));
+        config.apply(&mut sock).await.unwrap();
+        assert_eq!(sock.pre_init_limit, 0);
+        assert_eq!(sock.max_message_len, 1000);
+        assert!(!sock.close_on_peer_error);
+        assert!(sock.answer_gossip_queries);
+    }
+
+    #[tokio::test]
+    async fn handshake_over_an_existing_stream() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let our_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let their_pubkey = PublicKey::from_secret_key(
+            &bitcoin::secp256k1::Secp256k1::signing_only(),
+            &SecretKey::from_slice(&[2; 32]).unwrap(),
+        );
+        let handshake = tokio::spawn(LNSocket::from_stream(stream, our_key, their_pubkey));
+
+        let mut act_one = [0u8; 50];
+        server.read_exact(&mut act_one).await.unwrap();
+        assert_eq!(act_one[0], 0, "handshake version");
+        // an act two of the wrong version can't be from the node we expect
+        server.write_all(&[1u8; ACT_TWO_SIZE]).await.unwrap();
+        assert!(handshake.await.unwrap().is_err());
+
+        // hanging up after act one could be anything from rate limiting to the wrong
+        // service, so it is no key mismatch
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let handshake = tokio::spawn(LNSocket::from_stream(stream, our_key, their_pubkey));
+        server.read_exact(&mut act_one).await.unwrap();
+        drop(server);
+        assert!(matches!(
+            handshake.await.unwrap(),
+            Err(Error::Io(io::ErrorKind::UnexpectedEof))
+        ));
+    }
+
+    #[tokio::test]
+    async fn write_and_confirm_waits_for_the_pong() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        // the write mustn't sit out the linger
+        sock.set_write_linger(Some(Duration::from_secs(600)))
+            .await
+            .unwrap();
+
+        let peer_side = tokio::spawn(async move {
+            assert!(matches!(
+                peer_recv(&mut server, &mut peer).await,
+                Message::Warning(_)
+            ));
+            let Message::Ping(ping) = peer_recv(&mut server, &mut peer).await else {
+                panic!("expected a ping");
+            };
+            let warning = msgs::WarningMessage::new(None, "in between");
+            peer_send(&mut server, &mut peer, &warning).await;
+            let pong = msgs::Pong {
+                byteslen: ping.ponglen,
+            };
+            peer_send(&mut server, &mut peer, &pong).await;
+            (server, peer)
+        });
+
+        let ours = msgs::WarningMessage::new(None, "hello");
+        sock.write_and_confirm(&ours).await.unwrap();
+        peer_side.await.unwrap();
+        // what came before the pong is still there
+        assert!(
+            matches!(sock.read().await.unwrap(), Message::Warning(w) if w.data == "in between")
+        );
+    }
+
+    #[tokio::test]
+    async fn write_and_confirm_bounds_what_it_sets_aside() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let peer_side = tokio::spawn(async move {
+            peer_recv(&mut server, &mut peer).await;
+            peer_recv(&mut server, &mut peer).await;
+            let warning = msgs::WarningMessage::new(None, "in between");
+            for _ in 0..=MAX_PROBE_BACKLOG {
+                peer_send(&mut server, &mut peer, &warning).await;
+            }
+            (server, peer)
+        });
+
+        let ours = msgs::WarningMessage::new(None, "hello");
+        let err = sock.write_and_confirm(&ours).await.err().unwrap();
+        assert!(matches!(err, Error::Io(io::ErrorKind::OutOfMemory)));
+        peer_side.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ping_probes_check_the_pong_length() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let peer_side = tokio::spawn(async move {
+            for short in [0, 1] {
+                let Message::Ping(ping) = peer_recv(&mut server, &mut peer).await else {
+                    panic!("expected a ping");
+                };
+                assert_eq!(ping.byteslen, 1000);
+                let ours = ping.ponglen;
+                let ping = msgs::Ping {
+                    ponglen: 0,
+                    byteslen: 0,
+                };
+                peer_send(&mut server, &mut peer, &ping).await;
+                let pong = msgs::Pong {
+                    byteslen: ours - short,
+                };
+                peer_send(&mut server, &mut peer, &pong).await;
+            }
+        });
+
+        let probe = PingProbe {
+            pattern: crate::ping::PingPattern::Random,
+            ..PingProbe::new(500, 1000)
+        };
+        assert!(matches!(
+            sock.ping_probe(probe).await.unwrap(),
+            PingOutcome::Pong { .. }
+        ));
+        assert!(matches!(
+            sock.ping_probe(probe).await.unwrap(),
+            PingOutcome::LengthMismatch {
+                expected: 500,
+                received: 499,
+                ..
+            }
+        ));
+        peer_side.await.unwrap();
+        // the pings read while waiting are still there
+        for _ in 0..2 {
+            assert!(matches!(sock.read().await.unwrap(), Message::Ping(_)));
+        }
+        assert!(matches!(
+            sock.ping_probe(PingProbe::new(MAX_PONGLEN + 1, 0)).await,
+            Err(Error::Io(io::ErrorKind::InvalidInput))
+        ));
+    }
+
+    #[tokio::test]
+    async fn ping_probes_wait_for_their_own_pong() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let keepalive = msgs::Ping {
+            ponglen: 8,
+            byteslen: 0,
+        };
+        sock.write_and_flush(&keepalive).await.unwrap();
+        let peer_side = tokio::spawn(async move {
+            for _ in 0..2 {
+                assert!(matches!(
+                    peer_recv(&mut server, &mut peer).await,
+                    Message::Ping(_)
+                ));
+            }
+            for _ in 0..2 {
+                peer_send(&mut server, &mut peer, &msgs::Pong { byteslen: 8 }).await;
+            }
+            (server, peer)
+        });
+
+        let probe = PingProbe {
+            byteslen: 100,
+            ..PingProbe::new(8, 0)
+        };
+        assert!(matches!(
+            sock.ping_probe(probe).await.unwrap(),
+            PingOutcome::Pong { .. }
+        ));
+        // the keepalive's pong is still there for reads
+        assert!(matches!(
+            sock.read().await.unwrap(),
+            Message::Pong(msgs::Pong { byteslen: 8 })
+        ));
+
+        let _peer = peer_side.await.unwrap();
+        let probe = PingProbe {
+            timeout: Duration::from_millis(50),
+            ..PingProbe::new(8, 0)
+        };
+        assert!(matches!(
+            sock.ping_probe(probe).await,
+            Err(Error::Io(io::ErrorKind::TimedOut))
+        ));
+    }
+
+    #[tokio::test]
+    async fn all_channels_error_closes_the_socket() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+
+        let channel_error = msgs::ErrorMessage::new(Some(ChannelId([1; 32])), "bad htlc");
+        peer_send(&mut server, &mut peer, &channel_error).await;
+        assert!(matches!(sock.read().await.unwrap(), Message::Error(_)));
+
+        let fatal = msgs::ErrorMessage::new(None, "go away");
+        peer_send(&mut server, &mut peer, &fatal).await;
+        for _ in 0..2 {
+            assert!(matches!(
+                sock.read().await,
+                Err(Error::PeerClosedConnection { message }) if message == "go away"
+            ));
+        }
+
+        // or read as any other message
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_close_on_peer_error(false);
+        peer_send(&mut server, &mut peer, &fatal).await;
+        assert!(matches!(sock.read().await.unwrap(), Message::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn our_init_can_go_first() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_init_order(InitOrder::OursFirst);
+        sock.set_network(Network::Signet);
+
+        let exchange = tokio::spawn(async move { sock.perform_init().await.map(|_| sock) });
+        // the peer holds everything back until it has seen our init
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Init(ours) if ours.networks == Some(vec![crate::network::chain_hash(Network::Signet)])
+        ));
+        let gossip = msgs::WarningMessage {
+            channel_id: crate::ln::types::ChannelId([0; 32]),
+            data: "stored".to_string(),
+        };
+        peer_send(&mut server, &mut peer, &gossip).await;
+        peer_send(&mut server, &mut peer, &init()).await;
+
+        let mut sock = exchange.await.unwrap().unwrap();
+        assert!(sock.their_init().is_some());
+        assert!(matches!(sock.read().await.unwrap(), Message::Warning(w) if w.data == "stored"));
+    }
+
+    #[tokio::test]
+    async fn privacy_options_shape_init_and_writes() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.reconnect.dialer = Dialer::new().with_privacy(crate::privacy::PrivacyOptions {
+            minimal_init: true,
+            ..Default::default()
+        });
+        sock.set_padding(PaddingPolicy::Bucketed(256))
+            .await
+            .unwrap();
+
+        let exchange = tokio::spawn(async move { sock.perform_init().await.map(|_| sock) });
+        peer_send(&mut server, &mut peer, &init()).await;
+        // init itself goes out unpadded
+        match peer_recv_raw(&mut server, &mut peer).await {
+            (16, payload) => {
+                // no zero-padded feature fields: the global features are empty
+                assert_eq!(&payload[..2], &[0, 0]);
+            }
+            other => panic!("{other:?}"),
+        }
+
+        let sock = exchange.await.unwrap().unwrap();
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 8,
+        };
+        sock.write(&ping).await.unwrap();
+        let mut transport = transport::Transport::from_channel(peer);
+        let mut written = [0u8; 256];
+        server.read_exact(&mut written).await.unwrap();
+        let (ping_frame, padding) = written.split_at_mut(48);
+        assert_eq!(
+            transport.decrypt_frame(ping_frame).unwrap().0,
+            msgs::Ping::TYPE
+        );
+        let (type_id, zeros) = transport.decrypt_frame(padding).unwrap();
+        assert_eq!(type_id, crate::privacy::PADDING_TYPE);
+        assert!(zeros.iter().all(|b| *b == 0));
+        assert_eq!(
+            server.try_read(&mut [0; 1]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[tokio::test]
+    async fn frame_hooks_see_type_and_length() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        sock.on_frame_sent(move |frame| {
+            log.lock().unwrap().push(("out", frame.type_id, frame.len))
+        });
+        let log = seen.clone();
+        sock.on_frame_received(move |frame| {
+            log.lock().unwrap().push(("in", frame.type_id, frame.len))
+        });
+
+        let ping = msgs::Ping {
+            ponglen: 2,
+            byteslen: 8,
+        };
+        sock.write(&ping).await.unwrap();
+        peer_recv(&mut server, &mut peer).await;
+        peer_send(&mut server, &mut peer, &msgs::Pong { byteslen: 2 }).await;
+        sock.read().await.unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [("out", msgs::Ping::TYPE, 14), ("in", msgs::Pong::TYPE, 6)]
+        );
+
+        sock.clear_frame_hooks();
+        sock.write(&ping).await.unwrap();
+        peer_recv(&mut server, &mut peer).await;
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn strict_init_fails_on_early_message() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        sock.set_pre_init_limit(0);
+
+        peer_send(
+            &mut server,
+            &mut peer,
+            &msgs::Ping {
+                ponglen: 0,
+                byteslen: 0,
+            },
+        )
+        .await;
+        assert!(matches!(
+            sock.perform_init().await,
+            Err(Error::FirstMessageNotInit)
+        ));
+    }
+
+    #[tokio::test]
+    async fn warnings_are_surfaced_as_events() {
+        let (mut sock, mut server, mut peer) = loopback_pair().await;
+        let mut events = sock.events();
+
+        let warning = msgs::WarningMessage {
+            channel_id: crate::ln::types::ChannelId([0; 32]),
+            data: "fee too low".to_string(),
+        };
+        peer_send(&mut server, &mut peer, &warning).await;
+
+        // the reader still sees it
+        assert!(
+            matches!(sock.read().await.unwrap(), Message::Warning(w) if w.data == "fee too low")
+        );
+        assert_eq!(events.try_next(), Some(SocketEvent::Warning(warning)));
+        assert_eq!(sock.stats().warnings.received, 1);
+
+        drop(sock);
+        assert_eq!(events.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn stalls_and_failures_are_classified() {
+        let (mut sock, server, _peer) = loopback_pair().await;
+        let mut events = sock.events();
+        sock.set_write_stall_threshold(Some(Duration::from_millis(50)))
+            .await
+            .unwrap();
+
+        // the peer never reads, so the socket buffers fill up
+        let sender = sock.sender();
+        let flood = tokio::spawn(async move {
+            loop {
+                if sender.send(&msgs::Pong { byteslen: 60_000 }).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let event = tokio::time::timeout(Duration::from_secs(10), events.next())
+            .await
+            .expect("the write should stall");
+        assert!(matches!(event, Some(SocketEvent::WriteStalled { .. })));
+
+        drop(server);
+        assert!(sock.read().await.is_err());
+        let mut causes = Vec::new();
+        while let Some(event) = events.try_next() {
+            if let SocketEvent::ReadFailed(cause) | SocketEvent::WriteFailed(cause) = event {
+                causes.push(cause);
+            }
+        }
+        assert!(causes.contains(&FailureCause::Reset), "{causes:?}");
+        flood.abort();
+
+        assert_eq!(
+            FailureCause::of(io::ErrorKind::NetworkUnreachable),
+            FailureCause::NetworkUnreachable
+        );
+        assert_eq!(
+            FailureCause::of_error(&Error::Io(io::ErrorKind::TimedOut)),
+            Some(FailureCause::Timeout)
+        );
+        assert_eq!(FailureCause::of_error(&Error::Json), None);
+    }
+
     #[tokio::test]
     async fn test_ping_pong() -> Result<(), Error> {
         let key = SecretKey::new(&mut rand::thread_rng());
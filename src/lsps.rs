@@ -0,0 +1,487 @@
+//! A client for Lightning Service Providers, per [LSPS0] (bLIP-50).
+//!
+//! LSPs speak JSON-RPC 2.0 over the custom message type [`LSPS_MESSAGE_TYPE`], one request
+//! or response per message. [`LspsClient`] is to an LSP what
+//! [`CommandoClient`](crate::CommandoClient) is to a CLN node: it frames the requests, gives
+//! each a random id and matches the responses to them, on a background task built on
+//! [`RequestResponse`]. Typed calls cover [LSPS1] (buying a channel) and [LSPS2] (JIT
+//! channels); [`LspsClient::call`] reaches anything else.
+//!
+//! ```no_run
+//! use lnsocket::lsps::{LspsClient, LSPS_FEATURE_BIT};
+//! use lnsocket::LNSocket;
+//! # async fn ex(key: bitcoin::secp256k1::SecretKey, lsp: bitcoin::secp256k1::PublicKey) -> Result<(), lnsocket::Error> {
+//! let sock = LNSocket::connect_and_init(key, lsp, "lsp.example.com:9735").await?;
+//! assert!(sock.peer_supports_feature(LSPS_FEATURE_BIT));
+//!
+//! let lsp = LspsClient::spawn(sock);
+//! if lsp.list_protocols().await?.contains(&2) {
+//!     let menu = lsp.lsps2_get_info(None).await?.opening_fee_params_menu;
+//!     let jit = lsp.lsps2_buy(&menu[0], Some(100_000_000)).await?;
+//!     println!("invoice through {}", jit.jit_channel_scid);
+//! }
+//! # Ok(()) }
+//! ```
+//!
+//! Amounts are `u64`s in the unit their name says; on the wire LSPS sends them as strings.
+//! Every reply struct keeps the fields it doesn't know about in `extra`. An LSP's JSON-RPC
+//! errors come back as `Error::Rpc`.
+//!
+//! [LSPS0]: https://github.com/lightning/blips/blob/master/blip-0050.md
+//! [LSPS1]: https://github.com/lightning/blips/blob/master/blip-0051.md
+//! [LSPS2]: https://github.com/lightning/blips/blob/master/blip-0052.md
+
+use std::io;
+use std::time::Duration;
+
+use bitcoin::secp256k1::rand::{self, Rng};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+use crate::Error;
+use crate::commando::MessageTransport;
+use crate::error::RpcError;
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::Type;
+use crate::reqresp::{Request, RequestResponse, Response};
+use crate::util::ser::{LengthLimitedRead, Writeable, Writer};
+
+/// The custom message type LSPS0 runs over.
+pub const LSPS_MESSAGE_TYPE: u16 = 37913;
+
+/// The feature bit an LSP sets in its `init`, see
+/// [`LNSocket::peer_supports_feature`](crate::LNSocket::peer_supports_feature).
+pub const LSPS_FEATURE_BIT: usize = 729;
+
+/// A JSON-RPC request, serialized.
+#[derive(Debug)]
+pub struct LspsRequest {
+    id: String,
+    body: Vec<u8>,
+}
+
+impl LspsRequest {
+    fn new(method: &str, params: Value) -> Result<Self, Error> {
+        let id = format!("{:016x}", rand::thread_rng().r#gen::<u64>());
+        let body = serde_json::to_vec(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))?;
+        Ok(Self { id, body })
+    }
+}
+
+impl Type for LspsRequest {
+    fn type_id(&self) -> u16 {
+        LSPS_MESSAGE_TYPE
+    }
+}
+
+impl Writeable for LspsRequest {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        w.write_all(&self.body)
+    }
+}
+
+impl Request for LspsRequest {
+    type Id = String;
+
+    fn request_id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+/// A JSON-RPC response from the LSP.
+#[derive(Debug)]
+pub struct LspsResponse {
+    id: String,
+    result: Result<Value, RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RawResponse {
+    id: Option<String>,
+    method: Option<String>,
+    /// `Some(Value::Null)` for `"result": null`, a valid result, unlike a missing one.
+    #[serde(default, deserialize_with = "present")]
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+fn present<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(d).map(Some)
+}
+
+impl Response for LspsResponse {
+    type Id = String;
+
+    /// Requests and notifications from the LSP aren't responses and are dropped.
+    fn read<R: LengthLimitedRead>(type_id: u16, r: &mut R) -> Result<Option<Self>, DecodeError> {
+        if type_id != LSPS_MESSAGE_TYPE {
+            return Ok(None);
+        }
+        let mut body = Vec::new();
+        r.read_to_end(&mut body)?;
+        let raw: RawResponse =
+            serde_json::from_slice(&body).map_err(|_| DecodeError::InvalidValue)?;
+        let (Some(id), None) = (raw.id, raw.method) else {
+            return Ok(None);
+        };
+        let result = match (raw.result, raw.error) {
+            (_, Some(err)) => Err(err),
+            (Some(result), None) => Ok(result),
+            (None, None) => return Err(DecodeError::InvalidValue),
+        };
+        Ok(Some(Self { id, result }))
+    }
+
+    fn request_id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+/// Talks to an LSP, see the [module docs](self). Cheap to clone.
+#[derive(Clone)]
+pub struct LspsClient {
+    inner: RequestResponse<LspsRequest, LspsResponse>,
+}
+
+impl LspsClient {
+    /// Take over `transport`, usually an [`LNSocket`](crate::LNSocket) after `init`.
+    pub fn spawn<T: MessageTransport>(transport: T) -> Self {
+        Self {
+            inner: RequestResponse::spawn(transport),
+        }
+    }
+
+    /// Fail calls the LSP doesn't answer within `timeout` with `Error::Io(TimedOut)`. No
+    /// timeout by default.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.inner = self.inner.with_timeout(timeout);
+        self
+    }
+
+    /// Call `method` with `params`, which must be a JSON object, and return its result.
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, Error> {
+        let resp = self.inner.call(LspsRequest::new(method, params)?).await?;
+        resp.result.map_err(Error::Rpc)
+    }
+
+    /// [`LspsClient::call`], decoding the result into `T`.
+    pub async fn call_typed<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: impl Serialize,
+    ) -> Result<T, Error> {
+        let result = self.call(method, serde_json::to_value(params)?).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// `lsps0.list_protocols`: the LSPS numbers the LSP supports, e.g. `[1, 2]`.
+    pub async fn list_protocols(&self) -> Result<Vec<u16>, Error> {
+        #[derive(Deserialize)]
+        struct Protocols {
+            protocols: Vec<u16>,
+        }
+        let reply: Protocols = self.call_typed("lsps0.list_protocols", json!({})).await?;
+        Ok(reply.protocols)
+    }
+
+    /// `lsps1.get_info`: the limits on channels the LSP sells.
+    pub async fn lsps1_get_info(&self) -> Result<lsps1::GetInfo, Error> {
+        self.call_typed("lsps1.get_info", json!({})).await
+    }
+
+    /// `lsps1.create_order`: buy a channel, to be paid as the returned order says.
+    pub async fn lsps1_create_order(
+        &self,
+        order: &lsps1::CreateOrder,
+    ) -> Result<lsps1::Order, Error> {
+        self.call_typed("lsps1.create_order", order).await
+    }
+
+    /// `lsps1.get_order`: where an order stands.
+    pub async fn lsps1_get_order(&self, order_id: &str) -> Result<lsps1::Order, Error> {
+        self.call_typed("lsps1.get_order", json!({ "order_id": order_id }))
+            .await
+    }
+
+    /// `lsps2.get_info`: what a JIT channel costs, with the LSP's `token` if it gave one.
+    pub async fn lsps2_get_info(&self, token: Option<&str>) -> Result<lsps2::GetInfo, Error> {
+        let params = match token {
+            Some(token) => json!({ "token": token }),
+            None => json!({}),
+        };
+        self.call_typed("lsps2.get_info", params).await
+    }
+
+    /// `lsps2.buy`: ask for a JIT channel on the terms of one entry of the menu from
+    /// [`LspsClient::lsps2_get_info`], passed back unchanged. Without `payment_size_msat` the
+    /// invoice may be for any amount within the menu's limits.
+    pub async fn lsps2_buy(
+        &self,
+        params: &lsps2::OpeningFeeParams,
+        payment_size_msat: Option<u64>,
+    ) -> Result<lsps2::Buy, Error> {
+        let mut req = json!({ "opening_fee_params": params });
+        if let Some(msat) = payment_size_msat {
+            req["payment_size_msat"] = msat.to_string().into();
+        }
+        self.call_typed("lsps2.buy", req).await
+    }
+}
+
+/// Amounts, which LSPS sends as decimal strings.
+mod amount {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount {
+        Number(u64),
+        String(String),
+    }
+
+    pub fn serialize<S: Serializer>(amount: &u64, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&amount.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+        match Amount::deserialize(d)? {
+            Amount::Number(amount) => Ok(amount),
+            Amount::String(s) => s
+                .parse()
+                .map_err(|_| D::Error::custom(format!("invalid amount {s:?}"))),
+        }
+    }
+}
+
+/// Buying a channel from the LSP, see [`LspsClient::lsps1_create_order`].
+pub mod lsps1 {
+    use super::*;
+
+    /// Reply of `lsps1.get_info`.
+    #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+    pub struct GetInfo {
+        pub min_required_channel_confirmations: u16,
+        pub min_funding_confirms_within_blocks: u16,
+        pub supports_zero_channel_reserve: bool,
+        pub max_channel_expiry_blocks: u32,
+        #[serde(with = "amount")]
+        pub min_initial_client_balance_sat: u64,
+        #[serde(with = "amount")]
+        pub max_initial_client_balance_sat: u64,
+        #[serde(with = "amount")]
+        pub min_initial_lsp_balance_sat: u64,
+        #[serde(with = "amount")]
+        pub max_initial_lsp_balance_sat: u64,
+        #[serde(with = "amount")]
+        pub min_channel_balance_sat: u64,
+        #[serde(with = "amount")]
+        pub max_channel_balance_sat: u64,
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
+
+    /// Params of `lsps1.create_order`.
+    #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+    pub struct CreateOrder {
+        #[serde(with = "amount")]
+        pub lsp_balance_sat: u64,
+        #[serde(with = "amount")]
+        pub client_balance_sat: u64,
+        pub required_channel_confirmations: u16,
+        pub funding_confirms_within_blocks: u16,
+        pub channel_expiry_blocks: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub token: Option<String>,
+        /// Where to refund an on-chain payment if the channel can't be opened.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub refund_onchain_address: Option<String>,
+        pub announce_channel: bool,
+    }
+
+    /// Reply of `lsps1.create_order` and `lsps1.get_order`.
+    ///
+    /// `payment` and `channel` are left as JSON: how an order can be paid is still changing
+    /// from one revision of the spec to the next.
+    #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+    pub struct Order {
+        pub order_id: String,
+        /// `"CREATED"`, `"COMPLETED"` or `"FAILED"`.
+        pub order_state: String,
+        pub created_at: String,
+        #[serde(with = "amount")]
+        pub lsp_balance_sat: u64,
+        #[serde(with = "amount")]
+        pub client_balance_sat: u64,
+        pub payment: Value,
+        /// Once the channel is open.
+        #[serde(default)]
+        pub channel: Option<Value>,
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
+}
+
+/// Just-in-time channels, see [`LspsClient::lsps2_buy`].
+pub mod lsps2 {
+    use super::*;
+
+    /// Reply of `lsps2.get_info`.
+    #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+    pub struct GetInfo {
+        pub opening_fee_params_menu: Vec<OpeningFeeParams>,
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
+
+    /// One way the LSP offers to open a JIT channel, signed by its `promise`: pass it back to
+    /// `lsps2.buy` exactly as received.
+    #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+    pub struct OpeningFeeParams {
+        #[serde(with = "amount")]
+        pub min_fee_msat: u64,
+        /// Parts per million of the payment.
+        pub proportional: u32,
+        /// An ISO 8601 timestamp.
+        pub valid_until: String,
+        pub min_lifetime: u32,
+        pub max_client_to_self_delay: u32,
+        #[serde(with = "amount")]
+        pub min_payment_size_msat: u64,
+        #[serde(with = "amount")]
+        pub max_payment_size_msat: u64,
+        pub promise: String,
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
+
+    impl OpeningFeeParams {
+        /// The opening fee for a payment of `payment_msat`, or `None` on overflow.
+        pub fn fee_msat(&self, payment_msat: u64) -> Option<u64> {
+            let proportional = (payment_msat as u128 * self.proportional as u128)
+                .div_ceil(1_000_000)
+                .try_into()
+                .ok()?;
+            Some(self.min_fee_msat.max(proportional))
+        }
+    }
+
+    /// Reply of `lsps2.buy`.
+    #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+    pub struct Buy {
+        /// The short channel id to put in the invoice's route hint, e.g. `"29451x4815x1"`.
+        pub jit_channel_scid: String,
+        pub lsp_cltv_expiry_delta: u32,
+        #[serde(default)]
+        pub client_trusts_lsp: bool,
+        #[serde(flatten)]
+        pub extra: Map<String, Value>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lnsocket::testing::*;
+
+    /// The next request the client sent, as JSON.
+    async fn lsp_recv(
+        server: &mut tokio::net::TcpStream,
+        peer: &mut crate::ln::peer_channel_encryptor::PeerChannelEncryptor,
+    ) -> Value {
+        let (type_id, payload) = peer_recv_raw(server, peer).await;
+        assert_eq!(type_id, LSPS_MESSAGE_TYPE);
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    fn lsp_reply(id: &Value, body: Value) -> LspsRequest {
+        let mut msg = json!({ "jsonrpc": "2.0", "id": id });
+        msg.as_object_mut()
+            .unwrap()
+            .extend(body.as_object().unwrap().clone());
+        LspsRequest {
+            id: String::new(),
+            body: serde_json::to_vec(&msg).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_are_json_rpc_over_37913() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let lsp = LspsClient::spawn(sock);
+
+        let call = tokio::spawn({
+            let lsp = lsp.clone();
+            async move { lsp.list_protocols().await }
+        });
+        let req = lsp_recv(&mut server, &mut peer).await;
+        assert_eq!(req["jsonrpc"], "2.0");
+        assert_eq!(req["method"], "lsps0.list_protocols");
+        assert_eq!(req["params"], json!({}));
+        // a notification from the LSP is no answer
+        let note = lsp_reply(
+            &Value::Null,
+            json!({ "method": "lsps0.note", "params": {} }),
+        );
+        peer_send(&mut server, &mut peer, &note).await;
+        let reply = lsp_reply(&req["id"], json!({ "result": { "protocols": [1, 2] } }));
+        peer_send(&mut server, &mut peer, &reply).await;
+        assert_eq!(call.await.unwrap().unwrap(), [1, 2]);
+
+        let call = tokio::spawn({
+            let lsp = lsp.clone();
+            async move { lsp.lsps1_get_order("abc").await }
+        });
+        let req = lsp_recv(&mut server, &mut peer).await;
+        assert_eq!(req["params"]["order_id"], "abc");
+        let reply = lsp_reply(
+            &req["id"],
+            json!({ "error": { "code": 101, "message": "Not found" } }),
+        );
+        peer_send(&mut server, &mut peer, &reply).await;
+        assert!(matches!(
+            call.await.unwrap(),
+            Err(Error::Rpc(RpcError { code: 101, .. }))
+        ));
+
+        let call = tokio::spawn({
+            let lsp = lsp.clone();
+            async move { lsp.call("lsps0.ack", json!({})).await }
+        });
+        let req = lsp_recv(&mut server, &mut peer).await;
+        let reply = lsp_reply(&req["id"], json!({ "result": null }));
+        peer_send(&mut server, &mut peer, &reply).await;
+        assert_eq!(call.await.unwrap().unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn lsps2_params_round_trip_as_strings() {
+        let menu: lsps2::GetInfo = serde_json::from_value(json!({
+            "opening_fee_params_menu": [{
+                "min_fee_msat": "546000",
+                "proportional": 1200,
+                "valid_until": "2023-02-23T08:47:30.511Z",
+                "min_lifetime": 1008,
+                "max_client_to_self_delay": 2016,
+                "min_payment_size_msat": "1000",
+                "max_payment_size_msat": "1000000",
+                "promise": "abcdefghijklmnopqrstuvwxyz",
+            }]
+        }))
+        .unwrap();
+        let params = &menu.opening_fee_params_menu[0];
+        assert_eq!(params.min_fee_msat, 546_000);
+        assert_eq!(params.fee_msat(1_000_000_000), Some(1_200_000));
+        assert_eq!(params.fee_msat(1_000), Some(546_000));
+
+        let back = serde_json::to_value(params).unwrap();
+        assert_eq!(back["min_fee_msat"], "546000");
+        assert_eq!(back["promise"], "abcdefghijklmnopqrstuvwxyz");
+    }
+}
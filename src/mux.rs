@@ -0,0 +1,781 @@
+//! Several independent byte streams over one connection.
+//!
+//! Two endpoints that both run lnsocket can share one Noise session between several app
+//! protocols with a [`Mux`]: either side opens streams named after their protocol, and the
+//! other accepts them. Each stream delivers its bytes in order and has its own flow control,
+//! so a slow reader of one protocol doesn't hold up the others.
+//!
+//! ```no_run
+//! use lnsocket::mux::Mux;
+//! # async fn ex(sock: lnsocket::LNSocket) -> Result<(), lnsocket::Error> {
+//! let mut mux = Mux::spawn(sock);
+//! let mut chat = mux.open("chat").await?;
+//! chat.send(b"hello").await?;
+//!
+//! while let Some(mut stream) = mux.accept().await {
+//!     println!("peer opened {}", stream.protocol());
+//!     tokio::spawn(async move {
+//!         while let Ok(Some(bytes)) = stream.recv().await {
+//!             println!("{} bytes", bytes.len());
+//!         }
+//!     });
+//! }
+//! # Ok(()) }
+//! ```
+//!
+//! ### Wire format
+//! Every frame is a custom message of one odd type, [`DEFAULT_MUX_MESSAGE_TYPE`] unless
+//! [`MuxConfig::message_type`] says otherwise, so peers without a mux ignore it. Its payload
+//! is a `u32` stream id, a `u8` kind and a body:
+//! - `open` (0): the protocol name, UTF-8. The side with the lower node id opens streams
+//!   with even ids, the other side odd ones.
+//! - `data` (1): bytes of the stream.
+//! - `credit` (2): a `u32`, bytes the receiver of the frame may send on top of what it could.
+//! - `close` (3): the sender of the frame won't send more on the stream.
+//! - `reset` (4): the stream is abandoned.
+//!
+//! ### Flow control
+//! Each side grants the other [`MuxConfig::window`] bytes of a stream when the stream opens,
+//! and more as its app reads them. A writer without credit waits in [`MuxStream::send`];
+//! data past the credit resets the stream.
+//!
+//! A background task owns the connection. It answers pings and drops every message that
+//! isn't a mux frame. When the transport has a [`MessageSender`], frames are written from a
+//! task of their own, so that the connection is still read while a write waits for the
+//! peer. It doesn't reconnect: once the connection fails, every stream fails with its error.
+//! The task stops once the [`Mux`] and all its streams are dropped.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use tokio::sync::{Semaphore, mpsc, oneshot};
+
+use crate::Error;
+use crate::commando::MessageTransport;
+use crate::ln::peer_channel_encryptor::LN_MAX_MSG_LEN;
+use crate::ln::wire::{self, Message, Type};
+use crate::sender::{Frame, MessageSender};
+use crate::util::ser::{Writeable, Writer};
+
+/// The custom message type of mux frames unless [`MuxConfig::message_type`] changes it.
+pub const DEFAULT_MUX_MESSAGE_TYPE: u16 = 0x8c4d;
+
+/// The default of [`MuxConfig::window`].
+pub const DEFAULT_WINDOW: u32 = 256 * 1024;
+
+/// Stream id and kind.
+const HEADER_LEN: usize = 5;
+
+/// The most data one frame carries: the largest message, less its type and the header.
+const MAX_CHUNK: usize = LN_MAX_MSG_LEN - 2 - HEADER_LEN;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Open = 0,
+    Data = 1,
+    Credit = 2,
+    Close = 3,
+    Reset = 4,
+}
+
+impl Kind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        Some(match kind {
+            0 => Kind::Open,
+            1 => Kind::Data,
+            2 => Kind::Credit,
+            3 => Kind::Close,
+            4 => Kind::Reset,
+            _ => return None,
+        })
+    }
+}
+
+struct MuxFrame<'a> {
+    type_id: u16,
+    stream_id: u32,
+    kind: Kind,
+    body: &'a [u8],
+}
+
+impl Type for MuxFrame<'_> {
+    fn type_id(&self) -> u16 {
+        self.type_id
+    }
+}
+
+impl Writeable for MuxFrame<'_> {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.stream_id.write(w)?;
+        (self.kind as u8).write(w)?;
+        w.write_all(self.body)
+    }
+}
+
+/// The stream id, kind and body of a mux frame's payload.
+fn parse_frame(payload: &[u8]) -> Option<(u32, Option<Kind>, &[u8])> {
+    let (header, body) = payload.split_at_checked(HEADER_LEN)?;
+    let stream_id = u32::from_be_bytes(header[..4].try_into().expect("four bytes"));
+    Some((stream_id, Kind::from_u8(header[4]), body))
+}
+
+/// Options of a [`Mux`]. Both ends must use the same message type.
+#[derive(Clone, Debug)]
+pub struct MuxConfig {
+    message_type: u16,
+    window: u32,
+    backlog: usize,
+}
+
+impl Default for MuxConfig {
+    fn default() -> Self {
+        Self {
+            message_type: DEFAULT_MUX_MESSAGE_TYPE,
+            window: DEFAULT_WINDOW,
+            backlog: 16,
+        }
+    }
+}
+
+impl MuxConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Carry frames in custom messages of `type_id`, which should be odd so that peers
+    /// without a mux ignore them. Default [`DEFAULT_MUX_MESSAGE_TYPE`].
+    pub fn message_type(mut self, type_id: u16) -> Self {
+        self.message_type = type_id;
+        self
+    }
+
+    /// How many bytes of a stream the peer may send ahead of our app reading them (default
+    /// [`DEFAULT_WINDOW`]). What a stream buffers is bounded by this.
+    pub fn window(mut self, bytes: u32) -> Self {
+        self.window = bytes.max(1);
+        self
+    }
+
+    /// How many streams the peer opened may wait for [`Mux::accept`] (default 16). Streams
+    /// opened past it are reset.
+    pub fn backlog(mut self, streams: usize) -> Self {
+        self.backlog = streams.max(1);
+        self
+    }
+}
+
+enum Ctrl {
+    Open {
+        protocol: String,
+        inbound: mpsc::UnboundedSender<Inbound>,
+        credit: Arc<Semaphore>,
+        done: oneshot::Sender<Result<u32, Error>>,
+    },
+    Data {
+        id: u32,
+        data: Vec<u8>,
+    },
+    /// The app read `len` bytes of the stream.
+    Consumed {
+        id: u32,
+        len: usize,
+    },
+    Close {
+        id: u32,
+    },
+    Dropped {
+        id: u32,
+    },
+}
+
+/// What the task hands a stream.
+enum Inbound {
+    Data(Vec<u8>),
+    Closed,
+    Failed(Error),
+}
+
+/// Streams over one connection, see the [module docs](self).
+pub struct Mux {
+    ctrl: mpsc::UnboundedSender<Ctrl>,
+    incoming: mpsc::Receiver<MuxStream>,
+}
+
+impl Mux {
+    /// Take over `transport` and run streams on it from a background task.
+    pub fn spawn<T: MessageTransport>(transport: T) -> Self {
+        Self::spawn_with_config(transport, MuxConfig::default())
+    }
+
+    pub fn spawn_with_config<T: MessageTransport>(transport: T, config: MuxConfig) -> Self {
+        let (ctrl, ctrl_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::channel(config.backlog);
+        let next_id = if transport.our_node_id() < transport.their_pubkey() {
+            0
+        } else {
+            1
+        };
+        let (failed_tx, write_failed) = mpsc::unbounded_channel();
+        let writer = transport.sender().map(|sender| {
+            let (writer, frames) = mpsc::unbounded_channel();
+            tokio::spawn(write_frames(sender, frames, failed_tx));
+            writer
+        });
+        let pump = Pump {
+            transport,
+            writer,
+            write_failed,
+            config,
+            ctrl: ctrl.downgrade(),
+            incoming: incoming_tx,
+            streams: HashMap::new(),
+            next_id,
+        };
+        tokio::spawn(pump.run(ctrl_rx));
+        Self { ctrl, incoming }
+    }
+
+    /// Open a stream for `protocol`, which the peer gets from [`Mux::accept`]. Data can be
+    /// sent right away; it goes out once the peer has granted credit.
+    pub async fn open(&self, protocol: impl Into<String>) -> Result<MuxStream, Error> {
+        let protocol = protocol.into();
+        let (done, rx) = oneshot::channel();
+        let (inbound_tx, inbound) = mpsc::unbounded_channel();
+        let credit = Arc::new(Semaphore::new(0));
+        self.ctrl
+            .send(Ctrl::Open {
+                protocol: protocol.clone(),
+                inbound: inbound_tx,
+                credit: credit.clone(),
+                done,
+            })
+            .map_err(|_| Error::NotConnected)?;
+        let id = rx.await.map_err(|_| Error::NotConnected)??;
+        Ok(MuxStream {
+            id,
+            protocol,
+            ctrl: self.ctrl.clone(),
+            inbound,
+            credit,
+            closed: false,
+        })
+    }
+
+    /// The next stream the peer opened, `None` once the connection is gone.
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.incoming.recv().await
+    }
+}
+
+/// One stream of a [`Mux`]. Dropping it before both sides closed it resets it.
+pub struct MuxStream {
+    id: u32,
+    protocol: String,
+    ctrl: mpsc::UnboundedSender<Ctrl>,
+    inbound: mpsc::UnboundedReceiver<Inbound>,
+    credit: Arc<Semaphore>,
+    closed: bool,
+}
+
+impl std::fmt::Debug for MuxStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxStream")
+            .field("id", &self.id)
+            .field("protocol", &self.protocol)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The protocol the stream was opened for.
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    /// Queue `data` to be sent, waiting for the peer to grant credit for it. Fails with
+    /// `Io(BrokenPipe)` after [`MuxStream::close`], and with `Io(ConnectionReset)` once the
+    /// stream or the connection is gone.
+    pub async fn send(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        if self.closed {
+            return Err(Error::Io(io::ErrorKind::BrokenPipe));
+        }
+        let reset = |_| Error::Io(io::ErrorKind::ConnectionReset);
+        while !data.is_empty() {
+            // whatever credit there is, at least a byte
+            self.credit.acquire().await.map_err(reset)?.forget();
+            let len = data
+                .len()
+                .min(MAX_CHUNK)
+                .min(1 + self.credit.available_permits());
+            if len > 1 {
+                self.credit
+                    .try_acquire_many(len as u32 - 1)
+                    .map_err(|_| Error::Io(io::ErrorKind::ConnectionReset))?
+                    .forget();
+            }
+            let (chunk, rest) = data.split_at(len);
+            self.ctrl
+                .send(Ctrl::Data {
+                    id: self.id,
+                    data: chunk.to_vec(),
+                })
+                .map_err(|_| Error::NotConnected)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// The next bytes the peer sent, `None` once it closed the stream. Fails with
+    /// `Io(ConnectionReset)` when the peer reset it, `Io(InvalidData)` when it sent more than
+    /// its credit, and with the connection's error when that failed.
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self.inbound.recv().await {
+            Some(Inbound::Data(data)) => {
+                let _ = self.ctrl.send(Ctrl::Consumed {
+                    id: self.id,
+                    len: data.len(),
+                });
+                Ok(Some(data))
+            }
+            Some(Inbound::Failed(err)) => Err(err),
+            Some(Inbound::Closed) | None => Ok(None),
+        }
+    }
+
+    /// Tell the peer we won't send more. The stream can still be read.
+    pub fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            let _ = self.ctrl.send(Ctrl::Close { id: self.id });
+        }
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let _ = self.ctrl.send(Ctrl::Dropped { id: self.id });
+    }
+}
+
+/// A stream as the task sees it.
+struct StreamState {
+    /// `None` once the peer closed the stream.
+    to_app: Option<mpsc::UnboundedSender<Inbound>>,
+    /// What we may send.
+    credit: Arc<Semaphore>,
+    /// Bytes received beyond those we granted credit for again.
+    unacked: usize,
+    /// Bytes the app read that we haven't granted credit for yet.
+    consumed: usize,
+    closed: bool,
+}
+
+impl StreamState {
+    fn new(to_app: mpsc::UnboundedSender<Inbound>, credit: Arc<Semaphore>) -> Self {
+        Self {
+            to_app: Some(to_app),
+            credit,
+            unacked: 0,
+            consumed: 0,
+            closed: false,
+        }
+    }
+
+    fn fail(&mut self, err: Error) {
+        if let Some(to_app) = self.to_app.take() {
+            let _ = to_app.send(Inbound::Failed(err));
+        }
+        self.credit.close();
+    }
+}
+
+/// Write `frames` in order until one fails, which is reported on `failed`.
+async fn write_frames(
+    sender: MessageSender,
+    mut frames: mpsc::UnboundedReceiver<Frame>,
+    failed: mpsc::UnboundedSender<Error>,
+) {
+    while let Some(frame) = frames.recv().await {
+        if let Err(err) = sender.send_frames(vec![frame]).await {
+            let _ = failed.send(err);
+            return;
+        }
+    }
+}
+
+struct Pump<T> {
+    transport: T,
+    /// Frames for [`write_frames`], `None` when the transport has no sender and frames are
+    /// written inline.
+    writer: Option<mpsc::UnboundedSender<Frame>>,
+    write_failed: mpsc::UnboundedReceiver<Error>,
+    config: MuxConfig,
+    /// For the streams the peer opens; weak so that the task stops with the last handle.
+    ctrl: mpsc::WeakUnboundedSender<Ctrl>,
+    incoming: mpsc::Sender<MuxStream>,
+    streams: HashMap<u32, StreamState>,
+    next_id: u32,
+}
+
+impl<T: MessageTransport> Pump<T> {
+    async fn run(mut self, mut ctrl_rx: mpsc::UnboundedReceiver<Ctrl>) {
+        let err = loop {
+            let res = tokio::select! {
+                ctrl = ctrl_rx.recv() => {
+                    let Some(ctrl) = ctrl else {
+                        tracing::debug!("mux: every handle is gone, stopping");
+                        return;
+                    };
+                    self.on_ctrl(ctrl).await
+                }
+                frame = self.transport.read_frame() => match frame {
+                    Ok((type_id, payload)) => self.on_message(type_id, payload).await,
+                    Err(err) => Err(err),
+                },
+                Some(err) = self.write_failed.recv() => Err(err),
+            };
+            if let Err(err) = res {
+                break err;
+            }
+        };
+        tracing::debug!("mux: connection failed: {err}");
+        for stream in self.streams.values_mut() {
+            stream.fail(err.clone());
+        }
+    }
+
+    async fn write(&mut self, stream_id: u32, kind: Kind, body: &[u8]) -> Result<(), Error> {
+        let frame = Frame::new(&MuxFrame {
+            type_id: self.config.message_type,
+            stream_id,
+            kind,
+            body,
+        })?;
+        let Some(writer) = &self.writer else {
+            return self.transport.write_frame(frame).await;
+        };
+        if writer.send(frame).is_err() {
+            // the writer stopped on a failure it reported
+            return Err(self.write_failed.try_recv().unwrap_or(Error::NotConnected));
+        }
+        Ok(())
+    }
+
+    async fn on_ctrl(&mut self, ctrl: Ctrl) -> Result<(), Error> {
+        match ctrl {
+            Ctrl::Open {
+                protocol,
+                inbound,
+                credit,
+                done,
+            } => {
+                let id = self.next_id;
+                if protocol.len() > MAX_CHUNK {
+                    let _ = done.send(Err(Error::Io(io::ErrorKind::InvalidInput)));
+                    return Ok(());
+                }
+                if let Err(err) = self.open(id, &protocol).await {
+                    let _ = done.send(Err(err.clone()));
+                    return Err(err);
+                }
+                self.next_id = id.wrapping_add(2);
+                self.streams.insert(id, StreamState::new(inbound, credit));
+                let _ = done.send(Ok(id));
+            }
+            Ctrl::Data { id, data } => {
+                if self.streams.contains_key(&id) {
+                    self.write(id, Kind::Data, &data).await?;
+                }
+            }
+            Ctrl::Consumed { id, len } => {
+                let half_window = self.config.window as usize / 2;
+                let Some(stream) = self.streams.get_mut(&id) else {
+                    return Ok(());
+                };
+                stream.consumed += len;
+                // grant in batches, not a frame per read
+                if stream.consumed >= half_window.max(1) {
+                    let grant = std::mem::take(&mut stream.consumed);
+                    stream.unacked -= grant;
+                    self.write(id, Kind::Credit, &(grant as u32).to_be_bytes())
+                        .await?;
+                }
+            }
+            Ctrl::Close { id } => {
+                let Some(stream) = self.streams.get_mut(&id) else {
+                    return Ok(());
+                };
+                stream.closed = true;
+                if stream.to_app.is_none() {
+                    self.streams.remove(&id);
+                }
+                self.write(id, Kind::Close, &[]).await?;
+            }
+            Ctrl::Dropped { id } => {
+                // still here, so not closed both ways
+                if self.streams.remove(&id).is_some() {
+                    self.write(id, Kind::Reset, &[]).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Announce stream `id` and grant the peer its window.
+    async fn open(&mut self, id: u32, protocol: &str) -> Result<(), Error> {
+        self.write(id, Kind::Open, protocol.as_bytes()).await?;
+        self.write(id, Kind::Credit, &self.config.window.to_be_bytes())
+            .await
+    }
+
+    async fn on_message(&mut self, type_id: u16, payload: Vec<u8>) -> Result<(), Error> {
+        if type_id == self.config.message_type {
+            match parse_frame(&payload) {
+                Some((id, Some(kind), body)) => return self.on_frame(id, kind, body).await,
+                Some((id, None, _)) => tracing::debug!("mux: unknown frame kind on stream {id}"),
+                None => tracing::warn!("mux: frame too short"),
+            }
+            return Ok(());
+        }
+        match wire::read_payload::<(), _>(&mut &payload[..], type_id, |_, _| Ok(None)) {
+            Ok(Message::Ping(ping)) => self.transport.answer_ping(&ping).await,
+            Ok(_) => {
+                tracing::trace!("mux: ignoring message type {type_id}");
+                Ok(())
+            }
+            Err(err) => {
+                tracing::warn!("mux: undecodable message type {type_id}: {err:?}");
+                Ok(())
+            }
+        }
+    }
+
+    async fn on_frame(&mut self, id: u32, kind: Kind, body: &[u8]) -> Result<(), Error> {
+        if kind == Kind::Open {
+            return self.on_open(id, body).await;
+        }
+        let window = self.config.window as usize;
+        let Some(stream) = self.streams.get_mut(&id) else {
+            // e.g. data that crossed our reset
+            tracing::trace!("mux: {kind:?} for unknown stream {id}");
+            return Ok(());
+        };
+        match kind {
+            Kind::Open => unreachable!("handled above"),
+            Kind::Data => {
+                let Some(to_app) = &stream.to_app else {
+                    tracing::debug!("mux: data on stream {id} after its close");
+                    return Ok(());
+                };
+                stream.unacked += body.len();
+                if stream.unacked > window {
+                    tracing::warn!("mux: stream {id} sent past its credit, resetting");
+                    stream.fail(Error::Io(io::ErrorKind::InvalidData));
+                    self.streams.remove(&id);
+                    return self.write(id, Kind::Reset, &[]).await;
+                }
+                let _ = to_app.send(Inbound::Data(body.to_vec()));
+            }
+            Kind::Credit => {
+                let Ok(grant) = <[u8; 4]>::try_from(body) else {
+                    tracing::debug!("mux: malformed credit on stream {id}");
+                    return Ok(());
+                };
+                let room = Semaphore::MAX_PERMITS - stream.credit.available_permits();
+                let grant = u32::from_be_bytes(grant) as usize;
+                stream.credit.add_permits(grant.min(room));
+            }
+            Kind::Close => {
+                if let Some(to_app) = stream.to_app.take() {
+                    let _ = to_app.send(Inbound::Closed);
+                }
+                if stream.closed {
+                    self.streams.remove(&id);
+                }
+            }
+            Kind::Reset => {
+                stream.fail(Error::Io(io::ErrorKind::ConnectionReset));
+                self.streams.remove(&id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_open(&mut self, id: u32, body: &[u8]) -> Result<(), Error> {
+        if id % 2 == self.next_id % 2 || self.streams.contains_key(&id) {
+            tracing::warn!("mux: peer opened stream {id}, which isn't its to open");
+            return Ok(());
+        }
+        let (Ok(protocol), Some(ctrl)) = (std::str::from_utf8(body), self.ctrl.upgrade()) else {
+            return self.write(id, Kind::Reset, &[]).await;
+        };
+        let (inbound_tx, inbound) = mpsc::unbounded_channel();
+        let credit = Arc::new(Semaphore::new(0));
+        self.streams
+            .insert(id, StreamState::new(inbound_tx, credit.clone()));
+        self.write(id, Kind::Credit, &self.config.window.to_be_bytes())
+            .await?;
+        let stream = MuxStream {
+            id,
+            protocol: protocol.to_string(),
+            ctrl,
+            inbound,
+            credit,
+            closed: false,
+        };
+        // a stream nobody takes is dropped, which resets it
+        if self.incoming.try_send(stream).is_err() {
+            tracing::debug!("mux: nobody accepted stream {id}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lnsocket::testing::*;
+    use std::time::Duration;
+
+    async fn read_all(stream: &mut MuxStream) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = stream.recv().await.unwrap() {
+            bytes.extend(chunk);
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn streams_carry_bytes_both_ways_independently() {
+        let (a, b) = fake_pair();
+        let a = Mux::spawn(a);
+        let mut b = Mux::spawn(b);
+
+        let mut chat = a.open("chat").await.unwrap();
+        let mut files = a.open("files").await.unwrap();
+        assert_eq!((chat.id(), files.id()), (0, 2));
+        let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        files.send(&big).await.unwrap();
+        files.close();
+        chat.send(b"hi").await.unwrap();
+
+        let mut their_chat = b.accept().await.unwrap();
+        let mut their_files = b.accept().await.unwrap();
+        assert_eq!(their_chat.protocol(), "chat");
+        assert_eq!(their_files.protocol(), "files");
+        assert_eq!(their_chat.recv().await.unwrap().unwrap(), b"hi");
+        their_chat.send(b"hello").await.unwrap();
+        assert_eq!(chat.recv().await.unwrap().unwrap(), b"hello");
+
+        assert_eq!(read_all(&mut their_files).await, big);
+        assert!(matches!(
+            files.send(b"more").await,
+            Err(Error::Io(io::ErrorKind::BrokenPipe))
+        ));
+
+        // the other side opens odd ids
+        let back = b.open("chat").await.unwrap();
+        assert_eq!(back.id(), 1);
+        drop(back);
+        let mut reset = a.open("x").await.unwrap();
+        drop(their_chat);
+        assert!(matches!(
+            chat.recv().await,
+            Err(Error::Io(io::ErrorKind::ConnectionReset))
+        ));
+        assert!(matches!(
+            chat.send(b"x").await,
+            Err(Error::Io(io::ErrorKind::ConnectionReset))
+        ));
+        reset.close();
+    }
+
+    #[tokio::test]
+    async fn writers_wait_for_the_reader() {
+        let (a, b) = fake_pair();
+        let a = Mux::spawn_with_config(a, MuxConfig::new().window(8));
+        let mut b = Mux::spawn_with_config(b, MuxConfig::new().window(8));
+
+        let mut stream = a.open("slow").await.unwrap();
+        let mut theirs = b.accept().await.unwrap();
+        let mut sent = Box::pin(async move {
+            stream.send(&[7; 20]).await.unwrap();
+            stream.close();
+            stream
+        });
+        // nothing read yet: the writer stops at the window
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut sent)
+                .await
+                .is_err()
+        );
+        let (_stream, bytes) = tokio::join!(sent, read_all(&mut theirs));
+        assert_eq!(bytes, [7; 20]);
+    }
+
+    #[tokio::test]
+    async fn both_ends_can_write_a_full_window_at_once() {
+        // more than the loopback connection buffers, so writes wait for the peer to read
+        let window = 16 * 1024 * 1024;
+        let (a, b) = socket_pair().await;
+        let mut a = Mux::spawn_with_config(a, MuxConfig::new().window(window));
+        let mut b = Mux::spawn_with_config(b, MuxConfig::new().window(window));
+        let big = vec![5; window as usize];
+
+        let mut ours = a.open("a").await.unwrap();
+        let mut theirs = b.open("b").await.unwrap();
+        ours.send(&big).await.unwrap();
+        theirs.send(&big).await.unwrap();
+        ours.close();
+        theirs.close();
+
+        let (mut from_b, mut from_a) = (a.accept().await.unwrap(), b.accept().await.unwrap());
+        let read = async { tokio::join!(read_all(&mut from_a), read_all(&mut from_b)) };
+        let (got_a, got_b) = tokio::time::timeout(Duration::from_secs(30), read)
+            .await
+            .expect("both ends read while writing");
+        assert_eq!((got_a.len(), got_b.len()), (big.len(), big.len()));
+    }
+
+    #[tokio::test]
+    async fn data_past_the_credit_resets_the_stream() {
+        let (a, mut raw) = fake_pair();
+        let mut a = Mux::spawn_with_config(a, MuxConfig::new().window(4));
+        let frame = |kind: Kind, body: &[u8]| {
+            Frame::new(&MuxFrame {
+                type_id: DEFAULT_MUX_MESSAGE_TYPE,
+                stream_id: 1,
+                kind,
+                body,
+            })
+            .unwrap()
+        };
+        raw.write_frame(frame(Kind::Open, b"p")).await.unwrap();
+        let mut stream = a.accept().await.unwrap();
+        let (type_id, credit) = raw.read_frame().await.unwrap();
+        assert_eq!(type_id, DEFAULT_MUX_MESSAGE_TYPE);
+        assert_eq!(credit, [0, 0, 0, 1, Kind::Credit as u8, 0, 0, 0, 4]);
+
+        raw.write_frame(frame(Kind::Data, b"abcde")).await.unwrap();
+        assert!(matches!(
+            stream.recv().await,
+            Err(Error::Io(io::ErrorKind::InvalidData))
+        ));
+        let (_, reset) = raw.read_frame().await.unwrap();
+        assert_eq!(reset, [0, 0, 0, 1, Kind::Reset as u8]);
+
+        // streams fail with the connection
+        raw.write_frame(frame(Kind::Open, b"q")).await.unwrap();
+        let mut stream = a.accept().await.unwrap();
+        drop(raw);
+        assert!(matches!(
+            stream.recv().await,
+            Err(Error::Io(io::ErrorKind::UnexpectedEof))
+        ));
+    }
+}
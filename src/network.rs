@@ -0,0 +1,186 @@
+//! Talking to nodes on testnet, signet and regtest.
+//!
+//! Nothing in a BOLT 8 connection says which chain a node is on until `init`, where it may
+//! list the chains it cares about. Tell the [`Dialer`](crate::dial::Dialer) which network you
+//! expect with [`Dialer::with_network`](crate::dial::Dialer::with_network) and our `init`
+//! advertises it, while a peer that only lists other chains fails the connect with
+//! [`Error::NetworkMismatch`] instead of answering RPCs about coins you didn't mean to touch.
+//! [`Dialer::with_networks`](crate::dial::Dialer::with_networks) does the same for several
+//! chains at once.
+//!
+//! [`NodeUri`] parses the usual `<node id>@<host>[:port]` strings, with the port defaulting to
+//! the one CLN listens on for the network:
+//!
+//! ```
+//! use bitcoin::Network;
+//! use lnsocket::network::NodeUri;
+//!
+//! let uri = NodeUri::parse(
+//!     "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619@127.0.0.1",
+//!     Network::Regtest,
+//! )
+//! .unwrap();
+//! assert_eq!(uri.addr.to_string(), "127.0.0.1:19846");
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::Network;
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::PublicKey;
+
+use crate::Error;
+use crate::ln::msgs;
+use crate::socket_addr::{SocketAddress, SocketAddressParseError};
+
+/// The port CLN listens on by default on `network`.
+pub fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Testnet => 19735,
+        Network::Testnet4 => 49735,
+        Network::Signet => 39735,
+        Network::Regtest => 19846,
+        _ => crate::socket_addr::DEFAULT_PORT,
+    }
+}
+
+/// The chain hash `init` uses for `network`.
+pub fn chain_hash(network: Network) -> ChainHash {
+    ChainHash::using_genesis_block(network)
+}
+
+/// The chain hashes `init` uses for `networks`, in order.
+pub fn chain_hashes(networks: &[Network]) -> Vec<ChainHash> {
+    networks.iter().copied().map(chain_hash).collect()
+}
+
+/// Check that a peer's `init` is compatible with `network`. A peer that doesn't list any
+/// chains is assumed to be fine with all of them.
+pub fn check_peer_network(their_init: &msgs::Init, network: Network) -> Result<(), Error> {
+    check_peer_networks(their_init, &[network])
+}
+
+/// Like [`check_peer_network`], with the peer on any one of `networks` being enough. No
+/// networks at all are fine with any peer.
+pub fn check_peer_networks(their_init: &msgs::Init, networks: &[Network]) -> Result<(), Error> {
+    let ours = chain_hashes(networks);
+    match &their_init.networks {
+        Some(theirs)
+            if !ours.is_empty()
+                && !theirs.is_empty()
+                && !theirs.iter().any(|chain| ours.contains(chain)) =>
+        {
+            Err(Error::NetworkMismatch {
+                ours,
+                theirs: theirs.clone(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A chain hash by network name where we know it, for messages.
+pub(crate) struct ChainName<'a>(pub &'a ChainHash);
+
+impl fmt::Display for ChainName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match Network::from_chain_hash(*self.0) {
+            Some(network) => write!(f, "{network}"),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// A node id and the address to reach it at, as in `<node id>@<host>[:port]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeUri {
+    pub node_id: PublicKey,
+    pub addr: SocketAddress,
+}
+
+impl NodeUri {
+    /// Parse `<node id>@<address>`, defaulting the port to [`default_port`] of `network`.
+    pub fn parse(s: &str, network: Network) -> Result<Self, Error> {
+        let (node_id, addr) = s
+            .trim()
+            .split_once('@')
+            .ok_or(Error::InvalidAddress(SocketAddressParseError::InvalidInput))?;
+        let node_id = node_id
+            .parse()
+            .map_err(|_| Error::InvalidAddress(SocketAddressParseError::InvalidInput))?;
+        let addr = SocketAddress::parse_with_default_port(addr, default_port(network))?;
+        Ok(Self { node_id, addr })
+    }
+}
+
+impl FromStr for NodeUri {
+    type Err = Error;
+
+    /// Like [`NodeUri::parse`] on mainnet.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Self::parse(s, Network::Bitcoin)
+    }
+}
+
+impl fmt::Display for NodeUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.node_id, self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE_ID: &str = "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619";
+
+    #[test]
+    fn node_uri_ports_follow_the_network() {
+        let uri: NodeUri = format!("{NODE_ID}@node.example.com").parse().unwrap();
+        assert_eq!(uri.to_string(), format!("{NODE_ID}@node.example.com:9735"));
+
+        let uri = NodeUri::parse(&format!("{NODE_ID}@node.example.com"), Network::Signet).unwrap();
+        assert_eq!(uri.addr.to_string(), "node.example.com:39735");
+        // an explicit port wins
+        let uri = NodeUri::parse(&format!("{NODE_ID}@127.0.0.1:1234"), Network::Testnet).unwrap();
+        assert_eq!(uri.addr.to_string(), "127.0.0.1:1234");
+
+        assert!("node.example.com".parse::<NodeUri>().is_err());
+        assert!("02ab@node.example.com".parse::<NodeUri>().is_err());
+    }
+
+    #[test]
+    fn peer_network_must_overlap() {
+        let mut init = msgs::Init {
+            features: vec![],
+            global_features: vec![],
+            networks: None,
+            remote_network_address: None,
+        };
+        assert!(check_peer_network(&init, Network::Regtest).is_ok());
+
+        init.networks = Some(vec![
+            chain_hash(Network::Testnet),
+            chain_hash(Network::Regtest),
+        ]);
+        assert!(check_peer_network(&init, Network::Regtest).is_ok());
+
+        init.networks = Some(vec![chain_hash(Network::Bitcoin)]);
+        let err = check_peer_network(&init, Network::Regtest).unwrap_err();
+        assert!(matches!(err, Error::NetworkMismatch { .. }));
+        assert_eq!(err.to_string(), "peer is on bitcoin, we are on regtest");
+
+        let ours = [Network::Signet, Network::Bitcoin];
+        assert!(check_peer_networks(&init, &ours).is_ok());
+        assert!(check_peer_networks(&init, &[]).is_ok());
+        let err = check_peer_networks(&init, &ours[..1]).unwrap_err();
+        assert_eq!(err.to_string(), "peer is on bitcoin, we are on signet");
+        init.networks = Some(vec![chain_hash(Network::Testnet)]);
+        let err = check_peer_networks(&init, &ours).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "peer is on testnet, we are on signet, bitcoin"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! A node's alias and color, for showing it in a UI.
+//!
+//! Nodes publish both in their `node_announcement` gossip, and CLN reports them in `getinfo`
+//! and `listnodes`. [`NodeInfo`] can be built from either: decode an announcement the peer
+//! sent with [`NodeInfo::from_node_announcement`], or, with a rune, ask the node itself with
+//! [`CommandoClient::node_info`](crate::CommandoClient::node_info) or about another node with
+//! [`CommandoClient::lookup_node_info`](crate::CommandoClient::lookup_node_info).
+//!
+//! ```
+//! use lnsocket::node_info::NodeInfo;
+//!
+//! let getinfo = serde_json::json!({
+//!     "id": "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619",
+//!     "alias": "LOUDPRODUCER", "color": "02eec7", "num_peers": 3,
+//! });
+//! let info = NodeInfo::from_rpc(&getinfo).unwrap();
+//! assert_eq!(info.to_string(), "LOUDPRODUCER (#02eec7)");
+//! ```
+
+use std::fmt;
+
+use bitcoin::secp256k1::PublicKey;
+use serde_json::Value;
+
+use crate::ln::msgs::DecodeError;
+
+/// How a node presents itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub node_id: PublicKey,
+    /// The alias with its zero padding removed. Not necessarily unique, nor chosen in good
+    /// faith: don't use it to identify a node.
+    pub alias: String,
+    /// RGB.
+    pub color: [u8; 3],
+}
+
+impl NodeInfo {
+    /// Decode the payload (after the type) of a `node_announcement`. The signature is not
+    /// checked.
+    pub fn from_node_announcement(payload: &[u8]) -> Result<Self, DecodeError> {
+        let take = |at: usize, len: usize| payload.get(at..at + len).ok_or(DecodeError::ShortRead);
+        // signature, then the features and their length
+        let flen = u16::from_be_bytes(take(64, 2)?.try_into().unwrap()) as usize;
+        // and the timestamp
+        let at = 64 + 2 + flen + 4;
+        let node_id =
+            PublicKey::from_slice(take(at, 33)?).map_err(|_| DecodeError::InvalidValue)?;
+        let color = take(at + 33, 3)?.try_into().unwrap();
+        let alias = take(at + 33 + 3, 32)?;
+        let end = alias.iter().position(|b| *b == 0).unwrap_or(alias.len());
+        Ok(Self {
+            node_id,
+            alias: String::from_utf8_lossy(&alias[..end]).into_owned(),
+            color,
+        })
+    }
+
+    /// Pick the fields out of a `getinfo` reply or a `listnodes` entry. `None` if one is
+    /// missing, which for `listnodes` means the node hasn't announced itself.
+    pub fn from_rpc(value: &Value) -> Option<Self> {
+        let node_id = value.get("id").or_else(|| value.get("nodeid"))?;
+        let color = hex::decode(value.get("color")?.as_str()?).ok()?;
+        Some(Self {
+            node_id: node_id.as_str()?.parse().ok()?,
+            alias: value.get("alias")?.as_str()?.to_string(),
+            color: color.try_into().ok()?,
+        })
+    }
+
+    /// The color as CLN writes it, `rrggbb`.
+    pub fn color_hex(&self) -> String {
+        hex::encode(self.color)
+    }
+}
+
+impl fmt::Display for NodeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (#{})", self.alias, self.color_hex())
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod client {
+    use serde_json::{Value, json};
+
+    use super::NodeInfo;
+    use crate::commando::MessageTransport;
+    use crate::{CommandoClient, Error};
+    use bitcoin::secp256k1::PublicKey;
+
+    impl<T: MessageTransport> CommandoClient<T> {
+        /// The alias and color of the node we are connected to, from `getinfo`.
+        pub async fn node_info(&self) -> Result<NodeInfo, Error> {
+            let info = self.call("getinfo", json!({})).await?;
+            NodeInfo::from_rpc(&info).ok_or(Error::Json)
+        }
+
+        /// The alias and color the node has seen `node_id` announce, from `listnodes`.
+        /// `None` if it hasn't seen an announcement.
+        pub async fn lookup_node_info(
+            &self,
+            node_id: &PublicKey,
+        ) -> Result<Option<NodeInfo>, Error> {
+            let reply = self
+                .call("listnodes", json!({ "id": node_id.to_string() }))
+                .await?;
+            let nodes = reply
+                .get("nodes")
+                .and_then(Value::as_array)
+                .ok_or(Error::Json)?;
+            Ok(nodes.iter().find_map(NodeInfo::from_rpc))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NODE_ID: &str = "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619";
+
+    #[test]
+    fn from_node_announcement() {
+        let node_id: PublicKey = NODE_ID.parse().unwrap();
+        let mut payload = vec![0u8; 64];
+        payload.extend_from_slice(&[0, 2, 0x80, 0x00]); // features
+        payload.extend_from_slice(&1_700_000_000u32.to_be_bytes());
+        payload.extend_from_slice(&node_id.serialize());
+        payload.extend_from_slice(&[0xff, 0x99, 0x00]);
+        let mut alias = [0u8; 32];
+        alias[..5].copy_from_slice("nodé".as_bytes());
+        payload.extend_from_slice(&alias);
+        payload.extend_from_slice(&[0, 0]); // no addresses
+
+        let info = NodeInfo::from_node_announcement(&payload).unwrap();
+        assert_eq!(info.node_id, node_id);
+        assert_eq!(info.alias, "nodé");
+        assert_eq!(info.color_hex(), "ff9900");
+
+        assert_eq!(
+            NodeInfo::from_node_announcement(&payload[..100]),
+            Err(DecodeError::ShortRead)
+        );
+    }
+
+    #[test]
+    fn from_listnodes_entries() {
+        let entry =
+            json!({"nodeid": NODE_ID, "alias": "a", "color": "000001", "last_timestamp": 1});
+        assert_eq!(NodeInfo::from_rpc(&entry).unwrap().color, [0, 0, 1]);
+        // known from a channel, but never announced
+        assert_eq!(NodeInfo::from_rpc(&json!({ "nodeid": NODE_ID })), None);
+        assert_eq!(
+            NodeInfo::from_rpc(&json!({"id": NODE_ID, "alias": "a", "color": "01"})),
+            None
+        );
+    }
+}
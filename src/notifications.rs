@@ -0,0 +1,268 @@
+//! Asynchronous Core Lightning notifications received over commando.
+//!
+//! Besides replies to our own requests, a node may push JSON-RPC notifications
+//! (`{"method": ..., "params": ...}` without an `id`) over commando when the rune allows it,
+//! e.g. one carrying a `notifications=...` restriction. [`CommandoClient::notifications`]
+//! returns a [`NotificationStream`] of everything that arrives this way.
+//!
+//! Each [`Notification`] keeps its raw `params`; [`Notification::typed`] decodes the common
+//! topics into the structs in this module, and [`Notification::parse`] decodes into any
+//! serde type for the rest (e.g. `log` or `rpc_command`).
+//!
+//! [`CommandoClient::notifications`]: crate::CommandoClient::notifications
+
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+#[cfg(feature = "tokio")]
+use tokio::sync::broadcast;
+
+use crate::Error;
+
+/// How many notifications are buffered per stream before slow readers start dropping them.
+#[cfg(feature = "tokio")]
+pub(crate) const NOTIFICATION_BUFFER: usize = 256;
+
+/// A JSON-RPC notification pushed by the node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub method: String,
+    pub params: Value,
+}
+
+impl Notification {
+    /// Classifies a decoded reply body: JSON-RPC notifications have a `method` but no `id`.
+    /// Anything else is handed back untouched.
+    pub(crate) fn from_json(value: Value) -> Result<Self, Value> {
+        let is_notification = value.as_object().is_some_and(|obj| {
+            obj.get("method").is_some_and(Value::is_string) && !obj.contains_key("id")
+        });
+        if !is_notification {
+            return Err(value);
+        }
+
+        let Value::Object(mut obj) = value else {
+            unreachable!("checked above");
+        };
+        let Some(Value::String(method)) = obj.remove("method") else {
+            unreachable!("checked above");
+        };
+        let params = obj.remove("params").unwrap_or(Value::Null);
+        Ok(Notification { method, params })
+    }
+
+    /// Decode the payload into `T`. CLN wraps the payload in an object keyed by the topic
+    /// name (`{"invoice_payment": {...}}`); that wrapper is removed if present.
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let payload = self.params.get(&self.method).unwrap_or(&self.params);
+        T::deserialize(payload).map_err(|_| Error::Json)
+    }
+
+    /// Decode the well-known topics, leaving everything else as [`KnownNotification::Other`].
+    pub fn typed(&self) -> Result<KnownNotification, Error> {
+        Ok(match self.method.as_str() {
+            "invoice_payment" => KnownNotification::InvoicePayment(self.parse()?),
+            "forward_event" => KnownNotification::ForwardEvent(self.parse()?),
+            "channel_opened" => KnownNotification::ChannelOpened(self.parse()?),
+            _ => KnownNotification::Other(self.clone()),
+        })
+    }
+}
+
+/// A notification decoded by [`Notification::typed`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum KnownNotification {
+    InvoicePayment(InvoicePayment),
+    ForwardEvent(ForwardEvent),
+    ChannelOpened(ChannelOpened),
+    Other(Notification),
+}
+
+/// `invoice_payment`: one of our invoices was paid.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InvoicePayment {
+    pub label: String,
+    pub preimage: String,
+    #[serde(alias = "amount_msat", deserialize_with = "msat")]
+    pub msat: u64,
+}
+
+/// `forward_event`: an HTLC forward changed state.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ForwardEvent {
+    #[serde(default)]
+    pub payment_hash: Option<String>,
+    pub in_channel: String,
+    #[serde(default)]
+    pub out_channel: Option<String>,
+    #[serde(deserialize_with = "msat")]
+    pub in_msat: u64,
+    #[serde(default, deserialize_with = "opt_msat")]
+    pub out_msat: Option<u64>,
+    #[serde(default, deserialize_with = "opt_msat")]
+    pub fee_msat: Option<u64>,
+    /// `offered`, `settled`, `failed` or `local_failed`.
+    pub status: String,
+    #[serde(default)]
+    pub failcode: Option<u32>,
+    #[serde(default)]
+    pub failreason: Option<String>,
+    pub received_time: f64,
+    #[serde(default)]
+    pub resolved_time: Option<f64>,
+}
+
+/// `channel_opened`: a peer opened a channel to us.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChannelOpened {
+    /// The peer's node id.
+    pub id: String,
+    #[serde(alias = "amount", deserialize_with = "msat")]
+    pub funding_msat: u64,
+    pub funding_txid: String,
+    #[serde(default, alias = "funding_locked")]
+    pub channel_ready: bool,
+}
+
+/// CLN reports amounts as plain integers, or as `"1234msat"` strings in older versions.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Msat {
+    Number(u64),
+    String(String),
+}
+
+impl Msat {
+    fn into_u64<E: de::Error>(self) -> Result<u64, E> {
+        match self {
+            Msat::Number(n) => Ok(n),
+            Msat::String(s) => s
+                .strip_suffix("msat")
+                .unwrap_or(&s)
+                .parse()
+                .map_err(|_| E::custom(format!("invalid msat amount {s:?}"))),
+        }
+    }
+}
+
+fn msat<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    Msat::deserialize(d)?.into_u64()
+}
+
+fn opt_msat<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    Option::<Msat>::deserialize(d)?
+        .map(Msat::into_u64)
+        .transpose()
+}
+
+/// Notifications pushed by the node, see the [module docs](self).
+///
+/// Every stream sees every notification received after it was created. A stream that falls
+/// more than a few hundred notifications behind skips the oldest ones.
+#[cfg(feature = "tokio")]
+pub struct NotificationStream {
+    rx: broadcast::Receiver<Notification>,
+}
+
+#[cfg(feature = "tokio")]
+impl NotificationStream {
+    pub(crate) fn new(rx: broadcast::Receiver<Notification>) -> Self {
+        Self { rx }
+    }
+
+    /// Wait for the next notification. Returns `None` once the client is dropped.
+    pub async fn next(&mut self) -> Option<Notification> {
+        loop {
+            match self.rx.recv().await {
+                Ok(notification) => return Some(notification),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("notification stream lagged, skipped {skipped} notifications");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn classifies_notifications() {
+        let n = Notification::from_json(json!({
+            "jsonrpc": "2.0",
+            "method": "log",
+            "params": {"level": "info", "log": "hi"}
+        }))
+        .unwrap();
+        assert_eq!(n.method, "log");
+        assert_eq!(n.params["log"], "hi");
+
+        let reply = json!({"jsonrpc": "2.0", "id": 1, "result": {}});
+        assert_eq!(Notification::from_json(reply.clone()), Err(reply));
+    }
+
+    #[test]
+    fn typed_invoice_payment() {
+        let n = Notification::from_json(json!({
+            "method": "invoice_payment",
+            "params": {"invoice_payment": {
+                "label": "coffee",
+                "preimage": "00".repeat(32),
+                "msat": "1000msat"
+            }}
+        }))
+        .unwrap();
+
+        assert_eq!(
+            n.typed().unwrap(),
+            KnownNotification::InvoicePayment(InvoicePayment {
+                label: "coffee".to_string(),
+                preimage: "00".repeat(32),
+                msat: 1000,
+            })
+        );
+    }
+
+    #[test]
+    fn typed_forward_event_and_channel_opened() {
+        let fwd = Notification::from_json(json!({
+            "method": "forward_event",
+            "params": {"forward_event": {
+                "in_channel": "103x1x1",
+                "out_channel": "110x1x0",
+                "in_msat": 100001001,
+                "out_msat": 100000000,
+                "fee_msat": 1001,
+                "status": "settled",
+                "received_time": 1560696342.368
+            }}
+        }))
+        .unwrap();
+        let KnownNotification::ForwardEvent(fwd) = fwd.typed().unwrap() else {
+            panic!("expected forward_event");
+        };
+        assert_eq!(fwd.fee_msat, Some(1001));
+        assert_eq!(fwd.resolved_time, None);
+
+        let opened = Notification::from_json(json!({
+            "method": "channel_opened",
+            "params": {"channel_opened": {
+                "id": "03864ef025fde8fb587d989186ce6a4a186895ee44a926bfc370e2c366597a3f8f",
+                "funding_msat": 100000000,
+                "funding_txid": "aa".repeat(32),
+                "channel_ready": false
+            }}
+        }))
+        .unwrap();
+        assert!(matches!(
+            opened.typed().unwrap(),
+            KnownNotification::ChannelOpened(ChannelOpened {
+                funding_msat: 100000000,
+                ..
+            })
+        ));
+    }
+}
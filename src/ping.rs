@@ -0,0 +1,400 @@
+//! The BOLT #1 rules for `ping` and `pong`.
+//!
+//! They are short but easy to get wrong: a ping asking for 65532 or more bytes must not be
+//! answered, a pong must be exactly as long as its ping asked for, and a peer that pings far
+//! more often than once every 30 seconds is misbehaving. [`PingTracker`] applies them, and
+//! every place this crate answers pings goes through it: [`LNSocket`](crate::LNSocket) before
+//! `init`, the [`CommandoClient`](crate::CommandoClient) pump and
+//! [`RequestResponse`](crate::reqresp::RequestResponse). A socket's tracker also sees every
+//! ping sent through it, so that pongs answering none of them can be dropped before a read
+//! returns them, see [`PingPolicy::drop_unexpected_pongs`].
+//!
+//! What the tracker refused is counted in [`PingStats`], see
+//! [`LNSocket::ping_stats`](crate::LNSocket::ping_stats).
+//!
+//! A [`PingProbe`] is a ping for diagnosing the path to a peer: it carries as many bytes of
+//! a [`PingPattern`] as it is told and asks for a pong of a given length, so that
+//! [`LNSocket::ping_probe`](crate::LNSocket::ping_probe) can tell whether large frames make
+//! it through a middlebox in both directions.
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+
+use bitcoin::secp256k1::rand::{self, RngCore};
+
+use crate::clock::Instant;
+use crate::ln::msgs::{Ping, Pong};
+use crate::ln::wire::{Encode, Type};
+use crate::util::ser::{Writeable, Writer};
+
+/// The largest `num_pong_bytes` a ping may ask for and still be answered.
+pub const MAX_PONGLEN: u16 = 65531;
+
+/// How many of our pings are remembered while waiting for their pongs.
+const MAX_OUTSTANDING: usize = 64;
+
+/// How long [`LNSocket::ping_probe`](crate::LNSocket::ping_probe) waits for its pong unless
+/// the probe says otherwise.
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How strictly a [`PingTracker`] enforces the rules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PingPolicy {
+    /// Answer at most this many pings per `window`; the rest are ignored. `u32::MAX` answers
+    /// them all.
+    pub max_pings: u32,
+    pub window: Duration,
+    /// Drop pongs that don't answer a ping of ours, instead of passing them on to reads.
+    /// Off by default: reads return every pong, as they always have.
+    pub drop_unexpected_pongs: bool,
+}
+
+impl Default for PingPolicy {
+    /// Ten pings per 30 seconds, a burst well above the one a well-behaved peer sends.
+    fn default() -> Self {
+        Self {
+            max_pings: 10,
+            window: Duration::from_secs(30),
+            drop_unexpected_pongs: false,
+        }
+    }
+}
+
+/// What a [`PingTracker`] has seen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PingStats {
+    /// Pings answered with a pong.
+    pub answered: u64,
+    /// Pings not answered because they asked for more than [`MAX_PONGLEN`] bytes, as BOLT #1
+    /// says.
+    pub oversized: u64,
+    /// Pings not answered because the peer went over [`PingPolicy::max_pings`].
+    pub rate_limited: u64,
+    /// Pongs answering a ping of ours.
+    pub pongs: u64,
+    /// Pongs of a length none of our pings asked for, or malformed.
+    pub unexpected_pongs: u64,
+}
+
+/// What fills the bytes a [`PingProbe`] carries. The peer ignores them; they are there to
+/// look like the traffic a middlebox might mangle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PingPattern {
+    #[default]
+    Zeros,
+    /// All bits set.
+    Ones,
+    /// `0x55`, `0xaa`, ...: alternating bits.
+    Alternating,
+    /// `0x00`, `0x01`, ... `0xff`, `0x00`, ...
+    Counting,
+    /// Fresh random bytes for every probe, which don't compress.
+    Random,
+}
+
+impl PingPattern {
+    fn fill(self, buf: &mut [u8]) {
+        match self {
+            PingPattern::Zeros => buf.fill(0),
+            PingPattern::Ones => buf.fill(0xff),
+            PingPattern::Alternating => {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = if i % 2 == 0 { 0x55 } else { 0xaa };
+                }
+            }
+            PingPattern::Counting => {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = i as u8;
+                }
+            }
+            PingPattern::Random => rand::thread_rng().fill_bytes(buf),
+        }
+    }
+}
+
+/// A `ping` with `byteslen` bytes of `pattern`, asking for a pong of `ponglen` bytes. See
+/// [`LNSocket::ping_probe`](crate::LNSocket::ping_probe).
+///
+/// The whole message must fit in 65535 bytes, so `byteslen` is at most 65529; `ponglen`
+/// over [`MAX_PONGLEN`] is never answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PingProbe {
+    pub ponglen: u16,
+    pub byteslen: u16,
+    pub pattern: PingPattern,
+    /// How long to wait for the pong.
+    pub timeout: Duration,
+}
+
+impl PingProbe {
+    /// A probe of zeros, waiting [`DEFAULT_PROBE_TIMEOUT`] for its pong.
+    pub fn new(ponglen: u16, byteslen: u16) -> Self {
+        Self {
+            ponglen,
+            byteslen,
+            pattern: PingPattern::Zeros,
+            timeout: DEFAULT_PROBE_TIMEOUT,
+        }
+    }
+}
+
+impl Type for PingProbe {
+    fn type_id(&self) -> u16 {
+        Ping::TYPE
+    }
+}
+
+impl Writeable for PingProbe {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        let mut bytes = vec![0; self.byteslen as usize];
+        self.pattern.fill(&mut bytes);
+        self.ponglen.write(w)?;
+        bytes.write(w)
+    }
+}
+
+/// How the peer answered a [`PingProbe`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PingOutcome {
+    /// The pong was as long as asked for.
+    Pong { rtt: Duration },
+    /// The pong carried `received` bytes instead of the `expected` ones.
+    LengthMismatch {
+        expected: u16,
+        received: usize,
+        rtt: Duration,
+    },
+}
+
+/// Applies the BOLT #1 ping rules, see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct PingTracker {
+    policy: PingPolicy,
+    /// When the pings answered within the current window came in.
+    answered_at: VecDeque<Instant>,
+    /// The `num_pong_bytes` of our pings still waiting for a pong, oldest first.
+    outstanding: VecDeque<u16>,
+    stats: PingStats,
+}
+
+impl PingTracker {
+    pub fn new(policy: PingPolicy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: PingPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn policy(&self) -> PingPolicy {
+        self.policy
+    }
+
+    pub fn stats(&self) -> PingStats {
+        self.stats
+    }
+
+    /// The pong to answer `ping` with, if any; `now` is when it arrived.
+    pub fn on_ping(&mut self, ping: &Ping, now: Instant) -> Option<Pong> {
+        if ping.ponglen > MAX_PONGLEN {
+            self.stats.oversized += 1;
+            return None;
+        }
+        while let Some(&at) = self.answered_at.front() {
+            if now.saturating_duration_since(at) < self.policy.window {
+                break;
+            }
+            self.answered_at.pop_front();
+        }
+        if self.answered_at.len() >= self.policy.max_pings as usize {
+            self.stats.rate_limited += 1;
+            return None;
+        }
+        if self.policy.max_pings != u32::MAX {
+            self.answered_at.push_back(now);
+        }
+        self.stats.answered += 1;
+        Some(Pong {
+            byteslen: ping.ponglen,
+        })
+    }
+
+    /// Remember that we sent a ping asking for `ponglen` bytes back.
+    pub fn ping_sent(&mut self, ponglen: u16) {
+        if ponglen > MAX_PONGLEN {
+            return;
+        }
+        if self.outstanding.len() == MAX_OUTSTANDING {
+            self.outstanding.pop_front();
+        }
+        self.outstanding.push_back(ponglen);
+    }
+
+    /// Start waiting for the pong to a probe asking for `ponglen` bytes, before sending it.
+    pub(crate) fn probe_sent(&self, ponglen: u16) -> ProbeWait {
+        ProbeWait {
+            ahead: self.outstanding.clone(),
+            ponglen,
+        }
+    }
+
+    /// Check the payload of a pong against our pings, returning whether to pass it on.
+    pub fn on_pong(&mut self, payload: &[u8]) -> bool {
+        let answered = match payload {
+            [a, b, ignored @ ..] if ignored.len() == u16::from_be_bytes([*a, *b]) as usize => {
+                let len = ignored.len() as u16;
+                self.outstanding
+                    .iter()
+                    .position(|&ponglen| ponglen == len)
+                    .map(|at| self.outstanding.remove(at))
+                    .is_some()
+            }
+            _ => false,
+        };
+        if answered {
+            self.stats.pongs += 1;
+        } else {
+            self.stats.unexpected_pongs += 1;
+        }
+        answered || !self.policy.drop_unexpected_pongs
+    }
+}
+
+/// Tells the pong to a [`PingProbe`] from those to the pings sent before it, see
+/// [`PingTracker::probe_sent`]. The peer answers pings in order.
+#[derive(Debug)]
+pub(crate) struct ProbeWait {
+    /// The `num_pong_bytes` of the pings still to be answered before the probe.
+    ahead: VecDeque<u16>,
+    ponglen: u16,
+}
+
+impl ProbeWait {
+    /// Whether a pong carrying `received` bytes answers the probe.
+    pub(crate) fn answers_probe(&mut self, received: usize) -> bool {
+        // as long as one of the pings ahead asked for: the oldest of those
+        if let Some(at) = self.ahead.iter().position(|&len| len as usize == received) {
+            self.ahead.remove(at);
+            return false;
+        }
+        if received == self.ponglen as usize {
+            return true;
+        }
+        // a length nobody asked for answers the oldest ping, the probe once the rest are
+        self.ahead.pop_front().is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping(ponglen: u16) -> Ping {
+        Ping {
+            ponglen,
+            byteslen: 0,
+        }
+    }
+
+    #[test]
+    fn probes_are_pings_with_a_pattern() {
+        let probe = PingProbe {
+            pattern: PingPattern::Alternating,
+            ..PingProbe::new(7, 5)
+        };
+        let mut buf = crate::util::ser::VecWriter(Vec::new());
+        probe.write(&mut buf).unwrap();
+        assert_eq!(buf.0, [0, 7, 0, 5, 0x55, 0xaa, 0x55, 0xaa, 0x55]);
+
+        let mut counting = [0; 258];
+        PingPattern::Counting.fill(&mut counting);
+        assert_eq!((counting[255], counting[257]), (255, 1));
+    }
+
+    fn pong(len: u16) -> Vec<u8> {
+        let mut payload = len.to_be_bytes().to_vec();
+        payload.resize(2 + len as usize, 0);
+        payload
+    }
+
+    #[test]
+    fn pings_are_answered_within_the_rules() {
+        let mut tracker = PingTracker::new(PingPolicy {
+            max_pings: 2,
+            window: Duration::from_secs(30),
+            drop_unexpected_pongs: true,
+        });
+        let start = Instant::default();
+
+        assert_eq!(
+            tracker.on_ping(&ping(MAX_PONGLEN), start),
+            Some(Pong {
+                byteslen: MAX_PONGLEN
+            })
+        );
+        assert_eq!(tracker.on_ping(&ping(MAX_PONGLEN + 1), start), None);
+        assert!(tracker.on_ping(&ping(0), start).is_some());
+        // a third in the same window is one too many
+        assert_eq!(tracker.on_ping(&ping(0), start), None);
+        assert!(
+            tracker
+                .on_ping(&ping(0), start + Duration::from_secs(31))
+                .is_some()
+        );
+
+        assert_eq!(
+            tracker.stats(),
+            PingStats {
+                answered: 3,
+                oversized: 1,
+                rate_limited: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn pongs_must_answer_our_pings() {
+        let mut tracker = PingTracker::new(PingPolicy {
+            drop_unexpected_pongs: true,
+            ..PingPolicy::default()
+        });
+        tracker.ping_sent(4);
+        tracker.ping_sent(65532);
+
+        assert!(!tracker.on_pong(&pong(3)));
+        // byteslen says 4 but only 2 bytes follow
+        assert!(!tracker.on_pong(&[0, 4, 0, 0]));
+        assert!(tracker.on_pong(&pong(4)));
+        // each ping gets one pong
+        assert!(!tracker.on_pong(&pong(4)));
+
+        let stats = tracker.stats();
+        assert_eq!((stats.pongs, stats.unexpected_pongs), (1, 3));
+
+        tracker.set_policy(PingPolicy::default());
+        assert!(tracker.on_pong(&pong(4)));
+        assert_eq!(tracker.stats().unexpected_pongs, 4);
+    }
+
+    #[test]
+    fn probes_wait_for_the_pings_sent_before_them() {
+        let mut tracker = PingTracker::default();
+        tracker.ping_sent(0);
+        tracker.ping_sent(8);
+        let mut wait = tracker.probe_sent(8);
+        // the keepalive asking for 8 bytes is answered first
+        assert!(!wait.answers_probe(8));
+        assert!(wait.answers_probe(8));
+
+        let mut wait = tracker.probe_sent(500);
+        // a mangled pong answers the oldest ping
+        assert!(!wait.answers_probe(3));
+        assert!(!wait.answers_probe(8));
+        assert!(wait.answers_probe(499));
+    }
+}
@@ -0,0 +1,314 @@
+//! A set of connected peers to talk to at once.
+//!
+//! [`PeerPool`] keeps, per node id, a [`MessageSender`] for raw messages and a
+//! [`CommandoClient`] for RPC calls, either or both. [`PeerPool::broadcast`] sends one message
+//! to every peer and [`PeerPool::gather`] makes one commando call on every node, each peer with
+//! its own timeout, and both report one result per peer: a slow or dead peer doesn't hold up
+//! or fail the others. Useful for measuring the network, probing liquidity, or asking several
+//! nodes of your own the same thing.
+//!
+//! ```no_run
+//! # use lnsocket::{CommandoClient, LNSocket, ln::msgs};
+//! use lnsocket::pool::PeerPool;
+//! use serde_json::json;
+//! use std::time::Duration;
+//! # async fn ex(a: LNSocket, b: CommandoClient, b_id: bitcoin::secp256k1::PublicKey) {
+//! let mut pool = PeerPool::new();
+//! pool.insert_socket(&a);
+//! pool.insert_commando(b_id, b);
+//!
+//! let ping = msgs::Ping { ponglen: 0, byteslen: 0 };
+//! for (node, res) in pool.broadcast(&ping, Duration::from_secs(5), |_| true).await {
+//!     println!("{node}: {res:?}");
+//! }
+//! let infos = pool
+//!     .gather("getinfo", json!({}), Duration::from_secs(10), |_| true)
+//!     .await;
+//! # }
+//! ```
+//!
+//! With a [`CapabilityCache`] set, the pool also learns what each inserted socket's peer
+//! supports, and [`PeerPool::supporting`] makes a filter that sends a message only to the
+//! peers that understand it:
+//!
+//! ```no_run
+//! # use lnsocket::{LNSocket, ln::msgs};
+//! # use lnsocket::pool::PeerPool;
+//! # use std::time::Duration;
+//! use lnsocket::capabilities::CapabilityCache;
+//! use lnsocket::ln::features::ONION_MESSAGES_FEATURE_BIT;
+//! # async fn ex(a: LNSocket, ping: msgs::Ping) {
+//! let mut pool = PeerPool::new();
+//! pool.set_capabilities(CapabilityCache::new());
+//! pool.insert_socket(&a);
+//! let filter = pool.supporting(ONION_MESSAGES_FEATURE_BIT);
+//! pool.broadcast(&ping, Duration::from_secs(5), filter).await;
+//! # }
+//! ```
+//!
+//! The pool doesn't connect or reconnect anything itself. A [`CommandoClient`] reconnects on
+//! its own, but a sender taken from a socket dies with it: insert the new socket's sender
+//! after reconnecting.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcoin::secp256k1::PublicKey;
+use serde_json::Value;
+use tokio::task::JoinSet;
+
+use crate::capabilities::CapabilityCache;
+use crate::ln::wire::Type;
+use crate::sender::Frame;
+use crate::util::ser::Writeable;
+use crate::{CommandoClient, Error, LNSocket, MessageSender};
+
+#[derive(Default)]
+struct Peer {
+    sender: Option<MessageSender>,
+    commando: Option<Arc<CommandoClient>>,
+}
+
+/// Connected peers by node id, see the [module docs](self).
+#[derive(Default)]
+pub struct PeerPool {
+    peers: HashMap<PublicKey, Peer>,
+    capabilities: Option<CapabilityCache>,
+}
+
+impl PeerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep track of what peers support in `cache`, for [`PeerPool::supporting`]. The
+    /// cache may be shared with sockets that record in it themselves.
+    pub fn set_capabilities(&mut self, cache: CapabilityCache) {
+        self.capabilities = Some(cache);
+    }
+
+    pub fn capabilities(&self) -> Option<&CapabilityCache> {
+        self.capabilities.as_ref()
+    }
+
+    /// Add `sock`'s peer, for [`PeerPool::broadcast`], and record its `init` in the
+    /// [capability cache](PeerPool::set_capabilities). The socket stays with the caller to
+    /// read from.
+    pub fn insert_socket(&mut self, sock: &LNSocket) {
+        if let (Some(cache), Some(init)) = (&self.capabilities, sock.their_init()) {
+            cache.record(&sock.their_pubkey(), init);
+        }
+        self.insert_sender(sock.their_pubkey(), sock.sender());
+    }
+
+    /// Send [`PeerPool::broadcast`]s to `node_id` through `sender`, replacing any sender it
+    /// had.
+    pub fn insert_sender(&mut self, node_id: PublicKey, sender: MessageSender) {
+        self.peers.entry(node_id).or_default().sender = Some(sender);
+    }
+
+    /// Make [`PeerPool::gather`] calls on `node_id` through `client`, replacing any client it
+    /// had.
+    pub fn insert_commando(&mut self, node_id: PublicKey, client: CommandoClient) {
+        self.peers.entry(node_id).or_default().commando = Some(Arc::new(client));
+    }
+
+    /// Forget `node_id`, returning whether it was in the pool.
+    pub fn remove(&mut self, node_id: &PublicKey) -> bool {
+        self.peers.remove(node_id).is_some()
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = &PublicKey> {
+        self.peers.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// A filter for [`PeerPool::broadcast`] and [`PeerPool::gather`] that accepts the peers
+    /// known to support `bit`'s feature: those whose last `init` in the
+    /// [capability cache](PeerPool::set_capabilities) advertised it. Without a cache it
+    /// accepts none.
+    pub fn supporting(&self, bit: usize) -> impl Fn(&PublicKey) -> bool + use<> {
+        let cache = self.capabilities.clone();
+        move |node_id| {
+            cache
+                .as_ref()
+                .and_then(|cache| cache.supports(node_id, bit))
+                .unwrap_or(false)
+        }
+    }
+
+    /// Send `msg` to every peer with a sender that `filter` accepts, each send allowed
+    /// `timeout`. Returns a result per peer, in no particular order; a peer that took too long
+    /// gets `Io(TimedOut)`, though its message may still go out.
+    pub async fn broadcast<M: Type + Writeable>(
+        &self,
+        msg: &M,
+        timeout: Duration,
+        filter: impl Fn(&PublicKey) -> bool,
+    ) -> Vec<(PublicKey, Result<(), Error>)> {
+        let mut sends = JoinSet::new();
+        for (node_id, peer) in &self.peers {
+            let Some(sender) = peer.sender.clone().filter(|_| filter(node_id)) else {
+                continue;
+            };
+            // a frame per peer, each is encrypted for its own session
+            let frame = Frame::new(msg);
+            let node_id = *node_id;
+            sends.spawn(async move {
+                let res = match frame {
+                    Ok(frame) => with_timeout(timeout, sender.send_frames(vec![frame])).await,
+                    Err(err) => Err(err),
+                };
+                (node_id, res)
+            });
+        }
+        sends.join_all().await
+    }
+
+    /// Call `method` with `params` on every node with a commando client that `filter`
+    /// accepts, each call allowed `timeout`. Returns a result per node, in no particular
+    /// order.
+    pub async fn gather(
+        &self,
+        method: &str,
+        params: Value,
+        timeout: Duration,
+        filter: impl Fn(&PublicKey) -> bool,
+    ) -> Vec<(PublicKey, Result<Value, Error>)> {
+        let mut calls = JoinSet::new();
+        for (node_id, peer) in &self.peers {
+            let Some(client) = peer.commando.clone().filter(|_| filter(node_id)) else {
+                continue;
+            };
+            let (node_id, method, params) = (*node_id, method.to_string(), params.clone());
+            calls.spawn(async move {
+                let res = with_timeout(timeout, client.call(method, params)).await;
+                (node_id, res)
+            });
+        }
+        calls.join_all().await
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Duration,
+    fut: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    tokio::time::timeout(timeout, fut)
+        .await
+        .unwrap_or(Err(Error::Io(std::io::ErrorKind::TimedOut)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commando::CommandoConfig;
+    use crate::commando_protocol::{CommandoReplyChunk, IncomingCommandoMessage};
+    use crate::ln::msgs;
+    use crate::ln::wire::Message;
+    use crate::lnsocket::testing::*;
+
+    #[tokio::test]
+    async fn broadcast_and_gather_report_per_peer() {
+        let (a, mut a_server, mut a_peer) = loopback_pair().await;
+        let (b, mut b_server, mut b_peer) = loopback_pair().await;
+        let (c, _c_server, _c_peer) = loopback_pair().await;
+        let (a_id, b_id, c_id) = (key(10), key(11), key(12));
+
+        let mut pool = PeerPool::new();
+        pool.insert_sender(a_id, a.sender());
+        pool.insert_sender(b_id, b.sender());
+        assert!(pool.remove(&b_id));
+        let config = CommandoConfig::new().no_reconnect().random_ids(false);
+        pool.insert_commando(
+            b_id,
+            CommandoClient::spawn_with_config(b, "rune", config.clone()),
+        );
+        pool.insert_commando(c_id, CommandoClient::spawn_with_config(c, "rune", config));
+        assert_eq!(pool.len(), 3);
+
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 2,
+        };
+        let sent = pool
+            .broadcast(&ping, Duration::from_secs(5), |_| true)
+            .await;
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(&sent[0], (id, Ok(())) if *id == a_id));
+        assert!(matches!(
+            peer_recv(&mut a_server, &mut a_peer).await,
+            Message::Ping(_)
+        ));
+
+        // b answers, c never does
+        let answer = tokio::spawn(async move {
+            peer_recv(&mut b_server, &mut b_peer).await;
+            let reply = IncomingCommandoMessage::Done(CommandoReplyChunk {
+                req_id: 1,
+                chunk: br#"{"result":{"alias":"b"}}"#.to_vec(),
+            });
+            peer_send(&mut b_server, &mut b_peer, &reply).await;
+            (b_server, b_peer)
+        });
+        let mut replies = pool
+            .gather(
+                "getinfo",
+                serde_json::json!({}),
+                Duration::from_millis(200),
+                |id| *id != a_id,
+            )
+            .await;
+        replies.sort_by_key(|(id, _)| *id == c_id);
+        assert_eq!(replies[0].0, b_id);
+        assert_eq!(replies[0].1.as_ref().unwrap()["alias"], "b");
+        assert!(matches!(
+            replies[1],
+            (id, Err(Error::Io(std::io::ErrorKind::TimedOut))) if id == c_id
+        ));
+        answer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn supporting_filters_on_the_cached_features() {
+        use crate::ln::features::{self, ONION_MESSAGES_FEATURE_BIT};
+
+        let (mut a, mut a_server, mut a_peer) = loopback_pair().await;
+        let (b, _b_server, _b_peer) = loopback_pair().await;
+        let (c, _c_server, _c_peer) = loopback_pair().await;
+        let mut theirs = init();
+        features::set(&mut theirs.features, ONION_MESSAGES_FEATURE_BIT);
+        peer_send(&mut a_server, &mut a_peer, &theirs).await;
+        a.send_init(&init()).await.unwrap();
+        a.read_init().await.unwrap();
+
+        let mut pool = PeerPool::new();
+        assert!(!pool.supporting(ONION_MESSAGES_FEATURE_BIT)(
+            &a.their_pubkey()
+        ));
+        let cache = CapabilityCache::new();
+        pool.set_capabilities(cache.clone());
+        pool.insert_socket(&a);
+        // b said it doesn't, c was never seen
+        cache.record(&key(11), &init());
+        pool.insert_sender(key(11), b.sender());
+        pool.insert_sender(key(12), c.sender());
+
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 0,
+        };
+        let filter = pool.supporting(ONION_MESSAGES_FEATURE_BIT);
+        let sent = pool.broadcast(&ping, Duration::from_secs(5), filter).await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, a.their_pubkey());
+    }
+}
@@ -0,0 +1,220 @@
+//! Making connections harder to fingerprint.
+//!
+//! Traffic between two nodes is encrypted, but an observer on the path still sees when
+//! connections are made, from which local port, and how big every write is. The peer itself
+//! sees our `init`, whose exact encoding is specific to this crate. [`PrivacyOptions`] on a
+//! [`Dialer`](crate::dial::Dialer) blur each of these:
+//!
+//! - `connect_jitter` waits a random time before every connect, reconnects included.
+//! - `random_local_port` binds the socket to a random ephemeral port, with `SO_REUSEADDR`,
+//!   instead of taking the next one the OS hands out.
+//! - `padding` rounds the size of every write up as the [`PaddingPolicy`] says.
+//! - `minimal_init` encodes our feature bits in as few bytes as possible, as the spec asks
+//!   and other implementations do, rather than in our own fixed widths.
+//!
+//! All are off by default; [`PrivacyOptions::all`] turns them on.
+//!
+//! ```no_run
+//! # #[cfg(feature = "tokio")]
+//! use lnsocket::dial::Dialer;
+//! use lnsocket::privacy::PrivacyOptions;
+//! # #[cfg(feature = "tokio")]
+//! # async fn ex(key: bitcoin::secp256k1::SecretKey, pk: bitcoin::secp256k1::PublicKey) -> Result<(), lnsocket::Error> {
+//! let dialer = Dialer::new().with_privacy(PrivacyOptions::all());
+//! let sock = dialer.connect_and_init(key, pk, "node.example.com:9735").await?;
+//! # Ok(()) }
+//! ```
+//!
+//! ## Padding
+//!
+//! Commando requests differ in size enough to tell `getinfo` from `pay` by the bytes on the
+//! wire. BOLT 8 has no padding of its own: the length header covers exactly one message.
+//! Padding is therefore sent as extra messages of the odd type [`PADDING_TYPE`], filled with
+//! zeros, written in the same write as the messages they pad. Peers ignore odd types they
+//! don't know (BOLT 1), so only the byte count on the wire changes.
+//!
+//! The [`PaddingPolicy`] applies where messages are encrypted: in the writer task of an
+//! [`LNSocket`](crate::LNSocket) (see [`LNSocket::set_padding`](crate::LNSocket::set_padding)),
+//! where it pads every write, and in a sans-IO
+//! [`Transport`](crate::transport::Transport) (see
+//! [`Transport::set_padding`](crate::transport::Transport::set_padding)), where it pads every
+//! message. Nothing should be padded before the `init` exchange is done, as `init` must be the
+//! first message; the socket waits for it by itself, a `Transport` leaves it to the caller.
+
+use std::io;
+use std::time::Duration;
+
+use bitcoin::secp256k1::rand::{self, Rng};
+
+use crate::ln::msgs;
+use crate::ln::peer_channel_encryptor::LN_MAX_MSG_LEN;
+use crate::ln::wire::Type;
+use crate::transport::{LENGTH_HEADER_SIZE, MAC_SIZE};
+use crate::util::ser::{Writeable, Writer};
+
+/// The message type padding is sent as. Odd, so peers that don't know it ignore it.
+pub const PADDING_TYPE: u16 = 0xfffd;
+
+/// The bucket size of [`PrivacyOptions::all`].
+pub const DEFAULT_PADDING_BUCKET: usize = 512;
+
+/// The connect jitter of [`PrivacyOptions::all`].
+pub const DEFAULT_CONNECT_JITTER: Duration = Duration::from_secs(2);
+
+/// Wire bytes of a padding message with an empty body: length header, type and MAC.
+const PADDING_OVERHEAD: usize = LENGTH_HEADER_SIZE + 2 + MAC_SIZE;
+
+/// How outgoing writes are padded, see the [module docs](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    #[default]
+    None,
+    /// Pad every write with a message of a random size, its body up to this many bytes.
+    /// Hides exact sizes, but averages over many writes still tell them apart.
+    RandomUpTo(usize),
+    /// Pad every write up to the next multiple of this many bytes. As a padding message
+    /// takes at least 36 bytes, writes that fall just short of a multiple go to the one after.
+    /// Writes in the same bucket look the same.
+    Bucketed(usize),
+}
+
+impl PaddingPolicy {
+    /// The body sizes of the padding messages to append to a write of `len` wire bytes.
+    pub(crate) fn padding(&self, len: usize) -> Vec<usize> {
+        let mut gap = match *self {
+            PaddingPolicy::RandomUpTo(max) => {
+                PADDING_OVERHEAD + rand::thread_rng().gen_range(0..=max)
+            }
+            PaddingPolicy::Bucketed(bucket) if bucket > 0 && !len.is_multiple_of(bucket) => {
+                let mut gap = bucket - len % bucket;
+                while gap < PADDING_OVERHEAD {
+                    gap += bucket;
+                }
+                gap
+            }
+            _ => return Vec::new(),
+        };
+
+        let most = PADDING_OVERHEAD + LN_MAX_MSG_LEN - 2;
+        let mut bodies = Vec::new();
+        while gap > 0 {
+            let mut take = gap.min(most);
+            // don't leave a rest too small for a message of its own
+            if gap - take != 0 && gap - take < PADDING_OVERHEAD {
+                take -= PADDING_OVERHEAD;
+            }
+            bodies.push(take - PADDING_OVERHEAD);
+            gap -= take;
+        }
+        bodies
+    }
+}
+
+/// The message padding is made of: `len` zero bytes of type [`PADDING_TYPE`].
+pub(crate) struct Padding(pub usize);
+
+impl Type for Padding {
+    fn type_id(&self) -> u16 {
+        PADDING_TYPE
+    }
+}
+
+impl Writeable for Padding {
+    fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(&vec![0; self.0])
+    }
+}
+
+/// What to do against fingerprinting, see the [module docs](self). Everything is off by
+/// default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrivacyOptions {
+    /// Wait a random time up to this long before connecting.
+    pub connect_jitter: Option<Duration>,
+    /// Bind to a random ephemeral port rather than the one the OS picks.
+    pub random_local_port: bool,
+    pub padding: PaddingPolicy,
+    /// Send our `init` features minimally encoded.
+    pub minimal_init: bool,
+}
+
+impl PrivacyOptions {
+    /// Everything on: up to [`DEFAULT_CONNECT_JITTER`] of jitter and writes padded to
+    /// [`DEFAULT_PADDING_BUCKET`] bytes.
+    pub fn all() -> Self {
+        Self {
+            connect_jitter: Some(DEFAULT_CONNECT_JITTER),
+            random_local_port: true,
+            padding: PaddingPolicy::Bucketed(DEFAULT_PADDING_BUCKET),
+            minimal_init: true,
+        }
+    }
+
+    /// How long to wait before the next connect.
+    pub(crate) fn jitter(&self) -> Duration {
+        match self.connect_jitter {
+            Some(max) if !max.is_zero() => max.mul_f64(rand::thread_rng().r#gen::<f64>()),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// A port from the IANA ephemeral range, 49152 to 65535.
+pub(crate) fn random_ephemeral_port() -> u16 {
+    rand::thread_rng().gen_range(49152..=65535)
+}
+
+/// Drop the leading zero bytes of our feature fields.
+pub(crate) fn minimize_init(init: &mut msgs::Init) {
+    for features in [&mut init.features, &mut init.global_features] {
+        let zeros = features.iter().take_while(|b| **b == 0).count();
+        features.drain(..zeros);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding_fills_up_to_the_bucket() {
+        let bucketed = PaddingPolicy::Bucketed(256);
+        assert_eq!(bucketed.padding(48), vec![208 - PADDING_OVERHEAD]);
+        assert_eq!(bucketed.padding(512), Vec::<usize>::new());
+        // too close to the boundary for a padding message, go to the next one
+        assert_eq!(bucketed.padding(250), vec![256 + 6 - PADDING_OVERHEAD]);
+        assert_eq!(PaddingPolicy::None.padding(48), Vec::<usize>::new());
+        for _ in 0..32 {
+            let bodies = PaddingPolicy::RandomUpTo(100).padding(48);
+            assert!(bodies.len() == 1 && bodies[0] <= 100, "{bodies:?}");
+        }
+
+        // more than one message holds, and no rest is too small to send
+        let big = PaddingPolicy::Bucketed(200_000);
+        let bodies = big.padding(10);
+        assert_eq!(bodies.len(), 4);
+        let total: usize = bodies.iter().map(|b| b + PADDING_OVERHEAD).sum();
+        assert_eq!(total, 200_000 - 10);
+        assert!(bodies.iter().all(|b| *b <= LN_MAX_MSG_LEN - 2));
+
+        let padding = Padding(3);
+        let mut buf = Vec::new();
+        crate::ln::wire::write(&padding, &mut buf).unwrap();
+        assert_eq!(buf, [0xff, 0xfd, 0, 0, 0]);
+    }
+
+    #[test]
+    fn minimal_init_drops_leading_zeros() {
+        let mut features = vec![0; 5];
+        crate::ln::features::set(&mut features, 9);
+        let mut init = msgs::Init {
+            features,
+            global_features: vec![0; 2],
+            networks: None,
+            remote_network_address: None,
+        };
+        minimize_init(&mut init);
+        assert_eq!(init.features, [0x02, 0x00]);
+        assert!(init.global_features.is_empty());
+    }
+}
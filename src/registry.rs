@@ -0,0 +1,246 @@
+//! Bookkeeping of the live connections of a multi-connection app.
+//!
+//! A [`ConnectionRegistry`] is a plain value the app creates, there is no global one.
+//! Register every [`LNSocket`] and [`CommandoClient`] with it as it is made, and
+//! [`ConnectionRegistry::list`] tells what is connected where, for how long and with what
+//! traffic, e.g. for an admin or debug endpoint:
+//!
+//! ```no_run
+//! use lnsocket::registry::ConnectionRegistry;
+//! # async fn ex(sock: lnsocket::LNSocket, client: lnsocket::CommandoClient) {
+//! let registry = ConnectionRegistry::new();
+//! registry.register_socket(&sock);
+//! registry.register_commando(&client);
+//!
+//! for conn in registry.list() {
+//!     println!("{} {} up {:?}: {:?}", conn.id, conn.their_pubkey, conn.uptime, conn.stats);
+//! }
+//! # }
+//! ```
+//!
+//! Entries go away by themselves: a socket's once it and every sender taken from it are
+//! dropped, a client's once it is dropped and its pump has exited. The registry holds on to
+//! neither, so it never keeps a connection alive.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use bitcoin::secp256k1::PublicKey;
+
+use crate::commando::{Load, MessageTransport};
+use crate::stats::{StatsHandle, WireStats};
+use crate::{CommandoClient, LNSocket};
+
+/// The id a [`ConnectionRegistry`] gave a connection, unique within the registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(pub u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "conn#{}", self.0)
+    }
+}
+
+/// What was registered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionKind {
+    Socket,
+    Commando,
+}
+
+/// A connection as [`ConnectionRegistry::list`] sees it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub kind: ConnectionKind,
+    /// For a commando client, the node of its current connection.
+    pub their_pubkey: PublicKey,
+    /// Since it was registered.
+    pub uptime: Duration,
+    /// For a commando client, those of its current connection, which start over with every
+    /// reconnect. `None` for transports without stats.
+    pub stats: Option<WireStats>,
+    /// Commando calls in flight; `None` for a socket.
+    pub in_flight: Option<usize>,
+}
+
+enum Source {
+    Socket {
+        their_pubkey: PublicKey,
+        stats: StatsHandle,
+    },
+    Commando(Weak<Load>),
+}
+
+struct Entry {
+    source: Source,
+    registered_at: Instant,
+}
+
+impl Entry {
+    /// What the entry looks like now, or `None` once its connection is gone.
+    fn info(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        let (kind, their_pubkey, stats, in_flight) = match &self.source {
+            Source::Socket {
+                their_pubkey,
+                stats,
+            } => (
+                ConnectionKind::Socket,
+                *their_pubkey,
+                Some(stats.snapshot()?),
+                None,
+            ),
+            Source::Commando(load) => {
+                let load = load.upgrade()?;
+                let conn = load.conn();
+                let stats = conn.stats.and_then(|stats| stats.snapshot());
+                (
+                    ConnectionKind::Commando,
+                    conn.their_pubkey,
+                    stats,
+                    Some(load.in_flight()),
+                )
+            }
+        };
+        Some(ConnectionInfo {
+            id,
+            kind,
+            their_pubkey,
+            uptime: self.registered_at.elapsed(),
+            stats,
+            in_flight,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    entries: BTreeMap<ConnectionId, Entry>,
+}
+
+/// The live connections of an app, see the [module docs](self). Clones share the registry.
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register_socket(&self, sock: &LNSocket) -> ConnectionId {
+        self.insert(Source::Socket {
+            their_pubkey: sock.their_pubkey(),
+            stats: sock.stats_handle(),
+        })
+    }
+
+    pub fn register_commando<T: MessageTransport>(
+        &self,
+        client: &CommandoClient<T>,
+    ) -> ConnectionId {
+        self.insert(Source::Commando(Arc::downgrade(client.load())))
+    }
+
+    /// Forget a connection before it goes away, returning whether it was registered.
+    pub fn unregister(&self, id: ConnectionId) -> bool {
+        self.inner.lock().unwrap().entries.remove(&id).is_some()
+    }
+
+    pub fn get(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        let mut inner = self.inner.lock().unwrap();
+        let info = inner.entries.get(&id)?.info(id);
+        if info.is_none() {
+            inner.entries.remove(&id);
+        }
+        info
+    }
+
+    /// The live connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut live = Vec::with_capacity(inner.entries.len());
+        inner.entries.retain(|&id, entry| match entry.info(id) {
+            Some(info) => {
+                live.push(info);
+                true
+            }
+            None => false,
+        });
+        live
+    }
+
+    /// The live connections to `node_id`.
+    pub fn find(&self, node_id: &PublicKey) -> Vec<ConnectionInfo> {
+        let mut conns = self.list();
+        conns.retain(|conn| conn.their_pubkey == *node_id);
+        conns
+    }
+
+    fn insert(&self, source: Source) -> ConnectionId {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = ConnectionId(inner.next_id);
+        inner.entries.insert(
+            id,
+            Entry {
+                source,
+                registered_at: Instant::now(),
+            },
+        );
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commando::CommandoConfig;
+    use crate::lnsocket::testing::loopback_pair;
+
+    #[tokio::test]
+    async fn connections_are_listed_while_alive() {
+        let registry = ConnectionRegistry::new();
+        let (sock, _server, _peer) = loopback_pair().await;
+        let (other, _other_server, _other_peer) = loopback_pair().await;
+        let node = sock.their_pubkey();
+
+        let first = registry.register_socket(&sock);
+        let second = registry.register_socket(&other);
+        let listed = registry.list();
+        assert_eq!(
+            listed.iter().map(|c| c.id).collect::<Vec<_>>(),
+            [first, second]
+        );
+        assert_eq!(listed[0].kind, ConnectionKind::Socket);
+        assert_eq!(listed[0].stats, Some(WireStats::default()));
+        assert_eq!(registry.find(&node).len(), 2);
+
+        drop(other);
+        // the writer task lets go of the stats once it notices
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while registry.get(second).is_some() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(registry.list().len(), 1);
+
+        let client = CommandoClient::spawn_with_config(sock, "rune", CommandoConfig::new());
+        let id = registry.register_commando(&client);
+        let info = registry.get(id).unwrap();
+        assert_eq!(info.kind, ConnectionKind::Commando);
+        assert_eq!(info.their_pubkey, node);
+        assert_eq!(info.in_flight, Some(0));
+        assert!(info.stats.is_some());
+
+        assert!(registry.unregister(first));
+        assert!(!registry.unregister(first));
+        assert_eq!(registry.list().len(), 1);
+    }
+}
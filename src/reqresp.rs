@@ -0,0 +1,465 @@
+//! Request/response protocols over custom messages.
+//!
+//! Commando is one protocol of many that sends a request as a custom message and matches the
+//! answer to it by an id. [`RequestResponse`] is the same machinery for any such protocol: a
+//! background task owns the connection, writes requests, decodes responses and hands each
+//! one to the call waiting for its id. A protocol only defines its two messages:
+//!
+//! ```no_run
+//! use lnsocket::impl_wire_message;
+//! use lnsocket::reqresp::{Request, RequestResponse, Response};
+//! use lnsocket::ser::{DecodeError, LengthLimitedRead, Readable};
+//!
+//! #[derive(Debug)]
+//! struct Quote { id: u64, amount: u64 }
+//! impl_wire_message!(Quote, 0x9001, { id, amount });
+//!
+//! #[derive(Debug)]
+//! struct QuoteReply { id: u64, fee: u64 }
+//! impl_wire_message!(QuoteReply, 0x9003, { id, fee });
+//!
+//! impl Request for Quote {
+//!     type Id = u64;
+//!     fn request_id(&self) -> u64 { self.id }
+//! }
+//!
+//! impl Response for QuoteReply {
+//!     type Id = u64;
+//!     fn read<R: LengthLimitedRead>(type_id: u16, r: &mut R) -> Result<Option<Self>, DecodeError> {
+//!         if type_id != 0x9003 {
+//!             return Ok(None);
+//!         }
+//!         Readable::read(r).map(Some)
+//!     }
+//!     fn request_id(&self) -> u64 { self.id }
+//! }
+//!
+//! # async fn ex(sock: lnsocket::LNSocket) -> Result<(), lnsocket::Error> {
+//! let client = RequestResponse::<Quote, QuoteReply>::spawn(sock);
+//! let reply = client.call(Quote { id: 1, amount: 50_000 }).await?;
+//! # Ok(()) }
+//! ```
+//!
+//! The task answers pings and drops every message that isn't a response, or answers no call.
+//! When the transport has a [`MessageSender`](crate::sender::MessageSender), requests are
+//! written off the task, so it goes on reading while the peer holds a write up.
+//! It enforces call timeouts too, forgetting calls once their deadline passes, so a response
+//! that never comes doesn't keep its call around. It doesn't reconnect: once the connection
+//! fails, the calls in flight fail with its error and later ones with `Error::NotConnected`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::Error;
+use crate::clock::{Clock, Instant, SharedClock};
+use crate::commando::MessageTransport;
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::{self, Message, Type};
+use crate::sender::Frame;
+use crate::util::ser::{LengthLimitedRead, Writeable};
+
+/// How many calls may wait to be written before [`RequestResponse::call`] waits too.
+const CTRL_BUFFER: usize = 64;
+
+/// A request message of a [`RequestResponse`] protocol.
+pub trait Request: Type + Writeable + Send + 'static {
+    /// What a response carries to say which request it answers. Must be unique among the
+    /// calls in flight.
+    type Id: Eq + Hash + Clone + fmt::Debug + Send + 'static;
+
+    fn request_id(&self) -> Self::Id;
+}
+
+/// A response message of a [`RequestResponse`] protocol.
+pub trait Response: Send + 'static {
+    type Id;
+
+    /// Decode a message of type `type_id`, or return `Ok(None)` if it isn't a response of
+    /// this protocol.
+    fn read<R: LengthLimitedRead>(type_id: u16, r: &mut R) -> Result<Option<Self>, DecodeError>
+    where
+        Self: Sized;
+
+    /// The id of the request this answers.
+    fn request_id(&self) -> Self::Id;
+}
+
+type Reply<TResp> = oneshot::Sender<Result<TResp, Error>>;
+
+struct Call<TReq, TResp> {
+    req: TReq,
+    waiting: Waiting<TResp>,
+}
+
+/// A call the task has written, waiting for its response.
+struct Waiting<TResp> {
+    done: Reply<TResp>,
+    /// When to give up on the response, by the caller's clock.
+    deadline: Option<Instant>,
+    clock: SharedClock,
+}
+
+/// Sends `TReq`s and waits for the `TResp`s answering them, see the [module docs](self).
+///
+/// Cheap to clone; the background task stops once every handle is gone.
+pub struct RequestResponse<TReq, TResp> {
+    tx: mpsc::Sender<Call<TReq, TResp>>,
+    timeout: Option<Duration>,
+    clock: SharedClock,
+}
+
+impl<TReq, TResp> Clone for RequestResponse<TReq, TResp> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            timeout: self.timeout,
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<TReq, TResp> RequestResponse<TReq, TResp>
+where
+    TReq: Request,
+    TResp: Response<Id = TReq::Id>,
+{
+    /// Take over `transport` and serve calls on it from a background task.
+    pub fn spawn<T: MessageTransport>(transport: T) -> Self {
+        let (tx, rx) = mpsc::channel(CTRL_BUFFER);
+        tokio::spawn(pump(transport, rx));
+        Self {
+            tx,
+            timeout: None,
+            clock: SharedClock::default(),
+        }
+    }
+
+    /// Fail calls that get no response within `timeout` with `Error::Io(TimedOut)`. No
+    /// timeout by default.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Time the timeout with `clock` instead of tokio's timer, see the
+    /// [`clock`](crate::clock) module.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Send `req` and wait for the response with its id. Fails with
+    /// [`Error::RequestIdInUse`] if another call in flight has the same id.
+    pub async fn call(&self, req: TReq) -> Result<TResp, Error> {
+        let (done, rx) = oneshot::channel();
+        let deadline = self.timeout.map(|timeout| self.clock.now() + timeout);
+        let waiting = Waiting {
+            done,
+            deadline,
+            clock: self.clock.clone(),
+        };
+        self.tx
+            .send(Call { req, waiting })
+            .await
+            .map_err(|_| Error::NotConnected)?;
+        let reply = match deadline {
+            Some(deadline) => self
+                .clock
+                .timeout_at(deadline, rx)
+                .await
+                .ok_or(Error::Io(std::io::ErrorKind::TimedOut))?,
+            None => rx.await,
+        };
+        reply.map_err(|_| Error::NotConnected)?
+    }
+}
+
+async fn pump<T, TReq, TResp>(mut transport: T, mut rx: mpsc::Receiver<Call<TReq, TResp>>)
+where
+    T: MessageTransport,
+    TReq: Request,
+    TResp: Response<Id = TReq::Id>,
+{
+    let mut pending: HashMap<TReq::Id, Waiting<TResp>> = HashMap::new();
+    let (written_tx, mut written_rx) = mpsc::unbounded_channel();
+    let err = loop {
+        let expire_at = pending
+            .values()
+            .filter_map(|w| Some((w.deadline?, &w.clock)))
+            .min_by_key(|(deadline, _)| *deadline)
+            .map(|(deadline, clock)| clock.sleep_until(deadline));
+        let has_deadline = expire_at.is_some();
+
+        tokio::select! {
+            _ = expire_at.unwrap_or_else(|| Box::pin(std::future::pending())), if has_deadline => {
+                expire_calls(&mut pending);
+            }
+            call = rx.recv() => {
+                let Some(Call { req, waiting }) = call else {
+                    tracing::debug!("reqresp: every handle is gone, stopping");
+                    return;
+                };
+                // callers that gave up before their deadline left their entries behind
+                pending.retain(|_, w| !w.done.is_closed());
+                let id = req.request_id();
+                if pending.contains_key(&id) {
+                    let _ = waiting.done.send(Err(Error::RequestIdInUse(format!("{id:?}"))));
+                    continue;
+                }
+                let frame = match Frame::new(&req) {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        let _ = waiting.done.send(Err(err));
+                        continue;
+                    }
+                };
+                pending.insert(id.clone(), waiting);
+                // off the read path when we can, so a peer holding the write up is still read
+                match transport.sender() {
+                    Some(sender) => {
+                        let written = written_tx.clone();
+                        tokio::spawn(async move {
+                            let _ = written.send((id, sender.send_frames(vec![frame]).await));
+                        });
+                    }
+                    None => {
+                        if let Err(err) = transport.write_frame(frame).await {
+                            break err;
+                        }
+                    }
+                }
+            }
+            Some((id, res)) = written_rx.recv() => {
+                if let Err(err) = res {
+                    tracing::debug!("reqresp: [{id:?}] writing the request failed: {err}");
+                    break err;
+                }
+            }
+            frame = transport.read_frame() => {
+                let (type_id, payload) = match frame {
+                    Ok(frame) => frame,
+                    Err(err) => break err,
+                };
+                let msg = wire::read_payload(&mut &payload[..], type_id, |type_id, r| {
+                    TResp::read(type_id, r)
+                });
+                match msg {
+                    Ok(Message::Custom(resp)) => {
+                        let id = resp.request_id();
+                        match pending.remove(&id) {
+                            Some(waiting) => {
+                                let _ = waiting.done.send(Ok(resp));
+                            }
+                            None => tracing::debug!("reqresp: dropping response to unknown id {id:?}"),
+                        }
+                    }
+                    Ok(Message::Ping(ping)) => {
+                        if let Err(err) = transport.answer_ping(&ping).await {
+                            break err;
+                        }
+                    }
+                    Ok(_) => tracing::trace!("reqresp: ignoring message type {type_id}"),
+                    Err(err) => tracing::warn!("reqresp: undecodable message type {type_id}: {err:?}"),
+                }
+            }
+        }
+    };
+    tracing::debug!("reqresp: connection failed: {err}");
+    for (_, waiting) in pending {
+        let _ = waiting.done.send(Err(err.clone()));
+    }
+}
+
+/// Fail and forget the calls whose deadline has passed; a late response to one is dropped
+/// like any other response to an unknown id.
+fn expire_calls<Id: fmt::Debug, TResp>(pending: &mut HashMap<Id, Waiting<TResp>>) {
+    pending.retain(|id, w| {
+        let expired = w.deadline.is_some_and(|deadline| deadline <= w.clock.now());
+        if expired {
+            tracing::debug!("reqresp: [{id:?}] timed out, dropping it");
+        }
+        !expired
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs;
+    use crate::lnsocket::testing::*;
+    use crate::ser::Readable;
+
+    #[derive(Debug, PartialEq)]
+    struct Echo {
+        id: u64,
+        body: Vec<u8>,
+    }
+    crate::impl_wire_message!(Echo, 0x9001, { id, body });
+
+    #[derive(Debug, PartialEq)]
+    struct EchoReply {
+        id: u64,
+        body: Vec<u8>,
+    }
+    crate::impl_wire_message!(EchoReply, 0x9003, { id, body });
+
+    impl Request for Echo {
+        type Id = u64;
+        fn request_id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    impl Response for EchoReply {
+        type Id = u64;
+        fn read<R: LengthLimitedRead>(
+            type_id: u16,
+            r: &mut R,
+        ) -> Result<Option<Self>, DecodeError> {
+            if type_id != 0x9003 {
+                return Ok(None);
+            }
+            Readable::read(r).map(Some)
+        }
+        fn request_id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    fn echo(id: u64) -> Echo {
+        Echo {
+            id,
+            body: vec![id as u8],
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_are_matched_by_id() {
+        let (sock, mut server, mut peer) = loopback_pair().await;
+        let client = RequestResponse::<Echo, EchoReply>::spawn(sock);
+
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.call(echo(1)).await }
+        });
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Unknown(0x9001)
+        ));
+        let second = tokio::spawn({
+            let client = client.clone();
+            async move { client.call(echo(2)).await }
+        });
+        peer_recv(&mut server, &mut peer).await;
+
+        // a reused id is refused while the first call waits
+        assert!(matches!(
+            client.call(echo(1)).await,
+            Err(Error::RequestIdInUse(id)) if id == "1"
+        ));
+
+        // answered out of order, with a ping and a stray response in between
+        let ping = msgs::Ping {
+            ponglen: 2,
+            byteslen: 0,
+        };
+        peer_send(&mut server, &mut peer, &ping).await;
+        assert!(matches!(
+            peer_recv(&mut server, &mut peer).await,
+            Message::Pong(msgs::Pong { byteslen: 2 })
+        ));
+        let reply = |id| EchoReply {
+            id,
+            body: vec![id as u8],
+        };
+        peer_send(&mut server, &mut peer, &reply(7)).await;
+        peer_send(&mut server, &mut peer, &reply(2)).await;
+        peer_send(&mut server, &mut peer, &reply(1)).await;
+        assert_eq!(second.await.unwrap().unwrap(), reply(2));
+        assert_eq!(first.await.unwrap().unwrap(), reply(1));
+    }
+
+    #[tokio::test]
+    async fn responses_are_read_while_requests_wait_to_be_written() {
+        let (sock, mut server, mut peer) = loopback_pair_over(small_tcp_pair(4096).await);
+        let client = RequestResponse::<Echo, EchoReply>::spawn(sock);
+
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.call(echo(1)).await }
+        });
+        peer_recv(&mut server, &mut peer).await;
+        // more than the socket buffers hold, with the peer not reading
+        for id in 2..12 {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let big = Echo {
+                    id,
+                    body: vec![0; 60_000],
+                };
+                client.call(big).await
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let reply = EchoReply {
+            id: 1,
+            body: vec![1],
+        };
+        peer_send(&mut server, &mut peer, &reply).await;
+        let res = tokio::time::timeout(Duration::from_secs(5), first).await;
+        assert_eq!(res.expect("the response was read").unwrap().unwrap(), reply);
+    }
+
+    #[tokio::test]
+    async fn calls_fail_with_the_connection() {
+        let (sock, server, _peer) = loopback_pair().await;
+        let client = RequestResponse::<Echo, EchoReply>::spawn(sock)
+            .with_timeout(Some(Duration::from_millis(20)));
+        assert!(matches!(
+            client.call(echo(1)).await,
+            Err(Error::Io(std::io::ErrorKind::TimedOut))
+        ));
+
+        let call = tokio::spawn({
+            let client = client.clone().with_timeout(None);
+            async move { client.call(echo(2)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(server);
+        assert!(matches!(call.await.unwrap(), Err(Error::Io(_))));
+        assert!(client.call(echo(3)).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_are_forgotten_at_their_deadline() {
+        let clock = SharedClock::default();
+        let mut pending = HashMap::new();
+        let mut waiting = |id: u64, timeout: Option<u64>| {
+            let (done, rx) = oneshot::channel::<Result<EchoReply, Error>>();
+            let deadline = timeout.map(|secs| clock.now() + Duration::from_secs(secs));
+            let clock = clock.clone();
+            pending.insert(
+                id,
+                Waiting {
+                    done,
+                    deadline,
+                    clock,
+                },
+            );
+            rx
+        };
+        // callers still waiting, whose entries only the deadline can clear
+        let _rx = (waiting(1, Some(1)), waiting(2, Some(10)), waiting(3, None));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        expire_calls(&mut pending);
+        let mut left: Vec<u64> = pending.keys().copied().collect();
+        left.sort();
+        assert_eq!(left, [2, 3]);
+    }
+}
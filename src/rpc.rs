@@ -0,0 +1,602 @@
+//! Typed replies for common CLN RPC methods.
+//!
+//! [`CommandoClient::call`](crate::CommandoClient::call) returns raw JSON. For the methods
+//! below there are typed wrappers on [`CommandoClient`](crate::CommandoClient), and
+//! [`CommandoClient::call_typed`](crate::CommandoClient::call_typed) decodes any other reply
+//! into a type of your own.
+//!
+//! CLN adds fields to its replies from release to release. Every struct here keeps the fields
+//! it doesn't know about in `extra`, so nothing is lost when talking to a newer node:
+//!
+//! ```
+//! use lnsocket::rpc::ListIncome;
+//!
+//! let reply = serde_json::json!({"income_events": [{
+//!     "account": "wallet", "tag": "deposit", "credit_msat": 1000, "debit_msat": 0,
+//!     "currency": "bc", "timestamp": 1700000000, "new_field": true,
+//! }]});
+//! let income: ListIncome = serde_json::from_value(reply).unwrap();
+//! assert_eq!(income.income_events[0].extra["new_field"], true);
+//! ```
+//!
+//! Amounts are in millisatoshi. CLN reports them as plain numbers since v23; older nodes,
+//! and some plugins, send strings like `"1000msat"` or `"1sat"`, which are accepted too (see
+//! [`msat`]).
+
+use std::fmt;
+
+use bitcoin::secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Reply of `bkpr-listaccountevents`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ListAccountEvents {
+    pub events: Vec<AccountEvent>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// One entry of [`ListAccountEvents`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccountEvent {
+    pub account: String,
+    /// `"chain"`, `"channel"` or `"onchain_fee"`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// What happened, e.g. `"deposit"`, `"invoice"`, `"routed"`.
+    pub tag: String,
+    #[serde(with = "msat")]
+    pub credit_msat: u64,
+    #[serde(with = "msat")]
+    pub debit_msat: u64,
+    pub currency: String,
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub txid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blockheight: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_id: Option<String>,
+    #[serde(
+        default,
+        with = "msat::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub fees_msat: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_rebalance: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Reply of `bkpr-listincome`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ListIncome {
+    pub income_events: Vec<IncomeEvent>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// One entry of [`ListIncome`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct IncomeEvent {
+    pub account: String,
+    pub tag: String,
+    #[serde(with = "msat")]
+    pub credit_msat: u64,
+    #[serde(with = "msat")]
+    pub debit_msat: u64,
+    pub currency: String,
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub txid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_id: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Parameters of `bkpr-listincome`. The default lists all income with fees consolidated.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ListIncomeParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consolidate_fees: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<u64>,
+}
+
+/// Reply of `plugin list` (and of the other `plugin` subcommands).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PluginList {
+    pub command: String,
+    #[serde(default)]
+    pub plugins: Vec<PluginInfo>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// One entry of [`PluginList`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PluginInfo {
+    /// Path of the plugin executable on the node.
+    pub name: String,
+    pub active: bool,
+    /// Whether the plugin can be stopped at runtime.
+    #[serde(default)]
+    pub dynamic: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Reply of `createrune`: a rune the node will accept.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Rune {
+    /// The rune itself, base64.
+    pub rune: String,
+    /// The id the node can revoke it by (`blacklistrune`).
+    pub unique_id: String,
+    /// Set when the rune has no restrictions at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warning_unrestricted_rune: Option<String>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl fmt::Display for Rune {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.rune)
+    }
+}
+
+/// One restriction of a rune: the rune is usable if *any* of the alternatives holds. A
+/// rune is usable only if *all* of its restrictions hold.
+///
+/// Alternatives use CLN's rune syntax, `<field><condition><value>`, e.g. `method=getinfo` or
+/// `time<1700000000`; see `lightning-createrune(7)`. The constructors cover the common cases:
+///
+/// ```
+/// use lnsocket::rpc::Restriction;
+///
+/// let read_only = [
+///     Restriction::methods(["getinfo", "listfunds"]),
+///     Restriction::rate(10),
+/// ];
+/// assert_eq!(read_only[0].alternatives(), ["method=getinfo", "method=listfunds"]);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Restriction(Vec<String>);
+
+impl Restriction {
+    pub fn any_of<S: Into<String>>(alternatives: impl IntoIterator<Item = S>) -> Self {
+        Self(alternatives.into_iter().map(Into::into).collect())
+    }
+
+    /// Only these methods may be called.
+    pub fn methods<S: fmt::Display>(methods: impl IntoIterator<Item = S>) -> Self {
+        Self::any_of(methods.into_iter().map(|m| format!("method={m}")))
+    }
+
+    /// Only methods whose name starts with `prefix` (e.g. `list`) may be called.
+    pub fn method_prefix(prefix: &str) -> Self {
+        Self::any_of([format!("method^{prefix}")])
+    }
+
+    /// Only a commando peer with this node id may use the rune, e.g. the key from
+    /// [`CommandoClient::our_node_id`](crate::CommandoClient::our_node_id) of the component
+    /// that gets it.
+    pub fn id(node_id: &PublicKey) -> Self {
+        Self::any_of([format!("id={node_id}")])
+    }
+
+    /// The rune stops working at this unix time, in seconds.
+    pub fn expires_at(unix_secs: u64) -> Self {
+        Self::any_of([format!("time<{unix_secs}")])
+    }
+
+    /// At most `per_minute` calls per minute.
+    pub fn rate(per_minute: u32) -> Self {
+        Self::any_of([format!("rate={per_minute}")])
+    }
+
+    pub fn alternatives(&self) -> &[String] {
+        &self.0
+    }
+}
+
+/// Serde helpers for millisatoshi amounts, for `#[serde(with = "lnsocket::rpc::msat")]`.
+///
+/// Deserializes plain numbers as well as strings with a unit, `"1000msat"` or `"1sat"`, and
+/// serializes plain numbers.
+///
+/// ```
+/// #[derive(serde::Deserialize)]
+/// struct Channel {
+///     #[serde(with = "lnsocket::rpc::msat")]
+///     spendable_msat: u64,
+/// }
+///
+/// let old: Channel = serde_json::from_str(r#"{"spendable_msat": "21sat"}"#).unwrap();
+/// assert_eq!(old.spendable_msat, 21_000);
+/// ```
+pub mod msat {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount {
+        Number(u64),
+        String(String),
+    }
+
+    /// Parse `"<n>msat"`, `"<n>sat"` or a bare `"<n>"` (msat).
+    pub fn parse(s: &str) -> Option<u64> {
+        if let Some(n) = s.strip_suffix("msat") {
+            n.parse().ok()
+        } else if let Some(n) = s.strip_suffix("sat") {
+            n.parse::<u64>().ok()?.checked_mul(1000)
+        } else {
+            s.parse().ok()
+        }
+    }
+
+    pub fn serialize<S: Serializer>(msat: &u64, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(*msat)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+        match Amount::deserialize(d)? {
+            Amount::Number(msat) => Ok(msat),
+            Amount::String(s) => {
+                parse(&s).ok_or_else(|| D::Error::custom(format!("invalid amount {s:?}")))
+            }
+        }
+    }
+
+    /// The same for `Option<u64>`, for use with `#[serde(default, with = ...)]`.
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(msat: &Option<u64>, s: S) -> Result<S::Ok, S::Error> {
+            match msat {
+                Some(msat) => s.serialize_some(msat),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+            #[derive(Deserialize)]
+            struct Wrap(#[serde(with = "super")] u64);
+            Ok(Option::<Wrap>::deserialize(d)?.map(|Wrap(msat)| msat))
+        }
+    }
+}
+
+/// Serde helpers for hex-encoded bytes, for `#[serde(with = "lnsocket::rpc::hex")]` on a
+/// `Vec<u8>` or a fixed-size array such as a 32-byte payment hash.
+///
+/// Deserializing into an array fails unless the string has exactly the right length.
+///
+/// ```
+/// #[derive(serde::Deserialize, serde::Serialize)]
+/// struct Payment {
+///     #[serde(with = "lnsocket::rpc::hex")]
+///     payment_hash: [u8; 32],
+/// }
+///
+/// let reply = serde_json::json!({"payment_hash": "ab".repeat(32)});
+/// let payment: Payment = serde_json::from_value(reply).unwrap();
+/// assert_eq!(payment.payment_hash, [0xab; 32]);
+/// ```
+pub mod hex {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::fmt::Hex;
+
+    pub fn serialize<T: AsRef<[u8]>, S: Serializer>(bytes: &T, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(&Hex(bytes.as_ref()))
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<T, D::Error>
+    where
+        T: TryFrom<Vec<u8>>,
+        D: Deserializer<'de>,
+    {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(d)?;
+        let bytes = ::hex::decode(&*s).map_err(D::Error::custom)?;
+        let len = bytes.len();
+        T::try_from(bytes).map_err(|_| D::Error::custom(format!("unexpected length {len}")))
+    }
+
+    /// The same for `Option`s, for use with `#[serde(default, with = ...)]`.
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<T: AsRef<[u8]>, S: Serializer>(
+            bytes: &Option<T>,
+            s: S,
+        ) -> Result<S::Ok, S::Error> {
+            #[derive(Serialize)]
+            struct Wrap<'a>(#[serde(with = "super")] &'a [u8]);
+            match bytes {
+                Some(bytes) => s.serialize_some(&Wrap(bytes.as_ref())),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, T, D>(d: D) -> Result<Option<T>, D::Error>
+        where
+            T: TryFrom<Vec<u8>>,
+            D: Deserializer<'de>,
+        {
+            #[derive(Deserialize)]
+            #[serde(bound = "T: TryFrom<Vec<u8>>")]
+            struct Wrap<T>(#[serde(with = "super")] T);
+            Ok(Option::<Wrap<T>>::deserialize(d)?.map(|Wrap(bytes)| bytes))
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod client {
+    use serde::de::DeserializeOwned;
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::commando::{CallOpts, MessageTransport};
+    use crate::commando_protocol::rpc_error;
+    use crate::{CommandoClient, Error};
+
+    pub(super) fn createrune_params(
+        rune: &str,
+        restrictions: impl IntoIterator<Item = Restriction>,
+    ) -> Value {
+        let restrictions: Vec<Restriction> = restrictions.into_iter().collect();
+        json!({ "rune": rune, "restrictions": restrictions })
+    }
+
+    /// A JSON-RPC response, decoded straight into the caller's type.
+    #[derive(Deserialize)]
+    struct Response<T> {
+        result: Option<T>,
+        error: Option<Value>,
+    }
+
+    impl<S: MessageTransport> CommandoClient<S> {
+        /// Like [`CommandoClient::call`], decoding the reply into `T`. A reply that doesn't
+        /// fit `T` fails with `Error::Json`.
+        ///
+        /// The reply bytes are deserialized into `T` directly, without building a `Value`
+        /// first, so this is also the cheap way to make calls with very large replies.
+        pub async fn call_typed<T: DeserializeOwned>(
+            &self,
+            method: impl Into<String>,
+            params: Value,
+        ) -> Result<T, Error> {
+            let method = method.into();
+            self.with_rune_renewal(&method, CallOpts::default(), |opts| async {
+                let reply = self.call_raw(method.clone(), params.clone(), opts).await?;
+                let response: Response<T> = serde_json::from_slice(&reply).map_err(|err| {
+                    tracing::debug!(%method, "unexpected reply shape: {err}");
+                    Error::Json
+                })?;
+                match response {
+                    Response {
+                        error: Some(error), ..
+                    } => Err(Error::Rpc(rpc_error(&error))),
+                    Response {
+                        result: Some(result),
+                        ..
+                    } => Ok(result),
+                    // `"result": null`, or none at all, is fine for a `T` that takes null
+                    Response { result: None, .. } => T::deserialize(Value::Null).map_err(|err| {
+                        tracing::debug!(%method, "unexpected null result: {err}");
+                        Error::Json
+                    }),
+                }
+            })
+            .await
+        }
+
+        /// `bkpr-listaccountevents`, optionally for a single account.
+        pub async fn bkpr_list_account_events(
+            &self,
+            account: Option<&str>,
+        ) -> Result<ListAccountEvents, Error> {
+            let params = match account {
+                Some(account) => json!({ "account": account }),
+                None => json!({}),
+            };
+            self.call_typed("bkpr-listaccountevents", params).await
+        }
+
+        /// `bkpr-listincome`.
+        pub async fn bkpr_list_income(
+            &self,
+            params: ListIncomeParams,
+        ) -> Result<ListIncome, Error> {
+            let params = serde_json::to_value(params).map_err(|_| Error::Json)?;
+            self.call_typed("bkpr-listincome", params).await
+        }
+
+        /// Mint a rune with `restrictions` on top of the ones of the client's own rune, with
+        /// CLN's `createrune` (`commando-rune` on nodes older than v23.08).
+        ///
+        /// Handy to give a subcomponent or another device only the access it needs while the
+        /// rune the client was spawned with stays private. The node only mints runes for a
+        /// rune that may call `createrune` itself.
+        pub async fn mint_restricted_rune(
+            &self,
+            restrictions: impl IntoIterator<Item = Restriction>,
+        ) -> Result<Rune, Error> {
+            let params = createrune_params(&self.default_rune(), restrictions);
+            match self.call_typed("createrune", params.clone()).await {
+                Err(Error::Rpc(err)) if err.is_method_not_found() => {
+                    self.call_typed("commando-rune", params).await
+                }
+                res => res,
+            }
+        }
+
+        /// `plugin list`.
+        pub async fn plugin_list(&self) -> Result<PluginList, Error> {
+            self.call_typed("plugin", json!({ "subcommand": "list" }))
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn account_events_keep_unknown_fields() {
+        let reply = json!({"events": [
+            {
+                "account": "wallet",
+                "type": "chain",
+                "tag": "deposit",
+                "credit_msat": 200000000,
+                "debit_msat": 0,
+                "currency": "bcrt",
+                "outpoint": "7e3c8b1e0f9a4b7c2d1e0f9a4b7c2d1e0f9a4b7c2d1e0f9a4b7c2d1e0f9a4b7c:0",
+                "timestamp": 1700000000,
+                "blockheight": 110,
+                "origin": "external",
+            },
+            {
+                "account": "2a1b...",
+                "type": "channel",
+                "tag": "invoice",
+                "credit_msat": 5000,
+                "debit_msat": 0,
+                "currency": "bcrt",
+                "payment_id": "aa",
+                "part_id": 0,
+                "timestamp": 1700000100,
+                "fees_msat": 1,
+                "is_rebalance": false,
+            },
+        ]});
+        let events: ListAccountEvents = serde_json::from_value(reply.clone()).unwrap();
+
+        assert_eq!(events.events.len(), 2);
+        assert_eq!(events.events[0].kind, "chain");
+        assert_eq!(events.events[0].blockheight, Some(110));
+        assert_eq!(events.events[0].extra["origin"], "external");
+        assert_eq!(events.events[1].fees_msat, Some(1));
+        assert_eq!(events.events[1].extra["part_id"], 0);
+        // nothing is lost on the way back
+        assert_eq!(serde_json::to_value(&events).unwrap(), reply);
+    }
+
+    #[test]
+    fn amounts_with_units() {
+        let event: IncomeEvent = serde_json::from_value(json!({
+            "account": "wallet", "tag": "deposit", "credit_msat": "5sat",
+            "debit_msat": "0msat", "currency": "bc", "timestamp": 1,
+        }))
+        .unwrap();
+        assert_eq!(event.credit_msat, 5000);
+        assert_eq!(serde_json::to_value(&event).unwrap()["credit_msat"], 5000);
+
+        assert_eq!(msat::parse("1000"), Some(1000));
+        assert_eq!(msat::parse("1btc"), None);
+        assert_eq!(msat::parse(&format!("{}sat", u64::MAX)), None);
+    }
+
+    #[test]
+    fn hex_fields() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Payment {
+            #[serde(with = "hex")]
+            hash: [u8; 4],
+            #[serde(default, with = "hex::option")]
+            preimage: Option<Vec<u8>>,
+        }
+
+        let payment: Payment = serde_json::from_value(json!({"hash": "0011aaff"})).unwrap();
+        assert_eq!(payment.hash, [0x00, 0x11, 0xaa, 0xff]);
+        assert_eq!(payment.preimage, None);
+
+        let payment = Payment {
+            hash: [1; 4],
+            preimage: Some(vec![0xab]),
+        };
+        let value = serde_json::to_value(&payment).unwrap();
+        assert_eq!(value, json!({"hash": "01010101", "preimage": "ab"}));
+        assert_eq!(serde_json::from_value::<Payment>(value).unwrap(), payment);
+
+        for bad in [json!({"hash": "0011aa"}), json!({"hash": "zz11aaff"})] {
+            assert!(serde_json::from_value::<Payment>(bad).is_err());
+        }
+    }
+
+    #[test]
+    fn plugin_list_and_params() {
+        let reply = json!({
+            "command": "list",
+            "plugins": [{"name": "/usr/libexec/c-lightning/plugins/bookkeeper", "active": true, "dynamic": false}],
+        });
+        let list: PluginList = serde_json::from_value(reply).unwrap();
+        assert!(list.plugins[0].active);
+        assert!(list.plugins[0].name.ends_with("bookkeeper"));
+
+        let params = ListIncomeParams {
+            start_time: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(params).unwrap(),
+            json!({"start_time": 1})
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn restricted_rune_params() {
+        let node_id: PublicKey =
+            "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619"
+                .parse()
+                .unwrap();
+        let params = client::createrune_params(
+            "master",
+            [
+                Restriction::id(&node_id),
+                Restriction::methods(["getinfo", "listpays"]),
+                Restriction::expires_at(1700000000),
+            ],
+        );
+        assert_eq!(
+            params,
+            json!({"rune": "master", "restrictions": [
+                [format!("id={node_id}")],
+                ["method=getinfo", "method=listpays"],
+                ["time<1700000000"],
+            ]})
+        );
+
+        let rune: Rune = serde_json::from_value(json!({
+            "rune": "abc=",
+            "unique_id": "5",
+        }))
+        .unwrap();
+        assert_eq!(rune.to_string(), "abc=");
+        assert_eq!(rune.unique_id, "5");
+    }
+}
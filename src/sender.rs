@@ -0,0 +1,924 @@
+//! The writer task behind every [`LNSocket`](crate::LNSocket).
+//!
+//! Once the handshake is done the stream is split: the socket keeps the read half, and a
+//! background task owns the write half together with the sending direction of the Noise
+//! cipher. All writes — [`LNSocket::write`](crate::LNSocket::write) as well as any number of
+//! [`MessageSender`] clones — are queued to that task, which encrypts them in queue order, so
+//! nonces always hit the wire in order.
+//!
+//! Under load the task coalesces: everything already queued (up to 64 KiB) goes out in one
+//! vectored write instead of one small TCP segment per message. Nothing is delayed for this
+//! by default; [`LNSocket::set_write_linger`](crate::LNSocket::set_write_linger) trades a
+//! little latency for bigger batches, and [`MessageSender::flush`] cuts a linger short.
+//!
+//! Encryption happens on the writer task, which for a big burst (gossip rebroadcast, say) can
+//! keep a runtime thread busy for a while.
+//! [`LNSocket::set_encrypt_offload`](crate::LNSocket::set_encrypt_offload) moves the
+//! encryption of large batches to tokio's blocking pool. The task still waits for each batch
+//! to be encrypted before it starts on the next, so nonces stay in queue order.
+//!
+//! ## Ordering
+//!
+//! - Messages hit the wire in the order they were queued, whichever handle queued them:
+//!   [`LNSocket::write`](crate::LNSocket::write) and every [`MessageSender`] share one queue.
+//!   Calls awaited one after another from the same task are therefore written in that order.
+//! - A burst from [`MessageSender::send_frames`] is written back to back, with nothing from
+//!   other senders in between.
+//! - A send resolves once its bytes have been handed to the kernel, not when the peer has
+//!   read them. [`LNSocket::write_and_confirm`](crate::LNSocket::write_and_confirm) waits
+//!   for a `pong` for that.
+//!
+//! ## Reading while writing
+//!
+//! A write that waits for the peer, because the TCP send buffer is full, must not keep the
+//! socket from reading: the peer may be blocked writing to us in turn, and a ping left unread
+//! gets the connection dropped. So [`LNSocket::write`](crate::LNSocket::write) and its
+//! siblings take `&self` and return a [`PendingWrite`], which holds a [`MessageSender`]
+//! rather than a borrow of the socket. One task can poll a write and keep reading next to it:
+//!
+//! ```no_run
+//! # use lnsocket::{LNSocket, ln::msgs};
+//! # async fn ex(mut sock: LNSocket, big: msgs::Ping) -> Result<(), lnsocket::Error> {
+//! let mut write = std::pin::pin!(sock.write(&big));
+//! loop {
+//!     tokio::select! {
+//!         res = &mut write => break res?,
+//!         msg = sock.read() => println!("read {:?} meanwhile", msg?),
+//!     }
+//! }
+//! # Ok(()) }
+//! ```
+//!
+//! Like any future, a [`PendingWrite`] queues nothing until it is first polled.
+//!
+//! The task exits when the [`LNSocket`](crate::LNSocket) is dropped (after writing whatever was
+//! already queued); senders then fail with `BrokenPipe`.
+
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::Error;
+use crate::capture::{self, Direction, SharedCapture};
+use crate::clock::SharedClock;
+use crate::congestion::{Congestion, CongestionTracker};
+use crate::events::{FailureCause, SocketEvent};
+use crate::ln::features::SendGate;
+use crate::ln::msgs;
+use crate::ln::peer_channel_encryptor::{LN_MAX_MSG_LEN, MSG_BUF_ALLOC_SIZE, PeerChannelEncryptor};
+use crate::ln::wire::{self, Encode, Type};
+use crate::ping::{PingStats, PingTracker};
+use crate::privacy::{Padding, PaddingPolicy};
+use crate::stats::StatsRecorder;
+use crate::stream::WriteHalf;
+use crate::transport::MAC_SIZE;
+use crate::util::ser::{VecWriter, Writeable};
+
+/// How many messages may be queued for the writer task before senders wait.
+const OUTBOX_SIZE: usize = 128;
+
+/// Stop adding messages to a coalesced write beyond this many bytes.
+const MAX_BATCH_BYTES: usize = 64 * 1024;
+
+/// How long a write may wait for the peer before [`SocketEvent::WriteStalled`] is emitted,
+/// by default.
+pub const DEFAULT_WRITE_STALL_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// A message encoded for sending but not yet encrypted, see
+/// [`MessageSender::send_frames`].
+///
+/// Frames are only encrypted by the writer task, right before they are written, so that
+/// nonces always match the order bytes hit the wire.
+pub struct Frame {
+    /// 16 + 2 zero bytes for the encrypted length header, then type + payload.
+    buf: Vec<u8>,
+    type_id: u16,
+}
+
+impl Frame {
+    /// Encode `msg`. Fails with `Io(InvalidInput)` if it is larger than the BOLT 8 maximum of
+    /// 65535 bytes.
+    pub fn new<M: Type + Writeable>(msg: &M) -> Result<Frame, Error> {
+        let mut buf = VecWriter(Vec::with_capacity(MSG_BUF_ALLOC_SIZE));
+        buf.0.resize(16 + 2, 0);
+        wire::write(msg, &mut buf).expect("In-memory messages must never fail to serialize");
+        if buf.0.len() - (16 + 2) > LN_MAX_MSG_LEN {
+            return Err(Error::Io(io::ErrorKind::InvalidInput));
+        }
+        Ok(Frame {
+            buf: buf.0,
+            type_id: msg.type_id(),
+        })
+    }
+
+    pub fn type_id(&self) -> u16 {
+        self.type_id
+    }
+
+    /// Length of the encoded message, type included.
+    pub fn len(&self) -> usize {
+        self.buf.len() - (16 + 2)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The message body after the type.
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[16 + 2 + 2..]
+    }
+}
+
+/// Frames waiting for the writer task.
+struct Outbound {
+    frames: Vec<Frame>,
+    done: oneshot::Sender<io::Result<()>>,
+}
+
+/// Requests to the writer task.
+enum WriterMsg {
+    Send(Outbound),
+    /// Write whatever is being held back for coalescing now, then ack.
+    Flush(oneshot::Sender<()>),
+    SetLinger(Option<Duration>),
+    SetStallThreshold(Option<Duration>),
+    SetPadding(PaddingPolicy),
+    SetEncryptOffload(Option<usize>),
+    SetClock(SharedClock),
+}
+
+/// The writer task's knobs.
+struct Settings {
+    clock: SharedClock,
+    linger: Option<Duration>,
+    stall_threshold: Option<Duration>,
+    padding: PaddingPolicy,
+    /// Batches of at least this many bytes are encrypted on the blocking pool.
+    encrypt_offload: Option<usize>,
+}
+
+/// A cheap, cloneable handle for sending messages on an [`LNSocket`](crate::LNSocket) from
+/// other tasks, while the socket's owner keeps reading. Get one with
+/// [`LNSocket::sender`](crate::LNSocket::sender).
+///
+/// ```no_run
+/// # use lnsocket::{LNSocket, ln::msgs};
+/// # async fn ex(mut sock: LNSocket) -> Result<(), lnsocket::Error> {
+/// let sender = sock.sender();
+/// tokio::spawn(async move {
+///     let _ = sender.send(&msgs::Ping { ponglen: 4, byteslen: 8 }).await;
+/// });
+/// let _pong = sock.read().await?;
+/// # Ok(()) }
+/// ```
+#[derive(Clone)]
+pub struct MessageSender {
+    tx: mpsc::Sender<WriterMsg>,
+    gate: Arc<Mutex<SendGate>>,
+    congestion: Arc<CongestionTracker>,
+    pings: Arc<Mutex<PingTracker>>,
+    stats: Arc<Mutex<StatsRecorder>>,
+}
+
+impl MessageSender {
+    /// Queue `msg` and wait until it has been written to the socket.
+    ///
+    /// Messages from one sender are written in the order they were sent. Fails with
+    /// `Io(BrokenPipe)` once the socket is gone, `Io(InvalidInput)` if the encoded message is
+    /// larger than the BOLT 8 maximum of 65535 bytes, [`Error::FeatureNotNegotiated`] if
+    /// strict feature checking refuses the message type, and [`Error::QuotaExceeded`] once an
+    /// enforced outbound [quota](crate::stats#quotas) is used up.
+    pub async fn send<M: Type + Writeable>(&self, msg: &M) -> Result<(), Error> {
+        self.send_frames(vec![Frame::new(msg)?]).await
+    }
+
+    /// Send a burst of messages back to back: they are encrypted together and handed to the
+    /// socket in a single vectored write, with nothing from other senders in between.
+    ///
+    /// Either every frame passes the strict feature check or none is sent.
+    pub async fn send_frames(&self, frames: Vec<Frame>) -> Result<(), Error> {
+        self.queue(frames, false).await
+    }
+
+    /// Like [`MessageSender::send`], but write `msg` right away even with a
+    /// [`LNSocket::set_write_linger`](crate::LNSocket::set_write_linger), along with anything
+    /// queued before it.
+    pub async fn send_and_flush<M: Type + Writeable>(&self, msg: &M) -> Result<(), Error> {
+        self.queue(vec![Frame::new(msg)?], true).await
+    }
+
+    /// Queue `frames`, followed by a flush if asked to, and wait until they are written.
+    async fn queue(&self, frames: Vec<Frame>, flush: bool) -> Result<(), Error> {
+        {
+            let gate = self.gate.lock().unwrap();
+            for frame in &frames {
+                gate.check(frame.type_id)?;
+            }
+        }
+        if let Some(resets_in) = self
+            .stats
+            .lock()
+            .unwrap()
+            .quota_blocked(Direction::Outbound)
+        {
+            return Err(Error::QuotaExceeded {
+                direction: Direction::Outbound,
+                resets_in,
+            });
+        }
+        for frame in frames.iter().filter(|f| f.type_id == msgs::Ping::TYPE) {
+            if let [a, b, ..] = *frame.payload() {
+                self.pings
+                    .lock()
+                    .unwrap()
+                    .ping_sent(u16::from_be_bytes([a, b]));
+            }
+        }
+
+        let bytes = frames.iter().map(Frame::len).sum();
+        self.congestion.queued(bytes);
+        let (done, done_rx) = oneshot::channel();
+        if let Err(err) = self
+            .request(WriterMsg::Send(Outbound { frames, done }))
+            .await
+        {
+            self.congestion.dropped(bytes);
+            return Err(err);
+        }
+        if flush {
+            // nobody waits for the ack, the send resolving is what counts
+            self.request(WriterMsg::Flush(oneshot::channel().0)).await?;
+        }
+
+        done_rx
+            .await
+            .map_err(|_| Error::Io(io::ErrorKind::BrokenPipe))??;
+        Ok(())
+    }
+
+    /// Queue `frames` like [`MessageSender::queue`], as a future that owns a clone of this
+    /// sender instead of borrowing it.
+    pub(crate) fn detached(&self, frames: Result<Vec<Frame>, Error>, flush: bool) -> PendingWrite {
+        let sender = self.clone();
+        PendingWrite(Box::pin(async move { sender.queue(frames?, flush).await }))
+    }
+
+    /// Write out any messages held back by [`LNSocket::set_write_linger`] right away, and
+    /// wait until they are on the socket. A no-op without a linger.
+    ///
+    /// [`LNSocket::set_write_linger`]: crate::LNSocket::set_write_linger
+    pub async fn flush(&self) -> Result<(), Error> {
+        let (done, done_rx) = oneshot::channel();
+        self.request(WriterMsg::Flush(done)).await?;
+        done_rx
+            .await
+            .map_err(|_| Error::Io(io::ErrorKind::BrokenPipe))
+    }
+
+    async fn request(&self, msg: WriterMsg) -> Result<(), Error> {
+        self.tx
+            .send(msg)
+            .await
+            .map_err(|_| Error::Io(io::ErrorKind::BrokenPipe))
+    }
+
+    /// What the socket's [`PingTracker`] has counted, see
+    /// [`LNSocket::ping_stats`](crate::LNSocket::ping_stats).
+    pub fn ping_stats(&self) -> PingStats {
+        self.pings.lock().unwrap().stats()
+    }
+
+    /// Whether the socket's writer has stopped, e.g. because the socket was dropped.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// The socket's congestion signal right now, see the [`congestion`](crate::congestion)
+    /// module.
+    pub fn congestion(&self) -> Congestion {
+        self.congestion.snapshot()
+    }
+}
+
+/// A write on its way to the socket, resolving once it has been written. It borrows nothing,
+/// so the socket can be read while it waits, see [reading while writing](self#reading-while-writing).
+#[must_use = "writes do nothing unless polled"]
+pub struct PendingWrite(Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>);
+
+impl Future for PendingWrite {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// The socket's side of the writer task.
+pub(crate) struct Writer {
+    sender: MessageSender,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<io::Result<(WriteHalf, PeerChannelEncryptor)>>,
+}
+
+impl Writer {
+    /// Spawn the writer task around the write half of a stream and the sending direction of
+    /// a finished session.
+    pub(crate) fn spawn(
+        stream: WriteHalf,
+        channel: PeerChannelEncryptor,
+        stats: Arc<Mutex<StatsRecorder>>,
+        capture: SharedCapture,
+        events: broadcast::Sender<SocketEvent>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(OUTBOX_SIZE);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let congestion = Arc::new(CongestionTracker::new());
+        let gate = Arc::new(Mutex::new(SendGate::default()));
+        let task = tokio::spawn(writer_task(
+            stream,
+            channel,
+            Shared {
+                stats: stats.clone(),
+                capture,
+                events,
+                congestion: congestion.clone(),
+                gate: gate.clone(),
+            },
+            rx,
+            shutdown_rx,
+        ));
+        Writer {
+            sender: MessageSender {
+                tx,
+                gate,
+                congestion,
+                pings: Arc::default(),
+                stats,
+            },
+            shutdown,
+            task,
+        }
+    }
+
+    pub(crate) fn sender(&self) -> &MessageSender {
+        &self.sender
+    }
+
+    /// See [`LNSocket::set_write_linger`](crate::LNSocket::set_write_linger).
+    pub(crate) async fn set_linger(&self, linger: Option<Duration>) -> Result<(), Error> {
+        self.sender.request(WriterMsg::SetLinger(linger)).await
+    }
+
+    /// See [`LNSocket::set_write_stall_threshold`](crate::LNSocket::set_write_stall_threshold).
+    pub(crate) async fn set_stall_threshold(
+        &self,
+        threshold: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.sender
+            .request(WriterMsg::SetStallThreshold(threshold))
+            .await
+    }
+
+    /// See [`LNSocket::set_padding`](crate::LNSocket::set_padding).
+    pub(crate) async fn set_padding(&self, policy: PaddingPolicy) -> Result<(), Error> {
+        self.sender.request(WriterMsg::SetPadding(policy)).await
+    }
+
+    /// See [`LNSocket::set_encrypt_offload`](crate::LNSocket::set_encrypt_offload).
+    pub(crate) async fn set_encrypt_offload(&self, min_bytes: Option<usize>) -> Result<(), Error> {
+        self.sender
+            .request(WriterMsg::SetEncryptOffload(min_bytes))
+            .await
+    }
+
+    /// See [`LNSocket::set_clock`](crate::LNSocket::set_clock).
+    pub(crate) async fn set_clock(&self, clock: SharedClock) -> Result<(), Error> {
+        self.sender.request(WriterMsg::SetClock(clock)).await
+    }
+
+    /// The send policy shared by every [`MessageSender`] of this socket.
+    pub(crate) fn gate(&self) -> std::sync::MutexGuard<'_, SendGate> {
+        self.sender.gate.lock().unwrap()
+    }
+
+    pub(crate) fn congestion(&self) -> &CongestionTracker {
+        &self.sender.congestion
+    }
+
+    /// The ping rules shared by the socket and every [`MessageSender`] of it.
+    pub(crate) fn pings(&self) -> std::sync::MutexGuard<'_, PingTracker> {
+        self.sender.pings.lock().unwrap()
+    }
+
+    /// Stop the task after it has written everything already queued, and take back the write
+    /// half and the sending cipher.
+    #[cfg(unix)]
+    pub(crate) async fn stop(self) -> io::Result<(WriteHalf, PeerChannelEncryptor)> {
+        let _ = self.shutdown.send(());
+        self.task
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?
+    }
+}
+
+/// What the writer task reports to, shared with the socket.
+struct Shared {
+    stats: Arc<Mutex<StatsRecorder>>,
+    capture: SharedCapture,
+    events: broadcast::Sender<SocketEvent>,
+    congestion: Arc<CongestionTracker>,
+    /// Padding waits for the `init` exchange.
+    gate: Arc<Mutex<SendGate>>,
+}
+
+async fn writer_task(
+    mut stream: WriteHalf,
+    mut channel: PeerChannelEncryptor,
+    shared: Shared,
+    mut rx: mpsc::Receiver<WriterMsg>,
+    mut shutdown: oneshot::Receiver<()>,
+) -> io::Result<(WriteHalf, PeerChannelEncryptor)> {
+    let mut settings = Settings {
+        clock: SharedClock::default(),
+        linger: None,
+        stall_threshold: Some(DEFAULT_WRITE_STALL_THRESHOLD),
+        padding: PaddingPolicy::None,
+        encrypt_offload: None,
+    };
+    loop {
+        let first = tokio::select! {
+            biased;
+            // fires on an explicit stop and when the socket is dropped
+            _ = &mut shutdown => break,
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+
+        let mut batch = Batch::default();
+        batch.push(first, &mut settings);
+        // coalesce whatever else is already queued
+        while !batch.is_full() {
+            match rx.try_recv() {
+                Ok(msg) => batch.push(msg, &mut settings),
+                Err(_) => break,
+            }
+        }
+        // and, when asked to, whatever arrives shortly after
+        if let Some(wait) = settings.linger {
+            let mut deadline = settings.clock.sleep(wait);
+            while !batch.is_full() {
+                tokio::select! {
+                    _ = &mut deadline => break,
+                    msg = rx.recv() => match msg {
+                        Some(msg) => batch.push(msg, &mut settings),
+                        None => break,
+                    },
+                }
+            }
+        }
+        channel = batch
+            .write(&mut stream, channel, &shared, &settings)
+            .await?;
+    }
+
+    // don't lose what was queued before we were told to stop
+    rx.close();
+    let mut batch = Batch::default();
+    while let Some(msg) = rx.recv().await {
+        batch.push(msg, &mut settings);
+    }
+    let channel = batch
+        .write(&mut stream, channel, &shared, &settings)
+        .await?;
+
+    Ok((stream, channel))
+}
+
+/// Messages written together in one vectored write.
+#[derive(Default)]
+struct Batch {
+    sends: Vec<Outbound>,
+    flushes: Vec<oneshot::Sender<()>>,
+    bytes: usize,
+}
+
+impl Batch {
+    fn push(&mut self, msg: WriterMsg, settings: &mut Settings) {
+        match msg {
+            WriterMsg::Send(out) => {
+                self.bytes += out.frames.iter().map(|f| f.buf.len()).sum::<usize>();
+                self.sends.push(out);
+            }
+            WriterMsg::Flush(done) => self.flushes.push(done),
+            WriterMsg::SetLinger(new) => settings.linger = new,
+            WriterMsg::SetStallThreshold(new) => settings.stall_threshold = new,
+            WriterMsg::SetPadding(new) => settings.padding = new,
+            WriterMsg::SetEncryptOffload(new) => settings.encrypt_offload = new,
+            WriterMsg::SetClock(new) => settings.clock = new,
+        }
+    }
+
+    /// Full batches, and flushed ones, are written without waiting for more.
+    fn is_full(&self) -> bool {
+        self.bytes >= MAX_BATCH_BYTES || !self.flushes.is_empty()
+    }
+
+    /// Encrypt and write the batch, handing back the cipher for the next one. Fails only if
+    /// the cipher is lost, when nothing more can be written.
+    async fn write(
+        mut self,
+        stream: &mut WriteHalf,
+        channel: PeerChannelEncryptor,
+        shared: &Shared,
+        settings: &Settings,
+    ) -> io::Result<PeerChannelEncryptor> {
+        let padding = self.padding(shared, settings);
+        let mut frames: Vec<Frame> = self
+            .sends
+            .iter_mut()
+            .flat_map(|out| std::mem::take(&mut out.frames))
+            .collect();
+        let bytes = frames.iter().map(|f| f.len()).sum();
+        frames.extend(padding);
+        for frame in &frames {
+            // the plaintext is encrypted in place, so this is the last chance to capture it
+            capture::capture(
+                &shared.capture,
+                Direction::Outbound,
+                frame.type_id,
+                frame.payload(),
+            );
+        }
+        let (channel, frames) = match encrypt_frames(channel, frames, bytes, settings).await {
+            Ok(encrypted) => encrypted,
+            Err(_) => {
+                // the blocking pool is going away with the runtime, and the cipher with it
+                let kind = io::ErrorKind::BrokenPipe;
+                shared.congestion.dropped(bytes);
+                let _ = shared
+                    .events
+                    .send(SocketEvent::WriteFailed(FailureCause::of(kind)));
+                for out in self.sends {
+                    let _ = out.done.send(Err(kind.into()));
+                }
+                return Err(kind.into());
+            }
+        };
+
+        let start = settings.clock.now();
+        let res = if frames.is_empty() {
+            Ok(())
+        } else {
+            write_watched(stream, &frames, &shared.events, settings).await
+        };
+        match &res {
+            Ok(()) => {
+                if !frames.is_empty() {
+                    shared
+                        .congestion
+                        .written(bytes, settings.clock.since(start));
+                }
+                let report = {
+                    let mut stats = shared.stats.lock().unwrap();
+                    // encrypted, the frames end with the MAC of their body
+                    for frame in &frames {
+                        stats.record_outbound(frame.type_id, frame.len() - MAC_SIZE);
+                    }
+                    stats.take_quota_report()
+                };
+                report.fire();
+            }
+            Err(err) => {
+                shared.congestion.dropped(bytes);
+                // no subscribers is fine
+                let _ = shared
+                    .events
+                    .send(SocketEvent::WriteFailed(FailureCause::of(err.kind())));
+            }
+        }
+
+        for out in self.sends {
+            let _ = out.done.send(match &res {
+                Ok(()) => Ok(()),
+                Err(err) => Err(err.kind().into()),
+            });
+        }
+        for done in self.flushes {
+            let _ = done.send(());
+        }
+        Ok(channel)
+    }
+
+    /// The padding messages to write after this batch, once `init` is out of the way.
+    fn padding(&self, shared: &Shared, settings: &Settings) -> Vec<Frame> {
+        if settings.padding == PaddingPolicy::None
+            || self.sends.is_empty()
+            || !shared.gate.lock().unwrap().init_done()
+        {
+            return Vec::new();
+        }
+        let wire_len = self
+            .sends
+            .iter()
+            .flat_map(|out| &out.frames)
+            .map(|f| f.buf.len() + MAC_SIZE)
+            .sum();
+        settings
+            .padding
+            .padding(wire_len)
+            .into_iter()
+            .map(|len| Frame::new(&Padding(len)).expect("padding fits a message"))
+            .collect()
+    }
+}
+
+/// Encrypt `frames` in order, on the blocking pool if the batch is `bytes` long and that is
+/// over the offload threshold. Fails with `Error::Io(BrokenPipe)`, the cipher lost, if the
+/// runtime shuts down before the blocking pool gets to it.
+async fn encrypt_frames(
+    mut channel: PeerChannelEncryptor,
+    mut frames: Vec<Frame>,
+    bytes: usize,
+    settings: &Settings,
+) -> Result<(PeerChannelEncryptor, Vec<Frame>), Error> {
+    let encrypt = move || {
+        for frame in &mut frames {
+            channel.encrypt_message_with_header_0s(&mut frame.buf);
+        }
+        (channel, frames)
+    };
+    match settings.encrypt_offload {
+        Some(min_bytes) if bytes >= min_bytes => tokio::task::spawn_blocking(encrypt)
+            .await
+            .map_err(|_| Error::Io(io::ErrorKind::BrokenPipe)),
+        _ => Ok(encrypt()),
+    }
+}
+
+/// Write `frames`, reporting a stall once if the peer hasn't taken them after the stall
+/// threshold.
+async fn write_watched(
+    stream: &mut WriteHalf,
+    frames: &[Frame],
+    events: &broadcast::Sender<SocketEvent>,
+    settings: &Settings,
+) -> io::Result<()> {
+    let write = write_all_vectored(stream, frames);
+    let Some(threshold) = settings.stall_threshold else {
+        return write.await;
+    };
+    tokio::pin!(write);
+    let deadline = settings.clock.now() + threshold;
+    match settings.clock.timeout_at(deadline, &mut write).await {
+        Some(res) => res,
+        None => {
+            tracing::debug!("write stalled for {threshold:?}");
+            let _ = events.send(SocketEvent::WriteStalled { waited: threshold });
+            write.await
+        }
+    }
+}
+
+async fn write_all_vectored(stream: &mut WriteHalf, frames: &[Frame]) -> io::Result<()> {
+    let mut slices: Vec<IoSlice<'_>> = frames.iter().map(|f| IoSlice::new(&f.buf)).collect();
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        let n = stream.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut slices, n);
+    }
+    // a no-op on TCP, but TLS may hold on to what it couldn't write yet
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs;
+    use crate::ln::wire::Message;
+    use crate::lnsocket::testing::*;
+    use tokio::io::AsyncReadExt;
+
+    fn events() -> broadcast::Sender<SocketEvent> {
+        broadcast::channel(8).0
+    }
+
+    #[tokio::test]
+    async fn concurrent_senders_are_serialized() {
+        let (client, mut server) = tcp_pair().await;
+
+        let (ours, mut theirs) = encryptor_pair();
+        let stats = Arc::new(Mutex::new(StatsRecorder::new(SharedClock::default())));
+        let (_read_half, write_half) = client.into_split();
+        let writer = Writer::spawn(
+            write_half.into(),
+            ours,
+            stats.clone(),
+            SharedCapture::default(),
+            events(),
+        );
+
+        let mut tasks = Vec::new();
+        for i in 0..10u16 {
+            let sender = writer.sender().clone();
+            tasks.push(tokio::spawn(async move {
+                sender
+                    .send(&msgs::Ping {
+                        ponglen: i,
+                        byteslen: i * 10,
+                    })
+                    .await
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        let mut seen = Vec::new();
+        for _ in 0..10 {
+            match peer_recv(&mut server, &mut theirs).await {
+                Message::Ping(ping) => seen.push(ping.ponglen),
+                other => panic!("unexpected message {other:?}"),
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, (0..10).collect::<Vec<_>>());
+        assert_eq!(stats.lock().unwrap().snapshot().outbound.messages, 10);
+        // everything went out, and was sampled on the way
+        let congestion = writer.sender().congestion();
+        assert_eq!(congestion.pending_now, 0);
+        assert!(congestion.pending_bytes > 0.0);
+    }
+
+    #[tokio::test]
+    async fn frames_are_written_in_order() {
+        let (client, mut server) = tcp_pair().await;
+
+        let (ours, theirs) = encryptor_pair();
+        let stats = Arc::new(Mutex::new(StatsRecorder::new(SharedClock::default())));
+        let (_read_half, write_half) = client.into_split();
+        let writer = Writer::spawn(
+            write_half.into(),
+            ours,
+            stats.clone(),
+            SharedCapture::default(),
+            events(),
+        );
+
+        let frames = (0..5u16)
+            .map(|i| Frame::new(&msgs::Pong { byteslen: i }).unwrap())
+            .collect();
+        writer.sender().send_frames(frames).await.unwrap();
+
+        let mut transport = crate::transport::Transport::from_channel(theirs);
+        // header, type, byteslen and MAC per pong, plus 0 + 1 + 2 + 3 + 4 zero bytes
+        let mut wire = vec![0u8; 5 * (18 + 2 + 2 + 16) + 10];
+        server.read_exact(&mut wire).await.unwrap();
+        let mut rest = &mut wire[..];
+        for i in 0..5u16 {
+            let len = 18 + 2 + 2 + i as usize + 16;
+            let (frame, tail) = rest.split_at_mut(len);
+            let (type_id, payload) = transport.decrypt_frame(frame).unwrap();
+            assert_eq!(type_id, 19);
+            assert_eq!(payload.len(), 2 + i as usize);
+            rest = tail;
+        }
+        assert_eq!(stats.lock().unwrap().snapshot().outbound.messages, 5);
+    }
+
+    #[tokio::test]
+    async fn outbound_bytes_count_the_message_not_the_mac() {
+        let (client, mut server) = tcp_pair().await;
+
+        let (ours, mut theirs) = encryptor_pair();
+        let stats = Arc::new(Mutex::new(StatsRecorder::new(SharedClock::default())));
+        let (_read_half, write_half) = client.into_split();
+        let writer = Writer::spawn(
+            write_half.into(),
+            ours,
+            stats.clone(),
+            SharedCapture::default(),
+            events(),
+        );
+
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 10,
+        };
+        writer.sender().send(&ping).await.unwrap();
+        peer_recv(&mut server, &mut theirs).await;
+
+        // type, ponglen, byteslen and the padding, same as inbound counts it
+        let outbound = stats.lock().unwrap().snapshot().outbound;
+        assert_eq!(outbound.bytes, 2 + 2 + 2 + 10);
+        assert_eq!(outbound.by_type[&18].bytes, 2 + 2 + 2 + 10);
+    }
+
+    #[tokio::test]
+    async fn offloaded_encryption_keeps_nonces_in_order() {
+        let (client, mut server) = tcp_pair().await;
+
+        let (ours, mut theirs) = encryptor_pair();
+        let (_read_half, write_half) = client.into_split();
+        let writer = Writer::spawn(
+            write_half.into(),
+            ours,
+            Arc::new(Mutex::new(StatsRecorder::new(SharedClock::default()))),
+            SharedCapture::default(),
+            events(),
+        );
+        // only the bursts go to the blocking pool, the single pongs don't
+        writer.set_encrypt_offload(Some(1024)).await.unwrap();
+
+        let reader = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            for _ in 0..4 * 101 {
+                let (_, payload) = peer_recv_raw(&mut server, &mut theirs).await;
+                seen.push(u16::from_be_bytes([payload[0], payload[1]]));
+            }
+            seen
+        });
+
+        let mut expected = Vec::new();
+        for burst in 0..4u16 {
+            let frames = (0..100)
+                .map(|_| Frame::new(&msgs::Pong { byteslen: 16 }).unwrap())
+                .collect();
+            writer.sender().send_frames(frames).await.unwrap();
+            writer
+                .sender()
+                .send(&msgs::Pong { byteslen: burst })
+                .await
+                .unwrap();
+            expected.extend(std::iter::repeat_n(16, 100));
+            expected.push(burst);
+        }
+        assert_eq!(reader.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn flush_cuts_linger_short() {
+        let (client, _server) = tcp_pair().await;
+
+        let (ours, _) = encryptor_pair();
+        let (_read_half, write_half) = client.into_split();
+        let writer = Writer::spawn(
+            write_half.into(),
+            ours,
+            Arc::new(Mutex::new(StatsRecorder::new(SharedClock::default()))),
+            SharedCapture::default(),
+            events(),
+        );
+        writer
+            .set_linger(Some(Duration::from_secs(600)))
+            .await
+            .unwrap();
+
+        let sender = writer.sender().clone();
+        let send = tokio::spawn(async move { sender.send(&msgs::Pong { byteslen: 1 }).await });
+        tokio::task::yield_now().await;
+        assert!(!send.is_finished());
+
+        writer.sender().flush().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), send)
+            .await
+            .expect("flush should release the lingering send")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn senders_fail_after_socket_is_dropped() {
+        let (client, _server) = tcp_pair().await;
+
+        let (ours, _) = encryptor_pair();
+        let (_read_half, write_half) = client.into_split();
+        let writer = Writer::spawn(
+            write_half.into(),
+            ours,
+            Arc::new(Mutex::new(StatsRecorder::new(SharedClock::default()))),
+            SharedCapture::default(),
+            events(),
+        );
+        let sender = writer.sender().clone();
+        drop(writer);
+        while !sender.is_closed() {
+            tokio::task::yield_now().await;
+        }
+
+        let err = sender.send(&msgs::Pong { byteslen: 0 }).await.unwrap_err();
+        assert!(matches!(err, Error::Io(io::ErrorKind::BrokenPipe)));
+    }
+}
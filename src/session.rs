@@ -0,0 +1,198 @@
+//! Handing a live, authenticated connection to another process.
+//!
+//! A daemon proxying Lightning connections may want to restart (e.g. to upgrade) without
+//! dropping its peers. [`LNSocket::export_session`](crate::LNSocket::export_session) splits a
+//! socket into an [`ExportedSession`] — the BOLT 8 cipher state plus what is needed to
+//! reconnect — and the raw TCP file descriptor. The replacement process rebuilds the socket
+//! with [`LNSocket::import_session`](crate::LNSocket::import_session) and carries on encrypting
+//! exactly where the old one stopped.
+//!
+//! # Security
+//!
+//! The exported state contains **the live transport keys**. Anyone who reads it can decrypt
+//! the session and send as us on it. Only pass it over a channel you trust (an inherited pipe,
+//! a unix socket with restricted permissions); never write it to disk or logs.
+//!
+//! Our node secret key is left out: the importing process passes its own to
+//! [`LNSocket::import_session`](crate::LNSocket::import_session), which checks it against the
+//! node id the session was made with.
+//!
+//! # Caveats
+//!
+//! - Both processes must not use the connection at the same time. After exporting, the old
+//!   process must never touch the file descriptor again except to close its copy.
+//! - Export a socket only between messages. If a read future was dropped halfway through a
+//!   frame (e.g. in a `select!`), the start of the frame is in the socket's buffer rather
+//!   than the file descriptor, and the export fails with `Error::Io(WouldBlock)`; read the
+//!   rest of the frame first.
+//! - Wire stats start from zero in the new process.
+//! - The session carries the address the socket was connected with, as given, and the
+//!   settings of its [`Dialer`], for reconnects. A dial policy, TLS tunnel, SSH jump host or
+//!   cancellation token can't cross processes: add them back with
+//!   [`ExportedSession::with_dialer`].
+//! - Rust opens sockets with `FD_CLOEXEC`; to pass the fd across `exec` the caller must clear
+//!   that flag, or send it with `SCM_RIGHTS` instead.
+
+use crate::dial::Dialer;
+use crate::ln::msgs::{self, DecodeError};
+use crate::ln::peer_channel_encryptor::CipherState;
+use crate::util::ser::{Readable, Writeable, Writer};
+use bitcoin::secp256k1::PublicKey;
+use std::io;
+
+const SESSION_VERSION: u8 = 3;
+
+/// The serializable half of an exported [`LNSocket`](crate::LNSocket): everything except the
+/// socket itself.
+///
+/// Use [`Writeable::encode`] to turn it into bytes and [`ExportedSession::decode`] to get it
+/// back on the other side.
+pub struct ExportedSession {
+    pub(crate) their_pubkey: PublicKey,
+    pub(crate) our_pubkey: PublicKey,
+    pub(crate) host: String,
+    pub(crate) dialer: Dialer,
+    pub(crate) cipher: CipherState,
+    pub(crate) their_init: Option<msgs::Init>,
+}
+
+impl ExportedSession {
+    /// Parses a session serialized with [`Writeable::encode`].
+    pub fn decode(mut bytes: &[u8]) -> Result<Self, DecodeError> {
+        Readable::read(&mut bytes)
+    }
+
+    /// The node id of the peer this session is connected to.
+    pub fn their_pubkey(&self) -> PublicKey {
+        self.their_pubkey
+    }
+
+    /// Our node id on this session, the one the secret key given to
+    /// [`LNSocket::import_session`](crate::LNSocket::import_session) must belong to.
+    pub fn our_pubkey(&self) -> PublicKey {
+        self.our_pubkey
+    }
+
+    /// The address the socket was connected with, which reconnects resolve again.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Reconnect the imported socket through `dialer` rather than the one rebuilt from the
+    /// exported settings.
+    pub fn with_dialer(mut self, dialer: Dialer) -> Self {
+        self.dialer = dialer;
+        self
+    }
+}
+
+impl Writeable for ExportedSession {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        SESSION_VERSION.write(w)?;
+        w.write_all(&self.their_pubkey.serialize())?;
+        w.write_all(&self.our_pubkey.serialize())?;
+        self.host.write(w)?;
+        self.dialer.write_settings(w)?;
+        self.cipher.write(w)?;
+        self.their_init.write(w)
+    }
+}
+
+impl Readable for ExportedSession {
+    fn read<R: io::Read>(r: &mut R) -> Result<Self, DecodeError> {
+        let version: u8 = Readable::read(r)?;
+        if version != SESSION_VERSION {
+            return Err(DecodeError::UnknownVersion);
+        }
+        Ok(ExportedSession {
+            their_pubkey: read_pubkey(r)?,
+            our_pubkey: read_pubkey(r)?,
+            host: Readable::read(r)?,
+            dialer: Dialer::read_settings(r)?,
+            cipher: Readable::read(r)?,
+            their_init: Readable::read(r)?,
+        })
+    }
+}
+
+fn read_pubkey<R: io::Read>(r: &mut R) -> Result<PublicKey, DecodeError> {
+    let mut key = [0u8; 33];
+    r.read_exact(&mut key)?;
+    PublicKey::from_slice(&key).map_err(|_| DecodeError::InvalidValue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lnsocket::InitOrder;
+    use crate::privacy::PrivacyOptions;
+    use bitcoin::Network;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+    fn session() -> ExportedSession {
+        let secp_ctx = Secp256k1::signing_only();
+        let our_key = SecretKey::from_slice(&[0x11; 32]).unwrap();
+        ExportedSession {
+            their_pubkey: PublicKey::from_secret_key(
+                &secp_ctx,
+                &SecretKey::from_slice(&[0x21; 32]).unwrap(),
+            ),
+            our_pubkey: PublicKey::from_secret_key(&secp_ctx, &our_key),
+            host: "node.example.com:9735".to_string(),
+            dialer: Dialer::new()
+                .with_networks([Network::Regtest, Network::Signet])
+                .with_init_order(InitOrder::OursFirst)
+                .with_privacy(PrivacyOptions::all())
+                .with_tor_proxy("127.0.0.1:9050".parse().unwrap())
+                .with_dns_rebinding_protection(true),
+            cipher: CipherState {
+                sk: [1; 32],
+                sn: 7,
+                sck: [2; 32],
+                rk: [3; 32],
+                rn: 999,
+                rck: [4; 32],
+            },
+            their_init: Some(msgs::Init {
+                features: vec![0x02, 0x00],
+                global_features: vec![],
+                networks: None,
+                remote_network_address: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn session_roundtrips() {
+        let orig = session();
+        let decoded = ExportedSession::decode(&orig.encode()).unwrap();
+
+        assert_eq!(decoded.their_pubkey, orig.their_pubkey);
+        assert_eq!(decoded.our_pubkey, orig.our_pubkey);
+        // the secret key stays behind
+        let secret = SecretKey::from_slice(&[0x11; 32]).unwrap().secret_bytes();
+        assert!(!orig.encode().windows(32).any(|w| w == secret));
+        assert_eq!(decoded.host, orig.host);
+        let mut dialer = Vec::new();
+        orig.dialer.write_settings(&mut dialer).unwrap();
+        let mut decoded_dialer = Vec::new();
+        decoded.dialer.write_settings(&mut decoded_dialer).unwrap();
+        assert_eq!(decoded_dialer, dialer);
+        assert_eq!(
+            decoded.dialer.networks(),
+            [Network::Regtest, Network::Signet]
+        );
+        assert_eq!(decoded.cipher.encode(), orig.cipher.encode());
+        assert_eq!(decoded.their_init, orig.their_init);
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut bytes = session().encode();
+        bytes[0] = SESSION_VERSION + 1;
+        assert!(matches!(
+            ExportedSession::decode(&bytes),
+            Err(DecodeError::UnknownVersion)
+        ));
+    }
+}
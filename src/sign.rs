@@ -1 +1,115 @@
+//! Signed messages, as CLN's `signmessage` and `checkmessage` (and LND's `signmessage` and
+//! `verifymessage`) make them.
+//!
+//! A node proves it holds its key by signing a message: the double SHA-256 of
+//! `"Lightning Signed Message:"` and the message, signed with a recoverable signature and
+//! encoded in zbase32. Since the signer's key can be recovered from the signature, checking
+//! one needs no node id, and none of it needs an RPC round trip:
+//!
+//! ```
+//! use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+//! use lnsocket::sign;
+//!
+//! let key = SecretKey::from_slice(&[7; 32]).unwrap();
+//! let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &key);
+//!
+//! let signature = sign::sign_message(b"I own this node", &key);
+//! assert_eq!(sign::recover_signer(b"I own this node", &signature).unwrap(), node_id);
+//! assert!(sign::verify_message(b"I own this node", &signature, &node_id));
+//! ```
 
+use bitcoin::hashes::{Hash, sha256d};
+use bitcoin::secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use bitcoin::secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+use crate::Error;
+use crate::util::base32::Alphabet;
+
+const SIGNED_MSG_PREFIX: &[u8] = b"Lightning Signed Message:";
+
+fn message_hash(msg: &[u8]) -> Message {
+    let hash = sha256d::Hash::hash(&[SIGNED_MSG_PREFIX, msg].concat());
+    Message::from_digest(hash.to_byte_array())
+}
+
+/// Sign `msg` with `key`, returning the zbase32 signature `checkmessage` accepts.
+pub fn sign_message(msg: &[u8], key: &SecretKey) -> String {
+    let sig = Secp256k1::signing_only().sign_ecdsa_recoverable(&message_hash(msg), key);
+    let (recid, compact) = sig.serialize_compact();
+    let mut bytes = Vec::with_capacity(65);
+    bytes.push(recid.to_i32() as u8 + 31);
+    bytes.extend_from_slice(&compact);
+    Alphabet::ZBase32.encode(&bytes)
+}
+
+/// The node id that signed `msg` with `signature`. Fails with `Error::InvalidSignature` if
+/// `signature` isn't one at all; a signature of another message recovers some other key.
+pub fn recover_signer(msg: &[u8], signature: &str) -> Result<PublicKey, Error> {
+    let bytes = Alphabet::ZBase32
+        .decode(signature)
+        .map_err(|()| Error::InvalidSignature)?;
+    let [header, compact @ ..] = &bytes[..] else {
+        return Err(Error::InvalidSignature);
+    };
+    let sig = header
+        .checked_sub(31)
+        .ok_or(Error::InvalidSignature)
+        .and_then(|recid| RecoveryId::from_i32(recid.into()).map_err(|_| Error::InvalidSignature))
+        .and_then(|recid| {
+            RecoverableSignature::from_compact(compact, recid).map_err(|_| Error::InvalidSignature)
+        })?;
+    Secp256k1::verification_only()
+        .recover_ecdsa(&message_hash(msg), &sig)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+/// Whether `node_id` signed `msg` with `signature`.
+pub fn verify_message(msg: &[u8], signature: &str, node_id: &PublicKey) -> bool {
+    recover_signer(msg, signature).is_ok_and(|signer| signer == *node_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn signs_like_the_nodes_do() {
+        let mut one = [0; 32];
+        one[31] = 1;
+        let key = SecretKey::from_slice(&one).unwrap();
+        let sig = sign_message(b"test message", &key);
+        assert_eq!(
+            sig,
+            "d9tibmnic9t5y41hg7hkakdcra94akas9ku3rmmj4ag9mritc8ok4p5qzefs78c9pqfhpuftqqzhydbdwfg7u6w6wdxcqpqn4sj4e73e"
+        );
+        let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &key);
+        assert_eq!(recover_signer(b"test message", &sig).unwrap(), node_id);
+        assert!(verify_message(b"test message", &sig, &node_id));
+        assert!(!verify_message(b"other message", &sig, &node_id));
+    }
+
+    #[test]
+    fn verifies_signatures_from_other_implementations() {
+        // made with LND's signmessage
+        let node_id = PublicKey::from_str(
+            "02b80cabdf82638aac86948e4c06e82064f547768dcef977677b9ea931ea75bab5",
+        )
+        .unwrap();
+        assert!(verify_message(
+            b"is this compatible?",
+            "rbgfioj114mh48d8egqx8o9qxqw4fmhe8jbeeabdioxnjk8z3t1ma1hu1fiswpakgucwwzwo6ofycffbsqusqdimugbh41n1g698hr9t",
+            &node_id,
+        ));
+    }
+
+    #[test]
+    fn garbage_is_not_a_signature() {
+        for sig in ["", "yy", "not zbase32!", &"y".repeat(104)] {
+            assert!(matches!(
+                recover_signer(b"msg", sig),
+                Err(Error::InvalidSignature)
+            ));
+        }
+    }
+}
@@ -1,12 +1,33 @@
+//! BOLT 7 node addresses.
+//!
+//! [`SocketAddress`] mirrors the address descriptors of `node_announcement` (IPv4, IPv6, Tor
+//! onion services and DNS hostnames) and parses the usual human-readable forms:
+//!
+//! ```
+//! use lnsocket::socket_addr::SocketAddress;
+//!
+//! let addr: SocketAddress = "node.example.com".parse().unwrap();
+//! assert_eq!(addr.to_string(), "node.example.com:9735");
+//! assert!(matches!("[::1]:9736".parse(), Ok(SocketAddress::TcpIpV6 { port: 9736, .. })));
+//! ```
+
 use crate::ln::msgs::DecodeError;
 use crate::util::{
     base32,
-    ser::{Hostname, Readable, Writeable, Writer},
+    ser::{Readable, Writeable, Writer},
 };
 use std::fmt::Display;
 use std::io::{self, Read};
 use std::str::FromStr;
 
+pub use crate::util::ser::Hostname;
+
+/// The port assumed when an address doesn't specify one.
+pub const DEFAULT_PORT: u16 = 9735;
+
+/// The only onion service version Tor still supports.
+const ONION_V3_VERSION: u8 = 3;
+
 /// An address which can be used to connect to a remote peer.
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub enum SocketAddress {
@@ -158,6 +179,8 @@ pub enum SocketAddressParseError {
     InvalidPort,
     /// Invalid onion v3 address
     InvalidOnionV3,
+    /// Invalid DNS hostname, e.g. an empty or overlong label
+    InvalidHostname,
 }
 
 impl std::fmt::Display for SocketAddressParseError {
@@ -173,6 +196,7 @@ impl std::fmt::Display for SocketAddressParseError {
             ),
             SocketAddressParseError::InvalidPort => write!(f, "Invalid port"),
             SocketAddressParseError::InvalidOnionV3 => write!(f, "Invalid onion v3 address"),
+            SocketAddressParseError::InvalidHostname => write!(f, "Invalid hostname"),
         }
     }
 }
@@ -233,43 +257,6 @@ impl std::net::ToSocketAddrs for SocketAddress {
     }
 }
 
-/*
-/// Parses an OnionV3 host and port into a [`SocketAddress::OnionV3`].
-///
-/// The host part must end with ".onion".
-pub fn parse_onion_address(
-    host: &str,
-    port: u16,
-) -> Result<SocketAddress, SocketAddressParseError> {
-    if host.ends_with(".onion") {
-        let domain = &host[..host.len() - ".onion".len()];
-        if domain.len() != 56 {
-            return Err(SocketAddressParseError::InvalidOnionV3);
-        }
-        let onion = base32::Alphabet::RFC4648 { padding: false }
-            .decode(&domain)
-            .map_err(|_| SocketAddressParseError::InvalidOnionV3)?;
-        if onion.len() != 35 {
-            return Err(SocketAddressParseError::InvalidOnionV3);
-        }
-        let version = onion[0];
-        let first_checksum_flag = onion[1];
-        let second_checksum_flag = onion[2];
-        let mut ed25519_pubkey = [0; 32];
-        ed25519_pubkey.copy_from_slice(&onion[3..35]);
-        let checksum = u16::from_be_bytes([first_checksum_flag, second_checksum_flag]);
-        return Ok(SocketAddress::OnionV3 {
-            ed25519_pubkey,
-            checksum,
-            version,
-            port,
-        });
-    } else {
-        return Err(SocketAddressParseError::InvalidInput);
-    }
-}
-*/
-
 impl Display for SocketAddress {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
@@ -306,11 +293,12 @@ impl Display for SocketAddress {
                 version,
                 port,
             } => {
-                let [first_checksum_flag, second_checksum_flag] = checksum.to_be_bytes();
-                let mut addr = vec![*version, first_checksum_flag, second_checksum_flag];
-                addr.extend_from_slice(ed25519_pubkey);
+                // onion v3 hostnames are base32(pubkey || checksum || version)
+                let mut addr = ed25519_pubkey.to_vec();
+                addr.extend_from_slice(&checksum.to_be_bytes());
+                addr.push(*version);
                 let onion = base32::Alphabet::RFC4648 { padding: false }.encode(&addr);
-                write!(f, "{}.onion:{}", onion, port)?
+                write!(f, "{}.onion:{}", onion.to_lowercase(), port)?
             }
             SocketAddress::Hostname { hostname, port } => write!(f, "{}:{}", hostname, port)?,
         }
@@ -321,30 +309,72 @@ impl Display for SocketAddress {
 impl FromStr for SocketAddress {
     type Err = SocketAddressParseError;
 
+    /// Parses `<ipv4>[:port]`, `[<ipv6>][:port]`, a bare `<ipv6>`, `<onion v3>.onion[:port]`
+    /// or `<hostname>[:port]`. The port defaults to [`DEFAULT_PORT`].
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match std::net::SocketAddr::from_str(s) {
-            Ok(addr) => Ok(addr.into()),
-            Err(_) => {
-                let trimmed_input = match s.rfind(":") {
-                    Some(pos) => pos,
-                    None => return Err(SocketAddressParseError::InvalidInput),
-                };
-                let host = &s[..trimmed_input];
-                let port: u16 = s[trimmed_input + 1..]
-                    .parse()
-                    .map_err(|_| SocketAddressParseError::InvalidPort)?;
-                if host.ends_with(".onion") {
-                    return parse_onion_address(host, port);
-                };
-                if let Ok(hostname) = Hostname::try_from(s[..trimmed_input].to_string()) {
-                    return Ok(SocketAddress::Hostname { hostname, port });
-                };
-                Err(SocketAddressParseError::SocketAddrParse)
-            }
+        Self::parse_with_default_port(s, DEFAULT_PORT)
+    }
+}
+
+impl SocketAddress {
+    /// Like parsing with [`FromStr`], with `default_port` for addresses without one, e.g.
+    /// [`network::default_port`](crate::network::default_port) for nodes off mainnet.
+    pub fn parse_with_default_port(
+        s: &str,
+        default_port: u16,
+    ) -> Result<Self, SocketAddressParseError> {
+        if let Ok(addr) = std::net::SocketAddr::from_str(s) {
+            return Ok(addr.into());
+        }
+        // IP addresses without a port; a bare IPv6 address is ambiguous with host:port
+        let unbracketed = s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(s);
+        if let Ok(ip) = std::net::IpAddr::from_str(unbracketed) {
+            return Ok(std::net::SocketAddr::new(ip, default_port).into());
+        }
+        if s.starts_with('[') {
+            return Err(SocketAddressParseError::SocketAddrParse);
+        }
+
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| SocketAddressParseError::InvalidPort)?,
+            ),
+            None => (s, default_port),
+        };
+        if host.is_empty() {
+            return Err(SocketAddressParseError::InvalidInput);
+        }
+        if host.ends_with(".onion") {
+            return parse_onion_address(host, port);
         }
+        if !is_valid_dns_name(host) {
+            return Err(SocketAddressParseError::InvalidHostname);
+        }
+        let hostname = Hostname::try_from(host.to_string())
+            .map_err(|_| SocketAddressParseError::InvalidHostname)?;
+        Ok(SocketAddress::Hostname { hostname, port })
     }
 }
 
+/// Stricter than [`Hostname`], which accepts anything made of valid characters: labels must
+/// be 1-63 characters and may not start or end with a hyphen.
+fn is_valid_dns_name(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    host.len() <= 253
+        && Hostname::str_is_valid_hostname(host)
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+        })
+}
+
 /// Parses an OnionV3 host and port into a [`SocketAddress::OnionV3`].
 ///
 /// The host part must end with ".onion".
@@ -364,15 +394,14 @@ pub fn parse_onion_address(
         let onion = base32::Alphabet::RFC4648 { padding: false }
             .decode(domain)
             .map_err(|_| SocketAddressParseError::InvalidOnionV3)?;
-        if onion.len() != 35 {
+        if onion.len() != 35 || onion[34] != ONION_V3_VERSION {
             return Err(SocketAddressParseError::InvalidOnionV3);
         }
-        let version = onion[0];
-        let first_checksum_flag = onion[1];
-        let second_checksum_flag = onion[2];
+        // pubkey || checksum || version
         let mut ed25519_pubkey = [0; 32];
-        ed25519_pubkey.copy_from_slice(&onion[3..35]);
-        let checksum = u16::from_be_bytes([first_checksum_flag, second_checksum_flag]);
+        ed25519_pubkey.copy_from_slice(&onion[..32]);
+        let checksum = u16::from_be_bytes([onion[32], onion[33]]);
+        let version = onion[34];
         Ok(SocketAddress::OnionV3 {
             ed25519_pubkey,
             checksum,
@@ -383,3 +412,84 @@ pub fn parse_onion_address(
         Err(SocketAddressParseError::InvalidInput)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONION: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+
+    #[test]
+    fn ports_default_to_9735() {
+        let parse = |s: &str| s.parse::<SocketAddress>().unwrap().to_string();
+        assert_eq!(parse("127.0.0.1"), "127.0.0.1:9735");
+        assert_eq!(parse("127.0.0.1:9736"), "127.0.0.1:9736");
+        assert_eq!(parse("node.example.com"), "node.example.com:9735");
+        assert_eq!(parse("node.example.com:1"), "node.example.com:1");
+        assert_eq!(parse(ONION), format!("{ONION}:9735"));
+        for v6 in ["::1", "[::1]"] {
+            assert!(matches!(
+                v6.parse(),
+                Ok(SocketAddress::TcpIpV6 { port: 9735, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn onion_v3_roundtrips() {
+        let addr: SocketAddress = format!("{ONION}:9735").parse().unwrap();
+        let SocketAddress::OnionV3 { version, .. } = addr else {
+            panic!("expected onion v3, got {addr:?}");
+        };
+        assert_eq!(version, 3);
+        assert!(addr.is_tor());
+        assert_eq!(addr.to_string(), format!("{ONION}:9735"));
+
+        // v2 addresses and corrupted version bytes are rejected
+        assert_eq!(
+            "expyuzz4wqqyqhjn.onion:80".parse::<SocketAddress>(),
+            Err(SocketAddressParseError::InvalidOnionV3)
+        );
+        let bad_version = ONION.replace("wid.onion", "wia.onion");
+        assert_eq!(
+            bad_version.parse::<SocketAddress>(),
+            Err(SocketAddressParseError::InvalidOnionV3)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_hostnames_and_ports() {
+        for (input, err) in [
+            ("", SocketAddressParseError::InvalidInput),
+            (":9735", SocketAddressParseError::InvalidInput),
+            (
+                "node.example.com:http",
+                SocketAddressParseError::InvalidPort,
+            ),
+            (
+                "node.example.com:70000",
+                SocketAddressParseError::InvalidPort,
+            ),
+            (
+                "node..example.com",
+                SocketAddressParseError::InvalidHostname,
+            ),
+            (
+                "-node.example.com",
+                SocketAddressParseError::InvalidHostname,
+            ),
+            (
+                "node$.example.com",
+                SocketAddressParseError::InvalidHostname,
+            ),
+            ("[not-an-ip]:9735", SocketAddressParseError::SocketAddrParse),
+        ] {
+            assert_eq!(input.parse::<SocketAddress>(), Err(err), "{input:?}");
+        }
+        assert!(
+            format!("{}.com", "a".repeat(64))
+                .parse::<SocketAddress>()
+                .is_err()
+        );
+    }
+}
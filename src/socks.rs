@@ -0,0 +1,115 @@
+//! Just enough of a SOCKS5 client (RFC 1928) to reach onion addresses through Tor.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::Error;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CONNECT: u8 = 1;
+const ATYP_V4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_V6: u8 = 4;
+
+/// Ask the proxy behind `stream` to connect to `host:port`, leaving the stream connected to
+/// it. The host name goes to the proxy as is, so Tor resolves it.
+pub(crate) async fn connect(stream: &mut TcpStream, host: &str, port: u16) -> Result<(), Error> {
+    stream.write_all(&[VERSION, 1, NO_AUTH]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [VERSION, NO_AUTH] {
+        return Err(Error::Proxy(
+            "proxy requires authentication we don't support".to_string(),
+        ));
+    }
+
+    let host = host.as_bytes();
+    let len = u8::try_from(host.len())
+        .map_err(|_| Error::Proxy("host name too long for SOCKS5".to_string()))?;
+    let mut request = vec![VERSION, CONNECT, 0, ATYP_DOMAIN, len];
+    request.extend_from_slice(host);
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(Error::Proxy(reply_error(reply[1])));
+    }
+    // skip the address the proxy bound, and its port
+    let skip = match reply[3] {
+        ATYP_V4 => 4,
+        ATYP_V6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        other => return Err(Error::Proxy(format!("unknown address type {other}"))),
+    };
+    stream.read_exact(&mut vec![0; skip + 2]).await?;
+    Ok(())
+}
+
+fn reply_error(code: u8) -> String {
+    let reason = match code {
+        1 => "general failure",
+        2 => "connection not allowed",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    };
+    format!("proxy could not connect: {reason} ({code})")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A proxy that accepts one CONNECT and answers it with `rep`, returning what it was asked
+    /// for.
+    async fn fake_proxy(rep: u8) -> (std::net::SocketAddr, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[VERSION, NO_AUTH]).await.unwrap();
+            let mut head = [0u8; 5];
+            stream.read_exact(&mut head).await.unwrap();
+            let mut rest = vec![0u8; head[4] as usize + 2];
+            stream.read_exact(&mut rest).await.unwrap();
+            stream
+                .write_all(&[VERSION, rep, 0, ATYP_V4, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            rest
+        });
+        (addr, task)
+    }
+
+    #[tokio::test]
+    async fn connects_through_the_proxy() {
+        let (proxy, asked) = fake_proxy(0).await;
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        connect(&mut stream, "example.onion", 9735).await.unwrap();
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+
+        let mut expected = b"example.onion".to_vec();
+        expected.extend_from_slice(&9735u16.to_be_bytes());
+        assert_eq!(asked.await.unwrap(), expected);
+
+        let (proxy, _) = fake_proxy(4).await;
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        assert!(matches!(
+            connect(&mut stream, "example.onion", 9735).await,
+            Err(Error::Proxy(msg)) if msg.contains("host unreachable")
+        ));
+    }
+}
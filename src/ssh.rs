@@ -0,0 +1,453 @@
+//! Dialing through an SSH jump host.
+//!
+//! Operators often expose their node only to an SSH bastion, not to the internet. A
+//! [`Dialer`](crate::dial::Dialer) with an [`SshJump`] logs in to the bastion and asks it for
+//! a `direct-tcpip` channel to the node, the same thing `ssh -J` or `ssh -W` does, so no port
+//! forward has to be set up by hand:
+//!
+//! ```no_run
+//! use lnsocket::dial::Dialer;
+//! use lnsocket::ssh::SshJump;
+//! # async fn ex(key: bitcoin::secp256k1::SecretKey, pk: bitcoin::secp256k1::PublicKey) -> Result<(), lnsocket::Error> {
+//! let jump = SshJump::new("bastion.example.com", "operator").with_key_file("/home/me/.ssh/id_ed25519", None);
+//! let dialer = Dialer::new().with_ssh_jump(jump);
+//! let sock = dialer.connect_and_init(key, pk, "localhost:9735").await?;
+//! # Ok(()) }
+//! ```
+//!
+//! Every address the dialer is given is handed to the bastion as is and resolved there, so
+//! `localhost` is the bastion itself and names only its network knows work too. The
+//! [`DialPolicy`](crate::dial::DialPolicy) therefore sees no resolved addresses, as for onion
+//! addresses, and DNS rebinding protection doesn't apply. Reconnects log in again.
+//!
+//! The bastion's host key is checked against `~/.ssh/known_hosts`, another file given with
+//! [`SshJump::with_known_hosts`], or the keys pinned with [`SshJump::with_host_key`].
+//! Authentication is with the SSH agent unless a key file or password is given.
+//!
+//! The SSH session is only a way to the node: the Noise handshake inside it still
+//! authenticates the node and encrypts everything. A socket dialed this way can't
+//! [`export_session`](crate::LNSocket::export_session).
+//!
+//! Logging in blocks a thread of tokio's blocking pool, which a dropped or cancelled connect
+//! can't stop. It gives up with `Error::Io(TimedOut)` at the connect's deadline, or after
+//! [`DEFAULT_LOGIN_TIMEOUT`] if there is none, so that a bastion that stalls holds the thread
+//! no longer.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use bitcoin::hashes::{Hash, sha256};
+use ssh2::{CheckResult, HashType, KnownHostFileKind, Session};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::Error;
+
+/// How much of the node's traffic is buffered between the SSH channel and the socket.
+const BUFFER: usize = 64 * 1024;
+
+/// How long logging in to the bastion may take when the connect has no deadline.
+pub const DEFAULT_LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// libssh2's `LIBSSH2_ERROR_TIMEOUT`, what a blocking call fails with past the session's
+/// timeout.
+const ERROR_TIMEOUT: i32 = -9;
+
+/// How to log in to the bastion.
+#[derive(Clone)]
+enum Auth {
+    Agent,
+    KeyFile {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+    Password(String),
+}
+
+/// An SSH bastion to dial through, see the [module docs](self).
+#[derive(Clone)]
+pub struct SshJump {
+    host: String,
+    port: u16,
+    user: String,
+    auth: Auth,
+    known_hosts: Option<PathBuf>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl fmt::Debug for SshJump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let auth = match &self.auth {
+            Auth::Agent => "agent",
+            Auth::KeyFile { .. } => "key file",
+            Auth::Password(_) => "password",
+        };
+        f.debug_struct("SshJump")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("auth", &auth)
+            .field("known_hosts", &self.known_hosts)
+            .field("pins", &self.pins.len())
+            .finish()
+    }
+}
+
+impl SshJump {
+    /// Log in to `host` as `user` with the SSH agent. `host` may end in `:port`, the port is
+    /// 22 otherwise.
+    pub fn new(host: &str, user: &str) -> Self {
+        let (host, port) = match host.rsplit_once(':') {
+            Some((name, port)) if !name.contains(':') || name.ends_with(']') => {
+                match port.parse() {
+                    Ok(port) => (name.trim_start_matches('[').trim_end_matches(']'), port),
+                    Err(_) => (host, 22),
+                }
+            }
+            _ => (host, 22),
+        };
+        Self {
+            host: host.to_string(),
+            port,
+            user: user.to_string(),
+            auth: Auth::Agent,
+            known_hosts: None,
+            pins: Vec::new(),
+        }
+    }
+
+    /// Log in with the private key at `path` instead of the agent, decrypting it with
+    /// `passphrase` if it is encrypted.
+    pub fn with_key_file(mut self, path: impl Into<PathBuf>, passphrase: Option<&str>) -> Self {
+        self.auth = Auth::KeyFile {
+            path: path.into(),
+            passphrase: passphrase.map(str::to_string),
+        };
+        self
+    }
+
+    /// Log in with `password` instead of the agent.
+    pub fn with_password(mut self, password: &str) -> Self {
+        self.auth = Auth::Password(password.to_string());
+        self
+    }
+
+    /// Check the bastion's host key against the OpenSSH `known_hosts` file at `path` instead
+    /// of `~/.ssh/known_hosts`.
+    pub fn with_known_hosts(mut self, path: impl Into<PathBuf>) -> Self {
+        self.known_hosts = Some(path.into());
+        self
+    }
+
+    /// Accept a host key whose SHA-256 hash is `sha256`, the bytes of the fingerprint
+    /// `ssh-keygen -lf` prints in base64. Once a key is pinned, only pinned keys are accepted
+    /// and no `known_hosts` file is read.
+    pub fn with_host_key(mut self, sha256: [u8; 32]) -> Self {
+        self.pins.push(sha256);
+        self
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Log in over `stream`, a TCP connection to the bastion, and open a channel to
+    /// `host:port` from there, by `deadline` if there is one. The returned stream carries the
+    /// channel's bytes.
+    pub(crate) async fn open(
+        &self,
+        stream: tokio::net::TcpStream,
+        host: &str,
+        port: u16,
+        deadline: Option<Instant>,
+    ) -> Result<DuplexStream, Error> {
+        let timeout = deadline.map_or(DEFAULT_LOGIN_TIMEOUT, |deadline| {
+            deadline.saturating_duration_since(Instant::now())
+        });
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let (jump, host) = (self.clone(), host.to_string());
+        let (session, channel, stream) =
+            tokio::task::spawn_blocking(move || jump.login(stream, &host, port, timeout))
+                .await
+                .map_err(|_| Error::Io(io::ErrorKind::Interrupted))??;
+
+        // libssh2 reads and writes the socket itself, we only wait for it to be ready
+        session.set_blocking(false);
+        stream.set_nonblocking(true)?;
+        let ready = AsyncFd::new(stream)?;
+        let (ours, theirs) = tokio::io::duplex(BUFFER);
+        tokio::spawn(async move {
+            if let Err(err) = pump(&session, channel, &ready, theirs).await {
+                tracing::debug!("ssh channel closed: {err}");
+            }
+        });
+        Ok(ours)
+    }
+
+    fn login(
+        &self,
+        stream: TcpStream,
+        host: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<(Session, ssh2::Channel, TcpStream), Error> {
+        let mut session = Session::new().map_err(ssh_error)?;
+        session.set_tcp_stream(stream.try_clone()?);
+        // in milliseconds, where 0 is none
+        session.set_timeout(timeout.as_millis().clamp(1, u32::MAX.into()) as u32);
+        session.handshake().map_err(ssh_error)?;
+        self.check_host_key(&session)?;
+
+        match &self.auth {
+            Auth::Agent => session.userauth_agent(&self.user),
+            Auth::KeyFile { path, passphrase } => {
+                session.userauth_pubkey_file(&self.user, None, path, passphrase.as_deref())
+            }
+            Auth::Password(password) => session.userauth_password(&self.user, password),
+        }
+        .map_err(ssh_error)?;
+        if !session.authenticated() {
+            return Err(Error::Ssh(format!("{} was not let in", self.user)));
+        }
+
+        let channel =
+            session
+                .channel_direct_tcpip(host, port, None)
+                .map_err(|err| match ssh_error(err) {
+                    Error::Ssh(err) => {
+                        Error::Ssh(format!("opening a channel to {host}:{port}: {err}"))
+                    }
+                    err => err,
+                })?;
+        Ok((session, channel, stream))
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result<(), Error> {
+        if !self.pins.is_empty() {
+            let hash = session
+                .host_key_hash(HashType::Sha256)
+                .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                .ok_or_else(|| Error::Ssh("the bastion sent no host key".to_string()))?;
+            return match self.pins.contains(&hash) {
+                true => Ok(()),
+                false => Err(Error::Ssh(format!(
+                    "host key of {} is not pinned, its SHA-256 is {}",
+                    self.host,
+                    sha256::Hash::from_byte_array(hash)
+                ))),
+            };
+        }
+
+        let path = match &self.known_hosts {
+            Some(path) => path.clone(),
+            None => std::env::var_os("HOME")
+                .map(|home| Path::new(&home).join(".ssh/known_hosts"))
+                .ok_or_else(|| Error::Ssh("no known_hosts file, HOME is not set".to_string()))?,
+        };
+        let mut known = session.known_hosts().map_err(ssh_error)?;
+        known
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .map_err(|err| Error::Ssh(format!("reading {}: {err}", path.display())))?;
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| Error::Ssh("the bastion sent no host key".to_string()))?;
+        match known.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(Error::Ssh(format!(
+                "host key of {} does not match {}",
+                self.host,
+                path.display()
+            ))),
+            CheckResult::NotFound => Err(Error::Ssh(format!(
+                "{} is not in {}",
+                self.host,
+                path.display()
+            ))),
+            CheckResult::Failure => Err(Error::Ssh("checking the host key failed".to_string())),
+        }
+    }
+}
+
+fn ssh_error(err: ssh2::Error) -> Error {
+    if err.code() == ssh2::ErrorCode::Session(ERROR_TIMEOUT) {
+        return Error::Io(io::ErrorKind::TimedOut);
+    }
+    Error::Ssh(err.to_string())
+}
+
+/// Moves bytes between the non-blocking `channel` and `socket`, the socket's end of the
+/// duplex, until either side closes.
+async fn pump(
+    session: &Session,
+    mut channel: ssh2::Channel,
+    ready: &AsyncFd<TcpStream>,
+    socket: DuplexStream,
+) -> io::Result<()> {
+    let (mut from_socket, mut to_socket) = tokio::io::split(socket);
+    let mut inbound = vec![0; BUFFER];
+    let mut outbound: Vec<u8> = Vec::with_capacity(BUFFER);
+    let mut socket_closed = false;
+
+    loop {
+        let mut progress = false;
+        match channel.read(&mut inbound) {
+            Ok(0) if channel.eof() => break,
+            Ok(0) => {}
+            Ok(n) => {
+                to_socket.write_all(&inbound[..n]).await?;
+                progress = true;
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        if !outbound.is_empty() {
+            match channel.write(&outbound) {
+                Ok(n) => {
+                    outbound.drain(..n);
+                    progress = true;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if socket_closed && outbound.is_empty() {
+            break;
+        }
+        if progress {
+            continue;
+        }
+
+        // both sides would block: wait for the socket, or for libssh2's sake the bastion
+        let directions = session.block_directions();
+        tokio::select! {
+            read = from_socket.read_buf(&mut outbound), if outbound.is_empty() && !socket_closed => {
+                socket_closed = read? == 0;
+            }
+            guard = ready.readable() => guard?.clear_ready(),
+            guard = ready.writable(), if matches!(
+                directions,
+                ssh2::BlockDirections::Outbound | ssh2::BlockDirections::Both
+            ) => guard?.clear_ready(),
+        }
+    }
+
+    // a channel we can't close cleanly dies with the session anyway
+    let _ = channel.send_eof();
+    let _ = channel.close();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dial::Dialer;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn new_takes_an_optional_port() {
+        let jump = SshJump::new("bastion.example.com", "me");
+        assert_eq!((jump.host(), jump.port()), ("bastion.example.com", 22));
+        let jump = SshJump::new("bastion.example.com:2222", "me");
+        assert_eq!((jump.host(), jump.port()), ("bastion.example.com", 2222));
+        let jump = SshJump::new("[::1]:2222", "me");
+        assert_eq!((jump.host(), jump.port()), ("::1", 2222));
+        let jump = SshJump::new("::1", "me");
+        assert_eq!((jump.host(), jump.port()), ("::1", 22));
+    }
+
+    #[test]
+    fn debug_leaves_out_secrets() {
+        let jump = SshJump::new("bastion", "me").with_password("hunter2");
+        let debug = format!("{jump:?}");
+        assert!(debug.contains("password"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[tokio::test]
+    async fn address_checks_see_literal_ips_and_refuse_hostnames() {
+        use crate::dial::NoPrivateAddrs;
+
+        let pk = PublicKey::from_secret_key(
+            &Secp256k1::new(),
+            &SecretKey::from_slice(&[1; 32]).unwrap(),
+        );
+        let jump = || SshJump::new("bastion.example.com", "me");
+        let dialer = Dialer::new()
+            .with_ssh_jump(jump())
+            .with_policy(NoPrivateAddrs);
+        let res = dialer.resolve(&pk, "10.0.0.1:9735").await;
+        assert!(matches!(res, Err(Error::DialDenied(_))), "{:?}", res.err());
+        assert!(dialer.resolve(&pk, "1.1.1.1:9735").await.is_ok());
+        let res = dialer.resolve(&pk, "node.example.com:9735").await;
+        assert!(matches!(res, Err(Error::Ssh(_))), "{:?}", res.err());
+
+        let dialer = Dialer::new()
+            .with_ssh_jump(jump())
+            .with_dns_rebinding_protection(true);
+        let res = dialer.resolve(&pk, "node.example.com:9735").await;
+        assert!(matches!(res, Err(Error::Ssh(_))), "{:?}", res.err());
+        // without address checks the bastion resolves it
+        let dialer = Dialer::new().with_ssh_jump(jump());
+        assert!(dialer.resolve(&pk, "node.example.com:9735").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_bastion_that_isnt_ssh_fails_the_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let jump = SshJump::new(&addr.to_string(), "me").with_host_key([0; 32]);
+        let dialer = Dialer::new().with_ssh_jump(jump);
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let res = dialer
+            .connect(
+                key,
+                PublicKey::from_secret_key(&secp, &key),
+                "localhost:9735",
+            )
+            .await;
+        assert!(matches!(res, Err(Error::Ssh(_))), "{:?}", res.err());
+    }
+
+    #[tokio::test]
+    async fn a_stalled_bastion_times_out_the_login() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let jump = SshJump::new(&addr.to_string(), "me").with_host_key([0; 32]);
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let res = tokio::time::timeout(
+            Duration::from_secs(10),
+            jump.open(stream, "localhost", 9735, Some(deadline)),
+        )
+        .await
+        .expect("the login gives up by itself");
+        assert!(
+            matches!(res, Err(Error::Io(io::ErrorKind::TimedOut))),
+            "{:?}",
+            res.err()
+        );
+    }
+}
@@ -0,0 +1,607 @@
+//! Per-connection wire statistics.
+//!
+//! [`WireStats`] is a lightweight histogram of the messages flowing over an
+//! [`LNSocket`](crate::LNSocket): for each direction it tracks a count and byte total per
+//! message type id, plus a coarse size histogram. It is updated on every read and write, and
+//! a snapshot can be taken at any time via [`LNSocket::stats`](crate::LNSocket::stats).
+//!
+//! Sizes are plaintext message sizes (type + payload), not including the BOLT 8 length
+//! header and MACs.
+//!
+//! ## Quotas
+//!
+//! The same accounting can cap a connection, for metered mobile users or tenants of a hosted
+//! service. A [`Quota`] set with [`LNSocket::set_quota`](crate::LNSocket::set_quota) counts
+//! the bytes one direction carries per window, BOLT 8 overhead included, and reports a
+//! [`QuotaEvent`] to the hook set with [`LNSocket::on_quota`](crate::LNSocket::on_quota) when
+//! they reach its warning threshold and its limit:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use lnsocket::capture::Direction;
+//! use lnsocket::stats::{Quota, QuotaEvent};
+//! # #[cfg(feature = "tokio")]
+//! # fn ex(sock: &mut lnsocket::LNSocket) {
+//! sock.set_quota(
+//!     Direction::Inbound,
+//!     Some(Quota {
+//!         warn_percent: Some(80),
+//!         enforce: true,
+//!         ..Quota::new(50 << 20, Duration::from_secs(24 * 3600))
+//!     }),
+//! );
+//! sock.on_quota(|event| match event {
+//!     QuotaEvent::Warning { used, .. } => println!("{used} bytes in, nearly at the cap"),
+//!     QuotaEvent::Exceeded { .. } => println!("daily cap reached"),
+//!     _ => {}
+//! });
+//! # }
+//! ```
+//!
+//! An enforced quota also refuses traffic past the limit until the window is over: reads
+//! fail with [`Error::QuotaExceeded`](crate::Error::QuotaExceeded) without touching the wire,
+//! as do sends queued once the limit is reached. The message that crosses the limit still
+//! goes through. Quotas are per connection; a reconnect starts from zero.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use bitcoin::secp256k1::PublicKey;
+
+use crate::capture::Direction;
+use crate::clock::{Instant, SharedClock};
+use crate::ln::msgs;
+use crate::transport::{LENGTH_HEADER_SIZE, MAC_SIZE};
+
+/// Upper bounds (inclusive) of the size histogram buckets. Anything larger lands in the
+/// last bucket.
+pub const SIZE_BUCKETS: [usize; 6] = [64, 256, 1024, 4096, 16384, 65535];
+
+/// At most this many peer warnings are logged per [`WARNING_LOG_WINDOW`]; the rest are only
+/// counted, see [`WarningStats::suppressed`].
+pub const WARNING_LOG_BURST: u32 = 5;
+
+/// The window [`WARNING_LOG_BURST`] applies to.
+pub const WARNING_LOG_WINDOW: Duration = Duration::from_secs(60);
+
+/// Peer warnings longer than this are truncated in log lines.
+const WARNING_LOG_MAX_CHARS: usize = 256;
+
+/// Count and byte total for a single message type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TypeStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Statistics for one direction (inbound or outbound) of a connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DirectionStats {
+    /// Message count and bytes keyed by message type id.
+    pub by_type: BTreeMap<u16, TypeStats>,
+    /// Message counts per size bucket, see [`SIZE_BUCKETS`].
+    pub size_buckets: [u64; SIZE_BUCKETS.len()],
+    /// Total messages seen.
+    pub messages: u64,
+    /// Total plaintext bytes seen.
+    pub bytes: u64,
+}
+
+impl DirectionStats {
+    pub(crate) fn record(&mut self, type_id: u16, len: usize) {
+        let entry = self.by_type.entry(type_id).or_default();
+        entry.count += 1;
+        entry.bytes += len as u64;
+
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|max| len <= *max)
+            .unwrap_or(SIZE_BUCKETS.len() - 1);
+        self.size_buckets[bucket] += 1;
+
+        self.messages += 1;
+        self.bytes += len as u64;
+    }
+
+    /// The message types with the highest byte totals, largest first.
+    pub fn top_types(&self, n: usize) -> Vec<(u16, TypeStats)> {
+        let mut types: Vec<(u16, TypeStats)> = self.by_type.iter().map(|(t, s)| (*t, *s)).collect();
+        types.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(&b.0)));
+        types.truncate(n);
+        types
+    }
+}
+
+impl fmt::Display for DirectionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} msgs/{} bytes", self.messages, self.bytes)?;
+        let top = self.top_types(3);
+        if !top.is_empty() {
+            write!(f, " [")?;
+            for (i, (type_id, stats)) in top.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: {}/{}", type_id, stats.count, stats.bytes)?;
+            }
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+}
+
+/// `warning` messages received from the peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WarningStats {
+    pub received: u64,
+    /// Warnings that were not logged because the peer exceeded [`WARNING_LOG_BURST`].
+    pub suppressed: u64,
+}
+
+/// A snapshot of the wire statistics for a connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WireStats {
+    pub inbound: DirectionStats,
+    pub outbound: DirectionStats,
+    pub warnings: WarningStats,
+}
+
+impl fmt::Display for WireStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in: {}, out: {}", self.inbound, self.outbound)
+    }
+}
+
+/// What a message costs against a [`Quota`] on top of its plaintext size: the encrypted
+/// length header and the MAC of the body.
+pub const FRAME_OVERHEAD: usize = LENGTH_HEADER_SIZE + MAC_SIZE;
+
+/// How many bytes one direction of a connection may carry per window, see the
+/// [module docs](self#quotas).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    /// The limit, in bytes per window.
+    pub bytes: u64,
+    /// Windows are back to back, the first one starting when the quota is set.
+    pub window: Duration,
+    /// Report [`QuotaEvent::Warning`] once this share of `bytes`, in percent, is used.
+    pub warn_percent: Option<u8>,
+    /// Refuse traffic past the limit until the window is over, rather than only reporting
+    /// [`QuotaEvent::Exceeded`].
+    pub enforce: bool,
+}
+
+impl Quota {
+    /// `bytes` per `window`, reported but not enforced, without a warning.
+    pub fn new(bytes: u64, window: Duration) -> Self {
+        Self {
+            bytes,
+            window,
+            warn_percent: None,
+            enforce: false,
+        }
+    }
+}
+
+/// A [`Quota`] threshold was reached, at most once of each per window and direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum QuotaEvent {
+    /// `used` bytes passed the quota's `warn_percent`.
+    Warning {
+        direction: Direction,
+        used: u64,
+        quota: Quota,
+    },
+    /// `used` bytes reached the quota's limit.
+    Exceeded {
+        direction: Direction,
+        used: u64,
+        quota: Quota,
+    },
+}
+
+/// Where a direction stands against its [`Quota`], see
+/// [`LNSocket::quota_usage`](crate::LNSocket::quota_usage).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub quota: Quota,
+    /// Bytes used in the current window.
+    pub used: u64,
+    /// Until the current window is over.
+    pub resets_in: Duration,
+}
+
+pub(crate) type QuotaHook = Arc<dyn Fn(QuotaEvent) + Send + Sync>;
+
+/// One direction's use of its quota in the current window.
+struct QuotaMeter {
+    quota: Quota,
+    window_start: Instant,
+    used: u64,
+    warned: bool,
+    exceeded: bool,
+}
+
+impl QuotaMeter {
+    fn new(quota: Quota, now: Instant) -> Self {
+        Self {
+            quota,
+            window_start: now,
+            used: 0,
+            warned: false,
+            exceeded: false,
+        }
+    }
+
+    /// Start a new window if the current one is over.
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < self.quota.window {
+            return;
+        }
+        let windows = (elapsed.as_nanos() / self.quota.window.as_nanos().max(1)) as u32;
+        self.window_start += self.quota.window.saturating_mul(windows);
+        self.used = 0;
+        self.warned = false;
+        self.exceeded = false;
+    }
+
+    fn add(&mut self, direction: Direction, bytes: usize, now: Instant) -> Option<QuotaEvent> {
+        self.roll(now);
+        self.used += bytes as u64;
+        let (used, quota) = (self.used, self.quota);
+        if used >= quota.bytes && !self.exceeded {
+            // a message past both thresholds at once only reports the limit
+            self.exceeded = true;
+            self.warned = true;
+            return Some(QuotaEvent::Exceeded {
+                direction,
+                used,
+                quota,
+            });
+        }
+        let warn_at = quota
+            .warn_percent
+            .map(|percent| quota.bytes.saturating_mul(percent.into()) / 100);
+        match warn_at {
+            Some(warn_at) if used >= warn_at && !self.warned => {
+                self.warned = true;
+                Some(QuotaEvent::Warning {
+                    direction,
+                    used,
+                    quota,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn usage(&mut self, now: Instant) -> QuotaUsage {
+        self.roll(now);
+        QuotaUsage {
+            quota: self.quota,
+            used: self.used,
+            resets_in: self
+                .quota
+                .window
+                .saturating_sub(now.saturating_duration_since(self.window_start)),
+        }
+    }
+}
+
+/// Quota events to hand to the hook once the stats are unlocked, so that the hook can look
+/// at them.
+#[must_use]
+pub(crate) struct QuotaReport(Option<QuotaHook>, Vec<QuotaEvent>);
+
+impl QuotaReport {
+    pub(crate) fn fire(self) {
+        if let Some(hook) = self.0 {
+            self.1.into_iter().for_each(|event| hook(event));
+        }
+    }
+}
+
+/// The stats of a connection, for whoever doesn't hold the socket, e.g. a
+/// [`ConnectionRegistry`](crate::registry::ConnectionRegistry). Doesn't keep the connection's
+/// stats around once it is gone.
+#[derive(Clone, Debug)]
+pub struct StatsHandle(Weak<Mutex<StatsRecorder>>);
+
+impl StatsHandle {
+    pub(crate) fn new(recorder: &Arc<Mutex<StatsRecorder>>) -> Self {
+        Self(Arc::downgrade(recorder))
+    }
+
+    /// A snapshot of the stats, or `None` once the socket and every sender of it are dropped.
+    pub fn snapshot(&self) -> Option<WireStats> {
+        Some(self.0.upgrade()?.lock().unwrap().snapshot())
+    }
+}
+
+/// Stats plus the bookkeeping for the optional periodic log line.
+pub(crate) struct StatsRecorder {
+    clock: SharedClock,
+    stats: WireStats,
+    log_interval: Option<Duration>,
+    last_log: Instant,
+    warning_window: Instant,
+    warnings_logged: u32,
+    warnings_suppressed: u64,
+    inbound_quota: Option<QuotaMeter>,
+    outbound_quota: Option<QuotaMeter>,
+    quota_hook: Option<QuotaHook>,
+    quota_events: Vec<QuotaEvent>,
+}
+
+impl StatsRecorder {
+    pub(crate) fn new(clock: SharedClock) -> Self {
+        let now = clock.now();
+        Self {
+            clock,
+            stats: WireStats::default(),
+            log_interval: None,
+            last_log: now,
+            warning_window: now,
+            warnings_logged: 0,
+            warnings_suppressed: 0,
+            inbound_quota: None,
+            outbound_quota: None,
+            quota_hook: None,
+            quota_events: Vec::new(),
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> WireStats {
+        self.stats.clone()
+    }
+
+    /// Time the windows with `clock` from now on, starting them over.
+    pub(crate) fn set_clock(&mut self, clock: SharedClock) {
+        let now = clock.now();
+        self.clock = clock;
+        self.last_log = now;
+        self.warning_window = now;
+        for meter in [&mut self.inbound_quota, &mut self.outbound_quota]
+            .into_iter()
+            .flatten()
+        {
+            meter.window_start = now;
+        }
+    }
+
+    pub(crate) fn set_log_interval(&mut self, interval: Option<Duration>) {
+        self.log_interval = interval;
+        self.last_log = self.clock.now();
+    }
+
+    pub(crate) fn record_inbound(&mut self, type_id: u16, len: usize) {
+        self.stats.inbound.record(type_id, len);
+        self.meter(Direction::Inbound, len);
+        self.maybe_log();
+    }
+
+    pub(crate) fn record_outbound(&mut self, type_id: u16, len: usize) {
+        self.stats.outbound.record(type_id, len);
+        self.meter(Direction::Outbound, len);
+        self.maybe_log();
+    }
+
+    fn quota_meter(&mut self, direction: Direction) -> &mut Option<QuotaMeter> {
+        match direction {
+            Direction::Inbound => &mut self.inbound_quota,
+            Direction::Outbound => &mut self.outbound_quota,
+        }
+    }
+
+    fn meter(&mut self, direction: Direction, len: usize) {
+        let now = self.clock.now();
+        let event = self
+            .quota_meter(direction)
+            .as_mut()
+            .and_then(|meter| meter.add(direction, len + FRAME_OVERHEAD, now));
+        self.quota_events.extend(event);
+    }
+
+    /// Replace the quota of `direction`, starting a new window.
+    pub(crate) fn set_quota(&mut self, direction: Direction, quota: Option<Quota>) {
+        let now = self.clock.now();
+        *self.quota_meter(direction) = quota.map(|quota| QuotaMeter::new(quota, now));
+    }
+
+    pub(crate) fn set_quota_hook(&mut self, hook: Option<QuotaHook>) {
+        self.quota_hook = hook;
+    }
+
+    pub(crate) fn quota_usage(&mut self, direction: Direction) -> Option<QuotaUsage> {
+        let now = self.clock.now();
+        self.quota_meter(direction)
+            .as_mut()
+            .map(|meter| meter.usage(now))
+    }
+
+    /// How long until the window is over, if `direction` has used up an enforced quota.
+    pub(crate) fn quota_blocked(&mut self, direction: Direction) -> Option<Duration> {
+        self.quota_usage(direction)
+            .filter(|usage| usage.quota.enforce && usage.used >= usage.quota.bytes)
+            .map(|usage| usage.resets_in)
+    }
+
+    /// The quota events since the last report.
+    pub(crate) fn take_quota_report(&mut self) -> QuotaReport {
+        QuotaReport(
+            self.quota_hook.clone(),
+            std::mem::take(&mut self.quota_events),
+        )
+    }
+
+    /// Count a warning from `peer` and log it, unless the peer has already used up its
+    /// [`WARNING_LOG_BURST`] for the current window. Returns whether it was logged.
+    pub(crate) fn record_warning(
+        &mut self,
+        peer: &PublicKey,
+        warning: &msgs::WarningMessage,
+    ) -> bool {
+        self.stats.warnings.received += 1;
+
+        if self.clock.since(self.warning_window) >= WARNING_LOG_WINDOW {
+            if self.warnings_suppressed > 0 {
+                tracing::warn!(
+                    %peer,
+                    "suppressed {} warnings from peer in the last {:?}",
+                    self.warnings_suppressed,
+                    WARNING_LOG_WINDOW
+                );
+            }
+            self.warning_window = self.clock.now();
+            self.warnings_logged = 0;
+            self.warnings_suppressed = 0;
+        }
+
+        if self.warnings_logged >= WARNING_LOG_BURST {
+            self.warnings_suppressed += 1;
+            self.stats.warnings.suppressed += 1;
+            return false;
+        }
+        self.warnings_logged += 1;
+
+        // peer-controlled text: truncate, and let Debug escape control characters
+        let data: String = warning.data.chars().take(WARNING_LOG_MAX_CHARS).collect();
+        tracing::warn!(%peer, channel_id = %warning.channel_id, "peer warning: {data:?}");
+        true
+    }
+
+    fn maybe_log(&mut self) {
+        let Some(interval) = self.log_interval else {
+            return;
+        };
+        if self.clock.since(self.last_log) >= interval {
+            self.last_log = self.clock.now();
+            tracing::info!("wire stats: {}", self.stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::testing::ManualClock;
+
+    #[test]
+    fn records_counts_bytes_and_buckets() {
+        let mut stats = DirectionStats::default();
+        stats.record(18, 10);
+        stats.record(18, 300);
+        stats.record(0x594b, 65535);
+
+        assert_eq!(stats.messages, 3);
+        assert_eq!(stats.bytes, 65845);
+        assert_eq!(
+            stats.by_type[&18],
+            TypeStats {
+                count: 2,
+                bytes: 310
+            }
+        );
+        assert_eq!(stats.size_buckets, [1, 0, 1, 0, 0, 1]);
+    }
+
+    #[test]
+    fn warning_logging_is_throttled() {
+        let peer = PublicKey::from_secret_key(
+            &bitcoin::secp256k1::Secp256k1::signing_only(),
+            &bitcoin::secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
+        );
+        let warning = msgs::WarningMessage {
+            channel_id: crate::ln::types::ChannelId([0; 32]),
+            data: "\x1b[2Jspam".to_string(),
+        };
+
+        let clock = ManualClock::default();
+        let mut recorder = StatsRecorder::new(SharedClock::new(clock.clone()));
+        let logged = (0..20)
+            .filter(|_| recorder.record_warning(&peer, &warning))
+            .count();
+        assert_eq!(logged, WARNING_LOG_BURST as usize);
+        assert_eq!(
+            recorder.snapshot().warnings,
+            WarningStats {
+                received: 20,
+                suppressed: 20 - WARNING_LOG_BURST as u64,
+            }
+        );
+
+        // a new window gets a new burst
+        clock.advance(WARNING_LOG_WINDOW);
+        assert!(recorder.record_warning(&peer, &warning));
+    }
+
+    #[test]
+    fn quotas_report_each_threshold_once_per_window() {
+        let clock = ManualClock::default();
+        let mut recorder = StatsRecorder::new(SharedClock::new(clock.clone()));
+        let quota = Quota {
+            warn_percent: Some(50),
+            enforce: true,
+            ..Quota::new(1000, Duration::from_secs(60))
+        };
+        recorder.set_quota(Direction::Outbound, Some(quota));
+        let events = |recorder: &mut StatsRecorder| recorder.take_quota_report().1;
+
+        // 300 bytes a message with the overhead
+        let len = 300 - FRAME_OVERHEAD;
+        recorder.record_outbound(1, len);
+        recorder.record_inbound(1, 5000);
+        assert!(events(&mut recorder).is_empty());
+        assert_eq!(recorder.quota_blocked(Direction::Outbound), None);
+
+        recorder.record_outbound(1, len);
+        recorder.record_outbound(1, len);
+        assert_eq!(
+            events(&mut recorder),
+            [QuotaEvent::Warning {
+                direction: Direction::Outbound,
+                used: 600,
+                quota
+            }]
+        );
+        recorder.record_outbound(1, len);
+        recorder.record_outbound(1, len);
+        assert_eq!(
+            events(&mut recorder),
+            [QuotaEvent::Exceeded {
+                direction: Direction::Outbound,
+                used: 1200,
+                quota
+            }]
+        );
+        assert!(recorder.quota_blocked(Direction::Outbound).is_some());
+        assert_eq!(recorder.quota_blocked(Direction::Inbound), None);
+
+        // the next window starts over
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(recorder.quota_blocked(Direction::Outbound), None);
+        let usage = recorder.quota_usage(Direction::Outbound).unwrap();
+        assert_eq!(usage.used, 0);
+        assert!(usage.resets_in <= Duration::from_secs(59));
+        recorder.record_outbound(1, 2000);
+        assert!(matches!(
+            events(&mut recorder)[..],
+            [QuotaEvent::Exceeded { used: 2034, .. }]
+        ));
+    }
+
+    #[test]
+    fn top_types_orders_by_bytes() {
+        let mut stats = DirectionStats::default();
+        stats.record(1, 10);
+        stats.record(2, 1000);
+        stats.record(3, 100);
+
+        let top: Vec<u16> = stats.top_types(2).into_iter().map(|(t, _)| t).collect();
+        assert_eq!(top, vec![2, 3]);
+    }
+}
@@ -0,0 +1,218 @@
+//! The byte stream under an [`LNSocket`](crate::LNSocket): plain TCP, TCP wrapped in TLS
+//! to a fronting proxy (see [`tls`](crate::tls)), or a channel through an SSH jump host (see
+//! [`ssh`](crate::ssh)).
+//!
+//! Like the TCP stream it wraps, it is split after the handshake into the read half the
+//! socket keeps and the write half the writer task owns.
+
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[cfg(all(feature = "ssh", unix))]
+use tokio::io::DuplexStream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+#[cfg(feature = "tls")]
+use tokio_rustls::client::TlsStream;
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(all(feature = "ssh", unix))]
+    Ssh(DuplexStream),
+}
+
+pub(crate) enum ReadHalf {
+    Tcp(OwnedReadHalf),
+    #[cfg(feature = "tls")]
+    Tls(tokio::io::ReadHalf<Box<TlsStream<TcpStream>>>),
+    #[cfg(all(feature = "ssh", unix))]
+    Ssh(tokio::io::ReadHalf<DuplexStream>),
+}
+
+pub(crate) enum WriteHalf {
+    Tcp(OwnedWriteHalf),
+    #[cfg(feature = "tls")]
+    Tls(tokio::io::WriteHalf<Box<TlsStream<TcpStream>>>),
+    #[cfg(all(feature = "ssh", unix))]
+    Ssh(tokio::io::WriteHalf<DuplexStream>),
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+impl From<OwnedWriteHalf> for WriteHalf {
+    fn from(half: OwnedWriteHalf) -> Self {
+        WriteHalf::Tcp(half)
+    }
+}
+
+impl Stream {
+    pub(crate) fn into_split(self) -> (ReadHalf, WriteHalf) {
+        match self {
+            Stream::Tcp(stream) => {
+                let (read, write) = stream.into_split();
+                (ReadHalf::Tcp(read), WriteHalf::Tcp(write))
+            }
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => {
+                let (read, write) = tokio::io::split(stream);
+                (ReadHalf::Tls(read), WriteHalf::Tls(write))
+            }
+            #[cfg(all(feature = "ssh", unix))]
+            Stream::Ssh(stream) => {
+                let (read, write) = tokio::io::split(stream);
+                (ReadHalf::Ssh(read), WriteHalf::Ssh(write))
+            }
+        }
+    }
+}
+
+impl ReadHalf {
+    /// The TCP stream back from both halves. Fails with `Unsupported` for a TLS stream or SSH
+    /// channel, whose session state can't be taken out of the process.
+    #[cfg(unix)]
+    pub(crate) fn reunite(self, write: WriteHalf) -> io::Result<TcpStream> {
+        match (self, write) {
+            (ReadHalf::Tcp(read), WriteHalf::Tcp(write)) => Ok(read
+                .reunite(write)
+                .expect("read and write halves come from the same stream")),
+            #[cfg(any(feature = "tls", feature = "ssh"))]
+            _ => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(all(feature = "ssh", unix))]
+            Stream::Ssh(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(all(feature = "ssh", unix))]
+            Stream::Ssh(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(all(feature = "ssh", unix))]
+            Stream::Ssh(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(all(feature = "ssh", unix))]
+            Stream::Ssh(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncRead for ReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ReadHalf::Tcp(half) => Pin::new(half).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            ReadHalf::Tls(half) => Pin::new(half).poll_read(cx, buf),
+            #[cfg(all(feature = "ssh", unix))]
+            ReadHalf::Ssh(half) => Pin::new(half).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for WriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            WriteHalf::Tls(half) => Pin::new(half).poll_write(cx, buf),
+            #[cfg(all(feature = "ssh", unix))]
+            WriteHalf::Ssh(half) => Pin::new(half).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "tls")]
+            WriteHalf::Tls(half) => Pin::new(half).poll_write_vectored(cx, bufs),
+            #[cfg(all(feature = "ssh", unix))]
+            WriteHalf::Ssh(half) => Pin::new(half).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            WriteHalf::Tcp(half) => half.is_write_vectored(),
+            #[cfg(feature = "tls")]
+            WriteHalf::Tls(half) => half.is_write_vectored(),
+            #[cfg(all(feature = "ssh", unix))]
+            WriteHalf::Ssh(half) => half.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            WriteHalf::Tls(half) => Pin::new(half).poll_flush(cx),
+            #[cfg(all(feature = "ssh", unix))]
+            WriteHalf::Ssh(half) => Pin::new(half).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(half) => Pin::new(half).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            WriteHalf::Tls(half) => Pin::new(half).poll_shutdown(cx),
+            #[cfg(all(feature = "ssh", unix))]
+            WriteHalf::Ssh(half) => Pin::new(half).poll_shutdown(cx),
+        }
+    }
+}
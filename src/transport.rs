@@ -0,0 +1,488 @@
+//! Sans-IO BOLT 8 handshake and message framing.
+//!
+//! [`LNSocket`](crate::LNSocket) is a tokio wrapper around these types; they do no I/O
+//! themselves, so they can drive a connection over any transport or async runtime:
+//!
+//! 1. [`Handshake::new`] gives the act one bytes to send.
+//! 2. Read [`ACT_TWO_SIZE`] bytes and pass them to [`Handshake::process_act_two`], which yields
+//!    the act three bytes to send and a ready [`Transport`].
+//! 3. Encrypt outgoing messages with [`Transport::encrypt_message`]. For incoming ones, read
+//!    [`LENGTH_HEADER_SIZE`] bytes, decrypt them with [`Transport::decrypt_length_header`],
+//!    read that many bytes more and hand them to [`Transport::decrypt_message`].
+//!
+//! ```
+//! use lnsocket::transport::Handshake;
+//! # fn send(_: &[u8]) {}
+//! # fn ex(our_key: bitcoin::secp256k1::SecretKey, their_pubkey: bitcoin::secp256k1::PublicKey) {
+//! let (handshake, act_one) = Handshake::new(our_key, their_pubkey);
+//! send(&act_one);
+//! # }
+//! ```
+//!
+//! With the `futures-io` feature, [`crate::futures_io`] wires these up to any
+//! `futures::io::{AsyncRead, AsyncWrite}` stream (async-std, smol, ...).
+
+use bitcoin::Network;
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey, rand};
+
+use crate::Error;
+use crate::ln::msgs::{self, DecodeError};
+use crate::ln::peer_channel_encryptor::{LN_MAX_MSG_LEN, PeerChannelEncryptor};
+use crate::ln::wire;
+use crate::privacy::{Padding, PaddingPolicy};
+use crate::util::ser::Writeable;
+
+/// Size of the act two message we read from the responder.
+pub const ACT_TWO_SIZE: usize = 50;
+
+/// Size of the encrypted length header in front of every message.
+pub const LENGTH_HEADER_SIZE: usize = 18;
+
+/// Size of the MAC following every message body.
+pub const MAC_SIZE: usize = 16;
+
+/// The initiator side of a BOLT 8 handshake in progress.
+pub struct Handshake {
+    channel: PeerChannelEncryptor,
+    our_key: SecretKey,
+    their_pubkey: PublicKey,
+}
+
+impl Handshake {
+    /// Start a handshake with a fresh random ephemeral key, returning the act one bytes to
+    /// send to the peer.
+    pub fn new(our_key: SecretKey, their_pubkey: PublicKey) -> (Handshake, [u8; 50]) {
+        let ephemeral = SecretKey::new(&mut rand::thread_rng());
+        Self::start(our_key, their_pubkey, ephemeral)
+    }
+
+    /// Start a handshake with `ephemeral` as the ephemeral key, so the act one and act three
+    /// bytes are the same every time, e.g. to check them against the BOLT 8 test vectors.
+    ///
+    /// Reusing an ephemeral key breaks the security of the session: this is for tests only,
+    /// and needs the `test-utils` feature outside of this crate's own.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn with_ephemeral_key(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        ephemeral: SecretKey,
+    ) -> (Handshake, [u8; 50]) {
+        Self::start(our_key, their_pubkey, ephemeral)
+    }
+
+    fn start(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        ephemeral: SecretKey,
+    ) -> (Handshake, [u8; 50]) {
+        let mut channel = PeerChannelEncryptor::new_outbound(their_pubkey, ephemeral);
+        let act_one = channel.get_act_one(&Secp256k1::signing_only());
+        (
+            Handshake {
+                channel,
+                our_key,
+                their_pubkey,
+            },
+            act_one,
+        )
+    }
+
+    /// Process the responder's act two, returning the act three bytes to send and the
+    /// transport for the rest of the session.
+    ///
+    /// Fails with `Error::PeerKeyMismatch` if act two is well formed but doesn't decrypt.
+    pub fn process_act_two(
+        mut self,
+        act_two: &[u8; ACT_TWO_SIZE],
+    ) -> Result<(Transport, [u8; 66]), Error> {
+        let act_three = self
+            .channel
+            .process_act_two(&Secp256k1::signing_only(), act_two, &self.our_key)
+            .map_err(|err| {
+                // version and key are checked first, anything after is the MAC
+                if act_two[0] == 0 && PublicKey::from_slice(&act_two[1..34]).is_ok() {
+                    Error::PeerKeyMismatch {
+                        expected: self.their_pubkey,
+                    }
+                } else {
+                    err.into()
+                }
+            })?;
+        Ok((Transport::from_channel(self.channel), act_three))
+    }
+}
+
+/// Encryption and framing for an established BOLT 8 session.
+pub struct Transport {
+    channel: PeerChannelEncryptor,
+    padding: PaddingPolicy,
+    max_message_len: usize,
+}
+
+impl Transport {
+    /// Encode and encrypt `msg`, returning the bytes to write: the encrypted length header
+    /// followed by the encrypted message, and any padding.
+    ///
+    /// Panics if the encoded message is longer than 65535 bytes.
+    pub fn encrypt_message<M: wire::Type + Writeable>(&mut self, msg: &M) -> Vec<u8> {
+        let mut frame = self.channel.encrypt_message(msg);
+        for len in self.padding.padding(frame.len()) {
+            frame.extend_from_slice(&self.channel.encrypt_message(&Padding(len)));
+        }
+        frame
+    }
+
+    /// Pad every message encrypted from now on as `policy` says, see the
+    /// [`privacy`](crate::privacy) module. Only turn this on once `init` has been exchanged.
+    pub fn set_padding(&mut self, policy: PaddingPolicy) {
+        self.padding = policy;
+    }
+
+    /// Refuse messages longer than `len` bytes (type and payload) from now on, at most 65535,
+    /// the default. See [`Transport::decrypt_length_header`].
+    pub fn set_max_message_len(&mut self, len: usize) {
+        self.max_message_len = len.min(LN_MAX_MSG_LEN);
+    }
+
+    /// Decrypt a length header, returning how many bytes to read next (the message plus its
+    /// MAC).
+    ///
+    /// Fails with `Error::BadMac` if the header doesn't authenticate and with
+    /// `Error::MessageTooLarge` if it declares a message over the
+    /// [limit](Transport::set_max_message_len), so a peer can't make the reader allocate more
+    /// than that.
+    pub fn decrypt_length_header(
+        &mut self,
+        hdr: &[u8; LENGTH_HEADER_SIZE],
+    ) -> Result<usize, Error> {
+        decrypt_length_header(&mut self.channel, hdr, self.max_message_len)
+    }
+
+    /// Decrypt a message body read after its length header, returning the message type and
+    /// payload.
+    pub fn decrypt_message(&mut self, body: Vec<u8>) -> Result<(u16, Vec<u8>), Error> {
+        decrypt_message(&mut self.channel, body)
+    }
+
+    pub(crate) fn from_channel(channel: PeerChannelEncryptor) -> Self {
+        Transport {
+            channel,
+            padding: PaddingPolicy::None,
+            max_message_len: LN_MAX_MSG_LEN,
+        }
+    }
+
+    /// Decrypt a whole frame as produced by [`Transport::encrypt_message`] (length header,
+    /// message and MAC) in place, returning the message type and payload.
+    ///
+    /// Useful for fixtures and for frames read outside of the usual header-then-body loop.
+    pub fn decrypt_frame<'a>(&mut self, frame: &'a mut [u8]) -> Result<(u16, &'a [u8]), Error> {
+        if frame.len() < LENGTH_HEADER_SIZE + MAC_SIZE {
+            return Err(Error::Decode(DecodeError::ShortRead));
+        }
+        let (hdr, body) = frame.split_at_mut(LENGTH_HEADER_SIZE);
+        let len = self.decrypt_length_header((&*hdr).try_into().expect("split at header size"))?;
+        if body.len() != len {
+            return Err(Error::Decode(DecodeError::BadLengthDescriptor));
+        }
+        self.channel
+            .decrypt_message(body)
+            .map_err(|_| Error::BadMac)?;
+
+        let msg = &body[..len - MAC_SIZE];
+        if msg.len() < 2 {
+            return Err(Error::Decode(DecodeError::ShortRead));
+        }
+        Ok((u16::from_be_bytes([msg[0], msg[1]]), &msg[2..]))
+    }
+
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) fn into_channel(self) -> PeerChannelEncryptor {
+        self.channel
+    }
+}
+
+/// The `init` we answer a peer's `init` with: no required features and the same networks.
+pub fn init_reply(their_init: &msgs::Init) -> msgs::Init {
+    init_reply_on(their_init, &[])
+}
+
+/// Like [`init_reply`], but advertise only `networks` unless there are none.
+pub fn init_reply_on(their_init: &msgs::Init, networks: &[Network]) -> msgs::Init {
+    our_init(match networks {
+        [] => their_init.networks.clone(),
+        networks => Some(crate::network::chain_hashes(networks)),
+    })
+}
+
+/// The `init` to send before the peer's, advertising `networks`, or no networks if there
+/// are none.
+pub fn init_first(networks: &[Network]) -> msgs::Init {
+    our_init(match networks {
+        [] => None,
+        networks => Some(crate::network::chain_hashes(networks)),
+    })
+}
+
+fn our_init(networks: Option<Vec<ChainHash>>) -> msgs::Init {
+    #[allow(unused_mut)]
+    let mut our_features = vec![0; 5];
+    #[cfg(feature = "compression")]
+    crate::ln::features::set(
+        &mut our_features,
+        crate::commando_protocol::COMMANDO_COMPRESSION_FEATURE_BIT,
+    );
+
+    msgs::Init {
+        features: our_features,
+        global_features: vec![0; 2],
+        remote_network_address: None,
+        networks,
+    }
+}
+
+/// How many bytes `msg` takes on the wire once encrypted: length header, type, payload and
+/// MAC, without encrypting or even encoding it. Padding, if any, comes on top.
+///
+/// Useful to account bandwidth or enforce a quota before sending, or to size buffers. A
+/// message whose [`wire::encoded_len`] is over 65535 bytes can't be sent at all.
+pub fn wire_len<M: wire::Type + Writeable>(msg: &M) -> usize {
+    LENGTH_HEADER_SIZE + wire::encoded_len(msg) + MAC_SIZE
+}
+
+/// Decrypt a length header and check the length it declares against `max_len`.
+pub(crate) fn decrypt_length_header(
+    channel: &mut PeerChannelEncryptor,
+    hdr: &[u8; LENGTH_HEADER_SIZE],
+    max_len: usize,
+) -> Result<usize, Error> {
+    let len = channel
+        .decrypt_length_header(hdr)
+        .map_err(|_| Error::BadMac)? as usize;
+    if len > max_len {
+        return Err(Error::MessageTooLarge { len, max: max_len });
+    }
+    Ok(len + MAC_SIZE)
+}
+
+/// Decrypt `body` (message + MAC) in place and split off the message type.
+pub(crate) fn decrypt_message(
+    channel: &mut PeerChannelEncryptor,
+    mut body: Vec<u8>,
+) -> Result<(u16, Vec<u8>), Error> {
+    if body.len() < MAC_SIZE {
+        return Err(Error::Decode(DecodeError::ShortRead));
+    }
+    if body.len() > LN_MAX_MSG_LEN + MAC_SIZE {
+        return Err(Error::MessageTooLarge {
+            len: body.len() - MAC_SIZE,
+            max: LN_MAX_MSG_LEN,
+        });
+    }
+    channel
+        .decrypt_message(&mut body)
+        .map_err(|_| Error::BadMac)?;
+    body.truncate(body.len().saturating_sub(MAC_SIZE));
+
+    if body.len() < 2 {
+        return Err(Error::Decode(DecodeError::ShortRead));
+    }
+    let type_id = u16::from_be_bytes([body[0], body[1]]);
+    body.drain(..2);
+    Ok((type_id, body))
+}
+
+/// Keys and sessions for tests; [`crate::lnsocket::testing`] has the socket fixtures.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::ln::peer_channel_encryptor::CipherState;
+    use bitcoin::secp256k1::{PublicKey, SecretKey};
+
+    /// The node id of the secret key made of `byte`s.
+    pub(crate) fn key(byte: u8) -> PublicKey {
+        PublicKey::from_secret_key(
+            &bitcoin::secp256k1::Secp256k1::signing_only(),
+            &SecretKey::from_slice(&[byte; 32]).unwrap(),
+        )
+    }
+
+    /// Session keys sending with `sk` and receiving with `rk`: `cipher(a, b)` talks to
+    /// `cipher(b, a)`.
+    pub(crate) fn cipher(sk: u8, rk: u8) -> CipherState {
+        CipherState {
+            sk: [sk; 32],
+            sn: 0,
+            sck: [sk; 32],
+            rk: [rk; 32],
+            rn: 0,
+            rck: [rk; 32],
+        }
+    }
+
+    /// Our end and the peer's of a session whose handshake is already done.
+    pub(crate) fn encryptor_pair() -> (PeerChannelEncryptor, PeerChannelEncryptor) {
+        (
+            PeerChannelEncryptor::from_cipher_state(key(2), cipher(3, 4)),
+            PeerChannelEncryptor::from_cipher_state(key(2), cipher(4, 3)),
+        )
+    }
+
+    /// A [`Transport`] with the keys of [`cipher`]`(sk, rk)`.
+    pub(crate) fn transport(sk: u8, rk: u8) -> Transport {
+        Transport::from_channel(PeerChannelEncryptor::from_cipher_state(
+            key(1),
+            cipher(sk, rk),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::testing::transport;
+
+    #[test]
+    fn frames_roundtrip() {
+        let (mut alice, mut bob) = (transport(1, 2), transport(2, 1));
+
+        for byteslen in [0, 7, 1000] {
+            let mut frame = alice.encrypt_message(&msgs::Pong { byteslen });
+            let (type_id, payload) = bob.decrypt_frame(&mut frame).unwrap();
+            assert_eq!(type_id, 19);
+            assert_eq!(payload.len(), 2 + byteslen as usize);
+        }
+    }
+
+    #[test]
+    fn wire_len_matches_the_encrypted_size() {
+        let mut alice = transport(1, 2);
+        let ping = msgs::Ping {
+            ponglen: 1,
+            byteslen: 300,
+        };
+        assert_eq!(wire::encoded_len(&ping), 2 + 4 + 300);
+        assert_eq!(wire_len(&ping), alice.encrypt_message(&ping).len());
+        let init = init_first(&[]);
+        assert_eq!(wire_len(&init), alice.encrypt_message(&init).len());
+    }
+
+    #[test]
+    fn padding_follows_the_message() {
+        let (mut alice, mut bob) = (transport(1, 2), transport(2, 1));
+        alice.set_padding(PaddingPolicy::Bucketed(128));
+        let mut bytes = alice.encrypt_message(&msgs::Pong { byteslen: 7 });
+        assert_eq!(bytes.len(), 128);
+
+        let (pong, padding) = bytes.split_at_mut(18 + 2 + 2 + 7 + MAC_SIZE);
+        assert_eq!(bob.decrypt_frame(pong).unwrap().0, 19);
+        let (type_id, zeros) = bob.decrypt_frame(padding).unwrap();
+        assert_eq!(type_id, crate::privacy::PADDING_TYPE);
+        assert_eq!(zeros.len(), 128 - 45 - 36);
+    }
+
+    #[test]
+    fn fixed_ephemeral_key_matches_the_bolt8_vectors() {
+        let key = |hex: &str| SecretKey::from_slice(&hex::decode(hex).unwrap()).unwrap();
+        let their_pubkey = PublicKey::from_secret_key(
+            &Secp256k1::signing_only(),
+            &key("2121212121212121212121212121212121212121212121212121212121212121"),
+        );
+        let (handshake, act_one) = Handshake::with_ephemeral_key(
+            key("1111111111111111111111111111111111111111111111111111111111111111"),
+            their_pubkey,
+            key("1212121212121212121212121212121212121212121212121212121212121212"),
+        );
+        assert_eq!(
+            hex::encode(act_one),
+            "00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df608655115\
+             1f58b8afe6c195782c6a"
+        );
+
+        let act_two: [u8; ACT_TWO_SIZE] = hex::decode(
+            "0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac58\
+             3c9ef6eafca3f730ae",
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        let (_, act_three) = handshake.process_act_two(&act_two).unwrap();
+        assert_eq!(
+            hex::encode(act_three),
+            "00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc28\
+             fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba"
+        );
+
+        // the same act two, but we expected another node
+        let other = PublicKey::from_secret_key(
+            &Secp256k1::signing_only(),
+            &key("2222222222222222222222222222222222222222222222222222222222222222"),
+        );
+        let (handshake, _) = Handshake::with_ephemeral_key(
+            key("1111111111111111111111111111111111111111111111111111111111111111"),
+            other,
+            key("1212121212121212121212121212121212121212121212121212121212121212"),
+        );
+        assert!(matches!(
+            handshake.process_act_two(&act_two),
+            Err(Error::PeerKeyMismatch { expected }) if expected == other
+        ));
+    }
+
+    #[test]
+    fn malformed_ciphertext_is_an_error() {
+        let mut bob = transport(2, 1);
+        for hdr in [[0; LENGTH_HEADER_SIZE], [0xff; LENGTH_HEADER_SIZE]] {
+            assert!(matches!(
+                bob.decrypt_length_header(&hdr),
+                Err(Error::BadMac)
+            ));
+        }
+        for (len, err) in [(0, "short"), (MAC_SIZE, "mac"), (70_000, "large")] {
+            match (bob.decrypt_message(vec![0xff; len]), err) {
+                (Err(Error::Decode(DecodeError::ShortRead)), "short")
+                | (Err(Error::BadMac), "mac")
+                | (Err(Error::MessageTooLarge { .. }), "large") => {}
+                (res, _) => panic!("{len} bytes: {res:?}"),
+            }
+        }
+
+        let (mut alice, mut bob) = (transport(1, 2), transport(2, 1));
+        let mut frame = alice.encrypt_message(&msgs::Pong { byteslen: 4 });
+        let last = frame.len() - 1;
+        frame[last] ^= 1;
+        assert!(matches!(bob.decrypt_frame(&mut frame), Err(Error::BadMac)));
+    }
+
+    #[test]
+    fn declared_lengths_over_the_limit_are_refused() {
+        let (mut alice, mut bob) = (transport(1, 2), transport(2, 1));
+        bob.set_max_message_len(10);
+        // type, length and 6 bytes fit
+        let mut frame = alice.encrypt_message(&msgs::Pong { byteslen: 6 });
+        assert_eq!(bob.decrypt_frame(&mut frame).unwrap().1.len(), 8);
+
+        let frame = alice.encrypt_message(&msgs::Pong { byteslen: 7 });
+        let hdr = frame[..LENGTH_HEADER_SIZE].try_into().unwrap();
+        assert!(matches!(
+            bob.decrypt_length_header(&hdr),
+            Err(Error::MessageTooLarge { len: 11, max: 10 })
+        ));
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let (mut alice, mut bob) = (transport(1, 2), transport(2, 1));
+        let mut frame = alice.encrypt_message(&msgs::Pong { byteslen: 4 });
+        let len = frame.len();
+        assert!(matches!(
+            bob.decrypt_frame(&mut frame[..len - 1]),
+            Err(Error::Decode(DecodeError::BadLengthDescriptor))
+        ));
+        assert!(matches!(
+            bob.decrypt_frame(&mut [0; 10]),
+            Err(Error::Decode(DecodeError::ShortRead))
+        ));
+    }
+}
@@ -13,7 +13,7 @@ use crate::prelude::*;
 const RFC4648_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
 
 // Zbase encoding alphabet
-//const ZBASE_ALPHABET: &[u8] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+const ZBASE_ALPHABET: &[u8] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
 
 /// RFC4648 decoding table
 const RFC4648_INV_ALPHABET: [i8; 43] = [
@@ -21,13 +21,11 @@ const RFC4648_INV_ALPHABET: [i8; 43] = [
     9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
 ];
 
-/*
 // Zbase decoding table
 const ZBASE_INV_ALPHABET: [i8; 43] = [
     -1, 18, -1, 25, 26, 27, 30, 29, 7, 31, -1, -1, -1, -1, -1, -1, -1, 24, 1, 12, 3, 8, 5, 6, 28,
     21, 9, 10, -1, 11, 2, 16, 13, 14, 4, 22, 17, 19, -1, 20, 15, 0, 23,
 ];
-*/
 
 /// Alphabet used for encoding and decoding.
 #[derive(Copy, Clone)]
@@ -37,8 +35,8 @@ pub enum Alphabet {
         /// Whether to use padding.
         padding: bool,
     },
-    // Zbase32 encoding.
-    //ZBase32,
+    /// Zbase32 encoding.
+    ZBase32,
 }
 
 impl Alphabet {
@@ -62,7 +60,8 @@ impl Alphabet {
                     return String::from_utf8(ret).expect("Invalid UTF-8");
                 }
                 ret
-            } //Self::ZBase32 => Self::encode_data(data, ZBASE_ALPHABET),
+            }
+            Self::ZBase32 => Self::encode_data(data, ZBASE_ALPHABET),
         };
         ret.truncate(output_length);
 
@@ -86,7 +85,8 @@ impl Alphabet {
                     });
                 }
                 (&data[..unpadded_data_length], RFC4648_INV_ALPHABET)
-            } //Self::ZBase32 => (data, ZBASE_INV_ALPHABET),
+            }
+            Self::ZBase32 => (data, ZBASE_INV_ALPHABET),
         };
         // If the string has more characters than are required to alphabet_encode the number of bytes
         // decodable, treat the string as invalid.
@@ -180,6 +180,29 @@ mod tests {
         (&[0xF8, 0x3E, 0x7F, 0x83], "7A7H7AY="),
     ];
 
+    const ZBASE32_TEST_VECTORS: &[(&[u8], &str)] = &[
+        (b"", ""),
+        (b"\x00", "yy"),
+        (&[0xf0, 0xbf, 0xc7], "6n9hq"),
+        (&[0xd4, 0x7a, 0x04], "4t7ye"),
+        (
+            &[
+                0x00, 0x44, 0x32, 0x14, 0xc7, 0x42, 0x54, 0xb6, 0x35, 0xcf, 0x84, 0x65, 0x3a, 0x56,
+                0xd7, 0xc6, 0x75, 0xbe, 0x77, 0xdf,
+            ],
+            "ybndrfg8ejkmcpqxot1uwisza345h769",
+        ),
+    ];
+
+    #[test]
+    fn test_zbase32_encode_decode() {
+        for (input, encoded) in ZBASE32_TEST_VECTORS {
+            assert_eq!(&Alphabet::ZBase32.encode(input), encoded);
+            assert_eq!(&Alphabet::ZBase32.decode(encoded).unwrap()[..], &input[..]);
+        }
+        assert!(Alphabet::ZBase32.decode("y2").is_err()); // `2` is not in the alphabet
+    }
+
     #[test]
     fn test_rfc4648_encode() {
         for (input, encoded) in RFC4648_TEST_VECTORS {
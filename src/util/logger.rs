@@ -140,34 +140,6 @@ where
     }
 }
 
-/// Wrapper for logging a [`PublicKey`] in hex format.
-///
-/// This is not exported to bindings users as fmt can't be used in C
-#[doc(hidden)]
-pub struct DebugPubKey<'a>(pub &'a PublicKey);
-impl<'a> core::fmt::Display for DebugPubKey<'a> {
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
-        for i in self.0.serialize().iter() {
-            write!(f, "{:02x}", i)?;
-        }
-        Ok(())
-    }
-}
-
-/// Wrapper for logging byte slices in hex format.
-///
-/// This is not exported to bindings users as fmt can't be used in C
-#[doc(hidden)]
-pub struct DebugBytes<'a>(pub &'a [u8]);
-impl<'a> core::fmt::Display for DebugBytes<'a> {
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
-        for i in self.0 {
-            write!(f, "{:02x}", i)?;
-        }
-        Ok(())
-    }
-}
-
 /// Wrapper for logging `Iterator`s.
 ///
 /// This is not exported to bindings users as fmt can't be used in C
@@ -257,6 +257,12 @@ impl LengthLimitedRead for &[u8] {
     }
 }
 
+impl<R: LengthLimitedRead + ?Sized> LengthLimitedRead for &mut R {
+    fn remaining_bytes(&self) -> u64 {
+        (**self).remaining_bytes()
+    }
+}
+
 impl LengthLimitedRead for Cursor<&[u8]> {
     fn remaining_bytes(&self) -> u64 {
         let len = self.get_ref().len() as u64;
@@ -845,6 +851,11 @@ impl Hostname {
         self.0.len() as u8
     }
 
+    /// Whether the hostname is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     /// Check if the chars in `s` are allowed to be included in a [`Hostname`].
     pub(crate) fn str_is_valid_hostname(s: &str) -> bool {
         s.len() <= 255
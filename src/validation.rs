@@ -0,0 +1,264 @@
+//! Checks on the params and results of commando calls.
+//!
+//! CLN renames fields and changes their types between releases. A validator registered for a
+//! method with [`CommandoConfig::validate_params`] or [`CommandoConfig::validate_result`]
+//! turns such a change into an [`Error::Validation`] at the call, where a staging run catches
+//! it, instead of into wrong data further down.
+//!
+//! ```no_run
+//! # #[cfg(feature = "tokio")]
+//! use lnsocket::commando::CommandoConfig;
+//! use serde_json::Value;
+//!
+//! # #[cfg(feature = "tokio")]
+//! let config = CommandoConfig::new().validate_result("getinfo", |result: &Value| {
+//!     match result["blockheight"] {
+//!         Value::Number(_) => Ok(()),
+//!         _ => Err("blockheight is not a number".to_string()),
+//!     }
+//! });
+//! ```
+//!
+//! With the `jsonschema` feature, [`JsonSchema`] checks a value against a JSON schema.
+//!
+//! Params are checked before every call. Results are checked in every mode that buffers the
+//! reply: [`CommandoClient::call_raw`](crate::CommandoClient::call_raw) and `call_typed`
+//! parse it into a `Value` for that when the method has a validator. Replies streamed to a
+//! sink can't be checked, so such calls are refused instead.
+//!
+//! [`CommandoConfig::validate_params`]: crate::commando::CommandoConfig::validate_params
+//! [`CommandoConfig::validate_result`]: crate::commando::CommandoConfig::validate_result
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::Error;
+
+/// Which side of a call failed validation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Payload {
+    Params,
+    Result,
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Payload::Params => "params",
+            Payload::Result => "result",
+        })
+    }
+}
+
+/// A value a [`Validator`] refused, inside [`Error::Validation`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    pub method: String,
+    pub payload: Payload,
+    /// What the validator found wrong, at least one thing.
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.method,
+            self.payload,
+            self.problems.join("; ")
+        )
+    }
+}
+
+/// Checks a value, returning what is wrong with it. Implemented for closures returning
+/// `Result<(), String>`.
+pub trait Validator: Send + Sync {
+    fn validate(&self, value: &Value) -> Result<(), Vec<String>>;
+}
+
+impl<F> Validator for F
+where
+    F: Fn(&Value) -> Result<(), String> + Send + Sync,
+{
+    fn validate(&self, value: &Value) -> Result<(), Vec<String>> {
+        self(value).map_err(|problem| vec![problem])
+    }
+}
+
+/// A [`Validator`] checking against a JSON schema, every draft the `jsonschema` crate knows.
+#[cfg(feature = "jsonschema")]
+pub struct JsonSchema(jsonschema::Validator);
+
+#[cfg(feature = "jsonschema")]
+impl JsonSchema {
+    /// Compiles `schema`, failing with what is wrong with it.
+    pub fn new(schema: &Value) -> Result<Self, String> {
+        jsonschema::validator_for(schema)
+            .map(Self)
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(feature = "jsonschema")]
+impl Validator for JsonSchema {
+    fn validate(&self, value: &Value) -> Result<(), Vec<String>> {
+        let problems: Vec<String> = self
+            .0
+            .iter_errors(value)
+            .map(|err| match err.instance_path.as_str() {
+                "" => err.to_string(),
+                path => format!("{path}: {err}"),
+            })
+            .collect();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+/// The validators of a [`CommandoConfig`](crate::commando::CommandoConfig), by method.
+#[derive(Clone, Default)]
+pub(crate) struct Validators {
+    params: HashMap<String, Arc<dyn Validator>>,
+    results: HashMap<String, Arc<dyn Validator>>,
+}
+
+impl fmt::Debug for Validators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Validators")
+            .field("params", &self.params.keys())
+            .field("results", &self.results.keys())
+            .finish()
+    }
+}
+
+impl Validators {
+    pub(crate) fn insert(
+        &mut self,
+        payload: Payload,
+        method: String,
+        validator: Arc<dyn Validator>,
+    ) {
+        match payload {
+            Payload::Params => self.params.insert(method, validator),
+            Payload::Result => self.results.insert(method, validator),
+        };
+    }
+
+    /// Runs `method`'s validator for `payload` on `value`, if it has one.
+    pub(crate) fn check(&self, payload: Payload, method: &str, value: &Value) -> Result<(), Error> {
+        let validators = match payload {
+            Payload::Params => &self.params,
+            Payload::Result => &self.results,
+        };
+        let Some(validator) = validators.get(method) else {
+            return Ok(());
+        };
+        validator
+            .validate(value)
+            .map_err(|problems| invalid(method, payload, problems))
+    }
+
+    /// Runs `method`'s result validator, if it has one, on the result of the JSON-RPC
+    /// response `reply`. An error response has no result to check; a reply that isn't JSON
+    /// fails with `Error::Json`.
+    pub(crate) fn check_raw_result(&self, method: &str, reply: &[u8]) -> Result<(), Error> {
+        if !self.results.contains_key(method) {
+            return Ok(());
+        }
+        let response: Value = serde_json::from_slice(reply).map_err(|_| Error::Json)?;
+        if response.get("error").is_some_and(|err| !err.is_null()) {
+            return Ok(());
+        }
+        let result = response.get("result").unwrap_or(&Value::Null);
+        self.check(Payload::Result, method, result)
+    }
+
+    /// Fails if `method` has a result validator, for calls whose result can't be checked.
+    pub(crate) fn check_unbuffered(&self, method: &str) -> Result<(), Error> {
+        if !self.results.contains_key(method) {
+            return Ok(());
+        }
+        let problem = "a streamed reply can't be validated".to_string();
+        Err(invalid(method, Payload::Result, vec![problem]))
+    }
+}
+
+fn invalid(method: &str, payload: Payload, problems: Vec<String>) -> Error {
+    tracing::debug!(%method, %payload, ?problems, "commando validation failed");
+    Error::Validation(Box::new(ValidationError {
+        method: method.to_string(),
+        payload,
+        problems,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn validators() -> Validators {
+        let mut validators = Validators::default();
+        let has_id: Arc<dyn Validator> = Arc::new(|value: &Value| match value.get("id") {
+            Some(_) => Ok(()),
+            None => Err("missing id".to_string()),
+        });
+        validators.insert(Payload::Result, "getinfo".to_string(), has_id);
+        validators
+    }
+
+    #[test]
+    fn only_registered_methods_are_checked() {
+        let validators = validators();
+        assert!(
+            validators
+                .check(Payload::Result, "getinfo", &json!({"id": "02ab"}))
+                .is_ok()
+        );
+        assert!(
+            validators
+                .check(Payload::Params, "getinfo", &json!({}))
+                .is_ok()
+        );
+        assert!(
+            validators
+                .check(Payload::Result, "listpeers", &json!({}))
+                .is_ok()
+        );
+
+        let Err(Error::Validation(err)) = validators.check(Payload::Result, "getinfo", &json!({}))
+        else {
+            panic!("getinfo without an id passed");
+        };
+        assert_eq!(err.problems, ["missing id"]);
+        assert_eq!(err.to_string(), "getinfo result: missing id");
+    }
+
+    #[cfg(feature = "jsonschema")]
+    #[test]
+    fn json_schema_reports_every_problem() {
+        let schema = JsonSchema::new(&json!({
+            "type": "object",
+            "required": ["id"],
+            "properties": {"blockheight": {"type": "integer"}}
+        }))
+        .unwrap();
+        assert!(
+            schema
+                .validate(&json!({"id": "02ab", "blockheight": 1}))
+                .is_ok()
+        );
+        let problems = schema.validate(&json!({"blockheight": "1"})).unwrap_err();
+        assert_eq!(problems.len(), 2);
+        assert!(problems.iter().any(|p| p.starts_with("/blockheight: ")));
+
+        assert!(JsonSchema::new(&json!({"type": 5})).is_err());
+    }
+}
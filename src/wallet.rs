@@ -0,0 +1,481 @@
+//! A wallet on top of commando, behind the `wallet` cargo feature.
+//!
+//! [`WalletClient`] covers what a wallet app asks of a CLN node — the balance, paying and
+//! creating invoices, and the payment history — without method names, reply shapes or
+//! amount strings to deal with:
+//!
+//! ```no_run
+//! use lnsocket::wallet::{Msat, WalletClient};
+//! # async fn ex(client: lnsocket::CommandoClient, bolt11: &str) -> Result<(), lnsocket::Error> {
+//! let wallet = WalletClient::new(client);
+//! println!("{} sat spendable", wallet.balance().await?.lightning.to_sat());
+//!
+//! let invoice = wallet.create_invoice(Some(Msat::from_sat(2_100)), "coffee").await?;
+//! println!("pay me: {}", invoice.bolt11);
+//!
+//! let paid = wallet.pay_invoice(bolt11).await?;
+//! println!("paid {} with {} fees", paid.amount, paid.fee());
+//! # Ok(()) }
+//! ```
+//!
+//! A payment that doesn't go through fails with `Error::PaymentFailed`, which says why; an
+//! `Error::Rpc` means the call itself was wrong or not allowed. For anything else, the
+//! [`CommandoClient`] is still there, see [`WalletClient::commando`].
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::secp256k1::rand::{self, Rng};
+use serde_json::{Value, json};
+
+use crate::commando::{CallOpts, MessageTransport};
+use crate::rpc::msat;
+use crate::{CommandoClient, Error, LNSocket};
+
+/// How long [`WalletClient::pay_invoice`] waits for `pay`, which keeps trying routes for up
+/// to a minute by default.
+pub const PAY_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// An amount in millisatoshi, the unit CLN counts in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Msat(pub u64);
+
+impl Msat {
+    /// `sat` satoshi, saturating at `u64::MAX` msat.
+    pub fn from_sat(sat: u64) -> Self {
+        Self(sat.saturating_mul(1000))
+    }
+
+    /// Whole satoshi, rounded down: what can be spent on chain.
+    pub fn to_sat(self) -> u64 {
+        self.0 / 1000
+    }
+
+    /// Rounded down to whole satoshi, see [`Msat::to_sat`].
+    pub fn to_amount(self) -> bitcoin::Amount {
+        bitcoin::Amount::from_sat(self.to_sat())
+    }
+
+    /// An amount field of a CLN reply, a number or a string such as `"1000msat"`.
+    fn from_rpc(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => n.as_u64().map(Self),
+            Value::String(s) => msat::parse(s).map(Self),
+            _ => None,
+        }
+    }
+}
+
+impl From<bitcoin::Amount> for Msat {
+    fn from(amount: bitcoin::Amount) -> Self {
+        Self::from_sat(amount.to_sat())
+    }
+}
+
+impl std::ops::Add for Msat {
+    type Output = Msat;
+
+    fn add(self, other: Msat) -> Msat {
+        Msat(self.0.saturating_add(other.0))
+    }
+}
+
+impl std::ops::AddAssign for Msat {
+    fn add_assign(&mut self, other: Msat) {
+        *self = *self + other;
+    }
+}
+
+impl fmt::Display for Msat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} msat", self.0)
+    }
+}
+
+/// What the node holds, from `listfunds`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Balance {
+    pub onchain_confirmed: Msat,
+    /// On-chain outputs waiting for confirmation, and coinbase outputs not mature yet.
+    pub onchain_unconfirmed: Msat,
+    /// Our side of the channels that are open and usable. Not all of it can be sent: part is
+    /// held as the channel reserve, and fees come out of it.
+    pub lightning: Msat,
+    /// Our side of the channels still opening or already closing.
+    pub lightning_pending: Msat,
+}
+
+impl Balance {
+    /// Add up a `listfunds` reply. `None` if it isn't one.
+    pub fn from_listfunds(reply: &Value) -> Option<Self> {
+        let mut balance = Balance::default();
+        for output in reply.get("outputs")?.as_array()? {
+            let amount = Msat::from_rpc(output.get("amount_msat")?)?;
+            match output.get("status").and_then(Value::as_str) {
+                Some("confirmed") => balance.onchain_confirmed += amount,
+                // spent outputs only show up when asked for
+                Some("spent") => {}
+                _ => balance.onchain_unconfirmed += amount,
+            }
+        }
+        for channel in reply.get("channels")?.as_array()? {
+            let amount = Msat::from_rpc(channel.get("our_amount_msat")?)?;
+            match channel.get("state").and_then(Value::as_str) {
+                Some("CHANNELD_NORMAL") => balance.lightning += amount,
+                _ => balance.lightning_pending += amount,
+            }
+        }
+        Some(balance)
+    }
+
+    /// What can be spent now: confirmed on-chain funds and usable channels.
+    pub fn spendable(&self) -> Msat {
+        self.onchain_confirmed + self.lightning
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PaymentStatus {
+    Pending,
+    Complete,
+    Failed,
+}
+
+impl PaymentStatus {
+    /// The `status` of a `pay` or `listpays` entry, or of a `listinvoices` one.
+    fn from_rpc(status: &str) -> Option<Self> {
+        match status {
+            "pending" | "unpaid" => Some(Self::Pending),
+            "complete" | "paid" => Some(Self::Complete),
+            "failed" | "expired" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of [`WalletClient::pay_invoice`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Payment {
+    /// Hex, as are the other hashes and preimages here.
+    pub payment_hash: String,
+    /// Proof of payment, once complete.
+    pub preimage: Option<String>,
+    /// What the recipient got.
+    pub amount: Msat,
+    /// What left our channels, fees included.
+    pub amount_sent: Msat,
+    /// `Pending` when the node gave up waiting while parts of the payment are still in
+    /// flight: check `list_transactions` later.
+    pub status: PaymentStatus,
+}
+
+impl Payment {
+    /// Pick the fields out of a `pay` reply.
+    pub fn from_pay(reply: &Value) -> Option<Self> {
+        Some(Self {
+            payment_hash: reply.get("payment_hash")?.as_str()?.to_string(),
+            preimage: reply
+                .get("payment_preimage")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            amount: Msat::from_rpc(reply.get("amount_msat")?)?,
+            amount_sent: Msat::from_rpc(reply.get("amount_sent_msat")?)?,
+            status: PaymentStatus::from_rpc(reply.get("status")?.as_str()?)?,
+        })
+    }
+
+    pub fn fee(&self) -> Msat {
+        Msat(self.amount_sent.0.saturating_sub(self.amount.0))
+    }
+}
+
+/// An invoice made by [`WalletClient::create_invoice`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CreatedInvoice {
+    pub bolt11: String,
+    pub payment_hash: String,
+    /// The label the node knows it by, for `listinvoices` or `waitinvoice`.
+    pub label: String,
+    /// Unix time, in seconds.
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// One entry of [`WalletClient::list_transactions`]: an invoice of ours that was paid, or a
+/// payment we made.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    pub direction: Direction,
+    /// Received, or delivered to the recipient.
+    pub amount: Msat,
+    /// For outgoing payments, what routing cost.
+    pub fee: Option<Msat>,
+    pub payment_hash: String,
+    pub status: PaymentStatus,
+    /// Unix time, in seconds, when it was paid or, if it wasn't, created.
+    pub timestamp: u64,
+    /// Text set by whoever made the invoice: sanitize before display.
+    pub description: Option<String>,
+}
+
+impl Transaction {
+    /// An entry of `listinvoices`. `None` unless it was paid.
+    pub fn from_invoice(entry: &Value) -> Option<Self> {
+        if entry.get("status")?.as_str()? != "paid" {
+            return None;
+        }
+        let amount = entry
+            .get("amount_received_msat")
+            .or_else(|| entry.get("amount_msat"))?;
+        Some(Self {
+            direction: Direction::Incoming,
+            amount: Msat::from_rpc(amount)?,
+            fee: None,
+            payment_hash: entry.get("payment_hash")?.as_str()?.to_string(),
+            status: PaymentStatus::Complete,
+            timestamp: entry.get("paid_at")?.as_u64()?,
+            description: description(entry),
+        })
+    }
+
+    /// An entry of `listpays`.
+    pub fn from_pay(entry: &Value) -> Option<Self> {
+        let status = PaymentStatus::from_rpc(entry.get("status")?.as_str()?)?;
+        let timestamp = entry
+            .get("completed_at")
+            .or_else(|| entry.get("created_at"))?;
+        // failed payments report no amounts
+        let amount = entry.get("amount_msat").and_then(Msat::from_rpc);
+        let sent = entry.get("amount_sent_msat").and_then(Msat::from_rpc);
+        Some(Self {
+            direction: Direction::Outgoing,
+            amount: amount.unwrap_or_default(),
+            fee: amount
+                .zip(sent)
+                .map(|(amount, sent)| Msat(sent.0.saturating_sub(amount.0))),
+            payment_hash: entry.get("payment_hash")?.as_str()?.to_string(),
+            status,
+            timestamp: timestamp.as_u64()?,
+            description: description(entry),
+        })
+    }
+}
+
+fn description(entry: &Value) -> Option<String> {
+    entry
+        .get("description")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+/// Paid invoices and payments, newest first.
+fn transactions(invoices: &Value, pays: &Value) -> Option<Vec<Transaction>> {
+    let incoming = invoices.get("invoices")?.as_array()?;
+    let outgoing = pays.get("pays")?.as_array()?;
+    let mut all: Vec<Transaction> = incoming
+        .iter()
+        .filter_map(Transaction::from_invoice)
+        .chain(outgoing.iter().filter_map(Transaction::from_pay))
+        .collect();
+    all.sort_by_key(|t| std::cmp::Reverse(t.timestamp));
+    Some(all)
+}
+
+/// Payment failures as `Error::PaymentFailed`, other errors as they are.
+fn payment_error(err: Error) -> Error {
+    match err {
+        Error::Rpc(err) if err.is_payment_failure() => Error::PaymentFailed {
+            reason: err.kind(),
+            message: err.message,
+        },
+        err => err,
+    }
+}
+
+/// A label no other invoice has.
+fn fresh_label() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let nonce: u32 = rand::thread_rng().r#gen();
+    format!("lnsocket-{}-{nonce:08x}", now.as_millis())
+}
+
+/// Wallet operations over a [`CommandoClient`], see the [module docs](self).
+///
+/// The rune needs `listfunds`, `pay`, `invoice`, `listinvoices` and `listpays`, or just the
+/// ones of the calls made.
+pub struct WalletClient<T: MessageTransport = LNSocket> {
+    commando: CommandoClient<T>,
+}
+
+impl<T: MessageTransport> WalletClient<T> {
+    pub fn new(commando: CommandoClient<T>) -> Self {
+        Self { commando }
+    }
+
+    /// The client underneath, for any other call.
+    pub fn commando(&self) -> &CommandoClient<T> {
+        &self.commando
+    }
+
+    pub fn into_inner(self) -> CommandoClient<T> {
+        self.commando
+    }
+
+    pub async fn balance(&self) -> Result<Balance, Error> {
+        let reply = self.commando.call("listfunds", json!({})).await?;
+        Balance::from_listfunds(&reply).ok_or(Error::Json)
+    }
+
+    /// Pay a BOLT 11 invoice with an amount, waiting up to [`PAY_TIMEOUT`].
+    ///
+    /// The call is never resent after a reconnect, though paying the same invoice twice
+    /// only pays it once. If it times out, the payment may still complete: look for its hash
+    /// in [`WalletClient::list_transactions`].
+    pub async fn pay_invoice(&self, bolt11: &str) -> Result<Payment, Error> {
+        let opts = CallOpts::new().retry(0).timeout(PAY_TIMEOUT);
+        let reply = self
+            .commando
+            .call_with_opts("pay", json!({ "bolt11": bolt11 }), opts)
+            .await
+            .map_err(payment_error)?;
+        Payment::from_pay(&reply).ok_or(Error::Json)
+    }
+
+    /// Create an invoice for `amount`, or for any amount the payer chooses if `None`, with
+    /// the node's default expiry.
+    pub async fn create_invoice(
+        &self,
+        amount: Option<Msat>,
+        description: &str,
+    ) -> Result<CreatedInvoice, Error> {
+        let amount_msat = match amount {
+            Some(amount) => json!(amount.0),
+            None => json!("any"),
+        };
+        let label = fresh_label();
+        let params = json!({
+            "amount_msat": amount_msat,
+            "label": label,
+            "description": description,
+        });
+        let reply = self.commando.call("invoice", params).await?;
+        let field = |name: &str| reply.get(name).and_then(Value::as_str).map(str::to_string);
+        Ok(CreatedInvoice {
+            bolt11: field("bolt11").ok_or(Error::Json)?,
+            payment_hash: field("payment_hash").ok_or(Error::Json)?,
+            expires_at: reply
+                .get("expires_at")
+                .and_then(Value::as_u64)
+                .ok_or(Error::Json)?,
+            label,
+        })
+    }
+
+    /// Invoices of ours that were paid and payments we made, pending and failed ones
+    /// included, newest first.
+    pub async fn list_transactions(&self) -> Result<Vec<Transaction>, Error> {
+        let (invoices, pays) = tokio::try_join!(
+            self.commando.call("listinvoices", json!({})),
+            self.commando.call("listpays", json!({})),
+        )?;
+        transactions(&invoices, &pays).ok_or(Error::Json)
+    }
+}
+
+impl<T: MessageTransport> From<CommandoClient<T>> for WalletClient<T> {
+    fn from(commando: CommandoClient<T>) -> Self {
+        Self::new(commando)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CLNErrorCode, RpcError};
+
+    #[test]
+    fn balance_from_listfunds() {
+        let reply = json!({
+            "outputs": [
+                {"amount_msat": 100_000_000, "status": "confirmed"},
+                {"amount_msat": "5000sat", "status": "unconfirmed"},
+                {"amount_msat": 1_000, "status": "spent"},
+            ],
+            "channels": [
+                {"our_amount_msat": 40_000_123, "state": "CHANNELD_NORMAL"},
+                {"our_amount_msat": 7_000, "state": "CHANNELD_AWAITING_LOCKIN"},
+            ],
+        });
+        let balance = Balance::from_listfunds(&reply).unwrap();
+        assert_eq!(balance.onchain_confirmed, Msat::from_sat(100_000));
+        assert_eq!(balance.onchain_unconfirmed, Msat(5_000_000));
+        assert_eq!(balance.lightning.to_sat(), 40_000);
+        assert_eq!(balance.lightning_pending, Msat(7_000));
+        assert_eq!(balance.spendable(), Msat(140_000_123));
+        assert_eq!(
+            Msat::from(bitcoin::Amount::from_sat(3))
+                .to_amount()
+                .to_sat(),
+            3
+        );
+        assert_eq!(Balance::from_listfunds(&json!({"outputs": []})), None);
+    }
+
+    #[test]
+    fn history_is_newest_first() {
+        let invoices = json!({"invoices": [
+            {"status": "paid", "amount_received_msat": 2_000, "payment_hash": "aa",
+             "paid_at": 30, "description": "coffee"},
+            {"status": "unpaid", "amount_msat": 1_000, "payment_hash": "bb"},
+        ]});
+        let pays = json!({"pays": [
+            {"status": "complete", "amount_msat": "1000msat", "amount_sent_msat": 1_002,
+             "payment_hash": "cc", "created_at": 10, "completed_at": 20},
+            {"status": "failed", "payment_hash": "dd", "created_at": 40},
+        ]});
+        let history = transactions(&invoices, &pays).unwrap();
+        let hashes: Vec<_> = history.iter().map(|t| t.payment_hash.as_str()).collect();
+        assert_eq!(hashes, ["dd", "aa", "cc"]);
+        assert_eq!(history[0].status, PaymentStatus::Failed);
+        assert_eq!(history[0].fee, None);
+        assert_eq!(history[1].direction, Direction::Incoming);
+        assert_eq!(history[1].description.as_deref(), Some("coffee"));
+        assert_eq!(history[2].fee, Some(Msat(2)));
+        assert_eq!(history[2].timestamp, 20);
+    }
+
+    #[test]
+    fn pay_replies_and_failures() {
+        let reply = json!({
+            "payment_hash": "aa", "payment_preimage": "bb", "status": "complete",
+            "amount_msat": 10_000, "amount_sent_msat": 10_010, "parts": 1,
+        });
+        let payment = Payment::from_pay(&reply).unwrap();
+        assert_eq!(payment.preimage.as_deref(), Some("bb"));
+        assert_eq!(payment.fee(), Msat(10));
+
+        let failed = payment_error(Error::Rpc(RpcError {
+            code: 205,
+            message: "Could not find a route".to_string(),
+            data: None,
+        }));
+        assert!(matches!(
+            failed,
+            Error::PaymentFailed { reason: CLNErrorCode::PayRouteNotFound, ref message }
+                if message == "Could not find a route"
+        ));
+        let denied = payment_error(Error::Rpc(RpcError {
+            code: 19537,
+            message: String::new(),
+            data: None,
+        }));
+        assert!(matches!(denied, Error::Rpc(_)));
+    }
+}